@@ -0,0 +1,119 @@
+extern crate virtualization_rs;
+
+use std::io::{self, BufRead, Write};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use structopt::StructOpt;
+use virtualization_rs::nbd::crypt::{CryptKey, EncryptedBackend};
+use virtualization_rs::nbd::server::{NbdExport, NbdServerBuilder};
+use virtualization_rs::nbd::unix_url;
+use zeroize::Zeroizing;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "cryptdisk")]
+struct Opt {
+    /// key file to use instead of asking for a passphrase
+    #[structopt(short, long, parse(from_os_str))]
+    key_file: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// creates an empty encrypted image of the given size in MiB
+    Create {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        size_mib: u64,
+    },
+    /// re-encrypts an image under a new key
+    Rekey {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        /// key file of the new key, a new passphrase is asked for otherwise
+        #[structopt(long, parse(from_os_str))]
+        new_key_file: Option<PathBuf>,
+    },
+    /// serves the decrypted image over NBD on a unix socket
+    Serve {
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+        #[structopt(parse(from_os_str))]
+        socket: PathBuf,
+    },
+}
+
+/// reads a line from the terminal with echo turned off, or from stdin as it
+/// is when that is not a terminal
+fn read_passphrase(prompt: &str) -> io::Result<Zeroizing<String>> {
+    eprint!("{}: ", prompt);
+    io::stderr().flush()?;
+    let fd = io::stdin().as_raw_fd();
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    let terminal = unsafe { libc::tcgetattr(fd, &mut saved) } == 0;
+    if terminal {
+        let mut quiet = saved;
+        quiet.c_lflag &= !libc::ECHO;
+        quiet.c_lflag |= libc::ECHONL;
+        if unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &quiet) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // sized up front so reading does not leave copies in reallocated memory
+    let mut line = Zeroizing::new(String::with_capacity(1024));
+    let result = io::stdin().lock().read_line(&mut line);
+    if terminal {
+        unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &saved) };
+    }
+    result?;
+    let len = line.trim_end_matches(&['\r', '\n'][..]).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+fn read_key(key_file: Option<PathBuf>, prompt: &str) -> io::Result<CryptKey> {
+    if let Some(path) = key_file {
+        return CryptKey::key_file(path);
+    }
+    let passphrase = read_passphrase(prompt)?;
+    Ok(CryptKey::passphrase(passphrase.as_str()))
+}
+
+fn run(opt: Opt) -> io::Result<()> {
+    match opt.command {
+        Command::Create { image, size_mib } => {
+            let key = read_key(opt.key_file, "passphrase")?;
+            EncryptedBackend::create(image, size_mib << 20, &key)?;
+        }
+        Command::Rekey {
+            image,
+            new_key_file,
+        } => {
+            let old = read_key(opt.key_file, "current passphrase")?;
+            let new = read_key(new_key_file, "new passphrase")?;
+            EncryptedBackend::rekey(image, &old, &new)?;
+        }
+        Command::Serve { image, socket } => {
+            let key = read_key(opt.key_file, "passphrase")?;
+            let disk = EncryptedBackend::open(image, &key, false)?;
+            drop(key);
+            let server = NbdServerBuilder::new()
+                .export(NbdExport::new("disk", disk))
+                .build();
+            let listener = UnixListener::bind(&socket)?;
+            println!("{}", unix_url(&socket, "disk"));
+            server.serve_unix(listener)?;
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run(Opt::from_args()) {
+        eprintln!("cryptdisk: {}", err);
+        std::process::exit(1);
+    }
+}
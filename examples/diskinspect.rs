@@ -0,0 +1,28 @@
+extern crate virtualization_rs;
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+use virtualization_rs::disk::inspect::inspect_disk_image;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "diskinspect")]
+struct Opt {
+    #[structopt(parse(from_os_str), required = true)]
+    images: Vec<PathBuf>,
+}
+
+fn main() {
+    let mut failed = false;
+    for image in Opt::from_args().images {
+        match inspect_disk_image(&image) {
+            Ok(report) => print!("{}", report),
+            Err(err) => {
+                eprintln!("diskinspect: {}: {}", image.display(), err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,55 @@
+extern crate virtualization_rs;
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+use virtualization_rs::disk::usage::{disk_usage, reclaim_disk_image, ReclaimError};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "diskspace")]
+struct Opt {
+    /// punch holes for all-zero blocks; the images must not be attached
+    #[structopt(short, long)]
+    reclaim: bool,
+
+    #[structopt(parse(from_os_str), required = true)]
+    images: Vec<PathBuf>,
+}
+
+fn mib(bytes: u64) -> f64 {
+    bytes as f64 / (1024.0 * 1024.0)
+}
+
+fn run(opt: Opt) -> Result<(), ReclaimError> {
+    println!(
+        "{:>12} {:>12} {:>12}  image",
+        "apparent", "allocated", "saved"
+    );
+    let mut saved = 0;
+    for image in &opt.images {
+        let (usage, image_saved) = if opt.reclaim {
+            let report = reclaim_disk_image(image)?;
+            (report.after, report.saved())
+        } else {
+            (disk_usage(image)?, 0)
+        };
+        saved += image_saved;
+        println!(
+            "{:>10.1}Mi {:>10.1}Mi {:>10.1}Mi  {}",
+            mib(usage.apparent),
+            mib(usage.allocated),
+            mib(image_saved),
+            image.display()
+        );
+    }
+    if opt.reclaim {
+        println!("released {:.1} MiB", mib(saved));
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run(Opt::from_args()) {
+        eprintln!("diskspace: {}", err);
+        std::process::exit(1);
+    }
+}
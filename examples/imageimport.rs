@@ -0,0 +1,51 @@
+extern crate virtualization_rs;
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+use virtualization_rs::disk::import::{ImageImporter, ImportError};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "imageimport")]
+struct Opt {
+    /// gzip, xz or zstd compressed raw image
+    #[structopt(parse(from_os_str))]
+    src: PathBuf,
+
+    /// raw image to create
+    #[structopt(parse(from_os_str))]
+    dst: PathBuf,
+
+    /// decompression threads, all cores by default
+    #[structopt(short, long)]
+    threads: Option<usize>,
+}
+
+fn run(opt: Opt) -> Result<(), ImportError> {
+    let mut importer = ImageImporter::new(opt.src).progress(|p| {
+        eprint!(
+            "\r{:3}% {} MiB",
+            p.read * 100 / p.compressed_size.max(1),
+            p.written >> 20
+        )
+    });
+    if let Some(threads) = opt.threads {
+        importer = importer.threads(threads);
+    }
+    let report = importer.import(opt.dst)?;
+    eprintln!();
+    println!(
+        "{}: {} image of {} bytes, {} bytes allocated",
+        report.path.display(),
+        report.compression,
+        report.size,
+        report.allocated
+    );
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run(Opt::from_args()) {
+        eprintln!("imageimport: {}", err);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,101 @@
+extern crate virtualization_rs;
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+use virtualization_rs::disk::store::{ImageStore, StoreError};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "imagestore")]
+struct Opt {
+    /// directory of the image store
+    #[structopt(short, long, parse(from_os_str), default_value = "images")]
+    store: PathBuf,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// adds a raw disk image to the store, optionally tagging it
+    Import {
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+        #[structopt(short, long)]
+        tag: Option<String>,
+    },
+    /// points a tag at an image
+    Tag { name: String, image: String },
+    /// removes a tag
+    Untag { name: String },
+    /// lists the images and their tags
+    List,
+    /// clones an image into a writable disk of a virtual machine
+    Checkout {
+        vm: String,
+        image: String,
+        #[structopt(parse(from_os_str))]
+        path: PathBuf,
+    },
+    /// drops the references of a virtual machine
+    Untrack { vm: String },
+    /// deletes the images that are neither tagged nor referenced
+    Gc,
+}
+
+fn run(opt: Opt) -> Result<(), StoreError> {
+    let store = ImageStore::open(opt.store)?;
+    match opt.command {
+        Command::Import { path, tag } => {
+            let digest = store.import(path)?;
+            if let Some(tag) = tag {
+                store.tag(&tag, &digest.to_string())?;
+            }
+            println!("{}", digest);
+        }
+        Command::Tag { name, image } => {
+            store.tag(&name, &image)?;
+        }
+        Command::Untag { name } => store.untag(&name)?,
+        Command::List => {
+            let references = store.references()?;
+            for image in store.images()? {
+                let vms: Vec<&str> = references
+                    .iter()
+                    .filter(|r| r.digest == image.digest)
+                    .map(|r| r.vm.as_str())
+                    .collect();
+                println!(
+                    "{} {:>12} tags: {} vms: {}",
+                    image.digest,
+                    image.size,
+                    image.tags.join(","),
+                    vms.join(",")
+                );
+            }
+        }
+        Command::Checkout { vm, image, path } => {
+            let disk = store.checkout(&vm, &image, path)?;
+            println!("{} ({:?})", disk.path.display(), disk.method);
+        }
+        Command::Untrack { vm } => store.untrack(&vm)?,
+        Command::Gc => {
+            let report = store.gc()?;
+            for reference in &report.stale_references {
+                println!("dropped {} of {}", reference.path.display(), reference.vm);
+            }
+            for digest in &report.removed {
+                println!("removed {}", digest);
+            }
+            println!("freed {} bytes", report.freed);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run(Opt::from_args()) {
+        eprintln!("imagestore: {}", err);
+        std::process::exit(1);
+    }
+}
@@ -1,10 +1,16 @@
 extern crate virtualization_rs;
 
+#[cfg(target_os = "macos")]
 use block::{Block, ConcreteBlock};
+#[cfg(target_os = "macos")]
 use libc::sleep;
+#[cfg(target_os = "macos")]
 use objc::rc::StrongPtr;
+#[cfg(target_os = "macos")]
 use std::fs::canonicalize;
+#[cfg(target_os = "macos")]
 use std::sync::{Arc, RwLock};
+#[cfg(target_os = "macos")]
 use virtualization_rs::{
     base::{dispatch_async, dispatch_queue_create, Id, NSError, NSFileHandle, NIL},
     virtualization::{
@@ -24,9 +30,12 @@ use virtualization_rs::{
     },
 };
 
+#[cfg(target_os = "macos")]
 use std::path::PathBuf;
+#[cfg(target_os = "macos")]
 use structopt::StructOpt;
 
+#[cfg(target_os = "macos")]
 #[derive(StructOpt, Debug)]
 #[structopt(name = "simplevm")]
 struct Opt {
@@ -49,6 +58,12 @@ struct Opt {
     memory_size: usize,
 }
 
+#[cfg(not(target_os = "macos"))]
+fn main() {
+    println!("simplevm requires macOS");
+}
+
+#[cfg(target_os = "macos")]
 fn main() {
     let opt = Opt::from_args();
 
@@ -106,7 +121,7 @@ fn main() {
         {
             Ok(x) => x,
             Err(err) => {
-                err.dump();
+                eprintln!("{}", err);
                 return;
             }
         };
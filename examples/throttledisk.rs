@@ -0,0 +1,80 @@
+extern crate virtualization_rs;
+
+use std::io;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use structopt::StructOpt;
+use virtualization_rs::nbd::backend::FileBackend;
+use virtualization_rs::nbd::metered::{IoLimits, MeteredBackend, OpStats};
+use virtualization_rs::nbd::server::{NbdExport, NbdServerBuilder};
+use virtualization_rs::nbd::unix_url;
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "throttledisk")]
+struct Opt {
+    #[structopt(parse(from_os_str))]
+    image: PathBuf,
+
+    #[structopt(parse(from_os_str))]
+    socket: PathBuf,
+
+    /// operations per second
+    #[structopt(long)]
+    iops: Option<u64>,
+
+    /// bytes per second
+    #[structopt(long)]
+    bandwidth: Option<u64>,
+
+    /// seconds between statistics lines
+    #[structopt(long, default_value = "10")]
+    interval: u64,
+}
+
+fn describe(name: &str, stats: &OpStats) -> String {
+    let micros = |d: Option<Duration>| d.map(|d| d.as_micros()).unwrap_or(0);
+    format!(
+        "{} {} ops {} bytes mean {}us p99 <{}us",
+        name,
+        stats.ops,
+        stats.bytes,
+        micros(stats.mean_latency()),
+        micros(stats.latency.quantile(0.99))
+    )
+}
+
+fn run(opt: Opt) -> io::Result<()> {
+    let disk = MeteredBackend::new(FileBackend::open(&opt.image, false)?).limits(IoLimits {
+        iops: opt.iops,
+        bandwidth: opt.bandwidth,
+    });
+    let meter = disk.meter();
+    let interval = Duration::from_secs(opt.interval.max(1));
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let stats = meter.stats();
+        eprintln!(
+            "{}, {}, throttled {} ops for {}ms",
+            describe("read", &stats.read),
+            describe("write", &stats.write),
+            stats.throttled_ops,
+            stats.throttled_time.as_millis()
+        );
+    });
+
+    let server = NbdServerBuilder::new()
+        .export(NbdExport::new("disk", disk))
+        .build();
+    let listener = UnixListener::bind(&opt.socket)?;
+    println!("{}", unix_url(&opt.socket, "disk"));
+    server.serve_unix(listener)
+}
+
+fn main() {
+    if let Err(err) = run(Opt::from_args()) {
+        eprintln!("throttledisk: {}", err);
+        std::process::exit(1);
+    }
+}
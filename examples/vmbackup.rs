@@ -0,0 +1,123 @@
+extern crate virtualization_rs;
+
+use std::path::PathBuf;
+use structopt::StructOpt;
+use virtualization_rs::definition::{
+    StorageDeviceDefinition, VZVirtualMachineState, VirtualMachineDefinition,
+    VirtualMachineLifecycle,
+};
+use virtualization_rs::disk::backup::{BackupError, BackupRepository, Chunking};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "vmbackup")]
+struct Opt {
+    /// backup repository, created when missing
+    #[structopt(parse(from_os_str))]
+    repository: PathBuf,
+
+    #[structopt(subcommand)]
+    command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// backs up the disks of a virtual machine that is not running
+    Backup {
+        name: String,
+        #[structopt(parse(from_os_str), required = true)]
+        disks: Vec<PathBuf>,
+        /// fixed chunks of this many KiB instead of content-defined ones
+        #[structopt(long)]
+        fixed_kib: Option<u32>,
+    },
+    /// lists the snapshots
+    List,
+    /// restores the disks of a snapshot into a directory
+    Restore {
+        id: String,
+        #[structopt(parse(from_os_str))]
+        dir: PathBuf,
+    },
+    /// deletes a snapshot
+    Forget { id: String },
+    /// frees the chunks of deleted snapshots
+    Gc,
+}
+
+/// stand-in for the virtual machine, which runs in another process if at all
+///
+/// The state of a virtual machine of another process cannot be seen from
+/// here, so it is reported as stopped and the state check of `backup` is
+/// skipped. The shared locks `backup` takes on the disks still make it fail
+/// with `BackupError::Lock` while a virtual machine has them attached
+/// writable.
+struct Offline;
+
+impl VirtualMachineLifecycle for Offline {
+    fn state(&self) -> VZVirtualMachineState {
+        VZVirtualMachineState::VZVirtualMachineStateStopped
+    }
+}
+
+fn run(opt: Opt) -> Result<(), BackupError> {
+    let repository = BackupRepository::open(opt.repository)?;
+    match opt.command {
+        Command::Backup {
+            name,
+            disks,
+            fixed_kib,
+        } => {
+            let mut definition = VirtualMachineDefinition::new(&name);
+            for disk in disks {
+                definition
+                    .storage_devices
+                    .push(StorageDeviceDefinition::new(disk, false));
+            }
+            let repository = match fixed_kib {
+                Some(kib) => repository.chunking(Chunking::Fixed(kib << 10)),
+                None => repository,
+            };
+            let report = repository.backup(&definition, &Offline)?;
+            println!(
+                "{}: scanned {} bytes, stored {} new chunks in {} bytes, {} unchanged disks",
+                report.snapshot.id,
+                report.scanned,
+                report.new_chunks,
+                report.new_bytes,
+                report.unchanged_disks
+            );
+        }
+        Command::List => {
+            for snapshot in repository.snapshots()? {
+                let size: u64 = snapshot.disks.iter().map(|d| d.data_size()).sum();
+                println!(
+                    "{}\t{} disks\t{} bytes of data",
+                    snapshot.id,
+                    snapshot.disks.len(),
+                    size
+                );
+            }
+        }
+        Command::Restore { id, dir } => {
+            for path in repository.restore(&id, dir)? {
+                println!("{}", path.display());
+            }
+        }
+        Command::Forget { id } => repository.remove_snapshot(&id)?,
+        Command::Gc => {
+            let report = repository.gc()?;
+            println!(
+                "removed {} chunks, freed {} bytes",
+                report.removed_chunks, report.freed
+            );
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    if let Err(err) = run(Opt::from_args()) {
+        eprintln!("vmbackup: {}", err);
+        std::process::exit(1);
+    }
+}
@@ -1,6 +1,7 @@
 //! base module
 
 use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
 use std::slice;
 use std::str;
 
@@ -137,6 +138,16 @@ impl NSFileHandle {
         }
     }
 
+    /// wraps `fd`, closing it on dealloc if `close_on_dealloc`
+    pub fn init_with_file_descriptor(fd: RawFd, close_on_dealloc: bool) -> NSFileHandle {
+        unsafe {
+            let close = if close_on_dealloc { YES } else { NO };
+            let obj: Id = msg_send![class!(NSFileHandle), alloc];
+            let p = StrongPtr::new(msg_send![obj, initWithFileDescriptor:fd closeOnDealloc:close]);
+            NSFileHandle(p)
+        }
+    }
+
     pub fn file_handle_with_standard_input() -> NSFileHandle {
         unsafe {
             let p = StrongPtr::retain(msg_send![class!(NSFileHandle), fileHandleWithStandardInput]);
@@ -0,0 +1,160 @@
+//! cloud-init module
+
+use crate::fs::iso9660::IsoImageBuilder;
+
+#[cfg(target_os = "macos")]
+use crate::virtualization::storage_device::{
+    DiskImageAttachmentError, VZDiskImageStorageDeviceAttachment,
+    VZDiskImageStorageDeviceAttachmentBuilder,
+};
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// volume label cloud-init looks for on a NoCloud seed
+pub const NOCLOUD_VOLUME_ID: &str = "cidata";
+
+/// error of `NoCloudSeedBuilder`
+#[derive(Debug)]
+pub enum CloudInitError {
+    Io(io::Error),
+    #[cfg(target_os = "macos")]
+    Attachment(DiskImageAttachmentError),
+}
+
+impl fmt::Display for CloudInitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloudInitError::Io(err) => write!(f, "{}", err),
+            #[cfg(target_os = "macos")]
+            CloudInitError::Attachment(err) => write!(f, "failed to attach seed image: {}", err),
+        }
+    }
+}
+
+impl error::Error for CloudInitError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            CloudInitError::Io(err) => Some(err),
+            #[cfg(target_os = "macos")]
+            CloudInitError::Attachment(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for CloudInitError {
+    fn from(err: io::Error) -> Self {
+        CloudInitError::Io(err)
+    }
+}
+
+/// builder for NoCloud seed images
+///
+/// The seed is an ISO 9660 image labeled `cidata` that cloud-init reads on
+/// first boot. `meta-data` is always written, empty unless given.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::cloud_init::NoCloudSeedBuilder;
+/// # fn main() -> Result<(), virtualization_rs::cloud_init::CloudInitError> {
+/// NoCloudSeedBuilder::new()
+///     .meta_data("instance-id: vm1\nlocal-hostname: vm1\n")
+///     .user_data("#cloud-config\npassword: ubuntu\nchpasswd: { expire: false }\n")
+///     .write_to_file("seed.iso")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct NoCloudSeedBuilder {
+    user_data: Option<Vec<u8>>,
+    meta_data: Vec<u8>,
+    network_config: Option<Vec<u8>>,
+    vendor_data: Option<Vec<u8>>,
+    modified: Option<SystemTime>,
+}
+
+impl NoCloudSeedBuilder {
+    pub fn new() -> Self {
+        NoCloudSeedBuilder {
+            user_data: None,
+            meta_data: Vec::new(),
+            network_config: None,
+            vendor_data: None,
+            modified: None,
+        }
+    }
+
+    pub fn user_data<T: Into<Vec<u8>>>(mut self, user_data: T) -> Self {
+        self.user_data = Some(user_data.into());
+        self
+    }
+
+    pub fn meta_data<T: Into<Vec<u8>>>(mut self, meta_data: T) -> Self {
+        self.meta_data = meta_data.into();
+        self
+    }
+
+    pub fn network_config<T: Into<Vec<u8>>>(mut self, network_config: T) -> Self {
+        self.network_config = Some(network_config.into());
+        self
+    }
+
+    pub fn vendor_data<T: Into<Vec<u8>>>(mut self, vendor_data: T) -> Self {
+        self.vendor_data = Some(vendor_data.into());
+        self
+    }
+
+    /// timestamp recorded in the image, the current time by default
+    pub fn modified(mut self, modified: SystemTime) -> Self {
+        self.modified = Some(modified);
+        self
+    }
+
+    fn image(&self) -> IsoImageBuilder {
+        let mut image = IsoImageBuilder::new()
+            .volume_id(NOCLOUD_VOLUME_ID)
+            .file("meta-data", self.meta_data.clone());
+        if let Some(modified) = self.modified {
+            image = image.modified(modified);
+        }
+        let optional = [
+            ("user-data", &self.user_data),
+            ("network-config", &self.network_config),
+            ("vendor-data", &self.vendor_data),
+        ];
+        for (name, data) in optional.iter() {
+            if let Some(data) = data {
+                image = image.file(name, data.clone());
+            }
+        }
+        image
+    }
+
+    /// writes the seed image to `path`
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), CloudInitError> {
+        self.image().write_to_file(path)?;
+        Ok(())
+    }
+
+    /// writes the seed image to `path` and opens it as a read-only attachment
+    #[cfg(target_os = "macos")]
+    pub fn build<P: AsRef<Path>>(
+        &self,
+        path: P,
+    ) -> Result<VZDiskImageStorageDeviceAttachment, CloudInitError> {
+        self.write_to_file(&path)?;
+        let path = path.as_ref().canonicalize()?;
+        VZDiskImageStorageDeviceAttachmentBuilder::new()
+            .path(path.to_string_lossy())
+            .read_only(true)
+            .build()
+            .map_err(CloudInitError::Attachment)
+    }
+}
+
+impl Default for NoCloudSeedBuilder {
+    fn default() -> Self {
+        NoCloudSeedBuilder::new()
+    }
+}
@@ -0,0 +1,289 @@
+//! virtual machine definition module
+//!
+//! Platform independent description of a virtual machine. The tooling in
+//! `disk` works on these definitions so that it can be used without
+//! Virtualization.framework.
+
+use crate::disk::store::Digest;
+
+use std::error;
+use std::fmt;
+use std::path::PathBuf;
+
+/// definition of a virtual machine
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VirtualMachineDefinition {
+    pub name: String,
+    pub cpu_count: usize,
+    pub memory_size: usize,
+    pub kernel: PathBuf,
+    pub initial_ramdisk: PathBuf,
+    pub command_line: String,
+    pub storage_devices: Vec<StorageDeviceDefinition>,
+}
+
+impl VirtualMachineDefinition {
+    pub fn new<T: Into<String>>(name: T) -> VirtualMachineDefinition {
+        VirtualMachineDefinition {
+            name: name.into(),
+            ..Default::default()
+        }
+    }
+}
+
+/// definition of a disk image attached through the Virtio interface
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageDeviceDefinition {
+    pub path: PathBuf,
+    pub read_only: bool,
+    /// expected SHA-256 of the image, checked before it is attached
+    ///
+    /// Only useful for read-only images, since the guest changes the
+    /// contents of writable ones.
+    pub digest: Option<Digest>,
+    pub caching_mode: VZDiskImageCachingMode,
+    pub synchronization_mode: VZDiskImageSynchronizationMode,
+}
+
+impl StorageDeviceDefinition {
+    pub fn new<T: Into<PathBuf>>(path: T, read_only: bool) -> StorageDeviceDefinition {
+        StorageDeviceDefinition {
+            path: path.into(),
+            read_only,
+            digest: None,
+            caching_mode: VZDiskImageCachingMode::default(),
+            synchronization_mode: VZDiskImageSynchronizationMode::default(),
+        }
+    }
+
+    pub fn digest(mut self, digest: Digest) -> Self {
+        self.digest = Some(digest);
+        self
+    }
+
+    pub fn caching_mode(mut self, caching_mode: VZDiskImageCachingMode) -> Self {
+        self.caching_mode = caching_mode;
+        self
+    }
+
+    pub fn synchronization_mode(
+        mut self,
+        synchronization_mode: VZDiskImageSynchronizationMode,
+    ) -> Self {
+        self.synchronization_mode = synchronization_mode;
+        self
+    }
+
+    /// checks that this host can attach the disk with its caching and
+    /// synchronization modes, see `validate_disk_image_modes`
+    pub fn validate(&self) -> Result<(), DiskModeError> {
+        validate_disk_image_modes(
+            self.caching_mode,
+            self.synchronization_mode,
+            disk_image_modes_supported(),
+        )
+    }
+}
+
+/// how the host caches the data of a disk image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VZDiskImageCachingMode {
+    /// The framework chooses.
+    #[default]
+    VZDiskImageCachingModeAutomatic = 0,
+
+    /// I/O bypasses the page cache of the host.
+    VZDiskImageCachingModeUncached = 1,
+
+    /// I/O goes through the page cache of the host.
+    VZDiskImageCachingModeCached = 2,
+}
+
+impl VZDiskImageCachingMode {
+    /// `automatic`, `uncached` or `cached`
+    pub fn name(&self) -> &'static str {
+        match self {
+            VZDiskImageCachingMode::VZDiskImageCachingModeAutomatic => "automatic",
+            VZDiskImageCachingMode::VZDiskImageCachingModeUncached => "uncached",
+            VZDiskImageCachingMode::VZDiskImageCachingModeCached => "cached",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<VZDiskImageCachingMode> {
+        match name {
+            "automatic" => Some(VZDiskImageCachingMode::VZDiskImageCachingModeAutomatic),
+            "uncached" => Some(VZDiskImageCachingMode::VZDiskImageCachingModeUncached),
+            "cached" => Some(VZDiskImageCachingMode::VZDiskImageCachingModeCached),
+            _ => None,
+        }
+    }
+}
+
+/// how flushes of the guest reach the disk image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VZDiskImageSynchronizationMode {
+    /// Flushes wait until the data is on permanent storage (`F_FULLFSYNC`).
+    #[default]
+    VZDiskImageSynchronizationModeFull = 1,
+
+    /// Flushes wait for `fsync`, which may leave the data in the cache of the drive.
+    VZDiskImageSynchronizationModeFsync = 2,
+
+    /// Flushes are ignored; data written by the guest may be lost when the host crashes.
+    VZDiskImageSynchronizationModeNone = 3,
+}
+
+impl VZDiskImageSynchronizationMode {
+    /// `full`, `fsync` or `none`
+    pub fn name(&self) -> &'static str {
+        match self {
+            VZDiskImageSynchronizationMode::VZDiskImageSynchronizationModeFull => "full",
+            VZDiskImageSynchronizationMode::VZDiskImageSynchronizationModeFsync => "fsync",
+            VZDiskImageSynchronizationMode::VZDiskImageSynchronizationModeNone => "none",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<VZDiskImageSynchronizationMode> {
+        match name {
+            "full" => Some(VZDiskImageSynchronizationMode::VZDiskImageSynchronizationModeFull),
+            "fsync" => Some(VZDiskImageSynchronizationMode::VZDiskImageSynchronizationModeFsync),
+            "none" => Some(VZDiskImageSynchronizationMode::VZDiskImageSynchronizationModeNone),
+            _ => None,
+        }
+    }
+}
+
+/// how flushes of the guest reach a network block device
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VZDiskSynchronizationMode {
+    /// Flushes are sent to the server.
+    #[default]
+    VZDiskSynchronizationModeFull = 0,
+
+    /// Flushes are ignored.
+    VZDiskSynchronizationModeNone = 1,
+}
+
+/// error of the caching and synchronization modes of a disk image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiskModeError {
+    /// the installed Virtualization.framework has no caching and
+    /// synchronization modes, which need macOS 12
+    Unavailable,
+}
+
+impl fmt::Display for DiskModeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiskModeError::Unavailable => write!(
+                f,
+                "caching and synchronization modes need macOS 12 or later"
+            ),
+        }
+    }
+}
+
+impl error::Error for DiskModeError {}
+
+/// checks that a disk image may be attached with these modes by a
+/// Virtualization.framework that has caching and synchronization modes
+/// when `modes_supported` is true
+///
+/// The defaults are always accepted, other modes only when they are
+/// supported.
+/// # Examples
+/// ```rust
+/// # use virtualization_rs::definition::*;
+/// let cached = VZDiskImageCachingMode::VZDiskImageCachingModeCached;
+/// let full = VZDiskImageSynchronizationMode::VZDiskImageSynchronizationModeFull;
+/// assert!(validate_disk_image_modes(cached, full, true).is_ok());
+/// assert_eq!(
+///     validate_disk_image_modes(cached, full, false),
+///     Err(DiskModeError::Unavailable)
+/// );
+/// ```
+pub fn validate_disk_image_modes(
+    caching_mode: VZDiskImageCachingMode,
+    synchronization_mode: VZDiskImageSynchronizationMode,
+    modes_supported: bool,
+) -> Result<(), DiskModeError> {
+    let defaults = caching_mode == VZDiskImageCachingMode::default()
+        && synchronization_mode == VZDiskImageSynchronizationMode::default();
+    if defaults || modes_supported {
+        Ok(())
+    } else {
+        Err(DiskModeError::Unavailable)
+    }
+}
+
+/// whether this host supports caching and synchronization modes
+///
+/// Without Virtualization.framework definitions are only stored and moved
+/// around, so every mode is taken as supported there.
+pub fn disk_image_modes_supported() -> bool {
+    #[cfg(target_os = "macos")]
+    {
+        crate::virtualization::storage_device::VZDiskImageStorageDeviceAttachment::modes_supported()
+    }
+    #[cfg(not(target_os = "macos"))]
+    {
+        true
+    }
+}
+
+/// state of virtual machine
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VZVirtualMachineState {
+    /// Initial state before the virtual machine is started.
+    VZVirtualMachineStateStopped,
+
+    /// Running virtual machine.
+    VZVirtualMachineStateRunning,
+
+    /// A started virtual machine is paused. This state can only be transitioned from VZVirtualMachineStatePausing.
+    VZVirtualMachineStatePaused,
+
+    /// The virtual machine has encountered an internal error.
+    VZVirtualMachineStateError,
+
+    /// The virtual machine is configuring the hardware and starting.
+    VZVirtualMachineStateStarting,
+
+    /// The virtual machine is being paused. This is the intermediate state between VZVirtualMachineStateRunning and VZVirtualMachineStatePaused.
+    VZVirtualMachineStatePausing,
+
+    /// The virtual machine is being resumed. This is the intermediate state between VZVirtualMachineStatePaused and VZVirtualMachineStateRunning. */
+    VZVirtualMachineStateResuming,
+
+    /// Other
+    Other,
+}
+
+impl VZVirtualMachineState {
+    /// true when the disks of the virtual machine are not in use
+    pub fn is_stopped(&self) -> bool {
+        matches!(
+            self,
+            VZVirtualMachineState::VZVirtualMachineStateStopped
+                | VZVirtualMachineState::VZVirtualMachineStateError
+        )
+    }
+}
+
+/// common behaviors for things that know the state of a virtual machine
+///
+/// Implemented by `VZVirtualMachine`, which reports its live state.
+pub trait VirtualMachineLifecycle {
+    fn state(&self) -> VZVirtualMachineState;
+}
+
+/// virtual machine that is always stopped, for the examples of the
+/// documentation
+#[doc(hidden)]
+pub struct StoppedVm;
+
+impl VirtualMachineLifecycle for StoppedVm {
+    fn state(&self) -> VZVirtualMachineState {
+        VZVirtualMachineState::VZVirtualMachineStateStopped
+    }
+}
@@ -0,0 +1,823 @@
+//! backup module
+
+use crate::definition::{VZVirtualMachineState, VirtualMachineDefinition, VirtualMachineLifecycle};
+use crate::disk::lock::{DiskImageLock, DiskImageLockError};
+use crate::disk::sparse::{data_ranges, is_zero};
+use crate::disk::store::Digest;
+use crate::fs::civil_from_unix;
+use crate::util::{create_with, write_atomic};
+
+use sha2::{Digest as _, Sha256};
+
+use std::collections::{BTreeSet, HashSet};
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const SNAPSHOT_VERSION: &str = "snapshot 1";
+const COMPRESSION_LEVEL: i32 = 3;
+const READ_SIZE: usize = 1 << 20;
+
+/// random values of the gear hash used for content-defined chunking
+const GEAR: [u64; 256] = gear_table();
+
+/// error of `BackupRepository`
+#[derive(Debug)]
+pub enum BackupError {
+    Io(io::Error),
+    /// the virtual machine is neither stopped nor paused
+    NotQuiesced(VZVirtualMachineState),
+    /// a disk is attached to a running virtual machine
+    Lock(DiskImageLockError),
+    /// a virtual machine name that cannot be used in a snapshot name
+    InvalidName(String),
+    /// no snapshot of that name
+    NotFound(String),
+    /// the manifest of a snapshot is damaged
+    InvalidSnapshot(String),
+    /// a stored chunk does not have the contents its name promises
+    CorruptChunk(Digest),
+    /// the restore destination already exists
+    AlreadyExists(PathBuf),
+}
+
+impl fmt::Display for BackupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BackupError::Io(err) => write!(f, "{}", err),
+            BackupError::NotQuiesced(state) => {
+                write!(
+                    f,
+                    "virtual machine is neither stopped nor paused ({:?})",
+                    state
+                )
+            }
+            BackupError::Lock(err) => write!(f, "{}", err),
+            BackupError::InvalidName(name) => write!(f, "invalid name {:?}", name),
+            BackupError::NotFound(id) => write!(f, "snapshot {} not found", id),
+            BackupError::InvalidSnapshot(id) => write!(f, "snapshot {} is damaged", id),
+            BackupError::CorruptChunk(digest) => write!(f, "chunk {} is corrupt", digest),
+            BackupError::AlreadyExists(path) => write!(f, "{} already exists", path.display()),
+        }
+    }
+}
+
+impl error::Error for BackupError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            BackupError::Io(err) => Some(err),
+            BackupError::Lock(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BackupError {
+    fn from(err: io::Error) -> Self {
+        BackupError::Io(err)
+    }
+}
+
+impl From<DiskImageLockError> for BackupError {
+    fn from(err: DiskImageLockError) -> Self {
+        BackupError::Lock(err)
+    }
+}
+
+/// how disks are split into chunks
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chunking {
+    /// chunks of this many bytes at multiples of it
+    Fixed(u32),
+    /// chunk boundaries chosen by a rolling hash of the contents, so that
+    /// data moved within a disk still deduplicates; `avg` must be a power of
+    /// two, at least 2, between `min` and `max`
+    ContentDefined { min: u32, avg: u32, max: u32 },
+}
+
+impl Default for Chunking {
+    /// content-defined chunks of 256 KiB to 4 MiB, 1 MiB on average
+    fn default() -> Self {
+        Chunking::ContentDefined {
+            min: 256 << 10,
+            avg: 1 << 20,
+            max: 4 << 20,
+        }
+    }
+}
+
+impl Chunking {
+    fn check(&self) -> io::Result<()> {
+        let valid = match *self {
+            Chunking::Fixed(size) => size > 0,
+            Chunking::ContentDefined { min, avg, max } => {
+                min > 0 && min <= avg && avg <= max && avg >= 2 && avg.is_power_of_two()
+            }
+        };
+        if valid {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid chunking {:?}", self),
+            ))
+        }
+    }
+
+    fn format(&self) -> String {
+        match self {
+            Chunking::Fixed(size) => format!("fixed {}", size),
+            Chunking::ContentDefined { min, avg, max } => format!("cdc {} {} {}", min, avg, max),
+        }
+    }
+
+    fn parse(s: &str) -> Option<Chunking> {
+        let fields: Vec<&str> = s.split(' ').collect();
+        match fields.as_slice() {
+            ["fixed", size] => Some(Chunking::Fixed(size.parse().ok()?)),
+            ["cdc", min, avg, max] => Some(Chunking::ContentDefined {
+                min: min.parse().ok()?,
+                avg: avg.parse().ok()?,
+                max: max.parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// point-in-time copy of the disks of a virtual machine
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    /// `<vm>-<UTC time>`, e.g. `web-20240501T020000Z`
+    pub id: String,
+    pub vm: String,
+    /// seconds since the unix epoch
+    pub time: u64,
+    pub chunking: Chunking,
+    pub disks: Vec<SnapshotDisk>,
+}
+
+/// disk of a snapshot
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SnapshotDisk {
+    /// path of the disk when it was backed up
+    pub path: PathBuf,
+    pub read_only: bool,
+    pub size: u64,
+    /// chunks holding data, by offset; everything else is zeros
+    pub chunks: Vec<ChunkRef>,
+    /// identity of the file version that was read, when it did not change
+    /// while it was read
+    version: Option<FileVersion>,
+}
+
+impl SnapshotDisk {
+    /// bytes of the disk that are not zeros
+    pub fn data_size(&self) -> u64 {
+        self.chunks.iter().map(|chunk| chunk.len).sum()
+    }
+}
+
+/// chunk of a disk in a snapshot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkRef {
+    pub offset: u64,
+    pub len: u64,
+    /// SHA-256 of the uncompressed contents
+    pub digest: Digest,
+}
+
+/// result of `BackupRepository::backup`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupReport {
+    pub snapshot: Snapshot,
+    /// bytes of data read and hashed
+    pub scanned: u64,
+    /// chunks that were not in the repository yet
+    pub new_chunks: u64,
+    /// compressed bytes written for the new chunks
+    pub new_bytes: u64,
+    /// disks taken over from the previous snapshot without being read
+    pub unchanged_disks: usize,
+}
+
+/// result of `BackupRepository::gc`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BackupGcReport {
+    /// chunks no snapshot refers to any more
+    pub removed_chunks: u64,
+    /// bytes of storage released
+    pub freed: u64,
+}
+
+/// device, inode, size and modification time of a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct FileVersion {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl FileVersion {
+    fn of(metadata: &fs::Metadata) -> FileVersion {
+        FileVersion {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        }
+    }
+}
+
+/// repository of incremental, deduplicated backups of virtual machine disks
+///
+/// Disks are split into chunks that are stored once, compressed with zstd
+/// and named after the SHA-256 digest of their contents, so a backup only
+/// writes the chunks that changed since any earlier one. Holes and chunks
+/// of zeros are not stored at all. A disk whose file has not changed since
+/// the previous snapshot of the virtual machine is not even read.
+///
+/// Every backup is a snapshot that can be restored on its own into sparse
+/// raw images. `remove_snapshot` and `gc` free the chunks of old ones.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::definition::*;
+/// # use virtualization_rs::disk::backup::BackupRepository;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let mut definition = VirtualMachineDefinition::new("web");
+/// definition.storage_devices.push(StorageDeviceDefinition::new("web.img", false));
+///
+/// let repository = BackupRepository::open("/var/backups/vms")?;
+/// # let vm = StoppedVm;
+/// // `vm` is the stopped or paused `VZVirtualMachine`
+/// let report = repository.backup(&definition, &vm)?;
+/// println!("{}: {} new bytes", report.snapshot.id, report.new_bytes);
+///
+/// repository.restore(&report.snapshot.id, "/tmp/web-restored")?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct BackupRepository {
+    dir: PathBuf,
+    chunking: Chunking,
+}
+
+impl BackupRepository {
+    /// opens the repository at `dir`, creating it when it does not exist
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<BackupRepository, BackupError> {
+        let repository = BackupRepository {
+            dir: dir.into(),
+            chunking: Chunking::default(),
+        };
+        for dir in &[repository.chunk_dir(), repository.snapshot_dir()] {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(repository)
+    }
+
+    /// chunking of new backups
+    ///
+    /// Chunks only deduplicate against backups made with the same chunking.
+    pub fn chunking(mut self, chunking: Chunking) -> Self {
+        self.chunking = chunking;
+        self
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// backs up every disk of the definition as a new snapshot
+    ///
+    /// The virtual machine must be stopped or paused. Disks of a stopped one
+    /// are locked shared while they are read, so they cannot be attached
+    /// writable meanwhile. A paused virtual machine holds the locks itself;
+    /// its snapshot is crash-consistent, as writes the guest has not flushed
+    /// yet are missing.
+    pub fn backup<L: VirtualMachineLifecycle>(
+        &self,
+        definition: &VirtualMachineDefinition,
+        vm: &L,
+    ) -> Result<BackupReport, BackupError> {
+        let state = vm.state();
+        let paused = state == VZVirtualMachineState::VZVirtualMachineStatePaused;
+        if !state.is_stopped() && !paused {
+            return Err(BackupError::NotQuiesced(state));
+        }
+        check_name(&definition.name)?;
+        self.chunking.check()?;
+
+        let _lock = self.lock()?;
+        let _disk_locks = if paused {
+            Vec::new()
+        } else {
+            definition
+                .storage_devices
+                .iter()
+                .map(|disk| DiskImageLock::shared(&disk.path))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let previous = self
+            .snapshots()?
+            .into_iter()
+            .filter(|s| s.vm == definition.name && s.chunking == self.chunking)
+            .max_by_key(|s| s.time);
+
+        let mut scanned = 0;
+        let mut new_chunks = 0;
+        let mut new_bytes = 0;
+        let mut unchanged_disks = 0;
+        let mut synced_dirs = BTreeSet::new();
+        let mut disks = Vec::new();
+        for disk in &definition.storage_devices {
+            let file = File::open(&disk.path)?;
+            let before = FileVersion::of(&file.metadata()?);
+            let unchanged = previous.as_ref().and_then(|previous| {
+                previous.disks.iter().find(|d| {
+                    d.path == disk.path
+                        && d.version == Some(before)
+                        && d.chunks.iter().all(|c| self.chunk_path(&c.digest).exists())
+                })
+            });
+            if let Some(unchanged) = unchanged {
+                unchanged_disks += 1;
+                disks.push(SnapshotDisk {
+                    read_only: disk.read_only,
+                    ..unchanged.clone()
+                });
+                continue;
+            }
+
+            let mut chunks = Vec::new();
+            for_each_chunk(&file, before.size, self.chunking, |offset, data| {
+                scanned += data.len() as u64;
+                if is_zero(data) {
+                    return Ok(());
+                }
+                let digest = Digest::from(<[u8; 32]>::from(Sha256::digest(data)));
+                if let Some(stored) = self.store_chunk(&digest, data)? {
+                    new_chunks += 1;
+                    new_bytes += stored;
+                    if let Some(dir) = self.chunk_path(&digest).parent() {
+                        synced_dirs.insert(dir.to_path_buf());
+                    }
+                }
+                chunks.push(ChunkRef {
+                    offset,
+                    len: data.len() as u64,
+                    digest,
+                });
+                Ok(())
+            })?;
+            let after = FileVersion::of(&file.metadata()?);
+            disks.push(SnapshotDisk {
+                path: disk.path.clone(),
+                read_only: disk.read_only,
+                size: before.size,
+                chunks,
+                version: if after == before { Some(before) } else { None },
+            });
+        }
+        // the chunks must be durable before a manifest refers to them
+        for dir in synced_dirs {
+            File::open(dir)?.sync_all()?;
+        }
+
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let snapshot = Snapshot {
+            id: self.new_id(&definition.name, time),
+            vm: definition.name.clone(),
+            time,
+            chunking: self.chunking,
+            disks,
+        };
+        write_atomic(
+            &self.snapshot_dir().join(&snapshot.id),
+            format_snapshot(&snapshot).as_bytes(),
+        )?;
+        Ok(BackupReport {
+            snapshot,
+            scanned,
+            new_chunks,
+            new_bytes,
+            unchanged_disks,
+        })
+    }
+
+    /// snapshots in the repository, oldest first
+    pub fn snapshots(&self) -> Result<Vec<Snapshot>, BackupError> {
+        let mut snapshots = Vec::new();
+        for entry in fs::read_dir(self.snapshot_dir())? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if !name.starts_with('.') {
+                snapshots.push(self.snapshot(&name)?);
+            }
+        }
+        snapshots.sort_by(|a, b| (a.time, &a.id).cmp(&(b.time, &b.id)));
+        Ok(snapshots)
+    }
+
+    pub fn snapshot(&self, id: &str) -> Result<Snapshot, BackupError> {
+        check_name(id).map_err(|_| BackupError::NotFound(id.to_string()))?;
+        match fs::read_to_string(self.snapshot_dir().join(id)) {
+            Ok(text) => parse_snapshot(id, &text),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Err(BackupError::NotFound(id.to_string()))
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// restores every disk of snapshot `id` into the directory `dir`
+    ///
+    /// The images are named `<index>-<file name>` after the disks of the
+    /// definition that was backed up; their paths are returned in order.
+    pub fn restore<P: AsRef<Path>>(&self, id: &str, dir: P) -> Result<Vec<PathBuf>, BackupError> {
+        let snapshot = self.snapshot(id)?;
+        fs::create_dir_all(dir.as_ref())?;
+        let mut paths = Vec::new();
+        for (index, disk) in snapshot.disks.iter().enumerate() {
+            let name = disk
+                .path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let path = dir.as_ref().join(format!("{}-{}", index, name));
+            self.restore_snapshot_disk(disk, &path)?;
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+
+    /// restores disk `index` of snapshot `id` into a new sparse raw image
+    pub fn restore_disk<P: AsRef<Path>>(
+        &self,
+        id: &str,
+        index: usize,
+        dst: P,
+    ) -> Result<(), BackupError> {
+        let snapshot = self.snapshot(id)?;
+        let disk = snapshot
+            .disks
+            .get(index)
+            .ok_or_else(|| BackupError::NotFound(format!("{} disk {}", id, index)))?;
+        self.restore_snapshot_disk(disk, dst.as_ref())
+    }
+
+    /// deletes snapshot `id`; its chunks are freed by `gc`
+    pub fn remove_snapshot(&self, id: &str) -> Result<(), BackupError> {
+        check_name(id).map_err(|_| BackupError::NotFound(id.to_string()))?;
+        let _lock = self.lock()?;
+        match fs::remove_file(self.snapshot_dir().join(id)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Err(BackupError::NotFound(id.to_string()))
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// deletes the chunks that no snapshot refers to
+    pub fn gc(&self) -> Result<BackupGcReport, BackupError> {
+        let _lock = self.lock()?;
+        let live: HashSet<String> = self
+            .snapshots()?
+            .iter()
+            .flat_map(|s| s.disks.iter())
+            .flat_map(|d| d.chunks.iter())
+            .map(|c| c.digest.to_hex())
+            .collect();
+        let mut report = BackupGcReport::default();
+        for entry in fs::read_dir(self.chunk_dir())? {
+            let dir = entry?.path();
+            if !dir.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(&dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if live.contains(&name) {
+                    continue;
+                }
+                report.freed += entry.metadata()?.blocks() * 512;
+                // hidden files are chunks a crashed backup did not finish
+                if !name.starts_with('.') {
+                    report.removed_chunks += 1;
+                }
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(report)
+    }
+
+    fn restore_snapshot_disk(&self, disk: &SnapshotDisk, dst: &Path) -> Result<(), BackupError> {
+        if dst.exists() {
+            return Err(BackupError::AlreadyExists(dst.to_path_buf()));
+        }
+        match create_with(dst, |tmp| self.write_disk(disk, tmp)) {
+            Err(BackupError::Io(err)) if err.kind() == io::ErrorKind::AlreadyExists => {
+                Err(BackupError::AlreadyExists(dst.to_path_buf()))
+            }
+            result => result,
+        }
+    }
+
+    /// writes the chunks of `disk` into a new sparse file at `path`
+    fn write_disk(&self, disk: &SnapshotDisk, path: &Path) -> Result<(), BackupError> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        file.set_len(disk.size)?;
+        for chunk in &disk.chunks {
+            let data = self.read_chunk(&chunk.digest)?;
+            if data.len() as u64 != chunk.len || chunk.offset + chunk.len > disk.size {
+                return Err(BackupError::CorruptChunk(chunk.digest));
+            }
+            file.write_all_at(&data, chunk.offset)?;
+        }
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// reads and checks the chunk named `digest`
+    fn read_chunk(&self, digest: &Digest) -> Result<Vec<u8>, BackupError> {
+        let compressed = match fs::read(self.chunk_path(digest)) {
+            Ok(compressed) => compressed,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(BackupError::CorruptChunk(*digest))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let data =
+            zstd::decode_all(&compressed[..]).map_err(|_| BackupError::CorruptChunk(*digest))?;
+        if <[u8; 32]>::from(Sha256::digest(&data)) != *digest.as_bytes() {
+            return Err(BackupError::CorruptChunk(*digest));
+        }
+        Ok(data)
+    }
+
+    /// stores a chunk unless it is already present; returns the bytes
+    /// written for a new chunk
+    fn store_chunk(&self, digest: &Digest, data: &[u8]) -> io::Result<Option<u64>> {
+        let path = self.chunk_path(digest);
+        if path.exists() {
+            return Ok(None);
+        }
+        let compressed = zstd::bulk::compress(data, COMPRESSION_LEVEL)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // chunks are named after their contents, so a concurrent writer of
+        // the same chunk replaces it with the same bytes
+        write_atomic(&path, &compressed)?;
+        Ok(Some(compressed.len() as u64))
+    }
+
+    /// `<vm>-<UTC time>`, with a counter when that name is taken
+    fn new_id(&self, vm: &str, time: u64) -> String {
+        let (year, month, day, hour, minute, second) = civil_from_unix(time as i64);
+        let base = format!(
+            "{}-{:04}{:02}{:02}T{:02}{:02}{:02}Z",
+            vm, year, month, day, hour, minute, second
+        );
+        let mut id = base.clone();
+        let mut n = 1;
+        while self.snapshot_dir().join(&id).exists() {
+            id = format!("{}-{}", base, n);
+            n += 1;
+        }
+        id
+    }
+
+    fn chunk_dir(&self) -> PathBuf {
+        self.dir.join("chunks")
+    }
+
+    fn chunk_path(&self, digest: &Digest) -> PathBuf {
+        let hex = digest.to_hex();
+        self.chunk_dir().join(&hex[..2]).join(hex)
+    }
+
+    fn snapshot_dir(&self) -> PathBuf {
+        self.dir.join("snapshots")
+    }
+
+    /// takes the repository lock, which keeps `gc` from deleting the chunks
+    /// of a backup in progress
+    fn lock(&self) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.dir.join("lock"))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(file)
+    }
+}
+
+/// calls `f` with the offset and contents of every chunk of the data ranges
+/// of `file`
+fn for_each_chunk<F>(
+    file: &File,
+    size: u64,
+    chunking: Chunking,
+    mut f: F,
+) -> Result<(), BackupError>
+where
+    F: FnMut(u64, &[u8]) -> Result<(), BackupError>,
+{
+    for range in data_ranges(file, 0, size)? {
+        match chunking {
+            Chunking::Fixed(chunk_size) => {
+                let chunk_size = chunk_size as u64;
+                let mut buf = vec![0u8; chunk_size.min(range.end - range.start) as usize];
+                let mut pos = range.start;
+                while pos < range.end {
+                    let end = ((pos / chunk_size + 1) * chunk_size).min(range.end);
+                    let chunk = &mut buf[..(end - pos) as usize];
+                    file.read_exact_at(chunk, pos)?;
+                    f(pos, chunk)?;
+                    pos = end;
+                }
+            }
+            Chunking::ContentDefined { min, avg, max } => {
+                let (min, avg, max) = (min as usize, avg as usize, max as usize);
+                let mut buf: Vec<u8> = Vec::with_capacity(max + READ_SIZE);
+                let mut start = range.start;
+                let mut read_pos = range.start;
+                loop {
+                    while buf.len() < max && read_pos < range.end {
+                        let n = (range.end - read_pos).min(READ_SIZE as u64) as usize;
+                        let filled = buf.len();
+                        buf.resize(filled + n, 0);
+                        file.read_exact_at(&mut buf[filled..], read_pos)?;
+                        read_pos += n as u64;
+                    }
+                    if buf.is_empty() {
+                        break;
+                    }
+                    let cut = cut_point(&buf, min, avg, max);
+                    f(start, &buf[..cut])?;
+                    buf.drain(..cut);
+                    start += cut as u64;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// length of the next content-defined chunk at the start of `data`
+///
+/// `data` holds at least `max` bytes unless it is the end of the range.
+fn cut_point(data: &[u8], min: usize, avg: usize, max: usize) -> usize {
+    let end = data.len().min(max);
+    if end <= min {
+        return end;
+    }
+    // the gear hash depends on the last 64 bytes, its top bits are tested
+    let mask = !(!0u64 >> avg.trailing_zeros());
+    let mut hash = 0u64;
+    for (i, &b) in data[..end].iter().enumerate().skip(min) {
+        hash = (hash << 1).wrapping_add(GEAR[b as usize]);
+        if hash & mask == 0 {
+            return i + 1;
+        }
+    }
+    end
+}
+
+/// splitmix64 values, fixed so that chunk boundaries never change
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+fn format_snapshot(snapshot: &Snapshot) -> String {
+    let mut text = format!(
+        "{}\nvm {}\ntime {}\nchunking {}\n",
+        SNAPSHOT_VERSION,
+        snapshot.vm,
+        snapshot.time,
+        snapshot.chunking.format()
+    );
+    for disk in &snapshot.disks {
+        let version = match disk.version {
+            Some(v) => format!("{} {} {} {}", v.dev, v.ino, v.mtime, v.mtime_nsec),
+            None => "- - - -".to_string(),
+        };
+        text.push_str(&format!(
+            "disk {} {} {} {}\n",
+            if disk.read_only { "ro" } else { "rw" },
+            disk.size,
+            version,
+            disk.path.display()
+        ));
+        for chunk in &disk.chunks {
+            text.push_str(&format!(
+                "chunk {} {} {}\n",
+                chunk.offset, chunk.len, chunk.digest
+            ));
+        }
+    }
+    text
+}
+
+fn parse_snapshot(id: &str, text: &str) -> Result<Snapshot, BackupError> {
+    let invalid = || BackupError::InvalidSnapshot(id.to_string());
+    let mut lines = text.lines();
+    if lines.next() != Some(SNAPSHOT_VERSION) {
+        return Err(invalid());
+    }
+    let mut snapshot = Snapshot {
+        id: id.to_string(),
+        vm: String::new(),
+        time: 0,
+        chunking: Chunking::default(),
+        disks: Vec::new(),
+    };
+    for line in lines {
+        let (key, value) = line.split_once(' ').ok_or_else(invalid)?;
+        match key {
+            "vm" => snapshot.vm = value.to_string(),
+            "time" => snapshot.time = value.parse().map_err(|_| invalid())?,
+            "chunking" => snapshot.chunking = Chunking::parse(value).ok_or_else(invalid)?,
+            "disk" => {
+                let fields: Vec<&str> = value.splitn(7, ' ').collect();
+                if fields.len() != 7 {
+                    return Err(invalid());
+                }
+                let size = fields[1].parse().map_err(|_| invalid())?;
+                let version = if fields[2] == "-" {
+                    None
+                } else {
+                    Some(FileVersion {
+                        dev: fields[2].parse().map_err(|_| invalid())?,
+                        ino: fields[3].parse().map_err(|_| invalid())?,
+                        size,
+                        mtime: fields[4].parse().map_err(|_| invalid())?,
+                        mtime_nsec: fields[5].parse().map_err(|_| invalid())?,
+                    })
+                };
+                snapshot.disks.push(SnapshotDisk {
+                    path: PathBuf::from(fields[6]),
+                    read_only: match fields[0] {
+                        "ro" => true,
+                        "rw" => false,
+                        _ => return Err(invalid()),
+                    },
+                    size,
+                    chunks: Vec::new(),
+                    version,
+                });
+            }
+            "chunk" => {
+                let fields: Vec<&str> = value.split(' ').collect();
+                let disk = snapshot.disks.last_mut().ok_or_else(invalid)?;
+                match fields.as_slice() {
+                    [offset, len, digest] => disk.chunks.push(ChunkRef {
+                        offset: offset.parse().map_err(|_| invalid())?,
+                        len: len.parse().map_err(|_| invalid())?,
+                        digest: digest.parse().map_err(|_| invalid())?,
+                    }),
+                    _ => return Err(invalid()),
+                }
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(snapshot)
+}
+
+fn check_name(name: &str) -> Result<(), BackupError> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(|c: char| c == '/' || c == '\0' || c.is_whitespace())
+    {
+        Err(BackupError::InvalidName(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,795 @@
+//! virtual machine bundle module
+
+use crate::definition::{
+    StorageDeviceDefinition, VZDiskImageCachingMode, VZDiskImageSynchronizationMode,
+    VZVirtualMachineState, VirtualMachineDefinition, VirtualMachineLifecycle,
+};
+use crate::disk::sparse::data_ranges;
+use crate::disk::store::Digest;
+use crate::disk::tar::{TarReader, TarWriter};
+use crate::util::{replace_with, tmp_path};
+
+use sha2::{Digest as _, Sha256};
+
+use std::cell::Cell;
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read};
+use std::os::unix::fs::{FileExt, MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFINITION_ENTRY: &str = "definition";
+const MACHINE_IDENTIFIER_ENTRY: &str = "machine-identifier";
+const KERNEL_ENTRY: &str = "boot/kernel";
+const INITIAL_RAMDISK_ENTRY: &str = "boot/initrd";
+const DISK_DIR: &str = "disks";
+const LOG_DIR: &str = "logs";
+const MANIFEST_ENTRY: &str = "manifest";
+const MANIFEST_VERSION: &str = "bundle 1";
+/// largest definition or manifest that is read into memory
+const MAX_METADATA_SIZE: u64 = 1 << 20;
+const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+static ZEROS: [u8; 1 << 16] = [0; 1 << 16];
+
+/// error of `BundleExporter` and `BundleImporter`
+#[derive(Debug)]
+pub enum BundleError {
+    Io(io::Error),
+    /// the virtual machine is not stopped
+    NotStopped(VZVirtualMachineState),
+    /// the definition cannot be stored, e.g. a field holds a line break, or
+    /// that of an archive cannot be used on this host
+    InvalidDefinition(String),
+    /// the archive is not a bundle or is damaged
+    InvalidArchive(String),
+    /// a file of the archive does not have the digest of the manifest
+    DigestMismatch {
+        name: String,
+        expected: Digest,
+        actual: Digest,
+    },
+    AlreadyExists(PathBuf),
+}
+
+impl fmt::Display for BundleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BundleError::Io(err) => write!(f, "{}", err),
+            BundleError::NotStopped(state) => {
+                write!(f, "virtual machine is not stopped ({:?})", state)
+            }
+            BundleError::InvalidDefinition(message) => {
+                write!(f, "invalid definition: {}", message)
+            }
+            BundleError::InvalidArchive(message) => write!(f, "invalid bundle: {}", message),
+            BundleError::DigestMismatch {
+                name,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} is corrupted: expected {}, got {}",
+                name, expected, actual
+            ),
+            BundleError::AlreadyExists(path) => write!(f, "{} already exists", path.display()),
+        }
+    }
+}
+
+impl error::Error for BundleError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            BundleError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for BundleError {
+    fn from(err: io::Error) -> Self {
+        BundleError::Io(err)
+    }
+}
+
+/// progress of an export or import
+#[derive(Clone, Copy, Debug)]
+pub struct BundleProgress<'a> {
+    /// file of the bundle being processed
+    pub entry: &'a str,
+    /// bytes processed so far: data of the files on export, compressed
+    /// archive on import
+    pub done: u64,
+    pub total: u64,
+}
+
+/// file of a bundle as recorded by its manifest
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ManifestEntry {
+    /// path inside the bundle
+    pub name: String,
+    pub size: u64,
+    /// digest of the contents, holes read as zeros
+    pub digest: Digest,
+}
+
+/// virtual machine extracted by `BundleImporter::import`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bundle {
+    /// definition whose paths point into the bundle directory
+    pub definition: VirtualMachineDefinition,
+    pub machine_identifier: Option<PathBuf>,
+    pub logs: Vec<PathBuf>,
+    pub manifest: Vec<ManifestEntry>,
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(&BundleProgress) + 'a>;
+
+/// exports a virtual machine as a single archive
+///
+/// The archive is a zstd compressed POSIX tar file holding the definition,
+/// the kernel, the initial ramdisk, every disk, the machine identifier and
+/// the logs. Disks are stored as GNU sparse entries so their holes take no
+/// room, and `tar --zstd -xf` extracts them as sparse files. A manifest with
+/// the size and SHA-256 digest of every file comes last. The virtual
+/// machine must be stopped.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::definition::*;
+/// # use virtualization_rs::disk::bundle::BundleExporter;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let definition = VirtualMachineDefinition::new("web");
+/// # let vm = StoppedVm;
+/// BundleExporter::new(&definition)
+///     .machine_identifier("web/machine-identifier")
+///     .log("web/console.log")
+///     .progress(|p| eprint!("\r{} {}/{}", p.entry, p.done, p.total))
+///     .export(&vm, "web.vmbundle.tar.zst")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct BundleExporter<'a> {
+    definition: &'a VirtualMachineDefinition,
+    machine_identifier: Option<PathBuf>,
+    logs: Vec<PathBuf>,
+    compression_level: i32,
+    progress: Option<ProgressCallback<'a>>,
+}
+
+/// file to be stored in a bundle
+struct Source {
+    name: String,
+    path: PathBuf,
+    file: File,
+}
+
+impl<'a> BundleExporter<'a> {
+    pub fn new(definition: &'a VirtualMachineDefinition) -> BundleExporter<'a> {
+        BundleExporter {
+            definition,
+            machine_identifier: None,
+            logs: Vec::new(),
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            progress: None,
+        }
+    }
+
+    /// file holding the machine identifier of the virtual machine
+    pub fn machine_identifier<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.machine_identifier = Some(path.into());
+        self
+    }
+
+    /// adds a log file to the bundle
+    pub fn log<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.logs.push(path.into());
+        self
+    }
+
+    /// zstd compression level, 3 by default
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    pub fn progress<F: FnMut(&BundleProgress) + 'a>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// writes the bundle to `archive` and returns its manifest
+    ///
+    /// The archive is written next to `archive` and renamed into place once
+    /// complete.
+    pub fn export<L: VirtualMachineLifecycle, P: AsRef<Path>>(
+        &mut self,
+        vm: &L,
+        archive: P,
+    ) -> Result<Vec<ManifestEntry>, BundleError> {
+        let state = vm.state();
+        if !state.is_stopped() {
+            return Err(BundleError::NotStopped(state));
+        }
+        let archive = archive.as_ref();
+        let (definition, sources) = self.sources()?;
+        let definition = format_definition(&definition)?;
+
+        replace_with(archive, |tmp| self.write(tmp, &definition, &sources))
+    }
+
+    /// opens the files of the bundle and returns them with the definition
+    /// rewritten to their names inside the bundle
+    fn sources(&self) -> Result<(VirtualMachineDefinition, Vec<Source>), BundleError> {
+        let mut definition = self.definition.clone();
+        let mut files = Vec::new();
+        if !definition.kernel.as_os_str().is_empty() {
+            files.push((KERNEL_ENTRY.to_string(), definition.kernel.clone()));
+            definition.kernel = PathBuf::from(KERNEL_ENTRY);
+        }
+        if !definition.initial_ramdisk.as_os_str().is_empty() {
+            files.push((
+                INITIAL_RAMDISK_ENTRY.to_string(),
+                definition.initial_ramdisk.clone(),
+            ));
+            definition.initial_ramdisk = PathBuf::from(INITIAL_RAMDISK_ENTRY);
+        }
+        for (index, disk) in definition.storage_devices.iter_mut().enumerate() {
+            let name = format!("{}/{}", DISK_DIR, indexed_file_name(index, &disk.path));
+            files.push((name.clone(), disk.path.clone()));
+            disk.path = PathBuf::from(name);
+        }
+        if let Some(path) = &self.machine_identifier {
+            files.push((MACHINE_IDENTIFIER_ENTRY.to_string(), path.clone()));
+        }
+        for (index, path) in self.logs.iter().enumerate() {
+            let name = format!("{}/{}", LOG_DIR, indexed_file_name(index, path));
+            files.push((name, path.clone()));
+        }
+
+        let mut sources = Vec::with_capacity(files.len());
+        for (name, path) in files {
+            let file = File::open(&path)?;
+            sources.push(Source { name, path, file });
+        }
+        Ok((definition, sources))
+    }
+
+    fn write(
+        &mut self,
+        tmp: &Path,
+        definition: &str,
+        sources: &[Source],
+    ) -> Result<Vec<ManifestEntry>, BundleError> {
+        let mut total = definition.len() as u64;
+        for source in sources {
+            let len = source.file.metadata()?.len();
+            total += data_ranges(&source.file, 0, len)?
+                .iter()
+                .map(|r| r.end - r.start)
+                .sum::<u64>();
+        }
+        let level = self.compression_level;
+        let mut done = 0;
+        let mut report = |entry: &str, done: u64| {
+            if let Some(progress) = self.progress.as_mut() {
+                progress(&BundleProgress { entry, done, total });
+            }
+        };
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o644)
+            .open(tmp)?;
+        let encoder = zstd::Encoder::new(BufWriter::new(file), level)?;
+        let mut tar = TarWriter::new(encoder);
+        let now = unix_time(SystemTime::now());
+
+        let mut manifest = Vec::with_capacity(sources.len() + 1);
+        tar.append(DEFINITION_ENTRY, definition.as_bytes(), now)?;
+        manifest.push(ManifestEntry {
+            name: DEFINITION_ENTRY.to_string(),
+            size: definition.len() as u64,
+            digest: Digest::from(<[u8; 32]>::from(Sha256::digest(definition.as_bytes()))),
+        });
+        done += definition.len() as u64;
+        report(DEFINITION_ENTRY, done);
+
+        for source in sources {
+            let metadata = source.file.metadata()?;
+            let mut hasher = ZeroFillHasher::default();
+            tar.append_file(
+                &source.name,
+                &source.file,
+                metadata.mode(),
+                metadata.mtime().max(0) as u64,
+                |offset, data| {
+                    hasher.update(offset, data);
+                    done += data.len() as u64;
+                    report(&source.name, done);
+                    Ok(())
+                },
+            )
+            .map_err(|err| annotate(err, &source.path))?;
+            manifest.push(ManifestEntry {
+                name: source.name.clone(),
+                size: metadata.len(),
+                digest: hasher.finish(metadata.len()),
+            });
+        }
+
+        tar.append(MANIFEST_ENTRY, format_manifest(&manifest).as_bytes(), now)?;
+        let file = tar.finish()?.finish()?;
+        file.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        Ok(manifest)
+    }
+}
+
+/// verifies and extracts an archive written by `BundleExporter`
+///
+/// Every file is checked against the manifest while it is extracted.
+/// The bundle is extracted next to the destination directory and only
+/// renamed into place once all files match, so a damaged archive leaves
+/// nothing behind.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::bundle::BundleImporter;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let bundle = BundleImporter::new("web.vmbundle.tar.zst")
+///     .progress(|p| eprint!("\r{} {}/{}", p.entry, p.done, p.total))
+///     .import("vms/web")?;
+/// for disk in &bundle.definition.storage_devices {
+///     // VZDiskImageStorageDeviceAttachmentBuilder::new().path(&disk.path)...
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct BundleImporter<'a> {
+    archive: PathBuf,
+    progress: Option<ProgressCallback<'a>>,
+}
+
+impl<'a> BundleImporter<'a> {
+    pub fn new<P: Into<PathBuf>>(archive: P) -> BundleImporter<'a> {
+        BundleImporter {
+            archive: archive.into(),
+            progress: None,
+        }
+    }
+
+    pub fn progress<F: FnMut(&BundleProgress) + 'a>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// extracts the bundle into the new directory `dir`
+    pub fn import<P: AsRef<Path>>(&mut self, dir: P) -> Result<Bundle, BundleError> {
+        let dir = dir.as_ref();
+        if fs::symlink_metadata(dir).is_ok() {
+            return Err(BundleError::AlreadyExists(dir.to_path_buf()));
+        }
+        let tmp = tmp_path(dir);
+        fs::create_dir_all(&tmp)?;
+        let result = self.read(Some(&tmp)).and_then(|contents| {
+            fs::rename(&tmp, dir)?;
+            Ok(contents)
+        });
+        let (mut definition, manifest) = match result {
+            Ok(contents) => contents,
+            Err(err) => {
+                let _ = fs::remove_dir_all(&tmp);
+                return Err(err);
+            }
+        };
+
+        let dir = fs::canonicalize(dir)?;
+        let within = |path: &Path| dir.join(path);
+        if !definition.kernel.as_os_str().is_empty() {
+            definition.kernel = within(&definition.kernel);
+        }
+        if !definition.initial_ramdisk.as_os_str().is_empty() {
+            definition.initial_ramdisk = within(&definition.initial_ramdisk);
+        }
+        for disk in &mut definition.storage_devices {
+            disk.path = within(&disk.path);
+        }
+        let has = |name: &str| manifest.iter().any(|e| e.name == name);
+        Ok(Bundle {
+            definition,
+            machine_identifier: if has(MACHINE_IDENTIFIER_ENTRY) {
+                Some(dir.join(MACHINE_IDENTIFIER_ENTRY))
+            } else {
+                None
+            },
+            logs: manifest
+                .iter()
+                .filter(|e| Path::new(&e.name).starts_with(LOG_DIR))
+                .map(|e| dir.join(&e.name))
+                .collect(),
+            manifest,
+        })
+    }
+
+    /// checks every file of the archive against the manifest without
+    /// extracting it
+    pub fn verify(&mut self) -> Result<Vec<ManifestEntry>, BundleError> {
+        Ok(self.read(None)?.1)
+    }
+
+    /// reads the archive, extracting the files below `dir` when it is
+    /// given, and returns the definition and the verified manifest
+    fn read(
+        &mut self,
+        dir: Option<&Path>,
+    ) -> Result<(VirtualMachineDefinition, Vec<ManifestEntry>), BundleError> {
+        let file = File::open(&self.archive)?;
+        let total = file.metadata()?.len();
+        let read = Rc::new(Cell::new(0));
+        let counter = CountingReader {
+            inner: file,
+            count: read.clone(),
+        };
+        let decoder = zstd::Decoder::with_buffer(BufReader::new(counter))?;
+        let mut tar = TarReader::new(decoder);
+        let mut report = |entry: &str| {
+            if let Some(progress) = self.progress.as_mut() {
+                progress(&BundleProgress {
+                    entry,
+                    done: read.get(),
+                    total,
+                });
+            }
+        };
+
+        let mut found: Vec<ManifestEntry> = Vec::new();
+        let mut definition = None;
+        let manifest = loop {
+            let entry = tar
+                .next_entry()?
+                .ok_or_else(|| BundleError::InvalidArchive("missing manifest".to_string()))?;
+            check_entry_name(&entry.name)?;
+            if found.iter().any(|e| e.name == entry.name) {
+                return Err(BundleError::InvalidArchive(format!(
+                    "{} is stored twice",
+                    entry.name
+                )));
+            }
+            if entry.name == MANIFEST_ENTRY || entry.name == DEFINITION_ENTRY {
+                if entry.size > MAX_METADATA_SIZE {
+                    return Err(BundleError::InvalidArchive(format!(
+                        "{} is too large",
+                        entry.name
+                    )));
+                }
+                let mut data = vec![0u8; entry.size as usize];
+                tar.read_entry(&entry, |offset, chunk| {
+                    data[offset as usize..offset as usize + chunk.len()].copy_from_slice(chunk);
+                    Ok(())
+                })?;
+                let text = String::from_utf8(data).map_err(|_| {
+                    BundleError::InvalidArchive(format!("{} is not text", entry.name))
+                })?;
+                if entry.name == MANIFEST_ENTRY {
+                    break parse_manifest(&text)?;
+                }
+                found.push(ManifestEntry {
+                    name: entry.name.clone(),
+                    size: entry.size,
+                    digest: Digest::from(<[u8; 32]>::from(Sha256::digest(text.as_bytes()))),
+                });
+                definition = Some(text);
+                report(&entry.name);
+                continue;
+            }
+            let output = match dir {
+                Some(dir) => {
+                    let path = dir.join(&entry.name);
+                    if let Some(parent) = path.parent() {
+                        fs::create_dir_all(parent)?;
+                    }
+                    let file = OpenOptions::new()
+                        .write(true)
+                        .create_new(true)
+                        .mode(entry.mode & 0o777)
+                        .open(&path)?;
+                    file.set_len(entry.size)?;
+                    Some(file)
+                }
+                None => None,
+            };
+            let mut hasher = ZeroFillHasher::default();
+            tar.read_entry(&entry, |offset, chunk| {
+                if let Some(file) = &output {
+                    file.write_all_at(chunk, offset)?;
+                }
+                hasher.update(offset, chunk);
+                report(&entry.name);
+                Ok(())
+            })?;
+            if let Some(file) = output {
+                file.set_modified(UNIX_EPOCH + Duration::from_secs(entry.mtime))?;
+                file.sync_all()?;
+            }
+            found.push(ManifestEntry {
+                name: entry.name.clone(),
+                size: entry.size,
+                digest: hasher.finish(entry.size),
+            });
+        };
+        if tar.next_entry()?.is_some() {
+            return Err(BundleError::InvalidArchive(
+                "files after the manifest".to_string(),
+            ));
+        }
+        report(MANIFEST_ENTRY);
+
+        for expected in &manifest {
+            let actual = found
+                .iter()
+                .find(|e| e.name == expected.name)
+                .ok_or_else(|| {
+                    BundleError::InvalidArchive(format!("{} is missing", expected.name))
+                })?;
+            if actual.size != expected.size {
+                return Err(BundleError::InvalidArchive(format!(
+                    "{} has {} bytes instead of {}",
+                    expected.name, actual.size, expected.size
+                )));
+            }
+            if actual.digest != expected.digest {
+                return Err(BundleError::DigestMismatch {
+                    name: expected.name.clone(),
+                    expected: expected.digest,
+                    actual: actual.digest,
+                });
+            }
+        }
+        if let Some(extra) = found
+            .iter()
+            .find(|e| !manifest.iter().any(|m| m.name == e.name))
+        {
+            return Err(BundleError::InvalidArchive(format!(
+                "{} is not in the manifest",
+                extra.name
+            )));
+        }
+        let definition = definition
+            .ok_or_else(|| BundleError::InvalidArchive("missing definition".to_string()))?;
+        let definition = parse_definition(&definition)?;
+        for disk in &definition.storage_devices {
+            disk.validate().map_err(|err| {
+                BundleError::InvalidDefinition(format!("{}: {}", disk.path.display(), err))
+            })?;
+        }
+        let mut files = vec![&definition.kernel, &definition.initial_ramdisk];
+        files.extend(definition.storage_devices.iter().map(|d| &d.path));
+        if let Some(path) = files.into_iter().find(|path| {
+            !path.as_os_str().is_empty() && !manifest.iter().any(|e| Path::new(&e.name) == *path)
+        }) {
+            return Err(BundleError::InvalidArchive(format!(
+                "{} is missing",
+                path.display()
+            )));
+        }
+        Ok((definition, manifest))
+    }
+}
+
+/// SHA-256 of a file that is visited in increasing offsets, with the skipped
+/// holes hashed as zeros
+#[derive(Default)]
+struct ZeroFillHasher {
+    hasher: Sha256,
+    pos: u64,
+}
+
+impl ZeroFillHasher {
+    fn update(&mut self, offset: u64, data: &[u8]) {
+        self.fill(offset);
+        self.hasher.update(data);
+        self.pos += data.len() as u64;
+    }
+
+    fn finish(mut self, size: u64) -> Digest {
+        self.fill(size);
+        Digest::from(<[u8; 32]>::from(self.hasher.finalize()))
+    }
+
+    fn fill(&mut self, offset: u64) {
+        while self.pos < offset {
+            let n = (offset - self.pos).min(ZEROS.len() as u64) as usize;
+            self.hasher.update(&ZEROS[..n]);
+            self.pos += n as u64;
+        }
+    }
+}
+
+struct CountingReader<R: Read> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+/// accepts only the files a bundle is made of, which also keeps entries
+/// from escaping the destination directory
+fn check_entry_name(name: &str) -> Result<(), BundleError> {
+    let valid = match name.split_once('/') {
+        None => [DEFINITION_ENTRY, MACHINE_IDENTIFIER_ENTRY, MANIFEST_ENTRY].contains(&name),
+        Some((dir, file)) => {
+            name == KERNEL_ENTRY
+                || name == INITIAL_RAMDISK_ENTRY
+                || ((dir == DISK_DIR || dir == LOG_DIR)
+                    && !file.is_empty()
+                    && !file.starts_with('.')
+                    && !file.contains('/')
+                    && !file.contains('\0'))
+        }
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(BundleError::InvalidArchive(format!(
+            "unexpected file {:?}",
+            name
+        )))
+    }
+}
+
+fn format_definition(definition: &VirtualMachineDefinition) -> Result<String, BundleError> {
+    let mut text = String::new();
+    let mut line = |key: &str, value: &str| {
+        if value.contains('\n') {
+            return Err(BundleError::InvalidDefinition(format!(
+                "{} contains a line break",
+                key
+            )));
+        }
+        text.push_str(&format!("{} {}\n", key, value));
+        Ok(())
+    };
+    line("name", &definition.name)?;
+    line("cpu_count", &definition.cpu_count.to_string())?;
+    line("memory_size", &definition.memory_size.to_string())?;
+    if !definition.kernel.as_os_str().is_empty() {
+        line("kernel", &definition.kernel.to_string_lossy())?;
+    }
+    if !definition.initial_ramdisk.as_os_str().is_empty() {
+        line(
+            "initial_ramdisk",
+            &definition.initial_ramdisk.to_string_lossy(),
+        )?;
+    }
+    line("command_line", &definition.command_line)?;
+    for disk in &definition.storage_devices {
+        let mut value = if disk.read_only { "ro" } else { "rw" }.to_string();
+        if let Some(digest) = &disk.digest {
+            value.push_str(&format!(" {}", digest));
+        }
+        // the modes are only written when they differ from the defaults
+        if disk.caching_mode != VZDiskImageCachingMode::default() {
+            value.push_str(&format!(" caching={}", disk.caching_mode.name()));
+        }
+        if disk.synchronization_mode != VZDiskImageSynchronizationMode::default() {
+            value.push_str(&format!(" sync={}", disk.synchronization_mode.name()));
+        }
+        value.push_str(&format!(" {}", disk.path.to_string_lossy()));
+        line("disk", &value)?;
+    }
+    Ok(text)
+}
+
+/// parses a definition written by `format_definition`, whose paths must be
+/// files of the bundle
+fn parse_definition(text: &str) -> Result<VirtualMachineDefinition, BundleError> {
+    let invalid =
+        |line: &str| BundleError::InvalidArchive(format!("invalid definition line {:?}", line));
+    let bundle_path = |line: &str, path: &str| {
+        check_entry_name(path).map_err(|_| invalid(line))?;
+        Ok::<_, BundleError>(PathBuf::from(path))
+    };
+    let mut definition = VirtualMachineDefinition::default();
+    for line in text.lines() {
+        let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+        match key {
+            "name" => definition.name = value.to_string(),
+            "cpu_count" => definition.cpu_count = value.parse().map_err(|_| invalid(line))?,
+            "memory_size" => definition.memory_size = value.parse().map_err(|_| invalid(line))?,
+            "kernel" => definition.kernel = bundle_path(line, value)?,
+            "initial_ramdisk" => definition.initial_ramdisk = bundle_path(line, value)?,
+            "command_line" => definition.command_line = value.to_string(),
+            "disk" => {
+                let (mode, path) = value.split_once(' ').ok_or_else(|| invalid(line))?;
+                let read_only = match mode {
+                    "ro" => true,
+                    "rw" => false,
+                    _ => return Err(invalid(line)),
+                };
+                let mut disk = StorageDeviceDefinition::new("", read_only);
+                // bundle paths never start with `sha256:`, `caching=` or `sync=`
+                let mut path = path;
+                while let Some((option, rest)) = path.split_once(' ') {
+                    if option.starts_with("sha256:") {
+                        disk.digest = Some(option.parse().map_err(|_| invalid(line))?);
+                    } else if let Some(mode) = option.strip_prefix("caching=") {
+                        disk.caching_mode =
+                            VZDiskImageCachingMode::from_name(mode).ok_or_else(|| invalid(line))?;
+                    } else if let Some(mode) = option.strip_prefix("sync=") {
+                        disk.synchronization_mode = VZDiskImageSynchronizationMode::from_name(mode)
+                            .ok_or_else(|| invalid(line))?;
+                    } else {
+                        break;
+                    }
+                    path = rest;
+                }
+                disk.path = bundle_path(line, path)?;
+                definition.storage_devices.push(disk);
+            }
+            "" => {}
+            _ => return Err(invalid(line)),
+        }
+    }
+    Ok(definition)
+}
+
+fn format_manifest(manifest: &[ManifestEntry]) -> String {
+    let mut text = format!("{}\n", MANIFEST_VERSION);
+    for entry in manifest {
+        text.push_str(&format!("{} {} {}\n", entry.digest, entry.size, entry.name));
+    }
+    text
+}
+
+fn parse_manifest(text: &str) -> Result<Vec<ManifestEntry>, BundleError> {
+    let mut lines = text.lines();
+    if lines.next() != Some(MANIFEST_VERSION) {
+        return Err(BundleError::InvalidArchive(
+            "unsupported manifest version".to_string(),
+        ));
+    }
+    let mut manifest = Vec::new();
+    for line in lines.filter(|line| !line.is_empty()) {
+        let invalid = || BundleError::InvalidArchive(format!("invalid manifest line {:?}", line));
+        let mut fields = line.splitn(3, ' ');
+        let (digest, size, name) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(digest), Some(size), Some(name)) => (digest, size, name),
+            _ => return Err(invalid()),
+        };
+        check_entry_name(name)?;
+        manifest.push(ManifestEntry {
+            name: name.to_string(),
+            size: size.parse().map_err(|_| invalid())?,
+            digest: digest.parse().map_err(|_| invalid())?,
+        });
+    }
+    Ok(manifest)
+}
+
+fn indexed_file_name(index: usize, path: &Path) -> String {
+    let name = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("{}-{}", index, name)
+}
+
+fn annotate(err: io::Error, path: &Path) -> io::Error {
+    io::Error::new(err.kind(), format!("{}: {}", path.display(), err))
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
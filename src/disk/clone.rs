@@ -0,0 +1,170 @@
+//! disk image clone module
+
+use crate::disk::sparse::data_ranges;
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+const COPY_CHUNK: u64 = 1 << 20;
+
+/// how a disk image was cloned
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloneMethod {
+    /// the clone shares its blocks with the source (`FICLONE` or `clonefile`)
+    Reflink,
+    /// the data was copied, skipping the holes of the source
+    SparseCopy,
+}
+
+/// disk image created by `clone_disk_image`
+#[derive(Clone, Debug)]
+pub struct ClonedDiskImage {
+    /// absolute path of the clone
+    pub path: PathBuf,
+    pub method: CloneMethod,
+}
+
+impl From<ClonedDiskImage> for String {
+    fn from(image: ClonedDiskImage) -> String {
+        image.path.to_string_lossy().into_owned()
+    }
+}
+
+/// clones the disk image `src` to the new file `dst`
+///
+/// A copy-on-write clone is made when the file system supports it
+/// (`FICLONE` on Linux, `clonefile` on APFS). Otherwise the data is copied
+/// while keeping the holes of `src`. `dst` must not exist.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::clone::clone_disk_image;
+/// # fn main() -> std::io::Result<()> {
+/// let image = clone_disk_image("golden.img", "vm1.img")?;
+/// // VZDiskImageStorageDeviceAttachmentBuilder::new().path(image).read_only(false).build()
+/// # Ok(())
+/// # }
+/// ```
+pub fn clone_disk_image<P: AsRef<Path>, Q: AsRef<Path>>(
+    src: P,
+    dst: Q,
+) -> io::Result<ClonedDiskImage> {
+    let src = src.as_ref();
+    let dst = dst.as_ref();
+    let method = match reflink(src, dst) {
+        Ok(true) => CloneMethod::Reflink,
+        Ok(false) => {
+            sparse_copy(src, dst)?;
+            CloneMethod::SparseCopy
+        }
+        Err(err) => return Err(err),
+    };
+    Ok(ClonedDiskImage {
+        path: fs::canonicalize(dst)?,
+        method,
+    })
+}
+
+fn is_unsupported(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) => {
+            code == libc::EOPNOTSUPP
+                || code == libc::ENOTSUP
+                || code == libc::EXDEV
+                || code == libc::EINVAL
+                || code == libc::ENOTTY
+                || code == libc::ENOSYS
+        }
+        None => false,
+    }
+}
+
+/// makes a copy-on-write clone; returns `Ok(false)` when unsupported
+#[cfg(target_os = "linux")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    // _IOW(0x94, 9, int)
+    const FICLONE: u32 = 0x4004_9409;
+
+    let src_file = File::open(src)?;
+    let mode = src_file.metadata()?.permissions().mode();
+    let dst_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(dst)?;
+    let ret = unsafe { libc::ioctl(dst_file.as_raw_fd(), FICLONE as _, src_file.as_raw_fd()) };
+    if ret == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    drop(dst_file);
+    let _ = fs::remove_file(dst);
+    if is_unsupported(&err) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// makes a copy-on-write clone; returns `Ok(false)` when unsupported
+#[cfg(target_os = "macos")]
+fn reflink(src: &Path, dst: &Path) -> io::Result<bool> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let src_c = CString::new(src.as_os_str().as_bytes())?;
+    let dst_c = CString::new(dst.as_os_str().as_bytes())?;
+    if unsafe { libc::clonefile(src_c.as_ptr(), dst_c.as_ptr(), 0) } == 0 {
+        return Ok(true);
+    }
+    let err = io::Error::last_os_error();
+    if is_unsupported(&err) {
+        Ok(false)
+    } else {
+        Err(err)
+    }
+}
+
+/// makes a copy-on-write clone; returns `Ok(false)` when unsupported
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn reflink(_src: &Path, _dst: &Path) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// copies `src` to the new file `dst`, leaving holes where `src` has holes
+///
+/// A partial copy is removed on failure; an existing `dst` is never touched.
+pub fn sparse_copy<P: AsRef<Path>, Q: AsRef<Path>>(src: P, dst: Q) -> io::Result<()> {
+    let src = File::open(src)?;
+    let metadata = src.metadata()?;
+    let dst = dst.as_ref();
+    let dst_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(metadata.permissions().mode())
+        .open(dst)?;
+    if let Err(err) = copy_data(&src, &dst_file, metadata.len()) {
+        drop(dst_file);
+        let _ = fs::remove_file(dst);
+        return Err(err);
+    }
+    Ok(())
+}
+
+fn copy_data(src: &File, dst: &File, len: u64) -> io::Result<()> {
+    dst.set_len(len)?;
+    let mut buf = vec![0u8; COPY_CHUNK as usize];
+    for range in data_ranges(src, 0, len)? {
+        let mut offset = range.start;
+        while offset < range.end {
+            let n = (range.end - offset).min(COPY_CHUNK) as usize;
+            src.read_exact_at(&mut buf[..n], offset)?;
+            dst.write_all_at(&buf[..n], offset)?;
+            offset += n as u64;
+        }
+    }
+    dst.sync_all()
+}
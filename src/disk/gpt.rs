@@ -0,0 +1,444 @@
+//! GUID partition table module
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::str::FromStr;
+
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+const REVISION: u32 = 0x0001_0000;
+const HEADER_SIZE: u32 = 92;
+const ENTRY_SIZE: u32 = 128;
+const NUM_ENTRIES: u32 = 128;
+const NAME_UNITS: usize = 36;
+
+/// logical block sizes probed when looking for a GPT
+pub const BLOCK_SIZES: [u64; 2] = [512, 4096];
+
+/// GUID as stored on disk (the first three fields are little endian)
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub const NIL: Guid = Guid([0; 16]);
+    /// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+    pub const EFI_SYSTEM_PARTITION: Guid = Guid([
+        0x28, 0x73, 0x2a, 0xc1, 0x1f, 0xf8, 0xd2, 0x11, 0xba, 0x4b, 0x00, 0xa0, 0xc9, 0x3e, 0xc9,
+        0x3b,
+    ]);
+    /// EBD0A0A2-B9E5-4433-87C0-68B6B72699C7
+    pub const BASIC_DATA: Guid = Guid([
+        0xa2, 0xa0, 0xd0, 0xeb, 0xe5, 0xb9, 0x33, 0x44, 0x87, 0xc0, 0x68, 0xb6, 0xb7, 0x26, 0x99,
+        0xc7,
+    ]);
+    /// 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+    pub const LINUX_FILESYSTEM: Guid = Guid([
+        0xaf, 0x3d, 0xc6, 0x0f, 0x83, 0x84, 0x72, 0x47, 0x8e, 0x79, 0x3d, 0x69, 0xd8, 0x47, 0x7d,
+        0xe4,
+    ]);
+
+    pub fn is_nil(&self) -> bool {
+        self.0 == [0; 16]
+    }
+
+    /// random (version 4) GUID
+    pub fn random() -> io::Result<Guid> {
+        let mut raw = [0u8; 16];
+        File::open("/dev/urandom")?.read_exact(&mut raw)?;
+        // version in the high nibble of the little endian third field
+        raw[7] = (raw[7] & 0x0f) | 0x40;
+        raw[8] = (raw[8] & 0x3f) | 0x80;
+        Ok(Guid(raw))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{:02X}{:02X}{:02X}{:02X}{:02X}{:02X}",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]]),
+            b[8],
+            b[9],
+            b[10],
+            b[11],
+            b[12],
+            b[13],
+            b[14],
+            b[15]
+        )
+    }
+}
+
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl FromStr for Guid {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Guid, io::Error> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("bad GUID {}", s));
+        let fields: Vec<&str> = s.split('-').collect();
+        let lens = [8, 4, 4, 4, 12];
+        if fields.len() != lens.len() || fields.iter().zip(&lens).any(|(f, &l)| f.len() != l) {
+            return Err(invalid());
+        }
+        let hex: String = fields.concat();
+        let mut raw = [0u8; 16];
+        for (i, byte) in raw.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2).ok_or_else(invalid)?, 16)
+                .map_err(|_| invalid())?;
+        }
+        // the first three fields are stored little endian
+        raw[0..4].reverse();
+        raw[4..6].reverse();
+        raw[6..8].reverse();
+        Ok(Guid(raw))
+    }
+}
+
+/// GPT header
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GptHeader {
+    pub revision: u32,
+    pub current_lba: u64,
+    pub backup_lba: u64,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+    pub disk_guid: Guid,
+    pub partition_entry_lba: u64,
+    pub num_partition_entries: u32,
+    pub partition_entry_size: u32,
+}
+
+/// entry of the GPT partition array
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GptPartition {
+    pub type_guid: Guid,
+    pub unique_guid: Guid,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub attributes: u64,
+    /// partition name as UTF-16 code units, padded with zeros
+    pub name: [u16; NAME_UNITS],
+}
+
+impl GptPartition {
+    pub fn is_used(&self) -> bool {
+        !self.type_guid.is_nil()
+    }
+
+    pub fn name(&self) -> String {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(NAME_UNITS);
+        String::from_utf16_lossy(&self.name[..len])
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = [0; NAME_UNITS];
+        for (dst, src) in self.name.iter_mut().zip(name.encode_utf16()) {
+            *dst = src;
+        }
+    }
+
+    /// number of blocks covered by the partition
+    pub fn len_blocks(&self) -> u64 {
+        self.last_lba + 1 - self.first_lba
+    }
+
+    fn parse(raw: &[u8]) -> GptPartition {
+        let mut name = [0u16; NAME_UNITS];
+        for (i, unit) in name.iter_mut().enumerate() {
+            *unit = u16::from_le_bytes([raw[56 + i * 2], raw[57 + i * 2]]);
+        }
+        GptPartition {
+            type_guid: guid_at(raw, 0),
+            unique_guid: guid_at(raw, 16),
+            first_lba: u64_at(raw, 32),
+            last_lba: u64_at(raw, 40),
+            attributes: u64_at(raw, 48),
+            name,
+        }
+    }
+
+    fn serialize(&self, raw: &mut [u8]) {
+        raw[0..16].copy_from_slice(&self.type_guid.0);
+        raw[16..32].copy_from_slice(&self.unique_guid.0);
+        raw[32..40].copy_from_slice(&self.first_lba.to_le_bytes());
+        raw[40..48].copy_from_slice(&self.last_lba.to_le_bytes());
+        raw[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        for (i, unit) in self.name.iter().enumerate() {
+            raw[56 + i * 2..58 + i * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+}
+
+/// GUID partition table of a disk image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gpt {
+    pub block_size: u64,
+    /// header as read from (or to be written to) the primary location
+    pub header: GptHeader,
+    /// every slot of the partition array, including unused ones
+    pub partitions: Vec<GptPartition>,
+}
+
+impl Gpt {
+    /// empty table for a disk of `total_blocks` blocks, with the usual 128 entries
+    pub fn new(block_size: u64, total_blocks: u64, disk_guid: Guid) -> Gpt {
+        let entry_blocks = (NUM_ENTRIES as u64 * ENTRY_SIZE as u64).div_ceil(block_size);
+        let empty = GptPartition {
+            type_guid: Guid::NIL,
+            unique_guid: Guid::NIL,
+            first_lba: 0,
+            last_lba: 0,
+            attributes: 0,
+            name: [0; NAME_UNITS],
+        };
+        Gpt {
+            block_size,
+            header: GptHeader {
+                revision: REVISION,
+                current_lba: 1,
+                backup_lba: total_blocks - 1,
+                first_usable_lba: 2 + entry_blocks,
+                last_usable_lba: total_blocks - 2 - entry_blocks,
+                disk_guid,
+                partition_entry_lba: 2,
+                num_partition_entries: NUM_ENTRIES,
+                partition_entry_size: ENTRY_SIZE,
+            },
+            partitions: vec![empty; NUM_ENTRIES as usize],
+        }
+    }
+
+    /// reads the GPT of `file`
+    ///
+    /// The primary header is tried first and the backup header at the end of
+    /// the image is used when the primary one is damaged.
+    /// Returns `Ok(None)` when the image has no GPT.
+    pub fn read(file: &File) -> io::Result<Option<Gpt>> {
+        let len = file.metadata()?.len();
+        for &block_size in BLOCK_SIZES.iter() {
+            if len < block_size * 3 {
+                continue;
+            }
+            let backup_lba = len / block_size - 1;
+            for &lba in [1, backup_lba].iter() {
+                if let Some(gpt) = Gpt::read_at(file, len, block_size, lba)? {
+                    return Ok(Some(gpt.into_primary()));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn read_at(file: &File, len: u64, block_size: u64, lba: u64) -> io::Result<Option<Gpt>> {
+        let mut block = vec![0u8; block_size as usize];
+        file.read_exact_at(&mut block, lba * block_size)?;
+        if &block[0..8] != SIGNATURE {
+            return Ok(None);
+        }
+        let header_size = u32_at(&block, 12);
+        if header_size < HEADER_SIZE || header_size as u64 > block_size {
+            return Ok(None);
+        }
+        let header_crc = u32_at(&block, 16);
+        block[16..20].copy_from_slice(&[0; 4]);
+        if crc32fast::hash(&block[..header_size as usize]) != header_crc {
+            return Ok(None);
+        }
+        let header = GptHeader {
+            revision: u32_at(&block, 8),
+            current_lba: u64_at(&block, 24),
+            backup_lba: u64_at(&block, 32),
+            first_usable_lba: u64_at(&block, 40),
+            last_usable_lba: u64_at(&block, 48),
+            disk_guid: guid_at(&block, 56),
+            partition_entry_lba: u64_at(&block, 72),
+            num_partition_entries: u32_at(&block, 80),
+            partition_entry_size: u32_at(&block, 84),
+        };
+        if header.current_lba != lba
+            || header.partition_entry_size < 128
+            || !header.partition_entry_size.is_multiple_of(8)
+            || header.num_partition_entries > 4096
+        {
+            return Ok(None);
+        }
+        // a header whose entries lie outside the image is as unusable as a
+        // damaged one, so that the other copy is tried
+        let entry_size = header.partition_entry_size as usize;
+        let entries_len = header.num_partition_entries as u64 * entry_size as u64;
+        let entries_offset = match header.partition_entry_lba.checked_mul(block_size) {
+            Some(offset)
+                if offset
+                    .checked_add(entries_len)
+                    .is_some_and(|end| end <= len) =>
+            {
+                offset
+            }
+            _ => return Ok(None),
+        };
+        let mut entries = vec![0u8; entries_len as usize];
+        file.read_exact_at(&mut entries, entries_offset)?;
+        if crc32fast::hash(&entries) != u32_at(&block, 88) {
+            return Ok(None);
+        }
+        let partitions = entries
+            .chunks(entry_size)
+            .map(GptPartition::parse)
+            .collect();
+        Ok(Some(Gpt {
+            block_size,
+            header,
+            partitions,
+        }))
+    }
+
+    /// rewrites a header read from the backup location as the primary one
+    fn into_primary(mut self) -> Gpt {
+        if self.header.current_lba != 1 {
+            self.header.backup_lba = self.header.current_lba;
+            self.header.current_lba = 1;
+            self.header.partition_entry_lba = 2;
+        }
+        self
+    }
+
+    /// number of blocks occupied by one copy of the partition array
+    pub fn entry_blocks(&self) -> u64 {
+        let bytes =
+            self.header.num_partition_entries as u64 * self.header.partition_entry_size as u64;
+        bytes.div_ceil(self.block_size)
+    }
+
+    /// the used partition that ends last on the disk
+    pub fn last_partition(&self) -> Option<usize> {
+        self.partitions
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.is_used())
+            .max_by_key(|(_, p)| p.last_lba)
+            .map(|(i, _)| i)
+    }
+
+    /// moves the backup header and partition array to the end of a disk of
+    /// `total_blocks` blocks and updates the last usable block accordingly
+    pub fn relocate_backup(&mut self, total_blocks: u64) {
+        self.header.backup_lba = total_blocks - 1;
+        self.header.last_usable_lba = total_blocks - 2 - self.entry_blocks();
+    }
+
+    fn entries_bytes(&self) -> Vec<u8> {
+        let entry_size = self.header.partition_entry_size as usize;
+        let mut raw = vec![0u8; self.partitions.len() * entry_size];
+        for (partition, chunk) in self.partitions.iter().zip(raw.chunks_mut(entry_size)) {
+            partition.serialize(chunk);
+        }
+        raw
+    }
+
+    fn header_block(&self, current_lba: u64, other_lba: u64, entry_lba: u64, crc: u32) -> Vec<u8> {
+        let h = &self.header;
+        let mut block = vec![0u8; self.block_size as usize];
+        block[0..8].copy_from_slice(SIGNATURE);
+        block[8..12].copy_from_slice(&h.revision.to_le_bytes());
+        block[12..16].copy_from_slice(&HEADER_SIZE.to_le_bytes());
+        block[24..32].copy_from_slice(&current_lba.to_le_bytes());
+        block[32..40].copy_from_slice(&other_lba.to_le_bytes());
+        block[40..48].copy_from_slice(&h.first_usable_lba.to_le_bytes());
+        block[48..56].copy_from_slice(&h.last_usable_lba.to_le_bytes());
+        block[56..72].copy_from_slice(&h.disk_guid.0);
+        block[72..80].copy_from_slice(&entry_lba.to_le_bytes());
+        block[80..84].copy_from_slice(&h.num_partition_entries.to_le_bytes());
+        block[84..88].copy_from_slice(&h.partition_entry_size.to_le_bytes());
+        block[88..92].copy_from_slice(&crc.to_le_bytes());
+        let header_crc = crc32fast::hash(&block[..HEADER_SIZE as usize]);
+        block[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        block
+    }
+
+    /// writes the backup copy, then the primary copy of the table
+    ///
+    /// The backup goes first so that an interrupted write always leaves one
+    /// consistent copy behind.
+    pub fn write(&self, file: &File) -> io::Result<()> {
+        let bs = self.block_size;
+        let entries = self.entries_bytes();
+        let crc = crc32fast::hash(&entries);
+        let primary = self.header.current_lba;
+        let backup = self.header.backup_lba;
+        let backup_entry_lba = backup - self.entry_blocks();
+
+        file.write_all_at(&entries, backup_entry_lba * bs)?;
+        file.write_all_at(
+            &self.header_block(backup, primary, backup_entry_lba, crc),
+            backup * bs,
+        )?;
+        file.sync_data()?;
+        file.write_all_at(&entries, self.header.partition_entry_lba * bs)?;
+        file.write_all_at(
+            &self.header_block(primary, backup, self.header.partition_entry_lba, crc),
+            primary * bs,
+        )?;
+        file.sync_data()
+    }
+
+    /// writes a new protective MBR covering a disk of `total_blocks` blocks
+    pub fn create_protective_mbr(&self, file: &File, total_blocks: u64) -> io::Result<()> {
+        let mut mbr = [0u8; 512];
+        let size = (total_blocks - 1).min(u32::MAX as u64) as u32;
+        let entry = &mut mbr[446..462];
+        // CHS fields of a protective entry: start 0/0/2, end saturated
+        entry[1..4].copy_from_slice(&[0x00, 0x02, 0x00]);
+        entry[4] = 0xee;
+        entry[5..8].copy_from_slice(&[0xff, 0xff, 0xff]);
+        entry[8..12].copy_from_slice(&1u32.to_le_bytes());
+        entry[12..16].copy_from_slice(&size.to_le_bytes());
+        mbr[510..512].copy_from_slice(&[0x55, 0xaa]);
+        file.write_all_at(&mbr, 0)
+    }
+
+    /// updates the size of the protective MBR entry for a disk of
+    /// `total_blocks` blocks. Other MBR entries are left untouched.
+    pub fn write_protective_mbr(&self, file: &File, total_blocks: u64) -> io::Result<()> {
+        let mut mbr = [0u8; 512];
+        file.read_exact_at(&mut mbr, 0)?;
+        if mbr[510..512] != [0x55, 0xaa] {
+            return Ok(());
+        }
+        let size = (total_blocks - 1).min(u32::MAX as u64) as u32;
+        for i in 0..4 {
+            let entry = 446 + i * 16;
+            if mbr[entry + 4] == 0xee {
+                mbr[entry + 12..entry + 16].copy_from_slice(&size.to_le_bytes());
+            }
+        }
+        file.write_all_at(&mbr, 0)
+    }
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    let mut raw = [0u8; 4];
+    raw.copy_from_slice(&buf[offset..offset + 4]);
+    u32::from_le_bytes(raw)
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    let mut raw = [0u8; 8];
+    raw.copy_from_slice(&buf[offset..offset + 8]);
+    u64::from_le_bytes(raw)
+}
+
+fn guid_at(buf: &[u8], offset: usize) -> Guid {
+    let mut raw = [0u8; 16];
+    raw.copy_from_slice(&buf[offset..offset + 16]);
+    Guid(raw)
+}
@@ -0,0 +1,672 @@
+//! compressed image import module
+
+use crate::disk::sparse::is_zero;
+use crate::util::create_with;
+
+use flate2::read::MultiGzDecoder;
+use liblzma::bufread::XzDecoder;
+use liblzma::stream::MtStreamBuilder;
+
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const READ_CHUNK: usize = 1 << 20;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+const ZSTD_MAGIC: u32 = 0xfd2f_b528;
+/// memory the xz decoder may use for its threads before it falls back to one
+const XZ_THREADING_MEMORY: u64 = 1 << 30;
+
+/// error of `ImageImporter`
+#[derive(Debug)]
+pub enum ImportError {
+    Io(io::Error),
+    /// the source is not gzip, xz or zstd compressed
+    UnknownFormat(PathBuf),
+    /// the compressed stream is damaged or truncated
+    Corrupt(String),
+    AlreadyExists(PathBuf),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::Io(err) => write!(f, "{}", err),
+            ImportError::UnknownFormat(path) => {
+                write!(f, "{} is not gzip, xz or zstd compressed", path.display())
+            }
+            ImportError::Corrupt(msg) => write!(f, "corrupt compressed image: {}", msg),
+            ImportError::AlreadyExists(path) => write!(f, "{} already exists", path.display()),
+        }
+    }
+}
+
+impl error::Error for ImportError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ImportError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ImportError {
+    fn from(err: io::Error) -> Self {
+        ImportError::Io(err)
+    }
+}
+
+/// compression format of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Xz,
+    Zstd,
+}
+
+impl Compression {
+    /// recognizes the format from the first bytes of a file
+    /// # Examples
+    /// ```rust
+    /// # use virtualization_rs::disk::import::Compression;
+    /// assert_eq!(Compression::detect(&[0x1f, 0x8b, 8]), Some(Compression::Gzip));
+    /// assert_eq!(Compression::detect(b"\xfd7zXZ\0"), Some(Compression::Xz));
+    /// assert_eq!(Compression::detect(b"raw"), None);
+    /// ```
+    pub fn detect(magic: &[u8]) -> Option<Compression> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Compression::Gzip)
+        } else if magic.starts_with(b"\xfd7zXZ\0") {
+            Some(Compression::Xz)
+        } else if magic.starts_with(&ZSTD_MAGIC.to_le_bytes()) {
+            Some(Compression::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Compression::Gzip => write!(f, "gzip"),
+            Compression::Xz => write!(f, "xz"),
+            Compression::Zstd => write!(f, "zstd"),
+        }
+    }
+}
+
+/// progress of an import
+#[derive(Clone, Copy, Debug)]
+pub struct ImportProgress {
+    /// compressed bytes read so far
+    pub read: u64,
+    pub compressed_size: u64,
+    /// bytes of the image decompressed so far
+    pub written: u64,
+    /// size of the image when the compressed stream records it
+    pub size: Option<u64>,
+}
+
+/// result of `ImageImporter::import`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportReport {
+    pub path: PathBuf,
+    pub compression: Compression,
+    /// size of the image, a multiple of 512 bytes
+    pub size: u64,
+    /// bytes written; the zero blocks of the image are holes
+    pub allocated: u64,
+}
+
+type ProgressCallback<'a> = Box<dyn FnMut(&ImportProgress) + 'a>;
+
+/// imports a gzip, xz or zstd compressed raw disk image as a sparse raw
+/// image that `VZDiskImageStorageDeviceAttachmentBuilder` can attach
+///
+/// Blocks of zeros are not written, so they become holes. The size of the
+/// image is rounded up to a multiple of 512 bytes with zeros.
+///
+/// Decompression uses several threads where the format allows it: xz files
+/// of several blocks, as written by `xz -T`, and zstd files of several
+/// frames that record their size, as written by `pzstd`. Other files are
+/// decompressed on one thread while another one writes the image.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::import::ImageImporter;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let report = ImageImporter::new("Fedora-Server.raw.xz")
+///     .progress(|p| eprint!("\r{}/{}", p.read, p.compressed_size))
+///     .import("fedora.img")?;
+/// println!("{} bytes, {} allocated", report.size, report.allocated);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ImageImporter<'a> {
+    src: PathBuf,
+    compression: Option<Compression>,
+    threads: usize,
+    progress: Option<ProgressCallback<'a>>,
+    reported: Option<Instant>,
+}
+
+impl<'a> ImageImporter<'a> {
+    pub fn new<P: Into<PathBuf>>(src: P) -> ImageImporter<'a> {
+        ImageImporter {
+            src: src.into(),
+            compression: None,
+            threads: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            progress: None,
+            reported: None,
+        }
+    }
+
+    /// format of the source instead of detecting it from its first bytes
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// upper limit of decompression threads, all cores by default
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// called regularly while the image is decompressed, and once at the end
+    pub fn progress<F: FnMut(&ImportProgress) + 'a>(mut self, progress: F) -> Self {
+        self.progress = Some(Box::new(progress));
+        self
+    }
+
+    /// decompresses the source into the new file `dst`
+    ///
+    /// The image is written next to `dst` and linked into place once
+    /// complete. A file that appears at `dst` in the meantime is not
+    /// replaced; the import fails with `ImportError::AlreadyExists` instead.
+    pub fn import<P: AsRef<Path>>(&mut self, dst: P) -> Result<ImportReport, ImportError> {
+        let dst = dst.as_ref();
+        let src = File::open(&self.src)?;
+        let compressed_size = src.metadata()?.len();
+        let compression = match self.compression {
+            Some(compression) => compression,
+            None => {
+                let mut magic = [0u8; 6];
+                let n = src.read_at(&mut magic, 0)?;
+                Compression::detect(&magic[..n])
+                    .ok_or_else(|| ImportError::UnknownFormat(self.src.clone()))?
+            }
+        };
+        if dst.exists() {
+            return Err(ImportError::AlreadyExists(dst.to_path_buf()));
+        }
+
+        let result = create_with(dst, |tmp| {
+            let out = OpenOptions::new().write(true).create_new(true).open(tmp)?;
+            let (size, allocated) = self.decompress(compression, &src, compressed_size, &out)?;
+            let size = size.div_ceil(512) * 512;
+            out.set_len(size)?;
+            Ok(ImportReport {
+                path: dst.to_path_buf(),
+                compression,
+                size,
+                allocated,
+            })
+        });
+        match result {
+            Err(ImportError::Io(err)) if err.kind() == io::ErrorKind::AlreadyExists => {
+                Err(ImportError::AlreadyExists(dst.to_path_buf()))
+            }
+            result => result,
+        }
+    }
+
+    /// writes the decompressed image to `out`; returns its size and the
+    /// bytes written
+    fn decompress(
+        &mut self,
+        compression: Compression,
+        src: &File,
+        compressed_size: u64,
+        out: &File,
+    ) -> Result<(u64, u64), ImportError> {
+        let block = out.metadata()?.blksize().max(512);
+        let read = AtomicU64::new(0);
+        let input = FileRange {
+            file: src,
+            pos: 0,
+            end: compressed_size,
+            read: &read,
+        };
+        match compression {
+            Compression::Gzip => {
+                let decoder = MultiGzDecoder::new(BufReader::with_capacity(READ_CHUNK, input));
+                self.decompress_stream(decoder, out, block, &read, compressed_size)
+            }
+            Compression::Xz => {
+                let decoder = XzStreams {
+                    decoder: None,
+                    input: Some(BufReader::with_capacity(READ_CHUNK, input)),
+                    threads: self.threads as u32,
+                };
+                self.decompress_stream(decoder, out, block, &read, compressed_size)
+            }
+            Compression::Zstd => match zstd_frames(src, compressed_size) {
+                Ok(Some(frames)) if frames.len() > 1 && self.threads > 1 => {
+                    self.decompress_frames(&frames, src, out, block, &read, compressed_size)
+                }
+                _ => {
+                    let decoder = zstd::stream::read::Decoder::new(input)?;
+                    self.decompress_stream(decoder, out, block, &read, compressed_size)
+                }
+            },
+        }
+    }
+
+    /// decompresses on this thread and writes on another one
+    fn decompress_stream<R: Read>(
+        &mut self,
+        mut decoder: R,
+        out: &File,
+        block: u64,
+        read: &AtomicU64,
+        compressed_size: u64,
+    ) -> Result<(u64, u64), ImportError> {
+        thread::scope(|s| {
+            let (tx, rx) = mpsc::sync_channel::<(u64, Vec<u8>)>(4);
+            let writer = s.spawn(move || {
+                let mut allocated = 0;
+                for (offset, buf) in rx {
+                    allocated += write_sparse(out, &buf, offset, block)?;
+                }
+                Ok::<u64, io::Error>(allocated)
+            });
+            let mut written = 0;
+            let result = loop {
+                let mut buf = vec![0u8; READ_CHUNK];
+                let n = match read_full(&mut decoder, &mut buf) {
+                    Ok(n) => n,
+                    Err(err) => break Err(decode_error(err)),
+                };
+                if n == 0 {
+                    break Ok(());
+                }
+                buf.truncate(n);
+                // a failed writer hangs up, its error is returned below
+                if tx.send((written, buf)).is_err() {
+                    break Ok(());
+                }
+                written += n as u64;
+                self.report(
+                    read.load(Ordering::Relaxed),
+                    compressed_size,
+                    written,
+                    None,
+                    false,
+                );
+            };
+            drop(tx);
+            let allocated = writer.join().unwrap()?;
+            result?;
+            self.report(
+                read.load(Ordering::Relaxed),
+                compressed_size,
+                written,
+                None,
+                true,
+            );
+            Ok((written, allocated))
+        })
+    }
+
+    /// decompresses independent zstd frames on several threads
+    fn decompress_frames(
+        &mut self,
+        frames: &[ZstdFrame],
+        src: &File,
+        out: &File,
+        block: u64,
+        read: &AtomicU64,
+        compressed_size: u64,
+    ) -> Result<(u64, u64), ImportError> {
+        let size = frames
+            .iter()
+            .try_fold(0u64, |total, frame| total.checked_add(frame.size))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "zstd frames are too large")
+            })?;
+        let next = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let written = AtomicU64::new(0);
+        let threads = self.threads.min(frames.len());
+        thread::scope(|s| {
+            let (tx, rx) = mpsc::channel();
+            for _ in 0..threads {
+                let tx = tx.clone();
+                let (next, failed, written) = (&next, &failed, &written);
+                s.spawn(move || {
+                    let mut result = Ok(0);
+                    while !failed.load(Ordering::Relaxed) {
+                        let frame = match frames.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            Some(frame) => frame,
+                            None => break,
+                        };
+                        match decompress_frame(frame, src, out, block, read, written) {
+                            Ok(allocated) => {
+                                result = result.map(|total| total + allocated);
+                            }
+                            Err(err) => {
+                                failed.store(true, Ordering::Relaxed);
+                                result = Err(err);
+                            }
+                        }
+                    }
+                    let _ = tx.send(result);
+                });
+            }
+            drop(tx);
+
+            let mut allocated = 0;
+            let mut error = None;
+            let mut finished = 0;
+            while finished < threads {
+                match rx.recv_timeout(PROGRESS_INTERVAL) {
+                    Ok(result) => {
+                        finished += 1;
+                        match result {
+                            Ok(n) => allocated += n,
+                            Err(err) => error = error.or(Some(err)),
+                        }
+                    }
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                }
+                let done = written.load(Ordering::Relaxed);
+                self.report(
+                    read.load(Ordering::Relaxed),
+                    compressed_size,
+                    done,
+                    Some(size),
+                    false,
+                );
+            }
+            if let Some(err) = error {
+                return Err(err);
+            }
+            self.report(
+                read.load(Ordering::Relaxed),
+                compressed_size,
+                size,
+                Some(size),
+                true,
+            );
+            Ok((size, allocated))
+        })
+    }
+
+    fn report(
+        &mut self,
+        read: u64,
+        compressed_size: u64,
+        written: u64,
+        size: Option<u64>,
+        last: bool,
+    ) {
+        let progress = match self.progress.as_mut() {
+            Some(progress) => progress,
+            None => return,
+        };
+        let now = Instant::now();
+        if !last && self.reported.is_some_and(|t| now - t < PROGRESS_INTERVAL) {
+            return;
+        }
+        self.reported = Some(now);
+        progress(&ImportProgress {
+            read,
+            compressed_size,
+            written,
+            size,
+        });
+    }
+}
+
+/// reader of a range of a file that counts the bytes it reads
+struct FileRange<'f> {
+    file: &'f File,
+    pos: u64,
+    end: u64,
+    read: &'f AtomicU64,
+}
+
+impl Read for FileRange<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (self.end - self.pos).min(buf.len() as u64) as usize;
+        let n = self.file.read_at(&mut buf[..n], self.pos)?;
+        self.pos += n as u64;
+        self.read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// decoder of concatenated xz streams, each with the multithreaded decoder
+struct XzStreams<R: BufRead> {
+    decoder: Option<XzDecoder<R>>,
+    input: Option<R>,
+    threads: u32,
+}
+
+impl<R: BufRead> Read for XzStreams<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if let Some(decoder) = self.decoder.as_mut() {
+                let n = decoder.read(buf)?;
+                if n > 0 || buf.is_empty() {
+                    return Ok(n);
+                }
+                self.input = self.decoder.take().map(XzDecoder::into_inner);
+            }
+            let mut input = match self.input.take() {
+                Some(input) => input,
+                None => return Ok(0),
+            };
+            // streams may be followed by padding of zeros
+            loop {
+                let available = input.fill_buf()?;
+                if available.is_empty() {
+                    return Ok(0);
+                }
+                let zeros = available.iter().take_while(|&&b| b == 0).count();
+                if zeros == 0 {
+                    break;
+                }
+                input.consume(zeros);
+            }
+            let stream = MtStreamBuilder::new()
+                .threads(self.threads)
+                .memlimit_threading(XZ_THREADING_MEMORY)
+                .memlimit_stop(u64::MAX)
+                .decoder()?;
+            self.decoder = Some(XzDecoder::new_stream(input, stream));
+        }
+    }
+}
+
+/// zstd frame that records the size of its contents
+struct ZstdFrame {
+    start: u64,
+    end: u64,
+    /// offset of the contents in the image
+    offset: u64,
+    size: u64,
+}
+
+/// the frames of a zstd file, or `None` when a frame does not record its
+/// size and the file has to be decompressed in order
+fn zstd_frames(file: &File, len: u64) -> io::Result<Option<Vec<ZstdFrame>>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid zstd frame");
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    let mut offset = 0;
+    while pos < len {
+        let mut word = [0u8; 4];
+        file.read_exact_at(&mut word, pos)?;
+        let magic = u32::from_le_bytes(word);
+        if magic & 0xffff_fff0 == 0x184d_2a50 {
+            // skippable frame
+            file.read_exact_at(&mut word, pos + 4)?;
+            pos += 8 + u32::from_le_bytes(word) as u64;
+            continue;
+        }
+        if magic != ZSTD_MAGIC {
+            return Err(invalid());
+        }
+        let start = pos;
+        let mut descriptor = [0u8; 1];
+        file.read_exact_at(&mut descriptor, pos + 4)?;
+        pos += 5;
+        let descriptor = descriptor[0];
+        let single_segment = descriptor & 0x20 != 0;
+        let checksum = descriptor & 0x04 != 0;
+        let dictionary_len = [0, 1, 2, 4][(descriptor & 3) as usize];
+        let size_len = [single_segment as usize, 2, 4, 8][(descriptor >> 6) as usize];
+        if size_len == 0 {
+            return Ok(None);
+        }
+        pos += !single_segment as u64 + dictionary_len;
+        let mut size = [0u8; 8];
+        file.read_exact_at(&mut size[..size_len], pos)?;
+        pos += size_len as u64;
+        let mut size = u64::from_le_bytes(size);
+        if size_len == 2 {
+            size += 256;
+        }
+        loop {
+            let mut header = [0u8; 4];
+            file.read_exact_at(&mut header[..3], pos)?;
+            let header = u32::from_le_bytes(header);
+            pos += 3 + match (header >> 1) & 3 {
+                0 | 2 => (header >> 3) as u64,
+                1 => 1,
+                _ => return Err(invalid()),
+            };
+            if header & 1 != 0 {
+                break;
+            }
+        }
+        if checksum {
+            pos += 4;
+        }
+        if pos > len {
+            return Err(invalid());
+        }
+        frames.push(ZstdFrame {
+            start,
+            end: pos,
+            offset,
+            size,
+        });
+        offset = offset.checked_add(size).ok_or_else(invalid)?;
+    }
+    Ok(Some(frames))
+}
+
+/// decompresses one zstd frame into its place in `out`; returns the bytes
+/// written
+fn decompress_frame(
+    frame: &ZstdFrame,
+    src: &File,
+    out: &File,
+    block: u64,
+    read: &AtomicU64,
+    written: &AtomicU64,
+) -> Result<u64, ImportError> {
+    let input = FileRange {
+        file: src,
+        pos: frame.start,
+        end: frame.end,
+        read,
+    };
+    let mut decoder = zstd::stream::read::Decoder::new(input)?.single_frame();
+    let mut buf = vec![0u8; READ_CHUNK];
+    let mut done = 0;
+    let mut allocated = 0;
+    loop {
+        let n = read_full(&mut decoder, &mut buf).map_err(decode_error)?;
+        if n == 0 {
+            break;
+        }
+        if done + n as u64 > frame.size {
+            break;
+        }
+        allocated += write_sparse(out, &buf[..n], frame.offset + done, block)?;
+        done += n as u64;
+        written.fetch_add(n as u64, Ordering::Relaxed);
+    }
+    if done != frame.size {
+        return Err(ImportError::Corrupt(format!(
+            "zstd frame at {} does not hold the {} bytes it records",
+            frame.start, frame.size
+        )));
+    }
+    Ok(allocated)
+}
+
+/// writes the blocks of `buf` that are not all zeros at `offset` of `file`;
+/// returns the bytes written
+fn write_sparse(file: &File, buf: &[u8], offset: u64, block: u64) -> io::Result<u64> {
+    // end of the block that holds `buf[i]`, blocks being aligned in `file`
+    let block_end = |i: usize| {
+        let end = ((offset + i as u64) / block + 1) * block - offset;
+        (end as usize).min(buf.len())
+    };
+    let mut written = 0;
+    let mut i = 0;
+    while i < buf.len() {
+        let mut j = i;
+        while j < buf.len() && !is_zero(&buf[j..block_end(j)]) {
+            j = block_end(j);
+        }
+        if j > i {
+            file.write_all_at(&buf[i..j], offset + i as u64)?;
+            written += (j - i) as u64;
+        }
+        while j < buf.len() && is_zero(&buf[j..block_end(j)]) {
+            j = block_end(j);
+        }
+        i = j;
+    }
+    Ok(written)
+}
+
+/// reads until `buf` is full or the end of the stream
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// errors of the file system stay I/O errors, the others come from the
+/// decoder
+fn decode_error(err: io::Error) -> ImportError {
+    if err.raw_os_error().is_some() {
+        ImportError::Io(err)
+    } else {
+        ImportError::Corrupt(err.to_string())
+    }
+}
@@ -0,0 +1,783 @@
+//! disk image inspection module
+
+use crate::definition::VirtualMachineDefinition;
+use crate::disk::gpt::{Gpt, Guid};
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const MBR_SECTOR_SIZE: u64 = 512;
+const MBR_TYPE_PROTECTIVE: u8 = 0xee;
+/// extended boot records followed before giving up on a looping chain
+const MAX_LOGICAL_PARTITIONS: usize = 128;
+
+/// ISO 9660 volume descriptors searched for a Joliet one
+const ISO_DESCRIPTORS: usize = 16;
+
+const SWAP_PAGE_SIZES: [u64; 4] = [4096, 8192, 16384, 65536];
+
+/// GPT partition type names, as listed by `sfdisk`
+const GPT_TYPES: &[(&str, &str)] = &[
+    ("C12A7328-F81F-11D2-BA4B-00A0C93EC93B", "EFI System"),
+    ("21686148-6449-6E6F-744E-656564454649", "BIOS boot"),
+    (
+        "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7",
+        "Microsoft basic data",
+    ),
+    ("E3C9E316-0B5C-4DB8-817D-F92DF00215AE", "Microsoft reserved"),
+    ("0FC63DAF-8483-4772-8E79-3D69D8477DE4", "Linux filesystem"),
+    ("0657FD6D-A4AB-43C4-84E5-0933C84B4F4F", "Linux swap"),
+    ("E6D6D379-F507-44C2-A23C-238F2A3DF928", "Linux LVM"),
+    ("A19D880F-05FC-4D3B-A006-743F0F84911E", "Linux RAID"),
+    ("CA7D7CCB-63ED-4C53-861C-1742536059CC", "Linux LUKS"),
+    (
+        "BC13C2FF-59E6-4262-A352-B275FD6F7172",
+        "Linux extended boot",
+    ),
+    ("933AC7E1-2EB4-4F13-B844-0E14E2AEF915", "Linux home"),
+    (
+        "4F68BCE3-E8CD-4DB1-96E7-FBCAF984B709",
+        "Linux root (x86-64)",
+    ),
+    (
+        "B921B045-1DF0-41C3-AF44-4C6F280D3FAE",
+        "Linux root (ARM-64)",
+    ),
+    ("7C3457EF-0000-11AA-AA11-00306543ECAC", "Apple APFS"),
+    ("48465300-0000-11AA-AA11-00306543ECAC", "Apple HFS/HFS+"),
+    ("426F6F74-0000-11AA-AA11-00306543ECAC", "Apple boot"),
+];
+
+/// MBR partition type names, as listed by `fdisk`
+const MBR_TYPES: &[(u8, &str)] = &[
+    (0x01, "FAT12"),
+    (0x04, "FAT16 <32M"),
+    (0x05, "Extended"),
+    (0x06, "FAT16"),
+    (0x07, "HPFS/NTFS/exFAT"),
+    (0x0b, "W95 FAT32"),
+    (0x0c, "W95 FAT32 (LBA)"),
+    (0x0e, "W95 FAT16 (LBA)"),
+    (0x0f, "W95 Ext'd (LBA)"),
+    (0x82, "Linux swap"),
+    (0x83, "Linux"),
+    (0x85, "Linux extended"),
+    (0x8e, "Linux LVM"),
+    (0xa5, "FreeBSD"),
+    (0xaf, "HFS / HFS+"),
+    (0xee, "GPT"),
+    (0xef, "EFI (FAT-12/16/32)"),
+    (0xfd, "Linux raid autodetect"),
+];
+
+/// GPT attribute bits and their names
+const GPT_FLAGS: &[(u32, &str)] = &[
+    (0, "required"),
+    (1, "no-block-io"),
+    (2, "legacy-bios-bootable"),
+    (60, "read-only"),
+    (62, "hidden"),
+    (63, "no-automount"),
+];
+
+/// what `inspect_disk_image` found in a disk image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiskReport {
+    pub path: PathBuf,
+    pub size: u64,
+    pub table: PartitionTable,
+    pub partitions: Vec<PartitionReport>,
+    /// file system spanning the whole image, e.g. an unpartitioned disk or
+    /// the ISO 9660 tree of a hybrid installer image
+    pub filesystem: Option<FilesystemInfo>,
+}
+
+impl fmt::Display for DiskReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} bytes, ", self.path.display(), self.size)?;
+        match &self.table {
+            PartitionTable::None => writeln!(f, "no partition table")?,
+            PartitionTable::Mbr { disk_signature } => {
+                writeln!(f, "mbr, disk signature {:08x}", disk_signature)?
+            }
+            PartitionTable::Gpt {
+                disk_guid,
+                block_size,
+            } => writeln!(
+                f,
+                "gpt, disk guid {}, {}-byte blocks",
+                disk_guid, block_size
+            )?,
+        }
+        if let Some(fs) = &self.filesystem {
+            writeln!(f, "  whole disk: {}", fs)?;
+        }
+        for partition in &self.partitions {
+            write!(
+                f,
+                "  {:>2}: start {} size {}",
+                partition.number, partition.start, partition.size
+            )?;
+            match &partition.entry {
+                PartitionEntry::Mbr { type_id, .. } => write!(f, ", type {:02x}", type_id)?,
+                PartitionEntry::Gpt {
+                    type_guid,
+                    unique_guid,
+                    name,
+                    ..
+                } => {
+                    write!(f, ", type {}", type_guid)?;
+                    if !name.is_empty() {
+                        write!(f, ", name {:?}", name)?;
+                    }
+                    write!(f, ", guid {}", unique_guid)?;
+                }
+            }
+            if let Some(name) = partition.entry.type_name() {
+                write!(f, " ({})", name)?;
+            }
+            let flags = partition.entry.flags();
+            if !flags.is_empty() {
+                write!(f, ", flags {}", flags.join(","))?;
+            }
+            writeln!(f)?;
+            if let Some(fs) = &partition.filesystem {
+                writeln!(f, "      {}", fs)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// partition table of a disk image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionTable {
+    None,
+    Mbr {
+        disk_signature: u32,
+    },
+    Gpt {
+        disk_guid: Guid,
+        /// logical block size the table was found with
+        block_size: u64,
+    },
+}
+
+/// partition of a disk image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionReport {
+    /// partition number as the Linux kernel counts it: GPT entries from 1,
+    /// MBR primary partitions 1 to 4 and logical partitions from 5
+    pub number: usize,
+    /// offset in bytes
+    pub start: u64,
+    /// length in bytes
+    pub size: u64,
+    pub entry: PartitionEntry,
+    pub filesystem: Option<FilesystemInfo>,
+}
+
+/// partition table entry of a partition
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PartitionEntry {
+    Mbr {
+        type_id: u8,
+        bootable: bool,
+        /// inside an extended partition
+        logical: bool,
+    },
+    Gpt {
+        type_guid: Guid,
+        unique_guid: Guid,
+        name: String,
+        attributes: u64,
+    },
+}
+
+impl PartitionEntry {
+    /// name of the partition type, when it is a well-known one
+    pub fn type_name(&self) -> Option<&'static str> {
+        match self {
+            PartitionEntry::Mbr { type_id, .. } => MBR_TYPES
+                .iter()
+                .find(|(id, _)| id == type_id)
+                .map(|(_, name)| *name),
+            PartitionEntry::Gpt { type_guid, .. } => {
+                let guid = type_guid.to_string();
+                GPT_TYPES
+                    .iter()
+                    .find(|(id, _)| *id == guid)
+                    .map(|(_, name)| *name)
+            }
+        }
+    }
+
+    /// names of the flags set on the partition
+    pub fn flags(&self) -> Vec<&'static str> {
+        match self {
+            PartitionEntry::Mbr { bootable, .. } => {
+                if *bootable {
+                    vec!["boot"]
+                } else {
+                    Vec::new()
+                }
+            }
+            PartitionEntry::Gpt { attributes, .. } => GPT_FLAGS
+                .iter()
+                .filter(|(bit, _)| attributes & (1 << bit) != 0)
+                .map(|(_, name)| *name)
+                .collect(),
+        }
+    }
+}
+
+/// kind of file system, named as `blkid` does
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilesystemKind {
+    Ext2,
+    Ext3,
+    Ext4,
+    Xfs,
+    Btrfs,
+    Vfat,
+    Swap,
+    Luks,
+    Apfs,
+    Iso9660,
+}
+
+impl fmt::Display for FilesystemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            FilesystemKind::Ext2 => "ext2",
+            FilesystemKind::Ext3 => "ext3",
+            FilesystemKind::Ext4 => "ext4",
+            FilesystemKind::Xfs => "xfs",
+            FilesystemKind::Btrfs => "btrfs",
+            FilesystemKind::Vfat => "vfat",
+            FilesystemKind::Swap => "swap",
+            FilesystemKind::Luks => "crypto_LUKS",
+            FilesystemKind::Apfs => "apfs",
+            FilesystemKind::Iso9660 => "iso9660",
+        };
+        f.write_str(name)
+    }
+}
+
+/// file system found by its superblock
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilesystemInfo {
+    pub kind: FilesystemKind,
+    /// variant of the format, e.g. `FAT32`, LUKS `2` or swap `1`
+    pub version: Option<String>,
+    pub label: Option<String>,
+    /// UUID in the form `blkid` prints it
+    pub uuid: Option<String>,
+}
+
+impl fmt::Display for FilesystemInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(version) = &self.version {
+            write!(f, " ({})", version)?;
+        }
+        if let Some(label) = &self.label {
+            write!(f, " label={:?}", label)?;
+        }
+        if let Some(uuid) = &self.uuid {
+            write!(f, " uuid={}", uuid)?;
+        }
+        Ok(())
+    }
+}
+
+/// inspects the partition table and file systems of the raw disk image at
+/// `path`
+///
+/// Nothing is written, so this also works on images that are attached.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::inspect::inspect_disk_image;
+/// # fn main() -> std::io::Result<()> {
+/// let report = inspect_disk_image("ubuntu.img")?;
+/// print!("{}", report);
+/// for partition in &report.partitions {
+///     if let Some(fs) = &partition.filesystem {
+///         println!("{} {} {:?}", partition.number, fs.kind, fs.uuid);
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn inspect_disk_image<P: AsRef<Path>>(path: P) -> io::Result<DiskReport> {
+    let path = path.as_ref();
+    let file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let filesystem = probe_filesystem(&file, 0, size)?;
+
+    let (table, partitions) = if let Some(gpt) = Gpt::read(&file)? {
+        let table = PartitionTable::Gpt {
+            disk_guid: gpt.header.disk_guid,
+            block_size: gpt.block_size,
+        };
+        (table, gpt_partitions(&file, &gpt, size)?)
+    } else if filesystem
+        .as_ref()
+        .is_some_and(|fs| fs.kind == FilesystemKind::Vfat)
+    {
+        // the boot sector of an unpartitioned FAT volume looks like an MBR
+        (PartitionTable::None, Vec::new())
+    } else {
+        match mbr_partitions(&file, size)? {
+            Some((disk_signature, partitions)) => {
+                (PartitionTable::Mbr { disk_signature }, partitions)
+            }
+            None => (PartitionTable::None, Vec::new()),
+        }
+    };
+
+    Ok(DiskReport {
+        path: path.to_path_buf(),
+        size,
+        table,
+        partitions,
+        filesystem,
+    })
+}
+
+/// inspects every disk of the definition
+pub fn inspect_definition(definition: &VirtualMachineDefinition) -> io::Result<Vec<DiskReport>> {
+    definition
+        .storage_devices
+        .iter()
+        .map(|disk| inspect_disk_image(&disk.path))
+        .collect()
+}
+
+fn gpt_partitions(file: &File, gpt: &Gpt, size: u64) -> io::Result<Vec<PartitionReport>> {
+    let mut partitions = Vec::new();
+    for (index, entry) in gpt.partitions.iter().enumerate() {
+        if !entry.is_used() || entry.last_lba < entry.first_lba {
+            continue;
+        }
+        // corrupt entries must not overflow
+        let start = entry.first_lba.saturating_mul(gpt.block_size);
+        let len = (entry.last_lba - entry.first_lba)
+            .saturating_add(1)
+            .saturating_mul(gpt.block_size);
+        partitions.push(PartitionReport {
+            number: index + 1,
+            start,
+            size: len,
+            entry: PartitionEntry::Gpt {
+                type_guid: entry.type_guid,
+                unique_guid: entry.unique_guid,
+                name: entry.name(),
+                attributes: entry.attributes,
+            },
+            filesystem: probe_partition(file, start, len, size)?,
+        });
+    }
+    Ok(partitions)
+}
+
+/// reads the MBR and the extended boot records it links to
+fn mbr_partitions(file: &File, size: u64) -> io::Result<Option<(u32, Vec<PartitionReport>)>> {
+    let sector = read_bytes(file, 0, MBR_SECTOR_SIZE as usize, size)?;
+    let entries = match mbr_entries(&sector) {
+        Some(entries) if entries.iter().any(|e| e.type_id != 0) => entries,
+        _ => return Ok(None),
+    };
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.type_id == 0 || entry.sectors == 0 {
+            continue;
+        }
+        let start = entry.lba * MBR_SECTOR_SIZE;
+        let len = entry.sectors * MBR_SECTOR_SIZE;
+        let extended = is_extended(entry.type_id);
+        partitions.push(PartitionReport {
+            number: i + 1,
+            start,
+            size: len,
+            entry: PartitionEntry::Mbr {
+                type_id: entry.type_id,
+                bootable: entry.bootable,
+                logical: false,
+            },
+            filesystem: if extended || entry.type_id == MBR_TYPE_PROTECTIVE {
+                None
+            } else {
+                probe_partition(file, start, len, size)?
+            },
+        });
+        if extended {
+            logical_partitions(file, entry.lba, size, &mut partitions)?;
+        }
+    }
+    let disk_signature = u32::from_le_bytes([sector[440], sector[441], sector[442], sector[443]]);
+    Ok(Some((disk_signature, partitions)))
+}
+
+/// follows the chain of extended boot records starting at `extended_lba`
+fn logical_partitions(
+    file: &File,
+    extended_lba: u64,
+    size: u64,
+    partitions: &mut Vec<PartitionReport>,
+) -> io::Result<()> {
+    let mut ebr_lba = extended_lba;
+    // as in Linux, only EBRs that hold a partition take a number
+    let mut number = 5;
+    for _ in 0..MAX_LOGICAL_PARTITIONS {
+        if (ebr_lba + 1) * MBR_SECTOR_SIZE > size {
+            break;
+        }
+        let sector = read_bytes(
+            file,
+            ebr_lba * MBR_SECTOR_SIZE,
+            MBR_SECTOR_SIZE as usize,
+            size,
+        )?;
+        let entries = match mbr_entries(&sector) {
+            Some(entries) => entries,
+            None => break,
+        };
+        let logical = &entries[0];
+        if logical.type_id != 0 && logical.sectors != 0 {
+            let start = (ebr_lba + logical.lba) * MBR_SECTOR_SIZE;
+            let len = logical.sectors * MBR_SECTOR_SIZE;
+            partitions.push(PartitionReport {
+                number,
+                start,
+                size: len,
+                entry: PartitionEntry::Mbr {
+                    type_id: logical.type_id,
+                    bootable: logical.bootable,
+                    logical: true,
+                },
+                filesystem: probe_partition(file, start, len, size)?,
+            });
+            number += 1;
+        }
+        let next = &entries[1];
+        if !is_extended(next.type_id) || next.lba == 0 {
+            break;
+        }
+        // links are relative to the start of the extended partition
+        ebr_lba = extended_lba + next.lba;
+    }
+    Ok(())
+}
+
+struct MbrEntry {
+    bootable: bool,
+    type_id: u8,
+    lba: u64,
+    sectors: u64,
+}
+
+/// parses the four entries of an MBR or EBR sector, `None` when the sector
+/// is not one
+fn mbr_entries(sector: &[u8]) -> Option<Vec<MbrEntry>> {
+    if sector.len() < 512 || sector[510..512] != [0x55, 0xaa] {
+        return None;
+    }
+    let mut entries = Vec::with_capacity(4);
+    for raw in sector[446..510].chunks(16) {
+        if raw[0] != 0 && raw[0] != 0x80 {
+            return None;
+        }
+        entries.push(MbrEntry {
+            bootable: raw[0] == 0x80,
+            type_id: raw[4],
+            lba: u32::from_le_bytes([raw[8], raw[9], raw[10], raw[11]]) as u64,
+            sectors: u32::from_le_bytes([raw[12], raw[13], raw[14], raw[15]]) as u64,
+        });
+    }
+    Some(entries)
+}
+
+fn is_extended(type_id: u8) -> bool {
+    type_id == 0x05 || type_id == 0x0f || type_id == 0x85
+}
+
+/// probes a partition, clipped to the end of the image
+fn probe_partition(
+    file: &File,
+    start: u64,
+    len: u64,
+    size: u64,
+) -> io::Result<Option<FilesystemInfo>> {
+    if start >= size {
+        return Ok(None);
+    }
+    probe_filesystem(file, start, len.min(size - start))
+}
+
+/// looks for a file system superblock in `offset..offset + len` of `file`
+pub fn probe_filesystem(file: &File, offset: u64, len: u64) -> io::Result<Option<FilesystemInfo>> {
+    let end = offset.saturating_add(len);
+    let read = |at: u64, n: usize| read_bytes(file, offset + at, n, end);
+
+    let head = read(0, 4096)?;
+    if let Some(info) = probe_luks(&head)
+        .or_else(|| probe_xfs(&head))
+        .or_else(|| probe_apfs(&head))
+    {
+        return Ok(Some(info));
+    }
+    if let Some(info) = probe_ext(&read(1024, 1024)?) {
+        return Ok(Some(info));
+    }
+    if let Some(info) = probe_btrfs(&read(0x10000, 4096)?) {
+        return Ok(Some(info));
+    }
+    if let Some(info) = probe_iso9660(&read(0x8000, ISO_DESCRIPTORS * 2048)?) {
+        return Ok(Some(info));
+    }
+    for &page_size in SWAP_PAGE_SIZES.iter() {
+        if let Some(info) = probe_swap(&read(0, page_size as usize)?) {
+            return Ok(Some(info));
+        }
+    }
+    Ok(probe_vfat(&head))
+}
+
+fn probe_luks(raw: &[u8]) -> Option<FilesystemInfo> {
+    if raw.len() < 512 || raw[0..6] != *b"LUKS\xba\xbe" {
+        return None;
+    }
+    let version = u16::from_be_bytes([raw[6], raw[7]]);
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Luks,
+        version: Some(version.to_string()),
+        // LUKS1 has no label; the field holds the cipher name instead
+        label: if version >= 2 {
+            text(&raw[24..72])
+        } else {
+            None
+        },
+        uuid: text(&raw[168..208]),
+    })
+}
+
+fn probe_xfs(raw: &[u8]) -> Option<FilesystemInfo> {
+    if raw.len() < 120 || raw[0..4] != *b"XFSB" {
+        return None;
+    }
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Xfs,
+        version: Some((u16::from_be_bytes([raw[100], raw[101]]) & 0xf).to_string()),
+        label: text(&raw[108..120]),
+        uuid: uuid(&raw[32..48]),
+    })
+}
+
+fn probe_apfs(raw: &[u8]) -> Option<FilesystemInfo> {
+    if raw.len() < 88 || raw[32..36] != *b"NXSB" {
+        return None;
+    }
+    // volume names live in the volume superblocks, behind the object map
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Apfs,
+        version: None,
+        label: None,
+        uuid: uuid(&raw[72..88]),
+    })
+}
+
+fn probe_ext(sb: &[u8]) -> Option<FilesystemInfo> {
+    if sb.len() < 136 || sb[56..58] != [0x53, 0xef] {
+        return None;
+    }
+    let u32_at = |at: usize| u32::from_le_bytes([sb[at], sb[at + 1], sb[at + 2], sb[at + 3]]);
+    let compat = u32_at(92);
+    let incompat = u32_at(96);
+    let ro_compat = u32_at(100);
+    // the features mke2fs only enables for ext4
+    let ext4_incompat = 0x0040 | 0x0080 | 0x0200 | 0x0400 | 0x10000;
+    let ext4_ro_compat = 0x0008 | 0x0010 | 0x0020 | 0x0040 | 0x0400;
+    let kind = if incompat & ext4_incompat != 0 || ro_compat & ext4_ro_compat != 0 {
+        FilesystemKind::Ext4
+    } else if compat & 0x0004 != 0 {
+        FilesystemKind::Ext3
+    } else {
+        FilesystemKind::Ext2
+    };
+    Some(FilesystemInfo {
+        kind,
+        version: Some(format!(
+            "{}.{}",
+            u32_at(76),
+            u16::from_le_bytes([sb[62], sb[63]])
+        )),
+        label: text(&sb[120..136]),
+        uuid: uuid(&sb[104..120]),
+    })
+}
+
+fn probe_btrfs(sb: &[u8]) -> Option<FilesystemInfo> {
+    if sb.len() < 0x22b || sb[0x40..0x48] != *b"_BHRfS_M" {
+        return None;
+    }
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Btrfs,
+        version: None,
+        label: text(&sb[0x12b..0x22b]),
+        uuid: uuid(&sb[0x20..0x30]),
+    })
+}
+
+fn probe_iso9660(descriptors: &[u8]) -> Option<FilesystemInfo> {
+    let pvd = descriptors.get(..2048)?;
+    if pvd[0] != 1 || pvd[1..6] != *b"CD001" {
+        return None;
+    }
+    // the Joliet label keeps the case and characters the primary one drops
+    let joliet = descriptors
+        .chunks_exact(2048)
+        .skip(1)
+        .take_while(|d| d[1..6] == *b"CD001" && d[0] != 255)
+        .find(|d| d[0] == 2 && d[88..90] == *b"%/" && b"@CE".contains(&d[90]))
+        .and_then(|d| {
+            let units: Vec<u16> = d[40..72]
+                .chunks(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            text(String::from_utf16_lossy(&units).as_bytes())
+        });
+    // blkid derives the UUID from the creation time, YYYYMMDDHHMMSScc
+    let created = &pvd[813..829];
+    let uuid = if created.iter().all(u8::is_ascii_digit) && created.iter().any(|&c| c != b'0') {
+        let d = String::from_utf8_lossy(created);
+        Some(format!(
+            "{}-{}-{}-{}-{}-{}-{}",
+            &d[0..4],
+            &d[4..6],
+            &d[6..8],
+            &d[8..10],
+            &d[10..12],
+            &d[12..14],
+            &d[14..16]
+        ))
+    } else {
+        None
+    };
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Iso9660,
+        version: None,
+        label: joliet.or_else(|| text(&pvd[40..72])),
+        uuid,
+    })
+}
+
+fn probe_swap(page: &[u8]) -> Option<FilesystemInfo> {
+    if page.len() < 4096 {
+        return None;
+    }
+    let magic = &page[page.len() - 10..];
+    if magic == b"SWAPSPACE2" {
+        Some(FilesystemInfo {
+            kind: FilesystemKind::Swap,
+            version: Some(
+                u32::from_le_bytes([page[1024], page[1025], page[1026], page[1027]]).to_string(),
+            ),
+            label: text(&page[1052..1068]),
+            uuid: uuid(&page[1036..1052]),
+        })
+    } else if magic == b"SWAP-SPACE" {
+        Some(FilesystemInfo {
+            kind: FilesystemKind::Swap,
+            version: Some("0".to_string()),
+            label: None,
+            uuid: None,
+        })
+    } else {
+        None
+    }
+}
+
+fn probe_vfat(bs: &[u8]) -> Option<FilesystemInfo> {
+    if bs.len() < 512 || bs[510..512] != [0x55, 0xaa] {
+        return None;
+    }
+    let bytes_per_sector = u16::from_le_bytes([bs[11], bs[12]]);
+    let sectors_per_cluster = bs[13];
+    let reserved = u16::from_le_bytes([bs[14], bs[15]]);
+    let fats = bs[16];
+    if !(512..=4096).contains(&bytes_per_sector)
+        || !bytes_per_sector.is_power_of_two()
+        || !sectors_per_cluster.is_power_of_two()
+        || reserved == 0
+        || !(1..=2).contains(&fats)
+    {
+        return None;
+    }
+    // FAT32 has no 16-bit FAT size and a longer BPB
+    let ext = if u16::from_le_bytes([bs[22], bs[23]]) == 0 {
+        64
+    } else {
+        36
+    };
+    let fs_type = &bs[ext + 18..ext + 26];
+    if !fs_type.starts_with(b"FAT") {
+        return None;
+    }
+    let serial = &bs[ext + 3..ext + 7];
+    let label = text(&bs[ext + 7..ext + 18]).filter(|label| label != "NO NAME");
+    Some(FilesystemInfo {
+        kind: FilesystemKind::Vfat,
+        version: text(fs_type),
+        label,
+        uuid: Some(format!(
+            "{:02X}{:02X}-{:02X}{:02X}",
+            serial[3], serial[2], serial[1], serial[0]
+        )),
+    })
+}
+
+/// reads up to `len` bytes at `offset`, stopping at `end`
+fn read_bytes(file: &File, offset: u64, len: usize, end: u64) -> io::Result<Vec<u8>> {
+    let len = end.saturating_sub(offset).min(len as u64) as usize;
+    let mut buf = vec![0u8; len];
+    let mut done = 0;
+    while done < len {
+        match file.read_at(&mut buf[done..], offset + done as u64)? {
+            0 => break,
+            n => done += n,
+        }
+    }
+    buf.truncate(done);
+    Ok(buf)
+}
+
+/// text field padded with zeros or spaces
+fn text(raw: &[u8]) -> Option<String> {
+    let end = raw.iter().position(|&b| b == 0).unwrap_or(raw.len());
+    let text = String::from_utf8_lossy(&raw[..end]).trim_end().to_string();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// big endian UUID, `None` when it is nil
+fn uuid(raw: &[u8]) -> Option<String> {
+    if raw.iter().all(|&b| b == 0) {
+        return None;
+    }
+    let hex: String = raw.iter().map(|b| format!("{:02x}", b)).collect();
+    Some(format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    ))
+}
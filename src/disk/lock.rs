@@ -0,0 +1,205 @@
+//! disk image lock module
+
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+
+/// locks held by this process, as device, inode and whether they are exclusive
+static HELD: Mutex<Vec<(u64, u64, bool)>> = Mutex::new(Vec::new());
+
+/// error of `DiskImageLock`
+#[derive(Debug)]
+pub enum DiskImageLockError {
+    Io(io::Error),
+    /// another attachment, in this or another process, holds a conflicting lock
+    Locked {
+        path: PathBuf,
+        /// process holding the lock, when it can be found out
+        pid: Option<u32>,
+    },
+}
+
+impl fmt::Display for DiskImageLockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DiskImageLockError::Io(err) => write!(f, "{}", err),
+            DiskImageLockError::Locked {
+                path,
+                pid: Some(pid),
+            } => write!(f, "{} is in use by process {}", path.display(), pid),
+            DiskImageLockError::Locked { path, pid: None } => {
+                write!(f, "{} is in use by another process", path.display())
+            }
+        }
+    }
+}
+
+impl error::Error for DiskImageLockError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DiskImageLockError::Io(err) => Some(err),
+            DiskImageLockError::Locked { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for DiskImageLockError {
+    fn from(err: io::Error) -> Self {
+        DiskImageLockError::Io(err)
+    }
+}
+
+/// advisory lock on a disk image, released when dropped
+///
+/// Writers take an exclusive lock and readers a shared one, so an image is
+/// either written by a single attachment or read by any number of them.
+/// The lock is a `flock(2)` lock: it conflicts between processes as well as
+/// between attachments of the same process, and it is released by the
+/// kernel when the holder exits.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::lock::{DiskImageLock, DiskImageLockError};
+/// # fn main() -> Result<(), DiskImageLockError> {
+/// let lock = DiskImageLock::exclusive("disk.img")?;
+/// match DiskImageLock::shared("disk.img") {
+///     Err(DiskImageLockError::Locked { pid, .. }) => println!("held by {:?}", pid),
+///     _ => unreachable!(),
+/// }
+/// drop(lock);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct DiskImageLock {
+    file: File,
+    path: PathBuf,
+    id: (u64, u64),
+    exclusive: bool,
+}
+
+impl DiskImageLock {
+    /// locks `path` for writing
+    pub fn exclusive<P: AsRef<Path>>(path: P) -> Result<DiskImageLock, DiskImageLockError> {
+        DiskImageLock::acquire(path.as_ref(), true)
+    }
+
+    /// locks `path` for reading
+    pub fn shared<P: AsRef<Path>>(path: P) -> Result<DiskImageLock, DiskImageLockError> {
+        DiskImageLock::acquire(path.as_ref(), false)
+    }
+
+    fn acquire(path: &Path, exclusive: bool) -> Result<DiskImageLock, DiskImageLockError> {
+        let file = OpenOptions::new().read(true).write(exclusive).open(path)?;
+        let metadata = file.metadata()?;
+        let id = (metadata.dev(), metadata.ino());
+        let operation = if exclusive {
+            libc::LOCK_EX
+        } else {
+            libc::LOCK_SH
+        };
+        // holding the registry across flock keeps the holder lookup of a
+        // conflicting thread of this process accurate
+        let mut held = HELD.lock().unwrap_or_else(|err| err.into_inner());
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } != 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(err.into());
+            }
+            let pid = if held
+                .iter()
+                .any(|&(dev, ino, ex)| (dev, ino) == id && (exclusive || ex))
+            {
+                Some(process::id())
+            } else {
+                other_holder(&file, id, exclusive)
+            };
+            return Err(DiskImageLockError::Locked {
+                path: path.to_path_buf(),
+                pid,
+            });
+        }
+        held.push((id.0, id.1, exclusive));
+        Ok(DiskImageLock {
+            file,
+            path: path.to_path_buf(),
+            id,
+            exclusive,
+        })
+    }
+
+    /// file the lock is held on, opened for writing when it is exclusive
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive
+    }
+}
+
+impl Drop for DiskImageLock {
+    fn drop(&mut self) {
+        let mut held = HELD.lock().unwrap_or_else(|err| err.into_inner());
+        let entry = (self.id.0, self.id.1, self.exclusive);
+        if let Some(index) = held.iter().position(|&e| e == entry) {
+            held.remove(index);
+        }
+        // the lock itself goes away when `file` is closed
+    }
+}
+
+/// process of another program holding a lock that conflicts with the
+/// requested one
+///
+/// Record locks report their owner through `F_GETLK`. Owners of `flock`
+/// locks are only listed in `/proc/locks` on Linux.
+fn other_holder(file: &File, id: (u64, u64), exclusive: bool) -> Option<u32> {
+    let mut query: libc::flock = unsafe { std::mem::zeroed() };
+    query.l_type = if exclusive {
+        libc::F_WRLCK
+    } else {
+        libc::F_RDLCK
+    } as _;
+    query.l_whence = libc::SEEK_SET as _;
+    if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_GETLK, &mut query) } == 0
+        && query.l_type != libc::F_UNLCK as _
+        && query.l_pid > 0
+    {
+        return Some(query.l_pid as u32);
+    }
+    proc_locks_holder(id, exclusive)
+}
+
+#[cfg(target_os = "linux")]
+fn proc_locks_holder((dev, ino): (u64, u64), exclusive: bool) -> Option<u32> {
+    let locks = std::fs::read_to_string("/proc/locks").ok()?;
+    let device = format!("{:02x}:{:02x}:{}", libc::major(dev), libc::minor(dev), ino);
+    // "1: FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF", waiters carry "->"
+    locks.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 || fields[1] == "->" {
+            return None;
+        }
+        let conflicts = exclusive || fields[3] == "WRITE";
+        if conflicts && fields[5] == device {
+            fields[4].parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn proc_locks_holder(_id: (u64, u64), _exclusive: bool) -> Option<u32> {
+    None
+}
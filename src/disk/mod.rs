@@ -0,0 +1,21 @@
+//! disk image module
+//!
+//! Tools for the raw disk images that are handed to
+//! `VZDiskImageStorageDeviceAttachment`. Nothing here depends on
+//! Virtualization.framework, so it also works on Linux hosts.
+
+pub mod backup;
+pub mod bundle;
+pub mod clone;
+pub mod gpt;
+pub mod import;
+pub mod inspect;
+pub mod lock;
+pub mod resize;
+pub mod scratch;
+pub mod snapshot;
+pub mod sparse;
+pub mod store;
+mod tar;
+pub mod usage;
+pub mod verify;
@@ -0,0 +1,210 @@
+//! raw disk image resize module
+
+use crate::disk::gpt::Gpt;
+use crate::disk::sparse::{data_ranges, is_zero};
+
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+const SCAN_CHUNK: u64 = 1 << 20;
+
+/// error of `resize_raw_disk_image`
+#[derive(Debug)]
+pub enum ResizeError {
+    Io(io::Error),
+    /// the new size is not a multiple of the logical block size of the GPT
+    Unaligned {
+        size: u64,
+        block_size: u64,
+    },
+    /// the new size cannot hold both copies of the GPT
+    TooSmall {
+        size: u64,
+        min_size: u64,
+    },
+    /// shrinking would cut off the end of a partition
+    PartitionOutOfRange {
+        index: usize,
+        last_lba: u64,
+        last_usable_lba: u64,
+    },
+    /// shrinking an image without partition table would discard data
+    DataBeyondEnd {
+        offset: u64,
+    },
+}
+
+impl fmt::Display for ResizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResizeError::Io(err) => write!(f, "{}", err),
+            ResizeError::Unaligned { size, block_size } => write!(
+                f,
+                "size {} is not a multiple of the block size {}",
+                size, block_size
+            ),
+            ResizeError::TooSmall { size, min_size } => write!(
+                f,
+                "size {} is smaller than the minimum size {}",
+                size, min_size
+            ),
+            ResizeError::PartitionOutOfRange {
+                index,
+                last_lba,
+                last_usable_lba,
+            } => write!(
+                f,
+                "partition {} ends at block {} beyond the last usable block {}",
+                index + 1,
+                last_lba,
+                last_usable_lba
+            ),
+            ResizeError::DataBeyondEnd { offset } => {
+                write!(
+                    f,
+                    "image holds data at offset {} beyond the new size",
+                    offset
+                )
+            }
+        }
+    }
+}
+
+impl error::Error for ResizeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ResizeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ResizeError {
+    fn from(err: io::Error) -> Self {
+        ResizeError::Io(err)
+    }
+}
+
+/// resizes the raw disk image at `path` to `size` bytes
+///
+/// Growing extends the file sparsely. When the image has a GPT, the backup
+/// header and partition array are moved to the new end of the image and,
+/// if `grow_last_partition` is set, the last partition is extended up to the
+/// new last usable block. Shrinking is refused when it would cut off a
+/// partition, or data for images without a GPT.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::resize::resize_raw_disk_image;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// resize_raw_disk_image("ubuntu.img", 64 << 30, true)?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn resize_raw_disk_image<P: AsRef<Path>>(
+    path: P,
+    size: u64,
+    grow_last_partition: bool,
+) -> Result<(), ResizeError> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let old_size = file.metadata()?.len();
+    match Gpt::read(&file)? {
+        Some(gpt) => resize_gpt(&file, gpt, old_size, size, grow_last_partition),
+        None => resize_plain(&file, old_size, size),
+    }
+}
+
+fn resize_plain(file: &File, old_size: u64, size: u64) -> Result<(), ResizeError> {
+    if size < old_size {
+        let mut buf = vec![0u8; SCAN_CHUNK as usize];
+        for range in data_ranges(file, size, old_size)? {
+            let mut offset = range.start;
+            while offset < range.end {
+                let len = (range.end - offset).min(SCAN_CHUNK) as usize;
+                file.read_exact_at(&mut buf[..len], offset)?;
+                if let Some(pos) = buf[..len].iter().position(|&b| b != 0) {
+                    return Err(ResizeError::DataBeyondEnd {
+                        offset: offset + pos as u64,
+                    });
+                }
+                offset += len as u64;
+            }
+        }
+    }
+    file.set_len(size)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+fn resize_gpt(
+    file: &File,
+    mut gpt: Gpt,
+    old_size: u64,
+    size: u64,
+    grow_last_partition: bool,
+) -> Result<(), ResizeError> {
+    let block_size = gpt.block_size;
+    if !size.is_multiple_of(block_size) {
+        return Err(ResizeError::Unaligned { size, block_size });
+    }
+    let entry_blocks = gpt.entry_blocks();
+    let min_blocks = gpt.header.first_usable_lba + entry_blocks + 2;
+    let total_blocks = size / block_size;
+    if total_blocks < min_blocks {
+        return Err(ResizeError::TooSmall {
+            size,
+            min_size: min_blocks * block_size,
+        });
+    }
+
+    let old_backup_lba = gpt.header.backup_lba;
+    let data_end_lba = gpt
+        .last_partition()
+        .map(|i| gpt.partitions[i].last_lba)
+        .unwrap_or(0);
+
+    gpt.relocate_backup(total_blocks);
+    let last_usable_lba = gpt.header.last_usable_lba;
+    if grow_last_partition && size > old_size {
+        if let Some(i) = gpt.last_partition() {
+            let partition = &mut gpt.partitions[i];
+            partition.last_lba = partition.last_lba.max(last_usable_lba);
+        }
+    }
+    for (index, partition) in gpt.partitions.iter().enumerate() {
+        if partition.is_used() && partition.last_lba > last_usable_lba {
+            return Err(ResizeError::PartitionOutOfRange {
+                index,
+                last_lba: partition.last_lba,
+                last_usable_lba,
+            });
+        }
+    }
+
+    if size > old_size {
+        file.set_len(size)?;
+    }
+    gpt.write(file)?;
+    gpt.write_protective_mbr(file, total_blocks)?;
+
+    // the old backup copy now lies in free space; wipe it so that partition
+    // tools do not pick up a stale table
+    let stale_start = old_backup_lba.saturating_sub(entry_blocks);
+    if stale_start > data_end_lba && old_backup_lba < total_blocks - 1 - entry_blocks {
+        let stale = vec![0u8; ((entry_blocks + 1) * block_size) as usize];
+        let mut current = vec![0u8; stale.len()];
+        file.read_exact_at(&mut current, stale_start * block_size)?;
+        if !is_zero(&current) {
+            file.write_all_at(&stale, stale_start * block_size)?;
+        }
+    }
+
+    if size < old_size {
+        file.set_len(size)?;
+    }
+    file.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,248 @@
+//! scratch disk module
+
+use crate::definition::VirtualMachineLifecycle;
+use crate::fs::ext4::Ext4ImageBuilder;
+
+use std::convert::TryFrom;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+const FILE_PREFIX: &str = "vzrs-scratch-";
+const SWAP_PAGE_SIZE: u64 = 4096;
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// contents of a new scratch disk
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScratchFormat {
+    /// all zeros
+    Raw,
+    /// Linux swap area (version 1, 4 KiB pages), ready for `swapon`
+    Swap,
+    /// empty ext4 file system
+    Ext4,
+}
+
+/// builder for `ScratchDisk`
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::scratch::{ScratchDiskBuilder, ScratchFormat};
+/// # fn main() -> std::io::Result<()> {
+/// let swap = ScratchDiskBuilder::new(4 << 30)
+///     .format(ScratchFormat::Swap)
+///     .build()?;
+/// // VZDiskImageStorageDeviceAttachmentBuilder::new().scratch_disk(swap.clone()).build()
+/// // and, whenever the state of the virtual machine changes,
+/// // swap.remove_if_stopped(&vm)
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ScratchDiskBuilder {
+    size: u64,
+    format: ScratchFormat,
+    label: Option<String>,
+    dir: Option<PathBuf>,
+}
+
+impl ScratchDiskBuilder {
+    /// scratch disk of `size` bytes, rounded down to whole 4 KiB pages
+    pub fn new(size: u64) -> Self {
+        ScratchDiskBuilder {
+            size: size / SWAP_PAGE_SIZE * SWAP_PAGE_SIZE,
+            format: ScratchFormat::Raw,
+            label: None,
+            dir: None,
+        }
+    }
+
+    pub fn format(mut self, format: ScratchFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// label of the swap area or file system, up to 16 bytes
+    pub fn label<T: Into<String>>(mut self, label: T) -> Self {
+        self.label = Some(label.into());
+        self
+    }
+
+    /// directory of the image, the temporary directory by default
+    pub fn dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// creates the sparse image
+    ///
+    /// Scratch disks left behind in the directory by processes that no
+    /// longer run, e.g. killed ones, are deleted first.
+    pub fn build(&self) -> io::Result<ScratchDisk> {
+        if self.size == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "scratch disk size is smaller than a page",
+            ));
+        }
+        // resolved before the image exists, so no later step can fail and
+        // leave it behind
+        let dir = fs::canonicalize(match &self.dir {
+            Some(dir) => dir.clone(),
+            None => env::temp_dir(),
+        })?;
+        remove_stale(&dir)?;
+
+        let path = dir.join(format!(
+            "{}{}-{}.img",
+            FILE_PREFIX,
+            process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(&path)?;
+        // from here on the guard deletes the image when anything fails
+        let disk = ScratchDisk(Arc::new(ScratchImage {
+            path,
+            size: self.size,
+            format: self.format,
+        }));
+        file.set_len(self.size)?;
+        match self.format {
+            ScratchFormat::Raw => {}
+            ScratchFormat::Swap => write_swap_header(&file, self.size, self.label.as_deref())?,
+            ScratchFormat::Ext4 => {
+                let mut builder = Ext4ImageBuilder::new().size(self.size).journal(false);
+                if let Some(label) = &self.label {
+                    builder = builder.label(label.as_str());
+                }
+                builder.write_to_file(disk.path())?;
+            }
+        }
+        file.sync_all()?;
+        Ok(disk)
+    }
+}
+
+/// sparse raw disk image that is deleted once its virtual machine stops
+///
+/// Clones share the image. Hand one to the attachment and keep another to
+/// call `remove_if_stopped` whenever the state of the virtual machine
+/// changes: the image is deleted once the machine is stopped or has failed.
+/// Otherwise it is deleted when the last clone is dropped, which happens on
+/// normal exit and while unwinding from a panic, or, for the clone owned by
+/// an attachment, when the attachment object is deallocated. Images of
+/// processes that were killed are deleted by the next
+/// `ScratchDiskBuilder::build` in the same directory.
+#[derive(Clone, Debug)]
+pub struct ScratchDisk(Arc<ScratchImage>);
+
+#[derive(Debug)]
+struct ScratchImage {
+    path: PathBuf,
+    size: u64,
+    format: ScratchFormat,
+}
+
+impl ScratchDisk {
+    /// absolute path of the image
+    pub fn path(&self) -> &Path {
+        &self.0.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.0.size
+    }
+
+    pub fn format(&self) -> ScratchFormat {
+        self.0.format
+    }
+
+    /// deletes the image when `vm` is stopped or has failed; returns
+    /// whether the image is gone
+    ///
+    /// The framework may keep the image open until the attachment is
+    /// deallocated, so its space can come back only then.
+    pub fn remove_if_stopped<L: VirtualMachineLifecycle + ?Sized>(
+        &self,
+        vm: &L,
+    ) -> io::Result<bool> {
+        if !vm.state().is_stopped() {
+            return Ok(false);
+        }
+        match fs::remove_file(&self.0.path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(true),
+        }
+    }
+}
+
+impl Drop for ScratchImage {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// writes the header page of a Linux swap area, as `mkswap` does
+fn write_swap_header(file: &File, size: u64, label: Option<&str>) -> io::Result<()> {
+    let pages = size / SWAP_PAGE_SIZE;
+    if pages < 10 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "swap area needs at least 10 pages",
+        ));
+    }
+    let last_page = u32::try_from(pages - 1)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "swap area is too large"))?;
+    let mut uuid = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut uuid)?;
+    uuid[6] = (uuid[6] & 0x0f) | 0x40;
+    uuid[8] = (uuid[8] & 0x3f) | 0x80;
+
+    let mut page = vec![0u8; SWAP_PAGE_SIZE as usize];
+    // the first KiB is left for boot loaders
+    page[1024..1028].copy_from_slice(&1u32.to_le_bytes());
+    page[1028..1032].copy_from_slice(&last_page.to_le_bytes());
+    page[1036..1052].copy_from_slice(&uuid);
+    if let Some(label) = label {
+        if label.len() > 16 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("label {:?} is too long", label),
+            ));
+        }
+        page[1052..1052 + label.len()].copy_from_slice(label.as_bytes());
+    }
+    let magic = page.len() - SWAP_MAGIC.len();
+    page[magic..].copy_from_slice(SWAP_MAGIC);
+    file.write_all_at(&page, 0)
+}
+
+/// deletes the scratch disks in `dir` whose process is gone
+fn remove_stale(dir: &Path) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name();
+        let pid = name
+            .to_str()
+            .and_then(|name| name.strip_prefix(FILE_PREFIX))
+            .and_then(|rest| rest.split('-').next())
+            .and_then(|pid| pid.parse::<libc::pid_t>().ok());
+        if let Some(pid) = pid {
+            let ret = unsafe { libc::kill(pid, 0) };
+            if ret != 0 && io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH) {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,358 @@
+//! disk snapshot module
+
+use crate::definition::{VZVirtualMachineState, VirtualMachineDefinition, VirtualMachineLifecycle};
+use crate::disk::clone::clone_disk_image;
+
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const METADATA_FILE: &str = "snapshot";
+const RESTORE_SUFFIX: &str = ".restore";
+const JOURNAL_FILE: &str = ".restore-journal";
+
+/// error of `SnapshotManager`
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    /// the virtual machine is not stopped
+    NotStopped(VZVirtualMachineState),
+    InvalidName(String),
+    NotFound(String),
+    AlreadyExists(String),
+    /// the snapshot holds a disk that the definition does not have
+    DiskMismatch(PathBuf),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SnapshotError::Io(err) => write!(f, "{}", err),
+            SnapshotError::NotStopped(state) => {
+                write!(f, "virtual machine is not stopped ({:?})", state)
+            }
+            SnapshotError::InvalidName(name) => write!(f, "invalid snapshot name {:?}", name),
+            SnapshotError::NotFound(name) => write!(f, "snapshot {} not found", name),
+            SnapshotError::AlreadyExists(name) => write!(f, "snapshot {} already exists", name),
+            SnapshotError::DiskMismatch(path) => write!(
+                f,
+                "disk {} is not part of the virtual machine",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+/// named snapshot of the writable disks of a virtual machine
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Snapshot {
+    pub name: String,
+    pub created: SystemTime,
+    /// original paths of the disks in the snapshot
+    pub disks: Vec<PathBuf>,
+}
+
+/// creates, lists, deletes and restores snapshots of the disks of a virtual machine
+///
+/// Each snapshot is a directory below `dir` that holds a reflink (or sparse)
+/// copy of every writable disk of the definition. Read-only disks are not
+/// copied. Creating and restoring snapshots requires the virtual machine to
+/// be stopped.
+///
+/// A restore that is interrupted after its copies were staged is finished
+/// by `recover`, which `create` and `restore` also run first.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::definition::*;
+/// # use virtualization_rs::disk::snapshot::SnapshotManager;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// # let mut definition = VirtualMachineDefinition::new("web");
+/// # definition.storage_devices.push(StorageDeviceDefinition::new("web.img", false));
+/// # let vm = StoppedVm;
+/// let snapshots = SnapshotManager::new(&definition, "snapshots/web");
+/// snapshots.create("before-upgrade", &vm)?;
+/// snapshots.restore("before-upgrade", &vm)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct SnapshotManager<'a> {
+    definition: &'a VirtualMachineDefinition,
+    dir: PathBuf,
+}
+
+impl<'a> SnapshotManager<'a> {
+    pub fn new<P: Into<PathBuf>>(
+        definition: &'a VirtualMachineDefinition,
+        dir: P,
+    ) -> SnapshotManager<'a> {
+        SnapshotManager {
+            definition,
+            dir: dir.into(),
+        }
+    }
+
+    /// snapshots every writable disk of the definition as `name`
+    pub fn create<L: VirtualMachineLifecycle>(
+        &self,
+        name: &str,
+        vm: &L,
+    ) -> Result<Snapshot, SnapshotError> {
+        check_stopped(vm)?;
+        check_name(name)?;
+        self.recover()?;
+        let dst = self.dir.join(name);
+        if dst.exists() {
+            return Err(SnapshotError::AlreadyExists(name.to_string()));
+        }
+        fs::create_dir_all(&self.dir)?;
+
+        // build the snapshot next to its final location and rename it into
+        // place, so that a failure never leaves a partial snapshot behind
+        let tmp = self.dir.join(format!(".{}.tmp", name));
+        if tmp.exists() {
+            fs::remove_dir_all(&tmp)?;
+        }
+        fs::create_dir(&tmp)?;
+        let snapshot = Snapshot {
+            name: name.to_string(),
+            created: UNIX_EPOCH + Duration::from_secs(unix_time(SystemTime::now())),
+            disks: self.writable_disks(),
+        };
+        let result = self
+            .copy_disks(&snapshot, &tmp)
+            .and_then(|_| fs::rename(&tmp, &dst));
+        if let Err(err) = result {
+            let _ = fs::remove_dir_all(&tmp);
+            return Err(err.into());
+        }
+        Ok(snapshot)
+    }
+
+    /// returns the snapshots ordered by creation time
+    pub fn list(&self) -> Result<Vec<Snapshot>, SnapshotError> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut snapshots = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') || !entry.path().join(METADATA_FILE).is_file() {
+                continue;
+            }
+            snapshots.push(self.read(&name)?);
+        }
+        snapshots.sort_by(|a, b| a.created.cmp(&b.created).then(a.name.cmp(&b.name)));
+        Ok(snapshots)
+    }
+
+    /// returns the snapshot `name`
+    pub fn get(&self, name: &str) -> Result<Snapshot, SnapshotError> {
+        check_name(name)?;
+        self.read(name)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), SnapshotError> {
+        check_name(name)?;
+        let dir = self.dir.join(name);
+        if !dir.join(METADATA_FILE).is_file() {
+            return Err(SnapshotError::NotFound(name.to_string()));
+        }
+        fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+
+    /// restores the disks of the definition from the snapshot `name`
+    ///
+    /// All disks are staged next to the originals first, so a failing copy
+    /// leaves the current disks intact. The staged copies are then recorded
+    /// in a journal in the snapshot directory and renamed over the
+    /// originals. If this is interrupted, some disks may already hold the
+    /// snapshot while others do not; `recover` completes the restore from
+    /// the journal.
+    pub fn restore<L: VirtualMachineLifecycle>(
+        &self,
+        name: &str,
+        vm: &L,
+    ) -> Result<(), SnapshotError> {
+        check_stopped(vm)?;
+        self.recover()?;
+        let snapshot = self.get(name)?;
+        let writable = self.writable_disks();
+        if let Some(disk) = snapshot.disks.iter().find(|d| !writable.contains(d)) {
+            return Err(SnapshotError::DiskMismatch(disk.clone()));
+        }
+
+        let dir = self.dir.join(name);
+        let mut staged = Vec::with_capacity(snapshot.disks.len());
+        for (index, disk) in snapshot.disks.iter().enumerate() {
+            let tmp = restore_path(disk);
+            let _ = fs::remove_file(&tmp);
+            if let Err(err) = clone_disk_image(dir.join(disk_file_name(index, disk)), &tmp) {
+                for path in staged.iter() {
+                    let _ = fs::remove_file(path);
+                }
+                return Err(err.into());
+            }
+            staged.push(tmp);
+        }
+
+        let mut journal = String::new();
+        for disk in snapshot.disks.iter() {
+            journal.push_str(&format!("disk {}\n", disk.display()));
+        }
+        let journal_path = self.dir.join(JOURNAL_FILE);
+        let mut file = fs::File::create(&journal_path)?;
+        file.write_all(journal.as_bytes())?;
+        file.sync_all()?;
+        self.recover()
+    }
+
+    /// completes a restore that was interrupted while renaming its staged
+    /// disks into place
+    ///
+    /// Every staged copy listed in the journal that is still present is
+    /// renamed over its disk, then the journal is removed. Does nothing when
+    /// there is no journal.
+    pub fn recover(&self) -> Result<(), SnapshotError> {
+        let path = self.dir.join(JOURNAL_FILE);
+        let journal = match fs::read_to_string(&path) {
+            Ok(journal) => journal,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut disks = Vec::new();
+        for line in journal.lines() {
+            match line.strip_prefix("disk ") {
+                Some(disk) => disks.push(PathBuf::from(disk)),
+                None if line.is_empty() => {}
+                None => return Err(invalid_metadata(&path).into()),
+            }
+        }
+        for disk in disks {
+            let tmp = restore_path(&disk);
+            match fs::rename(&tmp, &disk) {
+                Ok(()) => {}
+                // already renamed before the interruption
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => return Err(err.into()),
+            }
+            if let Some(parent) = disk.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::File::open(parent)?.sync_all()?;
+            }
+        }
+        fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    fn writable_disks(&self) -> Vec<PathBuf> {
+        self.definition
+            .storage_devices
+            .iter()
+            .filter(|d| !d.read_only)
+            .map(|d| d.path.clone())
+            .collect()
+    }
+
+    fn copy_disks(&self, snapshot: &Snapshot, dir: &Path) -> io::Result<()> {
+        let mut metadata = format!("created {}\n", unix_time(snapshot.created));
+        for (index, disk) in snapshot.disks.iter().enumerate() {
+            clone_disk_image(disk, dir.join(disk_file_name(index, disk)))?;
+            metadata.push_str(&format!("disk {}\n", disk.display()));
+        }
+        let mut file = fs::File::create(dir.join(METADATA_FILE))?;
+        file.write_all(metadata.as_bytes())?;
+        file.sync_all()
+    }
+
+    fn read(&self, name: &str) -> Result<Snapshot, SnapshotError> {
+        let path = self.dir.join(name).join(METADATA_FILE);
+        let metadata = match fs::read_to_string(&path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                return Err(SnapshotError::NotFound(name.to_string()))
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let mut snapshot = Snapshot {
+            name: name.to_string(),
+            created: UNIX_EPOCH,
+            disks: Vec::new(),
+        };
+        for line in metadata.lines() {
+            if let Some(secs) = line.strip_prefix("created ") {
+                let secs = secs.parse().map_err(|_| invalid_metadata(&path))?;
+                snapshot.created = UNIX_EPOCH + Duration::from_secs(secs);
+            } else if let Some(disk) = line.strip_prefix("disk ") {
+                snapshot.disks.push(PathBuf::from(disk));
+            } else if !line.is_empty() {
+                return Err(invalid_metadata(&path).into());
+            }
+        }
+        Ok(snapshot)
+    }
+}
+
+fn check_stopped<L: VirtualMachineLifecycle>(vm: &L) -> Result<(), SnapshotError> {
+    let state = vm.state();
+    if state.is_stopped() {
+        Ok(())
+    } else {
+        Err(SnapshotError::NotStopped(state))
+    }
+}
+
+fn check_name(name: &str) -> Result<(), SnapshotError> {
+    if name.is_empty() || name.starts_with('.') || name.contains('/') || name.contains('\0') {
+        Err(SnapshotError::InvalidName(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+fn disk_file_name(index: usize, disk: &Path) -> String {
+    let name = disk
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    format!("{}-{}", index, name)
+}
+
+fn restore_path(disk: &Path) -> PathBuf {
+    let mut name = disk.file_name().unwrap_or_default().to_os_string();
+    name.push(RESTORE_SUFFIX);
+    disk.with_file_name(name)
+}
+
+fn unix_time(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn invalid_metadata(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid snapshot metadata {}", path.display()),
+    )
+}
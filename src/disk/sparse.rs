@@ -0,0 +1,97 @@
+//! sparse file module
+
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::unix::io::AsRawFd;
+
+/// returns the ranges of `file` within `start..end` that hold data
+///
+/// Holes are found with `SEEK_DATA`/`SEEK_HOLE`. When the file system does not
+/// support them the whole range is reported as data.
+/// The file offset of `file` is changed by this call.
+pub fn data_ranges(file: &File, start: u64, end: u64) -> io::Result<Vec<Range<u64>>> {
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        let data = unsafe { libc::lseek(fd, pos as libc::off_t, libc::SEEK_DATA) };
+        if data < 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(libc::ENXIO) => break,
+                Some(libc::EINVAL) | Some(libc::ENOTSUP) => {
+                    ranges.push(pos..end);
+                    break;
+                }
+                _ => return Err(err),
+            }
+        }
+        let data = data as u64;
+        if data >= end {
+            break;
+        }
+        let hole = unsafe { libc::lseek(fd, data as libc::off_t, libc::SEEK_HOLE) };
+        if hole < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let hole = (hole as u64).min(end);
+        ranges.push(data..hole);
+        pos = hole;
+    }
+    Ok(ranges)
+}
+
+/// returns true when every byte of `buf` is zero
+pub fn is_zero(buf: &[u8]) -> bool {
+    buf.iter().all(|&b| b == 0)
+}
+
+/// deallocates `len` bytes of `file` at `offset` without changing its size
+///
+/// The range reads back as zeros afterwards. Uses `fallocate(PUNCH_HOLE)` on
+/// Linux and `F_PUNCHHOLE` on macOS, where the range must be aligned to the
+/// file system block size.
+#[cfg(target_os = "linux")]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let ret = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            len as libc::off_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// deallocates `len` bytes of `file` at `offset` without changing its size
+///
+/// The range reads back as zeros afterwards. Uses `fallocate(PUNCH_HOLE)` on
+/// Linux and `F_PUNCHHOLE` on macOS, where the range must be aligned to the
+/// file system block size.
+#[cfg(target_os = "macos")]
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    let arg = libc::fpunchhole_t {
+        fp_flags: 0,
+        reserved: 0,
+        fp_offset: offset as libc::off_t,
+        fp_length: len as libc::off_t,
+    };
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_PUNCHHOLE, &arg) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// deallocates `len` bytes of `file` at `offset` without changing its size
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn punch_hole(_file: &File, _offset: u64, _len: u64) -> io::Result<()> {
+    Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP))
+}
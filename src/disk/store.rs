@@ -0,0 +1,596 @@
+//! image store module
+
+use crate::definition::VirtualMachineDefinition;
+use crate::disk::clone::{clone_disk_image, ClonedDiskImage};
+use crate::disk::sparse::data_ranges;
+use crate::util::write_atomic;
+
+use sha2::{Digest as _, Sha256};
+
+use std::error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const HASH_CHUNK: u64 = 1 << 20;
+const DIGEST_PREFIX: &str = "sha256:";
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// error of `ImageStore`
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    /// a tag or virtual machine name that cannot be stored as a file name
+    InvalidName(String),
+    /// a string that is neither a tag nor a `sha256:` digest
+    InvalidDigest(String),
+    /// no tag or image of that name
+    NotFound(String),
+    /// the checkout destination already exists
+    AlreadyExists(PathBuf),
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::Io(err) => write!(f, "{}", err),
+            StoreError::InvalidName(name) => write!(f, "invalid name {:?}", name),
+            StoreError::InvalidDigest(digest) => write!(f, "invalid digest {:?}", digest),
+            StoreError::NotFound(image) => write!(f, "image {} not found", image),
+            StoreError::AlreadyExists(path) => write!(f, "{} already exists", path.display()),
+        }
+    }
+}
+
+impl error::Error for StoreError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            StoreError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(err: io::Error) -> Self {
+        StoreError::Io(err)
+    }
+}
+
+/// SHA-256 digest of the contents of a disk image
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Digest([u8; 32]);
+
+impl Digest {
+    /// hashes the contents of the file at `path`, reading holes as zeros
+    pub fn of_file<P: AsRef<Path>>(path: P) -> io::Result<Digest> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; HASH_CHUNK as usize];
+        let mut pos = 0;
+        for range in data_ranges(&file, 0, len)?
+            .into_iter()
+            .chain(std::iter::once(len..len))
+        {
+            // the hole in front of the range
+            buf.iter_mut().for_each(|b| *b = 0);
+            while pos < range.start {
+                let n = (range.start - pos).min(HASH_CHUNK) as usize;
+                hasher.update(&buf[..n]);
+                pos += n as u64;
+            }
+            while pos < range.end {
+                let n = (range.end - pos).min(HASH_CHUNK) as usize;
+                file.read_exact_at(&mut buf[..n], pos)?;
+                hasher.update(&buf[..n]);
+                pos += n as u64;
+            }
+        }
+        Ok(Digest(hasher.finalize().into()))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// lowercase hexadecimal form without the `sha256:` prefix
+    pub fn to_hex(&self) -> String {
+        self.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl From<[u8; 32]> for Digest {
+    fn from(bytes: [u8; 32]) -> Digest {
+        Digest(bytes)
+    }
+}
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}{}", DIGEST_PREFIX, self.to_hex())
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Digest({})", self)
+    }
+}
+
+impl FromStr for Digest {
+    type Err = StoreError;
+
+    /// parses `sha256:<64 hex digits>`
+    fn from_str(s: &str) -> Result<Digest, StoreError> {
+        let invalid = || StoreError::InvalidDigest(s.to_string());
+        let hex = s.strip_prefix(DIGEST_PREFIX).ok_or_else(invalid)?;
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(invalid());
+        }
+        let mut bytes = [0u8; 32];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid())?;
+        }
+        Ok(Digest(bytes))
+    }
+}
+
+/// image held by the store
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub digest: Digest,
+    /// apparent size in bytes
+    pub size: u64,
+    pub tags: Vec<String>,
+}
+
+/// disk of a virtual machine that was checked out of the store
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reference {
+    /// name of the virtual machine definition
+    pub vm: String,
+    pub digest: Digest,
+    pub path: PathBuf,
+}
+
+/// result of `ImageStore::gc`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// images that were deleted
+    pub removed: Vec<Digest>,
+    /// references dropped because their disk no longer exists
+    pub stale_references: Vec<Reference>,
+    /// bytes of storage released
+    pub freed: u64,
+}
+
+/// content-addressed store of disk images
+///
+/// Every image is kept once below `dir`, named after the SHA-256 digest of
+/// its contents, and can be given any number of tags. Virtual machines get
+/// their own writable disk by checking an image out, which makes a reflink
+/// clone where the file system supports it, so the copies share their
+/// blocks with the stored image. Read-only disks can use `path` directly.
+///
+/// The store remembers which image every checked out disk came from.
+/// `gc` deletes the images that are neither tagged nor referenced by a
+/// disk of a tracked virtual machine.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::definition::*;
+/// # use virtualization_rs::disk::store::ImageStore;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let store = ImageStore::open("/var/lib/vms/images")?;
+/// let digest = store.import("ubuntu-22.04.img")?;
+/// store.tag("ubuntu:22.04", &digest.to_string())?;
+///
+/// let disk = store.checkout("web", "ubuntu:22.04", "/var/lib/vms/web/root.img")?;
+/// let mut definition = VirtualMachineDefinition::new("web");
+/// definition.storage_devices.push(StorageDeviceDefinition::new(&disk.path, false));
+/// store.track(&definition)?;
+/// // VZDiskImageStorageDeviceAttachmentBuilder::new().path(disk).read_only(false).build()
+///
+/// store.untag("ubuntu:22.04")?;
+/// let report = store.gc()?;
+/// println!("freed {} bytes", report.freed);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct ImageStore {
+    dir: PathBuf,
+}
+
+impl ImageStore {
+    /// opens the store at `dir`, creating it when it does not exist
+    pub fn open<P: Into<PathBuf>>(dir: P) -> Result<ImageStore, StoreError> {
+        let store = ImageStore { dir: dir.into() };
+        for dir in &[
+            store.blob_dir(),
+            store.tag_dir(),
+            store.ref_dir(),
+            store.tmp_dir(),
+        ] {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(store)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// adds the disk image at `path` to the store and returns its digest
+    ///
+    /// Importing an image that is already stored only returns its digest.
+    pub fn import<P: AsRef<Path>>(&self, path: P) -> Result<Digest, StoreError> {
+        let tmp = self.tmp_dir().join(format!(
+            "{}-{}",
+            process::id(),
+            TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = fs::remove_file(&tmp);
+        let result = clone_disk_image(path, &tmp)
+            .and_then(|_| Digest::of_file(&tmp))
+            .and_then(|digest| {
+                fs::set_permissions(&tmp, fs::Permissions::from_mode(0o444))?;
+                let _lock = self.lock()?;
+                let blob = self.blob_path(&digest);
+                if blob.exists() {
+                    fs::remove_file(&tmp)?;
+                } else {
+                    fs::rename(&tmp, &blob)?;
+                }
+                Ok(digest)
+            });
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+        }
+        Ok(result?)
+    }
+
+    /// points the tag `name` at `image`, a tag or a digest
+    pub fn tag(&self, name: &str, image: &str) -> Result<Digest, StoreError> {
+        check_name(name)?;
+        let _lock = self.lock()?;
+        let digest = self.resolve(image)?;
+        write_atomic(
+            &self.tag_dir().join(name),
+            format!("{}\n", digest).as_bytes(),
+        )?;
+        Ok(digest)
+    }
+
+    pub fn untag(&self, name: &str) -> Result<(), StoreError> {
+        check_name(name)?;
+        let _lock = self.lock()?;
+        match fs::remove_file(self.tag_dir().join(name)) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                Err(StoreError::NotFound(name.to_string()))
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// returns the tags and the digests they point at, ordered by name
+    pub fn tags(&self) -> Result<Vec<(String, Digest)>, StoreError> {
+        let mut tags = Vec::new();
+        for name in list_dir(&self.tag_dir())? {
+            if let Some(digest) = self.read_tag(&name)? {
+                tags.push((name, digest));
+            }
+        }
+        tags.sort();
+        Ok(tags)
+    }
+
+    /// returns the stored images ordered by digest
+    pub fn images(&self) -> Result<Vec<Image>, StoreError> {
+        let tags = self.tags()?;
+        let mut images = Vec::new();
+        for name in list_dir(&self.blob_dir())? {
+            let digest = match Digest::from_str(&format!("{}{}", DIGEST_PREFIX, name)) {
+                Ok(digest) => digest,
+                Err(_) => continue,
+            };
+            images.push(Image {
+                digest,
+                size: fs::metadata(self.blob_path(&digest))?.len(),
+                tags: tags
+                    .iter()
+                    .filter(|(_, d)| *d == digest)
+                    .map(|(name, _)| name.clone())
+                    .collect(),
+            });
+        }
+        images.sort_by_key(|image| image.digest);
+        Ok(images)
+    }
+
+    /// returns the digest of `image`, a tag or a `sha256:` digest
+    pub fn resolve(&self, image: &str) -> Result<Digest, StoreError> {
+        let digest = if image.starts_with(DIGEST_PREFIX) {
+            image.parse()?
+        } else {
+            check_name(image)?;
+            self.read_tag(image)?
+                .ok_or_else(|| StoreError::NotFound(image.to_string()))?
+        };
+        if self.blob_path(&digest).is_file() {
+            Ok(digest)
+        } else {
+            Err(StoreError::NotFound(image.to_string()))
+        }
+    }
+
+    /// path of the stored file of `image`
+    ///
+    /// Stored images are read-only and may only be attached with
+    /// `read_only(true)`. Use `checkout` for a writable disk.
+    pub fn path(&self, image: &str) -> Result<PathBuf, StoreError> {
+        Ok(self.blob_path(&self.resolve(image)?))
+    }
+
+    /// clones `image` to the new writable disk `dst` of the virtual machine `vm`
+    ///
+    /// The disk is recorded as a reference of `vm` to the image until it is
+    /// deleted or dropped from the definition with `track`.
+    pub fn checkout<Q: AsRef<Path>>(
+        &self,
+        vm: &str,
+        image: &str,
+        dst: Q,
+    ) -> Result<ClonedDiskImage, StoreError> {
+        check_name(vm)?;
+        let dst = dst.as_ref();
+        if fs::symlink_metadata(dst).is_ok() {
+            return Err(StoreError::AlreadyExists(dst.to_path_buf()));
+        }
+        // the lock keeps `gc` from deleting the image while it is cloned
+        let _lock = self.lock()?;
+        let digest = self.resolve(image)?;
+        let disk = clone_disk_image(self.blob_path(&digest), dst)?;
+        let result = fs::set_permissions(&disk.path, fs::Permissions::from_mode(0o644))
+            .map_err(StoreError::from)
+            .and_then(|_| {
+                let mut references = self.read_references(vm)?;
+                references.retain(|r| r.path != disk.path);
+                references.push(Reference {
+                    vm: vm.to_string(),
+                    digest,
+                    path: disk.path.clone(),
+                });
+                self.write_references(vm, &references)
+            });
+        if let Err(err) = result {
+            let _ = fs::remove_file(&disk.path);
+            return Err(err);
+        }
+        Ok(disk)
+    }
+
+    /// updates the references of the virtual machine to its current disks
+    ///
+    /// Checked out disks that are no longer part of the definition stop
+    /// referencing their image, and disks that use a stored image directly
+    /// start referencing it.
+    pub fn track(&self, definition: &VirtualMachineDefinition) -> Result<(), StoreError> {
+        check_name(&definition.name)?;
+        let _lock = self.lock()?;
+        let disks: Vec<PathBuf> = definition
+            .storage_devices
+            .iter()
+            .map(|d| fs::canonicalize(&d.path).unwrap_or_else(|_| d.path.clone()))
+            .collect();
+        let mut references = self.read_references(&definition.name)?;
+        references.retain(|r| disks.contains(&r.path));
+        let blob_dir = fs::canonicalize(self.blob_dir())?;
+        for disk in &disks {
+            let digest = match disk.strip_prefix(&blob_dir) {
+                Ok(name) => format!("{}{}", DIGEST_PREFIX, name.to_string_lossy()),
+                Err(_) => continue,
+            };
+            if let Ok(digest) = digest.parse() {
+                if !references.iter().any(|r| &r.path == disk) {
+                    references.push(Reference {
+                        vm: definition.name.clone(),
+                        digest,
+                        path: disk.clone(),
+                    });
+                }
+            }
+        }
+        self.write_references(&definition.name, &references)
+    }
+
+    /// drops every reference of the virtual machine `vm`
+    pub fn untrack(&self, vm: &str) -> Result<(), StoreError> {
+        check_name(vm)?;
+        let _lock = self.lock()?;
+        self.write_references(vm, &[])
+    }
+
+    /// returns the references of all virtual machines
+    pub fn references(&self) -> Result<Vec<Reference>, StoreError> {
+        let mut references = Vec::new();
+        for vm in list_dir(&self.ref_dir())? {
+            references.extend(self.read_references(&vm)?);
+        }
+        Ok(references)
+    }
+
+    /// deletes the images that are neither tagged nor referenced
+    ///
+    /// References to disks that no longer exist are dropped first, as are
+    /// imports left behind by processes that died.
+    pub fn gc(&self) -> Result<GcReport, StoreError> {
+        let _lock = self.lock()?;
+        let mut report = GcReport::default();
+        let mut live: Vec<Digest> = self.tags()?.into_iter().map(|(_, d)| d).collect();
+        for vm in list_dir(&self.ref_dir())? {
+            let (references, stale) = self
+                .read_references(&vm)?
+                .into_iter()
+                .partition::<Vec<_>, _>(|r| r.path.exists());
+            if !stale.is_empty() {
+                self.write_references(&vm, &references)?;
+                report.stale_references.extend(stale);
+            }
+            live.extend(references.into_iter().map(|r| r.digest));
+        }
+
+        for image in self.images()? {
+            if live.contains(&image.digest) {
+                continue;
+            }
+            let path = self.blob_path(&image.digest);
+            let blocks = fs::metadata(&path)?.blocks();
+            fs::remove_file(&path)?;
+            report.removed.push(image.digest);
+            report.freed += blocks * 512;
+        }
+
+        for name in list_dir(&self.tmp_dir())? {
+            let pid = name.split('-').next().and_then(|pid| pid.parse().ok());
+            if pid.is_some_and(|pid| !process_exists(pid)) {
+                let path = self.tmp_dir().join(&name);
+                let blocks = fs::metadata(&path)?.blocks();
+                fs::remove_file(&path)?;
+                report.freed += blocks * 512;
+            }
+        }
+        Ok(report)
+    }
+
+    fn blob_dir(&self) -> PathBuf {
+        self.dir.join("blobs").join("sha256")
+    }
+
+    fn blob_path(&self, digest: &Digest) -> PathBuf {
+        self.blob_dir().join(digest.to_hex())
+    }
+
+    fn tag_dir(&self) -> PathBuf {
+        self.dir.join("tags")
+    }
+
+    fn ref_dir(&self) -> PathBuf {
+        self.dir.join("refs")
+    }
+
+    fn tmp_dir(&self) -> PathBuf {
+        self.dir.join("tmp")
+    }
+
+    /// takes the store lock, which serializes changes to tags, references
+    /// and images between processes
+    fn lock(&self) -> io::Result<File> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.dir.join("lock"))?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(file)
+    }
+
+    fn read_tag(&self, name: &str) -> Result<Option<Digest>, StoreError> {
+        let path = self.tag_dir().join(name);
+        match fs::read_to_string(&path) {
+            Ok(digest) => Ok(Some(
+                digest
+                    .trim_end()
+                    .parse()
+                    .map_err(|_| invalid_metadata(&path))?,
+            )),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn read_references(&self, vm: &str) -> Result<Vec<Reference>, StoreError> {
+        let path = self.ref_dir().join(vm);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut references = Vec::new();
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            let (digest, disk) = line
+                .split_once(' ')
+                .ok_or_else(|| invalid_metadata(&path))?;
+            references.push(Reference {
+                vm: vm.to_string(),
+                digest: digest.parse().map_err(|_| invalid_metadata(&path))?,
+                path: PathBuf::from(disk),
+            });
+        }
+        Ok(references)
+    }
+
+    fn write_references(&self, vm: &str, references: &[Reference]) -> Result<(), StoreError> {
+        let path = self.ref_dir().join(vm);
+        if references.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            };
+        }
+        let contents: String = references
+            .iter()
+            .map(|r| format!("{} {}\n", r.digest, r.path.display()))
+            .collect();
+        Ok(write_atomic(&path, contents.as_bytes())?)
+    }
+}
+
+/// checks a tag or virtual machine name; names that start with `sha256:`
+/// would be read as digests and could never be resolved
+fn check_name(name: &str) -> Result<(), StoreError> {
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.starts_with(DIGEST_PREFIX)
+        || name.contains('/')
+        || name.contains('\0')
+    {
+        Err(StoreError::InvalidName(name.to_string()))
+    } else {
+        Ok(())
+    }
+}
+
+/// names of the entries of `dir`, without the hidden ones
+fn list_dir(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if !name.starts_with('.') {
+            names.push(name);
+        }
+    }
+    Ok(names)
+}
+
+fn process_exists(pid: libc::pid_t) -> bool {
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || io::Error::last_os_error().raw_os_error() != Some(libc::ESRCH)
+}
+
+fn invalid_metadata(path: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("invalid image store metadata {}", path.display()),
+    )
+}
@@ -0,0 +1,479 @@
+//! tar archive module
+//!
+//! Minimal reader and writer for POSIX (PAX) tar archives. Files with holes
+//! are stored as GNU sparse 1.0 entries, which GNU tar and bsdtar extract
+//! as sparse files.
+
+use crate::disk::sparse::data_ranges;
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::ops::Range;
+use std::os::unix::fs::FileExt;
+
+const BLOCK: usize = 512;
+const CHUNK: u64 = 1 << 20;
+/// largest size that fits the octal size field of a ustar header
+const MAX_OCTAL_SIZE: u64 = (1 << 33) - 1;
+
+const REGULAR: u8 = b'0';
+const PAX_HEADER: u8 = b'x';
+const PAX_GLOBAL_HEADER: u8 = b'g';
+
+/// writes a tar archive to `inner`
+pub(crate) struct TarWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> TarWriter<W> {
+    pub(crate) fn new(inner: W) -> TarWriter<W> {
+        TarWriter { inner }
+    }
+
+    /// appends the regular file `name` holding `data`
+    pub(crate) fn append(&mut self, name: &str, data: &[u8], mtime: u64) -> io::Result<()> {
+        self.header(name, 0o644, mtime, data.len() as u64, Vec::new())?;
+        self.inner.write_all(data)?;
+        self.pad(data.len() as u64)
+    }
+
+    /// appends the contents of `file` as `name`, keeping its holes
+    ///
+    /// `visit` is called with the offset and the contents of every data
+    /// range as it is written. Holes are not passed to it.
+    pub(crate) fn append_file<F>(
+        &mut self,
+        name: &str,
+        file: &File,
+        mode: u32,
+        mtime: u64,
+        mut visit: F,
+    ) -> io::Result<()>
+    where
+        F: FnMut(u64, &[u8]) -> io::Result<()>,
+    {
+        let len = file.metadata()?.len();
+        let ranges = data_ranges(file, 0, len)?;
+        let dense = len == 0 || (ranges.len() == 1 && ranges[0] == (0..len));
+
+        let mut stored = ranges.iter().map(|r| r.end - r.start).sum::<u64>();
+        if dense {
+            self.header(name, mode, mtime, len, Vec::new())?;
+        } else {
+            // GNU sparse 1.0: the map of data ranges precedes the data,
+            // padded to a whole block
+            let mut map: Vec<(u64, u64)> =
+                ranges.iter().map(|r| (r.start, r.end - r.start)).collect();
+            if ranges.last().is_none_or(|r| r.end < len) {
+                map.push((len, 0));
+            }
+            let mut text = format!("{}\n", map.len());
+            for (offset, size) in &map {
+                text.push_str(&format!("{}\n{}\n", offset, size));
+            }
+            let mut text = text.into_bytes();
+            text.resize(text.len().div_ceil(BLOCK) * BLOCK, 0);
+            stored += text.len() as u64;
+
+            let records = vec![
+                ("GNU.sparse.major", "1".to_string()),
+                ("GNU.sparse.minor", "0".to_string()),
+                ("GNU.sparse.name", name.to_string()),
+                ("GNU.sparse.realsize", len.to_string()),
+            ];
+            let sparse_name = format!("GNUSparseFile.0/{}", name);
+            self.header(&sparse_name, mode, mtime, stored, records)?;
+            self.inner.write_all(&text)?;
+        }
+
+        let mut buf = vec![0u8; CHUNK as usize];
+        for range in ranges {
+            let mut offset = range.start;
+            while offset < range.end {
+                let n = (range.end - offset).min(CHUNK) as usize;
+                file.read_exact_at(&mut buf[..n], offset)?;
+                self.inner.write_all(&buf[..n])?;
+                visit(offset, &buf[..n])?;
+                offset += n as u64;
+            }
+        }
+        self.pad(stored)
+    }
+
+    /// writes the end of archive marker and returns the inner writer
+    pub(crate) fn finish(mut self) -> io::Result<W> {
+        self.inner.write_all(&[0u8; BLOCK * 2])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// writes the header of a regular file, preceded by a PAX header when
+    /// `records` is not empty or the name or size do not fit a ustar header
+    fn header(
+        &mut self,
+        name: &str,
+        mode: u32,
+        mtime: u64,
+        size: u64,
+        mut records: Vec<(&str, String)>,
+    ) -> io::Result<()> {
+        if name.len() > 100 {
+            records.push(("path", name.to_string()));
+        }
+        if size > MAX_OCTAL_SIZE {
+            records.push(("size", size.to_string()));
+        }
+        if !records.is_empty() {
+            let mut pax = Vec::new();
+            for (key, value) in &records {
+                pax.extend_from_slice(&pax_record(key, value));
+            }
+            let pax_name = format!("PaxHeaders.0/{}", name);
+            let block = ustar_header(&pax_name, 0o644, mtime, pax.len() as u64, PAX_HEADER);
+            self.inner.write_all(&block)?;
+            self.inner.write_all(&pax)?;
+            self.pad(pax.len() as u64)?;
+        }
+        let block = ustar_header(name, mode, mtime, size, REGULAR);
+        self.inner.write_all(&block)
+    }
+
+    fn pad(&mut self, len: u64) -> io::Result<()> {
+        let rem = (len % BLOCK as u64) as usize;
+        if rem != 0 {
+            self.inner.write_all(&[0u8; BLOCK][rem..])?;
+        }
+        Ok(())
+    }
+}
+
+/// `<length> <key>=<value>\n`, where the length counts the whole record
+fn pax_record(key: &str, value: &str) -> Vec<u8> {
+    let body = key.len() + value.len() + 3;
+    let mut len = body + 1;
+    while len != body + len.to_string().len() {
+        len = body + len.to_string().len();
+    }
+    format!("{} {}={}\n", len, key, value).into_bytes()
+}
+
+fn ustar_header(name: &str, mode: u32, mtime: u64, size: u64, kind: u8) -> [u8; BLOCK] {
+    let mut block = [0u8; BLOCK];
+    // names that do not fit are carried by the PAX header, keep the tail
+    let name = name.as_bytes();
+    let name = &name[name.len().saturating_sub(100)..];
+    block[..name.len()].copy_from_slice(name);
+    octal(&mut block[100..108], u64::from(mode & 0o7777));
+    octal(&mut block[108..116], 0);
+    octal(&mut block[116..124], 0);
+    if size > MAX_OCTAL_SIZE {
+        // GNU base-256 encoding
+        block[124] = 0x80;
+        block[128..136].copy_from_slice(&size.to_be_bytes());
+    } else {
+        octal(&mut block[124..136], size);
+    }
+    octal(&mut block[136..148], mtime.min(MAX_OCTAL_SIZE));
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+    block[265..269].copy_from_slice(b"root");
+    block[297..301].copy_from_slice(b"root");
+    block[148..156].copy_from_slice(b"        ");
+    let sum: u32 = block.iter().map(|&b| u32::from(b)).sum();
+    block[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+    block
+}
+
+/// zero padded octal number followed by a NUL
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    field[..width].copy_from_slice(format!("{:0width$o}", value, width = width).as_bytes());
+    field[width] = 0;
+}
+
+/// file entry of a tar archive
+#[derive(Clone, Debug)]
+pub(crate) struct TarEntry {
+    pub(crate) name: String,
+    pub(crate) mode: u32,
+    pub(crate) mtime: u64,
+    /// size of the extracted file
+    pub(crate) size: u64,
+    /// ranges of the file stored in the archive, the rest are holes
+    pub(crate) ranges: Vec<Range<u64>>,
+}
+
+/// reads the regular files of a tar archive from `inner`
+///
+/// Directories are skipped. Links and special files are refused.
+pub(crate) struct TarReader<R: Read> {
+    inner: R,
+    /// bytes of the current entry, padding included, not read yet
+    remaining: u64,
+}
+
+impl<R: Read> TarReader<R> {
+    pub(crate) fn new(inner: R) -> TarReader<R> {
+        TarReader {
+            inner,
+            remaining: 0,
+        }
+    }
+
+    /// returns the next regular file, skipping the data of the current one
+    pub(crate) fn next_entry(&mut self) -> io::Result<Option<TarEntry>> {
+        self.skip_remaining()?;
+        let mut pax: Vec<(String, String)> = Vec::new();
+        loop {
+            let mut block = [0u8; BLOCK];
+            self.inner.read_exact(&mut block)?;
+            if block.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            check_header(&block)?;
+            let mut size = parse_size(&block[124..136])?;
+            if let Some((_, value)) = pax.iter().find(|(key, _)| key == "size") {
+                size = value.parse().map_err(|_| invalid("invalid pax size"))?;
+            }
+            let kind = block[156];
+            match kind {
+                PAX_HEADER | PAX_GLOBAL_HEADER => {
+                    let mut data = vec![
+                        0u8;
+                        usize::try_from(size)
+                            .map_err(|_| invalid("pax header too large"))?
+                    ];
+                    self.inner.read_exact(&mut data)?;
+                    self.remaining = padded(size) - size;
+                    self.skip_remaining()?;
+                    if kind == PAX_HEADER {
+                        pax.extend(parse_pax(&data)?);
+                    }
+                }
+                REGULAR | 0 | b'5' => {
+                    let name = match pax.iter().rev().find(|(key, _)| key == "path") {
+                        Some((_, path)) => path.clone(),
+                        None => ustar_name(&block),
+                    };
+                    self.remaining = padded(size);
+                    if kind == b'5' || name.ends_with('/') {
+                        self.skip_remaining()?;
+                        pax.clear();
+                        continue;
+                    }
+                    let mut entry = TarEntry {
+                        name,
+                        mode: parse_octal(&block[100..108])? as u32,
+                        mtime: parse_octal(&block[136..148])?,
+                        size,
+                        ranges: Some(0..size)
+                            .filter(|r| !r.is_empty())
+                            .into_iter()
+                            .collect(),
+                    };
+                    let value = |key: &str| {
+                        pax.iter()
+                            .rev()
+                            .find(|(k, _)| k == key)
+                            .map(|(_, v)| v.as_str())
+                    };
+                    if let Some(major) = value("GNU.sparse.major") {
+                        if major != "1" || value("GNU.sparse.minor") != Some("0") {
+                            return Err(invalid("unsupported sparse format"));
+                        }
+                        entry.name = value("GNU.sparse.name")
+                            .ok_or_else(|| invalid("sparse entry without name"))?
+                            .to_string();
+                        entry.size = value("GNU.sparse.realsize")
+                            .and_then(|s| s.parse().ok())
+                            .ok_or_else(|| invalid("sparse entry without size"))?;
+                        entry.ranges = self.read_sparse_map(entry.size, size)?;
+                    }
+                    return Ok(Some(entry));
+                }
+                _ => return Err(invalid("unsupported tar entry type")),
+            }
+        }
+    }
+
+    /// passes the offset and contents of every stored range of `entry`,
+    /// the entry last returned by `next_entry`, to `visit`
+    pub(crate) fn read_entry<F>(&mut self, entry: &TarEntry, mut visit: F) -> io::Result<()>
+    where
+        F: FnMut(u64, &[u8]) -> io::Result<()>,
+    {
+        let mut buf = vec![0u8; CHUNK as usize];
+        for range in &entry.ranges {
+            let mut offset = range.start;
+            while offset < range.end {
+                let n = (range.end - offset).min(CHUNK) as usize;
+                self.inner.read_exact(&mut buf[..n])?;
+                self.remaining -= n as u64;
+                visit(offset, &buf[..n])?;
+                offset += n as u64;
+            }
+        }
+        self.skip_remaining()
+    }
+
+    /// reads the sparse map at the start of the data of an entry of `stored`
+    /// bytes and returns the data ranges
+    fn read_sparse_map(&mut self, size: u64, stored: u64) -> io::Result<Vec<Range<u64>>> {
+        let mut text = Vec::new();
+        let mut parsed = 0;
+        let mut numbers = Vec::new();
+        let mut count = None;
+        while count.is_none_or(|count| numbers.len() < 1 + count * 2) {
+            if text.len() as u64 >= stored {
+                return Err(invalid("truncated sparse map"));
+            }
+            let mut block = [0u8; BLOCK];
+            self.inner.read_exact(&mut block)?;
+            self.remaining = self.remaining.saturating_sub(BLOCK as u64);
+            text.extend_from_slice(&block);
+            // numbers end with a newline, a number may span two blocks
+            while let Some(len) = text[parsed..].iter().position(|&b| b == b'\n') {
+                let line = std::str::from_utf8(&text[parsed..parsed + len])
+                    .map_err(|_| invalid("invalid sparse map"))?;
+                numbers.push(
+                    line.parse::<u64>()
+                        .map_err(|_| invalid("invalid sparse map"))?,
+                );
+                parsed += len + 1;
+            }
+            if count.is_none() && !numbers.is_empty() {
+                let n = usize::try_from(numbers[0]).map_err(|_| invalid("invalid sparse map"))?;
+                if n as u64 > stored {
+                    return Err(invalid("invalid sparse map"));
+                }
+                count = Some(n);
+            }
+        }
+        let mut ranges = Vec::new();
+        let mut end = 0;
+        let mut data = 0;
+        let count = count.unwrap_or(0);
+        for pair in numbers[1..1 + count * 2].chunks_exact(2) {
+            let (offset, len) = (pair[0], pair[1]);
+            if offset < end || offset.checked_add(len).is_none_or(|e| e > size) {
+                return Err(invalid("invalid sparse map"));
+            }
+            if len > 0 {
+                ranges.push(offset..offset + len);
+            }
+            end = offset + len;
+            data += len;
+        }
+        if data + text.len() as u64 != stored {
+            return Err(invalid("sparse map does not match the entry size"));
+        }
+        Ok(ranges)
+    }
+
+    fn skip_remaining(&mut self) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.inner).take(self.remaining), &mut io::sink())?;
+        if skipped != self.remaining {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining = 0;
+        Ok(())
+    }
+}
+
+fn check_header(block: &[u8; BLOCK]) -> io::Result<()> {
+    let expected = parse_octal(&block[148..156])?;
+    let sum: u64 = block
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                u64::from(b)
+            }
+        })
+        .sum();
+    if sum == expected {
+        Ok(())
+    } else {
+        Err(invalid("tar header checksum mismatch"))
+    }
+}
+
+fn ustar_name(block: &[u8; BLOCK]) -> String {
+    let field = |range: Range<usize>| {
+        let field = &block[range];
+        let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+        String::from_utf8_lossy(&field[..end]).into_owned()
+    };
+    let name = field(0..100);
+    let prefix = if &block[257..262] == b"ustar" {
+        field(345..500)
+    } else {
+        String::new()
+    };
+    if prefix.is_empty() {
+        name
+    } else {
+        format!("{}/{}", prefix, name)
+    }
+}
+
+fn parse_size(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        if field[1..4].iter().any(|&b| b != 0) || field[0] != 0x80 {
+            return Err(invalid("tar entry too large"));
+        }
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&field[4..12]);
+        Ok(u64::from_be_bytes(bytes))
+    } else {
+        parse_octal(field)
+    }
+}
+
+fn parse_octal(field: &[u8]) -> io::Result<u64> {
+    let text: String = field
+        .iter()
+        .take_while(|&&b| b != 0)
+        .map(|&b| b as char)
+        .collect();
+    let text = text.trim();
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| invalid("invalid number in tar header"))
+}
+
+fn parse_pax(mut data: &[u8]) -> io::Result<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        let space = data
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or_else(|| invalid("invalid pax record"))?;
+        let len: usize = std::str::from_utf8(&data[..space])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|&len| len > space + 1 && len <= data.len() && data[len - 1] == b'\n')
+            .ok_or_else(|| invalid("invalid pax record"))?;
+        let record = String::from_utf8(data[space + 1..len - 1].to_vec())
+            .map_err(|_| invalid("invalid pax record"))?;
+        let (key, value) = record
+            .split_once('=')
+            .ok_or_else(|| invalid("invalid pax record"))?;
+        records.push((key.to_string(), value.to_string()));
+        data = &data[len..];
+    }
+    Ok(records)
+}
+
+fn padded(size: u64) -> u64 {
+    size.div_ceil(BLOCK as u64) * BLOCK as u64
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
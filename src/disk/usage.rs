@@ -0,0 +1,219 @@
+//! disk space accounting module
+
+use crate::definition::{VZVirtualMachineState, VirtualMachineDefinition, VirtualMachineLifecycle};
+use crate::disk::lock::{DiskImageLock, DiskImageLockError};
+use crate::disk::sparse::{data_ranges, is_zero, punch_hole};
+
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+const SCAN_CHUNK: u64 = 1 << 20;
+
+/// error of `reclaim_disk_image` and `reclaim_disks`
+#[derive(Debug)]
+pub enum ReclaimError {
+    Io(io::Error),
+    /// the virtual machine is not stopped
+    NotStopped(VZVirtualMachineState),
+    /// the disk image is attached somewhere else
+    Lock(DiskImageLockError),
+}
+
+impl fmt::Display for ReclaimError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReclaimError::Io(err) => write!(f, "{}", err),
+            ReclaimError::NotStopped(state) => {
+                write!(f, "virtual machine is not stopped ({:?})", state)
+            }
+            ReclaimError::Lock(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl error::Error for ReclaimError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ReclaimError::Io(err) => Some(err),
+            ReclaimError::NotStopped(_) => None,
+            ReclaimError::Lock(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for ReclaimError {
+    fn from(err: io::Error) -> Self {
+        ReclaimError::Io(err)
+    }
+}
+
+impl From<DiskImageLockError> for ReclaimError {
+    fn from(err: DiskImageLockError) -> Self {
+        ReclaimError::Lock(err)
+    }
+}
+
+/// space used by a disk image
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DiskUsage {
+    /// size of the image as seen by the guest
+    pub apparent: u64,
+    /// bytes in the data ranges reported by `SEEK_DATA`/`SEEK_HOLE`
+    pub data: u64,
+    /// bytes of host storage allocated to the image
+    pub allocated: u64,
+}
+
+impl DiskUsage {
+    /// allocated bytes per apparent byte, 0 for an empty image
+    pub fn ratio(&self) -> f64 {
+        if self.apparent == 0 {
+            0.0
+        } else {
+            self.allocated as f64 / self.apparent as f64
+        }
+    }
+}
+
+/// returns the apparent and allocated size of the disk image at `path`
+pub fn disk_usage<P: AsRef<Path>>(path: P) -> io::Result<DiskUsage> {
+    file_usage(&File::open(path)?)
+}
+
+fn file_usage(file: &File) -> io::Result<DiskUsage> {
+    let metadata = file.metadata()?;
+    let data = data_ranges(file, 0, metadata.len())?
+        .iter()
+        .map(|r| r.end - r.start)
+        .sum();
+    Ok(DiskUsage {
+        apparent: metadata.len(),
+        data,
+        allocated: metadata.blocks() * 512,
+    })
+}
+
+/// returns the usage of every disk of the definition
+pub fn definition_usage(
+    definition: &VirtualMachineDefinition,
+) -> io::Result<Vec<(PathBuf, DiskUsage)>> {
+    definition
+        .storage_devices
+        .iter()
+        .map(|disk| Ok((disk.path.clone(), disk_usage(&disk.path)?)))
+        .collect()
+}
+
+/// result of reclaiming the zero blocks of a disk image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReclaimReport {
+    pub path: PathBuf,
+    pub before: DiskUsage,
+    pub after: DiskUsage,
+    /// bytes of all-zero blocks turned into holes
+    pub punched: u64,
+}
+
+impl ReclaimReport {
+    /// bytes of host storage released
+    pub fn saved(&self) -> u64 {
+        self.before.allocated.saturating_sub(self.after.allocated)
+    }
+}
+
+/// turns the all-zero blocks of `file` into holes and returns their size
+///
+/// Only the data ranges are scanned. Blocks are file system blocks, so the
+/// holes are aligned as `F_PUNCHHOLE` requires; a partial block at the end
+/// of the file is left alone. The contents read back unchanged.
+pub fn punch_zero_blocks(file: &File) -> io::Result<u64> {
+    let metadata = file.metadata()?;
+    let block = metadata.blksize().max(512);
+    let chunk = (SCAN_CHUNK / block).max(1) * block;
+    let end = metadata.len() / block * block;
+    let mut buf = vec![0u8; chunk as usize];
+    let mut punched = 0;
+    for range in data_ranges(file, 0, end)? {
+        let range_end = range.end.div_ceil(block).saturating_mul(block).min(end);
+        let mut pos = range.start / block * block;
+        // start of the run of zero blocks that ends at `pos`
+        let mut run = pos;
+        while pos < range_end {
+            let n = (range_end - pos).min(chunk) as usize;
+            file.read_exact_at(&mut buf[..n], pos)?;
+            for block_data in buf[..n].chunks(block as usize) {
+                if !is_zero(block_data) {
+                    punched += punch_run(file, run, pos)?;
+                    run = pos + block;
+                }
+                pos += block;
+            }
+        }
+        punched += punch_run(file, run, pos)?;
+    }
+    Ok(punched)
+}
+
+fn punch_run(file: &File, start: u64, end: u64) -> io::Result<u64> {
+    if end > start {
+        punch_hole(file, start, end - start)?;
+    }
+    Ok(end.saturating_sub(start))
+}
+
+/// punches holes for the all-zero blocks of the disk image at `path`
+///
+/// The image is locked exclusively while it is scanned, so this fails with
+/// `ReclaimError::Lock` when an attachment uses it.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::usage::reclaim_disk_image;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let report = reclaim_disk_image("web.img")?;
+/// println!(
+///     "{}: {} -> {} bytes allocated",
+///     report.path.display(),
+///     report.before.allocated,
+///     report.after.allocated
+/// );
+/// # Ok(())
+/// # }
+/// ```
+pub fn reclaim_disk_image<P: AsRef<Path>>(path: P) -> Result<ReclaimReport, ReclaimError> {
+    let lock = DiskImageLock::exclusive(path.as_ref())?;
+    let file = lock.file();
+    let before = file_usage(file)?;
+    let punched = punch_zero_blocks(file)?;
+    file.sync_all()?;
+    Ok(ReclaimReport {
+        path: path.as_ref().to_path_buf(),
+        before,
+        after: file_usage(file)?,
+        punched,
+    })
+}
+
+/// punches holes for the all-zero blocks of every writable disk of the
+/// definition
+///
+/// This gives back the space of blocks the guest has zeroed without booting
+/// it to run `fstrim`. The virtual machine must be stopped.
+pub fn reclaim_disks<L: VirtualMachineLifecycle>(
+    definition: &VirtualMachineDefinition,
+    vm: &L,
+) -> Result<Vec<ReclaimReport>, ReclaimError> {
+    let state = vm.state();
+    if !state.is_stopped() {
+        return Err(ReclaimError::NotStopped(state));
+    }
+    definition
+        .storage_devices
+        .iter()
+        .filter(|disk| !disk.read_only)
+        .map(|disk| reclaim_disk_image(&disk.path))
+        .collect()
+}
@@ -0,0 +1,353 @@
+//! disk image verification module
+
+use crate::definition::VirtualMachineDefinition;
+use crate::disk::store::Digest;
+use crate::util::write_atomic;
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+/// error of disk image verification
+#[derive(Debug)]
+pub enum VerifyError {
+    Io(io::Error),
+    /// the image does not have the expected contents
+    Mismatch {
+        path: PathBuf,
+        expected: Digest,
+        actual: Digest,
+    },
+    /// the checksum manifest has no entry for the image
+    NotListed(PathBuf),
+    /// a line of a checksum manifest could not be parsed
+    InvalidManifest(String),
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Io(err) => write!(f, "{}", err),
+            VerifyError::Mismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} does not match its checksum: expected {}, got {}",
+                path.display(),
+                expected,
+                actual
+            ),
+            VerifyError::NotListed(path) => {
+                write!(f, "{} is not listed in the manifest", path.display())
+            }
+            VerifyError::InvalidManifest(line) => write!(f, "invalid checksum line {:?}", line),
+        }
+    }
+}
+
+impl error::Error for VerifyError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            VerifyError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for VerifyError {
+    fn from(err: io::Error) -> Self {
+        VerifyError::Io(err)
+    }
+}
+
+/// digests of a `SHA256SUMS` file, as written by `sha256sum`
+///
+/// Both the GNU format (`<hex>  <name>`, `*` marking binary mode) and the
+/// BSD format (`SHA256 (<name>) = <hex>`) are read. Names are relative to
+/// the directory of the manifest.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChecksumManifest {
+    dir: PathBuf,
+    entries: Vec<(String, Digest)>,
+}
+
+impl ChecksumManifest {
+    /// parses a manifest whose names are relative to `dir`
+    pub fn parse<P: Into<PathBuf>>(text: &str, dir: P) -> Result<ChecksumManifest, VerifyError> {
+        let mut entries = Vec::new();
+        for line in text.lines() {
+            let line = line.trim_end_matches('\r');
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = || VerifyError::InvalidManifest(line.to_string());
+            let (hex, name) = if let Some(rest) = line.strip_prefix("SHA256 (") {
+                let (name, hex) = rest.rsplit_once(") = ").ok_or_else(invalid)?;
+                (hex, name)
+            } else {
+                let (hex, name) = line.split_once(' ').ok_or_else(invalid)?;
+                let name = name
+                    .strip_prefix(' ')
+                    .or_else(|| name.strip_prefix('*'))
+                    .ok_or_else(invalid)?;
+                (hex, name)
+            };
+            let digest: Digest = format!("sha256:{}", hex.to_ascii_lowercase())
+                .parse()
+                .map_err(|_| invalid())?;
+            entries.push((name.to_string(), digest));
+        }
+        Ok(ChecksumManifest {
+            dir: dir.into(),
+            entries,
+        })
+    }
+
+    /// reads the manifest at `path`
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ChecksumManifest, VerifyError> {
+        let path = path.as_ref();
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        ChecksumManifest::parse(&fs::read_to_string(path)?, dir)
+    }
+
+    /// returns the expected digest of the image at `path`
+    pub fn digest_of<P: AsRef<Path>>(&self, path: P) -> Option<Digest> {
+        let path = path.as_ref();
+        let canonical = fs::canonicalize(path).ok();
+        self.entries.iter().find_map(|(name, digest)| {
+            let listed = self.dir.join(name);
+            let same = listed == path
+                || (canonical.is_some() && fs::canonicalize(&listed).ok() == canonical);
+            if same {
+                Some(*digest)
+            } else {
+                None
+            }
+        })
+    }
+
+    /// listed names and their digests
+    pub fn entries(&self) -> &[(String, Digest)] {
+        &self.entries
+    }
+
+    /// sets the digest of every disk of the definition that is listed
+    ///
+    /// Fails with `VerifyError::NotListed` for the first disk that is not.
+    pub fn apply(&self, definition: &mut VirtualMachineDefinition) -> Result<(), VerifyError> {
+        for disk in &mut definition.storage_devices {
+            let digest = self
+                .digest_of(&disk.path)
+                .ok_or_else(|| VerifyError::NotListed(disk.path.clone()))?;
+            disk.digest = Some(digest);
+        }
+        Ok(())
+    }
+}
+
+/// identity of a file version: device, inode, size and modification time
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FileKey {
+    dev: u64,
+    ino: u64,
+    size: u64,
+    mtime: i64,
+    mtime_nsec: i64,
+}
+
+impl FileKey {
+    fn of(metadata: &fs::Metadata) -> FileKey {
+        FileKey {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+            size: metadata.len(),
+            mtime: metadata.mtime(),
+            mtime_nsec: metadata.mtime_nsec(),
+        }
+    }
+}
+
+/// cache of computed image digests
+///
+/// Entries are keyed on device, inode, size and modification time, so an
+/// image is only hashed again after it has been changed or replaced. The
+/// cache can be kept in a file to survive restarts of the host process.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::verify::{ChecksumManifest, DigestCache};
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let cache = DigestCache::open("/var/cache/vms/digests")?;
+/// let manifest = ChecksumManifest::open("/var/lib/images/SHA256SUMS")?;
+/// let image = "/var/lib/images/ubuntu-22.04.img";
+/// let expected = manifest.digest_of(image).expect("image is listed");
+/// cache.verify(image, &expected)?;
+/// cache.save()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct DigestCache {
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<FileKey, Digest>>,
+}
+
+impl DigestCache {
+    /// empty cache kept in memory
+    pub fn new() -> DigestCache {
+        DigestCache::default()
+    }
+
+    /// cache shared by the whole process, used by
+    /// `VZDiskImageStorageDeviceAttachmentBuilder` when none is given
+    pub fn global() -> &'static DigestCache {
+        static GLOBAL: OnceLock<DigestCache> = OnceLock::new();
+        GLOBAL.get_or_init(DigestCache::new)
+    }
+
+    /// loads the cache kept in the file `path`, which `save` writes
+    pub fn open<P: Into<PathBuf>>(path: P) -> io::Result<DigestCache> {
+        let path = path.into();
+        let mut entries = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(text) => {
+                for line in text.lines() {
+                    if let Some((key, digest)) = parse_cache_line(line) {
+                        entries.insert(key, digest);
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(DigestCache {
+            path: Some(path),
+            entries: Mutex::new(entries),
+        })
+    }
+
+    /// writes the cache to the file it was opened from
+    pub fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut text = String::new();
+        for (key, digest) in self.lock().iter() {
+            text.push_str(&format!(
+                "{} {} {} {} {} {}\n",
+                key.dev, key.ino, key.size, key.mtime, key.mtime_nsec, digest
+            ));
+        }
+        write_atomic(path, text.as_bytes())
+    }
+
+    /// returns the digest of the image at `path`, hashing it unless an
+    /// unchanged version is cached
+    pub fn digest<P: AsRef<Path>>(&self, path: P) -> io::Result<Digest> {
+        let path = path.as_ref();
+        let before = FileKey::of(&fs::metadata(path)?);
+        if let Some(digest) = self.lock().get(&before) {
+            return Ok(*digest);
+        }
+        let digest = Digest::of_file(path)?;
+        // a file written while it was hashed must not be cached
+        if FileKey::of(&fs::metadata(path)?) == before {
+            let mut entries = self.lock();
+            entries.retain(|key, _| (key.dev, key.ino) != (before.dev, before.ino));
+            entries.insert(before, digest);
+        }
+        Ok(digest)
+    }
+
+    /// checks that the image at `path` has the digest `expected`
+    pub fn verify<P: AsRef<Path>>(&self, path: P, expected: &Digest) -> Result<(), VerifyError> {
+        let path = path.as_ref();
+        let actual = self.digest(path)?;
+        if actual == *expected {
+            Ok(())
+        } else {
+            Err(VerifyError::Mismatch {
+                path: path.to_path_buf(),
+                expected: *expected,
+                actual,
+            })
+        }
+    }
+
+    /// checks several images at once, hashing them in parallel
+    ///
+    /// Returns the first error in the order of `images`.
+    pub fn verify_all(&self, images: &[(PathBuf, Digest)]) -> Result<(), VerifyError> {
+        let workers = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+            .min(images.len().max(1));
+        let next = Mutex::new(0);
+        let results: Vec<Mutex<Option<Result<(), VerifyError>>>> =
+            images.iter().map(|_| Mutex::new(None)).collect();
+        thread::scope(|scope| {
+            for _ in 0..workers {
+                scope.spawn(|| loop {
+                    let index = {
+                        let mut next = next.lock().unwrap_or_else(|err| err.into_inner());
+                        let index = *next;
+                        *next += 1;
+                        index
+                    };
+                    let (path, digest) = match images.get(index) {
+                        Some(image) => image,
+                        None => break,
+                    };
+                    let result = self.verify(path, digest);
+                    *results[index].lock().unwrap_or_else(|err| err.into_inner()) = Some(result);
+                });
+            }
+        });
+        for result in results {
+            if let Some(Err(err)) = result.into_inner().unwrap_or_else(|err| err.into_inner()) {
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    /// checks every disk of the definition that has a digest
+    pub fn verify_definition(
+        &self,
+        definition: &VirtualMachineDefinition,
+    ) -> Result<(), VerifyError> {
+        let images: Vec<(PathBuf, Digest)> = definition
+            .storage_devices
+            .iter()
+            .filter_map(|disk| disk.digest.map(|digest| (disk.path.clone(), digest)))
+            .collect();
+        self.verify_all(&images)
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<FileKey, Digest>> {
+        self.entries.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+fn parse_cache_line(line: &str) -> Option<(FileKey, Digest)> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    if fields.len() != 6 {
+        return None;
+    }
+    let key = FileKey {
+        dev: fields[0].parse().ok()?,
+        ino: fields[1].parse().ok()?,
+        size: fields[2].parse().ok()?,
+        mtime: fields[3].parse().ok()?,
+        mtime_nsec: fields[4].parse().ok()?,
+    };
+    Some((key, fields[5].parse().ok()?))
+}
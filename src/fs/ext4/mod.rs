@@ -0,0 +1,1503 @@
+//! ext4 image module
+
+pub mod volume;
+
+use crate::disk::sparse::is_zero;
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileExt, FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const BLOCK_SIZE: u64 = 4096;
+const BLOCKS_PER_GROUP: u64 = 32768;
+const INODE_SIZE: u64 = 256;
+const INODES_PER_BLOCK: u64 = BLOCK_SIZE / INODE_SIZE;
+const EXTRA_ISIZE: u16 = 32;
+const BYTES_PER_INODE: u64 = 16384;
+const DESC_SIZE: u64 = 32;
+
+const ROOT_INO: u32 = 2;
+const JOURNAL_INO: u32 = 8;
+const LOST_AND_FOUND_INO: u32 = 11;
+const LOST_AND_FOUND_BLOCKS: usize = 4;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+const EXTENTS_PER_BLOCK: usize = (BLOCK_SIZE as usize - 12) / 12;
+const MAX_EXTENT_LEN: u64 = 32768;
+const XATTR_MAGIC: u32 = 0xea02_0000;
+const JOURNAL_MAGIC: u32 = 0xc03b_3998;
+
+const COMPAT_HAS_JOURNAL: u32 = 0x4;
+const COMPAT_EXT_ATTR: u32 = 0x8;
+const COMPAT_DIR_INDEX: u32 = 0x20;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+
+const EXTENTS_FL: u32 = 0x80000;
+
+const S_IFIFO: u16 = 0o010000;
+const S_IFCHR: u16 = 0o020000;
+const S_IFDIR: u16 = 0o040000;
+const S_IFBLK: u16 = 0o060000;
+const S_IFREG: u16 = 0o100000;
+const S_IFLNK: u16 = 0o120000;
+const S_IFSOCK: u16 = 0o140000;
+
+/// longest symlink target stored in the inode itself
+const FAST_SYMLINK_MAX: usize = 59;
+/// room for extended attributes after the fixed part of an inode
+const INLINE_XATTR_SPACE: usize = INODE_SIZE as usize - 128 - EXTRA_ISIZE as usize - 4;
+
+/// content of a regular file
+#[derive(Clone)]
+enum Content {
+    Memory(Vec<u8>),
+    Host(PathBuf),
+}
+
+#[derive(Clone)]
+enum EntryKind {
+    File(Content),
+    Directory,
+    Symlink(Vec<u8>),
+    CharDevice(u32, u32),
+    BlockDevice(u32, u32),
+    Fifo,
+    Socket,
+}
+
+/// file, directory or special file of an ext4 image, with its metadata
+///
+/// Entries are owned by root unless `owner` is given.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::fs::ext4::Entry;
+/// let console = Entry::char_device(5, 1).mode(0o600);
+/// let init = Entry::file(b"#!/bin/sh\nexec /bin/sh\n".to_vec())
+///     .mode(0o755)
+///     .xattr("security.capability", vec![0; 20]);
+/// ```
+#[derive(Clone)]
+pub struct Entry {
+    kind: EntryKind,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    modified: Option<SystemTime>,
+    xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl Entry {
+    fn new(kind: EntryKind, mode: u16) -> Entry {
+        Entry {
+            kind,
+            mode,
+            uid: 0,
+            gid: 0,
+            modified: None,
+            xattrs: Vec::new(),
+        }
+    }
+
+    pub fn file(data: Vec<u8>) -> Entry {
+        Entry::new(EntryKind::File(Content::Memory(data)), 0o644)
+    }
+
+    /// regular file whose content is read from `path` when the image is written
+    pub fn host_file<P: Into<PathBuf>>(path: P) -> Entry {
+        Entry::new(EntryKind::File(Content::Host(path.into())), 0o644)
+    }
+
+    pub fn directory() -> Entry {
+        Entry::new(EntryKind::Directory, 0o755)
+    }
+
+    pub fn symlink<P: AsRef<Path>>(target: P) -> Entry {
+        let target = target.as_ref().as_os_str().as_bytes().to_vec();
+        Entry::new(EntryKind::Symlink(target), 0o777)
+    }
+
+    pub fn char_device(major: u32, minor: u32) -> Entry {
+        Entry::new(EntryKind::CharDevice(major, minor), 0o600)
+    }
+
+    pub fn block_device(major: u32, minor: u32) -> Entry {
+        Entry::new(EntryKind::BlockDevice(major, minor), 0o600)
+    }
+
+    pub fn fifo() -> Entry {
+        Entry::new(EntryKind::Fifo, 0o644)
+    }
+
+    pub fn socket() -> Entry {
+        Entry::new(EntryKind::Socket, 0o755)
+    }
+
+    /// permission bits, including the setuid, setgid and sticky bits
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = (mode & 0o7777) as u16;
+        self
+    }
+
+    pub fn owner(mut self, uid: u32, gid: u32) -> Self {
+        self.uid = uid;
+        self.gid = gid;
+        self
+    }
+
+    /// timestamp of the entry, the one of the builder by default
+    pub fn modified(mut self, modified: SystemTime) -> Self {
+        self.modified = Some(modified);
+        self
+    }
+
+    /// adds an extended attribute, e.g. `user.comment` or `security.selinux`
+    ///
+    /// `system.posix_acl_access` and `system.posix_acl_default` take the
+    /// value format of `setxattr(2)`.
+    pub fn xattr<T: Into<String>>(mut self, name: T, value: Vec<u8>) -> Self {
+        self.xattrs.push((name.into(), value));
+        self
+    }
+
+    fn is_dir(&self) -> bool {
+        matches!(self.kind, EntryKind::Directory)
+    }
+}
+
+/// builder for ext4 images, like `mke2fs -d`
+///
+/// Host directories are copied with their modes, ownership, timestamps,
+/// extended attributes (on Linux), symlinks, device nodes and hard links.
+/// Since unprivileged users can neither own files as root nor create device
+/// nodes, `host_owner` overrides the ownership of copied files and `entry`
+/// adds or replaces single entries on top of them. Without an explicit size
+/// the image gets about 20% free space.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::fs::ext4::{Entry, Ext4ImageBuilder};
+/// # fn main() -> std::io::Result<()> {
+/// Ext4ImageBuilder::new()
+///     .label("rootfs")
+///     .host_directory("/", "/path/to/rootfs")
+///     .host_owner(0, 0)
+///     .entry("/dev/console", Entry::char_device(5, 1).mode(0o600))
+///     .entry("/dev/null", Entry::char_device(1, 3).mode(0o666))
+///     .write_to_file("rootfs.img")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Ext4ImageBuilder {
+    size: Option<u64>,
+    label: String,
+    uuid: Option<[u8; 16]>,
+    journal: bool,
+    modified: SystemTime,
+    host_directories: Vec<(String, PathBuf)>,
+    host_owner: Option<(u32, u32)>,
+    entries: Vec<(String, Entry)>,
+}
+
+impl Ext4ImageBuilder {
+    pub fn new() -> Self {
+        Ext4ImageBuilder {
+            size: None,
+            label: String::new(),
+            uuid: None,
+            journal: true,
+            modified: SystemTime::now(),
+            host_directories: Vec::new(),
+            host_owner: None,
+            entries: Vec::new(),
+        }
+    }
+
+    /// size of the image in bytes, rounded down to whole blocks
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    /// volume label of up to 16 bytes
+    pub fn label<T: Into<String>>(mut self, label: T) -> Self {
+        self.label = label.into();
+        self
+    }
+
+    /// file system UUID, random by default
+    pub fn uuid(mut self, uuid: [u8; 16]) -> Self {
+        self.uuid = Some(uuid);
+        self
+    }
+
+    /// creates a journal, which is the default
+    pub fn journal(mut self, journal: bool) -> Self {
+        self.journal = journal;
+        self
+    }
+
+    /// timestamp of the entries that do not have their own
+    pub fn modified(mut self, modified: SystemTime) -> Self {
+        self.modified = modified;
+        self
+    }
+
+    /// copies everything below `source` into `dest`, which takes the
+    /// metadata of `source`
+    pub fn host_directory<P: Into<PathBuf>>(mut self, dest: &str, source: P) -> Self {
+        self.host_directories
+            .push((dest.to_string(), source.into()));
+        self
+    }
+
+    /// owner of every entry copied from the host
+    pub fn host_owner(mut self, uid: u32, gid: u32) -> Self {
+        self.host_owner = Some((uid, gid));
+        self
+    }
+
+    /// adds or replaces an entry after the host directories are copied;
+    /// missing parent directories are created
+    pub fn entry(mut self, path: &str, entry: Entry) -> Self {
+        self.entries.push((path.to_string(), entry));
+        self
+    }
+
+    pub fn file(self, path: &str, data: Vec<u8>) -> Self {
+        self.entry(path, Entry::file(data))
+    }
+
+    pub fn directory(self, path: &str) -> Self {
+        self.entry(path, Entry::directory())
+    }
+
+    /// writes the image to `path`, replacing any existing file
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if self.label.len() > 16 {
+            return Err(invalid(format!("label {:?} is too long", self.label)));
+        }
+        let mut root = Node::new(Entry::directory(), None);
+        for (dest, source) in self.host_directories.iter() {
+            let node = root.walk(&components(dest)?, true)?;
+            node.entry = host_entry(source, self.host_owner)?.0;
+            import_host(node, source, self.host_owner)?;
+        }
+        for (path, entry) in self.entries.iter() {
+            let node = root.walk(&components(path)?, entry.is_dir())?;
+            let entry = entry.clone();
+            if !entry.is_dir() {
+                node.children.clear();
+            }
+            node.entry = entry;
+            node.host_id = None;
+        }
+        if !root.children.contains_key(&b"lost+found"[..]) {
+            root.children.insert(
+                b"lost+found".to_vec(),
+                Node::new(Entry::directory().mode(0o700), None),
+            );
+        }
+
+        let inodes = plan_inodes(&root, self.modified, self.journal)?;
+        let data_blocks: u64 = inodes.iter().flatten().map(|i| i.data_blocks()).sum();
+        let (geometry, allocations) = match self.size {
+            Some(size) => {
+                let blocks = size / BLOCK_SIZE;
+                let journal = self.journal_blocks(blocks)?;
+                let geometry = Geometry::new(blocks, inodes.len() as u64)
+                    .ok_or_else(|| invalid(format!("{} bytes is too small", size)))?;
+                let allocations = geometry
+                    .allocate(&inodes, journal)
+                    .ok_or_else(|| invalid("the contents do not fit in the image"))?;
+                (geometry, allocations)
+            }
+            None => {
+                let min = if self.journal { 2048 } else { 64 };
+                let mut blocks = (data_blocks + data_blocks / 4 + 256).max(min);
+                loop {
+                    let journal = self.journal_blocks(blocks)?;
+                    if let Some(geometry) = Geometry::new(blocks + journal, inodes.len() as u64) {
+                        if let Some(allocations) = geometry.allocate(&inodes, journal) {
+                            break (geometry, allocations);
+                        }
+                    }
+                    if blocks > u32::MAX as u64 {
+                        return Err(invalid("the contents are too large"));
+                    }
+                    blocks += blocks / 4;
+                }
+            }
+        };
+
+        let mut random = [0u8; 32];
+        File::open("/dev/urandom")?.read_exact(&mut random)?;
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&random[..16]);
+        let image = Image {
+            geometry,
+            inodes: &inodes,
+            allocations: &allocations,
+            uuid: self.uuid.unwrap_or(uuid),
+            hash_seed: &random[16..],
+            label: &self.label,
+            time: unix_secs(self.modified),
+        };
+        let file = File::create(path)?;
+        file.set_len(image.geometry.blocks * BLOCK_SIZE)?;
+        image.write(&file)?;
+        file.sync_all()
+    }
+
+    /// journal size `mke2fs` picks for a file system of `blocks` blocks
+    fn journal_blocks(&self, blocks: u64) -> io::Result<u64> {
+        if !self.journal {
+            return Ok(0);
+        }
+        Ok(match blocks {
+            0..=2047 => return Err(invalid("an image with a journal needs at least 8 MiB")),
+            2048..=32767 => 1024,
+            32768..=262_143 => 4096,
+            262_144..=524_287 => 8192,
+            524_288..=4_194_303 => 16384,
+            _ => 32768,
+        })
+    }
+}
+
+impl Default for Ext4ImageBuilder {
+    fn default() -> Self {
+        Ext4ImageBuilder::new()
+    }
+}
+
+fn invalid<T: Into<String>>(msg: T) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg.into())
+}
+
+fn components(path: &str) -> io::Result<Vec<Vec<u8>>> {
+    let components: Vec<Vec<u8>> = path
+        .split('/')
+        .filter(|c| !c.is_empty())
+        .map(|c| c.as_bytes().to_vec())
+        .collect();
+    if components
+        .iter()
+        .any(|c| c == b"." || c == b".." || c.len() > 255 || c.contains(&0))
+    {
+        return Err(invalid(format!("invalid path {:?}", path)));
+    }
+    Ok(components)
+}
+
+fn unix_secs(time: SystemTime) -> (i64, u32) {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => (d.as_secs() as i64, d.subsec_nanos()),
+        Err(err) => {
+            let d = err.duration();
+            if d.subsec_nanos() == 0 {
+                (-(d.as_secs() as i64), 0)
+            } else {
+                (-(d.as_secs() as i64) - 1, 1_000_000_000 - d.subsec_nanos())
+            }
+        }
+    }
+}
+
+/// entry of the tree the image is built from
+struct Node {
+    entry: Entry,
+    /// device and inode number on the host, to keep hard links
+    host_id: Option<(u64, u64)>,
+    children: BTreeMap<Vec<u8>, Node>,
+}
+
+impl Node {
+    fn new(entry: Entry, host_id: Option<(u64, u64)>) -> Node {
+        Node {
+            entry,
+            host_id,
+            children: BTreeMap::new(),
+        }
+    }
+
+    /// finds or creates the node at `path`, creating directories on the way
+    fn walk(&mut self, path: &[Vec<u8>], dir: bool) -> io::Result<&mut Node> {
+        let mut node = self;
+        for (i, component) in path.iter().enumerate() {
+            if !node.entry.is_dir() {
+                return Err(invalid(format!(
+                    "{} is not a directory",
+                    String::from_utf8_lossy(&path[..i].join(&b'/'))
+                )));
+            }
+            let last = i + 1 == path.len();
+            node = node.children.entry(component.clone()).or_insert_with(|| {
+                let entry = if last && !dir {
+                    Entry::file(Vec::new())
+                } else {
+                    Entry::directory()
+                };
+                Node::new(entry, None)
+            });
+        }
+        Ok(node)
+    }
+}
+
+fn host_entry(path: &Path, owner: Option<(u32, u32)>) -> io::Result<(Entry, Option<(u64, u64)>)> {
+    let metadata = fs::symlink_metadata(path)?;
+    let file_type = metadata.file_type();
+    let rdev = metadata.rdev();
+    let (major, minor) = (libc::major(rdev as _) as u32, libc::minor(rdev as _) as u32);
+    let kind = if file_type.is_dir() {
+        EntryKind::Directory
+    } else if file_type.is_file() {
+        EntryKind::File(Content::Host(path.to_path_buf()))
+    } else if file_type.is_symlink() {
+        EntryKind::Symlink(fs::read_link(path)?.as_os_str().as_bytes().to_vec())
+    } else if file_type.is_char_device() {
+        EntryKind::CharDevice(major, minor)
+    } else if file_type.is_block_device() {
+        EntryKind::BlockDevice(major, minor)
+    } else if file_type.is_fifo() {
+        EntryKind::Fifo
+    } else {
+        EntryKind::Socket
+    };
+    let (uid, gid) = owner.unwrap_or((metadata.uid(), metadata.gid()));
+    let mtime = if metadata.mtime() >= 0 {
+        UNIX_EPOCH + Duration::new(metadata.mtime() as u64, metadata.mtime_nsec() as u32)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(metadata.mtime().unsigned_abs())
+    };
+    let entry = Entry {
+        kind,
+        mode: (metadata.mode() & 0o7777) as u16,
+        uid,
+        gid,
+        modified: Some(mtime),
+        xattrs: host_xattrs(path)?,
+    };
+    let host_id = if !file_type.is_dir() && metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    };
+    Ok((entry, host_id))
+}
+
+fn import_host(node: &mut Node, source: &Path, owner: Option<(u32, u32)>) -> io::Result<()> {
+    for dir_entry in fs::read_dir(source)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().as_bytes().to_vec();
+        let path = dir_entry.path();
+        let (entry, host_id) = host_entry(&path, owner)?;
+        let is_dir = entry.is_dir();
+        let child = node
+            .children
+            .entry(name)
+            .or_insert_with(|| Node::new(Entry::directory(), None));
+        if !is_dir {
+            child.children.clear();
+        }
+        child.entry = entry;
+        child.host_id = host_id;
+        if is_dir {
+            import_host(child, &path, owner)?;
+        }
+    }
+    Ok(())
+}
+
+/// extended attributes of a host file; only Linux has ext4 compatible ones
+#[cfg(target_os = "linux")]
+fn host_xattrs(path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    use std::ffi::CString;
+
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| invalid("path contains a NUL byte"))?;
+    let len = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+    if len < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(libc::ENOTSUP) => Ok(Vec::new()),
+            _ => Err(err),
+        };
+    }
+    let mut names = vec![0u8; len as usize];
+    let len = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr() as _, names.len()) };
+    if len < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    names.truncate(len as usize);
+    let mut xattrs = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let c_name = CString::new(name).unwrap();
+        let len =
+            unsafe { libc::lgetxattr(path.as_ptr(), c_name.as_ptr(), std::ptr::null_mut(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; len as usize];
+        let len = unsafe {
+            libc::lgetxattr(
+                path.as_ptr(),
+                c_name.as_ptr(),
+                value.as_mut_ptr() as _,
+                value.len(),
+            )
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        value.truncate(len as usize);
+        xattrs.push((String::from_utf8_lossy(name).into_owned(), value));
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn host_xattrs(_path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+/// extended attribute in its on-disk form
+struct Xattr {
+    index: u8,
+    name: Vec<u8>,
+    value: Vec<u8>,
+}
+
+impl Xattr {
+    fn new(name: &str, value: &[u8]) -> io::Result<Xattr> {
+        let (index, suffix, value) = match name {
+            "system.posix_acl_access" => (2, "", acl_to_disk(value)?),
+            "system.posix_acl_default" => (3, "", acl_to_disk(value)?),
+            _ => {
+                let prefixes = [
+                    ("user.", 1),
+                    ("trusted.", 4),
+                    ("security.", 6),
+                    ("system.", 7),
+                ];
+                let (prefix, index) = prefixes
+                    .iter()
+                    .find(|(prefix, _)| name.starts_with(prefix))
+                    .ok_or_else(|| invalid(format!("unsupported extended attribute {}", name)))?;
+                (*index, &name[prefix.len()..], value.to_vec())
+            }
+        };
+        if suffix.len() > 255 {
+            return Err(invalid(format!(
+                "extended attribute name {} is too long",
+                name
+            )));
+        }
+        Ok(Xattr {
+            index,
+            name: suffix.as_bytes().to_vec(),
+            value,
+        })
+    }
+
+    fn entry_len(&self) -> usize {
+        (16 + self.name.len()).div_ceil(4) * 4
+    }
+
+    fn value_len(&self) -> usize {
+        self.value.len().div_ceil(4) * 4
+    }
+
+    fn hash(&self) -> u32 {
+        let mut hash = 0u32;
+        for &c in self.name.iter() {
+            hash = (hash << 5) ^ (hash >> 27) ^ (c as i8 as i32 as u32);
+        }
+        let mut value = self.value.clone();
+        value.resize(self.value_len(), 0);
+        for word in value.chunks(4) {
+            let word = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            hash = (hash << 16) ^ (hash >> 16) ^ word;
+        }
+        hash
+    }
+}
+
+/// converts a POSIX ACL from the `setxattr(2)` format to the ext4 one
+fn acl_to_disk(value: &[u8]) -> io::Result<Vec<u8>> {
+    const USER: u16 = 0x02;
+    const GROUP: u16 = 0x08;
+    let bad = || invalid("malformed POSIX ACL");
+    if value.len() < 4 || !(value.len() - 4).is_multiple_of(8) || value[0..4] != 2u32.to_le_bytes()
+    {
+        return Err(bad());
+    }
+    let mut disk = 1u32.to_le_bytes().to_vec();
+    for entry in value[4..].chunks(8) {
+        let tag = u16::from_le_bytes([entry[0], entry[1]]);
+        disk.extend_from_slice(&entry[0..4]);
+        if tag == USER || tag == GROUP {
+            disk.extend_from_slice(&entry[4..8]);
+        }
+    }
+    Ok(disk)
+}
+
+enum InodeData {
+    /// directory blocks
+    Dir(Vec<u8>),
+    File(u64, Content),
+    FastSymlink(Vec<u8>),
+    SlowSymlink(Vec<u8>),
+    /// `i_block` words of a device node
+    Device(u32, u32),
+    Empty,
+    Journal,
+}
+
+struct PlannedInode {
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    links: u32,
+    time: (i64, u32),
+    data: InodeData,
+    xattrs: Vec<Xattr>,
+    /// the extended attributes do not fit in the inode
+    xattr_block: bool,
+}
+
+impl PlannedInode {
+    fn size(&self) -> u64 {
+        match &self.data {
+            InodeData::Dir(raw) => raw.len() as u64,
+            InodeData::File(size, _) => *size,
+            InodeData::FastSymlink(target) | InodeData::SlowSymlink(target) => target.len() as u64,
+            _ => 0,
+        }
+    }
+
+    fn data_blocks(&self) -> u64 {
+        let blocks = match &self.data {
+            InodeData::Journal | InodeData::FastSymlink(_) => 0,
+            _ => self.size().div_ceil(BLOCK_SIZE),
+        };
+        blocks + self.xattr_block as u64
+    }
+}
+
+/// assigns inode numbers in depth-first order and lays out directories
+fn plan_inodes(
+    root: &Node,
+    modified: SystemTime,
+    journal: bool,
+) -> io::Result<Vec<Option<PlannedInode>>> {
+    let mut numbers = Vec::new();
+    let mut hard_links = HashMap::new();
+    let mut next = LOST_AND_FOUND_INO + 1;
+    assign(root, ROOT_INO, &mut next, &mut numbers, &mut hard_links);
+
+    let mut inodes: Vec<Option<PlannedInode>> = (1..next).map(|_| None).collect();
+    let mut stack = vec![(root, ROOT_INO, ROOT_INO)];
+    let mut index = 0;
+    let default_time = unix_secs(modified);
+    while let Some((node, ino, parent)) = stack.pop() {
+        let entry = &node.entry;
+        let mut children = Vec::new();
+        let data = match &entry.kind {
+            EntryKind::Directory => {
+                let mut dir = vec![(b".".to_vec(), ino, 2u8), (b"..".to_vec(), parent, 2u8)];
+                for (name, child) in node.children.iter() {
+                    let child_ino = numbers[index];
+                    index += 1;
+                    dir.push((name.clone(), child_ino, file_type(&child.entry.kind)));
+                    children.push((child, child_ino));
+                }
+                let min_blocks = if ino == LOST_AND_FOUND_INO {
+                    LOST_AND_FOUND_BLOCKS
+                } else {
+                    1
+                };
+                InodeData::Dir(dir_blocks(&dir, min_blocks))
+            }
+            EntryKind::File(content) => {
+                let size = match content {
+                    Content::Memory(data) => data.len() as u64,
+                    Content::Host(path) => fs::metadata(path)?.len(),
+                };
+                InodeData::File(size, content.clone())
+            }
+            EntryKind::Symlink(target) if target.len() <= FAST_SYMLINK_MAX => {
+                InodeData::FastSymlink(target.clone())
+            }
+            EntryKind::Symlink(target) if target.len() < BLOCK_SIZE as usize => {
+                InodeData::SlowSymlink(target.clone())
+            }
+            EntryKind::Symlink(_) => return Err(invalid("symlink target is too long")),
+            EntryKind::CharDevice(major, minor) | EntryKind::BlockDevice(major, minor) => {
+                let (major, minor) = (*major, *minor);
+                if major < 256 && minor < 256 {
+                    InodeData::Device(major << 8 | minor, 0)
+                } else {
+                    InodeData::Device(0, (minor & 0xff) | (major << 8) | ((minor & !0xff) << 12))
+                }
+            }
+            EntryKind::Fifo | EntryKind::Socket => InodeData::Empty,
+        };
+        let slot = &mut inodes[ino as usize - 1];
+        if let Some(inode) = slot {
+            // another name of a hard linked file
+            inode.links += 1;
+            continue;
+        }
+        let mut xattrs = entry
+            .xattrs
+            .iter()
+            .map(|(name, value)| Xattr::new(name, value))
+            .collect::<io::Result<Vec<_>>>()?;
+        xattrs.sort_by(|a, b| {
+            (a.index, a.name.len(), &a.name).cmp(&(b.index, b.name.len(), &b.name))
+        });
+        let xattr_len: usize = xattrs.iter().map(|x| x.entry_len() + x.value_len()).sum();
+        let xattr_block = xattr_len + 4 > INLINE_XATTR_SPACE;
+        if xattr_len + 32 + 4 > BLOCK_SIZE as usize {
+            return Err(invalid("extended attributes do not fit in one block"));
+        }
+        let subdirs = children.iter().filter(|(c, _)| c.entry.is_dir()).count() as u32;
+        *slot = Some(PlannedInode {
+            mode: file_mode(&entry.kind) | entry.mode,
+            uid: entry.uid,
+            gid: entry.gid,
+            links: if entry.is_dir() { 2 + subdirs } else { 1 },
+            time: entry.modified.map(unix_secs).unwrap_or(default_time),
+            data,
+            xattrs,
+            xattr_block,
+        });
+        for (child, child_ino) in children.into_iter().rev() {
+            stack.push((child, child_ino, ino));
+        }
+    }
+
+    if !journal {
+        return Ok(inodes);
+    }
+    inodes[JOURNAL_INO as usize - 1] = Some(PlannedInode {
+        mode: S_IFREG | 0o600,
+        uid: 0,
+        gid: 0,
+        links: 1,
+        time: default_time,
+        data: InodeData::Journal,
+        xattrs: Vec::new(),
+        xattr_block: false,
+    });
+    Ok(inodes)
+}
+
+/// numbers the entries of every directory, in the order `plan_inodes` visits them
+fn assign(
+    node: &Node,
+    ino: u32,
+    next: &mut u32,
+    numbers: &mut Vec<u32>,
+    hard_links: &mut HashMap<(u64, u64), u32>,
+) {
+    let first = numbers.len();
+    for (name, child) in node.children.iter() {
+        let linked = child.host_id.and_then(|id| hard_links.get(&id).cloned());
+        let child_ino = if ino == ROOT_INO && name == b"lost+found" && child.entry.is_dir() {
+            LOST_AND_FOUND_INO
+        } else if let Some(linked) = linked {
+            linked
+        } else {
+            *next += 1;
+            if let Some(id) = child.host_id {
+                hard_links.insert(id, *next - 1);
+            }
+            *next - 1
+        };
+        numbers.push(child_ino);
+    }
+    // `plan_inodes` walks depth-first, listing a directory before its subdirectories
+    let assigned = numbers[first..].to_vec();
+    for (child, child_ino) in node.children.values().zip(assigned) {
+        if child.entry.is_dir() {
+            assign(child, child_ino, next, numbers, hard_links);
+        }
+    }
+}
+
+fn file_type(kind: &EntryKind) -> u8 {
+    match kind {
+        EntryKind::File(_) => 1,
+        EntryKind::Directory => 2,
+        EntryKind::CharDevice(..) => 3,
+        EntryKind::BlockDevice(..) => 4,
+        EntryKind::Fifo => 5,
+        EntryKind::Socket => 6,
+        EntryKind::Symlink(_) => 7,
+    }
+}
+
+fn file_mode(kind: &EntryKind) -> u16 {
+    match kind {
+        EntryKind::File(_) => S_IFREG,
+        EntryKind::Directory => S_IFDIR,
+        EntryKind::CharDevice(..) => S_IFCHR,
+        EntryKind::BlockDevice(..) => S_IFBLK,
+        EntryKind::Fifo => S_IFIFO,
+        EntryKind::Socket => S_IFSOCK,
+        EntryKind::Symlink(_) => S_IFLNK,
+    }
+}
+
+/// packs directory entries into blocks; entries never cross a block
+fn dir_blocks(entries: &[(Vec<u8>, u32, u8)], min_blocks: usize) -> Vec<u8> {
+    let block = BLOCK_SIZE as usize;
+    let mut raw: Vec<u8> = Vec::new();
+    let mut last = 0;
+    for (name, ino, file_type) in entries {
+        let rec_len = (8 + name.len()).div_ceil(4) * 4;
+        if raw.len() % block + rec_len > block {
+            let fill = block - raw.len() % block;
+            set_rec_len(&mut raw, last, fill);
+            raw.resize(raw.len() + fill, 0);
+        }
+        last = raw.len();
+        raw.extend_from_slice(&ino.to_le_bytes());
+        raw.extend_from_slice(&(rec_len as u16).to_le_bytes());
+        raw.push(name.len() as u8);
+        raw.push(*file_type);
+        raw.extend_from_slice(name);
+        raw.resize(last + rec_len, 0);
+    }
+    let fill = block - raw.len() % block;
+    if fill != block {
+        set_rec_len(&mut raw, last, fill);
+        raw.resize(raw.len() + fill, 0);
+    }
+    while raw.len() < min_blocks * block {
+        // empty block: one unused entry spanning it
+        let start = raw.len();
+        raw.resize(start + block, 0);
+        raw[start + 4..start + 6].copy_from_slice(&(block as u16).to_le_bytes());
+    }
+    raw
+}
+
+fn set_rec_len(raw: &mut [u8], entry: usize, extra: usize) {
+    let rec_len = u16::from_le_bytes([raw[entry + 4], raw[entry + 5]]) as usize + extra;
+    raw[entry + 4..entry + 6].copy_from_slice(&(rec_len as u16).to_le_bytes());
+}
+
+/// block groups of the file system
+#[derive(Clone, Copy)]
+struct Geometry {
+    blocks: u64,
+    groups: u64,
+    inodes_per_group: u64,
+    gdt_blocks: u64,
+}
+
+impl Geometry {
+    fn new(blocks: u64, min_inodes: u64) -> Option<Geometry> {
+        let mut blocks = blocks.min(u32::MAX as u64);
+        loop {
+            let groups = blocks.div_ceil(BLOCKS_PER_GROUP);
+            if groups == 0 {
+                return None;
+            }
+            let inodes = (blocks * BLOCK_SIZE / BYTES_PER_INODE).max(min_inodes + 16);
+            let inodes_per_group =
+                inodes.div_ceil(groups).div_ceil(INODES_PER_BLOCK).max(1) * INODES_PER_BLOCK;
+            if inodes_per_group > BLOCK_SIZE * 8 {
+                return None;
+            }
+            let geometry = Geometry {
+                blocks,
+                groups,
+                inodes_per_group,
+                gdt_blocks: (groups * DESC_SIZE).div_ceil(BLOCK_SIZE),
+            };
+            let last = groups - 1;
+            if geometry.group_end(last) < geometry.data_start(last) + 50 {
+                if groups == 1 {
+                    return None;
+                }
+                // like mke2fs, drop a last group too small to hold any data
+                blocks = geometry.group_start(last);
+                continue;
+            }
+            return Some(geometry);
+        }
+    }
+
+    fn has_super(group: u64) -> bool {
+        fn power_of(mut n: u64, base: u64) -> bool {
+            while n > 1 && n.is_multiple_of(base) {
+                n /= base;
+            }
+            n == 1
+        }
+        group <= 1 || power_of(group, 3) || power_of(group, 5) || power_of(group, 7)
+    }
+
+    fn group_start(&self, group: u64) -> u64 {
+        group * BLOCKS_PER_GROUP
+    }
+
+    fn group_end(&self, group: u64) -> u64 {
+        (self.group_start(group) + BLOCKS_PER_GROUP).min(self.blocks)
+    }
+
+    fn inode_table_blocks(&self) -> u64 {
+        self.inodes_per_group / INODES_PER_BLOCK
+    }
+
+    fn block_bitmap(&self, group: u64) -> u64 {
+        let start = self.group_start(group);
+        if Geometry::has_super(group) {
+            start + 1 + self.gdt_blocks
+        } else {
+            start
+        }
+    }
+
+    fn inode_bitmap(&self, group: u64) -> u64 {
+        self.block_bitmap(group) + 1
+    }
+
+    fn inode_table(&self, group: u64) -> u64 {
+        self.block_bitmap(group) + 2
+    }
+
+    fn data_start(&self, group: u64) -> u64 {
+        self.inode_table(group) + self.inode_table_blocks()
+    }
+
+    fn inodes(&self) -> u64 {
+        self.groups * self.inodes_per_group
+    }
+
+    /// lays out the blocks of every inode, one group after the other
+    fn allocate(
+        &self,
+        inodes: &[Option<PlannedInode>],
+        journal_blocks: u64,
+    ) -> Option<Allocations> {
+        if inodes.len() as u64 > self.inodes() {
+            return None;
+        }
+        let mut allocator = Allocator {
+            geometry: *self,
+            group: 0,
+            next: self.data_start(0),
+        };
+        let mut files = Vec::with_capacity(inodes.len());
+        for inode in inodes.iter() {
+            let inode = match inode {
+                Some(inode) => inode,
+                None => {
+                    files.push(Allocation::default());
+                    continue;
+                }
+            };
+            let blocks = match inode.data {
+                InodeData::Journal => journal_blocks,
+                _ => inode.data_blocks() - inode.xattr_block as u64,
+            };
+            let extents = allocator.extents(blocks)?;
+            let leaves = if extents.len() > 4 {
+                let count = extents.len().div_ceil(EXTENTS_PER_BLOCK);
+                if count > 4 {
+                    return None;
+                }
+                (0..count)
+                    .map(|_| allocator.block())
+                    .collect::<Option<Vec<_>>>()?
+            } else {
+                Vec::new()
+            };
+            let xattr_block = if inode.xattr_block {
+                Some(allocator.block()?)
+            } else {
+                None
+            };
+            files.push(Allocation {
+                extents,
+                leaves,
+                xattr_block,
+            });
+        }
+        Some(Allocations {
+            files,
+            end_group: allocator.group,
+            end_block: allocator.next,
+            journal_blocks,
+        })
+    }
+}
+
+struct Allocator {
+    geometry: Geometry,
+    group: u64,
+    next: u64,
+}
+
+impl Allocator {
+    fn block(&mut self) -> Option<u32> {
+        Some(self.extents(1)?[0].1)
+    }
+
+    /// `(logical, physical, length)` extents of `blocks` new blocks
+    fn extents(&mut self, blocks: u64) -> Option<Vec<(u32, u32, u16)>> {
+        let mut extents = Vec::new();
+        let mut logical = 0;
+        while logical < blocks {
+            if self.next >= self.geometry.group_end(self.group) {
+                self.group += 1;
+                if self.group >= self.geometry.groups {
+                    return None;
+                }
+                self.next = self.geometry.data_start(self.group);
+                continue;
+            }
+            let len = (blocks - logical)
+                .min(self.geometry.group_end(self.group) - self.next)
+                .min(MAX_EXTENT_LEN);
+            extents.push((logical as u32, self.next as u32, len as u16));
+            logical += len;
+            self.next += len;
+        }
+        Some(extents)
+    }
+}
+
+#[derive(Default)]
+struct Allocation {
+    extents: Vec<(u32, u32, u16)>,
+    leaves: Vec<u32>,
+    xattr_block: Option<u32>,
+}
+
+impl Allocation {
+    fn blocks(&self) -> u64 {
+        self.extents.iter().map(|e| e.2 as u64).sum::<u64>()
+            + self.leaves.len() as u64
+            + self.xattr_block.is_some() as u64
+    }
+}
+
+struct Allocations {
+    /// allocation of each inode, indexed by inode number - 1
+    files: Vec<Allocation>,
+    /// group and block where allocation stopped
+    end_group: u64,
+    end_block: u64,
+    journal_blocks: u64,
+}
+
+impl Allocations {
+    /// end of the allocated data blocks of `group`
+    fn used_end(&self, geometry: &Geometry, group: u64) -> u64 {
+        if group < self.end_group {
+            geometry.group_end(group)
+        } else if group == self.end_group {
+            self.end_block
+        } else {
+            geometry.data_start(group)
+        }
+    }
+}
+
+struct Image<'a> {
+    geometry: Geometry,
+    inodes: &'a [Option<PlannedInode>],
+    allocations: &'a Allocations,
+    uuid: [u8; 16],
+    hash_seed: &'a [u8],
+    label: &'a str,
+    time: (i64, u32),
+}
+
+impl<'a> Image<'a> {
+    fn write(&self, file: &File) -> io::Result<()> {
+        let g = &self.geometry;
+        for (index, inode) in self.inodes.iter().enumerate() {
+            if let Some(inode) = inode {
+                self.write_data(file, inode, &self.allocations.files[index])?;
+            }
+        }
+
+        let used_inodes = self.inodes.len() as u64;
+        let mut descriptors = vec![0u8; (g.gdt_blocks * BLOCK_SIZE) as usize];
+        let (mut free_blocks, mut free_inodes) = (0, 0);
+        for group in 0..g.groups {
+            let first_ino = group * g.inodes_per_group + 1;
+            let used = used_inodes
+                .saturating_sub(first_ino - 1)
+                .min(g.inodes_per_group);
+            let mut table = vec![0u8; (used * INODE_SIZE) as usize];
+            let mut dirs = 0u16;
+            for i in 0..used {
+                let ino = first_ino + i;
+                if let Some(inode) = &self.inodes[ino as usize - 1] {
+                    if inode.mode & 0o170000 == S_IFDIR {
+                        dirs += 1;
+                    }
+                    let raw = self.inode(inode, &self.allocations.files[ino as usize - 1]);
+                    let offset = (i * INODE_SIZE) as usize;
+                    table[offset..offset + INODE_SIZE as usize].copy_from_slice(&raw);
+                }
+            }
+            file.write_all_at(&table, g.inode_table(group) * BLOCK_SIZE)?;
+
+            let mut inode_bitmap = vec![0u8; BLOCK_SIZE as usize];
+            set_bits(&mut inode_bitmap, 0, used);
+            set_bits(&mut inode_bitmap, g.inodes_per_group, BLOCK_SIZE * 8);
+            file.write_all_at(&inode_bitmap, g.inode_bitmap(group) * BLOCK_SIZE)?;
+
+            let start = g.group_start(group);
+            let used_end = self.allocations.used_end(g, group);
+            let mut block_bitmap = vec![0u8; BLOCK_SIZE as usize];
+            set_bits(&mut block_bitmap, 0, used_end - start);
+            set_bits(
+                &mut block_bitmap,
+                g.group_end(group) - start,
+                BLOCK_SIZE * 8,
+            );
+            file.write_all_at(&block_bitmap, g.block_bitmap(group) * BLOCK_SIZE)?;
+
+            let group_free_blocks = g.group_end(group) - used_end;
+            let group_free_inodes = g.inodes_per_group - used;
+            free_blocks += group_free_blocks;
+            free_inodes += group_free_inodes;
+            let d = &mut descriptors[(group * DESC_SIZE) as usize..][..DESC_SIZE as usize];
+            d[0..4].copy_from_slice(&(g.block_bitmap(group) as u32).to_le_bytes());
+            d[4..8].copy_from_slice(&(g.inode_bitmap(group) as u32).to_le_bytes());
+            d[8..12].copy_from_slice(&(g.inode_table(group) as u32).to_le_bytes());
+            d[12..14].copy_from_slice(&(group_free_blocks as u16).to_le_bytes());
+            d[14..16].copy_from_slice(&(group_free_inodes as u16).to_le_bytes());
+            d[16..18].copy_from_slice(&dirs.to_le_bytes());
+        }
+
+        for group in (0..g.groups).filter(|&group| Geometry::has_super(group)) {
+            let start = g.group_start(group) * BLOCK_SIZE;
+            file.write_all_at(&descriptors, start + BLOCK_SIZE)?;
+            let superblock = self.superblock(group, free_blocks, free_inodes);
+            let offset = if group == 0 { 1024 } else { 0 };
+            file.write_all_at(&superblock, start + offset)?;
+        }
+        Ok(())
+    }
+
+    fn write_data(
+        &self,
+        file: &File,
+        inode: &PlannedInode,
+        allocation: &Allocation,
+    ) -> io::Result<()> {
+        let at = |block: u32| block as u64 * BLOCK_SIZE;
+        match &inode.data {
+            InodeData::Dir(raw) => {
+                write_extents(file, &allocation.extents, &mut &raw[..], raw.len() as u64)?
+            }
+            InodeData::SlowSymlink(target) => {
+                file.write_all_at(target, at(allocation.extents[0].1))?
+            }
+            InodeData::File(size, Content::Memory(data)) => {
+                write_extents(file, &allocation.extents, &mut &data[..], *size)?
+            }
+            InodeData::File(size, Content::Host(path)) => {
+                let mut source = File::open(path)?;
+                write_extents(file, &allocation.extents, &mut source, *size)?
+            }
+            InodeData::Journal => {
+                file.write_all_at(&self.journal_superblock(), at(allocation.extents[0].1))?
+            }
+            _ => {}
+        }
+        if !allocation.leaves.is_empty() {
+            for (leaf, extents) in allocation
+                .leaves
+                .iter()
+                .zip(allocation.extents.chunks(EXTENTS_PER_BLOCK))
+            {
+                let mut raw = vec![0u8; BLOCK_SIZE as usize];
+                raw[..12].copy_from_slice(&extent_header(extents.len(), EXTENTS_PER_BLOCK, 0));
+                for (i, extent) in extents.iter().enumerate() {
+                    raw[12 + i * 12..24 + i * 12].copy_from_slice(&extent_entry(extent));
+                }
+                file.write_all_at(&raw, at(*leaf))?;
+            }
+        }
+        if let Some(block) = allocation.xattr_block {
+            let mut raw = vec![0u8; BLOCK_SIZE as usize];
+            let hashes = write_xattrs(&mut raw, 32, 0, &inode.xattrs, true);
+            let hash = hashes
+                .iter()
+                .fold(0u32, |hash, &h| (hash << 16) ^ (hash >> 16) ^ h);
+            raw[0..4].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+            raw[4..8].copy_from_slice(&1u32.to_le_bytes());
+            raw[8..12].copy_from_slice(&1u32.to_le_bytes());
+            raw[12..16].copy_from_slice(&hash.to_le_bytes());
+            file.write_all_at(&raw, at(block))?;
+        }
+        Ok(())
+    }
+
+    fn inode(&self, inode: &PlannedInode, allocation: &Allocation) -> [u8; INODE_SIZE as usize] {
+        let mut raw = [0u8; INODE_SIZE as usize];
+        let size = match inode.data {
+            InodeData::Journal => self.allocations.journal_blocks * BLOCK_SIZE,
+            _ => inode.size(),
+        };
+        let (secs, nsecs) = inode.time;
+        let extra = ((((secs - secs as i32 as i64) >> 32) & 3) as u32) | (nsecs << 2);
+        let links = if inode.links > 65000 {
+            1
+        } else {
+            inode.links as u16
+        };
+        raw[0..2].copy_from_slice(&inode.mode.to_le_bytes());
+        raw[2..4].copy_from_slice(&(inode.uid as u16).to_le_bytes());
+        raw[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+        for offset in [8, 12, 16].iter() {
+            raw[*offset..*offset + 4].copy_from_slice(&(secs as u32).to_le_bytes());
+        }
+        raw[24..26].copy_from_slice(&(inode.gid as u16).to_le_bytes());
+        raw[26..28].copy_from_slice(&links.to_le_bytes());
+        raw[28..32]
+            .copy_from_slice(&((allocation.blocks() * (BLOCK_SIZE / 512)) as u32).to_le_bytes());
+        let i_block = &mut raw[40..100];
+        match &inode.data {
+            InodeData::FastSymlink(target) => i_block[..target.len()].copy_from_slice(target),
+            InodeData::Device(old, new) => {
+                i_block[0..4].copy_from_slice(&old.to_le_bytes());
+                i_block[4..8].copy_from_slice(&new.to_le_bytes());
+            }
+            InodeData::Empty => {}
+            _ => {
+                raw[32..36].copy_from_slice(&EXTENTS_FL.to_le_bytes());
+                raw[40..100].copy_from_slice(&extent_root(allocation));
+            }
+        }
+        if let Some(block) = allocation.xattr_block {
+            raw[104..108].copy_from_slice(&block.to_le_bytes());
+        }
+        raw[108..112].copy_from_slice(&((size >> 32) as u32).to_le_bytes());
+        raw[120..122].copy_from_slice(&((inode.uid >> 16) as u16).to_le_bytes());
+        raw[122..124].copy_from_slice(&((inode.gid >> 16) as u16).to_le_bytes());
+        raw[128..130].copy_from_slice(&EXTRA_ISIZE.to_le_bytes());
+        for offset in [132, 136, 140, 148].iter() {
+            raw[*offset..*offset + 4].copy_from_slice(&extra.to_le_bytes());
+        }
+        raw[144..148].copy_from_slice(&(secs as u32).to_le_bytes());
+        if !inode.xattrs.is_empty() && !inode.xattr_block {
+            let area = 128 + EXTRA_ISIZE as usize;
+            raw[area..area + 4].copy_from_slice(&XATTR_MAGIC.to_le_bytes());
+            write_xattrs(&mut raw[area + 4..], 0, 0, &inode.xattrs, false);
+        }
+        raw
+    }
+
+    fn superblock(&self, group: u64, free_blocks: u64, free_inodes: u64) -> Vec<u8> {
+        let g = &self.geometry;
+        let mut sb = vec![0u8; 1024];
+        let put32 = |sb: &mut [u8], offset: usize, value: u64| {
+            sb[offset..offset + 4].copy_from_slice(&(value as u32).to_le_bytes())
+        };
+        let put16 = |sb: &mut [u8], offset: usize, value: u64| {
+            sb[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes())
+        };
+        let now = self.time.0 as u64;
+        put32(&mut sb, 0, g.inodes());
+        put32(&mut sb, 4, g.blocks);
+        put32(&mut sb, 12, free_blocks);
+        put32(&mut sb, 16, free_inodes);
+        put32(&mut sb, 24, 2);
+        put32(&mut sb, 28, 2);
+        put32(&mut sb, 32, BLOCKS_PER_GROUP);
+        put32(&mut sb, 36, BLOCKS_PER_GROUP);
+        put32(&mut sb, 40, g.inodes_per_group);
+        put32(&mut sb, 48, now);
+        put16(&mut sb, 54, 0xffff);
+        put16(&mut sb, 56, 0xef53);
+        put16(&mut sb, 58, 1);
+        put16(&mut sb, 60, 1);
+        put32(&mut sb, 64, now);
+        put32(&mut sb, 76, 1);
+        put32(&mut sb, 84, LOST_AND_FOUND_INO as u64);
+        put16(&mut sb, 88, INODE_SIZE);
+        put16(&mut sb, 90, group);
+        let journal = self.allocations.journal_blocks > 0;
+        let compat =
+            COMPAT_EXT_ATTR | COMPAT_DIR_INDEX | if journal { COMPAT_HAS_JOURNAL } else { 0 };
+        put32(&mut sb, 92, compat as u64);
+        put32(&mut sb, 96, (INCOMPAT_FILETYPE | INCOMPAT_EXTENTS) as u64);
+        put32(
+            &mut sb,
+            100,
+            (RO_COMPAT_SPARSE_SUPER
+                | RO_COMPAT_LARGE_FILE
+                | RO_COMPAT_DIR_NLINK
+                | RO_COMPAT_EXTRA_ISIZE) as u64,
+        );
+        sb[104..120].copy_from_slice(&self.uuid);
+        sb[120..120 + self.label.len()].copy_from_slice(self.label.as_bytes());
+        if journal {
+            put32(&mut sb, 224, JOURNAL_INO as u64);
+            // backup of the journal inode's extent tree and size
+            let allocation = &self.allocations.files[JOURNAL_INO as usize - 1];
+            sb[268..328].copy_from_slice(&extent_root(allocation));
+            let size = self.allocations.journal_blocks * BLOCK_SIZE;
+            put32(&mut sb, 328, size >> 32);
+            put32(&mut sb, 332, size);
+            sb[253] = 1;
+        }
+        sb[236..252].copy_from_slice(self.hash_seed);
+        // half MD4
+        sb[252] = 1;
+        // user_xattr and acl
+        put32(&mut sb, 256, 0x000c);
+        put32(&mut sb, 264, now);
+        put16(&mut sb, 348, EXTRA_ISIZE as u64);
+        put16(&mut sb, 350, EXTRA_ISIZE as u64);
+        // unsigned directory hashes
+        put32(&mut sb, 352, 0x2);
+        sb
+    }
+
+    fn journal_superblock(&self) -> Vec<u8> {
+        let mut jsb = vec![0u8; BLOCK_SIZE as usize];
+        let put = |jsb: &mut [u8], offset: usize, value: u32| {
+            jsb[offset..offset + 4].copy_from_slice(&value.to_be_bytes())
+        };
+        put(&mut jsb, 0, JOURNAL_MAGIC);
+        // superblock version 2
+        put(&mut jsb, 4, 4);
+        put(&mut jsb, 12, BLOCK_SIZE as u32);
+        put(&mut jsb, 16, self.allocations.journal_blocks as u32);
+        put(&mut jsb, 20, 1);
+        put(&mut jsb, 24, 1);
+        jsb[48..64].copy_from_slice(&self.uuid);
+        put(&mut jsb, 64, 1);
+        jsb[256..272].copy_from_slice(&self.uuid);
+        jsb
+    }
+}
+
+/// writes `size` bytes from `source` to the blocks of `extents`, leaving
+/// zero blocks as holes of the image
+fn write_extents<R: Read>(
+    file: &File,
+    extents: &[(u32, u32, u16)],
+    source: &mut R,
+    size: u64,
+) -> io::Result<()> {
+    let mut buf = vec![0u8; 256 * BLOCK_SIZE as usize];
+    let mut remaining = size;
+    for &(_, physical, len) in extents {
+        let mut block = physical as u64;
+        let end = block + len as u64;
+        while block < end && remaining > 0 {
+            let n = ((end - block) * BLOCK_SIZE)
+                .min(buf.len() as u64)
+                .min(remaining) as usize;
+            source.read_exact(&mut buf[..n])?;
+            for (i, chunk) in buf[..n].chunks(BLOCK_SIZE as usize).enumerate() {
+                if !is_zero(chunk) {
+                    file.write_all_at(chunk, (block + i as u64) * BLOCK_SIZE)?;
+                }
+            }
+            remaining -= n as u64;
+            block += (n as u64).div_ceil(BLOCK_SIZE);
+        }
+    }
+    Ok(())
+}
+
+fn set_bits(bitmap: &mut [u8], from: u64, to: u64) {
+    for bit in from..to {
+        bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+    }
+}
+
+fn extent_header(entries: usize, max: usize, depth: u16) -> [u8; 12] {
+    let mut header = [0u8; 12];
+    header[0..2].copy_from_slice(&EXTENT_MAGIC.to_le_bytes());
+    header[2..4].copy_from_slice(&(entries as u16).to_le_bytes());
+    header[4..6].copy_from_slice(&(max as u16).to_le_bytes());
+    header[6..8].copy_from_slice(&depth.to_le_bytes());
+    header
+}
+
+fn extent_entry(&(logical, physical, len): &(u32, u32, u16)) -> [u8; 12] {
+    let mut entry = [0u8; 12];
+    entry[0..4].copy_from_slice(&logical.to_le_bytes());
+    entry[4..6].copy_from_slice(&len.to_le_bytes());
+    entry[8..12].copy_from_slice(&physical.to_le_bytes());
+    entry
+}
+
+/// the part of an extent tree stored in `i_block`
+fn extent_root(allocation: &Allocation) -> [u8; 60] {
+    let mut root = [0u8; 60];
+    if allocation.leaves.is_empty() {
+        root[..12].copy_from_slice(&extent_header(allocation.extents.len(), 4, 0));
+        for (i, extent) in allocation.extents.iter().enumerate() {
+            root[12 + i * 12..24 + i * 12].copy_from_slice(&extent_entry(extent));
+        }
+    } else {
+        root[..12].copy_from_slice(&extent_header(allocation.leaves.len(), 4, 1));
+        for (i, (leaf, extents)) in allocation
+            .leaves
+            .iter()
+            .zip(allocation.extents.chunks(EXTENTS_PER_BLOCK))
+            .enumerate()
+        {
+            let index = &mut root[12 + i * 12..24 + i * 12];
+            index[0..4].copy_from_slice(&extents[0].0.to_le_bytes());
+            index[4..8].copy_from_slice(&leaf.to_le_bytes());
+        }
+    }
+    root
+}
+
+/// writes entries at `entries_at` of `area` and values at its end; value
+/// offsets are relative to `base`. Returns the entry hashes.
+fn write_xattrs(
+    area: &mut [u8],
+    entries_at: usize,
+    base: usize,
+    xattrs: &[Xattr],
+    hashed: bool,
+) -> Vec<u32> {
+    let mut entry = entries_at;
+    let mut value_end = area.len();
+    let mut hashes = Vec::new();
+    for xattr in xattrs {
+        value_end -= xattr.value_len();
+        area[value_end..value_end + xattr.value.len()].copy_from_slice(&xattr.value);
+        let hash = if hashed { xattr.hash() } else { 0 };
+        hashes.push(hash);
+        let e = &mut area[entry..entry + xattr.entry_len()];
+        e[0] = xattr.name.len() as u8;
+        e[1] = xattr.index;
+        e[2..4].copy_from_slice(&((value_end - base) as u16).to_le_bytes());
+        e[8..12].copy_from_slice(&(xattr.value.len() as u32).to_le_bytes());
+        e[12..16].copy_from_slice(&hash.to_le_bytes());
+        e[16..16 + xattr.name.len()].copy_from_slice(&xattr.name);
+        entry += xattr.entry_len();
+    }
+    hashes
+}
@@ -0,0 +1,2139 @@
+//! ext4 volume editing module
+//!
+//! Changes files inside an existing, unmounted ext2/3/4 file system, for
+//! example to drop `authorized_keys` or a systemd unit into a guest disk
+//! before its first boot.
+
+use super::{
+    extent_header, set_bits, unix_secs, Geometry, EXTENTS_FL, EXTENT_MAGIC, FAST_SYMLINK_MAX,
+    INCOMPAT_EXTENTS, INCOMPAT_FILETYPE, MAX_EXTENT_LEN, ROOT_INO, RO_COMPAT_DIR_NLINK,
+    RO_COMPAT_SPARSE_SUPER, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFLNK, S_IFREG, S_IFSOCK,
+    XATTR_MAGIC,
+};
+use crate::disk::gpt::{Gpt, Guid};
+
+use std::collections::{BTreeSet, HashMap};
+use std::error;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::time::SystemTime;
+
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const EXT4_MAGIC: u16 = 0xef53;
+
+const SB_INODES_COUNT: usize = 0x00;
+const SB_BLOCKS_COUNT: (usize, usize) = (0x04, 0x150);
+const SB_FREE_BLOCKS: (usize, usize) = (0x0c, 0x158);
+const SB_FREE_INODES: usize = 0x10;
+const SB_FIRST_DATA_BLOCK: usize = 0x14;
+const SB_LOG_BLOCK_SIZE: usize = 0x18;
+const SB_BLOCKS_PER_GROUP: usize = 0x20;
+const SB_INODES_PER_GROUP: usize = 0x28;
+const SB_WTIME: usize = 0x30;
+const SB_MAGIC: usize = 0x38;
+const SB_STATE: usize = 0x3a;
+const SB_REV_LEVEL: usize = 0x4c;
+const SB_INODE_SIZE: usize = 0x58;
+const SB_FEATURE_COMPAT: usize = 0x5c;
+const SB_FEATURE_INCOMPAT: usize = 0x60;
+const SB_FEATURE_RO_COMPAT: usize = 0x64;
+const SB_UUID: usize = 0x68;
+const SB_VOLUME_NAME: usize = 0x78;
+const SB_RESERVED_GDT_BLOCKS: usize = 0xce;
+const SB_DESC_SIZE: usize = 0xfe;
+const SB_WANT_EXTRA_ISIZE: usize = 0x15e;
+const SB_CHECKSUM_TYPE: usize = 0x175;
+const SB_CHECKSUM_SEED: usize = 0x270;
+const SB_CHECKSUM: usize = 0x3fc;
+
+const STATE_VALID: u16 = 0x1;
+const STATE_ERROR: u16 = 0x2;
+
+const COMPAT_SPARSE_SUPER2: u32 = 0x200;
+const INCOMPAT_COMPRESSION: u32 = 0x1;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_JOURNAL_DEV: u32 = 0x8;
+const INCOMPAT_META_BG: u32 = 0x10;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_DIRDATA: u32 = 0x1000;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+const RO_COMPAT_GDT_CSUM: u32 = 0x10;
+const RO_COMPAT_QUOTA: u32 = 0x100;
+const RO_COMPAT_BIGALLOC: u32 = 0x200;
+const RO_COMPAT_METADATA_CSUM: u32 = 0x400;
+const RO_COMPAT_REPLICA: u32 = 0x800;
+const RO_COMPAT_READONLY: u32 = 0x1000;
+const RO_COMPAT_PROJECT: u32 = 0x2000;
+const RO_COMPAT_SHARED_BLOCKS: u32 = 0x4000;
+const RO_COMPAT_ORPHAN_PRESENT: u32 = 0x10000;
+
+/// features that change the on-disk layout in ways this module does not handle
+const UNSUPPORTED: [(usize, u32, &str); 15] = [
+    (SB_FEATURE_COMPAT, COMPAT_SPARSE_SUPER2, "sparse_super2"),
+    (SB_FEATURE_INCOMPAT, INCOMPAT_COMPRESSION, "compression"),
+    (SB_FEATURE_INCOMPAT, INCOMPAT_JOURNAL_DEV, "journal_dev"),
+    (SB_FEATURE_INCOMPAT, INCOMPAT_META_BG, "meta_bg"),
+    (SB_FEATURE_INCOMPAT, INCOMPAT_MMP, "mmp"),
+    (SB_FEATURE_INCOMPAT, INCOMPAT_EA_INODE, "ea_inode"),
+    (SB_FEATURE_INCOMPAT, INCOMPAT_DIRDATA, "dirdata"),
+    (SB_FEATURE_INCOMPAT, INCOMPAT_INLINE_DATA, "inline_data"),
+    (SB_FEATURE_RO_COMPAT, RO_COMPAT_QUOTA, "quota"),
+    (SB_FEATURE_RO_COMPAT, RO_COMPAT_BIGALLOC, "bigalloc"),
+    (SB_FEATURE_RO_COMPAT, RO_COMPAT_REPLICA, "replica"),
+    (SB_FEATURE_RO_COMPAT, RO_COMPAT_READONLY, "read-only"),
+    (SB_FEATURE_RO_COMPAT, RO_COMPAT_PROJECT, "project"),
+    (
+        SB_FEATURE_RO_COMPAT,
+        RO_COMPAT_SHARED_BLOCKS,
+        "shared_blocks",
+    ),
+    (
+        SB_FEATURE_RO_COMPAT,
+        RO_COMPAT_ORPHAN_PRESENT,
+        "orphan_present",
+    ),
+];
+
+const GD_BLOCK_BITMAP: (usize, usize) = (0x00, 0x20);
+const GD_INODE_BITMAP: (usize, usize) = (0x04, 0x24);
+const GD_INODE_TABLE: (usize, usize) = (0x08, 0x28);
+const GD_FREE_BLOCKS: (usize, usize) = (0x0c, 0x2c);
+const GD_FREE_INODES: (usize, usize) = (0x0e, 0x2e);
+const GD_USED_DIRS: (usize, usize) = (0x10, 0x30);
+const GD_FLAGS: usize = 0x12;
+const GD_BLOCK_BITMAP_CSUM: (usize, usize) = (0x18, 0x38);
+const GD_INODE_BITMAP_CSUM: (usize, usize) = (0x1a, 0x3a);
+const GD_ITABLE_UNUSED: (usize, usize) = (0x1c, 0x32);
+const GD_CHECKSUM: usize = 0x1e;
+
+const BG_INODE_UNINIT: u16 = 0x1;
+const BG_BLOCK_UNINIT: u16 = 0x2;
+
+const I_MODE: usize = 0x00;
+const I_UID: (usize, usize) = (0x02, 0x78);
+const I_SIZE: (usize, usize) = (0x04, 0x6c);
+const I_ATIME: (usize, usize) = (0x08, 0x8c);
+const I_CTIME: (usize, usize) = (0x0c, 0x84);
+const I_MTIME: (usize, usize) = (0x10, 0x88);
+const I_DTIME: usize = 0x14;
+const I_GID: (usize, usize) = (0x18, 0x7a);
+const I_LINKS: usize = 0x1a;
+const I_BLOCKS: (usize, usize) = (0x1c, 0x74);
+const I_FLAGS: usize = 0x20;
+const I_BLOCK: usize = 0x28;
+const I_GENERATION: usize = 0x64;
+const I_FILE_ACL: (usize, usize) = (0x68, 0x76);
+const I_CHECKSUM: (usize, usize) = (0x7c, 0x82);
+const I_EXTRA_ISIZE: usize = 0x80;
+const I_CRTIME: (usize, usize) = (0x90, 0x94);
+const GOOD_OLD_INODE_SIZE: usize = 128;
+
+const HUGE_FILE_FL: u32 = 0x40000;
+const INDEX_FL: u32 = 0x1000;
+const ENCRYPT_FL: u32 = 0x800;
+const CASEFOLD_FL: u32 = 0x4000_0000;
+const INLINE_DATA_FL: u32 = 0x1000_0000;
+
+const DIR_TAIL_SIZE: usize = 12;
+const DX_ROOT_INFO: usize = 0x18;
+const DIR_TAIL_TYPE: u8 = 0xde;
+const EXTENT_UNWRITTEN: u64 = 32768;
+const MAX_SYMLINKS: usize = 40;
+const MAX_LINKS: u16 = 65000;
+
+/// error of `Ext4Volume`
+#[derive(Debug)]
+pub enum Ext4VolumeError {
+    Io(io::Error),
+    /// no partition of the image matches the selector
+    PartitionNotFound,
+    /// the selected range holds no ext2, ext3 or ext4 file system
+    NotExt4,
+    /// the file system uses a feature that cannot be modified offline
+    UnsupportedFeature(&'static str),
+    /// the file system has errors or was not cleanly unmounted
+    NotClean,
+    /// on-disk structures are inconsistent
+    Corrupted(String),
+    NotFound(String),
+    AlreadyExists(String),
+    NotADirectory(String),
+    IsADirectory(String),
+    DirectoryNotEmpty(String),
+    InvalidPath(String),
+    /// out of free blocks or inodes
+    NoSpace,
+}
+
+impl fmt::Display for Ext4VolumeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ext4VolumeError::Io(err) => write!(f, "{}", err),
+            Ext4VolumeError::PartitionNotFound => write!(f, "no matching partition"),
+            Ext4VolumeError::NotExt4 => write!(f, "no ext4 file system found"),
+            Ext4VolumeError::UnsupportedFeature(feature) => {
+                write!(f, "file system feature {} is not supported", feature)
+            }
+            Ext4VolumeError::NotClean => {
+                write!(f, "file system was not cleanly unmounted, run e2fsck first")
+            }
+            Ext4VolumeError::Corrupted(msg) => write!(f, "file system is corrupted: {}", msg),
+            Ext4VolumeError::NotFound(path) => write!(f, "{} not found", path),
+            Ext4VolumeError::AlreadyExists(path) => write!(f, "{} already exists", path),
+            Ext4VolumeError::NotADirectory(path) => write!(f, "{} is not a directory", path),
+            Ext4VolumeError::IsADirectory(path) => write!(f, "{} is a directory", path),
+            Ext4VolumeError::DirectoryNotEmpty(path) => {
+                write!(f, "directory {} is not empty", path)
+            }
+            Ext4VolumeError::InvalidPath(path) => write!(f, "invalid path {:?}", path),
+            Ext4VolumeError::NoSpace => write!(f, "no space left on file system"),
+        }
+    }
+}
+
+impl error::Error for Ext4VolumeError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Ext4VolumeError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Ext4VolumeError {
+    fn from(err: io::Error) -> Self {
+        Ext4VolumeError::Io(err)
+    }
+}
+
+type Result<T> = std::result::Result<T, Ext4VolumeError>;
+
+fn corrupted<T: Into<String>>(msg: T) -> Ext4VolumeError {
+    Ext4VolumeError::Corrupted(msg.into())
+}
+
+/// where `Ext4Volume::open` finds the file system inside a disk image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Partition {
+    /// the image holds the file system itself, without partition table
+    Whole,
+    /// first GPT partition of this type
+    Type(Guid),
+    /// first GPT partition with this name
+    Name(String),
+    /// first GPT partition holding a file system with this label, or the
+    /// whole image when it has no partition table
+    Label(String),
+    /// GPT partition at this index of the partition array
+    Index(usize),
+}
+
+/// mode and owner of a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    /// file type and permission bits as in `st_mode`
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+}
+
+impl Metadata {
+    pub fn is_dir(&self) -> bool {
+        self.mode as u16 & 0o170000 == S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.mode as u16 & 0o170000 == S_IFREG
+    }
+
+    pub fn is_symlink(&self) -> bool {
+        self.mode as u16 & 0o170000 == S_IFLNK
+    }
+}
+
+/// ext2, ext3 or ext4 file system inside a raw disk image, opened for writing
+///
+/// Every method leaves the file system consistent when it returns, so
+/// `e2fsck` stays clean. Changes are written in place and are not journaled:
+/// the image must not be attached to a running virtual machine, and an
+/// interrupted write can corrupt it, so work on a clone when that matters.
+/// Directories indexed with htree are turned into linear directories when
+/// they are changed.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::gpt::Guid;
+/// # use virtualization_rs::fs::ext4::volume::{Ext4Volume, Partition};
+/// # fn main() -> Result<(), virtualization_rs::fs::ext4::volume::Ext4VolumeError> {
+/// let mut volume = Ext4Volume::open("disk.img", Partition::Type(Guid::LINUX_FILESYSTEM))?;
+/// volume.write_file("/etc/hostname", b"vm1\n")?;
+/// volume.create_dir_all("/root/.ssh")?;
+/// volume.set_permissions("/root/.ssh", 0o700)?;
+/// volume.write_file("/root/.ssh/authorized_keys", b"ssh-ed25519 AAAA... user@host\n")?;
+/// volume.set_permissions("/root/.ssh/authorized_keys", 0o600)?;
+/// volume.remove_file("/etc/machine-id")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Ext4Volume {
+    file: File,
+    offset: u64,
+    sb: Vec<u8>,
+    block_size: u64,
+    blocks_count: u64,
+    first_data_block: u64,
+    blocks_per_group: u64,
+    inodes_per_group: u64,
+    inode_size: u64,
+    group_count: u64,
+    desc_size: usize,
+    gdt: Vec<u8>,
+    /// seed of the metadata checksums, when `metadata_csum` is enabled
+    csum_seed: Option<u32>,
+    gdt_csum: bool,
+    block_bitmaps: HashMap<u64, Vec<u8>>,
+    inode_bitmaps: HashMap<u64, Vec<u8>>,
+    dirty_groups: BTreeSet<u64>,
+}
+
+impl Ext4Volume {
+    /// opens the file system at `partition` of the raw disk image at `path`
+    pub fn open<P: AsRef<Path>>(path: P, partition: Partition) -> Result<Ext4Volume> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let (offset, len) = locate(&file, &partition)?;
+        Ext4Volume::load(file, offset, len)
+    }
+
+    fn load(file: File, offset: u64, len: u64) -> Result<Ext4Volume> {
+        let sb = read_superblock(&file, offset)?.ok_or(Ext4VolumeError::NotExt4)?;
+        let log_block_size = u32_at(&sb, SB_LOG_BLOCK_SIZE);
+        if log_block_size > 6 {
+            return Err(Ext4VolumeError::NotExt4);
+        }
+        let block_size = 1024u64 << log_block_size;
+        for &(field, mask, name) in UNSUPPORTED.iter() {
+            if u32_at(&sb, field) & mask != 0 {
+                return Err(Ext4VolumeError::UnsupportedFeature(name));
+            }
+        }
+        let state = u16_at(&sb, SB_STATE);
+        if u32_at(&sb, SB_FEATURE_INCOMPAT) & INCOMPAT_RECOVER != 0
+            || state & STATE_VALID == 0
+            || state & STATE_ERROR != 0
+        {
+            return Err(Ext4VolumeError::NotClean);
+        }
+        let incompat = u32_at(&sb, SB_FEATURE_INCOMPAT);
+        let ro_compat = u32_at(&sb, SB_FEATURE_RO_COMPAT);
+        let is_64bit = incompat & INCOMPAT_64BIT != 0;
+        let blocks_count = if is_64bit {
+            get_u32_pair(&sb, SB_BLOCKS_COUNT, true)
+        } else {
+            u32_at(&sb, SB_BLOCKS_COUNT.0) as u64
+        };
+        let first_data_block = u32_at(&sb, SB_FIRST_DATA_BLOCK) as u64;
+        let blocks_per_group = u32_at(&sb, SB_BLOCKS_PER_GROUP) as u64;
+        let inodes_per_group = u32_at(&sb, SB_INODES_PER_GROUP) as u64;
+        let inode_size = if u32_at(&sb, SB_REV_LEVEL) == 0 {
+            GOOD_OLD_INODE_SIZE as u64
+        } else {
+            u16_at(&sb, SB_INODE_SIZE) as u64
+        };
+        let desc_size = if is_64bit {
+            u16_at(&sb, SB_DESC_SIZE) as usize
+        } else {
+            32
+        };
+        if blocks_per_group == 0
+            || blocks_per_group > block_size * 8
+            || inodes_per_group == 0
+            || inodes_per_group > block_size * 8
+            || inode_size < GOOD_OLD_INODE_SIZE as u64
+            || inode_size > block_size
+            || !inode_size.is_power_of_two()
+            || desc_size < 32
+            || !desc_size.is_power_of_two()
+            || blocks_count <= first_data_block
+        {
+            return Err(corrupted("invalid superblock"));
+        }
+        if blocks_count * block_size > len {
+            return Err(corrupted("file system is larger than its partition"));
+        }
+        let group_count = (blocks_count - first_data_block).div_ceil(blocks_per_group);
+        if group_count * inodes_per_group != u32_at(&sb, SB_INODES_COUNT) as u64 {
+            return Err(corrupted("inode count does not match the group count"));
+        }
+        let csum_seed = if ro_compat & RO_COMPAT_METADATA_CSUM != 0 {
+            if sb[SB_CHECKSUM_TYPE] != 1 {
+                return Err(Ext4VolumeError::UnsupportedFeature("checksum type"));
+            }
+            if incompat & INCOMPAT_CSUM_SEED != 0 {
+                Some(u32_at(&sb, SB_CHECKSUM_SEED))
+            } else {
+                Some(crc32c(!0, &sb[SB_UUID..SB_UUID + 16]))
+            }
+        } else {
+            None
+        };
+        let mut gdt = vec![0u8; group_count as usize * desc_size];
+        file.read_exact_at(&mut gdt, offset + (first_data_block + 1) * block_size)?;
+        Ok(Ext4Volume {
+            file,
+            offset,
+            sb,
+            block_size,
+            blocks_count,
+            first_data_block,
+            blocks_per_group,
+            inodes_per_group,
+            inode_size,
+            group_count,
+            desc_size,
+            gdt,
+            csum_seed,
+            gdt_csum: ro_compat & RO_COMPAT_GDT_CSUM != 0,
+            block_bitmaps: HashMap::new(),
+            inode_bitmaps: HashMap::new(),
+            dirty_groups: BTreeSet::new(),
+        })
+    }
+
+    /// volume label of the file system
+    pub fn label(&self) -> String {
+        label_of(&self.sb)
+    }
+
+    /// mode, owner and size of `path`, following symbolic links
+    pub fn metadata(&self, path: &str) -> Result<Metadata> {
+        let ino = self.existing(path, true)?;
+        let inode = self.read_inode(ino)?;
+        Ok(Metadata {
+            mode: inode.mode() as u32,
+            uid: inode.get_u16_pair(I_UID),
+            gid: inode.get_u16_pair(I_GID),
+            size: inode.size(),
+        })
+    }
+
+    /// whether `path` exists, without following a final symbolic link
+    pub fn exists(&self, path: &str) -> Result<bool> {
+        match self.existing(path, false) {
+            Ok(_) => Ok(true),
+            Err(Ext4VolumeError::NotFound(_)) => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// contents of the regular file at `path`
+    pub fn read_file(&self, path: &str) -> Result<Vec<u8>> {
+        let ino = self.existing(path, true)?;
+        let inode = self.read_inode(ino)?;
+        if inode.is_dir() {
+            return Err(Ext4VolumeError::IsADirectory(path.to_string()));
+        }
+        self.read_data(&inode)
+    }
+
+    /// target of the symbolic link at `path`
+    pub fn read_link(&self, path: &str) -> Result<Vec<u8>> {
+        let ino = self.existing(path, false)?;
+        let inode = self.read_inode(ino)?;
+        if inode.file_type() != S_IFLNK {
+            return Err(Ext4VolumeError::InvalidPath(path.to_string()));
+        }
+        self.read_data(&inode)
+    }
+
+    /// names in the directory at `path`, without `.` and `..`
+    pub fn read_dir(&self, path: &str) -> Result<Vec<String>> {
+        let ino = self.existing(path, true)?;
+        let inode = self.read_inode(ino)?;
+        if !inode.is_dir() {
+            return Err(Ext4VolumeError::NotADirectory(path.to_string()));
+        }
+        let mut names = Vec::new();
+        for block in self.dir_blocks(&inode)? {
+            for entry in DirEntries::new(&block.data, self.dir_space()) {
+                if entry.ino != 0 && entry.name != b"." && entry.name != b".." {
+                    names.push(String::from_utf8_lossy(entry.name).into_owned());
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    /// creates or replaces the regular file at `path`
+    ///
+    /// A replaced file keeps its inode, mode and owner. New files are owned
+    /// by root with mode 0644. A final symbolic link is followed.
+    pub fn write_file<T: AsRef<[u8]>>(&mut self, path: &str, data: T) -> Result<()> {
+        let data = data.as_ref();
+        let resolved = self.resolve(path, true)?;
+        let now = SystemTime::now();
+        let ino = match resolved.ino {
+            Some(ino) => ino,
+            None => {
+                let result = self.create(&resolved, S_IFREG | 0o644, now, |volume, inode| {
+                    volume.write_data(inode, data)
+                });
+                return self.finish(result);
+            }
+        };
+        let mut inode = self.read_inode(ino)?;
+        if inode.is_dir() {
+            return Err(Ext4VolumeError::IsADirectory(path.to_string()));
+        }
+        if inode.file_type() != S_IFREG {
+            return Err(Ext4VolumeError::InvalidPath(path.to_string()));
+        }
+        // the new contents go to fresh blocks, so a full file system leaves
+        // the old ones in place
+        let old = self.mapping(&inode)?;
+        self.clear_blocks(&mut inode);
+        let result = self.write_data(&mut inode, data).and_then(|_| {
+            inode.set_times(&[I_MTIME, I_CTIME], now);
+            self.write_inode(&mut inode)?;
+            self.free_mapping(&old)
+        });
+        self.finish(result)
+    }
+
+    /// creates the directory at `path`, owned by root with mode 0755
+    pub fn create_dir(&mut self, path: &str) -> Result<()> {
+        let resolved = self.resolve(path, false)?;
+        if resolved.ino.is_some() {
+            return Err(Ext4VolumeError::AlreadyExists(path.to_string()));
+        }
+        let result = self.make_dir(&resolved);
+        self.finish(result)
+    }
+
+    /// creates the directory at `path` and its missing parents
+    pub fn create_dir_all(&mut self, path: &str) -> Result<()> {
+        let mut current = String::new();
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            current.push('/');
+            current.push_str(component);
+            match self.resolve(&current, true)? {
+                Resolved { ino: Some(ino), .. } => {
+                    if !self.read_inode(ino)?.is_dir() {
+                        return Err(Ext4VolumeError::NotADirectory(current));
+                    }
+                }
+                resolved => {
+                    let result = self.make_dir(&resolved);
+                    self.finish(result)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// creates a symbolic link at `path` pointing to `target`
+    pub fn symlink<T: AsRef<[u8]>>(&mut self, target: T, path: &str) -> Result<()> {
+        let target = target.as_ref();
+        if target.is_empty() || target.len() >= self.block_size as usize {
+            return Err(Ext4VolumeError::InvalidPath(
+                String::from_utf8_lossy(target).into_owned(),
+            ));
+        }
+        let resolved = self.resolve(path, false)?;
+        if resolved.ino.is_some() {
+            return Err(Ext4VolumeError::AlreadyExists(path.to_string()));
+        }
+        let now = SystemTime::now();
+        let result = self.create(&resolved, S_IFLNK | 0o777, now, |volume, inode| {
+            if target.len() > FAST_SYMLINK_MAX {
+                return volume.write_data(inode, target);
+            }
+            inode.set_flags(inode.flags() & !EXTENTS_FL);
+            inode.raw[I_BLOCK..I_BLOCK + 60].copy_from_slice(&[0; 60]);
+            inode.raw[I_BLOCK..I_BLOCK + target.len()].copy_from_slice(target);
+            inode.set_size(target.len() as u64);
+            Ok(())
+        });
+        self.finish(result)
+    }
+
+    /// changes the permission bits of `path`, following symbolic links
+    pub fn set_permissions(&mut self, path: &str, mode: u32) -> Result<()> {
+        let ino = self.existing(path, true)?;
+        let mut inode = self.read_inode(ino)?;
+        let mode = inode.file_type() | (mode as u16 & 0o7777);
+        put_u16(&mut inode.raw, I_MODE, mode);
+        inode.set_times(&[I_CTIME], SystemTime::now());
+        let result = self.write_inode(&mut inode);
+        self.finish(result)
+    }
+
+    /// changes the owner of `path`, following symbolic links
+    pub fn set_owner(&mut self, path: &str, uid: u32, gid: u32) -> Result<()> {
+        let ino = self.existing(path, true)?;
+        let mut inode = self.read_inode(ino)?;
+        inode.set_u16_pair(I_UID, uid);
+        inode.set_u16_pair(I_GID, gid);
+        inode.set_times(&[I_CTIME], SystemTime::now());
+        let result = self.write_inode(&mut inode);
+        self.finish(result)
+    }
+
+    /// removes the file, symbolic link or special file at `path`
+    pub fn remove_file(&mut self, path: &str) -> Result<()> {
+        let (parent, name, ino) = self.existing_entry(path)?;
+        if self.read_inode(ino)?.is_dir() {
+            return Err(Ext4VolumeError::IsADirectory(path.to_string()));
+        }
+        let result = self.unlink(parent, &name, ino);
+        self.finish(result)
+    }
+
+    /// removes the empty directory at `path`
+    pub fn remove_dir(&mut self, path: &str) -> Result<()> {
+        let (parent, name, ino) = self.existing_entry(path)?;
+        let inode = self.read_inode(ino)?;
+        if !inode.is_dir() {
+            return Err(Ext4VolumeError::NotADirectory(path.to_string()));
+        }
+        if !self.is_empty_dir(&inode)? {
+            return Err(Ext4VolumeError::DirectoryNotEmpty(path.to_string()));
+        }
+        let result = self.unlink(parent, &name, ino);
+        self.finish(result)
+    }
+
+    /// removes `path` and, for a directory, everything below it
+    pub fn remove_dir_all(&mut self, path: &str) -> Result<()> {
+        let (parent, name, ino) = self.existing_entry(path)?;
+        let result = self.remove_tree(parent, &name, ino);
+        self.finish(result)
+    }
+
+    fn remove_tree(&mut self, parent: u32, name: &[u8], ino: u32) -> Result<()> {
+        let inode = self.read_inode(ino)?;
+        if inode.is_dir() {
+            let mut children = Vec::new();
+            for block in self.dir_blocks(&inode)? {
+                for entry in DirEntries::new(&block.data, self.dir_space()) {
+                    if entry.ino != 0 && entry.name != b"." && entry.name != b".." {
+                        children.push((entry.name.to_vec(), entry.ino));
+                    }
+                }
+            }
+            for (child, child_ino) in children {
+                self.remove_tree(ino, &child, child_ino)?;
+            }
+        }
+        self.unlink(parent, name, ino)
+    }
+
+    /// writes back cached metadata and syncs the image after an operation
+    fn finish(&mut self, result: Result<()>) -> Result<()> {
+        let flushed = self.flush();
+        result?;
+        flushed
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        let groups: Vec<u64> = self.dirty_groups.iter().cloned().collect();
+        self.dirty_groups.clear();
+        for group in groups {
+            if let Some(bitmap) = self.block_bitmaps.get(&group) {
+                let block = self.get_gd_u32(group, GD_BLOCK_BITMAP);
+                self.file
+                    .write_all_at(bitmap, self.offset + block * self.block_size)?;
+                if let Some(seed) = self.csum_seed {
+                    let crc = crc32c(seed, &bitmap[..self.blocks_per_group as usize / 8]);
+                    self.set_gd_u16(group, GD_BLOCK_BITMAP_CSUM, crc);
+                }
+            }
+            if let Some(bitmap) = self.inode_bitmaps.get(&group) {
+                let block = self.get_gd_u32(group, GD_INODE_BITMAP);
+                self.file
+                    .write_all_at(bitmap, self.offset + block * self.block_size)?;
+                if let Some(seed) = self.csum_seed {
+                    let crc = crc32c(seed, &bitmap[..self.inodes_per_group as usize / 8]);
+                    self.set_gd_u16(group, GD_INODE_BITMAP_CSUM, crc);
+                }
+            }
+            if let Some(checksum) = self.group_checksum(group) {
+                let at = group as usize * self.desc_size + GD_CHECKSUM;
+                put_u16(&mut self.gdt, at, checksum);
+            }
+            let at = group as usize * self.desc_size;
+            self.file.write_all_at(
+                &self.gdt[at..at + self.desc_size],
+                self.offset + (self.first_data_block + 1) * self.block_size + at as u64,
+            )?;
+        }
+        let (now, _) = unix_secs(SystemTime::now());
+        put_u32(&mut self.sb, SB_WTIME, now as u32);
+        if self.csum_seed.is_some() {
+            let crc = crc32c(!0, &self.sb[..SB_CHECKSUM]);
+            put_u32(&mut self.sb, SB_CHECKSUM, crc);
+        }
+        self.file
+            .write_all_at(&self.sb, self.offset + SUPERBLOCK_OFFSET)?;
+        self.file.sync_data()?;
+        Ok(())
+    }
+
+    fn is_64bit(&self) -> bool {
+        u32_at(&self.sb, SB_FEATURE_INCOMPAT) & INCOMPAT_64BIT != 0
+    }
+
+    fn has_incompat(&self, mask: u32) -> bool {
+        u32_at(&self.sb, SB_FEATURE_INCOMPAT) & mask != 0
+    }
+
+    fn has_ro_compat(&self, mask: u32) -> bool {
+        u32_at(&self.sb, SB_FEATURE_RO_COMPAT) & mask != 0
+    }
+
+    // block access
+
+    fn check_block(&self, block: u64) -> Result<()> {
+        if block < self.first_data_block || block >= self.blocks_count {
+            return Err(corrupted(format!("block {} out of range", block)));
+        }
+        Ok(())
+    }
+
+    fn read_block(&self, block: u64) -> Result<Vec<u8>> {
+        self.check_block(block)?;
+        let mut data = vec![0u8; self.block_size as usize];
+        self.file
+            .read_exact_at(&mut data, self.offset + block * self.block_size)?;
+        Ok(data)
+    }
+
+    fn write_block(&self, block: u64, data: &[u8]) -> Result<()> {
+        self.check_block(block)?;
+        self.file
+            .write_all_at(data, self.offset + block * self.block_size)?;
+        Ok(())
+    }
+
+    // group descriptors
+
+    fn get_gd_u32(&self, group: u64, (lo, hi): (usize, usize)) -> u64 {
+        let desc = &self.gdt[group as usize * self.desc_size..][..self.desc_size];
+        get_u32_pair(desc, (lo, hi), self.desc_size >= 64)
+    }
+
+    fn get_gd_u16(&self, group: u64, (lo, hi): (usize, usize)) -> u32 {
+        let desc = &self.gdt[group as usize * self.desc_size..][..self.desc_size];
+        let mut value = u16_at(desc, lo) as u32;
+        if self.desc_size >= 64 {
+            value |= (u16_at(desc, hi) as u32) << 16;
+        }
+        value
+    }
+
+    fn set_gd_u16(&mut self, group: u64, (lo, hi): (usize, usize), value: u32) {
+        let wide = self.desc_size >= 64;
+        let desc = &mut self.gdt[group as usize * self.desc_size..][..self.desc_size];
+        put_u16(desc, lo, value as u16);
+        if wide {
+            put_u16(desc, hi, (value >> 16) as u16);
+        }
+    }
+
+    fn gd_flags(&self, group: u64) -> u16 {
+        u16_at(&self.gdt, group as usize * self.desc_size + GD_FLAGS)
+    }
+
+    fn set_gd_flags(&mut self, group: u64, flags: u16) {
+        put_u16(
+            &mut self.gdt,
+            group as usize * self.desc_size + GD_FLAGS,
+            flags,
+        );
+    }
+
+    fn group_checksum(&self, group: u64) -> Option<u16> {
+        let desc = &self.gdt[group as usize * self.desc_size..][..self.desc_size];
+        let index = (group as u32).to_le_bytes();
+        if let Some(seed) = self.csum_seed {
+            let mut crc = crc32c(seed, &index);
+            crc = crc32c(crc, &desc[..GD_CHECKSUM]);
+            crc = crc32c(crc, &[0, 0]);
+            crc = crc32c(crc, &desc[GD_CHECKSUM + 2..]);
+            Some(crc as u16)
+        } else if self.gdt_csum {
+            let mut crc = crc16(!0, &self.sb[SB_UUID..SB_UUID + 16]);
+            crc = crc16(crc, &index);
+            crc = crc16(crc, &desc[..GD_CHECKSUM]);
+            if self.is_64bit() && self.desc_size > GD_CHECKSUM + 2 {
+                crc = crc16(crc, &desc[GD_CHECKSUM + 2..]);
+            }
+            Some(crc)
+        } else {
+            None
+        }
+    }
+
+    fn uses_uninit_flags(&self) -> bool {
+        self.csum_seed.is_some() || self.gdt_csum
+    }
+
+    fn group_start(&self, group: u64) -> u64 {
+        self.first_data_block + group * self.blocks_per_group
+    }
+
+    fn group_blocks(&self, group: u64) -> u64 {
+        (self.blocks_count - self.group_start(group)).min(self.blocks_per_group)
+    }
+
+    fn group_of_block(&self, block: u64) -> u64 {
+        (block - self.first_data_block) / self.blocks_per_group
+    }
+
+    fn group_of_inode(&self, ino: u32) -> u64 {
+        (ino as u64 - 1) / self.inodes_per_group
+    }
+
+    fn adjust_free_blocks(&mut self, group: u64, delta: i64) {
+        let free = self.get_gd_u16(group, GD_FREE_BLOCKS) as i64 + delta;
+        self.set_gd_u16(group, GD_FREE_BLOCKS, free as u32);
+        let is_64bit = self.is_64bit();
+        let free = get_u32_pair(&self.sb, SB_FREE_BLOCKS, is_64bit) as i64 + delta;
+        set_u32_pair(&mut self.sb, SB_FREE_BLOCKS, is_64bit, free as u64);
+        self.dirty_groups.insert(group);
+    }
+
+    fn adjust_free_inodes(&mut self, group: u64, delta: i64, dirs: i64) {
+        let free = self.get_gd_u16(group, GD_FREE_INODES) as i64 + delta;
+        self.set_gd_u16(group, GD_FREE_INODES, free as u32);
+        let used_dirs = self.get_gd_u16(group, GD_USED_DIRS) as i64 + dirs;
+        self.set_gd_u16(group, GD_USED_DIRS, used_dirs as u32);
+        let free = u32_at(&self.sb, SB_FREE_INODES) as i64 + delta;
+        put_u32(&mut self.sb, SB_FREE_INODES, free as u32);
+        self.dirty_groups.insert(group);
+    }
+
+    // bitmaps
+
+    fn load_block_bitmap(&mut self, group: u64) -> Result<()> {
+        if self.block_bitmaps.contains_key(&group) {
+            return Ok(());
+        }
+        let bitmap = if self.uses_uninit_flags() && self.gd_flags(group) & BG_BLOCK_UNINIT != 0 {
+            self.uninit_block_bitmap(group)
+        } else {
+            self.read_block(self.get_gd_u32(group, GD_BLOCK_BITMAP))?
+        };
+        self.block_bitmaps.insert(group, bitmap);
+        Ok(())
+    }
+
+    /// block bitmap of a group whose bitmap was never written: only the
+    /// superblock and group descriptor backups and the bitmaps and inode
+    /// tables placed in the group are in use
+    fn uninit_block_bitmap(&self, group: u64) -> Vec<u8> {
+        let mut bitmap = vec![0u8; self.block_size as usize];
+        let start = self.group_start(group);
+        let end = start + self.group_blocks(group);
+        set_bits(&mut bitmap, end - start, self.block_size * 8);
+        let sparse = self.has_ro_compat(RO_COMPAT_SPARSE_SUPER);
+        if !sparse || Geometry::has_super(group) {
+            let gdt_blocks = (self.group_count * self.desc_size as u64).div_ceil(self.block_size);
+            let reserved = u16_at(&self.sb, SB_RESERVED_GDT_BLOCKS) as u64;
+            set_bits(&mut bitmap, 0, 1 + gdt_blocks + reserved);
+        }
+        let table_blocks = (self.inodes_per_group * self.inode_size).div_ceil(self.block_size);
+        for other in 0..self.group_count {
+            let block_bitmap = self.get_gd_u32(other, GD_BLOCK_BITMAP);
+            let inode_bitmap = self.get_gd_u32(other, GD_INODE_BITMAP);
+            let table = self.get_gd_u32(other, GD_INODE_TABLE);
+            for &(from, to) in [
+                (block_bitmap, block_bitmap + 1),
+                (inode_bitmap, inode_bitmap + 1),
+                (table, table + table_blocks),
+            ]
+            .iter()
+            {
+                let (from, to) = (from.max(start), to.min(end));
+                if from < to {
+                    set_bits(&mut bitmap, from - start, to - start);
+                }
+            }
+        }
+        bitmap
+    }
+
+    fn load_inode_bitmap(&mut self, group: u64) -> Result<()> {
+        if self.inode_bitmaps.contains_key(&group) {
+            return Ok(());
+        }
+        let bitmap = if self.uses_uninit_flags() && self.gd_flags(group) & BG_INODE_UNINIT != 0 {
+            let mut bitmap = vec![0u8; self.block_size as usize];
+            set_bits(&mut bitmap, self.inodes_per_group, self.block_size * 8);
+            bitmap
+        } else {
+            self.read_block(self.get_gd_u32(group, GD_INODE_BITMAP))?
+        };
+        self.inode_bitmaps.insert(group, bitmap);
+        Ok(())
+    }
+
+    /// allocates `count` blocks, preferring the group of `goal`
+    fn allocate_blocks(&mut self, count: u64, goal: u64) -> Result<Vec<(u64, u64)>> {
+        let mut runs: Vec<(u64, u64)> = Vec::new();
+        let mut left = count;
+        let first = self.group_of_block(goal.max(self.first_data_block).min(self.blocks_count - 1));
+        for i in 0..self.group_count {
+            if left == 0 {
+                break;
+            }
+            let group = (first + i) % self.group_count;
+            if self.get_gd_u16(group, GD_FREE_BLOCKS) == 0 {
+                continue;
+            }
+            self.load_block_bitmap(group)?;
+            let blocks = self.group_blocks(group);
+            let start = self.group_start(group);
+            let mut taken = 0;
+            {
+                let bitmap = self.block_bitmaps.get_mut(&group).unwrap();
+                let mut bit = 0;
+                while bit < blocks && left > 0 {
+                    if test_bit(bitmap, bit) {
+                        bit += 1;
+                        continue;
+                    }
+                    let from = bit;
+                    while bit < blocks && left > 0 && !test_bit(bitmap, bit) {
+                        set_bits(bitmap, bit, bit + 1);
+                        bit += 1;
+                        left -= 1;
+                    }
+                    taken += bit - from;
+                    match runs.last_mut() {
+                        Some(last) if last.0 + last.1 == start + from => last.1 += bit - from,
+                        _ => runs.push((start + from, bit - from)),
+                    }
+                }
+            }
+            if taken > 0 {
+                let flags = self.gd_flags(group);
+                self.set_gd_flags(group, flags & !BG_BLOCK_UNINIT);
+                self.adjust_free_blocks(group, -(taken as i64));
+            }
+        }
+        if left > 0 {
+            for (start, len) in runs {
+                self.free_blocks(start, len)?;
+            }
+            return Err(Ext4VolumeError::NoSpace);
+        }
+        Ok(runs)
+    }
+
+    fn free_blocks(&mut self, start: u64, len: u64) -> Result<()> {
+        let mut block = start;
+        while block < start + len {
+            self.check_block(block)?;
+            let group = self.group_of_block(block);
+            let group_end = self.group_start(group) + self.group_blocks(group);
+            let end = (start + len).min(group_end);
+            self.load_block_bitmap(group)?;
+            let group_start = self.group_start(group);
+            let bitmap = self.block_bitmaps.get_mut(&group).unwrap();
+            let mut freed = 0;
+            for b in block..end {
+                let bit = b - group_start;
+                if test_bit(bitmap, bit) {
+                    bitmap[(bit / 8) as usize] &= !(1 << (bit % 8));
+                    freed += 1;
+                }
+            }
+            self.adjust_free_blocks(group, freed);
+            block = end;
+        }
+        Ok(())
+    }
+
+    fn allocate_inode(&mut self, goal_group: u64, dir: bool) -> Result<u32> {
+        for i in 0..self.group_count {
+            let group = (goal_group + i) % self.group_count;
+            if self.get_gd_u16(group, GD_FREE_INODES) == 0 {
+                continue;
+            }
+            self.load_inode_bitmap(group)?;
+            let ipg = self.inodes_per_group;
+            let bitmap = self.inode_bitmaps.get_mut(&group).unwrap();
+            let index = match (0..ipg).find(|&bit| !test_bit(bitmap, bit)) {
+                Some(index) => index,
+                None => continue,
+            };
+            set_bits(bitmap, index, index + 1);
+            if self.uses_uninit_flags() {
+                let flags = self.gd_flags(group);
+                self.set_gd_flags(group, flags & !BG_INODE_UNINIT);
+                let unused = self.get_gd_u16(group, GD_ITABLE_UNUSED) as u64;
+                if index >= ipg - unused {
+                    self.set_gd_u16(group, GD_ITABLE_UNUSED, (ipg - index - 1) as u32);
+                }
+            }
+            self.adjust_free_inodes(group, -1, if dir { 1 } else { 0 });
+            return Ok((group * ipg + index + 1) as u32);
+        }
+        Err(Ext4VolumeError::NoSpace)
+    }
+
+    fn free_inode(&mut self, ino: u32, dir: bool) -> Result<()> {
+        let group = self.group_of_inode(ino);
+        self.load_inode_bitmap(group)?;
+        let bit = (ino as u64 - 1) % self.inodes_per_group;
+        let bitmap = self.inode_bitmaps.get_mut(&group).unwrap();
+        bitmap[(bit / 8) as usize] &= !(1 << (bit % 8));
+        self.adjust_free_inodes(group, 1, if dir { -1 } else { 0 });
+        Ok(())
+    }
+
+    // inodes
+
+    fn inode_position(&self, ino: u32) -> Result<u64> {
+        if ino == 0 || ino as u64 > self.group_count * self.inodes_per_group {
+            return Err(corrupted(format!("inode {} out of range", ino)));
+        }
+        let group = self.group_of_inode(ino);
+        let index = (ino as u64 - 1) % self.inodes_per_group;
+        let table = self.get_gd_u32(group, GD_INODE_TABLE);
+        Ok(self.offset + table * self.block_size + index * self.inode_size)
+    }
+
+    fn read_inode(&self, ino: u32) -> Result<Inode> {
+        let mut raw = vec![0u8; self.inode_size as usize];
+        self.file
+            .read_exact_at(&mut raw, self.inode_position(ino)?)?;
+        Ok(Inode {
+            ino,
+            raw,
+            block_sectors: self.block_size / 512,
+        })
+    }
+
+    fn write_inode(&mut self, inode: &mut Inode) -> Result<()> {
+        if self.csum_seed.is_some() {
+            let has_hi = inode.has_field(I_CHECKSUM.1, 2);
+            put_u16(&mut inode.raw, I_CHECKSUM.0, 0);
+            if has_hi {
+                put_u16(&mut inode.raw, I_CHECKSUM.1, 0);
+            }
+            let crc = crc32c(self.inode_seed(inode), &inode.raw);
+            put_u16(&mut inode.raw, I_CHECKSUM.0, crc as u16);
+            if has_hi {
+                put_u16(&mut inode.raw, I_CHECKSUM.1, (crc >> 16) as u16);
+            }
+        }
+        self.file
+            .write_all_at(&inode.raw, self.inode_position(inode.ino)?)?;
+        Ok(())
+    }
+
+    /// checksum seed of the blocks owned by `inode`
+    fn inode_seed(&self, inode: &Inode) -> u32 {
+        let seed = self.csum_seed.unwrap_or(0);
+        let crc = crc32c(seed, &inode.ino.to_le_bytes());
+        crc32c(crc, &inode.raw[I_GENERATION..I_GENERATION + 4])
+    }
+
+    fn new_inode(&mut self, parent: u32, mode: u16, now: SystemTime) -> Result<Inode> {
+        let ino = self.allocate_inode(self.group_of_inode(parent), mode & 0o170000 == S_IFDIR)?;
+        let mut inode = Inode {
+            ino,
+            raw: vec![0u8; self.inode_size as usize],
+            block_sectors: self.block_size / 512,
+        };
+        put_u16(&mut inode.raw, I_MODE, mode);
+        put_u16(&mut inode.raw, I_LINKS, 1);
+        if inode.raw.len() > GOOD_OLD_INODE_SIZE {
+            let want = u16_at(&self.sb, SB_WANT_EXTRA_ISIZE).max(32);
+            let extra = want.min((self.inode_size as usize - GOOD_OLD_INODE_SIZE) as u16);
+            put_u16(&mut inode.raw, I_EXTRA_ISIZE, extra);
+        }
+        let generation = Guid::random().map(|g| g.0).unwrap_or_default();
+        inode.raw[I_GENERATION..I_GENERATION + 4].copy_from_slice(&generation[..4]);
+        inode.set_times(&[I_ATIME, I_CTIME, I_MTIME, I_CRTIME], now);
+        if self.has_incompat(INCOMPAT_EXTENTS) {
+            inode.set_flags(EXTENTS_FL);
+            let header = extent_header(0, 4, 0);
+            inode.raw[I_BLOCK..I_BLOCK + 12].copy_from_slice(&header);
+        }
+        Ok(inode)
+    }
+
+    // file data
+
+    /// logical to physical mapping of the blocks of `inode`
+    fn mapping(&self, inode: &Inode) -> Result<Mapping> {
+        let mut mapping = Mapping::default();
+        if inode.is_fast_symlink() || !inode.has_blocks() {
+            return Ok(mapping);
+        }
+        if inode.flags() & INLINE_DATA_FL != 0 {
+            return Err(Ext4VolumeError::UnsupportedFeature("inline_data"));
+        }
+        if inode.flags() & EXTENTS_FL != 0 {
+            let root = inode.raw[I_BLOCK..I_BLOCK + 60].to_vec();
+            self.walk_extents(&root, &mut mapping, 0)?;
+        } else {
+            let per_block = self.block_size / 4;
+            let mut logical = 0;
+            for i in 0..15 {
+                let block = u32_at(&inode.raw, I_BLOCK + i * 4) as u64;
+                let depth = i.saturating_sub(11) as u32;
+                if block == 0 {
+                    logical += per_block.pow(depth);
+                    continue;
+                }
+                self.walk_indirect(block, depth, &mut logical, &mut mapping)?;
+            }
+        }
+        Ok(mapping)
+    }
+
+    fn walk_extents(&self, node: &[u8], mapping: &mut Mapping, level: usize) -> Result<()> {
+        if u16_at(node, 0) != EXTENT_MAGIC || level > 5 {
+            return Err(corrupted("bad extent header"));
+        }
+        let entries = u16_at(node, 2) as usize;
+        let depth = u16_at(node, 6);
+        if 12 + entries * 12 > node.len() {
+            return Err(corrupted("bad extent header"));
+        }
+        for i in 0..entries {
+            let entry = &node[12 + i * 12..24 + i * 12];
+            if depth == 0 {
+                let len = u16_at(entry, 4) as u64;
+                let (len, unwritten) = if len > EXTENT_UNWRITTEN {
+                    (len - EXTENT_UNWRITTEN, true)
+                } else {
+                    (len, false)
+                };
+                let physical = (u16_at(entry, 6) as u64) << 32 | u32_at(entry, 8) as u64;
+                mapping.extents.push(Extent {
+                    logical: u32_at(entry, 0) as u64,
+                    physical,
+                    len,
+                    unwritten,
+                });
+            } else {
+                let child = (u16_at(entry, 8) as u64) << 32 | u32_at(entry, 4) as u64;
+                let block = self.read_block(child)?;
+                mapping.tree.push(child);
+                self.walk_extents(&block, mapping, level + 1)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn walk_indirect(
+        &self,
+        block: u64,
+        depth: u32,
+        logical: &mut u64,
+        mapping: &mut Mapping,
+    ) -> Result<()> {
+        if depth == 0 {
+            self.check_block(block)?;
+            match mapping.extents.last_mut() {
+                Some(last)
+                    if last.logical + last.len == *logical && last.physical + last.len == block =>
+                {
+                    last.len += 1
+                }
+                _ => mapping.extents.push(Extent {
+                    logical: *logical,
+                    physical: block,
+                    len: 1,
+                    unwritten: false,
+                }),
+            }
+            *logical += 1;
+            return Ok(());
+        }
+        let per_block = self.block_size / 4;
+        let data = self.read_block(block)?;
+        mapping.tree.push(block);
+        for i in 0..per_block as usize {
+            let child = u32_at(&data, i * 4) as u64;
+            if child == 0 {
+                *logical += per_block.pow(depth - 1);
+            } else {
+                self.walk_indirect(child, depth - 1, logical, mapping)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_data(&self, inode: &Inode) -> Result<Vec<u8>> {
+        let size = inode.size();
+        if inode.is_fast_symlink() {
+            return Ok(inode.raw[I_BLOCK..I_BLOCK + size as usize].to_vec());
+        }
+        let mut data = vec![0u8; size as usize];
+        for extent in self.mapping(inode)?.extents {
+            if extent.unwritten {
+                continue;
+            }
+            let start = extent.logical * self.block_size;
+            if start >= size {
+                continue;
+            }
+            let end = ((extent.logical + extent.len) * self.block_size).min(size);
+            self.check_block(extent.physical + extent.len - 1)?;
+            self.file.read_exact_at(
+                &mut data[start as usize..end as usize],
+                self.offset + extent.physical * self.block_size,
+            )?;
+        }
+        Ok(data)
+    }
+
+    /// frees the data and mapping blocks of `inode` and resets it to empty
+    fn truncate(&mut self, inode: &mut Inode) -> Result<()> {
+        let mapping = self.mapping(inode)?;
+        self.free_mapping(&mapping)?;
+        self.clear_blocks(inode);
+        Ok(())
+    }
+
+    fn free_mapping(&mut self, mapping: &Mapping) -> Result<()> {
+        for extent in &mapping.extents {
+            self.free_blocks(extent.physical, extent.len)?;
+        }
+        for &block in &mapping.tree {
+            self.free_blocks(block, 1)?;
+        }
+        Ok(())
+    }
+
+    /// resets `inode` to an empty file without releasing its blocks
+    fn clear_blocks(&self, inode: &mut Inode) {
+        inode.raw[I_BLOCK..I_BLOCK + 60].copy_from_slice(&[0; 60]);
+        let mut flags = inode.flags() & !(HUGE_FILE_FL | INDEX_FL);
+        if self.has_incompat(INCOMPAT_EXTENTS) {
+            flags |= EXTENTS_FL;
+            let header = extent_header(0, 4, 0);
+            inode.raw[I_BLOCK..I_BLOCK + 12].copy_from_slice(&header);
+        }
+        inode.set_flags(flags);
+        inode.set_size(0);
+        self.set_block_count(inode, 0);
+    }
+
+    fn require_extents(&self) -> Result<()> {
+        if !self.has_incompat(INCOMPAT_EXTENTS) {
+            return Err(Ext4VolumeError::UnsupportedFeature(
+                "writing files without extents",
+            ));
+        }
+        Ok(())
+    }
+
+    /// writes `data` to the empty `inode`, releasing the new blocks again
+    /// when it fails
+    fn write_data(&mut self, inode: &mut Inode, data: &[u8]) -> Result<()> {
+        self.require_extents()?;
+        let count = (data.len() as u64).div_ceil(self.block_size);
+        let goal = self.group_start(self.group_of_inode(inode.ino));
+        let runs = self.allocate_blocks(count, goal)?;
+        let mut extents = Vec::new();
+        let mut logical = 0;
+        for &(start, len) in &runs {
+            let from = (logical * self.block_size) as usize;
+            let to = (((logical + len) * self.block_size) as usize).min(data.len());
+            self.file
+                .write_all_at(&data[from..to], self.offset + start * self.block_size)?;
+            let tail = (len * self.block_size) as usize - (to - from);
+            if tail > 0 {
+                let at = self.offset + start * self.block_size + (to - from) as u64;
+                self.file.write_all_at(&vec![0; tail], at)?;
+            }
+            extents.push(Extent {
+                logical,
+                physical: start,
+                len,
+                unwritten: false,
+            });
+            logical += len;
+        }
+        let tree = match self.set_extents(inode, &extents) {
+            Ok(tree) => tree,
+            Err(err) => {
+                for &(start, len) in &runs {
+                    self.free_blocks(start, len)?;
+                }
+                return Err(err);
+            }
+        };
+        inode.set_size(data.len() as u64);
+        self.set_block_count(inode, count + tree);
+        Ok(())
+    }
+
+    /// stores `extents` in the extent tree of `inode`, replacing its root;
+    /// returns the number of tree blocks allocated
+    fn set_extents(&mut self, inode: &mut Inode, extents: &[Extent]) -> Result<u64> {
+        let mut entries = Vec::new();
+        for extent in extents {
+            let mut done = 0;
+            while done < extent.len {
+                let len = (extent.len - done).min(MAX_EXTENT_LEN);
+                entries.push((extent.logical + done, extent.physical + done, len));
+                done += len;
+            }
+        }
+        let mut root = [0u8; 60];
+        if entries.len() <= 4 {
+            root[..12].copy_from_slice(&extent_header(entries.len(), 4, 0));
+            for (i, entry) in entries.iter().enumerate() {
+                root[12 + i * 12..24 + i * 12].copy_from_slice(&extent_entry(*entry));
+            }
+            inode.raw[I_BLOCK..I_BLOCK + 60].copy_from_slice(&root);
+            return Ok(0);
+        }
+        let per_leaf = (self.block_size as usize - 12) / 12;
+        let leaves = entries.len().div_ceil(per_leaf);
+        if leaves > 4 {
+            return Err(Ext4VolumeError::NoSpace);
+        }
+        let goal = entries[0].1;
+        let runs = self.allocate_blocks(leaves as u64, goal)?;
+        let blocks: Vec<u64> = runs
+            .iter()
+            .flat_map(|&(start, len)| start..start + len)
+            .collect();
+        root[..12].copy_from_slice(&extent_header(leaves, 4, 1));
+        let seed = self.inode_seed(inode);
+        for (i, (chunk, &block)) in entries.chunks(per_leaf).zip(blocks.iter()).enumerate() {
+            let mut leaf = vec![0u8; self.block_size as usize];
+            leaf[..12].copy_from_slice(&extent_header(chunk.len(), per_leaf, 0));
+            for (j, entry) in chunk.iter().enumerate() {
+                leaf[12 + j * 12..24 + j * 12].copy_from_slice(&extent_entry(*entry));
+            }
+            if self.csum_seed.is_some() {
+                let tail = 12 + per_leaf * 12;
+                let crc = crc32c(seed, &leaf[..tail]);
+                put_u32(&mut leaf, tail, crc);
+            }
+            self.write_block(block, &leaf)?;
+            let index = &mut root[12 + i * 12..24 + i * 12];
+            put_u32(index, 0, chunk[0].0 as u32);
+            put_u32(index, 4, block as u32);
+            put_u16(index, 8, (block >> 32) as u16);
+        }
+        inode.raw[I_BLOCK..I_BLOCK + 60].copy_from_slice(&root);
+        Ok(leaves as u64)
+    }
+
+    fn set_block_count(&self, inode: &mut Inode, blocks: u64) {
+        let acl = if inode.file_acl() != 0 { 1 } else { 0 };
+        let sectors = (blocks + acl) * inode.block_sectors;
+        put_u32(&mut inode.raw, I_BLOCKS.0, sectors as u32);
+        if self.has_ro_compat(RO_COMPAT_HUGE_FILE) {
+            put_u16(&mut inode.raw, I_BLOCKS.1, (sectors >> 32) as u16);
+        }
+    }
+
+    /// releases `inode` once its last link is gone
+    fn release(&mut self, inode: &mut Inode) -> Result<()> {
+        let dir = inode.is_dir();
+        if inode.has_blocks() {
+            self.truncate(inode)?;
+        }
+        let acl = inode.file_acl();
+        if acl != 0 {
+            let mut block = self.read_block(acl)?;
+            if u32_at(&block, 0) != XATTR_MAGIC {
+                return Err(corrupted(format!("bad xattr block {}", acl)));
+            }
+            let refs = u32_at(&block, 4);
+            if refs > 1 {
+                put_u32(&mut block, 4, refs - 1);
+                if let Some(seed) = self.csum_seed {
+                    put_u32(&mut block, 0x10, 0);
+                    let crc = crc32c(seed, &acl.to_le_bytes());
+                    let crc = crc32c(crc, &block);
+                    put_u32(&mut block, 0x10, crc);
+                }
+                self.write_block(acl, &block)?;
+            } else {
+                self.free_blocks(acl, 1)?;
+            }
+            inode.set_file_acl(0);
+        }
+        put_u16(&mut inode.raw, I_LINKS, 0);
+        inode.set_size(0);
+        put_u32(&mut inode.raw, I_BLOCKS.0, 0);
+        put_u16(&mut inode.raw, I_BLOCKS.1, 0);
+        let (now, _) = unix_secs(SystemTime::now());
+        put_u32(&mut inode.raw, I_DTIME, now as u32);
+        self.write_inode(inode)?;
+        self.free_inode(inode.ino, dir)
+    }
+
+    // directories
+
+    /// bytes of a directory block available to entries
+    fn dir_space(&self) -> usize {
+        if self.csum_seed.is_some() {
+            self.block_size as usize - DIR_TAIL_SIZE
+        } else {
+            self.block_size as usize
+        }
+    }
+
+    fn dir_blocks(&self, inode: &Inode) -> Result<Vec<DirBlock>> {
+        let count = inode.size() / self.block_size;
+        let mut blocks = Vec::new();
+        for extent in self.mapping(inode)?.extents {
+            for i in 0..extent.len {
+                let logical = extent.logical + i;
+                if logical >= count || extent.unwritten {
+                    continue;
+                }
+                let physical = extent.physical + i;
+                blocks.push(DirBlock {
+                    logical,
+                    physical,
+                    data: self.read_block(physical)?,
+                });
+            }
+        }
+        Ok(blocks)
+    }
+
+    fn write_dir_block(&self, dir: &Inode, block: &mut DirBlock) -> Result<()> {
+        if self.csum_seed.is_some() {
+            let tail = self.dir_space();
+            let data = &mut block.data;
+            data[tail..].copy_from_slice(&[0; DIR_TAIL_SIZE]);
+            put_u16(data, tail + 4, DIR_TAIL_SIZE as u16);
+            data[tail + 7] = DIR_TAIL_TYPE;
+            let crc = crc32c(self.inode_seed(dir), &data[..tail]);
+            put_u32(data, tail + 8, crc);
+        }
+        self.write_block(block.physical, &block.data)
+    }
+
+    fn lookup(&self, dir: &Inode, name: &[u8]) -> Result<Option<u32>> {
+        for block in self.dir_blocks(dir)? {
+            for entry in DirEntries::new(&block.data, self.dir_space()) {
+                if entry.ino != 0 && entry.name == name {
+                    return Ok(Some(entry.ino));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_empty_dir(&self, dir: &Inode) -> Result<bool> {
+        for block in self.dir_blocks(dir)? {
+            for entry in DirEntries::new(&block.data, self.dir_space()) {
+                if entry.ino != 0 && entry.name != b"." && entry.name != b".." {
+                    return Ok(false);
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// checks that entries of `dir` can be changed, turning an htree
+    /// directory into a linear one first
+    fn prepare_dir(&mut self, dir: &mut Inode) -> Result<()> {
+        if dir.flags() & (ENCRYPT_FL | CASEFOLD_FL) != 0 {
+            return Err(Ext4VolumeError::UnsupportedFeature(
+                "encrypted or case-folded directories",
+            ));
+        }
+        if dir.flags() & INDEX_FL == 0 {
+            return Ok(());
+        }
+        let mut blocks = self.dir_blocks(dir)?;
+        let root = match blocks.first() {
+            Some(block) if block.logical == 0 => block.data.clone(),
+            _ => return Err(corrupted(format!("directory {} has no blocks", dir.ino))),
+        };
+        // the htree root sits after `.` and `..` in block 0, interior nodes
+        // fill blocks of their own behind an empty entry
+        let levels = root[DX_ROOT_INFO + 6] as usize;
+        let mut nodes = BTreeSet::new();
+        let mut current = vec![0u64];
+        for _ in 0..levels {
+            let mut next = Vec::new();
+            for &logical in &current {
+                let node = blocks
+                    .iter()
+                    .find(|b| b.logical == logical)
+                    .ok_or_else(|| corrupted("htree node out of range"))?;
+                let at = if logical == 0 {
+                    DX_ROOT_INFO + root[DX_ROOT_INFO + 5] as usize
+                } else {
+                    8
+                };
+                let count = u16_at(&node.data, at + 2) as usize;
+                for i in 0..count {
+                    let child = (u32_at(&node.data, at + 4 + i * 8) & 0x0fff_ffff) as u64;
+                    if nodes.insert(child) {
+                        next.push(child);
+                    }
+                }
+            }
+            current = next;
+        }
+        let space = self.dir_space();
+        let block_size = self.block_size as usize;
+        for block in blocks.iter_mut() {
+            if block.logical == 0 {
+                block.data[DX_ROOT_INFO..].iter_mut().for_each(|b| *b = 0);
+                put_u16(&mut block.data, 16, (space - 12) as u16);
+            } else if nodes.contains(&block.logical) {
+                block.data = vec![0u8; block_size];
+                put_u16(&mut block.data, 4, space as u16);
+            } else {
+                continue;
+            }
+            self.write_dir_block(dir, block)?;
+        }
+        dir.set_flags(dir.flags() & !INDEX_FL);
+        self.write_inode(dir)
+    }
+
+    fn add_entry(&mut self, dir: &mut Inode, name: &[u8], ino: u32, kind: u8) -> Result<()> {
+        self.prepare_dir(dir)?;
+        let kind = if self.has_incompat(INCOMPAT_FILETYPE) {
+            kind
+        } else {
+            0
+        };
+        let needed = entry_len(name.len());
+        let space = self.dir_space();
+        for mut block in self.dir_blocks(dir)? {
+            let mut at = 0;
+            while at < space {
+                let rec_len = u16_at(&block.data, at + 4) as usize;
+                if rec_len < 8 || at + rec_len > space {
+                    return Err(corrupted(format!("bad directory entry in {}", dir.ino)));
+                }
+                let used = if u32_at(&block.data, at) == 0 {
+                    0
+                } else {
+                    entry_len(block.data[at + 6] as usize)
+                };
+                if rec_len - used >= needed {
+                    let target = if used == 0 {
+                        at
+                    } else {
+                        put_u16(&mut block.data, at + 4, used as u16);
+                        at + used
+                    };
+                    put_dir_entry(&mut block.data, target, rec_len - used, name, ino, kind);
+                    self.write_dir_block(dir, &mut block)?;
+                    return Ok(());
+                }
+                at += rec_len;
+            }
+        }
+        if dir.flags() & EXTENTS_FL == 0 {
+            return Err(Ext4VolumeError::UnsupportedFeature(
+                "growing directories without extents",
+            ));
+        }
+        let logical = dir.size() / self.block_size;
+        let mut mapping = self.mapping(dir)?;
+        let goal = mapping
+            .extents
+            .last()
+            .map(|e| e.physical + e.len)
+            .unwrap_or_else(|| self.group_start(self.group_of_inode(dir.ino)));
+        let (physical, _) = self.allocate_blocks(1, goal)?[0];
+        let mut block = DirBlock {
+            logical,
+            physical,
+            data: vec![0u8; self.block_size as usize],
+        };
+        put_dir_entry(&mut block.data, 0, space, name, ino, kind);
+        self.write_dir_block(dir, &mut block)?;
+        match mapping.extents.last_mut() {
+            Some(last)
+                if last.logical + last.len == logical
+                    && last.physical + last.len == physical
+                    && !last.unwritten =>
+            {
+                last.len += 1
+            }
+            _ => mapping.extents.push(Extent {
+                logical,
+                physical,
+                len: 1,
+                unwritten: false,
+            }),
+        }
+        let data_blocks: u64 = mapping.extents.iter().map(|e| e.len).sum();
+        let tree = match self.set_extents(dir, &mapping.extents) {
+            Ok(tree) => tree,
+            Err(err) => {
+                self.free_blocks(physical, 1)?;
+                return Err(err);
+            }
+        };
+        for &block in &mapping.tree {
+            self.free_blocks(block, 1)?;
+        }
+        dir.set_size((logical + 1) * self.block_size);
+        self.set_block_count(dir, data_blocks + tree);
+        self.write_inode(dir)
+    }
+
+    fn remove_entry(&mut self, dir: &mut Inode, name: &[u8]) -> Result<()> {
+        self.prepare_dir(dir)?;
+        let space = self.dir_space();
+        for mut block in self.dir_blocks(dir)? {
+            let mut previous: Option<usize> = None;
+            let mut at = 0;
+            while at < space {
+                let rec_len = u16_at(&block.data, at + 4) as usize;
+                if rec_len < 8 || at + rec_len > space {
+                    return Err(corrupted(format!("bad directory entry in {}", dir.ino)));
+                }
+                let len = block.data[at + 6] as usize;
+                if u32_at(&block.data, at) != 0 && &block.data[at + 8..at + 8 + len] == name {
+                    match previous {
+                        Some(previous) => {
+                            let merged = u16_at(&block.data, previous + 4) as usize + rec_len;
+                            put_u16(&mut block.data, previous + 4, merged as u16);
+                        }
+                        None => put_u32(&mut block.data, at, 0),
+                    }
+                    return self.write_dir_block(dir, &mut block);
+                }
+                previous = Some(at);
+                at += rec_len;
+            }
+        }
+        Err(Ext4VolumeError::NotFound(
+            String::from_utf8_lossy(name).into_owned(),
+        ))
+    }
+
+    fn link(&mut self, parent: u32, name: &[u8], inode: &Inode, now: SystemTime) -> Result<()> {
+        let mut dir = self.read_inode(parent)?;
+        self.add_entry(&mut dir, name, inode.ino, dir_entry_type(inode.file_type()))?;
+        let mut dir = self.read_inode(parent)?;
+        if inode.is_dir() {
+            let links = dir.links();
+            if links >= MAX_LINKS - 1 && self.has_ro_compat(RO_COMPAT_DIR_NLINK) {
+                put_u16(&mut dir.raw, I_LINKS, 1);
+            } else if links != 1 {
+                put_u16(&mut dir.raw, I_LINKS, links + 1);
+            }
+        }
+        dir.set_times(&[I_MTIME, I_CTIME], now);
+        self.write_inode(&mut dir)
+    }
+
+    fn unlink(&mut self, parent: u32, name: &[u8], ino: u32) -> Result<()> {
+        let now = SystemTime::now();
+        let mut dir = self.read_inode(parent)?;
+        self.remove_entry(&mut dir, name)?;
+        let mut dir = self.read_inode(parent)?;
+        let mut inode = self.read_inode(ino)?;
+        if inode.is_dir() {
+            let links = dir.links();
+            if links > 2 {
+                put_u16(&mut dir.raw, I_LINKS, links - 1);
+            }
+            dir.set_times(&[I_MTIME, I_CTIME], now);
+            self.write_inode(&mut dir)?;
+            self.release(&mut inode)
+        } else {
+            dir.set_times(&[I_MTIME, I_CTIME], now);
+            self.write_inode(&mut dir)?;
+            let links = inode.links().saturating_sub(1);
+            if links == 0 {
+                self.release(&mut inode)
+            } else {
+                put_u16(&mut inode.raw, I_LINKS, links);
+                inode.set_times(&[I_CTIME], now);
+                self.write_inode(&mut inode)
+            }
+        }
+    }
+
+    fn make_dir(&mut self, resolved: &Resolved) -> Result<()> {
+        self.require_extents()?;
+        let parent = resolved.parent;
+        self.create(
+            resolved,
+            S_IFDIR | 0o755,
+            SystemTime::now(),
+            |volume, inode| {
+                let goal = volume.group_start(volume.group_of_inode(inode.ino));
+                let (physical, _) = volume.allocate_blocks(1, goal)?[0];
+                let mut block = DirBlock {
+                    logical: 0,
+                    physical,
+                    data: vec![0u8; volume.block_size as usize],
+                };
+                let kind = if volume.has_incompat(INCOMPAT_FILETYPE) {
+                    2
+                } else {
+                    0
+                };
+                let space = volume.dir_space();
+                put_dir_entry(&mut block.data, 0, 12, b".", inode.ino, kind);
+                put_dir_entry(&mut block.data, 12, space - 12, b"..", parent, kind);
+                volume.write_dir_block(inode, &mut block)?;
+                let extent = Extent {
+                    logical: 0,
+                    physical,
+                    len: 1,
+                    unwritten: false,
+                };
+                volume.set_extents(inode, &[extent])?;
+                put_u16(&mut inode.raw, I_LINKS, 2);
+                inode.set_size(volume.block_size);
+                volume.set_block_count(inode, 1);
+                Ok(())
+            },
+        )
+    }
+
+    /// allocates an inode, lets `fill` set up its contents and links it at
+    /// `resolved`, releasing everything again when a step fails
+    fn create<F>(&mut self, resolved: &Resolved, mode: u16, now: SystemTime, fill: F) -> Result<()>
+    where
+        F: FnOnce(&mut Ext4Volume, &mut Inode) -> Result<()>,
+    {
+        let mut inode = self.new_inode(resolved.parent, mode, now)?;
+        let result = fill(self, &mut inode)
+            .and_then(|_| self.write_inode(&mut inode))
+            .and_then(|_| self.link(resolved.parent, &resolved.name, &inode, now));
+        if result.is_err() {
+            self.release(&mut inode)?;
+        }
+        result
+    }
+
+    // paths
+
+    /// walks `path` from the root, following symbolic links in directory
+    /// components and, with `follow`, in the final one
+    fn resolve(&self, path: &str, follow: bool) -> Result<Resolved> {
+        let mut pending = components(path.as_bytes())
+            .ok_or_else(|| Ext4VolumeError::InvalidPath(path.to_string()))?;
+        if pending.is_empty() {
+            return Err(Ext4VolumeError::InvalidPath(path.to_string()));
+        }
+        pending.reverse();
+        let mut dir = ROOT_INO;
+        let mut links = 0;
+        while let Some(name) = pending.pop() {
+            let inode = self.read_inode(dir)?;
+            if !inode.is_dir() {
+                return Err(Ext4VolumeError::NotADirectory(path.to_string()));
+            }
+            let child = match self.lookup(&inode, &name)? {
+                Some(ino) => self.read_inode(ino)?,
+                None if pending.is_empty() => {
+                    return Ok(Resolved {
+                        parent: dir,
+                        name,
+                        ino: None,
+                    })
+                }
+                None => return Err(Ext4VolumeError::NotFound(path.to_string())),
+            };
+            let is_last = pending.is_empty();
+            if child.file_type() == S_IFLNK && (!is_last || follow) {
+                links += 1;
+                if links > MAX_SYMLINKS {
+                    return Err(Ext4VolumeError::InvalidPath(path.to_string()));
+                }
+                let target = self.read_data(&child)?;
+                if target.starts_with(b"/") {
+                    dir = ROOT_INO;
+                }
+                let mut target = components(&target)
+                    .ok_or_else(|| Ext4VolumeError::InvalidPath(path.to_string()))?;
+                if target.is_empty() {
+                    target.push(b".".to_vec());
+                }
+                pending.extend(target.into_iter().rev());
+                continue;
+            }
+            if is_last {
+                return Ok(Resolved {
+                    parent: dir,
+                    name,
+                    ino: Some(child.ino),
+                });
+            }
+            dir = child.ino;
+        }
+        unreachable!()
+    }
+
+    fn existing(&self, path: &str, follow: bool) -> Result<u32> {
+        if components(path.as_bytes()).is_some_and(|c| c.is_empty()) {
+            return Ok(ROOT_INO);
+        }
+        self.resolve(path, follow)?
+            .ino
+            .ok_or_else(|| Ext4VolumeError::NotFound(path.to_string()))
+    }
+
+    fn existing_entry(&self, path: &str) -> Result<(u32, Vec<u8>, u32)> {
+        let resolved = self.resolve(path, false)?;
+        match resolved.ino {
+            Some(ino) if resolved.name != b"." && resolved.name != b".." => {
+                Ok((resolved.parent, resolved.name, ino))
+            }
+            Some(_) => Err(Ext4VolumeError::InvalidPath(path.to_string())),
+            None => Err(Ext4VolumeError::NotFound(path.to_string())),
+        }
+    }
+}
+
+/// offset and length of the file system selected by `partition`
+fn locate(file: &File, partition: &Partition) -> Result<(u64, u64)> {
+    if let Partition::Whole = partition {
+        return Ok((0, file.metadata()?.len()));
+    }
+    let gpt = match (Gpt::read(file)?, partition) {
+        (Some(gpt), _) => gpt,
+        (None, Partition::Label(label)) => {
+            return match read_superblock(file, 0)? {
+                Some(sb) if label_of(&sb) == *label => Ok((0, file.metadata()?.len())),
+                _ => Err(Ext4VolumeError::PartitionNotFound),
+            };
+        }
+        (None, _) => return Err(Ext4VolumeError::PartitionNotFound),
+    };
+    for (index, entry) in gpt.partitions.iter().enumerate() {
+        if !entry.is_used() {
+            continue;
+        }
+        let offset = entry.first_lba * gpt.block_size;
+        let found = match partition {
+            Partition::Whole => true,
+            Partition::Type(guid) => entry.type_guid == *guid,
+            Partition::Name(name) => entry.name() == *name,
+            Partition::Label(label) => {
+                read_superblock(file, offset)?.is_some_and(|sb| label_of(&sb) == *label)
+            }
+            Partition::Index(i) => index == *i,
+        };
+        if found {
+            return Ok((offset, entry.len_blocks() * gpt.block_size));
+        }
+    }
+    Err(Ext4VolumeError::PartitionNotFound)
+}
+
+fn read_superblock(file: &File, offset: u64) -> Result<Option<Vec<u8>>> {
+    let mut sb = vec![0u8; SUPERBLOCK_SIZE];
+    match file.read_exact_at(&mut sb, offset + SUPERBLOCK_OFFSET) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    if u16_at(&sb, SB_MAGIC) != EXT4_MAGIC {
+        return Ok(None);
+    }
+    Ok(Some(sb))
+}
+
+fn label_of(sb: &[u8]) -> String {
+    let label = &sb[SB_VOLUME_NAME..SB_VOLUME_NAME + 16];
+    let len = label.iter().position(|&b| b == 0).unwrap_or(label.len());
+    String::from_utf8_lossy(&label[..len]).into_owned()
+}
+
+fn components(path: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let components: Vec<Vec<u8>> = path
+        .split(|&b| b == b'/')
+        .filter(|c| !c.is_empty())
+        .map(|c| c.to_vec())
+        .collect();
+    if components.iter().any(|c| c.len() > 255 || c.contains(&0)) {
+        return None;
+    }
+    Some(components)
+}
+
+struct Resolved {
+    parent: u32,
+    name: Vec<u8>,
+    ino: Option<u32>,
+}
+
+struct Inode {
+    ino: u32,
+    raw: Vec<u8>,
+    /// 512-byte sectors per file system block
+    block_sectors: u64,
+}
+
+impl Inode {
+    fn mode(&self) -> u16 {
+        u16_at(&self.raw, I_MODE)
+    }
+
+    fn file_type(&self) -> u16 {
+        self.mode() & 0o170000
+    }
+
+    fn is_dir(&self) -> bool {
+        self.file_type() == S_IFDIR
+    }
+
+    fn size(&self) -> u64 {
+        get_u32_pair(&self.raw, I_SIZE, true)
+    }
+
+    fn set_size(&mut self, size: u64) {
+        set_u32_pair(&mut self.raw, I_SIZE, true, size);
+    }
+
+    fn links(&self) -> u16 {
+        u16_at(&self.raw, I_LINKS)
+    }
+
+    fn flags(&self) -> u32 {
+        u32_at(&self.raw, I_FLAGS)
+    }
+
+    fn set_flags(&mut self, flags: u32) {
+        put_u32(&mut self.raw, I_FLAGS, flags);
+    }
+
+    fn file_acl(&self) -> u64 {
+        u32_at(&self.raw, I_FILE_ACL.0) as u64 | (u16_at(&self.raw, I_FILE_ACL.1) as u64) << 32
+    }
+
+    fn set_file_acl(&mut self, block: u64) {
+        put_u32(&mut self.raw, I_FILE_ACL.0, block as u32);
+        put_u16(&mut self.raw, I_FILE_ACL.1, (block >> 32) as u16);
+    }
+
+    fn get_u16_pair(&self, (lo, hi): (usize, usize)) -> u32 {
+        u16_at(&self.raw, lo) as u32 | (u16_at(&self.raw, hi) as u32) << 16
+    }
+
+    fn set_u16_pair(&mut self, (lo, hi): (usize, usize), value: u32) {
+        put_u16(&mut self.raw, lo, value as u16);
+        put_u16(&mut self.raw, hi, (value >> 16) as u16);
+    }
+
+    /// whether the field at `offset` lies within the extra inode space in use
+    fn has_field(&self, offset: usize, len: usize) -> bool {
+        self.raw.len() > GOOD_OLD_INODE_SIZE
+            && GOOD_OLD_INODE_SIZE + u16_at(&self.raw, I_EXTRA_ISIZE) as usize >= offset + len
+    }
+
+    fn set_times(&mut self, fields: &[(usize, usize)], time: SystemTime) {
+        let (secs, nanos) = unix_secs(time);
+        for &(base, extra) in fields {
+            if base >= GOOD_OLD_INODE_SIZE && !self.has_field(base, 4) {
+                continue;
+            }
+            put_u32(&mut self.raw, base, secs as u32);
+            if self.has_field(extra, 4) {
+                let epoch = ((secs - secs as i32 as i64) >> 32) as u32 & 3;
+                put_u32(&mut self.raw, extra, nanos << 2 | epoch);
+            }
+        }
+    }
+
+    /// whether the data blocks field is in use; device nodes, fifos,
+    /// sockets and fast symbolic links keep no blocks
+    fn has_blocks(&self) -> bool {
+        match self.file_type() {
+            S_IFCHR | S_IFBLK | S_IFIFO | S_IFSOCK => false,
+            S_IFLNK => !self.is_fast_symlink(),
+            _ => true,
+        }
+    }
+
+    /// symbolic link with the target stored in `i_block`, recognized like
+    /// e2fsprogs does by the absence of data blocks
+    fn is_fast_symlink(&self) -> bool {
+        if self.file_type() != S_IFLNK || self.flags() & INLINE_DATA_FL != 0 {
+            return false;
+        }
+        let sectors = u32_at(&self.raw, I_BLOCKS.0) as u64;
+        let acl_sectors = if self.file_acl() != 0 {
+            sectors.min(self.block_sectors)
+        } else {
+            0
+        };
+        self.size() <= FAST_SYMLINK_MAX as u64 && sectors == acl_sectors
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Extent {
+    logical: u64,
+    physical: u64,
+    len: u64,
+    unwritten: bool,
+}
+
+#[derive(Default)]
+struct Mapping {
+    extents: Vec<Extent>,
+    /// extent tree or indirect blocks
+    tree: Vec<u64>,
+}
+
+struct DirBlock {
+    logical: u64,
+    physical: u64,
+    data: Vec<u8>,
+}
+
+struct DirEntry<'a> {
+    ino: u32,
+    name: &'a [u8],
+}
+
+/// entries of a directory block, stopping at the first malformed one
+struct DirEntries<'a> {
+    data: &'a [u8],
+    space: usize,
+    at: usize,
+}
+
+impl<'a> DirEntries<'a> {
+    fn new(data: &'a [u8], space: usize) -> DirEntries<'a> {
+        DirEntries {
+            data,
+            space: space.min(data.len()),
+            at: 0,
+        }
+    }
+}
+
+impl<'a> Iterator for DirEntries<'a> {
+    type Item = DirEntry<'a>;
+
+    fn next(&mut self) -> Option<DirEntry<'a>> {
+        if self.at + 8 > self.space {
+            return None;
+        }
+        let at = self.at;
+        let rec_len = u16_at(self.data, at + 4) as usize;
+        let name_len = self.data[at + 6] as usize;
+        if rec_len < 8 || at + rec_len > self.space || 8 + name_len > rec_len {
+            return None;
+        }
+        self.at += rec_len;
+        Some(DirEntry {
+            ino: u32_at(self.data, at),
+            name: &self.data[at + 8..at + 8 + name_len],
+        })
+    }
+}
+
+fn entry_len(name_len: usize) -> usize {
+    (8 + name_len + 3) & !3
+}
+
+fn put_dir_entry(data: &mut [u8], at: usize, rec_len: usize, name: &[u8], ino: u32, kind: u8) {
+    data[at..at + rec_len].iter_mut().for_each(|b| *b = 0);
+    put_u32(data, at, ino);
+    put_u16(data, at + 4, rec_len as u16);
+    data[at + 6] = name.len() as u8;
+    data[at + 7] = kind;
+    data[at + 8..at + 8 + name.len()].copy_from_slice(name);
+}
+
+fn dir_entry_type(file_type: u16) -> u8 {
+    match file_type {
+        S_IFREG => 1,
+        S_IFDIR => 2,
+        S_IFCHR => 3,
+        S_IFBLK => 4,
+        S_IFIFO => 5,
+        S_IFSOCK => 6,
+        S_IFLNK => 7,
+        _ => 0,
+    }
+}
+
+fn extent_entry((logical, physical, len): (u64, u64, u64)) -> [u8; 12] {
+    let mut entry = [0u8; 12];
+    put_u32(&mut entry, 0, logical as u32);
+    put_u16(&mut entry, 4, len as u16);
+    put_u16(&mut entry, 6, (physical >> 32) as u16);
+    put_u32(&mut entry, 8, physical as u32);
+    entry
+}
+
+fn test_bit(bitmap: &[u8], bit: u64) -> bool {
+    bitmap[(bit / 8) as usize] & (1 << (bit % 8)) != 0
+}
+
+/// CRC-32C as used by ext4, without the final inversion
+fn crc32c(seed: u32, data: &[u8]) -> u32 {
+    !crc32c::crc32c_append(!seed, data)
+}
+
+/// CRC-16 of the older `gdt_csum` feature
+fn crc16(mut crc: u16, data: &[u8]) -> u16 {
+    for &byte in data {
+        crc ^= byte as u16;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn u16_at(raw: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([raw[at], raw[at + 1]])
+}
+
+fn u32_at(raw: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([raw[at], raw[at + 1], raw[at + 2], raw[at + 3]])
+}
+
+fn put_u16(raw: &mut [u8], at: usize, value: u16) {
+    raw[at..at + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(raw: &mut [u8], at: usize, value: u32) {
+    raw[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn get_u32_pair(raw: &[u8], (lo, hi): (usize, usize), wide: bool) -> u64 {
+    let mut value = u32_at(raw, lo) as u64;
+    if wide {
+        value |= (u32_at(raw, hi) as u64) << 32;
+    }
+    value
+}
+
+fn set_u32_pair(raw: &mut [u8], (lo, hi): (usize, usize), wide: bool, value: u64) {
+    put_u32(raw, lo, value as u32);
+    if wide {
+        put_u32(raw, hi, (value >> 32) as u32);
+    }
+}
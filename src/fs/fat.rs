@@ -0,0 +1,877 @@
+//! FAT image module
+
+use crate::disk::gpt::{Gpt, Guid};
+use crate::fs::{civil_from_unix, unix_secs, FileTree, Node};
+
+use std::collections::{BTreeMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const SECTOR: u64 = 512;
+/// longest long file name
+pub const MAX_NAME_LEN: usize = 255;
+/// characters that are not allowed in long file names
+const FORBIDDEN: &str = "\\:*?\"<>|";
+const SHORT_SPECIAL: &str = "!#$%&'()-@^_`{}~";
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+const FAT12_MAX_CLUSTERS: u64 = 4084;
+const FAT16_MAX_CLUSTERS: u64 = 65524;
+const FAT32_MAX_CLUSTERS: u64 = 0x0fff_fff4;
+const ROOT_ENTRIES: u64 = 512;
+const MAX_DIR_ENTRIES: u64 = 65536;
+
+/// first partition block; partitions are aligned to 1 MiB
+const PARTITION_ALIGNMENT: u64 = 1 << 20;
+
+/// FAT variant, chosen by the size of the volume unless given
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    fn for_size(size: u64) -> FatType {
+        if size < 16 << 20 {
+            FatType::Fat12
+        } else if size < 512 << 20 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    fn cluster_range(self) -> (u64, u64) {
+        match self {
+            FatType::Fat12 => (1, FAT12_MAX_CLUSTERS),
+            FatType::Fat16 => (FAT12_MAX_CLUSTERS + 1, FAT16_MAX_CLUSTERS),
+            FatType::Fat32 => (FAT16_MAX_CLUSTERS + 1, FAT32_MAX_CLUSTERS),
+        }
+    }
+
+    /// bytes used by `entries` FAT entries
+    fn fat_bytes(self, entries: u64) -> u64 {
+        match self {
+            FatType::Fat12 => (entries * 3).div_ceil(2),
+            FatType::Fat16 => entries * 2,
+            FatType::Fat32 => entries * 4,
+        }
+    }
+
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    fn name(self) -> &'static [u8; 8] {
+        match self {
+            FatType::Fat12 => b"FAT12   ",
+            FatType::Fat16 => b"FAT16   ",
+            FatType::Fat32 => b"FAT32   ",
+        }
+    }
+}
+
+/// builder for FAT12/16/32 images with long file names
+///
+/// Files come from memory or from host directories, which are read when the
+/// image is written. Without an explicit size the image is sized to fit its
+/// contents. With `gpt_partition` the volume is placed in the single partition
+/// of a GPT disk image, e.g. for an EFI system partition.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::disk::gpt::Guid;
+/// # use virtualization_rs::fs::fat::{FatImageBuilder, FatType};
+/// # fn main() -> std::io::Result<()> {
+/// FatImageBuilder::new()
+///     .fat_type(FatType::Fat32)
+///     .size(64 << 20)
+///     .volume_label("ESP")
+///     .host_directory("EFI/BOOT", "/path/to/efi/boot")
+///     .gpt_partition(Guid::EFI_SYSTEM_PARTITION, "EFI System Partition")
+///     .write_to_file("esp.img")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct FatImageBuilder {
+    size: Option<u64>,
+    fat_type: Option<FatType>,
+    volume_label: String,
+    volume_id: Option<u32>,
+    modified: SystemTime,
+    tree: FileTree,
+    host_directories: Vec<(String, PathBuf)>,
+    partition: Option<(Guid, String)>,
+}
+
+impl FatImageBuilder {
+    pub fn new() -> Self {
+        FatImageBuilder {
+            size: None,
+            fat_type: None,
+            volume_label: String::new(),
+            volume_id: None,
+            modified: SystemTime::now(),
+            tree: FileTree::new(MAX_NAME_LEN, FORBIDDEN),
+            host_directories: Vec::new(),
+            partition: None,
+        }
+    }
+
+    /// size of the volume in bytes, rounded down to whole sectors
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = Some(size);
+        self
+    }
+
+    pub fn fat_type(mut self, fat_type: FatType) -> Self {
+        self.fat_type = Some(fat_type);
+        self
+    }
+
+    /// label of up to 11 characters, stored upper case
+    pub fn volume_label<T: Into<String>>(mut self, volume_label: T) -> Self {
+        self.volume_label = volume_label.into();
+        self
+    }
+
+    /// volume serial number, derived from the modification time by default
+    pub fn volume_id(mut self, volume_id: u32) -> Self {
+        self.volume_id = Some(volume_id);
+        self
+    }
+
+    /// timestamp recorded for every file
+    pub fn modified(mut self, modified: SystemTime) -> Self {
+        self.modified = modified;
+        self
+    }
+
+    /// adds a directory; `path` uses `/` as separator
+    pub fn directory(mut self, path: &str) -> Self {
+        self.tree.directory(path);
+        self
+    }
+
+    /// adds a file, creating its parent directories; `path` uses `/` as separator
+    pub fn file(mut self, path: &str, data: Vec<u8>) -> Self {
+        self.tree.file(path, data);
+        self
+    }
+
+    /// adds the regular files and directories below `source` to `dest`
+    pub fn host_directory<P: Into<PathBuf>>(mut self, dest: &str, source: P) -> Self {
+        self.host_directories
+            .push((dest.to_string(), source.into()));
+        self
+    }
+
+    /// places the volume in the only partition of a new GPT disk image
+    pub fn gpt_partition<T: Into<String>>(mut self, type_guid: Guid, name: T) -> Self {
+        self.partition = Some((type_guid, name.into()));
+        self
+    }
+
+    /// writes the image to `path`, replacing any existing file
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let label = short_label(&self.volume_label)?;
+        let mut tree = FileTree::new(MAX_NAME_LEN, FORBIDDEN);
+        tree.root = clone_nodes(&self.tree.root);
+        self.tree.check()?;
+        for (dest, source) in self.host_directories.iter() {
+            tree.host_directory(dest, source)?;
+        }
+        tree.check()?;
+
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        flatten(&tree.root, 0, &mut dirs, &mut files);
+        let names = dirs.iter().map(short_names).collect::<Vec<_>>();
+        let root_entries = dir_entries(&dirs[0], &names[0], true, label.is_some());
+        if root_entries > MAX_DIR_ENTRIES {
+            return Err(too_many_entries());
+        }
+
+        let layout = match self.size {
+            Some(size) => {
+                let fat_type = self.fat_type.unwrap_or_else(|| FatType::for_size(size));
+                let layout = Layout::new(size / SECTOR, fat_type, root_entries)
+                    .ok_or_else(|| invalid_size(size, fat_type))?;
+                if !layout.fits(&dirs, &names, &files, label.is_some()) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "the contents do not fit in the volume",
+                    ));
+                }
+                layout
+            }
+            None => self.fitting_layout(&dirs, &names, &files, root_entries, label.is_some())?,
+        };
+
+        let volume = Volume {
+            layout,
+            dirs: &dirs,
+            names: &names,
+            files: &files,
+            label,
+            volume_id: self.volume_id.unwrap_or(unix_secs(self.modified) as u32),
+            time: dos_time(self.modified),
+        };
+
+        let file = File::create(path)?;
+        match &self.partition {
+            Some((type_guid, name)) => {
+                let volume_blocks = layout.sectors;
+                let start = PARTITION_ALIGNMENT / SECTOR;
+                let mut gpt = Gpt::new(SECTOR, 2 * start + volume_blocks, Guid::random()?);
+                let total = start + volume_blocks + gpt.entry_blocks() + 1;
+                gpt.relocate_backup(total);
+                let partition = &mut gpt.partitions[0];
+                partition.type_guid = *type_guid;
+                partition.unique_guid = Guid::random()?;
+                partition.first_lba = start;
+                partition.last_lba = start + volume_blocks - 1;
+                partition.set_name(name);
+                file.set_len(total * SECTOR)?;
+                gpt.create_protective_mbr(&file, total)?;
+                volume.write(&file, start)?;
+                gpt.write(&file)
+            }
+            None => {
+                file.set_len(layout.sectors * SECTOR)?;
+                volume.write(&file, 0)?;
+                file.sync_all()
+            }
+        }
+    }
+
+    /// smallest layout, in steps of 1 MiB, that holds the contents
+    fn fitting_layout(
+        &self,
+        dirs: &[Dir],
+        names: &[Vec<ShortName>],
+        files: &[&[u8]],
+        root_entries: u64,
+        label: bool,
+    ) -> io::Result<Layout> {
+        let content: u64 = files
+            .iter()
+            .map(|f| (f.len() as u64).div_ceil(4096) * 4096)
+            .sum::<u64>()
+            + dirs.len() as u64 * 4096;
+        let mut size = (content + content / 8 + (1 << 20)).div_ceil(1 << 20) << 20;
+        if self.fat_type == Some(FatType::Fat32) {
+            size = size.max(33 << 20);
+        }
+        loop {
+            let fat_type = self.fat_type.unwrap_or_else(|| FatType::for_size(size));
+            if let Some(layout) = Layout::new(size / SECTOR, fat_type, root_entries) {
+                if layout.fits(dirs, names, files, label) {
+                    return Ok(layout);
+                }
+            }
+            if size > 2 << 40 {
+                return Err(invalid_size(size, fat_type));
+            }
+            size += (size / 4).div_ceil(1 << 20) << 20;
+        }
+    }
+}
+
+impl Default for FatImageBuilder {
+    fn default() -> Self {
+        FatImageBuilder::new()
+    }
+}
+
+fn clone_nodes(nodes: &BTreeMap<String, Node>) -> BTreeMap<String, Node> {
+    nodes
+        .iter()
+        .map(|(name, node)| {
+            let node = match node {
+                Node::File(data) => Node::File(data.clone()),
+                Node::Dir(children) => Node::Dir(clone_nodes(children)),
+            };
+            (name.clone(), node)
+        })
+        .collect()
+}
+
+fn invalid_size(size: u64, fat_type: FatType) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{} bytes is not a valid size for {:?}", size, fat_type),
+    )
+}
+
+fn too_many_entries() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "too many entries in one directory",
+    )
+}
+
+/// geometry of a FAT volume
+#[derive(Clone, Copy)]
+struct Layout {
+    fat_type: FatType,
+    sectors: u64,
+    sectors_per_cluster: u64,
+    reserved_sectors: u64,
+    fat_sectors: u64,
+    /// entries of the fixed root directory of FAT12/16, 0 for FAT32
+    root_entries: u64,
+    clusters: u64,
+}
+
+impl Layout {
+    fn new(sectors: u64, fat_type: FatType, root_entries: u64) -> Option<Layout> {
+        let (min_clusters, max_clusters) = fat_type.cluster_range();
+        let (reserved_sectors, root_entries) = match fat_type {
+            FatType::Fat32 => (32, 0),
+            _ => (1, root_entries.max(ROOT_ENTRIES).div_ceil(16) * 16),
+        };
+        if sectors > u32::MAX as u64 {
+            return None;
+        }
+        let root_sectors = root_entries * 32 / SECTOR;
+        // cluster sizes mkfs.fat and Windows use for FAT32 volumes of this size
+        let first_shift = match fat_type {
+            FatType::Fat32 if sectors <= 532_480 => 0,
+            FatType::Fat32 if sectors <= 16_777_216 => 3,
+            FatType::Fat32 if sectors <= 33_554_432 => 4,
+            FatType::Fat32 if sectors <= 67_108_864 => 5,
+            FatType::Fat32 => 6,
+            _ => 0,
+        };
+        for shift in first_shift..8 {
+            let sectors_per_cluster = 1 << shift;
+            let mut fat_sectors = 1;
+            let clusters = loop {
+                let overhead = reserved_sectors + 2 * fat_sectors + root_sectors;
+                let clusters = sectors.checked_sub(overhead)? / sectors_per_cluster;
+                let needed = fat_type.fat_bytes(clusters + 2).div_ceil(SECTOR);
+                if needed <= fat_sectors {
+                    break clusters;
+                }
+                fat_sectors = needed;
+            };
+            if clusters > max_clusters {
+                continue;
+            }
+            if clusters < min_clusters {
+                return None;
+            }
+            return Some(Layout {
+                fat_type,
+                sectors,
+                sectors_per_cluster,
+                reserved_sectors,
+                fat_sectors,
+                root_entries,
+                clusters,
+            });
+        }
+        None
+    }
+
+    fn cluster_bytes(&self) -> u64 {
+        self.sectors_per_cluster * SECTOR
+    }
+
+    fn root_sectors(&self) -> u64 {
+        self.root_entries * 32 / SECTOR
+    }
+
+    fn data_sector(&self) -> u64 {
+        self.reserved_sectors + 2 * self.fat_sectors + self.root_sectors()
+    }
+
+    /// clusters taken by a directory of `entries` entries
+    fn dir_clusters(&self, dir: usize, entries: u64) -> u64 {
+        if dir == 0 && self.fat_type != FatType::Fat32 {
+            0
+        } else {
+            (entries * 32).div_ceil(self.cluster_bytes()).max(1)
+        }
+    }
+
+    fn fits(&self, dirs: &[Dir], names: &[Vec<ShortName>], files: &[&[u8]], label: bool) -> bool {
+        let mut clusters = 0;
+        for (index, dir) in dirs.iter().enumerate() {
+            let entries = dir_entries(dir, &names[index], index == 0, label);
+            if index == 0 && self.fat_type != FatType::Fat32 && entries > self.root_entries {
+                return false;
+            }
+            clusters += self.dir_clusters(index, entries);
+        }
+        for data in files {
+            clusters += (data.len() as u64).div_ceil(self.cluster_bytes());
+        }
+        clusters <= self.clusters
+    }
+}
+
+enum EntryKind {
+    Dir(usize),
+    File(usize),
+}
+
+struct Entry {
+    name: String,
+    kind: EntryKind,
+}
+
+struct Dir {
+    parent: usize,
+    entries: Vec<Entry>,
+}
+
+fn flatten<'a>(
+    children: &'a BTreeMap<String, Node>,
+    parent: usize,
+    dirs: &mut Vec<Dir>,
+    files: &mut Vec<&'a [u8]>,
+) {
+    let index = dirs.len();
+    dirs.push(Dir {
+        parent,
+        entries: Vec::new(),
+    });
+    for (name, node) in children {
+        let kind = match node {
+            Node::File(data) => {
+                files.push(data);
+                EntryKind::File(files.len() - 1)
+            }
+            Node::Dir(grandchildren) => {
+                let child = dirs.len();
+                flatten(grandchildren, index, dirs, files);
+                EntryKind::Dir(child)
+            }
+        };
+        dirs[index].entries.push(Entry {
+            name: name.clone(),
+            kind,
+        });
+    }
+}
+
+/// 8.3 name of an entry and whether long name entries have to precede it
+struct ShortName {
+    name: [u8; 11],
+    long: bool,
+}
+
+/// number of 32 byte entries of a directory
+fn dir_entries(dir: &Dir, names: &[ShortName], root: bool, label: bool) -> u64 {
+    let mut count = if root { label as u64 } else { 2 };
+    for (entry, short) in dir.entries.iter().zip(names) {
+        count += 1;
+        if short.long {
+            count += (entry.name.encode_utf16().count() as u64).div_ceil(13);
+        }
+    }
+    count
+}
+
+fn is_short_char(c: char) -> bool {
+    c.is_ascii_uppercase() || c.is_ascii_digit() || SHORT_SPECIAL.contains(c)
+}
+
+/// unique short names, keeping names that already are valid 8.3 names
+fn short_names(dir: &Dir) -> Vec<ShortName> {
+    let exact: HashSet<[u8; 11]> = dir
+        .entries
+        .iter()
+        .filter_map(|e| exact_short_name(&e.name))
+        .collect();
+    let mut used = HashSet::new();
+    let mut names = Vec::with_capacity(dir.entries.len());
+    for entry in dir.entries.iter() {
+        if let Some(name) = exact_short_name(&entry.name) {
+            used.insert(name);
+            names.push(ShortName { name, long: false });
+            continue;
+        }
+        let trimmed = entry.name.trim_start_matches('.');
+        let (base, ext) = match trimmed.rfind('.') {
+            Some(dot) => (&trimmed[..dot], &trimmed[dot + 1..]),
+            None => (trimmed, ""),
+        };
+        let basis = |s: &str, max: usize| -> String {
+            s.chars()
+                .filter(|&c| c != ' ' && c != '.')
+                .map(|c| {
+                    let c = c.to_ascii_uppercase();
+                    if is_short_char(c) {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .take(max)
+                .collect()
+        };
+        let mut base = basis(base, 8);
+        if base.is_empty() {
+            base.push('_');
+        }
+        let ext = basis(ext, 3);
+        let mut n = 1;
+        let name = loop {
+            let tail = format!("~{}", n);
+            let keep = base.len().min(8 - tail.len());
+            let candidate = pack_short_name(&format!("{}{}", &base[..keep], tail), &ext);
+            if !exact.contains(&candidate) && used.insert(candidate) {
+                break candidate;
+            }
+            n += 1;
+        };
+        names.push(ShortName { name, long: true });
+    }
+    names
+}
+
+/// the name as an 8.3 name if it is a valid upper case short name
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = match name.find('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || (name.contains('.') && ext.is_empty())
+        || !base.chars().chain(ext.chars()).all(is_short_char)
+    {
+        return None;
+    }
+    Some(pack_short_name(base, ext))
+}
+
+fn pack_short_name(base: &str, ext: &str) -> [u8; 11] {
+    let mut name = [b' '; 11];
+    name[..base.len()].copy_from_slice(base.as_bytes());
+    name[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    name
+}
+
+/// upper case volume label, or `None` for an unlabeled volume
+fn short_label(label: &str) -> io::Result<Option<[u8; 11]>> {
+    if label.is_empty() {
+        return Ok(None);
+    }
+    let upper = label.to_ascii_uppercase();
+    if upper.len() > 11 || !upper.chars().all(|c| c == ' ' || is_short_char(c)) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid volume label {:?}", label),
+        ));
+    }
+    let mut raw = [b' '; 11];
+    raw[..upper.len()].copy_from_slice(upper.as_bytes());
+    Ok(Some(raw))
+}
+
+fn checksum(name: &[u8; 11]) -> u8 {
+    name.iter()
+        .fold(0u8, |sum, &b| sum.rotate_right(1).wrapping_add(b))
+}
+
+/// DOS date and time, in UTC
+fn dos_time(time: SystemTime) -> (u16, u16) {
+    let (year, month, day, hour, minute, second) = civil_from_unix(unix_secs(time));
+    if year < 1980 {
+        return (0x21, 0);
+    }
+    let year = year.min(2107) as u16;
+    let date = ((year - 1980) << 9) | ((month as u16) << 5) | day as u16;
+    let time = ((hour as u16) << 11) | ((minute as u16) << 5) | (second as u16 / 2);
+    (date, time)
+}
+
+/// a laid out volume ready to be written
+struct Volume<'a> {
+    layout: Layout,
+    dirs: &'a [Dir],
+    names: &'a [Vec<ShortName>],
+    files: &'a [&'a [u8]],
+    label: Option<[u8; 11]>,
+    volume_id: u32,
+    time: (u16, u16),
+}
+
+impl<'a> Volume<'a> {
+    /// writes the volume starting at sector `start` of `file`
+    fn write(&self, file: &File, start: u64) -> io::Result<()> {
+        let layout = &self.layout;
+        let base = start * SECTOR;
+
+        // contiguous allocation: directories first, then files
+        let mut next = 2;
+        let mut dir_clusters = Vec::with_capacity(self.dirs.len());
+        for (index, dir) in self.dirs.iter().enumerate() {
+            let entries = dir_entries(dir, &self.names[index], index == 0, self.label.is_some());
+            if entries > MAX_DIR_ENTRIES {
+                return Err(too_many_entries());
+            }
+            let count = layout.dir_clusters(index, entries);
+            dir_clusters.push((next, count));
+            next += count;
+        }
+        let mut file_clusters = Vec::with_capacity(self.files.len());
+        for data in self.files.iter() {
+            let count = (data.len() as u64).div_ceil(layout.cluster_bytes());
+            file_clusters.push((next, count));
+            next += count;
+        }
+        let used = next - 2;
+
+        let mut fat = vec![0u32; layout.clusters as usize + 2];
+        let eoc = layout.fat_type.end_of_chain();
+        fat[0] = eoc & !0xff | 0xf8;
+        fat[1] = eoc;
+        for &(first, count) in dir_clusters.iter().chain(file_clusters.iter()) {
+            for cluster in first..first + count {
+                fat[cluster as usize] = if cluster + 1 == first + count {
+                    eoc
+                } else {
+                    cluster as u32 + 1
+                };
+            }
+        }
+        let fat = self.encode_fat(&fat);
+        for copy in 0..2 {
+            let sector = layout.reserved_sectors + copy * layout.fat_sectors;
+            file.write_all_at(&fat, base + sector * SECTOR)?;
+        }
+
+        let cluster_offset = |cluster: u64| {
+            base + (layout.data_sector() + (cluster - 2) * layout.sectors_per_cluster) * SECTOR
+        };
+        for (index, dir) in self.dirs.iter().enumerate() {
+            let raw = self.directory(index, dir, &dir_clusters, &file_clusters);
+            let offset = if index == 0 && layout.fat_type != FatType::Fat32 {
+                base + (layout.reserved_sectors + 2 * layout.fat_sectors) * SECTOR
+            } else {
+                cluster_offset(dir_clusters[index].0)
+            };
+            file.write_all_at(&raw, offset)?;
+        }
+        for (data, &(first, _)) in self.files.iter().zip(file_clusters.iter()) {
+            if !data.is_empty() {
+                file.write_all_at(data, cluster_offset(first))?;
+            }
+        }
+
+        let boot = self.boot_sector(start);
+        file.write_all_at(&boot, base)?;
+        if layout.fat_type == FatType::Fat32 {
+            let info = fs_info(layout.clusters - used, next);
+            file.write_all_at(&info, base + SECTOR)?;
+            file.write_all_at(&boot, base + 6 * SECTOR)?;
+            file.write_all_at(&info, base + 7 * SECTOR)?;
+        }
+        Ok(())
+    }
+
+    fn encode_fat(&self, fat: &[u32]) -> Vec<u8> {
+        let layout = &self.layout;
+        let mut raw = vec![0u8; (layout.fat_sectors * SECTOR) as usize];
+        match layout.fat_type {
+            FatType::Fat12 => {
+                for (i, &entry) in fat.iter().enumerate() {
+                    let offset = i * 3 / 2;
+                    if i % 2 == 0 {
+                        raw[offset] = entry as u8;
+                        raw[offset + 1] |= (entry >> 8) as u8 & 0x0f;
+                    } else {
+                        raw[offset] |= (entry << 4) as u8;
+                        raw[offset + 1] = (entry >> 4) as u8;
+                    }
+                }
+            }
+            FatType::Fat16 => {
+                for (chunk, &entry) in raw.chunks_mut(2).zip(fat) {
+                    chunk.copy_from_slice(&(entry as u16).to_le_bytes());
+                }
+            }
+            FatType::Fat32 => {
+                for (chunk, &entry) in raw.chunks_mut(4).zip(fat) {
+                    chunk.copy_from_slice(&entry.to_le_bytes());
+                }
+            }
+        }
+        raw
+    }
+
+    fn boot_sector(&self, hidden_sectors: u64) -> Vec<u8> {
+        let layout = &self.layout;
+        let mut boot = vec![0u8; SECTOR as usize];
+        let fat32 = layout.fat_type == FatType::Fat32;
+        boot[0..3].copy_from_slice(if fat32 {
+            &[0xeb, 0x58, 0x90]
+        } else {
+            &[0xeb, 0x3c, 0x90]
+        });
+        boot[3..11].copy_from_slice(b"MSWIN4.1");
+        boot[11..13].copy_from_slice(&(SECTOR as u16).to_le_bytes());
+        boot[13] = layout.sectors_per_cluster as u8;
+        boot[14..16].copy_from_slice(&(layout.reserved_sectors as u16).to_le_bytes());
+        boot[16] = 2;
+        boot[17..19].copy_from_slice(&(layout.root_entries as u16).to_le_bytes());
+        if !fat32 && layout.sectors < 0x10000 {
+            boot[19..21].copy_from_slice(&(layout.sectors as u16).to_le_bytes());
+        } else {
+            boot[32..36].copy_from_slice(&(layout.sectors as u32).to_le_bytes());
+        }
+        boot[21] = 0xf8;
+        // geometry mkfs.fat reports for disks without one
+        boot[24..26].copy_from_slice(&32u16.to_le_bytes());
+        boot[26..28].copy_from_slice(&64u16.to_le_bytes());
+        boot[28..32].copy_from_slice(&(hidden_sectors as u32).to_le_bytes());
+        let ext = if fat32 {
+            boot[36..40].copy_from_slice(&(layout.fat_sectors as u32).to_le_bytes());
+            boot[44..48].copy_from_slice(&2u32.to_le_bytes());
+            boot[48..50].copy_from_slice(&1u16.to_le_bytes());
+            boot[50..52].copy_from_slice(&6u16.to_le_bytes());
+            64
+        } else {
+            boot[22..24].copy_from_slice(&(layout.fat_sectors as u16).to_le_bytes());
+            36
+        };
+        boot[ext] = 0x80;
+        boot[ext + 2] = 0x29;
+        boot[ext + 3..ext + 7].copy_from_slice(&self.volume_id.to_le_bytes());
+        boot[ext + 7..ext + 18].copy_from_slice(self.label.as_ref().unwrap_or(b"NO NAME    "));
+        boot[ext + 18..ext + 26].copy_from_slice(layout.fat_type.name());
+        boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+        boot
+    }
+
+    /// raw contents of a directory, padded to its allocation
+    fn directory(
+        &self,
+        index: usize,
+        dir: &Dir,
+        dir_clusters: &[(u64, u64)],
+        file_clusters: &[(u64, u64)],
+    ) -> Vec<u8> {
+        let layout = &self.layout;
+        let mut raw = Vec::new();
+        if index == 0 {
+            if let Some(label) = &self.label {
+                raw.extend_from_slice(&self.short_entry(label, ATTR_VOLUME_ID, 0, 0));
+            }
+        } else {
+            let parent = if dir.parent == 0 {
+                // ".." of a directory in the root points at cluster 0, even on FAT32
+                0
+            } else {
+                dir_clusters[dir.parent].0
+            };
+            raw.extend_from_slice(&self.short_entry(
+                b".          ",
+                ATTR_DIRECTORY,
+                dir_clusters[index].0,
+                0,
+            ));
+            raw.extend_from_slice(&self.short_entry(b"..         ", ATTR_DIRECTORY, parent, 0));
+        }
+        for (entry, short) in dir.entries.iter().zip(self.names[index].iter()) {
+            if short.long {
+                raw.extend_from_slice(&long_entries(&entry.name, &short.name));
+            }
+            let (attr, cluster, size) = match entry.kind {
+                EntryKind::Dir(child) => (ATTR_DIRECTORY, dir_clusters[child].0, 0),
+                EntryKind::File(file) => {
+                    let data = self.files[file];
+                    let cluster = if data.is_empty() {
+                        0
+                    } else {
+                        file_clusters[file].0
+                    };
+                    (ATTR_ARCHIVE, cluster, data.len() as u32)
+                }
+            };
+            raw.extend_from_slice(&self.short_entry(&short.name, attr, cluster, size));
+        }
+        let len = if index == 0 && layout.fat_type != FatType::Fat32 {
+            layout.root_sectors() * SECTOR
+        } else {
+            dir_clusters[index].1 * layout.cluster_bytes()
+        };
+        raw.resize(len as usize, 0);
+        raw
+    }
+
+    fn short_entry(&self, name: &[u8; 11], attr: u8, cluster: u64, size: u32) -> [u8; 32] {
+        let (date, time) = self.time;
+        let mut entry = [0u8; 32];
+        entry[0..11].copy_from_slice(name);
+        entry[11] = attr;
+        if attr != ATTR_VOLUME_ID {
+            entry[14..16].copy_from_slice(&time.to_le_bytes());
+            entry[16..18].copy_from_slice(&date.to_le_bytes());
+            entry[18..20].copy_from_slice(&date.to_le_bytes());
+        }
+        entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        entry[22..24].copy_from_slice(&time.to_le_bytes());
+        entry[24..26].copy_from_slice(&date.to_le_bytes());
+        entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        entry
+    }
+}
+
+/// long name entries of `name`, in on-disk order
+fn long_entries(name: &str, short: &[u8; 11]) -> Vec<u8> {
+    let mut units: Vec<u16> = name.encode_utf16().collect();
+    let count = units.len().div_ceil(13);
+    if !units.len().is_multiple_of(13) {
+        units.push(0);
+        units.resize(count * 13, 0xffff);
+    }
+    let sum = checksum(short);
+    let mut raw = Vec::with_capacity(count * 32);
+    for ordinal in (1..=count).rev() {
+        let chunk = &units[(ordinal - 1) * 13..ordinal * 13];
+        let mut entry = [0u8; 32];
+        entry[0] = ordinal as u8 | if ordinal == count { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = sum;
+        let slots = (1..11)
+            .step_by(2)
+            .chain((14..26).step_by(2))
+            .chain((28..32).step_by(2));
+        for (slot, unit) in slots.zip(chunk) {
+            entry[slot..slot + 2].copy_from_slice(&unit.to_le_bytes());
+        }
+        raw.extend_from_slice(&entry);
+    }
+    raw
+}
+
+/// FAT32 file system information sector
+fn fs_info(free_clusters: u64, next_free: u64) -> Vec<u8> {
+    let mut info = vec![0u8; SECTOR as usize];
+    info[0..4].copy_from_slice(&0x4161_5252u32.to_le_bytes());
+    info[484..488].copy_from_slice(&0x6141_7272u32.to_le_bytes());
+    info[488..492].copy_from_slice(&(free_clusters as u32).to_le_bytes());
+    info[492..496].copy_from_slice(&(next_free as u32).to_le_bytes());
+    info[508..512].copy_from_slice(&0xaa55_0000u32.to_le_bytes());
+    info
+}
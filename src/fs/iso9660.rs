@@ -0,0 +1,693 @@
+//! ISO 9660 image module
+
+use crate::fs::{civil_from_unix, unix_secs, FileTree, Node};
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::SystemTime;
+
+const SECTOR: usize = 2048;
+const SYSTEM_AREA_SECTORS: u32 = 16;
+/// longest file name accepted, the limit of Joliet
+pub const MAX_NAME_LEN: usize = 64;
+
+const FLAG_DIRECTORY: u8 = 2;
+
+const ER_ID: &str = "RRIP_1991A";
+const ER_DESCRIPTOR: &str =
+    "THE ROCK RIDGE INTERCHANGE PROTOCOL PROVIDES SUPPORT FOR POSIX FILE SYSTEM SEMANTICS";
+const ER_SOURCE: &str = "PLEASE CONTACT DISC PUBLISHER FOR SPECIFICATION SOURCE.  SEE PUBLISHER IDENTIFIER IN PRIMARY VOLUME DESCRIPTOR FOR CONTACT INFORMATION.";
+
+/// builder for ISO 9660 images with Joliet and Rock Ridge extensions
+///
+/// Rock Ridge keeps the original names and POSIX modes for Linux guests and
+/// Joliet keeps the names for other systems. The ISO 9660 names themselves
+/// are 8.3 names derived from the original ones.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::fs::iso9660::IsoImageBuilder;
+/// # fn main() -> std::io::Result<()> {
+/// IsoImageBuilder::new()
+///     .volume_id("cidata")
+///     .file("meta-data", b"instance-id: vm1\n".to_vec())
+///     .write_to_file("seed.iso")?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct IsoImageBuilder {
+    volume_id: String,
+    modified: SystemTime,
+    tree: FileTree,
+}
+
+impl IsoImageBuilder {
+    pub fn new() -> Self {
+        IsoImageBuilder {
+            volume_id: String::new(),
+            modified: SystemTime::now(),
+            tree: FileTree::new(MAX_NAME_LEN, ""),
+        }
+    }
+
+    pub fn volume_id<T: Into<String>>(mut self, volume_id: T) -> Self {
+        self.volume_id = volume_id.into();
+        self
+    }
+
+    /// timestamp recorded for the volume and every file
+    pub fn modified(mut self, modified: SystemTime) -> Self {
+        self.modified = modified;
+        self
+    }
+
+    /// adds a directory; `path` uses `/` as separator
+    pub fn directory(mut self, path: &str) -> Self {
+        self.tree.directory(path);
+        self
+    }
+
+    /// adds a file, creating its parent directories; `path` uses `/` as separator
+    pub fn file(mut self, path: &str, data: Vec<u8>) -> Self {
+        self.tree.file(path, data);
+        self
+    }
+
+    pub fn write_to_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = File::create(path)?;
+        let mut out = BufWriter::new(&file);
+        self.write(&mut out)?;
+        out.flush()?;
+        drop(out);
+        file.sync_all()
+    }
+
+    /// writes the image to `out`
+    pub fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        self.tree.check()?;
+        Image::new(self)?.write(out)
+    }
+}
+
+impl Default for IsoImageBuilder {
+    fn default() -> Self {
+        IsoImageBuilder::new()
+    }
+}
+
+enum EntryKind {
+    Dir(usize),
+    File(usize),
+}
+
+struct Entry {
+    name: String,
+    kind: EntryKind,
+}
+
+struct Dir {
+    parent: usize,
+    entries: Vec<Entry>,
+}
+
+/// one of the two directory hierarchies (ISO 9660 with Rock Ridge, or Joliet)
+struct Tree {
+    joliet: bool,
+    /// identifiers of the entries of each directory
+    ids: Vec<Vec<Vec<u8>>>,
+    /// entries of each directory sorted by identifier
+    order: Vec<Vec<usize>>,
+    /// directories in path table order
+    dirs: Vec<usize>,
+    /// position of each directory in `dirs`
+    number: Vec<usize>,
+    /// first sector and size in bytes of each directory
+    extents: Vec<(u32, u32)>,
+    path_table_size: u32,
+    path_table_l: u32,
+    path_table_m: u32,
+}
+
+struct Image<'a> {
+    builder: &'a IsoImageBuilder,
+    dirs: Vec<Dir>,
+    files: Vec<&'a [u8]>,
+    file_extents: Vec<u32>,
+    primary: Tree,
+    joliet: Tree,
+    continuation: u32,
+    total_sectors: u32,
+    time: [u8; 7],
+}
+
+impl<'a> Image<'a> {
+    fn new(builder: &'a IsoImageBuilder) -> io::Result<Image<'a>> {
+        let mut dirs = Vec::new();
+        let mut files = Vec::new();
+        flatten(&builder.tree.root, 0, &mut dirs, &mut files);
+
+        let mut primary = Tree::new(&dirs, false);
+        let mut joliet = Tree::new(&dirs, true);
+
+        let continuation = SYSTEM_AREA_SECTORS + 3;
+        let mut sector = continuation + 1;
+        for tree in [&mut primary, &mut joliet].iter_mut() {
+            let table_sectors = sectors(tree.path_table_size as u64);
+            tree.path_table_l = sector;
+            tree.path_table_m = sector + table_sectors;
+            sector += table_sectors * 2;
+        }
+
+        let time = record_time(builder.modified);
+        let mut image = Image {
+            builder,
+            dirs,
+            files,
+            file_extents: Vec::new(),
+            primary,
+            joliet,
+            continuation,
+            total_sectors: 0,
+            time,
+        };
+
+        // directory sizes do not depend on locations, so lay them out first
+        for joliet in [false, true].iter() {
+            let count = image.dirs.len();
+            let mut extents = vec![(0, 0); count];
+            for (dir, extent) in extents.iter_mut().enumerate() {
+                let size = image.directory(*joliet, dir).len() as u32;
+                *extent = (sector, size);
+                sector += size / SECTOR as u32;
+            }
+            image.tree_mut(*joliet).extents = extents;
+        }
+        for data in image.files.iter() {
+            if data.len() as u64 > u32::MAX as u64 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "files larger than 4 GiB are not supported",
+                ));
+            }
+            image.file_extents.push(sector);
+            sector += sectors(data.len() as u64);
+        }
+        image.total_sectors = sector;
+        Ok(image)
+    }
+
+    fn tree(&self, joliet: bool) -> &Tree {
+        if joliet {
+            &self.joliet
+        } else {
+            &self.primary
+        }
+    }
+
+    fn tree_mut(&mut self, joliet: bool) -> &mut Tree {
+        if joliet {
+            &mut self.joliet
+        } else {
+            &mut self.primary
+        }
+    }
+
+    fn extent(&self, joliet: bool, dir: usize) -> (u32, u32) {
+        self.tree(joliet)
+            .extents
+            .get(dir)
+            .cloned()
+            .unwrap_or((0, 0))
+    }
+
+    fn write<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        out.write_all(&vec![0u8; SYSTEM_AREA_SECTORS as usize * SECTOR])?;
+        out.write_all(&self.volume_descriptor(false))?;
+        out.write_all(&self.volume_descriptor(true))?;
+        let mut terminator = vec![0u8; SECTOR];
+        terminator[0] = 255;
+        terminator[1..6].copy_from_slice(b"CD001");
+        terminator[6] = 1;
+        out.write_all(&terminator)?;
+
+        let mut continuation = er_entry();
+        continuation.resize(SECTOR, 0);
+        out.write_all(&continuation)?;
+
+        for joliet in [false, true].iter() {
+            for big_endian in [false, true].iter() {
+                let mut table = self.path_table(*joliet, *big_endian);
+                table.resize(pad(table.len()), 0);
+                out.write_all(&table)?;
+            }
+        }
+        for joliet in [false, true].iter() {
+            for dir in 0..self.dirs.len() {
+                out.write_all(&self.directory(*joliet, dir))?;
+            }
+        }
+        for data in self.files.iter() {
+            out.write_all(data)?;
+            out.write_all(&vec![0u8; pad(data.len()) - data.len()])?;
+        }
+        Ok(())
+    }
+
+    fn volume_descriptor(&self, joliet: bool) -> Vec<u8> {
+        let tree = self.tree(joliet);
+        let mut vd = vec![0u8; SECTOR];
+        vd[0] = if joliet { 2 } else { 1 };
+        vd[1..6].copy_from_slice(b"CD001");
+        vd[6] = 1;
+        let volume_id = if joliet {
+            self.builder.volume_id.clone()
+        } else {
+            self.builder.volume_id.to_uppercase()
+        };
+        put_text(&mut vd[8..40], "", joliet);
+        put_text(&mut vd[40..72], &volume_id, joliet);
+        put_both32(&mut vd[80..88], self.total_sectors);
+        if joliet {
+            // UCS-2 level 3
+            vd[88..91].copy_from_slice(b"%/E");
+        }
+        put_both16(&mut vd[120..124], 1);
+        put_both16(&mut vd[124..128], 1);
+        put_both16(&mut vd[128..132], SECTOR as u16);
+        put_both32(&mut vd[132..140], tree.path_table_size);
+        vd[140..144].copy_from_slice(&tree.path_table_l.to_le_bytes());
+        vd[148..152].copy_from_slice(&tree.path_table_m.to_be_bytes());
+        let (extent, size) = self.extent(joliet, 0);
+        let root = record(extent, size, FLAG_DIRECTORY, &[0], &[], &self.time);
+        vd[156..190].copy_from_slice(&root);
+        for field in [
+            190..318,
+            318..446,
+            446..574,
+            574..702,
+            702..739,
+            739..776,
+            776..813,
+        ]
+        .iter()
+        .cloned()
+        {
+            put_text(&mut vd[field], "", joliet);
+        }
+        let date = volume_time(self.builder.modified);
+        vd[813..830].copy_from_slice(&date);
+        vd[830..847].copy_from_slice(&date);
+        for field in [847..864, 864..881].iter().cloned() {
+            vd[field.start..field.end - 1].copy_from_slice(b"0000000000000000");
+        }
+        vd[881] = 1;
+        vd
+    }
+
+    fn path_table(&self, joliet: bool, big_endian: bool) -> Vec<u8> {
+        let tree = self.tree(joliet);
+        let mut table = Vec::with_capacity(tree.path_table_size as usize);
+        for &dir in tree.dirs.iter() {
+            let id = tree.dir_id(&self.dirs, dir);
+            let parent = tree.number[self.dirs[dir].parent] as u16 + 1;
+            let (extent, _) = self.extent(joliet, dir);
+            table.push(id.len() as u8);
+            table.push(0);
+            if big_endian {
+                table.extend_from_slice(&extent.to_be_bytes());
+                table.extend_from_slice(&parent.to_be_bytes());
+            } else {
+                table.extend_from_slice(&extent.to_le_bytes());
+                table.extend_from_slice(&parent.to_le_bytes());
+            }
+            table.extend_from_slice(&id);
+            if id.len() % 2 == 1 {
+                table.push(0);
+            }
+        }
+        table
+    }
+
+    /// builds the extent of a directory; its size is a multiple of the sector size
+    fn directory(&self, joliet: bool, dir: usize) -> Vec<u8> {
+        let tree = self.tree(joliet);
+        let (extent, size) = self.extent(joliet, dir);
+        let parent = self.dirs[dir].parent;
+        let (parent_extent, parent_size) = self.extent(joliet, parent);
+        let mut records = Vec::new();
+
+        let mut dot_susp = Vec::new();
+        if !joliet {
+            if dir == 0 {
+                dot_susp.extend_from_slice(&[b'S', b'P', 7, 1, 0xbe, 0xef, 0]);
+                let er_len = er_entry().len() as u32;
+                dot_susp.extend_from_slice(&[b'C', b'E', 28, 1]);
+                let mut ce = [0u8; 24];
+                put_both32(&mut ce[0..8], self.continuation);
+                put_both32(&mut ce[8..16], 0);
+                put_both32(&mut ce[16..24], er_len);
+                dot_susp.extend_from_slice(&ce);
+            }
+            dot_susp.extend_from_slice(&self.rock_ridge(None, self.dir_mode(dir)));
+        }
+        records.push(record(
+            extent,
+            size,
+            FLAG_DIRECTORY,
+            &[0],
+            &dot_susp,
+            &self.time,
+        ));
+        let dotdot_susp = if joliet {
+            Vec::new()
+        } else {
+            self.rock_ridge(None, self.dir_mode(parent))
+        };
+        records.push(record(
+            parent_extent,
+            parent_size,
+            FLAG_DIRECTORY,
+            &[1],
+            &dotdot_susp,
+            &self.time,
+        ));
+
+        for &index in tree.order[dir].iter() {
+            let entry = &self.dirs[dir].entries[index];
+            let id = &tree.ids[dir][index];
+            let (extent, size, flags, mode) = match entry.kind {
+                EntryKind::Dir(child) => {
+                    let (extent, size) = self.extent(joliet, child);
+                    (extent, size, FLAG_DIRECTORY, self.dir_mode(child))
+                }
+                EntryKind::File(file) => (
+                    self.file_extents.get(file).cloned().unwrap_or(0),
+                    self.files[file].len() as u32,
+                    0,
+                    (0o100644, 1),
+                ),
+            };
+            let susp = if joliet {
+                Vec::new()
+            } else {
+                self.rock_ridge(Some(&entry.name), mode)
+            };
+            records.push(record(extent, size, flags, id, &susp, &self.time));
+        }
+
+        let mut raw = Vec::new();
+        for r in records {
+            if raw.len() % SECTOR + r.len() > SECTOR {
+                raw.resize(pad(raw.len()), 0);
+            }
+            raw.extend_from_slice(&r);
+        }
+        raw.resize(pad(raw.len()).max(SECTOR), 0);
+        raw
+    }
+
+    /// mode and link count of a directory
+    fn dir_mode(&self, dir: usize) -> (u32, u32) {
+        let subdirs = self.dirs[dir]
+            .entries
+            .iter()
+            .filter(|e| matches!(e.kind, EntryKind::Dir(_)))
+            .count() as u32;
+        (0o040755, 2 + subdirs)
+    }
+
+    /// Rock Ridge entries of a directory record
+    fn rock_ridge(&self, name: Option<&str>, (mode, nlink): (u32, u32)) -> Vec<u8> {
+        let mut susp = Vec::new();
+        let mut flags = 0x01 | 0x80;
+        if name.is_some() {
+            flags |= 0x08;
+        }
+        susp.extend_from_slice(&[b'R', b'R', 5, 1, flags]);
+        if let Some(name) = name {
+            susp.extend_from_slice(&[b'N', b'M', 5 + name.len() as u8, 1, 0]);
+            susp.extend_from_slice(name.as_bytes());
+        }
+        let mut px = [0u8; 36];
+        px[0..4].copy_from_slice(&[b'P', b'X', 36, 1]);
+        put_both32(&mut px[4..12], mode);
+        put_both32(&mut px[12..20], nlink);
+        susp.extend_from_slice(&px);
+        // modification, access and attribute change time
+        susp.extend_from_slice(&[b'T', b'F', 5 + 3 * 7, 1, 0x0e]);
+        for _ in 0..3 {
+            susp.extend_from_slice(&self.time);
+        }
+        susp
+    }
+}
+
+impl Tree {
+    fn new(dirs: &[Dir], joliet: bool) -> Tree {
+        let mut ids = Vec::with_capacity(dirs.len());
+        let mut order = Vec::with_capacity(dirs.len());
+        for dir in dirs {
+            let dir_ids = if joliet {
+                dir.entries
+                    .iter()
+                    .map(|e| joliet_id(&e.name, matches!(e.kind, EntryKind::File(_))))
+                    .collect()
+            } else {
+                iso_ids(&dir.entries)
+            };
+            let mut dir_order: Vec<usize> = (0..dir.entries.len()).collect();
+            dir_order.sort_by(|&a, &b| dir_ids[a].cmp(&dir_ids[b]));
+            ids.push(dir_ids);
+            order.push(dir_order);
+        }
+
+        let mut tree_dirs = Vec::with_capacity(dirs.len());
+        let mut queue = VecDeque::new();
+        queue.push_back(0);
+        while let Some(dir) = queue.pop_front() {
+            tree_dirs.push(dir);
+            for &index in order[dir].iter() {
+                if let EntryKind::Dir(child) = dirs[dir].entries[index].kind {
+                    queue.push_back(child);
+                }
+            }
+        }
+        let mut number = vec![0; dirs.len()];
+        for (i, &dir) in tree_dirs.iter().enumerate() {
+            number[dir] = i;
+        }
+
+        let mut tree = Tree {
+            joliet,
+            ids,
+            order,
+            dirs: tree_dirs,
+            number,
+            extents: Vec::new(),
+            path_table_size: 0,
+            path_table_l: 0,
+            path_table_m: 0,
+        };
+        tree.path_table_size = tree
+            .dirs
+            .iter()
+            .map(|&dir| {
+                let len = tree.dir_id(dirs, dir).len();
+                (8 + len + len % 2) as u32
+            })
+            .sum();
+        tree
+    }
+
+    /// identifier of a directory in the path table
+    fn dir_id(&self, dirs: &[Dir], dir: usize) -> Vec<u8> {
+        if dir == 0 {
+            return vec![0];
+        }
+        let parent = dirs[dir].parent;
+        let index = dirs[parent]
+            .entries
+            .iter()
+            .position(|e| matches!(e.kind, EntryKind::Dir(d) if d == dir))
+            .unwrap();
+        let id = self.ids[parent][index].clone();
+        debug_assert!(self.joliet || !id.contains(&b';'));
+        id
+    }
+}
+
+fn flatten<'a>(
+    children: &'a BTreeMap<String, Node>,
+    parent: usize,
+    dirs: &mut Vec<Dir>,
+    files: &mut Vec<&'a [u8]>,
+) {
+    let index = dirs.len();
+    dirs.push(Dir {
+        parent,
+        entries: Vec::new(),
+    });
+    for (name, node) in children {
+        let kind = match node {
+            Node::File(data) => {
+                files.push(data);
+                EntryKind::File(files.len() - 1)
+            }
+            Node::Dir(grandchildren) => {
+                let child = dirs.len();
+                flatten(grandchildren, index, dirs, files);
+                EntryKind::Dir(child)
+            }
+        };
+        dirs[index].entries.push(Entry {
+            name: name.clone(),
+            kind,
+        });
+    }
+}
+
+/// unique 8.3 identifiers made of d-characters
+fn iso_ids(entries: &[Entry]) -> Vec<Vec<u8>> {
+    let mut used = HashSet::new();
+    let mut ids = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let is_file = matches!(entry.kind, EntryKind::File(_));
+        let (stem, ext) = match entry.name.rfind('.') {
+            Some(dot) if is_file && dot > 0 => (&entry.name[..dot], &entry.name[dot + 1..]),
+            _ => (entry.name.as_str(), ""),
+        };
+        let stem = d_chars(stem, 8);
+        let ext = d_chars(ext, 3);
+        let mut candidate = stem.clone();
+        let mut n = 0;
+        while !used.insert((candidate.clone(), ext.clone())) {
+            n += 1;
+            let suffix = n.to_string();
+            let keep = stem.len().min(8 - suffix.len());
+            candidate = format!("{}{}", &stem[..keep], suffix);
+        }
+        let id = if is_file {
+            format!("{}.{};1", candidate, ext)
+        } else {
+            candidate
+        };
+        ids.push(id.into_bytes());
+    }
+    ids
+}
+
+fn d_chars(s: &str, max: usize) -> String {
+    s.chars()
+        .map(|c| match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' | c @ '0'..='9' => c,
+            _ => '_',
+        })
+        .take(max)
+        .collect()
+}
+
+fn joliet_id(name: &str, is_file: bool) -> Vec<u8> {
+    let mut id: Vec<u8> = name.encode_utf16().flat_map(|u| u.to_be_bytes()).collect();
+    if is_file {
+        id.extend_from_slice(&[0, b';', 0, b'1']);
+    }
+    id
+}
+
+fn record(extent: u32, size: u32, flags: u8, id: &[u8], susp: &[u8], time: &[u8; 7]) -> Vec<u8> {
+    let id_pad = if id.len().is_multiple_of(2) { 1 } else { 0 };
+    let mut len = 33 + id.len() + id_pad + susp.len();
+    len += len % 2;
+    let mut r = vec![0u8; len];
+    r[0] = len as u8;
+    put_both32(&mut r[2..10], extent);
+    put_both32(&mut r[10..18], size);
+    r[18..25].copy_from_slice(time);
+    r[25] = flags;
+    put_both16(&mut r[28..32], 1);
+    r[32] = id.len() as u8;
+    r[33..33 + id.len()].copy_from_slice(id);
+    let start = 33 + id.len() + id_pad;
+    r[start..start + susp.len()].copy_from_slice(susp);
+    r
+}
+
+fn er_entry() -> Vec<u8> {
+    let mut er = vec![
+        b'E',
+        b'R',
+        (8 + ER_ID.len() + ER_DESCRIPTOR.len() + ER_SOURCE.len()) as u8,
+        1,
+        ER_ID.len() as u8,
+        ER_DESCRIPTOR.len() as u8,
+        ER_SOURCE.len() as u8,
+        1,
+    ];
+    er.extend_from_slice(ER_ID.as_bytes());
+    er.extend_from_slice(ER_DESCRIPTOR.as_bytes());
+    er.extend_from_slice(ER_SOURCE.as_bytes());
+    er
+}
+
+fn put_text(field: &mut [u8], text: &str, joliet: bool) {
+    if joliet {
+        let units: Vec<u16> = text.encode_utf16().collect();
+        for (i, chunk) in field.chunks_mut(2).enumerate() {
+            let unit = units.get(i).cloned().unwrap_or(0x20);
+            if chunk.len() == 2 {
+                chunk.copy_from_slice(&unit.to_be_bytes());
+            } else {
+                chunk[0] = 0x20;
+            }
+        }
+    } else {
+        for (i, byte) in field.iter_mut().enumerate() {
+            *byte = text.as_bytes().get(i).cloned().unwrap_or(b' ');
+        }
+    }
+}
+
+fn put_both16(field: &mut [u8], value: u16) {
+    field[0..2].copy_from_slice(&value.to_le_bytes());
+    field[2..4].copy_from_slice(&value.to_be_bytes());
+}
+
+fn put_both32(field: &mut [u8], value: u32) {
+    field[0..4].copy_from_slice(&value.to_le_bytes());
+    field[4..8].copy_from_slice(&value.to_be_bytes());
+}
+
+fn sectors(len: u64) -> u32 {
+    len.div_ceil(SECTOR as u64) as u32
+}
+
+fn pad(len: usize) -> usize {
+    len.div_ceil(SECTOR) * SECTOR
+}
+
+/// 7 byte recording date of directory records, in UTC
+fn record_time(time: SystemTime) -> [u8; 7] {
+    let (year, month, day, hour, minute, second) = civil_from_unix(unix_secs(time));
+    [
+        (year - 1900).clamp(0, 255) as u8,
+        month as u8,
+        day as u8,
+        hour as u8,
+        minute as u8,
+        second as u8,
+        0,
+    ]
+}
+
+/// 17 byte date of volume descriptors, in UTC
+fn volume_time(time: SystemTime) -> [u8; 17] {
+    let (year, month, day, hour, minute, second) = civil_from_unix(unix_secs(time));
+    let text = format!(
+        "{:04}{:02}{:02}{:02}{:02}{:02}00",
+        year, month, day, hour, minute, second
+    );
+    let mut date = [0u8; 17];
+    date[..16].copy_from_slice(text.as_bytes());
+    date
+}
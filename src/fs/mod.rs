@@ -0,0 +1,174 @@
+//! file system image module
+//!
+//! Writers for the file system images that are attached to guests next to
+//! their boot disk.
+
+pub mod ext4;
+pub mod fat;
+pub mod iso9660;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// entry of the in-memory tree an image is built from
+pub(crate) enum Node {
+    File(Vec<u8>),
+    Dir(BTreeMap<String, Node>),
+}
+
+/// in-memory directory tree shared by the image builders
+///
+/// Invalid paths are remembered instead of failing right away, so that the
+/// builders keep their chained style and report the error when writing.
+pub(crate) struct FileTree {
+    pub(crate) root: BTreeMap<String, Node>,
+    max_name_len: usize,
+    forbidden: &'static str,
+    invalid: Option<String>,
+}
+
+impl FileTree {
+    /// `max_name_len` is counted in UTF-16 units; `forbidden` lists characters
+    /// names may not contain besides `/` and NUL
+    pub(crate) fn new(max_name_len: usize, forbidden: &'static str) -> FileTree {
+        FileTree {
+            root: BTreeMap::new(),
+            max_name_len,
+            forbidden,
+            invalid: None,
+        }
+    }
+
+    /// adds a directory; `path` uses `/` as separator
+    pub(crate) fn directory(&mut self, path: &str) {
+        if self.insert(path, None).is_err() {
+            self.invalid.get_or_insert_with(|| path.to_string());
+        }
+    }
+
+    /// adds a file, creating its parent directories; `path` uses `/` as separator
+    pub(crate) fn file(&mut self, path: &str, data: Vec<u8>) {
+        if self.insert(path, Some(data)).is_err() {
+            self.invalid.get_or_insert_with(|| path.to_string());
+        }
+    }
+
+    /// copies the regular files and directories below `source` into `dest`
+    pub(crate) fn host_directory(&mut self, dest: &str, source: &Path) -> io::Result<()> {
+        self.directory(dest);
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            let name = entry.file_name().into_string().map_err(|name| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("file name {:?} is not valid UTF-8", name),
+                )
+            })?;
+            let path = format!("{}/{}", dest.trim_end_matches('/'), name);
+            let file_type = fs::metadata(entry.path())?.file_type();
+            if file_type.is_dir() {
+                self.host_directory(&path, &entry.path())?;
+            } else if file_type.is_file() {
+                self.file(&path, fs::read(entry.path())?);
+            }
+        }
+        Ok(())
+    }
+
+    /// fails with the first path that could not be added
+    pub(crate) fn check(&self) -> io::Result<()> {
+        match &self.invalid {
+            Some(path) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid path {:?}", path),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn insert(&mut self, path: &str, data: Option<Vec<u8>>) -> Result<(), ()> {
+        let components: Vec<&str> = path.split('/').filter(|c| !c.is_empty()).collect();
+        let (name, parents) = match components.split_last() {
+            Some(split) => split,
+            // the root directory always exists
+            None if data.is_none() => return Ok(()),
+            None => return Err(()),
+        };
+        for component in components.iter() {
+            self.check_name(component)?;
+        }
+        let mut dir = &mut self.root;
+        for component in parents {
+            let node = dir
+                .entry(component.to_string())
+                .or_insert_with(|| Node::Dir(BTreeMap::new()));
+            dir = match node {
+                Node::Dir(children) => children,
+                Node::File(_) => return Err(()),
+            };
+        }
+        match data {
+            Some(data) => {
+                if let Some(Node::Dir(_)) = dir.get(*name) {
+                    return Err(());
+                }
+                dir.insert(name.to_string(), Node::File(data));
+            }
+            None => match dir
+                .entry(name.to_string())
+                .or_insert_with(|| Node::Dir(BTreeMap::new()))
+            {
+                Node::Dir(_) => {}
+                Node::File(_) => return Err(()),
+            },
+        }
+        Ok(())
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), ()> {
+        if name == "."
+            || name == ".."
+            || name.contains(|c: char| c == '\0' || self.forbidden.contains(c))
+            || name.encode_utf16().count() > self.max_name_len
+        {
+            Err(())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// splits seconds since the unix epoch into UTC year, month, day, hour, minute and second
+pub(crate) fn civil_from_unix(secs: i64) -> (i64, u32, u32, u32, u32, u32) {
+    let days = secs.div_euclid(86400);
+    let rem = secs.rem_euclid(86400);
+    // days to civil date, from Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        (rem / 3600) as u32,
+        (rem % 3600 / 60) as u32,
+        (rem % 60) as u32,
+    )
+}
+
+/// seconds since the unix epoch, zero for earlier times
+pub(crate) fn unix_secs(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
@@ -6,9 +6,23 @@
 //! See the [simplevm](https://github.com/suzusuzu/virtualization-rs/blob/main/examples/simplevm.rs) for more details.
 //!
 //! The example is inspired from [SimpleVM](https://github.com/KhaosT/SimpleVM).
+//!
+//! The framework bindings are only available on macOS. The disk image tooling
+//! does not depend on the framework and builds on every unix platform.
 
+#[cfg(target_os = "macos")]
 extern crate block;
+#[cfg(target_os = "macos")]
 extern crate objc;
 
+#[cfg(target_os = "macos")]
 pub mod base;
+pub mod cloud_init;
+pub mod definition;
+pub mod disk;
+pub mod fs;
+pub mod nbd;
+pub mod net;
+mod util;
+#[cfg(target_os = "macos")]
 pub mod virtualization;
@@ -0,0 +1,149 @@
+//! block backend module
+
+use crate::disk::sparse::punch_hole;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+
+const ZERO_CHUNK: usize = 1 << 20;
+
+/// common behaviors for the storage behind an NBD export
+///
+/// Methods take `&self` so that one backend can serve several connections;
+/// implementations provide their own synchronization.
+pub trait BlockBackend: Send + Sync {
+    /// size of the device in bytes
+    fn size(&self) -> u64;
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// makes every completed write durable
+    fn flush(&self) -> io::Result<()>;
+
+    /// true when the backend refuses writes
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// discards a range; the contents of the range are unspecified afterwards
+    fn trim(&self, _offset: u64, _len: u64) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// zeroes a range; `may_trim` allows deallocating it instead of writing zeros
+    fn write_zeroes(&self, offset: u64, len: u64, _may_trim: bool) -> io::Result<()> {
+        fill_zeroes(self, offset, len)
+    }
+}
+
+/// zeroes a range of `backend` by writing zeros
+pub fn fill_zeroes<B: BlockBackend + ?Sized>(backend: &B, offset: u64, len: u64) -> io::Result<()> {
+    let zeros = vec![0u8; ZERO_CHUNK.min(len as usize)];
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(zeros.len() as u64) as usize;
+        backend.write_at(&zeros[..n], offset + done)?;
+        done += n as u64;
+    }
+    Ok(())
+}
+
+/// backend that serves a raw disk image file
+pub struct FileBackend {
+    file: File,
+    size: u64,
+    read_only: bool,
+}
+
+impl FileBackend {
+    pub fn open<P: AsRef<Path>>(path: P, read_only: bool) -> io::Result<FileBackend> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        FileBackend::new(file, read_only)
+    }
+
+    pub fn new(file: File, read_only: bool) -> io::Result<FileBackend> {
+        let size = file.metadata()?.len();
+        Ok(FileBackend {
+            file,
+            size,
+            read_only,
+        })
+    }
+}
+
+impl BlockBackend for FileBackend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.file.read_exact_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.file.write_all_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        // trimming is advisory, so file systems without hole punching are fine
+        match punch_hole(&self.file, offset, len) {
+            Err(err)
+                if err.raw_os_error() == Some(libc::EOPNOTSUPP)
+                    || err.raw_os_error() == Some(libc::EINVAL) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+        if may_trim && punch_hole(&self.file, offset, len).is_ok() {
+            return Ok(());
+        }
+        fill_zeroes(self, offset, len)
+    }
+}
+
+impl<B: BlockBackend + ?Sized> BlockBackend for Arc<B> {
+    fn size(&self) -> u64 {
+        (**self).size()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        (**self).read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        (**self).write_at(buf, offset)
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn read_only(&self) -> bool {
+        (**self).read_only()
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        (**self).trim(offset, len)
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+        (**self).write_zeroes(offset, len, may_trim)
+    }
+}
@@ -0,0 +1,676 @@
+//! encrypted backend module
+
+use crate::disk::lock::{DiskImageLock, DiskImageLockError};
+use crate::disk::sparse::{data_ranges, is_zero, punch_hole};
+use crate::nbd::backend::{fill_zeroes, BlockBackend};
+use crate::util::replace_with;
+
+use aes::cipher::generic_array::GenericArray;
+use aes::cipher::{BlockDecrypt, BlockEncrypt, KeyInit};
+use aes::Aes256;
+use argon2::{Algorithm, Argon2, Params, Version};
+use sha2::{Digest, Sha256, Sha512};
+use zeroize::Zeroizing;
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::RwLock;
+
+const MAGIC: &[u8; 8] = b"VZXTSIMG";
+const VERSION: u32 = 1;
+/// bytes before the first sector; the header is padded to this size
+const HEADER_LEN: u64 = 4096;
+/// bytes of the header covered by its checksum
+const HEADER_BODY_LEN: usize = 104;
+const SECTOR_SIZE: u64 = 4096;
+const CHUNK_SECTORS: u64 = 256;
+const KEY_CHECK_CONTEXT: &[u8] = b"virtualization-rs xts key check";
+const MIN_KEY_FILE_LEN: usize = 32;
+
+/// most Argon2id memory, passes and lanes an image may ask for, so a
+/// forged header cannot make opening it exhaust the host
+const MAX_ARGON2_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 64;
+const MAX_ARGON2_PARALLELISM: u32 = 64;
+
+const KDF_ARGON2ID: u32 = 1;
+const KDF_KEY_FILE: u32 = 2;
+const KDF_RAW: u32 = 3;
+
+/// cost of deriving a key from a passphrase with Argon2id
+///
+/// At most 4 GiB of memory, 64 passes and 64 lanes are accepted, both when
+/// an image is created and when its header is read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Argon2Params {
+    /// memory in KiB
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Argon2Params {
+    fn check(&self) -> io::Result<()> {
+        if self.memory_kib > MAX_ARGON2_MEMORY_KIB
+            || self.iterations > MAX_ARGON2_ITERATIONS
+            || self.parallelism > MAX_ARGON2_PARALLELISM
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "argon2 parameters exceed the supported maximum",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Argon2Params {
+    /// 64 MiB, 3 passes and 4 lanes
+    fn default() -> Self {
+        Argon2Params {
+            memory_kib: 64 * 1024,
+            iterations: 3,
+            parallelism: 4,
+        }
+    }
+}
+
+enum Secret {
+    Passphrase(Zeroizing<Vec<u8>>),
+    KeyFile(Zeroizing<Vec<u8>>),
+    Raw(Zeroizing<[u8; 64]>),
+}
+
+/// secret that unlocks an `EncryptedBackend`
+///
+/// The secret is wiped from memory when the key is dropped.
+pub struct CryptKey {
+    secret: Secret,
+    params: Argon2Params,
+}
+
+impl CryptKey {
+    /// key derived from `passphrase` with Argon2id
+    pub fn passphrase<T: Into<String>>(passphrase: T) -> CryptKey {
+        CryptKey {
+            secret: Secret::Passphrase(Zeroizing::new(passphrase.into().into_bytes())),
+            params: Argon2Params::default(),
+        }
+    }
+
+    /// key derived from the contents of a key file of at least 32 bytes
+    pub fn key_file<P: AsRef<Path>>(path: P) -> io::Result<CryptKey> {
+        let mut contents = Zeroizing::new(Vec::new());
+        File::open(path)?.read_to_end(&mut contents)?;
+        if contents.len() < MIN_KEY_FILE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("key file is shorter than {} bytes", MIN_KEY_FILE_LEN),
+            ));
+        }
+        Ok(CryptKey {
+            secret: Secret::KeyFile(contents),
+            params: Argon2Params::default(),
+        })
+    }
+
+    /// AES-256-XTS key used as is: the data key followed by the tweak key
+    pub fn raw(key: [u8; 64]) -> CryptKey {
+        CryptKey {
+            secret: Secret::Raw(Zeroizing::new(key)),
+            params: Argon2Params::default(),
+        }
+    }
+
+    /// Argon2id cost for images created with this passphrase
+    ///
+    /// Existing images keep the cost they were created with.
+    pub fn argon2_params(mut self, params: Argon2Params) -> Self {
+        self.params = params;
+        self
+    }
+
+    fn kdf(&self) -> u32 {
+        match self.secret {
+            Secret::Passphrase(_) => KDF_ARGON2ID,
+            Secret::KeyFile(_) => KDF_KEY_FILE,
+            Secret::Raw(_) => KDF_RAW,
+        }
+    }
+
+    /// derives the XTS key for an image with `header`
+    fn derive(&self, header: &Header) -> io::Result<Zeroizing<[u8; 64]>> {
+        if self.kdf() != header.kdf {
+            return Err(wrong_key());
+        }
+        let mut key = Zeroizing::new([0u8; 64]);
+        match &self.secret {
+            Secret::Passphrase(passphrase) => {
+                let params = Params::new(
+                    header.params.memory_kib,
+                    header.params.iterations,
+                    header.params.parallelism,
+                    Some(key.len()),
+                )
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+                Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+                    .hash_password_into(passphrase, &header.salt, &mut key[..])
+                    .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+            }
+            Secret::KeyFile(contents) => {
+                let mut hasher = Sha512::new();
+                hasher.update(header.salt);
+                hasher.update(&contents[..]);
+                key.copy_from_slice(&hasher.finalize());
+            }
+            Secret::Raw(raw) => key.copy_from_slice(&raw[..]),
+        }
+        Ok(key)
+    }
+}
+
+impl fmt::Debug for CryptKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.secret {
+            Secret::Passphrase(_) => "Passphrase",
+            Secret::KeyFile(_) => "KeyFile",
+            Secret::Raw(_) => "Raw",
+        };
+        f.debug_struct("CryptKey")
+            .field("kind", &kind)
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+/// header of an encrypted image
+struct Header {
+    size: u64,
+    kdf: u32,
+    params: Argon2Params,
+    salt: [u8; 32],
+    check: [u8; 32],
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut raw = Vec::with_capacity(HEADER_LEN as usize);
+        raw.extend_from_slice(MAGIC);
+        raw.extend_from_slice(&VERSION.to_le_bytes());
+        raw.extend_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+        raw.extend_from_slice(&self.size.to_le_bytes());
+        raw.extend_from_slice(&self.kdf.to_le_bytes());
+        raw.extend_from_slice(&self.params.memory_kib.to_le_bytes());
+        raw.extend_from_slice(&self.params.iterations.to_le_bytes());
+        raw.extend_from_slice(&self.params.parallelism.to_le_bytes());
+        raw.extend_from_slice(&self.salt);
+        raw.extend_from_slice(&self.check);
+        let crc = crc32fast::hash(&raw);
+        raw.extend_from_slice(&crc.to_le_bytes());
+        raw.resize(HEADER_LEN as usize, 0);
+        raw
+    }
+
+    fn decode(raw: &[u8]) -> io::Result<Header> {
+        let u32_at = |at: usize| u32::from_le_bytes(raw[at..at + 4].try_into().unwrap());
+        if &raw[0..8] != MAGIC {
+            return Err(invalid_data("not an encrypted image"));
+        }
+        if crc32fast::hash(&raw[..HEADER_BODY_LEN]) != u32_at(HEADER_BODY_LEN) {
+            return Err(invalid_data("encrypted image header checksum mismatch"));
+        }
+        if u32_at(8) != VERSION || u32_at(12) as u64 != SECTOR_SIZE {
+            return Err(invalid_data("unsupported encrypted image version"));
+        }
+        let params = Argon2Params {
+            memory_kib: u32_at(28),
+            iterations: u32_at(32),
+            parallelism: u32_at(36),
+        };
+        if params.check().is_err() {
+            return Err(invalid_data(
+                "encrypted image asks for a too costly key derivation",
+            ));
+        }
+        Ok(Header {
+            size: u64::from_le_bytes(raw[16..24].try_into().unwrap()),
+            kdf: u32_at(24),
+            params,
+            salt: raw[40..72].try_into().unwrap(),
+            check: raw[72..104].try_into().unwrap(),
+        })
+    }
+}
+
+/// AES-256-XTS with one data unit per sector, as in IEEE 1619
+struct Xts {
+    data: Aes256,
+    tweak: Aes256,
+}
+
+impl Xts {
+    fn new(key: &[u8; 64]) -> Xts {
+        Xts {
+            data: Aes256::new(GenericArray::from_slice(&key[..32])),
+            tweak: Aes256::new(GenericArray::from_slice(&key[32..])),
+        }
+    }
+
+    fn initial_tweak(&self, sector: u64) -> u128 {
+        let mut block = GenericArray::from([0u8; 16]);
+        block[..8].copy_from_slice(&sector.to_le_bytes());
+        self.tweak.encrypt_block(&mut block);
+        u128::from_le_bytes(block.into())
+    }
+
+    fn encrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+        let mut tweak = self.initial_tweak(sector);
+        for block in buf.chunks_exact_mut(16) {
+            xor_block(block, tweak);
+            self.data.encrypt_block(GenericArray::from_mut_slice(block));
+            xor_block(block, tweak);
+            tweak = next_tweak(tweak);
+        }
+    }
+
+    fn decrypt_sector(&self, sector: u64, buf: &mut [u8]) {
+        let mut tweak = self.initial_tweak(sector);
+        for block in buf.chunks_exact_mut(16) {
+            xor_block(block, tweak);
+            self.data.decrypt_block(GenericArray::from_mut_slice(block));
+            xor_block(block, tweak);
+            tweak = next_tweak(tweak);
+        }
+    }
+
+    /// encrypts whole sectors starting at `first`; sectors of zeros are
+    /// stored as zeros
+    fn encrypt_sectors(&self, first: u64, buf: &mut [u8]) {
+        for (i, sector) in buf.chunks_exact_mut(SECTOR_SIZE as usize).enumerate() {
+            if !is_zero(sector) {
+                self.encrypt_sector(first + i as u64, sector);
+            }
+        }
+    }
+
+    /// decrypts whole sectors starting at `first`; sectors of zeros, e.g.
+    /// holes, read as zeros
+    fn decrypt_sectors(&self, first: u64, buf: &mut [u8]) {
+        for (i, sector) in buf.chunks_exact_mut(SECTOR_SIZE as usize).enumerate() {
+            if !is_zero(sector) {
+                self.decrypt_sector(first + i as u64, sector);
+            }
+        }
+    }
+}
+
+fn xor_block(block: &mut [u8], tweak: u128) {
+    for (b, t) in block.iter_mut().zip(tweak.to_le_bytes().iter()) {
+        *b ^= t;
+    }
+}
+
+/// multiplies the tweak by x in GF(2^128)
+fn next_tweak(tweak: u128) -> u128 {
+    let carry = (tweak >> 127) as u8;
+    (tweak << 1) ^ (0x87 * carry as u128)
+}
+
+/// backend that keeps a disk encrypted at rest with AES-256-XTS
+///
+/// The image starts with a 4 KiB header holding the size, the salt and
+/// parameters of the key derivation and a key check value; the sectors
+/// follow, 4 KiB each, every one encrypted with its number as the tweak.
+/// The guest sees the plain disk, while only ciphertext reaches the host's
+/// storage.
+///
+/// A sector stored as zeros reads as zeros, so unwritten, zeroed and
+/// trimmed sectors stay holes in the image. This reveals which sectors
+/// hold data, as discards do on a dm-crypt volume. XTS does not
+/// authenticate the data either: a modified sector decrypts to garbage
+/// rather than failing.
+///
+/// The image is locked like an attached disk image: exclusively while it is
+/// open for writing and shared while it is open read-only, so opening an
+/// image that is written elsewhere fails with `io::ErrorKind::ResourceBusy`.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::nbd::{crypt::*, server::*};
+/// # fn main() -> std::io::Result<()> {
+/// let key = CryptKey::passphrase("correct horse battery staple");
+/// let disk = EncryptedBackend::create("dev.img", 32 << 30, &key)?;
+/// let server = NbdServerBuilder::new()
+///     .export(NbdExport::new("dev", disk))
+///     .build();
+/// # Ok(())
+/// # }
+/// ```
+pub struct EncryptedBackend {
+    lock: DiskImageLock,
+    xts: Xts,
+    size: u64,
+    read_only: bool,
+    /// held shared by reads and exclusively by writes, so a read never sees
+    /// a sector in the middle of its read-modify-write
+    io_lock: RwLock<()>,
+}
+
+impl EncryptedBackend {
+    /// creates an empty, sparse encrypted image of `size` bytes
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        size: u64,
+        key: &CryptKey,
+    ) -> io::Result<EncryptedBackend> {
+        key.params.check()?;
+        let mut salt = [0u8; 32];
+        File::open("/dev/urandom")?.read_exact(&mut salt)?;
+        let mut header = Header {
+            size,
+            kdf: key.kdf(),
+            params: key.params,
+            salt,
+            check: [0; 32],
+        };
+        let derived = key.derive(&header)?;
+        header.check = key_check(&derived);
+
+        let path = path.as_ref();
+        OpenOptions::new().write(true).create_new(true).open(path)?;
+        let lock = DiskImageLock::exclusive(path).map_err(lock_error)?;
+        let file = lock.file();
+        file.set_len(HEADER_LEN + sectors(size) * SECTOR_SIZE)?;
+        file.write_all_at(&header.encode(), 0)?;
+        file.sync_all()?;
+        Ok(EncryptedBackend {
+            lock,
+            xts: Xts::new(&derived),
+            size,
+            read_only: false,
+            io_lock: RwLock::new(()),
+        })
+    }
+
+    /// opens an encrypted image; a key that does not fit fails with
+    /// `io::ErrorKind::PermissionDenied`
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        key: &CryptKey,
+        read_only: bool,
+    ) -> io::Result<EncryptedBackend> {
+        let lock = if read_only {
+            DiskImageLock::shared(path)
+        } else {
+            DiskImageLock::exclusive(path)
+        };
+        EncryptedBackend::unlock(lock.map_err(lock_error)?, key, read_only)
+    }
+
+    /// reads the header of the locked image and checks `key` against it
+    fn unlock(
+        lock: DiskImageLock,
+        key: &CryptKey,
+        read_only: bool,
+    ) -> io::Result<EncryptedBackend> {
+        let file = lock.file();
+        let mut raw = vec![0u8; HEADER_LEN as usize];
+        file.read_exact_at(&mut raw, 0)
+            .map_err(|_| invalid_data("not an encrypted image"))?;
+        let header = Header::decode(&raw)?;
+        if file.metadata()?.len() != HEADER_LEN + sectors(header.size) * SECTOR_SIZE {
+            return Err(invalid_data("encrypted image is truncated"));
+        }
+        let derived = key.derive(&header)?;
+        if !constant_time_eq(&key_check(&derived), &header.check) {
+            return Err(wrong_key());
+        }
+        Ok(EncryptedBackend {
+            lock,
+            xts: Xts::new(&derived),
+            size: header.size,
+            read_only,
+            io_lock: RwLock::new(()),
+        })
+    }
+
+    /// re-encrypts the image at `path` from `old` to `new`
+    ///
+    /// The new image is written next to the old one and renamed over it, so
+    /// an interrupted rotation leaves the image under the old key. Holes
+    /// are kept, so this needs as much free space as the data in the image.
+    /// The image is locked exclusively while it is rotated, so this fails
+    /// with `io::ErrorKind::ResourceBusy` when it is open elsewhere.
+    pub fn rekey<P: AsRef<Path>>(path: P, old: &CryptKey, new: &CryptKey) -> io::Result<()> {
+        let path = path.as_ref();
+        let lock = DiskImageLock::exclusive(path).map_err(lock_error)?;
+        let source = EncryptedBackend::unlock(lock, old, true)?;
+        replace_with(path, |tmp| {
+            let target = EncryptedBackend::create(tmp, source.size, new)?;
+            source.copy_sectors_to(&target)?;
+            fs::set_permissions(tmp, source.file().metadata()?.permissions())
+        })
+    }
+
+    pub fn sector_size(&self) -> u64 {
+        SECTOR_SIZE
+    }
+
+    fn file(&self) -> &File {
+        self.lock.file()
+    }
+
+    /// re-encrypts the data sectors into `target`, which has the same size
+    fn copy_sectors_to(&self, target: &EncryptedBackend) -> io::Result<()> {
+        let end = HEADER_LEN + sectors(self.size) * SECTOR_SIZE;
+        let mut buf = vec![0u8; (CHUNK_SECTORS * SECTOR_SIZE) as usize];
+        for range in data_ranges(self.file(), HEADER_LEN, end)? {
+            let mut sector = (range.start - HEADER_LEN) / SECTOR_SIZE;
+            let last = (range.end - HEADER_LEN).div_ceil(SECTOR_SIZE);
+            while sector < last {
+                let n = (last - sector).min(CHUNK_SECTORS);
+                let chunk = &mut buf[..(n * SECTOR_SIZE) as usize];
+                let pos = HEADER_LEN + sector * SECTOR_SIZE;
+                self.file().read_exact_at(chunk, pos)?;
+                if !is_zero(chunk) {
+                    self.xts.decrypt_sectors(sector, chunk);
+                    target.xts.encrypt_sectors(sector, chunk);
+                    target.file().write_all_at(chunk, pos)?;
+                }
+                sector += n;
+            }
+        }
+        Ok(())
+    }
+
+    /// reads and decrypts one whole sector into `buf`
+    fn read_sector(&self, sector: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.file()
+            .read_exact_at(buf, HEADER_LEN + sector * SECTOR_SIZE)?;
+        self.xts.decrypt_sectors(sector, buf);
+        Ok(())
+    }
+
+    /// writes zeros over the whole sectors `first..last`
+    fn zero_sectors(&self, first: u64, last: u64, may_trim: bool) -> io::Result<()> {
+        let pos = HEADER_LEN + first * SECTOR_SIZE;
+        let len = (last - first) * SECTOR_SIZE;
+        if may_trim && punch_hole(self.file(), pos, len).is_ok() {
+            return Ok(());
+        }
+        // sectors of zeros read as zeros, so they are not encrypted
+        let zeros = vec![0u8; (CHUNK_SECTORS * SECTOR_SIZE).min(len) as usize];
+        let mut done = 0;
+        while done < len {
+            let n = (len - done).min(zeros.len() as u64) as usize;
+            self.file().write_all_at(&zeros[..n], pos + done)?;
+            done += n as u64;
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, offset: u64, len: u64) -> io::Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            Err(io::Error::from_raw_os_error(libc::EPERM))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BlockBackend for EncryptedBackend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check_range(offset, buf.len() as u64)?;
+        let max = (CHUNK_SECTORS * SECTOR_SIZE) as usize;
+        let mut chunk = vec![0u8; max.min(buf.len() + 2 * SECTOR_SIZE as usize)];
+        let _guard = self.io_lock.read().unwrap();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let sector = pos / SECTOR_SIZE;
+            let skip = (pos % SECTOR_SIZE) as usize;
+            let n = (buf.len() - done).min(chunk.len() - skip);
+            let sectors_len = (skip + n).div_ceil(SECTOR_SIZE as usize) * SECTOR_SIZE as usize;
+            let sectors = &mut chunk[..sectors_len];
+            self.file()
+                .read_exact_at(sectors, HEADER_LEN + sector * SECTOR_SIZE)?;
+            self.xts.decrypt_sectors(sector, sectors);
+            buf[done..done + n].copy_from_slice(&sectors[skip..skip + n]);
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check_writable()?;
+        self.check_range(offset, buf.len() as u64)?;
+        let sector_size = SECTOR_SIZE as usize;
+        let max = (CHUNK_SECTORS * SECTOR_SIZE) as usize;
+        let mut chunk = vec![0u8; max.min(buf.len() + 2 * sector_size)];
+        let _guard = self.io_lock.write().unwrap();
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let sector = pos / SECTOR_SIZE;
+            let skip = (pos % SECTOR_SIZE) as usize;
+            let n = (buf.len() - done).min(chunk.len() - skip);
+            let sectors_len = (skip + n).div_ceil(sector_size) * sector_size;
+            let sectors = &mut chunk[..sectors_len];
+            // partially written sectors are read, modified and written back
+            if skip != 0 {
+                self.read_sector(sector, &mut sectors[..sector_size])?;
+            }
+            let tail = sectors_len - sector_size;
+            if !(skip + n).is_multiple_of(sector_size) && (tail != 0 || skip == 0) {
+                let last = sector + (tail / sector_size) as u64;
+                self.read_sector(last, &mut sectors[tail..])?;
+            }
+            sectors[skip..skip + n].copy_from_slice(&buf[done..done + n]);
+            self.xts.encrypt_sectors(sector, sectors);
+            self.file()
+                .write_all_at(sectors, HEADER_LEN + sector * SECTOR_SIZE)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.file().sync_data()
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.check_writable()?;
+        self.check_range(offset, len)?;
+        let first = offset.div_ceil(SECTOR_SIZE);
+        let last = (offset + len) / SECTOR_SIZE;
+        if first >= last {
+            return Ok(());
+        }
+        let _guard = self.io_lock.write().unwrap();
+        // trimming is advisory, so file systems without hole punching are fine
+        match punch_hole(
+            self.file(),
+            HEADER_LEN + first * SECTOR_SIZE,
+            (last - first) * SECTOR_SIZE,
+        ) {
+            Err(err)
+                if err.raw_os_error() == Some(libc::EOPNOTSUPP)
+                    || err.raw_os_error() == Some(libc::EINVAL) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+        self.check_writable()?;
+        self.check_range(offset, len)?;
+        let end = offset + len;
+        let first = offset.div_ceil(SECTOR_SIZE);
+        let last = end / SECTOR_SIZE;
+        if first >= last {
+            return fill_zeroes(self, offset, len);
+        }
+        fill_zeroes(self, offset, first * SECTOR_SIZE - offset)?;
+        {
+            let _guard = self.io_lock.write().unwrap();
+            self.zero_sectors(first, last, may_trim)?;
+        }
+        fill_zeroes(self, last * SECTOR_SIZE, end - last * SECTOR_SIZE)
+    }
+}
+
+fn sectors(size: u64) -> u64 {
+    size.div_ceil(SECTOR_SIZE)
+}
+
+fn key_check(key: &[u8; 64]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_CHECK_CONTEXT);
+    hasher.update(key);
+    hasher.finalize().into()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn lock_error(err: DiskImageLockError) -> io::Error {
+    match err {
+        DiskImageLockError::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::ResourceBusy, err),
+    }
+}
+
+fn wrong_key() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "wrong key for encrypted image",
+    )
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
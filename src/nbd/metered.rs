@@ -0,0 +1,438 @@
+//! metered backend module
+
+use crate::nbd::backend::BlockBackend;
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// number of latency buckets; the last one also counts everything slower
+pub const LATENCY_BUCKETS: usize = 28;
+
+/// limits of a `MeteredBackend`
+///
+/// `None` or a rate of 0 leaves a dimension unlimited. Both limits are
+/// token buckets that fill at the given rate and hold one second worth of
+/// tokens, so a disk that was idle may burst up to a second of I/O at full
+/// speed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoLimits {
+    /// operations per second, counting reads, writes, trims and zeroing
+    pub iops: Option<u64>,
+    /// bytes read or written per second
+    pub bandwidth: Option<u64>,
+}
+
+impl IoLimits {
+    pub fn unlimited() -> IoLimits {
+        IoLimits::default()
+    }
+
+    /// the same limits with rates of 0 spelled as `None`
+    fn normalized(self) -> IoLimits {
+        IoLimits {
+            iops: self.iops.filter(|&rate| rate > 0),
+            bandwidth: self.bandwidth.filter(|&rate| rate > 0),
+        }
+    }
+}
+
+/// histogram of operation latencies in power of two microsecond buckets
+///
+/// Bucket 0 counts operations that took less than 1µs, bucket `i` those
+/// that took from 2^(i-1) up to 2^i µs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    counts: [u64; LATENCY_BUCKETS],
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            counts: [0; LATENCY_BUCKETS],
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// number of operations in each bucket
+    pub fn counts(&self) -> &[u64; LATENCY_BUCKETS] {
+        &self.counts
+    }
+
+    /// exclusive upper bound of bucket `index`
+    pub fn upper_bound(index: usize) -> Duration {
+        Duration::from_micros(1 << index)
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// upper bound of the bucket holding the `q` quantile, e.g. 0.99;
+    /// `None` without operations
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let rank = ((q.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (index, n) in self.counts.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                return Some(LatencyHistogram::upper_bound(index));
+            }
+        }
+        Some(LatencyHistogram::upper_bound(LATENCY_BUCKETS - 1))
+    }
+
+    fn bucket(latency: Duration) -> usize {
+        let micros = latency.as_micros();
+        let bits = (128 - micros.leading_zeros()) as usize;
+        bits.min(LATENCY_BUCKETS - 1)
+    }
+}
+
+/// counters of one kind of operation
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct OpStats {
+    pub ops: u64,
+    pub bytes: u64,
+    /// operations that failed; they are counted in `ops` as well
+    pub errors: u64,
+    /// time spent in the backend, without time spent throttled
+    pub total_time: Duration,
+    pub latency: LatencyHistogram,
+}
+
+impl OpStats {
+    /// average time spent in the backend
+    pub fn mean_latency(&self) -> Option<Duration> {
+        if self.ops == 0 {
+            None
+        } else {
+            Some(Duration::from_nanos(
+                (self.total_time.as_nanos() / self.ops as u128) as u64,
+            ))
+        }
+    }
+}
+
+/// statistics of a `MeteredBackend` since it was created or reset
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IoStats {
+    pub read: OpStats,
+    pub write: OpStats,
+    pub flush: OpStats,
+    /// trims and zeroing; `bytes` is the size of the ranges
+    pub discard: OpStats,
+    /// operations that had to wait for the limits
+    pub throttled_ops: u64,
+    /// total time operations waited for the limits
+    pub throttled_time: Duration,
+}
+
+#[derive(Default)]
+struct OpCounters {
+    ops: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    nanos: AtomicU64,
+    latency: [AtomicU64; LATENCY_BUCKETS],
+}
+
+impl OpCounters {
+    fn record(&self, bytes: u64, latency: Duration, ok: bool) {
+        self.ops.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if !ok {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+        self.nanos
+            .fetch_add(latency.as_nanos() as u64, Ordering::Relaxed);
+        self.latency[LatencyHistogram::bucket(latency)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> OpStats {
+        let mut latency = LatencyHistogram::default();
+        for (count, counter) in latency.counts.iter_mut().zip(&self.latency) {
+            *count = counter.load(Ordering::Relaxed);
+        }
+        OpStats {
+            ops: self.ops.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            total_time: Duration::from_nanos(self.nanos.load(Ordering::Relaxed)),
+            latency,
+        }
+    }
+
+    fn reset(&self) {
+        self.ops.store(0, Ordering::Relaxed);
+        self.bytes.store(0, Ordering::Relaxed);
+        self.errors.store(0, Ordering::Relaxed);
+        self.nanos.store(0, Ordering::Relaxed);
+        for counter in &self.latency {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+/// token bucket that may go into debt, so that a request larger than the
+/// bucket still passes after waiting long enough
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+}
+
+impl Bucket {
+    /// full bucket filling at `rate`, which is not 0
+    fn new(rate: u64) -> Bucket {
+        Bucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+        }
+    }
+
+    /// bucket filling at `rate` that keeps the tokens of `old`, up to its
+    /// new capacity, so lowering a limit does not grant a new burst
+    fn resized(old: Option<Bucket>, rate: u64) -> Bucket {
+        let mut bucket = Bucket::new(rate);
+        if let Some(old) = old {
+            bucket.tokens = old.tokens.min(bucket.tokens);
+        }
+        bucket
+    }
+
+    /// takes `cost` tokens and returns how long the caller has to wait
+    fn take(&mut self, elapsed: Duration, cost: u64) -> Duration {
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.rate);
+        self.tokens -= cost as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+struct Throttle {
+    limits: IoLimits,
+    iops: Option<Bucket>,
+    bandwidth: Option<Bucket>,
+    refilled: Instant,
+}
+
+impl Throttle {
+    fn new(limits: IoLimits) -> Throttle {
+        let limits = limits.normalized();
+        Throttle {
+            limits,
+            iops: limits.iops.map(Bucket::new),
+            bandwidth: limits.bandwidth.map(Bucket::new),
+            refilled: Instant::now(),
+        }
+    }
+
+    fn set_limits(&mut self, limits: IoLimits) {
+        // bring the buckets up to date before they change their rate
+        let now = Instant::now();
+        let elapsed = now - self.refilled;
+        self.refilled = now;
+        for bucket in self.iops.iter_mut().chain(self.bandwidth.iter_mut()) {
+            bucket.take(elapsed, 0);
+        }
+        let limits = limits.normalized();
+        self.limits = limits;
+        let (iops, bandwidth) = (self.iops.take(), self.bandwidth.take());
+        self.iops = limits.iops.map(|rate| Bucket::resized(iops, rate));
+        self.bandwidth = limits
+            .bandwidth
+            .map(|rate| Bucket::resized(bandwidth, rate));
+    }
+
+    fn take(&mut self, bytes: u64) -> Duration {
+        let now = Instant::now();
+        let elapsed = now - self.refilled;
+        self.refilled = now;
+        let iops = self.iops.as_mut().map(|b| b.take(elapsed, 1));
+        let bandwidth = self.bandwidth.as_mut().map(|b| b.take(elapsed, bytes));
+        iops.into_iter().chain(bandwidth).max().unwrap_or_default()
+    }
+}
+
+/// statistics and limits of a `MeteredBackend`, shared with the code that
+/// monitors or controls the disk while it is served
+pub struct IoMeter {
+    read: OpCounters,
+    write: OpCounters,
+    flush: OpCounters,
+    discard: OpCounters,
+    throttled_ops: AtomicU64,
+    throttled_nanos: AtomicU64,
+    throttle: Mutex<Throttle>,
+}
+
+impl IoMeter {
+    fn new(limits: IoLimits) -> IoMeter {
+        IoMeter {
+            read: OpCounters::default(),
+            write: OpCounters::default(),
+            flush: OpCounters::default(),
+            discard: OpCounters::default(),
+            throttled_ops: AtomicU64::new(0),
+            throttled_nanos: AtomicU64::new(0),
+            throttle: Mutex::new(Throttle::new(limits)),
+        }
+    }
+
+    pub fn stats(&self) -> IoStats {
+        IoStats {
+            read: self.read.snapshot(),
+            write: self.write.snapshot(),
+            flush: self.flush.snapshot(),
+            discard: self.discard.snapshot(),
+            throttled_ops: self.throttled_ops.load(Ordering::Relaxed),
+            throttled_time: Duration::from_nanos(self.throttled_nanos.load(Ordering::Relaxed)),
+        }
+    }
+
+    /// sets every counter back to zero
+    pub fn reset(&self) {
+        for counters in &[&self.read, &self.write, &self.flush, &self.discard] {
+            counters.reset();
+        }
+        self.throttled_ops.store(0, Ordering::Relaxed);
+        self.throttled_nanos.store(0, Ordering::Relaxed);
+    }
+
+    pub fn limits(&self) -> IoLimits {
+        self.throttle.lock().unwrap().limits
+    }
+
+    /// replaces the limits; operations already waiting finish their wait
+    ///
+    /// A limited dimension keeps the tokens it has, up to the capacity of
+    /// the new limit, and one that was unlimited starts with a full bucket.
+    pub fn set_limits(&self, limits: IoLimits) {
+        self.throttle.lock().unwrap().set_limits(limits);
+    }
+
+    /// waits until the limits allow an operation of `bytes`
+    fn throttle(&self, bytes: u64) {
+        let wait = self.throttle.lock().unwrap().take(bytes);
+        if wait > Duration::from_secs(0) {
+            self.throttled_ops.fetch_add(1, Ordering::Relaxed);
+            self.throttled_nanos
+                .fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+            thread::sleep(wait);
+        }
+    }
+}
+
+/// backend that counts the operations on another backend and limits their
+/// rate
+///
+/// Wrapping the backend of each export gives per-disk statistics and keeps
+/// one busy guest from taking all the bandwidth of the host's disk. The
+/// `IoMeter` returned by `meter` reads the statistics and changes the limits
+/// while the disk is served.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::nbd::{backend::FileBackend, metered::*, server::*};
+/// # fn main() -> std::io::Result<()> {
+/// let disk = MeteredBackend::new(FileBackend::open("disk.img", false)?).limits(IoLimits {
+///     iops: Some(2000),
+///     bandwidth: Some(100 << 20),
+/// });
+/// let meter = disk.meter();
+/// let server = NbdServerBuilder::new()
+///     .export(NbdExport::new("disk", disk))
+///     .build();
+///
+/// // later, from another thread
+/// meter.set_limits(IoLimits::unlimited());
+/// println!("{} bytes read", meter.stats().read.bytes);
+/// # Ok(())
+/// # }
+/// ```
+pub struct MeteredBackend<B> {
+    inner: B,
+    meter: Arc<IoMeter>,
+}
+
+impl<B: BlockBackend> MeteredBackend<B> {
+    /// wraps `inner` without limits
+    pub fn new(inner: B) -> MeteredBackend<B> {
+        MeteredBackend {
+            inner,
+            meter: Arc::new(IoMeter::new(IoLimits::unlimited())),
+        }
+    }
+
+    pub fn limits(self, limits: IoLimits) -> Self {
+        self.meter.set_limits(limits);
+        self
+    }
+
+    pub fn meter(&self) -> Arc<IoMeter> {
+        self.meter.clone()
+    }
+
+    pub fn into_inner(self) -> B {
+        self.inner
+    }
+
+    fn measure<F>(&self, counters: &OpCounters, bytes: u64, f: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<()>,
+    {
+        let start = Instant::now();
+        let result = f();
+        counters.record(bytes, start.elapsed(), result.is_ok());
+        result
+    }
+}
+
+impl<B: BlockBackend> BlockBackend for MeteredBackend<B> {
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.meter.throttle(buf.len() as u64);
+        self.measure(&self.meter.read, buf.len() as u64, || {
+            self.inner.read_at(buf, offset)
+        })
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.meter.throttle(buf.len() as u64);
+        self.measure(&self.meter.write, buf.len() as u64, || {
+            self.inner.write_at(buf, offset)
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        self.measure(&self.meter.flush, 0, || self.inner.flush())
+    }
+
+    fn read_only(&self) -> bool {
+        self.inner.read_only()
+    }
+
+    fn trim(&self, offset: u64, len: u64) -> io::Result<()> {
+        self.meter.throttle(0);
+        self.measure(&self.meter.discard, len, || self.inner.trim(offset, len))
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+        self.meter.throttle(0);
+        self.measure(&self.meter.discard, len, || {
+            self.inner.write_zeroes(offset, len, may_trim)
+        })
+    }
+}
@@ -0,0 +1,45 @@
+//! network block device module
+//!
+//! A pure Rust NBD server whose exports can be attached to a virtual machine
+//! with `VZNetworkBlockDeviceStorageDeviceAttachment`.
+
+pub mod backend;
+pub mod crypt;
+pub mod metered;
+pub mod overlay;
+pub mod server;
+
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// URL of an export served on a unix domain socket
+/// # Examples
+/// ```rust
+/// # use virtualization_rs::nbd::unix_url;
+/// assert_eq!(unix_url("/tmp/nbd.sock", "disk"), "nbd+unix:///disk?socket=/tmp/nbd.sock");
+/// ```
+pub fn unix_url<P: AsRef<Path>>(socket: P, export: &str) -> String {
+    format!(
+        "nbd+unix:///{}?socket={}",
+        percent_encode(export),
+        percent_encode(&socket.as_ref().to_string_lossy()).replace("%2F", "/")
+    )
+}
+
+/// URL of an export served over TCP
+pub fn tcp_url(addr: SocketAddr, export: &str) -> String {
+    format!("nbd://{}/{}", addr, percent_encode(export))
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(b as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    encoded
+}
@@ -0,0 +1,380 @@
+//! copy-on-write overlay backend module
+
+use crate::disk::sparse::punch_hole;
+use crate::nbd::backend::BlockBackend;
+use crate::util::write_atomic;
+
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// cluster size used by `OverlayBackend::create`
+pub const DEFAULT_CLUSTER_SIZE: u32 = 64 * 1024;
+
+const BITMAP_MAGIC: &[u8; 8] = b"VZOVBMP1";
+const BITMAP_HEADER_LEN: usize = 24;
+
+/// backend that keeps the writes of one virtual machine in a delta file on
+/// top of a read-only base
+///
+/// The delta is a sparse file of the size of the base; a cluster that has
+/// been written is read from the delta, every other cluster from the base.
+/// Which clusters are in the delta is recorded in an allocation bitmap next
+/// to it (`<delta>.bitmap`).
+///
+/// The bitmap is only rewritten on `flush`, after the delta has been synced,
+/// and is replaced atomically. After a crash the overlay therefore reads
+/// every cluster either as of the last flush or with a later write, never
+/// with a cluster that was only half copied up from the base.
+///
+/// The base may itself be an overlay opened read-only, which gives a chain
+/// of bases.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::nbd::{backend::FileBackend, overlay::*, server::*};
+/// # fn main() -> std::io::Result<()> {
+/// let base = FileBackend::open("ubuntu.img", true)?;
+/// let overlay = OverlayBackend::create("vm1.delta", base, DEFAULT_CLUSTER_SIZE)?;
+/// let server = NbdServerBuilder::new()
+///     .export(NbdExport::new("vm1", overlay))
+///     .build();
+/// # Ok(())
+/// # }
+/// ```
+pub struct OverlayBackend {
+    delta: File,
+    bitmap_path: PathBuf,
+    base: Arc<dyn BlockBackend>,
+    size: u64,
+    cluster_size: u64,
+    read_only: bool,
+    bitmap: Mutex<Bitmap>,
+}
+
+struct Bitmap {
+    words: Vec<u64>,
+    dirty: bool,
+}
+
+impl Bitmap {
+    fn get(&self, cluster: u64) -> bool {
+        self.words[(cluster / 64) as usize] & (1 << (cluster % 64)) != 0
+    }
+
+    fn set(&mut self, cluster: u64) {
+        if !self.get(cluster) {
+            self.words[(cluster / 64) as usize] |= 1 << (cluster % 64);
+            self.dirty = true;
+        }
+    }
+
+    fn count(&self) -> u64 {
+        self.words.iter().map(|w| w.count_ones() as u64).sum()
+    }
+}
+
+impl OverlayBackend {
+    /// creates an empty overlay over `base`
+    ///
+    /// `cluster_size` must be a power of two of at least 512 bytes.
+    pub fn create<P: AsRef<Path>, B: BlockBackend + 'static>(
+        delta: P,
+        base: B,
+        cluster_size: u32,
+    ) -> io::Result<OverlayBackend> {
+        if !cluster_size.is_power_of_two() || cluster_size < 512 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid cluster size {}", cluster_size),
+            ));
+        }
+        let delta = delta.as_ref();
+        let size = base.size();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(delta)?;
+        file.set_len(size)?;
+        file.sync_all()?;
+        let overlay = OverlayBackend {
+            delta: file,
+            bitmap_path: OverlayBackend::bitmap_path(delta),
+            base: Arc::new(base),
+            size,
+            cluster_size: cluster_size as u64,
+            read_only: false,
+            bitmap: Mutex::new(Bitmap {
+                words: vec![0; words(size, cluster_size as u64)],
+                dirty: false,
+            }),
+        };
+        overlay.write_bitmap(&overlay.bitmap.lock().unwrap())?;
+        Ok(overlay)
+    }
+
+    /// opens an existing overlay over `base`
+    pub fn open<P: AsRef<Path>, B: BlockBackend + 'static>(
+        delta: P,
+        base: B,
+        read_only: bool,
+    ) -> io::Result<OverlayBackend> {
+        let delta = delta.as_ref();
+        let bitmap_path = OverlayBackend::bitmap_path(delta);
+        let raw = fs::read(&bitmap_path)?;
+        if raw.len() < BITMAP_HEADER_LEN + 4 || &raw[0..8] != BITMAP_MAGIC {
+            return Err(invalid_data("bad overlay bitmap"));
+        }
+        let (body, crc) = raw.split_at(raw.len() - 4);
+        if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+            return Err(invalid_data("overlay bitmap checksum mismatch"));
+        }
+        let cluster_size = u32::from_le_bytes(body[8..12].try_into().unwrap()) as u64;
+        let size = u64::from_le_bytes(body[16..24].try_into().unwrap());
+        if size != base.size() {
+            return Err(invalid_data("overlay size does not match its base"));
+        }
+        if !cluster_size.is_power_of_two() || cluster_size < 512 {
+            return Err(invalid_data("bad overlay cluster size"));
+        }
+        if body.len() != BITMAP_HEADER_LEN + words(size, cluster_size) * 8 {
+            return Err(invalid_data("bad overlay bitmap"));
+        }
+        let words = body[BITMAP_HEADER_LEN..]
+            .chunks(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .open(delta)?;
+        if file.metadata()?.len() != size {
+            return Err(invalid_data("overlay size does not match its base"));
+        }
+        Ok(OverlayBackend {
+            delta: file,
+            bitmap_path,
+            base: Arc::new(base),
+            size,
+            cluster_size,
+            read_only,
+            bitmap: Mutex::new(Bitmap {
+                words,
+                dirty: false,
+            }),
+        })
+    }
+
+    /// path of the allocation bitmap of the delta file `delta`
+    pub fn bitmap_path<P: AsRef<Path>>(delta: P) -> PathBuf {
+        let mut name = delta.as_ref().as_os_str().to_os_string();
+        name.push(".bitmap");
+        PathBuf::from(name)
+    }
+
+    pub fn cluster_size(&self) -> u64 {
+        self.cluster_size
+    }
+
+    /// number of clusters held by the delta
+    pub fn allocated_clusters(&self) -> u64 {
+        self.bitmap.lock().unwrap().count()
+    }
+
+    /// writes every cluster of the delta into the base and empties the overlay
+    ///
+    /// The base must have been opened writable. The bitmap is only cleared
+    /// after the base has been flushed, so an interrupted commit can simply be
+    /// run again.
+    pub fn commit(&self) -> io::Result<()> {
+        if self.read_only || self.base.read_only() {
+            return Err(io::Error::from_raw_os_error(libc::EPERM));
+        }
+        let mut bitmap = self.bitmap.lock().unwrap();
+        self.delta.sync_data()?;
+        if bitmap.dirty {
+            self.write_bitmap(&bitmap)?;
+            bitmap.dirty = false;
+        }
+        let mut buf = vec![0u8; self.cluster_size as usize];
+        for cluster in 0..self.clusters() {
+            if !bitmap.get(cluster) {
+                continue;
+            }
+            let (start, len) = self.cluster_range(cluster);
+            self.delta.read_exact_at(&mut buf[..len], start)?;
+            self.base.write_at(&buf[..len], start)?;
+        }
+        self.base.flush()?;
+
+        for word in bitmap.words.iter_mut() {
+            *word = 0;
+        }
+        bitmap.dirty = true;
+        self.write_bitmap(&bitmap)?;
+        bitmap.dirty = false;
+
+        // drop the stale data; the bitmap no longer points at it
+        self.delta.set_len(0)?;
+        self.delta.set_len(self.size)?;
+        self.delta.sync_all()
+    }
+
+    fn clusters(&self) -> u64 {
+        self.size.div_ceil(self.cluster_size)
+    }
+
+    fn cluster_range(&self, cluster: u64) -> (u64, usize) {
+        let start = cluster * self.cluster_size;
+        (start, (self.size - start).min(self.cluster_size) as usize)
+    }
+
+    /// replaces the bitmap file with the contents of `bitmap`
+    fn write_bitmap(&self, bitmap: &Bitmap) -> io::Result<()> {
+        let mut raw = Vec::with_capacity(BITMAP_HEADER_LEN + bitmap.words.len() * 8 + 4);
+        raw.extend_from_slice(BITMAP_MAGIC);
+        raw.extend_from_slice(&(self.cluster_size as u32).to_le_bytes());
+        raw.extend_from_slice(&0u32.to_le_bytes());
+        raw.extend_from_slice(&self.size.to_le_bytes());
+        for word in bitmap.words.iter() {
+            raw.extend_from_slice(&word.to_le_bytes());
+        }
+        let crc = crc32fast::hash(&raw);
+        raw.extend_from_slice(&crc.to_le_bytes());
+
+        write_atomic(&self.bitmap_path, &raw)
+    }
+
+    /// calls `f` with every piece of `offset..offset + len` that lies in a
+    /// single cluster, as (cluster, offset, position in the request, length)
+    fn for_each_piece<F>(&self, offset: u64, len: usize, mut f: F) -> io::Result<()>
+    where
+        F: FnMut(u64, u64, usize, usize) -> io::Result<()>,
+    {
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let cluster = pos / self.cluster_size;
+            let n = ((cluster + 1) * self.cluster_size - pos).min((len - done) as u64) as usize;
+            f(cluster, pos, done, n)?;
+            done += n;
+        }
+        Ok(())
+    }
+
+    /// writes `data`, which lies within `cluster`, to the delta
+    fn write_piece(
+        &self,
+        bitmap: &mut Bitmap,
+        cluster: u64,
+        pos: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let (start, len) = self.cluster_range(cluster);
+        if !bitmap.get(cluster) && data.len() < len {
+            // copy the cluster up from the base before modifying part of it
+            let mut cluster_buf = vec![0u8; len];
+            self.base.read_at(&mut cluster_buf, start)?;
+            let from = (pos - start) as usize;
+            cluster_buf[from..from + data.len()].copy_from_slice(data);
+            self.delta.write_all_at(&cluster_buf, start)?;
+        } else {
+            self.delta.write_all_at(data, pos)?;
+        }
+        bitmap.set(cluster);
+        Ok(())
+    }
+
+    fn check_range(&self, offset: u64, len: u64) -> io::Result<()> {
+        match offset.checked_add(len) {
+            Some(end) if end <= self.size => Ok(()),
+            _ => Err(io::Error::from_raw_os_error(libc::EINVAL)),
+        }
+    }
+
+    fn check_writable(&self) -> io::Result<()> {
+        if self.read_only {
+            Err(io::Error::from_raw_os_error(libc::EPERM))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl BlockBackend for OverlayBackend {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        self.check_range(offset, buf.len() as u64)?;
+        let bitmap = self.bitmap.lock().unwrap();
+        self.for_each_piece(offset, buf.len(), |cluster, pos, at, n| {
+            if bitmap.get(cluster) {
+                self.delta.read_exact_at(&mut buf[at..at + n], pos)
+            } else {
+                self.base.read_at(&mut buf[at..at + n], pos)
+            }
+        })
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<()> {
+        self.check_writable()?;
+        self.check_range(offset, buf.len() as u64)?;
+        let mut bitmap = self.bitmap.lock().unwrap();
+        self.for_each_piece(offset, buf.len(), |cluster, pos, at, n| {
+            self.write_piece(&mut bitmap, cluster, pos, &buf[at..at + n])
+        })
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let mut bitmap = self.bitmap.lock().unwrap();
+        self.delta.sync_data()?;
+        if bitmap.dirty {
+            self.write_bitmap(&bitmap)?;
+            bitmap.dirty = false;
+        }
+        Ok(())
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn write_zeroes(&self, offset: u64, len: u64, may_trim: bool) -> io::Result<()> {
+        self.check_writable()?;
+        self.check_range(offset, len)?;
+        // the zeros have to be recorded in the delta, otherwise the base
+        // shows through; whole clusters become holes in the delta
+        let zeros = vec![0u8; self.cluster_size as usize];
+        let mut bitmap = self.bitmap.lock().unwrap();
+        self.for_each_piece(offset, len as usize, |cluster, pos, _, n| {
+            let (start, cluster_len) = self.cluster_range(cluster);
+            if may_trim && n == cluster_len && punch_hole(&self.delta, start, n as u64).is_ok() {
+                bitmap.set(cluster);
+                return Ok(());
+            }
+            self.write_piece(&mut bitmap, cluster, pos, &zeros[..n])
+        })
+    }
+}
+
+impl Drop for OverlayBackend {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+fn words(size: u64, cluster_size: u64) -> usize {
+    size.div_ceil(cluster_size).div_ceil(64) as usize
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
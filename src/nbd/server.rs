@@ -0,0 +1,587 @@
+//! NBD server module
+
+use crate::disk::sparse::is_zero;
+use crate::nbd::backend::BlockBackend;
+
+use std::convert::TryInto;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::sync::Arc;
+use std::thread;
+
+const NBD_MAGIC: u64 = 0x4e42_444d_4147_4943;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const OPTION_REPLY_MAGIC: u64 = 0x0003_e889_0455_65a9;
+const REQUEST_MAGIC: u32 = 0x2560_9513;
+const SIMPLE_REPLY_MAGIC: u32 = 0x6744_6698;
+const STRUCTURED_REPLY_MAGIC: u32 = 0x668e_33ef;
+
+const FLAG_FIXED_NEWSTYLE: u16 = 1 << 0;
+const FLAG_NO_ZEROES: u16 = 1 << 1;
+
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_ABORT: u32 = 2;
+const OPT_LIST: u32 = 3;
+const OPT_INFO: u32 = 6;
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNSUP: u32 = (1 << 31) | 1;
+const REP_ERR_INVALID: u32 = (1 << 31) | 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) | 6;
+
+const INFO_EXPORT: u16 = 0;
+const INFO_DESCRIPTION: u16 = 2;
+const INFO_BLOCK_SIZE: u16 = 3;
+
+const TRANSMISSION_HAS_FLAGS: u16 = 1 << 0;
+const TRANSMISSION_READ_ONLY: u16 = 1 << 1;
+const TRANSMISSION_SEND_FLUSH: u16 = 1 << 2;
+const TRANSMISSION_SEND_FUA: u16 = 1 << 3;
+const TRANSMISSION_SEND_TRIM: u16 = 1 << 5;
+const TRANSMISSION_SEND_WRITE_ZEROES: u16 = 1 << 6;
+const TRANSMISSION_SEND_DF: u16 = 1 << 7;
+
+const CMD_FLAG_FUA: u16 = 1 << 0;
+const CMD_FLAG_NO_HOLE: u16 = 1 << 1;
+
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_TRIM: u16 = 4;
+const CMD_WRITE_ZEROES: u16 = 6;
+
+const REPLY_FLAG_DONE: u16 = 1 << 0;
+const REPLY_TYPE_NONE: u16 = 0;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+
+const NBD_EPERM: u32 = 1;
+const NBD_EIO: u32 = 5;
+const NBD_ENOMEM: u32 = 12;
+const NBD_EINVAL: u32 = 22;
+const NBD_ENOSPC: u32 = 28;
+const NBD_EOVERFLOW: u32 = 75;
+const NBD_ENOTSUP: u32 = 95;
+
+/// largest option payload accepted during negotiation
+const MAX_OPTION_LENGTH: u32 = 64 * 1024;
+/// largest READ or WRITE request
+pub const MAX_REQUEST_LENGTH: u32 = 32 * 1024 * 1024;
+const PREFERRED_BLOCK_SIZE: u32 = 4096;
+
+/// export of an `NbdServer`
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::nbd::{backend::FileBackend, server::NbdExport};
+/// # fn main() -> std::io::Result<()> {
+/// let export = NbdExport::new("disk", FileBackend::open("disk.img", false)?).read_only(true);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct NbdExport {
+    name: String,
+    description: String,
+    backend: Arc<dyn BlockBackend>,
+    read_only: bool,
+}
+
+impl NbdExport {
+    pub fn new<T: Into<String>, B: BlockBackend + 'static>(name: T, backend: B) -> NbdExport {
+        let read_only = backend.read_only();
+        NbdExport {
+            name: name.into(),
+            description: String::new(),
+            backend: Arc::new(backend),
+            read_only,
+        }
+    }
+
+    /// refuses writes even when the backend accepts them
+    pub fn read_only(mut self, read_only: bool) -> NbdExport {
+        self.read_only = read_only || self.backend.read_only();
+        self
+    }
+
+    pub fn description<T: Into<String>>(mut self, description: T) -> NbdExport {
+        self.description = description.into();
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn transmission_flags(&self, structured: bool) -> u16 {
+        let mut flags = TRANSMISSION_HAS_FLAGS | TRANSMISSION_SEND_FLUSH | TRANSMISSION_SEND_FUA;
+        if self.read_only {
+            flags |= TRANSMISSION_READ_ONLY;
+        } else {
+            flags |= TRANSMISSION_SEND_TRIM | TRANSMISSION_SEND_WRITE_ZEROES;
+        }
+        if structured {
+            flags |= TRANSMISSION_SEND_DF;
+        }
+        flags
+    }
+}
+
+/// builder for NbdServer
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::nbd::{backend::FileBackend, server::*};
+/// # use std::os::unix::net::UnixListener;
+/// # fn main() -> std::io::Result<()> {
+/// let server = NbdServerBuilder::new()
+///     .export(NbdExport::new("disk", FileBackend::open("disk.img", false)?))
+///     .build();
+/// server.serve_unix(UnixListener::bind("/tmp/disk.sock")?)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct NbdServerBuilder {
+    exports: Vec<NbdExport>,
+}
+
+impl NbdServerBuilder {
+    pub fn new() -> Self {
+        NbdServerBuilder {
+            exports: Vec::new(),
+        }
+    }
+
+    pub fn export(mut self, export: NbdExport) -> Self {
+        self.exports.retain(|e| e.name != export.name);
+        self.exports.push(export);
+        self
+    }
+
+    pub fn build(self) -> NbdServer {
+        NbdServer {
+            exports: Arc::new(self.exports),
+        }
+    }
+}
+
+impl Default for NbdServerBuilder {
+    fn default() -> Self {
+        NbdServerBuilder::new()
+    }
+}
+
+/// NBD server speaking the fixed newstyle protocol
+///
+/// Supports `NBD_OPT_GO`/`NBD_OPT_INFO`, `NBD_OPT_LIST`, structured replies,
+/// `NBD_CMD_TRIM`, `NBD_CMD_WRITE_ZEROES`, FUA and read-only exports.
+/// Each connection is served on its own thread.
+#[derive(Clone)]
+pub struct NbdServer {
+    exports: Arc<Vec<NbdExport>>,
+}
+
+impl NbdServer {
+    /// accepts connections on `listener` until accepting fails
+    pub fn serve_tcp(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept()?;
+            stream.set_nodelay(true)?;
+            let server = self.clone();
+            thread::spawn(move || server.handle_connection(stream));
+        }
+    }
+
+    /// accepts connections on `listener` until accepting fails
+    pub fn serve_unix(&self, listener: UnixListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept()?;
+            let server = self.clone();
+            thread::spawn(move || server.handle_connection(stream));
+        }
+    }
+
+    /// serves a single connection until the client disconnects
+    pub fn handle_connection<S: Read + Write>(&self, mut stream: S) -> io::Result<()> {
+        match self.negotiate(&mut stream)? {
+            Some((export, structured)) => Connection {
+                stream,
+                export,
+                structured,
+            }
+            .transmission(),
+            None => Ok(()),
+        }
+    }
+
+    fn find_export(&self, name: &str) -> Option<&NbdExport> {
+        match self.exports.iter().find(|e| e.name == name) {
+            Some(export) => Some(export),
+            // the empty name selects the default export
+            None if name.is_empty() => self.exports.first(),
+            None => None,
+        }
+    }
+
+    fn negotiate<S: Read + Write>(&self, stream: &mut S) -> io::Result<Option<(NbdExport, bool)>> {
+        let mut hello = Vec::with_capacity(18);
+        hello.extend_from_slice(&NBD_MAGIC.to_be_bytes());
+        hello.extend_from_slice(&IHAVEOPT.to_be_bytes());
+        hello.extend_from_slice(&(FLAG_FIXED_NEWSTYLE | FLAG_NO_ZEROES).to_be_bytes());
+        stream.write_all(&hello)?;
+        stream.flush()?;
+
+        let client_flags = read_u32(stream)?;
+        let no_zeroes = client_flags & FLAG_NO_ZEROES as u32 != 0;
+        let mut structured = false;
+
+        loop {
+            if read_u64(stream)? != IHAVEOPT {
+                return Err(invalid_data("bad option magic"));
+            }
+            let option = read_u32(stream)?;
+            let length = read_u32(stream)?;
+            if length > MAX_OPTION_LENGTH {
+                return Err(invalid_data("option too long"));
+            }
+            let mut data = vec![0u8; length as usize];
+            stream.read_exact(&mut data)?;
+
+            match option {
+                OPT_EXPORT_NAME => {
+                    let name = String::from_utf8_lossy(&data);
+                    let export = match self.find_export(&name) {
+                        Some(export) => export.clone(),
+                        // this option has no way to report an error
+                        None => return Ok(None),
+                    };
+                    let mut reply = Vec::with_capacity(10 + 124);
+                    reply.extend_from_slice(&export.backend.size().to_be_bytes());
+                    reply.extend_from_slice(&export.transmission_flags(structured).to_be_bytes());
+                    if !no_zeroes {
+                        reply.extend_from_slice(&[0u8; 124]);
+                    }
+                    stream.write_all(&reply)?;
+                    stream.flush()?;
+                    return Ok(Some((export, structured)));
+                }
+                OPT_ABORT => {
+                    option_reply(stream, option, REP_ACK, &[])?;
+                    return Ok(None);
+                }
+                OPT_LIST => {
+                    if !data.is_empty() {
+                        option_reply(stream, option, REP_ERR_INVALID, &[])?;
+                        continue;
+                    }
+                    for export in self.exports.iter() {
+                        let mut payload = Vec::with_capacity(4 + export.name.len());
+                        payload.extend_from_slice(&(export.name.len() as u32).to_be_bytes());
+                        payload.extend_from_slice(export.name.as_bytes());
+                        option_reply(stream, option, REP_SERVER, &payload)?;
+                    }
+                    option_reply(stream, option, REP_ACK, &[])?;
+                }
+                OPT_STRUCTURED_REPLY => {
+                    if data.is_empty() {
+                        structured = true;
+                        option_reply(stream, option, REP_ACK, &[])?;
+                    } else {
+                        option_reply(stream, option, REP_ERR_INVALID, &[])?;
+                    }
+                }
+                OPT_INFO | OPT_GO => {
+                    let (name, requests) = match parse_info_request(&data) {
+                        Some(request) => request,
+                        None => {
+                            option_reply(stream, option, REP_ERR_INVALID, &[])?;
+                            continue;
+                        }
+                    };
+                    let export = match self.find_export(&name) {
+                        Some(export) => export.clone(),
+                        None => {
+                            option_reply(stream, option, REP_ERR_UNKNOWN, &[])?;
+                            continue;
+                        }
+                    };
+                    send_info(stream, option, &export, &requests, structured)?;
+                    option_reply(stream, option, REP_ACK, &[])?;
+                    if option == OPT_GO {
+                        return Ok(Some((export, structured)));
+                    }
+                }
+                _ => option_reply(stream, option, REP_ERR_UNSUP, &[])?,
+            }
+        }
+    }
+}
+
+fn parse_info_request(data: &[u8]) -> Option<(String, Vec<u16>)> {
+    let name_len = u32::from_be_bytes(data.get(0..4)?.try_into().ok()?) as usize;
+    let name = data.get(4..4 + name_len)?;
+    let rest = &data[4 + name_len..];
+    let count = u16::from_be_bytes(rest.get(0..2)?.try_into().ok()?) as usize;
+    let requests = rest.get(2..2 + count * 2)?;
+    if rest.len() != 2 + count * 2 {
+        return None;
+    }
+    let requests = requests
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect();
+    Some((String::from_utf8_lossy(name).into_owned(), requests))
+}
+
+fn send_info<S: Write>(
+    stream: &mut S,
+    option: u32,
+    export: &NbdExport,
+    requests: &[u16],
+    structured: bool,
+) -> io::Result<()> {
+    let mut payload = Vec::with_capacity(12);
+    payload.extend_from_slice(&INFO_EXPORT.to_be_bytes());
+    payload.extend_from_slice(&export.backend.size().to_be_bytes());
+    payload.extend_from_slice(&export.transmission_flags(structured).to_be_bytes());
+    option_reply(stream, option, REP_INFO, &payload)?;
+
+    if requests.contains(&INFO_BLOCK_SIZE) {
+        let mut payload = Vec::with_capacity(14);
+        payload.extend_from_slice(&INFO_BLOCK_SIZE.to_be_bytes());
+        payload.extend_from_slice(&1u32.to_be_bytes());
+        payload.extend_from_slice(&PREFERRED_BLOCK_SIZE.to_be_bytes());
+        payload.extend_from_slice(&MAX_REQUEST_LENGTH.to_be_bytes());
+        option_reply(stream, option, REP_INFO, &payload)?;
+    }
+    if requests.contains(&INFO_DESCRIPTION) && !export.description.is_empty() {
+        let mut payload = Vec::with_capacity(2 + export.description.len());
+        payload.extend_from_slice(&INFO_DESCRIPTION.to_be_bytes());
+        payload.extend_from_slice(export.description.as_bytes());
+        option_reply(stream, option, REP_INFO, &payload)?;
+    }
+    Ok(())
+}
+
+fn option_reply<S: Write>(stream: &mut S, option: u32, reply: u32, data: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(20 + data.len());
+    buf.extend_from_slice(&OPTION_REPLY_MAGIC.to_be_bytes());
+    buf.extend_from_slice(&option.to_be_bytes());
+    buf.extend_from_slice(&reply.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    stream.write_all(&buf)?;
+    stream.flush()
+}
+
+struct Request {
+    flags: u16,
+    command: u16,
+    handle: u64,
+    offset: u64,
+    length: u32,
+}
+
+struct Connection<S> {
+    stream: S,
+    export: NbdExport,
+    structured: bool,
+}
+
+impl<S: Read + Write> Connection<S> {
+    fn transmission(mut self) -> io::Result<()> {
+        loop {
+            let mut raw = [0u8; 28];
+            match self.stream.read_exact(&mut raw) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            }
+            if u32::from_be_bytes(raw[0..4].try_into().unwrap()) != REQUEST_MAGIC {
+                return Err(invalid_data("bad request magic"));
+            }
+            let request = Request {
+                flags: u16::from_be_bytes(raw[4..6].try_into().unwrap()),
+                command: u16::from_be_bytes(raw[6..8].try_into().unwrap()),
+                handle: u64::from_be_bytes(raw[8..16].try_into().unwrap()),
+                offset: u64::from_be_bytes(raw[16..24].try_into().unwrap()),
+                length: u32::from_be_bytes(raw[24..28].try_into().unwrap()),
+            };
+            match request.command {
+                CMD_READ => self.read(&request)?,
+                CMD_WRITE => self.write(&request)?,
+                CMD_DISC => return Ok(()),
+                CMD_FLUSH => {
+                    let result = self.export.backend.flush();
+                    self.reply(&request, result)?;
+                }
+                CMD_TRIM => {
+                    let result = self.check_write(&request).and_then(|_| {
+                        self.export
+                            .backend
+                            .trim(request.offset, request.length as u64)
+                            .and_then(|_| self.fua(&request))
+                    });
+                    self.reply(&request, result)?;
+                }
+                CMD_WRITE_ZEROES => {
+                    let may_trim = request.flags & CMD_FLAG_NO_HOLE == 0;
+                    let result = self.check_write(&request).and_then(|_| {
+                        self.export
+                            .backend
+                            .write_zeroes(request.offset, request.length as u64, may_trim)
+                            .and_then(|_| self.fua(&request))
+                    });
+                    self.reply(&request, result)?;
+                }
+                _ => self.error_reply(request.handle, NBD_EINVAL)?,
+            }
+        }
+    }
+
+    fn in_bounds(&self, request: &Request) -> bool {
+        request
+            .offset
+            .checked_add(request.length as u64)
+            .is_some_and(|end| end <= self.export.backend.size())
+    }
+
+    fn check_write(&self, request: &Request) -> io::Result<()> {
+        if self.export.read_only {
+            Err(io::Error::from_raw_os_error(libc::EPERM))
+        } else if !self.in_bounds(request) {
+            Err(io::Error::from_raw_os_error(libc::ENOSPC))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn fua(&self, request: &Request) -> io::Result<()> {
+        if request.flags & CMD_FLAG_FUA != 0 {
+            self.export.backend.flush()
+        } else {
+            Ok(())
+        }
+    }
+
+    fn read(&mut self, request: &Request) -> io::Result<()> {
+        if request.length > MAX_REQUEST_LENGTH {
+            return self.error_reply(request.handle, NBD_EOVERFLOW);
+        }
+        if !self.in_bounds(request) {
+            return self.error_reply(request.handle, NBD_EINVAL);
+        }
+        let mut buf = vec![0u8; request.length as usize];
+        if let Err(err) = self.export.backend.read_at(&mut buf, request.offset) {
+            return self.error_reply(request.handle, errno(&err));
+        }
+        if !self.structured {
+            return self.simple_reply(request.handle, 0, &buf);
+        }
+        if buf.is_empty() {
+            return self.chunk(request.handle, REPLY_TYPE_NONE, &[]);
+        }
+        if is_zero(&buf) {
+            let mut payload = Vec::with_capacity(12);
+            payload.extend_from_slice(&request.offset.to_be_bytes());
+            payload.extend_from_slice(&request.length.to_be_bytes());
+            return self.chunk(request.handle, REPLY_TYPE_OFFSET_HOLE, &payload);
+        }
+        let mut payload = Vec::with_capacity(8 + buf.len());
+        payload.extend_from_slice(&request.offset.to_be_bytes());
+        payload.extend_from_slice(&buf);
+        self.chunk(request.handle, REPLY_TYPE_OFFSET_DATA, &payload)
+    }
+
+    fn write(&mut self, request: &Request) -> io::Result<()> {
+        if request.length > MAX_REQUEST_LENGTH {
+            // the payload cannot be skipped safely, so drop the connection
+            self.error_reply(request.handle, NBD_EOVERFLOW)?;
+            return Err(invalid_data("write request too large"));
+        }
+        let mut buf = vec![0u8; request.length as usize];
+        self.stream.read_exact(&mut buf)?;
+        let result = self.check_write(request).and_then(|_| {
+            self.export
+                .backend
+                .write_at(&buf, request.offset)
+                .and_then(|_| self.fua(request))
+        });
+        self.reply(request, result)
+    }
+
+    fn reply(&mut self, request: &Request, result: io::Result<()>) -> io::Result<()> {
+        match result {
+            Ok(()) => self.simple_reply(request.handle, 0, &[]),
+            Err(err) => self.error_reply(request.handle, errno(&err)),
+        }
+    }
+
+    fn error_reply(&mut self, handle: u64, error: u32) -> io::Result<()> {
+        if self.structured {
+            let mut payload = Vec::with_capacity(6);
+            payload.extend_from_slice(&error.to_be_bytes());
+            payload.extend_from_slice(&0u16.to_be_bytes());
+            self.chunk(handle, REPLY_TYPE_ERROR, &payload)
+        } else {
+            self.simple_reply(handle, error, &[])
+        }
+    }
+
+    fn simple_reply(&mut self, handle: u64, error: u32, data: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(16 + data.len());
+        buf.extend_from_slice(&SIMPLE_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&error.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf)?;
+        self.stream.flush()
+    }
+
+    /// sends the one and only chunk of a structured reply
+    fn chunk(&mut self, handle: u64, reply_type: u16, payload: &[u8]) -> io::Result<()> {
+        let mut buf = Vec::with_capacity(20 + payload.len());
+        buf.extend_from_slice(&STRUCTURED_REPLY_MAGIC.to_be_bytes());
+        buf.extend_from_slice(&REPLY_FLAG_DONE.to_be_bytes());
+        buf.extend_from_slice(&reply_type.to_be_bytes());
+        buf.extend_from_slice(&handle.to_be_bytes());
+        buf.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        buf.extend_from_slice(payload);
+        self.stream.write_all(&buf)?;
+        self.stream.flush()
+    }
+}
+
+/// maps an I/O error to the error numbers of the NBD protocol
+fn errno(err: &io::Error) -> u32 {
+    match err.raw_os_error() {
+        Some(libc::EPERM) | Some(libc::EROFS) => NBD_EPERM,
+        Some(libc::ENOMEM) => NBD_ENOMEM,
+        Some(libc::EINVAL) => NBD_EINVAL,
+        Some(libc::ENOSPC) | Some(libc::EDQUOT) => NBD_ENOSPC,
+        Some(libc::EOVERFLOW) => NBD_EOVERFLOW,
+        Some(libc::EOPNOTSUPP) => NBD_ENOTSUP,
+        _ => NBD_EIO,
+    }
+}
+
+fn read_u32<R: Read>(stream: &mut R) -> io::Result<u32> {
+    let mut raw = [0u8; 4];
+    stream.read_exact(&mut raw)?;
+    Ok(u32::from_be_bytes(raw))
+}
+
+fn read_u64<R: Read>(stream: &mut R) -> io::Result<u64> {
+    let mut raw = [0u8; 8];
+    stream.read_exact(&mut raw)?;
+    Ok(u64::from_be_bytes(raw))
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
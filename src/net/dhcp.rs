@@ -0,0 +1,779 @@
+//! DHCP server module
+//!
+//! A DHCPv4 server speaking raw Ethernet frames on a switch port, so guests
+//! on a private network built with `EthernetSwitch` get their addresses
+//! without anything running on the host network.
+
+use crate::net::mac::MacAddress;
+use crate::net::packet::{arp_reply, UdpDatagram};
+use crate::util::write_atomic;
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const LEASES_VERSION: &str = "leases 1";
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const BROADCAST_FLAG: u16 = 0x8000;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// fixed BOOTP fields followed by the magic cookie
+const OPTIONS_OFFSET: usize = 240;
+/// some BOOTP clients drop shorter messages
+const MIN_MESSAGE_LEN: usize = 300;
+
+/// how long an offered address is held for the client
+const OFFER_HOLD: Duration = Duration::from_secs(60);
+/// how long an address declined by a client is not handed out
+const DECLINE_HOLD: Duration = Duration::from_secs(600);
+
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+const DHCPREQUEST: u8 = 3;
+const DHCPDECLINE: u8 = 4;
+const DHCPACK: u8 = 5;
+const DHCPNAK: u8 = 6;
+const DHCPRELEASE: u8 = 7;
+const DHCPINFORM: u8 = 8;
+
+const OPTION_PAD: u8 = 0;
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS_SERVERS: u8 = 6;
+const OPTION_HOSTNAME: u8 = 12;
+const OPTION_DOMAIN_NAME: u8 = 15;
+const OPTION_REQUESTED_ADDRESS: u8 = 50;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_RENEWAL_TIME: u8 = 58;
+const OPTION_REBINDING_TIME: u8 = 59;
+const OPTION_END: u8 = 255;
+
+/// error of `DhcpServerBuilder`
+#[derive(Debug)]
+pub enum DhcpError {
+    Io(io::Error),
+    /// the prefix leaves no room for clients
+    InvalidSubnet(u8),
+    /// the address range is empty or not inside the subnet
+    InvalidRange,
+    /// a reserved address outside the subnet
+    OutsideSubnet(Ipv4Addr),
+    /// the lease file is damaged at the given line
+    InvalidLeases(usize),
+    /// the named setting is longer than the 255 bytes of a DHCP option
+    OptionTooLong(&'static str),
+    /// a reserved host name that is not a valid DNS label
+    InvalidHostname(String),
+}
+
+impl fmt::Display for DhcpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DhcpError::Io(err) => write!(f, "{}", err),
+            DhcpError::InvalidSubnet(prefix_len) => write!(f, "invalid prefix /{}", prefix_len),
+            DhcpError::InvalidRange => write!(f, "address range is empty or outside the subnet"),
+            DhcpError::OutsideSubnet(address) => write!(f, "{} is outside the subnet", address),
+            DhcpError::InvalidLeases(line) => write!(f, "lease file is damaged at line {}", line),
+            DhcpError::OptionTooLong(name) => write!(f, "{} does not fit in a DHCP option", name),
+            DhcpError::InvalidHostname(name) => write!(f, "invalid host name {:?}", name),
+        }
+    }
+}
+
+impl error::Error for DhcpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DhcpError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DhcpError {
+    fn from(err: io::Error) -> Self {
+        DhcpError::Io(err)
+    }
+}
+
+/// fixed address for a NIC
+///
+/// Pairs with `MacAddress::for_vm` so that a VM keeps its address when it is
+/// re-created.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reservation {
+    pub mac: MacAddress,
+    pub address: Ipv4Addr,
+    /// host name handed to the guest; only its first label is used,
+    /// lowercased
+    pub hostname: Option<String>,
+}
+
+impl Reservation {
+    pub fn new(mac: MacAddress, address: Ipv4Addr) -> Reservation {
+        Reservation {
+            mac,
+            address,
+            hostname: None,
+        }
+    }
+
+    pub fn hostname<T: Into<String>>(mut self, hostname: T) -> Reservation {
+        self.hostname = Some(hostname.into());
+        self
+    }
+}
+
+/// address bound to a NIC
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub mac: MacAddress,
+    pub address: Ipv4Addr,
+    pub expires: SystemTime,
+    /// host name of the reservation, or the one the guest sent
+    pub hostname: Option<String>,
+}
+
+impl Lease {
+    pub fn is_active(&self) -> bool {
+        self.expires > SystemTime::now()
+    }
+}
+
+/// builder for DhcpServer
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::net::dhcp::*;
+/// # use virtualization_rs::net::mac::MacAddress;
+/// # use virtualization_rs::net::switch::EthernetSwitchBuilder;
+/// # use std::net::Ipv4Addr;
+/// # fn main() -> Result<(), DhcpError> {
+/// let switch = EthernetSwitchBuilder::new().build();
+/// let server = DhcpServerBuilder::new(Ipv4Addr::new(192, 168, 64, 1), 24)
+///     .router(Ipv4Addr::new(192, 168, 64, 1))
+///     .dns_server(Ipv4Addr::new(192, 168, 64, 1))
+///     .domain_name("vm.internal")
+///     .reservation(
+///         Reservation::new(MacAddress::for_vm("db", 0), Ipv4Addr::new(192, 168, 64, 10))
+///             .hostname("db"),
+///     )
+///     .lease_file("leases")
+///     .build()?;
+/// let (_port, socket) = switch.connect()?;
+/// std::thread::spawn(move || server.serve(socket));
+/// # Ok(())
+/// # }
+/// ```
+pub struct DhcpServerBuilder {
+    address: Ipv4Addr,
+    prefix_len: u8,
+    mac: MacAddress,
+    range: Option<(Ipv4Addr, Ipv4Addr)>,
+    lease_time: Duration,
+    router: Option<Ipv4Addr>,
+    dns_servers: Vec<Ipv4Addr>,
+    domain_name: Option<String>,
+    reservations: Vec<Reservation>,
+    lease_file: Option<PathBuf>,
+}
+
+impl DhcpServerBuilder {
+    /// server at `address` in the subnet `address/prefix_len`
+    pub fn new(address: Ipv4Addr, prefix_len: u8) -> Self {
+        DhcpServerBuilder {
+            address,
+            prefix_len,
+            mac: MacAddress::for_vm("dhcp", 0),
+            range: None,
+            lease_time: Duration::from_secs(3600),
+            router: None,
+            dns_servers: Vec::new(),
+            domain_name: None,
+            reservations: Vec::new(),
+            lease_file: None,
+        }
+    }
+
+    /// address the server sends from, derived from "dhcp" by default
+    pub fn mac_address(mut self, mac: MacAddress) -> Self {
+        self.mac = mac;
+        self
+    }
+
+    /// addresses handed out dynamically, the whole subnet by default
+    pub fn range(mut self, first: Ipv4Addr, last: Ipv4Addr) -> Self {
+        self.range = Some((first, last));
+        self
+    }
+
+    /// one hour by default
+    pub fn lease_time(mut self, lease_time: Duration) -> Self {
+        self.lease_time = lease_time;
+        self
+    }
+
+    pub fn router(mut self, router: Ipv4Addr) -> Self {
+        self.router = Some(router);
+        self
+    }
+
+    pub fn dns_server(mut self, server: Ipv4Addr) -> Self {
+        self.dns_servers.push(server);
+        self
+    }
+
+    pub fn domain_name<T: Into<String>>(mut self, domain_name: T) -> Self {
+        self.domain_name = Some(domain_name.into());
+        self
+    }
+
+    pub fn reservation(mut self, reservation: Reservation) -> Self {
+        self.reservations.retain(|r| r.mac != reservation.mac);
+        self.reservations.push(reservation);
+        self
+    }
+
+    /// file the leases are kept in across restarts
+    pub fn lease_file<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.lease_file = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn build(self) -> Result<DhcpServer, DhcpError> {
+        if self.prefix_len > 30 {
+            return Err(DhcpError::InvalidSubnet(self.prefix_len));
+        }
+        let mask = u32::MAX
+            .checked_shl(32 - self.prefix_len as u32)
+            .unwrap_or(0);
+        let network = u32::from(self.address) & mask;
+        let broadcast = network | !mask;
+        let in_subnet = |ip: Ipv4Addr| {
+            let ip = u32::from(ip);
+            ip > network && ip < broadcast
+        };
+        let (first, last) = match self.range {
+            Some((first, last)) => (u32::from(first), u32::from(last)),
+            None => (network + 1, broadcast - 1),
+        };
+        if first > last || !in_subnet(first.into()) || !in_subnet(last.into()) {
+            return Err(DhcpError::InvalidRange);
+        }
+        if let Some(r) = self.reservations.iter().find(|r| !in_subnet(r.address)) {
+            return Err(DhcpError::OutsideSubnet(r.address));
+        }
+        if self
+            .domain_name
+            .as_ref()
+            .is_some_and(|name| name.len() > 255)
+        {
+            return Err(DhcpError::OptionTooLong("domain name"));
+        }
+        if self.dns_servers.len() > 255 / 4 {
+            return Err(DhcpError::OptionTooLong("DNS server list"));
+        }
+        let mut reservations = self.reservations;
+        for r in &mut reservations {
+            if let Some(name) = r.hostname.take() {
+                match sanitize_hostname(name.as_bytes()) {
+                    Some(hostname) => r.hostname = Some(hostname),
+                    None => return Err(DhcpError::InvalidHostname(name)),
+                }
+            }
+        }
+
+        let config = Config {
+            address: self.address,
+            mac: self.mac,
+            netmask: mask.into(),
+            range: (first, last),
+            lease_time: self.lease_time,
+            router: self.router,
+            dns_servers: self.dns_servers,
+            domain_name: self.domain_name,
+            reserved: reservations.iter().map(|r| (r.address, r.mac)).collect(),
+            reservations: reservations.into_iter().map(|r| (r.mac, r)).collect(),
+            lease_file: self.lease_file,
+        };
+        let leases = match &config.lease_file {
+            Some(path) => read_leases(path)?
+                .into_iter()
+                .filter(|lease| in_subnet(lease.address))
+                .map(|lease| (lease.address, lease))
+                .collect(),
+            None => HashMap::new(),
+        };
+        Ok(DhcpServer(Arc::new(Shared {
+            config,
+            state: Mutex::new(State {
+                leases,
+                offers: HashMap::new(),
+                declined: HashMap::new(),
+            }),
+        })))
+    }
+}
+
+/// DHCPv4 server
+///
+/// Handles DISCOVER, REQUEST, DECLINE, RELEASE and INFORM from clients on
+/// the same link and answers ARP for its own address. The server is
+/// authoritative: requests for addresses it cannot grant are NAKed.
+/// Relayed requests are ignored.
+#[derive(Clone)]
+pub struct DhcpServer(Arc<Shared>);
+
+struct Shared {
+    config: Config,
+    state: Mutex<State>,
+}
+
+struct Config {
+    address: Ipv4Addr,
+    mac: MacAddress,
+    netmask: Ipv4Addr,
+    range: (u32, u32),
+    lease_time: Duration,
+    router: Option<Ipv4Addr>,
+    dns_servers: Vec<Ipv4Addr>,
+    domain_name: Option<String>,
+    reservations: HashMap<MacAddress, Reservation>,
+    reserved: HashMap<Ipv4Addr, MacAddress>,
+    lease_file: Option<PathBuf>,
+}
+
+struct State {
+    leases: HashMap<Ipv4Addr, Lease>,
+    offers: HashMap<MacAddress, (Ipv4Addr, Instant)>,
+    declined: HashMap<Ipv4Addr, Instant>,
+}
+
+struct Request<'a> {
+    message_type: u8,
+    xid: &'a [u8],
+    flags: u16,
+    ciaddr: Ipv4Addr,
+    chaddr: MacAddress,
+    options: HashMap<u8, &'a [u8]>,
+}
+
+impl Request<'_> {
+    fn address_option(&self, code: u8) -> Option<Ipv4Addr> {
+        match self.options.get(&code) {
+            Some(&&[a, b, c, d]) => Some(Ipv4Addr::new(a, b, c, d)),
+            _ => None,
+        }
+    }
+}
+
+impl DhcpServer {
+    pub fn address(&self) -> Ipv4Addr {
+        self.0.config.address
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.0.config.mac
+    }
+
+    /// leases that have not expired, sorted by address
+    pub fn leases(&self) -> Vec<Lease> {
+        let state = self.0.state.lock().unwrap();
+        let mut leases: Vec<_> = state
+            .leases
+            .values()
+            .filter(|l| l.is_active())
+            .cloned()
+            .collect();
+        leases.sort_by_key(|l| l.address);
+        leases
+    }
+
+    /// answers frames from the switch port until the socket is shut down
+    pub fn serve(&self, socket: UnixDatagram) -> io::Result<()> {
+        let mut frame = vec![0u8; 1 << 16];
+        loop {
+            let n = match socket.recv(&mut frame) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if let Some(reply) = self.handle_frame(&frame[..n])? {
+                match socket.send(&reply) {
+                    Ok(_) => {}
+                    Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => {}
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+    }
+
+    /// reply frame to an Ethernet frame, if any
+    ///
+    /// Fails only if the lease file cannot be written, in which case the
+    /// client is not answered.
+    pub fn handle_frame(&self, frame: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let config = &self.0.config;
+        if let Some(reply) = arp_reply(frame, config.mac, config.address) {
+            return Ok(Some(reply));
+        }
+        let udp = match UdpDatagram::parse_frame(frame) {
+            Some((_, udp)) if udp.dst.port() == SERVER_PORT => udp,
+            _ => return Ok(None),
+        };
+        if *udp.dst.ip() != Ipv4Addr::BROADCAST && *udp.dst.ip() != config.address {
+            return Ok(None);
+        }
+        let request = match parse_request(udp.payload) {
+            Some(request) => request,
+            None => return Ok(None),
+        };
+        let mut state = self.0.state.lock().unwrap();
+        self.0.handle(&mut state, &request)
+    }
+}
+
+impl Shared {
+    fn handle(&self, state: &mut State, request: &Request) -> io::Result<Option<Vec<u8>>> {
+        let now = Instant::now();
+        state.offers.retain(|_, &mut (_, until)| until > now);
+        state.declined.retain(|_, &mut until| until > now);
+        let mac = request.chaddr;
+        let server_id = request.address_option(OPTION_SERVER_ID);
+        let requested = request.address_option(OPTION_REQUESTED_ADDRESS);
+        let for_us = server_id.is_none_or(|id| id == self.config.address);
+
+        match request.message_type {
+            DHCPDISCOVER => Ok(self.allocate(state, mac, requested).map(|address| {
+                state.offers.insert(mac, (address, now + OFFER_HOLD));
+                self.reply(request, DHCPOFFER, address)
+            })),
+            DHCPREQUEST => {
+                if !for_us {
+                    // the client picked another server
+                    state.offers.remove(&mac);
+                    return Ok(None);
+                }
+                let address = match requested {
+                    Some(address) => address,
+                    None if !request.ciaddr.is_unspecified() => request.ciaddr,
+                    None => return Ok(None),
+                };
+                if !self.grantable(state, mac, address) {
+                    return Ok(Some(self.reply(request, DHCPNAK, Ipv4Addr::UNSPECIFIED)));
+                }
+                let hostname = match self.config.reservations.get(&mac) {
+                    Some(r) => r.hostname.clone(),
+                    None => request
+                        .options
+                        .get(&OPTION_HOSTNAME)
+                        .and_then(|name| sanitize_hostname(name)),
+                };
+                state.offers.remove(&mac);
+                state.leases.retain(|_, lease| lease.mac != mac);
+                state.leases.insert(
+                    address,
+                    Lease {
+                        mac,
+                        address,
+                        expires: SystemTime::now() + self.config.lease_time,
+                        hostname,
+                    },
+                );
+                self.persist(state)?;
+                Ok(Some(self.reply(request, DHCPACK, address)))
+            }
+            DHCPDECLINE => {
+                if let (true, Some(address)) = (for_us, requested) {
+                    if state.leases.get(&address).is_some_and(|l| l.mac == mac) {
+                        state.leases.remove(&address);
+                        self.persist(state)?;
+                    }
+                    state.offers.remove(&mac);
+                    state.declined.insert(address, now + DECLINE_HOLD);
+                }
+                Ok(None)
+            }
+            DHCPRELEASE => {
+                let lease = state.leases.get_mut(&request.ciaddr);
+                if let (true, Some(lease)) = (for_us, lease) {
+                    if lease.mac == mac && lease.is_active() {
+                        // the record stays so the client gets the address back
+                        lease.expires = SystemTime::now();
+                        self.persist(state)?;
+                    }
+                }
+                Ok(None)
+            }
+            DHCPINFORM if !request.ciaddr.is_unspecified() => {
+                Ok(Some(self.reply(request, DHCPACK, Ipv4Addr::UNSPECIFIED)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn in_range(&self, address: Ipv4Addr) -> bool {
+        let address = u32::from(address);
+        address >= self.config.range.0 && address <= self.config.range.1
+    }
+
+    /// whether `address` is free for `mac`, ignoring the range
+    fn available(&self, state: &State, mac: MacAddress, address: Ipv4Addr) -> bool {
+        address != self.config.address
+            && Some(address) != self.config.router
+            && self.config.reserved.get(&address).is_none_or(|&m| m == mac)
+            && !state.declined.contains_key(&address)
+            && state
+                .leases
+                .get(&address)
+                .is_none_or(|l| l.mac == mac || !l.is_active())
+            && state
+                .offers
+                .iter()
+                .all(|(&m, &(offered, _))| m == mac || offered != address)
+    }
+
+    fn grantable(&self, state: &State, mac: MacAddress, address: Ipv4Addr) -> bool {
+        match self.config.reservations.get(&mac) {
+            Some(r) => r.address == address && self.available(state, mac, address),
+            None => self.in_range(address) && self.available(state, mac, address),
+        }
+    }
+
+    fn allocate(
+        &self,
+        state: &State,
+        mac: MacAddress,
+        requested: Option<Ipv4Addr>,
+    ) -> Option<Ipv4Addr> {
+        if let Some(r) = self.config.reservations.get(&mac) {
+            return Some(r.address).filter(|&a| self.available(state, mac, a));
+        }
+        let offered = state.offers.get(&mac).map(|&(address, _)| address);
+        let previous = state.leases.values().find(|l| l.mac == mac);
+        let candidates = offered
+            .into_iter()
+            .chain(previous.map(|l| l.address))
+            .chain(requested);
+        for address in candidates {
+            if self.grantable(state, mac, address) {
+                return Some(address);
+            }
+        }
+        // never used addresses first, then the one expired the longest
+        let (first, last) = self.config.range;
+        (first..=last)
+            .map(Ipv4Addr::from)
+            .find(|a| !state.leases.contains_key(a) && self.available(state, mac, *a))
+            .or_else(|| {
+                state
+                    .leases
+                    .values()
+                    .filter(|l| self.in_range(l.address) && self.available(state, mac, l.address))
+                    .min_by_key(|l| l.expires)
+                    .map(|l| l.address)
+            })
+    }
+
+    fn reply(&self, request: &Request, message_type: u8, yiaddr: Ipv4Addr) -> Vec<u8> {
+        let config = &self.config;
+        let mut message = vec![0u8; OPTIONS_OFFSET];
+        message[0] = BOOTREPLY;
+        message[1] = 1;
+        message[2] = 6;
+        message[4..8].copy_from_slice(request.xid);
+        message[10..12].copy_from_slice(&request.flags.to_be_bytes());
+        if message_type != DHCPNAK {
+            message[12..16].copy_from_slice(&request.ciaddr.octets());
+        }
+        message[16..20].copy_from_slice(&yiaddr.octets());
+        message[28..34].copy_from_slice(&request.chaddr.octets());
+        message[236..240].copy_from_slice(&MAGIC_COOKIE);
+
+        // `build` keeps every option within 255 bytes
+        let mut option = |code: u8, data: &[u8]| {
+            message.push(code);
+            message.push(data.len() as u8);
+            message.extend_from_slice(data);
+        };
+        option(OPTION_MESSAGE_TYPE, &[message_type]);
+        option(OPTION_SERVER_ID, &config.address.octets());
+        if message_type != DHCPNAK {
+            if !yiaddr.is_unspecified() {
+                let lease_time = config.lease_time.as_secs().min(u32::MAX as u64) as u32;
+                option(OPTION_LEASE_TIME, &lease_time.to_be_bytes());
+                option(OPTION_RENEWAL_TIME, &(lease_time / 2).to_be_bytes());
+                option(
+                    OPTION_REBINDING_TIME,
+                    &((lease_time as u64 * 7 / 8) as u32).to_be_bytes(),
+                );
+            }
+            option(OPTION_SUBNET_MASK, &config.netmask.octets());
+            if let Some(router) = config.router {
+                option(OPTION_ROUTER, &router.octets());
+            }
+            if !config.dns_servers.is_empty() {
+                let servers: Vec<u8> = config.dns_servers.iter().flat_map(|s| s.octets()).collect();
+                option(OPTION_DNS_SERVERS, &servers);
+            }
+            if let Some(domain_name) = &config.domain_name {
+                option(OPTION_DOMAIN_NAME, domain_name.as_bytes());
+            }
+            // reserved host names are sanitized by `build`
+            let hostname = config
+                .reservations
+                .get(&request.chaddr)
+                .and_then(|r| r.hostname.as_ref());
+            if let Some(hostname) = hostname {
+                option(OPTION_HOSTNAME, hostname.as_bytes());
+            }
+        }
+        message.push(OPTION_END);
+        if message.len() < MIN_MESSAGE_LEN {
+            message.resize(MIN_MESSAGE_LEN, OPTION_PAD);
+        }
+
+        // RFC 2131 4.1: where a reply goes depends on what the client knows
+        let (dst, dst_mac) = if message_type == DHCPNAK
+            || (request.ciaddr.is_unspecified() && request.flags & BROADCAST_FLAG != 0)
+        {
+            (Ipv4Addr::BROADCAST, MacAddress::BROADCAST)
+        } else if !request.ciaddr.is_unspecified() {
+            (request.ciaddr, request.chaddr)
+        } else {
+            (yiaddr, request.chaddr)
+        };
+        UdpDatagram {
+            src: SocketAddrV4::new(config.address, SERVER_PORT),
+            dst: SocketAddrV4::new(dst, CLIENT_PORT),
+            payload: &message,
+        }
+        .encode_frame(config.mac, dst_mac)
+    }
+
+    fn persist(&self, state: &State) -> io::Result<()> {
+        let path = match &self.config.lease_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut leases: Vec<_> = state.leases.values().collect();
+        leases.sort_by_key(|l| l.address);
+        let mut contents = format!("{}\n", LEASES_VERSION);
+        for lease in leases {
+            let expires = lease
+                .expires
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            contents.push_str(&format!("{} {} {}", lease.mac, lease.address, expires));
+            if let Some(hostname) = &lease.hostname {
+                contents.push(' ');
+                contents.push_str(hostname);
+            }
+            contents.push('\n');
+        }
+        write_atomic(path, contents.as_bytes())
+    }
+}
+
+fn parse_request(message: &[u8]) -> Option<Request<'_>> {
+    if message.len() < OPTIONS_OFFSET
+        || message[0] != BOOTREQUEST
+        || message[1] != 1
+        || message[2] != 6
+        || message[236..240] != MAGIC_COOKIE
+    {
+        return None;
+    }
+    // relayed requests would need replies through the relay
+    if message[24..28] != [0; 4] {
+        return None;
+    }
+    let mut options = HashMap::new();
+    let mut rest = &message[OPTIONS_OFFSET..];
+    while let Some((&code, tail)) = rest.split_first() {
+        match code {
+            OPTION_PAD => rest = tail,
+            OPTION_END => break,
+            _ => {
+                let (&len, tail) = tail.split_first()?;
+                let data = tail.get(..len as usize)?;
+                options.entry(code).or_insert(data);
+                rest = &tail[len as usize..];
+            }
+        }
+    }
+    let message_type = match options.get(&OPTION_MESSAGE_TYPE) {
+        Some(&&[message_type]) => message_type,
+        _ => return None,
+    };
+    let mut chaddr = [0u8; 6];
+    chaddr.copy_from_slice(&message[28..34]);
+    Some(Request {
+        message_type,
+        xid: &message[4..8],
+        flags: u16::from_be_bytes([message[10], message[11]]),
+        ciaddr: Ipv4Addr::new(message[12], message[13], message[14], message[15]),
+        chaddr: MacAddress::new(chaddr),
+        options,
+    })
+}
+
+/// the first label of a host name sent by a guest, if it is a valid one
+fn sanitize_hostname(name: &[u8]) -> Option<String> {
+    let label = name.split(|&b| b == b'.').next()?;
+    let valid = !label.is_empty()
+        && label.len() <= 63
+        && label
+            .iter()
+            .all(|b| b.is_ascii_alphanumeric() || *b == b'-')
+        && label[0] != b'-';
+    if valid {
+        Some(String::from_utf8_lossy(label).to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+fn read_leases(path: &Path) -> Result<Vec<Lease>, DhcpError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut lines = contents.lines();
+    if lines.next() != Some(LEASES_VERSION) {
+        return Err(DhcpError::InvalidLeases(1));
+    }
+    let mut leases = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let invalid = || DhcpError::InvalidLeases(i + 2);
+        let fields: Vec<_> = line.split(' ').collect();
+        if fields.len() < 3 || fields.len() > 4 {
+            return Err(invalid());
+        }
+        let expires: u64 = fields[2].parse().map_err(|_| invalid())?;
+        leases.push(Lease {
+            mac: fields[0].parse().map_err(|_| invalid())?,
+            address: fields[1].parse().map_err(|_| invalid())?,
+            expires: UNIX_EPOCH + Duration::from_secs(expires),
+            hostname: match fields.get(3) {
+                Some(name) => Some(sanitize_hostname(name.as_bytes()).ok_or_else(invalid)?),
+                None => None,
+            },
+        });
+    }
+    Ok(leases)
+}
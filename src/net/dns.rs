@@ -0,0 +1,801 @@
+//! DNS server module
+//!
+//! A small DNS server for switched guest networks. Names of VMs are answered
+//! from DHCP leases and static host entries, everything else is forwarded to
+//! an upstream server through the host and cached.
+
+use crate::net::dhcp::DhcpServer;
+use crate::net::mac::MacAddress;
+use crate::net::packet::{arp_reply, UdpDatagram};
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::error;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const DNS_PORT: u16 = 53;
+const HEADER_LEN: usize = 12;
+/// largest upstream reply accepted, enough for EDNS clients
+const MAX_MESSAGE_LEN: usize = 4096;
+/// upper bound for how long upstream answers are cached
+const MAX_CACHE_TTL: u32 = 86400;
+/// queries waiting for the upstream server at the same time
+const MAX_PENDING: usize = 64;
+
+const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_PTR: u16 = 12;
+const TYPE_AAAA: u16 = 28;
+const TYPE_OPT: u16 = 41;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const FLAG_QR: u16 = 0x8000;
+const FLAG_AA: u16 = 0x0400;
+const FLAG_TC: u16 = 0x0200;
+const FLAG_RD: u16 = 0x0100;
+const FLAG_RA: u16 = 0x0080;
+
+const RCODE_NOERROR: u16 = 0;
+const RCODE_FORMERR: u16 = 1;
+const RCODE_SERVFAIL: u16 = 2;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+/// error of `DnsServerBuilder`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DnsError {
+    /// a name with an empty label, a label longer than 63 bytes or more
+    /// than 255 bytes in all
+    InvalidName(String),
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsError::InvalidName(name) => write!(f, "invalid domain name {:?}", name),
+        }
+    }
+}
+
+impl error::Error for DnsError {}
+
+/// builder for DnsServer
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::net::dhcp::DhcpServerBuilder;
+/// # use virtualization_rs::net::dns::DnsServerBuilder;
+/// # use virtualization_rs::net::switch::EthernetSwitchBuilder;
+/// # use std::net::Ipv4Addr;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let switch = EthernetSwitchBuilder::new().build();
+/// let dhcp = DhcpServerBuilder::new(Ipv4Addr::new(192, 168, 64, 1), 24)
+///     .dns_server(Ipv4Addr::new(192, 168, 64, 2))
+///     .domain_name("vm.internal")
+///     .build()?;
+/// let dns = DnsServerBuilder::new(Ipv4Addr::new(192, 168, 64, 2))
+///     .domain("vm.internal")
+///     .leases(&dhcp)
+///     .host("host", Ipv4Addr::new(192, 168, 64, 1))
+///     .upstream("1.1.1.1:53".parse()?)
+///     .build()?;
+/// let (_, socket) = switch.connect()?;
+/// std::thread::spawn(move || dhcp.serve(socket));
+/// let (_, socket) = switch.connect()?;
+/// std::thread::spawn(move || dns.serve(socket));
+/// # Ok(())
+/// # }
+/// ```
+pub struct DnsServerBuilder {
+    address: Ipv4Addr,
+    mac: MacAddress,
+    domain: String,
+    hosts: Vec<(String, IpAddr)>,
+    leases: Option<DhcpServer>,
+    upstream: Option<SocketAddr>,
+    ttl: Duration,
+    timeout: Duration,
+    cache_size: usize,
+}
+
+impl DnsServerBuilder {
+    /// server answering at `address`
+    pub fn new(address: Ipv4Addr) -> Self {
+        DnsServerBuilder {
+            address,
+            mac: MacAddress::for_vm("dns", 0),
+            domain: "vm.internal".to_string(),
+            hosts: Vec::new(),
+            leases: None,
+            upstream: None,
+            ttl: Duration::from_secs(30),
+            timeout: Duration::from_secs(2),
+            cache_size: 1024,
+        }
+    }
+
+    /// address the server sends from, derived from "dns" by default
+    pub fn mac_address(mut self, mac: MacAddress) -> Self {
+        self.mac = mac;
+        self
+    }
+
+    /// domain of the VM names, `vm.internal` by default
+    pub fn domain<T: Into<String>>(mut self, domain: T) -> Self {
+        self.domain = normalize(&domain.into());
+        self
+    }
+
+    /// static entry, names without a dot are relative to the domain
+    pub fn host<T: Into<String>, A: Into<IpAddr>>(mut self, name: T, address: A) -> Self {
+        self.hosts.push((name.into(), address.into()));
+        self
+    }
+
+    /// answers the host names of the active leases of `server`
+    pub fn leases(mut self, server: &DhcpServer) -> Self {
+        self.leases = Some(server.clone());
+        self
+    }
+
+    /// server other names are forwarded to, queries are refused without one
+    pub fn upstream(mut self, upstream: SocketAddr) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    /// TTL of local answers, 30 seconds by default
+    pub fn ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// how long to wait for the upstream server, 2 seconds by default
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// number of cached upstream answers, 1024 by default
+    pub fn cache_size(mut self, cache_size: usize) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    pub fn build(self) -> Result<DnsServer, DnsError> {
+        let domain = self.domain;
+        if !is_valid_name(&domain) {
+            return Err(DnsError::InvalidName(domain));
+        }
+        let mut hosts = Vec::with_capacity(self.hosts.len());
+        for (name, address) in self.hosts {
+            let normalized = normalize(&name);
+            let normalized = if normalized.contains('.') {
+                normalized
+            } else {
+                format!("{}.{}", normalized, domain)
+            };
+            if !is_valid_name(&normalized) {
+                return Err(DnsError::InvalidName(name));
+            }
+            hosts.push((normalized, address));
+        }
+        Ok(DnsServer(Arc::new(Shared {
+            address: self.address,
+            mac: self.mac,
+            domain,
+            hosts,
+            leases: self.leases,
+            upstream: self.upstream,
+            ttl: self.ttl.as_secs().min(u32::MAX as u64) as u32,
+            timeout: self.timeout,
+            cache_size: self.cache_size,
+            cache: Mutex::new(HashMap::new()),
+            pending: AtomicUsize::new(0),
+            ids: RandomState::new(),
+            queries: AtomicUsize::new(0),
+        })))
+    }
+}
+
+/// DNS server for a switched guest network
+///
+/// `<name>.<domain>` is answered with A and AAAA records from the static
+/// hosts and the DHCP leases, and addresses of those names with PTR
+/// records. Other names under the domain do not exist. A single-label name
+/// is answered like `<name>.<domain>` when a host or lease has that name.
+/// The rest is forwarded over UDP, which leaves truncated answers to clients
+/// retrying over TCP, and positive and negative answers are cached for their
+/// TTL.
+#[derive(Clone)]
+pub struct DnsServer(Arc<Shared>);
+
+struct Shared {
+    address: Ipv4Addr,
+    mac: MacAddress,
+    domain: String,
+    hosts: Vec<(String, IpAddr)>,
+    leases: Option<DhcpServer>,
+    upstream: Option<SocketAddr>,
+    ttl: u32,
+    timeout: Duration,
+    cache_size: usize,
+    cache: Mutex<HashMap<CacheKey, CacheEntry>>,
+    pending: AtomicUsize,
+    ids: RandomState,
+    queries: AtomicUsize,
+}
+
+type CacheKey = (String, u16, u16);
+
+struct CacheEntry {
+    reply: Vec<u8>,
+    stored: Instant,
+    expires: Instant,
+    /// offsets and values of the TTL fields in `reply`
+    ttls: Vec<(usize, u32)>,
+}
+
+struct Question {
+    id: u16,
+    flags: u16,
+    /// lowercase, without the trailing dot
+    name: String,
+    qtype: u16,
+    qclass: u16,
+    /// end of the question section
+    end: usize,
+}
+
+enum Answer {
+    Ready(Vec<u8>),
+    Forward,
+}
+
+impl DnsServer {
+    pub fn address(&self) -> Ipv4Addr {
+        self.0.address
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.0.mac
+    }
+
+    /// reply to a DNS query, waiting for the upstream server if needed
+    pub fn handle_query(&self, query: &[u8]) -> Option<Vec<u8>> {
+        match self.0.answer(query)? {
+            Answer::Ready(reply) => Some(reply),
+            Answer::Forward => Some(self.0.forward(query)),
+        }
+    }
+
+    /// reply frame to an Ethernet frame, if any
+    pub fn handle_frame(&self, frame: &[u8]) -> Option<Vec<u8>> {
+        if let Some(reply) = arp_reply(frame, self.0.mac, self.0.address) {
+            return Some(reply);
+        }
+        let (client_mac, client, query) = self.0.query_of(frame)?;
+        let reply = self.handle_query(query)?;
+        Some(self.0.encode(client_mac, client, &reply))
+    }
+
+    /// answers frames from the switch port until the socket is shut down
+    ///
+    /// Forwarded queries are waited for on their own threads.
+    pub fn serve(&self, socket: UnixDatagram) -> io::Result<()> {
+        let socket = Arc::new(socket);
+        let mut frame = vec![0u8; 1 << 16];
+        loop {
+            let n = match socket.recv(&mut frame) {
+                Ok(0) => return Ok(()),
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            if let Some(reply) = arp_reply(&frame[..n], self.0.mac, self.0.address) {
+                send(&socket, &reply)?;
+                continue;
+            }
+            let (client_mac, client, query) = match self.0.query_of(&frame[..n]) {
+                Some(query) => query,
+                None => continue,
+            };
+            let reply = match self.0.answer(query) {
+                Some(Answer::Ready(reply)) => reply,
+                Some(Answer::Forward) if self.0.pending.load(Ordering::Relaxed) < MAX_PENDING => {
+                    self.0.pending.fetch_add(1, Ordering::Relaxed);
+                    let shared = self.0.clone();
+                    let socket = socket.clone();
+                    let query = query.to_vec();
+                    thread::spawn(move || {
+                        let reply = shared.forward(&query);
+                        let _ = send(&socket, &shared.encode(client_mac, client, &reply));
+                        shared.pending.fetch_sub(1, Ordering::Relaxed);
+                    });
+                    continue;
+                }
+                Some(Answer::Forward) => match parse_question(query) {
+                    Ok(question) => self.0.reply(query, &question, RCODE_SERVFAIL, &[]),
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            send(&socket, &self.0.encode(client_mac, client, &reply))?;
+        }
+    }
+
+    /// forgets every cached answer
+    pub fn flush_cache(&self) {
+        self.0.cache.lock().unwrap().clear();
+    }
+}
+
+impl Shared {
+    /// the client and query of a frame for this server
+    fn query_of<'a>(&self, frame: &'a [u8]) -> Option<(MacAddress, SocketAddrV4, &'a [u8])> {
+        let (ethernet, udp) = UdpDatagram::parse_frame(frame)?;
+        if *udp.dst.ip() != self.address || udp.dst.port() != DNS_PORT {
+            return None;
+        }
+        Some((ethernet.src, udp.src, udp.payload))
+    }
+
+    fn encode(&self, client_mac: MacAddress, client: SocketAddrV4, reply: &[u8]) -> Vec<u8> {
+        UdpDatagram {
+            src: SocketAddrV4::new(self.address, DNS_PORT),
+            dst: client,
+            payload: reply,
+        }
+        .encode_frame(self.mac, client_mac)
+    }
+
+    fn answer(&self, query: &[u8]) -> Option<Answer> {
+        let question = match parse_question(query) {
+            Ok(question) => question,
+            Err(rcode) => return header_reply(query, rcode).map(Answer::Ready),
+        };
+        let names = self.names();
+        if let Some(address) = reverse_address(&question.name) {
+            let ptrs: Vec<_> = names
+                .iter()
+                .filter(|(_, a)| *a == address)
+                .map(|(name, _)| encode_name(name))
+                .collect();
+            if !ptrs.is_empty() && question.qclass == CLASS_IN {
+                let records: Vec<_> = match question.qtype {
+                    TYPE_PTR | TYPE_ANY => ptrs.into_iter().map(|p| (TYPE_PTR, p)).collect(),
+                    _ => Vec::new(),
+                };
+                return Some(Answer::Ready(self.reply(
+                    query,
+                    &question,
+                    RCODE_NOERROR,
+                    &records,
+                )));
+            }
+        } else if let Some(name) = self.local_name(&question.name, &names) {
+            let addresses: Vec<_> = names
+                .iter()
+                .filter(|(n, _)| *n == name)
+                .map(|&(_, a)| a)
+                .collect();
+            let rcode = if addresses.is_empty() && name != self.domain {
+                RCODE_NXDOMAIN
+            } else {
+                RCODE_NOERROR
+            };
+            let mut records = Vec::new();
+            for address in addresses {
+                match (address, question.qtype) {
+                    (IpAddr::V4(a), TYPE_A) | (IpAddr::V4(a), TYPE_ANY) => {
+                        records.push((TYPE_A, a.octets().to_vec()))
+                    }
+                    (IpAddr::V6(a), TYPE_AAAA) | (IpAddr::V6(a), TYPE_ANY) => {
+                        records.push((TYPE_AAAA, a.octets().to_vec()))
+                    }
+                    _ => {}
+                }
+            }
+            if question.qclass != CLASS_IN {
+                records.clear();
+            }
+            return Some(Answer::Ready(self.reply(query, &question, rcode, &records)));
+        }
+        match self.cached(query, &question) {
+            Some(reply) => Some(Answer::Ready(reply)),
+            None => Some(Answer::Forward),
+        }
+    }
+
+    /// static hosts followed by the names of active leases, deduplicated
+    fn names(&self) -> Vec<(String, IpAddr)> {
+        let mut names = self.hosts.clone();
+        if let Some(server) = &self.leases {
+            for lease in server.leases() {
+                if let Some(hostname) = lease.hostname {
+                    let name = format!("{}.{}", hostname, self.domain);
+                    // a long host name may not fit under a long domain
+                    if is_valid_name(&name) {
+                        names.push((name, lease.address.into()));
+                    }
+                }
+            }
+        }
+        let mut seen = Vec::new();
+        names.retain(|entry| {
+            let new = !seen.contains(entry);
+            seen.push(entry.clone());
+            new
+        });
+        names
+    }
+
+    /// the fully qualified name if `name` is answered locally
+    fn local_name(&self, name: &str, names: &[(String, IpAddr)]) -> Option<String> {
+        if name.is_empty() {
+            return None;
+        }
+        if !name.contains('.') {
+            let name = format!("{}.{}", name, self.domain);
+            return if names.iter().any(|(n, _)| *n == name) {
+                Some(name)
+            } else {
+                None
+            };
+        }
+        let in_domain = name == self.domain
+            || (name.ends_with(&self.domain)
+                && name[..name.len() - self.domain.len()].ends_with('.'));
+        if in_domain || self.hosts.iter().any(|(n, _)| n == name) {
+            Some(name.to_string())
+        } else {
+            None
+        }
+    }
+
+    fn reply(
+        &self,
+        query: &[u8],
+        question: &Question,
+        rcode: u16,
+        records: &[(u16, Vec<u8>)],
+    ) -> Vec<u8> {
+        let mut flags = FLAG_QR | (question.flags & FLAG_RD) | rcode;
+        if self.upstream.is_some() {
+            flags |= FLAG_RA;
+        }
+        if rcode == RCODE_NOERROR || rcode == RCODE_NXDOMAIN {
+            flags |= FLAG_AA;
+        }
+        let mut reply = Vec::with_capacity(question.end + records.len() * 32);
+        reply.extend_from_slice(&question.id.to_be_bytes());
+        reply.extend_from_slice(&flags.to_be_bytes());
+        reply.extend_from_slice(&1u16.to_be_bytes());
+        reply.extend_from_slice(&(records.len() as u16).to_be_bytes());
+        reply.extend_from_slice(&[0, 0, 0, 0]);
+        reply.extend_from_slice(&query[HEADER_LEN..question.end]);
+        for (rtype, data) in records {
+            // the name is a pointer to the question
+            reply.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+            reply.extend_from_slice(&rtype.to_be_bytes());
+            reply.extend_from_slice(&CLASS_IN.to_be_bytes());
+            reply.extend_from_slice(&self.ttl.to_be_bytes());
+            reply.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            reply.extend_from_slice(data);
+        }
+        reply
+    }
+
+    fn forward(&self, query: &[u8]) -> Vec<u8> {
+        let question = match parse_question(query) {
+            Ok(question) => question,
+            Err(rcode) => return header_reply(query, rcode).unwrap_or_default(),
+        };
+        let upstream = match self.upstream {
+            Some(upstream) => upstream,
+            None => return self.reply(query, &question, RCODE_REFUSED, &[]),
+        };
+        match self.exchange(upstream, query) {
+            Ok(reply) => {
+                self.store(&question, &reply);
+                reply
+            }
+            Err(_) => self.reply(query, &question, RCODE_SERVFAIL, &[]),
+        }
+    }
+
+    /// sends `query` from a fresh port with a fresh ID and waits for the reply
+    fn exchange(&self, upstream: SocketAddr, query: &[u8]) -> io::Result<Vec<u8>> {
+        let local: SocketAddr = match upstream {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(upstream)?;
+        let mut hasher = self.ids.build_hasher();
+        hasher.write_usize(self.queries.fetch_add(1, Ordering::Relaxed));
+        let id = (hasher.finish() as u16).to_be_bytes();
+        let mut request = query.to_vec();
+        request[0..2].copy_from_slice(&id);
+        socket.send(&request)?;
+
+        let deadline = Instant::now() + self.timeout;
+        let mut reply = vec![0u8; MAX_MESSAGE_LEN];
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return Err(io::ErrorKind::TimedOut.into());
+            }
+            socket.set_read_timeout(Some(left))?;
+            let n = match socket.recv(&mut reply) {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Err(io::ErrorKind::TimedOut.into())
+                }
+                Err(e) => return Err(e),
+            };
+            let end = match parse_question(&request) {
+                Ok(question) => question.end,
+                Err(_) => return Err(io::ErrorKind::InvalidInput.into()),
+            };
+            // stray or spoofed replies are ignored
+            if n >= end
+                && reply[0..2] == id
+                && reply[2] & 0x80 != 0
+                && reply[4..6] == [0, 1]
+                && reply[HEADER_LEN..end].eq_ignore_ascii_case(&request[HEADER_LEN..end])
+            {
+                reply.truncate(n);
+                reply[0..2].copy_from_slice(&query[0..2]);
+                return Ok(reply);
+            }
+        }
+    }
+
+    fn cached(&self, query: &[u8], question: &Question) -> Option<Vec<u8>> {
+        let key = (question.name.clone(), question.qtype, question.qclass);
+        let now = Instant::now();
+        let mut cache = self.cache.lock().unwrap();
+        let entry = cache.get(&key)?;
+        if entry.expires <= now {
+            cache.remove(&key);
+            return None;
+        }
+        let elapsed = now.duration_since(entry.stored).as_secs() as u32;
+        let mut reply = entry.reply.clone();
+        reply[0..2].copy_from_slice(&query[0..2]);
+        // same name in the client's spelling
+        reply[HEADER_LEN..question.end].copy_from_slice(&query[HEADER_LEN..question.end]);
+        for &(offset, ttl) in &entry.ttls {
+            reply[offset..offset + 4].copy_from_slice(&ttl.saturating_sub(elapsed).to_be_bytes());
+        }
+        Some(reply)
+    }
+
+    fn store(&self, question: &Question, reply: &[u8]) {
+        if self.cache_size == 0 {
+            return;
+        }
+        let (ttls, ttl) = match cache_ttl(reply, question.end) {
+            Some(found) => found,
+            None => return,
+        };
+        let now = Instant::now();
+        let expires = now + Duration::from_secs(ttl as u64);
+        let key = (question.name.clone(), question.qtype, question.qclass);
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.cache_size && !cache.contains_key(&key) {
+            cache.retain(|_, e| e.expires > now);
+            if cache.len() >= self.cache_size {
+                let soonest = cache
+                    .iter()
+                    .min_by_key(|(_, e)| e.expires)
+                    .map(|(k, _)| k.clone());
+                if let Some(soonest) = soonest {
+                    cache.remove(&soonest);
+                }
+            }
+        }
+        cache.insert(
+            key,
+            CacheEntry {
+                reply: reply.to_vec(),
+                stored: now,
+                expires,
+                ttls,
+            },
+        );
+    }
+}
+
+fn send(socket: &UnixDatagram, frame: &[u8]) -> io::Result<()> {
+    match socket.send(frame) {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => Ok(()),
+        Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+fn be16(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_be_bytes([
+        *data.get(offset)?,
+        *data.get(offset + 1)?,
+    ]))
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// the question of a standard query, or the RCODE to refuse it with
+fn parse_question(query: &[u8]) -> Result<Question, u16> {
+    if query.len() < HEADER_LEN {
+        return Err(RCODE_FORMERR);
+    }
+    let flags = be16(query, 2).unwrap();
+    if flags & FLAG_QR != 0 {
+        return Err(RCODE_FORMERR);
+    }
+    if flags & 0x7800 != 0 {
+        return Err(RCODE_NOTIMP);
+    }
+    if be16(query, 4) != Some(1) {
+        return Err(RCODE_FORMERR);
+    }
+    let mut labels = Vec::new();
+    let mut offset = HEADER_LEN;
+    loop {
+        let len = *query.get(offset).ok_or(RCODE_FORMERR)? as usize;
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        // no compression in questions
+        if len > 63 {
+            return Err(RCODE_FORMERR);
+        }
+        let label = query.get(offset..offset + len).ok_or(RCODE_FORMERR)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        offset += len;
+    }
+    let qtype = be16(query, offset).ok_or(RCODE_FORMERR)?;
+    let qclass = be16(query, offset + 2).ok_or(RCODE_FORMERR)?;
+    Ok(Question {
+        id: be16(query, 0).unwrap(),
+        flags,
+        name: labels.join("."),
+        qtype,
+        qclass,
+        end: offset + 4,
+    })
+}
+
+/// reply without a question to a query that could not be parsed
+fn header_reply(query: &[u8], rcode: u16) -> Option<Vec<u8>> {
+    let flags = be16(query, 2)?;
+    if flags & FLAG_QR != 0 {
+        return None;
+    }
+    let mut reply = vec![0u8; HEADER_LEN];
+    reply[0..2].copy_from_slice(&query[0..2]);
+    let flags = FLAG_QR | (flags & (0x7800 | FLAG_RD)) | rcode;
+    reply[2..4].copy_from_slice(&flags.to_be_bytes());
+    Some(reply)
+}
+
+/// whether `name` can be encoded: labels of 1 to 63 bytes, 255 bytes in all
+fn is_valid_name(name: &str) -> bool {
+    // the encoding adds a length byte before the first label and a root label
+    name.len() + 2 <= 255 && name.split('.').all(|l| !l.is_empty() && l.len() <= 63)
+}
+
+/// `name` must be valid, see `is_valid_name`
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(name.len() + 2);
+    for label in name.split('.') {
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    encoded
+}
+
+/// the address of an `in-addr.arpa` or `ip6.arpa` name
+fn reverse_address(name: &str) -> Option<IpAddr> {
+    if let Some(reversed) = name.strip_suffix(".in-addr.arpa") {
+        let octets: Vec<u8> = reversed
+            .split('.')
+            .rev()
+            .map(|o| o.parse().ok())
+            .collect::<Option<_>>()?;
+        let octets: [u8; 4] = octets.try_into().ok()?;
+        return Some(Ipv4Addr::from(octets).into());
+    }
+    let reversed = name.strip_suffix(".ip6.arpa")?;
+    let nibbles: Vec<u8> = reversed
+        .split('.')
+        .rev()
+        .map(|n| match n.len() {
+            1 => u8::from_str_radix(n, 16).ok(),
+            _ => None,
+        })
+        .collect::<Option<_>>()?;
+    if nibbles.len() != 32 {
+        return None;
+    }
+    let mut octets = [0u8; 16];
+    for (i, pair) in nibbles.chunks(2).enumerate() {
+        octets[i] = pair[0] << 4 | pair[1];
+    }
+    Some(Ipv6Addr::from(octets).into())
+}
+
+fn skip_name(message: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(offset)? as usize;
+        match len {
+            0 => return Some(offset + 1),
+            l if l & 0xc0 == 0xc0 => return Some(offset + 2),
+            l if l > 63 => return None,
+            l => offset += 1 + l,
+        }
+    }
+}
+
+/// TTL fields of an answer worth caching and how long to cache it
+///
+/// Negative answers are cached for the SOA minimum, answers without any
+/// TTL to go by are not cached.
+fn cache_ttl(reply: &[u8], question_end: usize) -> Option<(Vec<(usize, u32)>, u32)> {
+    let flags = be16(reply, 2)?;
+    let rcode = flags & 0x000f;
+    if flags & FLAG_TC != 0 || (rcode != RCODE_NOERROR && rcode != RCODE_NXDOMAIN) {
+        return None;
+    }
+    let answers = be16(reply, 6)? as usize;
+    let authorities = be16(reply, 8)? as usize;
+    let additionals = be16(reply, 10)? as usize;
+    let mut ttls = Vec::new();
+    let mut min_ttl: Option<u32> = None;
+    let mut offset = question_end;
+    for i in 0..answers + authorities + additionals {
+        offset = skip_name(reply, offset)?;
+        let rtype = be16(reply, offset)?;
+        let ttl_offset = offset + 4;
+        let ttl = u32::from_be_bytes(reply.get(ttl_offset..ttl_offset + 4)?.try_into().ok()?);
+        let len = be16(reply, offset + 8)? as usize;
+        let data = reply.get(offset + 10..offset + 10 + len)?;
+        offset += 10 + len;
+        if rtype == TYPE_OPT {
+            continue;
+        }
+        ttls.push((ttl_offset, ttl));
+        let section_ttl = if i < answers {
+            Some(ttl)
+        } else if i < answers + authorities && rtype == TYPE_SOA && len >= 4 {
+            let minimum = u32::from_be_bytes(data[len - 4..].try_into().ok()?);
+            Some(ttl.min(minimum))
+        } else {
+            None
+        };
+        if let Some(ttl) = section_ttl {
+            min_ttl = Some(min_ttl.map_or(ttl, |m| m.min(ttl)));
+        }
+    }
+    match min_ttl {
+        Some(0) | None => None,
+        Some(ttl) => Some((ttls, ttl.min(MAX_CACHE_TTL))),
+    }
+}
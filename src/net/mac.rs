@@ -0,0 +1,217 @@
+//! MAC address module
+
+use std::error;
+use std::fmt;
+use std::str::FromStr;
+
+use sha2::{Digest, Sha256};
+
+/// notation used to format a `MacAddress`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MacNotation {
+    /// `52:54:00:12:34:56`
+    #[default]
+    Colon,
+    /// `52-54-00-12-34-56`
+    Hyphen,
+    /// `5254.0012.3456`
+    Dotted,
+    /// `525400123456`
+    Bare,
+}
+
+/// error of `MacAddress::from_str` and of converting to a `VZMACAddress`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseMacAddressError(pub(crate) String);
+
+impl fmt::Display for ParseMacAddressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid MAC address `{}`", self.0)
+    }
+}
+
+impl error::Error for ParseMacAddressError {}
+
+/// 48-bit IEEE 802 MAC address
+///
+/// Parses the colon, hyphen, dotted and bare hexadecimal notations and
+/// displays in lowercase colon notation, which is what `VZMACAddress` uses.
+///
+/// ```rust
+/// # use virtualization_rs::net::mac::{MacAddress, MacNotation};
+/// let mac: MacAddress = "52-54-00-AB-CD-EF".parse().unwrap();
+/// assert_eq!(mac.to_string(), "52:54:00:ab:cd:ef");
+/// assert_eq!(mac.format(MacNotation::Dotted), "5254.00ab.cdef");
+/// assert!(mac.is_unicast() && mac.is_locally_administered());
+/// ```
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MacAddress([u8; 6]);
+
+impl MacAddress {
+    /// `ff:ff:ff:ff:ff:ff`
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    pub const fn new(octets: [u8; 6]) -> MacAddress {
+        MacAddress(octets)
+    }
+
+    /// stable locally administered unicast address of a VM's NIC
+    ///
+    /// The same VM name and NIC index always give the same address, so DHCP
+    /// reservations and neighbour caches survive re-creating the VM. The 46
+    /// free bits come from a SHA-256 of both, which makes collisions between
+    /// VMs on one host unlikely.
+    ///
+    /// ```rust
+    /// # use virtualization_rs::net::mac::MacAddress;
+    /// let mac = MacAddress::for_vm("db", 0);
+    /// assert_eq!(mac, MacAddress::for_vm("db", 0));
+    /// assert_ne!(mac, MacAddress::for_vm("db", 1));
+    /// assert!(mac.is_unicast() && mac.is_locally_administered());
+    /// ```
+    pub fn for_vm(name: &str, nic: u32) -> MacAddress {
+        let mut hasher = Sha256::new();
+        hasher.update(b"virtualization-rs mac\0");
+        hasher.update(name.as_bytes());
+        hasher.update([0]);
+        hasher.update(nic.to_le_bytes());
+        let digest = hasher.finalize();
+        let mut octets = [0u8; 6];
+        octets.copy_from_slice(&digest[..6]);
+        octets[0] = (octets[0] | 0x02) & !0x01;
+        MacAddress(octets)
+    }
+
+    pub const fn octets(&self) -> [u8; 6] {
+        self.0
+    }
+
+    /// the first three octets, the OUI of universally administered addresses
+    pub fn oui(&self) -> [u8; 3] {
+        [self.0[0], self.0[1], self.0[2]]
+    }
+
+    /// whether the I/G bit is clear
+    pub const fn is_unicast(&self) -> bool {
+        self.0[0] & 0x01 == 0
+    }
+
+    /// whether the I/G bit is set, which includes the broadcast address
+    pub const fn is_multicast(&self) -> bool {
+        !self.is_unicast()
+    }
+
+    pub fn is_broadcast(&self) -> bool {
+        *self == MacAddress::BROADCAST
+    }
+
+    /// whether the U/L bit is set
+    pub const fn is_locally_administered(&self) -> bool {
+        self.0[0] & 0x02 != 0
+    }
+
+    /// whether the U/L bit is clear, i.e. the address belongs to an OUI
+    pub const fn is_universally_administered(&self) -> bool {
+        !self.is_locally_administered()
+    }
+
+    /// formats in the given notation, always in lowercase
+    pub fn format(&self, notation: MacNotation) -> String {
+        let o = &self.0;
+        match notation {
+            MacNotation::Colon => self.to_string(),
+            MacNotation::Hyphen => format!(
+                "{:02x}-{:02x}-{:02x}-{:02x}-{:02x}-{:02x}",
+                o[0], o[1], o[2], o[3], o[4], o[5]
+            ),
+            MacNotation::Dotted => format!(
+                "{:02x}{:02x}.{:02x}{:02x}.{:02x}{:02x}",
+                o[0], o[1], o[2], o[3], o[4], o[5]
+            ),
+            MacNotation::Bare => format!(
+                "{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+                o[0], o[1], o[2], o[3], o[4], o[5]
+            ),
+        }
+    }
+}
+
+fn parse_groups(s: &str, sep: char, count: usize, width: usize) -> Option<[u8; 6]> {
+    let mut hex = String::with_capacity(12);
+    let mut groups = 0;
+    for group in s.split(sep) {
+        // macOS tools print colon notation without leading zeros
+        let short = sep == ':' && group.len() == 1;
+        if !(group.len() == width || short) || !group.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        if short {
+            hex.push('0');
+        }
+        hex.push_str(group);
+        groups += 1;
+    }
+    if groups != count {
+        return None;
+    }
+    parse_bare(&hex)
+}
+
+fn parse_bare(s: &str) -> Option<[u8; 6]> {
+    if s.len() != 12 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let mut octets = [0u8; 6];
+    for (i, octet) in octets.iter_mut().enumerate() {
+        *octet = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(octets)
+}
+
+impl FromStr for MacAddress {
+    type Err = ParseMacAddressError;
+
+    fn from_str(s: &str) -> Result<MacAddress, ParseMacAddressError> {
+        let octets = if s.contains(':') {
+            parse_groups(s, ':', 6, 2)
+        } else if s.contains('-') {
+            parse_groups(s, '-', 6, 2)
+        } else if s.contains('.') {
+            parse_groups(s, '.', 3, 4)
+        } else {
+            parse_bare(s)
+        };
+        octets
+            .map(MacAddress)
+            .ok_or_else(|| ParseMacAddressError(s.to_string()))
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let o = &self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            o[0], o[1], o[2], o[3], o[4], o[5]
+        )
+    }
+}
+
+impl fmt::Debug for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MacAddress({})", self)
+    }
+}
+
+impl From<[u8; 6]> for MacAddress {
+    fn from(octets: [u8; 6]) -> MacAddress {
+        MacAddress(octets)
+    }
+}
+
+impl From<MacAddress> for [u8; 6] {
+    fn from(mac: MacAddress) -> [u8; 6] {
+        mac.0
+    }
+}
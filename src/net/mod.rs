@@ -0,0 +1,9 @@
+//! network module
+//!
+//! Pure Rust helpers for the networks virtual machines are attached to.
+
+pub mod dhcp;
+pub mod dns;
+pub mod mac;
+pub mod packet;
+pub mod switch;
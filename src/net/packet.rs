@@ -0,0 +1,288 @@
+//! packet module
+//!
+//! Just enough Ethernet, ARP, IPv4 and UDP to run services such as DHCP
+//! directly on a switch port. IPv4 fragments and VLAN tags are not handled.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+
+use crate::net::mac::MacAddress;
+
+pub const ETHERTYPE_IPV4: u16 = 0x0800;
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+pub const IPPROTO_UDP: u8 = 17;
+
+const IPV4_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+const ARP_LEN: usize = 28;
+const ARP_REQUEST: u16 = 1;
+const ARP_REPLY: u16 = 2;
+
+fn be16(data: &[u8], offset: usize) -> u16 {
+    u16::from_be_bytes([data[offset], data[offset + 1]])
+}
+
+fn mac_at(data: &[u8], offset: usize) -> MacAddress {
+    let mut octets = [0u8; 6];
+    octets.copy_from_slice(&data[offset..offset + 6]);
+    MacAddress::new(octets)
+}
+
+fn ipv4_at(data: &[u8], offset: usize) -> Ipv4Addr {
+    Ipv4Addr::new(
+        data[offset],
+        data[offset + 1],
+        data[offset + 2],
+        data[offset + 3],
+    )
+}
+
+fn sum(data: &[u8], mut acc: u32) -> u32 {
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        acc += u16::from_be_bytes([chunk[0], chunk[1]]) as u32;
+    }
+    if let [last] = chunks.remainder() {
+        acc += (*last as u32) << 8;
+    }
+    acc
+}
+
+fn fold(mut acc: u32) -> u16 {
+    while acc > 0xffff {
+        acc = (acc & 0xffff) + (acc >> 16);
+    }
+    !(acc as u16)
+}
+
+fn udp_checksum(src: Ipv4Addr, dst: Ipv4Addr, datagram: &[u8]) -> u16 {
+    let mut acc = sum(&src.octets(), 0);
+    acc = sum(&dst.octets(), acc);
+    acc += IPPROTO_UDP as u32 + datagram.len() as u32;
+    match fold(sum(datagram, acc)) {
+        // zero means no checksum, so a computed zero is sent as all ones
+        0 => 0xffff,
+        checksum => checksum,
+    }
+}
+
+/// Ethernet II frame without the FCS
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EthernetFrame<'a> {
+    pub dst: MacAddress,
+    pub src: MacAddress,
+    pub ethertype: u16,
+    pub payload: &'a [u8],
+}
+
+impl<'a> EthernetFrame<'a> {
+    pub fn parse(frame: &'a [u8]) -> Option<EthernetFrame<'a>> {
+        if frame.len() < 14 {
+            return None;
+        }
+        Some(EthernetFrame {
+            dst: mac_at(frame, 0),
+            src: mac_at(frame, 6),
+            ethertype: be16(frame, 12),
+            payload: &frame[14..],
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(14 + self.payload.len());
+        frame.extend_from_slice(&self.dst.octets());
+        frame.extend_from_slice(&self.src.octets());
+        frame.extend_from_slice(&self.ethertype.to_be_bytes());
+        frame.extend_from_slice(self.payload);
+        frame
+    }
+}
+
+/// unfragmented IPv4 packet with a valid header checksum
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Packet<'a> {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub protocol: u8,
+    pub ttl: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Ipv4Packet<'a> {
+    pub fn parse(packet: &'a [u8]) -> Option<Ipv4Packet<'a>> {
+        if packet.len() < IPV4_HEADER_LEN || packet[0] >> 4 != 4 {
+            return None;
+        }
+        let header_len = (packet[0] & 0x0f) as usize * 4;
+        let total_len = be16(packet, 2) as usize;
+        let fragment = be16(packet, 6);
+        if header_len < IPV4_HEADER_LEN
+            || total_len < header_len
+            || total_len > packet.len()
+            || fragment & 0x3fff != 0
+            || fold(sum(&packet[..header_len], 0)) != 0
+        {
+            return None;
+        }
+        Some(Ipv4Packet {
+            src: ipv4_at(packet, 12),
+            dst: ipv4_at(packet, 16),
+            protocol: packet[9],
+            ttl: packet[8],
+            payload: &packet[header_len..total_len],
+        })
+    }
+
+    /// encodes with the don't fragment bit set
+    pub fn encode(&self) -> Vec<u8> {
+        let total_len = IPV4_HEADER_LEN + self.payload.len();
+        let mut packet = Vec::with_capacity(total_len);
+        packet.extend_from_slice(&[0x45, 0]);
+        packet.extend_from_slice(&(total_len as u16).to_be_bytes());
+        packet.extend_from_slice(&[0, 0, 0x40, 0, self.ttl, self.protocol, 0, 0]);
+        packet.extend_from_slice(&self.src.octets());
+        packet.extend_from_slice(&self.dst.octets());
+        let checksum = fold(sum(&packet, 0));
+        packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+        packet.extend_from_slice(self.payload);
+        packet
+    }
+}
+
+/// UDP datagram whose checksum, if present, is valid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UdpDatagram<'a> {
+    pub src: SocketAddrV4,
+    pub dst: SocketAddrV4,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UdpDatagram<'a> {
+    pub fn parse(packet: &Ipv4Packet<'a>) -> Option<UdpDatagram<'a>> {
+        let datagram = packet.payload;
+        if packet.protocol != IPPROTO_UDP || datagram.len() < UDP_HEADER_LEN {
+            return None;
+        }
+        let len = be16(datagram, 4) as usize;
+        if len < UDP_HEADER_LEN || len > datagram.len() {
+            return None;
+        }
+        let datagram = &datagram[..len];
+        if be16(datagram, 6) != 0 {
+            let mut acc = sum(&packet.src.octets(), 0);
+            acc = sum(&packet.dst.octets(), acc);
+            acc += IPPROTO_UDP as u32 + len as u32;
+            if fold(sum(datagram, acc)) != 0 {
+                return None;
+            }
+        }
+        Some(UdpDatagram {
+            src: SocketAddrV4::new(packet.src, be16(datagram, 0)),
+            dst: SocketAddrV4::new(packet.dst, be16(datagram, 2)),
+            payload: &datagram[UDP_HEADER_LEN..],
+        })
+    }
+
+    /// parses a whole Ethernet frame carrying a UDP datagram
+    pub fn parse_frame(frame: &'a [u8]) -> Option<(EthernetFrame<'a>, UdpDatagram<'a>)> {
+        let ethernet = EthernetFrame::parse(frame)?;
+        if ethernet.ethertype != ETHERTYPE_IPV4 {
+            return None;
+        }
+        let packet = Ipv4Packet::parse(ethernet.payload)?;
+        Some((ethernet, UdpDatagram::parse(&packet)?))
+    }
+
+    /// encodes as an Ethernet frame from `src_mac` to `dst_mac`
+    pub fn encode_frame(&self, src_mac: MacAddress, dst_mac: MacAddress) -> Vec<u8> {
+        let len = UDP_HEADER_LEN + self.payload.len();
+        let mut datagram = Vec::with_capacity(len);
+        datagram.extend_from_slice(&self.src.port().to_be_bytes());
+        datagram.extend_from_slice(&self.dst.port().to_be_bytes());
+        datagram.extend_from_slice(&(len as u16).to_be_bytes());
+        datagram.extend_from_slice(&[0, 0]);
+        datagram.extend_from_slice(self.payload);
+        let checksum = udp_checksum(*self.src.ip(), *self.dst.ip(), &datagram);
+        datagram[6..8].copy_from_slice(&checksum.to_be_bytes());
+        let packet = Ipv4Packet {
+            src: *self.src.ip(),
+            dst: *self.dst.ip(),
+            protocol: IPPROTO_UDP,
+            ttl: 64,
+            payload: &datagram,
+        }
+        .encode();
+        EthernetFrame {
+            dst: dst_mac,
+            src: src_mac,
+            ethertype: ETHERTYPE_IPV4,
+            payload: &packet,
+        }
+        .encode()
+    }
+}
+
+/// ARP packet for IPv4 over Ethernet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpPacket {
+    /// 1 for requests, 2 for replies
+    pub operation: u16,
+    pub sender_mac: MacAddress,
+    pub sender_ip: Ipv4Addr,
+    pub target_mac: MacAddress,
+    pub target_ip: Ipv4Addr,
+}
+
+impl ArpPacket {
+    pub fn parse(packet: &[u8]) -> Option<ArpPacket> {
+        if packet.len() < ARP_LEN || packet[0..6] != [0, 1, 8, 0, 6, 4] {
+            return None;
+        }
+        Some(ArpPacket {
+            operation: be16(packet, 6),
+            sender_mac: mac_at(packet, 8),
+            sender_ip: ipv4_at(packet, 14),
+            target_mac: mac_at(packet, 18),
+            target_ip: ipv4_at(packet, 24),
+        })
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut packet = Vec::with_capacity(ARP_LEN);
+        packet.extend_from_slice(&[0, 1, 8, 0, 6, 4]);
+        packet.extend_from_slice(&self.operation.to_be_bytes());
+        packet.extend_from_slice(&self.sender_mac.octets());
+        packet.extend_from_slice(&self.sender_ip.octets());
+        packet.extend_from_slice(&self.target_mac.octets());
+        packet.extend_from_slice(&self.target_ip.octets());
+        packet
+    }
+}
+
+/// reply frame to an ARP request for `ip` in `frame`, if it is one
+pub fn arp_reply(frame: &[u8], mac: MacAddress, ip: Ipv4Addr) -> Option<Vec<u8>> {
+    let ethernet = EthernetFrame::parse(frame)?;
+    if ethernet.ethertype != ETHERTYPE_ARP {
+        return None;
+    }
+    let request = ArpPacket::parse(ethernet.payload)?;
+    if request.operation != ARP_REQUEST || request.target_ip != ip {
+        return None;
+    }
+    let reply = ArpPacket {
+        operation: ARP_REPLY,
+        sender_mac: mac,
+        sender_ip: ip,
+        target_mac: request.sender_mac,
+        target_ip: request.sender_ip,
+    }
+    .encode();
+    Some(
+        EthernetFrame {
+            dst: request.sender_mac,
+            src: mac,
+            ethertype: ETHERTYPE_ARP,
+            payload: &reply,
+        }
+        .encode(),
+    )
+}
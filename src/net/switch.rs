@@ -0,0 +1,415 @@
+//! learning Ethernet switch module
+//!
+//! Connects the NICs of several virtual machines into a private layer 2
+//! network. Every port is a datagram socket carrying one Ethernet frame per
+//! datagram, which is what `VZFileHandleNetworkDeviceAttachment` expects.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::net::mac::MacAddress;
+
+/// destination, source and EtherType
+pub const ETHERNET_HEADER_LEN: usize = 14;
+/// room for one 802.1Q tag on top of the MTU
+const VLAN_TAG_LEN: usize = 4;
+
+// sizes recommended for VZFileHandleNetworkDeviceAttachment sockets
+const SOCKET_SEND_BUFFER: libc::c_int = 1 << 20;
+const SOCKET_RECEIVE_BUFFER: libc::c_int = 4 << 20;
+
+#[cfg(target_vendor = "apple")]
+const SEND_FLAGS: libc::c_int = libc::MSG_DONTWAIT;
+#[cfg(not(target_vendor = "apple"))]
+const SEND_FLAGS: libc::c_int = libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL;
+
+/// identifier of a switch port, never reused by the same switch
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PortId(u32);
+
+impl fmt::Display for PortId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "port {}", self.0)
+    }
+}
+
+/// counters of a switch port, seen from the switch
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PortStats {
+    /// frames received from the port
+    pub rx_frames: u64,
+    pub rx_bytes: u64,
+    /// frames delivered to the port
+    pub tx_frames: u64,
+    pub tx_bytes: u64,
+    /// frames for the port dropped because its socket was full or gone
+    pub dropped: u64,
+    /// runt and oversized frames received from the port
+    pub errors: u64,
+}
+
+/// entry of the MAC address table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacEntry {
+    pub mac: MacAddress,
+    pub port: PortId,
+    /// time since a frame from `mac` was last seen
+    pub age: Duration,
+}
+
+/// builder for EthernetSwitch
+pub struct EthernetSwitchBuilder {
+    aging_time: Duration,
+    mtu: usize,
+    max_addresses: usize,
+}
+
+impl EthernetSwitchBuilder {
+    pub fn new() -> Self {
+        EthernetSwitchBuilder {
+            aging_time: Duration::from_secs(300),
+            mtu: 1500,
+            max_addresses: 4096,
+        }
+    }
+
+    /// how long a learned address is kept without seeing frames from it,
+    /// 300 seconds by default
+    pub fn aging_time(mut self, aging_time: Duration) -> Self {
+        self.aging_time = aging_time;
+        self
+    }
+
+    /// largest payload forwarded, which should match the MTU of the
+    /// attachments, 1500 by default
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// size of the MAC address table, 4096 by default
+    ///
+    /// Addresses seen while the table is full are not learned, so frames to
+    /// them keep being flooded.
+    pub fn max_addresses(mut self, max_addresses: usize) -> Self {
+        self.max_addresses = max_addresses;
+        self
+    }
+
+    pub fn build(self) -> EthernetSwitch {
+        EthernetSwitch(Arc::new(Shared {
+            aging_time: self.aging_time,
+            max_frame: self.mtu + ETHERNET_HEADER_LEN + VLAN_TAG_LEN,
+            max_addresses: self.max_addresses,
+            next_port: AtomicU32::new(0),
+            ports: RwLock::new(Vec::new()),
+            table: Mutex::new(HashMap::new()),
+        }))
+    }
+}
+
+impl Default for EthernetSwitchBuilder {
+    fn default() -> Self {
+        EthernetSwitchBuilder::new()
+    }
+}
+
+/// learning Ethernet switch
+///
+/// Frames to learned unicast addresses go to their port only; broadcast,
+/// multicast and unknown unicast frames are flooded to every other port.
+/// Each port is read on its own thread. Dropping the last handle to the
+/// switch disconnects all ports.
+///
+/// ```rust,no_run
+/// # use virtualization_rs::net::switch::EthernetSwitchBuilder;
+/// # fn main() -> std::io::Result<()> {
+/// let switch = EthernetSwitchBuilder::new().build();
+/// // hand each socket to a VZFileHandleNetworkDeviceAttachment
+/// let (_web, web_socket) = switch.connect()?;
+/// let (_db, db_socket) = switch.connect()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct EthernetSwitch(Arc<Shared>);
+
+struct Shared {
+    aging_time: Duration,
+    max_frame: usize,
+    max_addresses: usize,
+    next_port: AtomicU32,
+    ports: RwLock<Vec<Arc<Port>>>,
+    table: Mutex<HashMap<MacAddress, (PortId, Instant)>>,
+}
+
+struct Port {
+    id: PortId,
+    socket: UnixDatagram,
+    closed: AtomicBool,
+    rx_frames: AtomicU64,
+    rx_bytes: AtomicU64,
+    tx_frames: AtomicU64,
+    tx_bytes: AtomicU64,
+    dropped: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl EthernetSwitch {
+    /// creates a port and returns the other end of its socket pair
+    pub fn connect(&self) -> io::Result<(PortId, UnixDatagram)> {
+        let (ours, theirs) = UnixDatagram::pair()?;
+        set_buffer_sizes(&theirs)?;
+        Ok((self.attach(ours)?, theirs))
+    }
+
+    /// creates a port on a datagram socket connected to a NIC
+    pub fn attach(&self, socket: UnixDatagram) -> io::Result<PortId> {
+        set_buffer_sizes(&socket)?;
+        #[cfg(target_vendor = "apple")]
+        setsockopt(&socket, libc::SO_NOSIGPIPE, 1)?;
+        let id = PortId(self.0.next_port.fetch_add(1, Ordering::Relaxed));
+        let port = Arc::new(Port {
+            id,
+            socket,
+            closed: AtomicBool::new(false),
+            rx_frames: AtomicU64::new(0),
+            rx_bytes: AtomicU64::new(0),
+            tx_frames: AtomicU64::new(0),
+            tx_bytes: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        });
+        // frames the reader forwards must find the port it learns them on
+        self.0.ports.write().unwrap().push(port.clone());
+        let shared = Arc::downgrade(&self.0);
+        let max_frame = self.0.max_frame;
+        let spawned = thread::Builder::new()
+            .name(format!("switch {}", id))
+            .spawn(move || read_port(shared, port, max_frame));
+        if let Err(err) = spawned {
+            self.0.disconnect(id);
+            return Err(err);
+        }
+        Ok(id)
+    }
+
+    /// removes a port, returns whether it was connected
+    pub fn disconnect(&self, port: PortId) -> bool {
+        self.0.disconnect(port)
+    }
+
+    pub fn ports(&self) -> Vec<PortId> {
+        self.0.ports.read().unwrap().iter().map(|p| p.id).collect()
+    }
+
+    pub fn port_stats(&self, port: PortId) -> Option<PortStats> {
+        let ports = self.0.ports.read().unwrap();
+        let port = ports.iter().find(|p| p.id == port)?;
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        Some(PortStats {
+            rx_frames: load(&port.rx_frames),
+            rx_bytes: load(&port.rx_bytes),
+            tx_frames: load(&port.tx_frames),
+            tx_bytes: load(&port.tx_bytes),
+            dropped: load(&port.dropped),
+            errors: load(&port.errors),
+        })
+    }
+
+    /// learned addresses that have not aged out, sorted by address
+    pub fn mac_table(&self) -> Vec<MacEntry> {
+        let now = Instant::now();
+        let table = self.0.table.lock().unwrap();
+        let mut entries: Vec<_> = table
+            .iter()
+            .map(|(&mac, &(port, seen))| MacEntry {
+                mac,
+                port,
+                age: now.duration_since(seen),
+            })
+            .filter(|e| e.age <= self.0.aging_time)
+            .collect();
+        entries.sort_by_key(|e| e.mac);
+        entries
+    }
+
+    /// forgets every learned address
+    pub fn flush_mac_table(&self) {
+        self.0.table.lock().unwrap().clear();
+    }
+}
+
+impl Shared {
+    fn forward(&self, ingress: &Port, frame: &[u8]) {
+        ingress.rx_frames.fetch_add(1, Ordering::Relaxed);
+        ingress
+            .rx_bytes
+            .fetch_add(frame.len() as u64, Ordering::Relaxed);
+        if frame.len() < ETHERNET_HEADER_LEN || frame.len() > self.max_frame {
+            ingress.errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut octets = [0u8; 6];
+        octets.copy_from_slice(&frame[0..6]);
+        let dst = MacAddress::new(octets);
+        octets.copy_from_slice(&frame[6..12]);
+        let src = MacAddress::new(octets);
+
+        let egress = {
+            let now = Instant::now();
+            let mut table = self.table.lock().unwrap();
+            if src.is_unicast() {
+                self.learn(&mut table, src, ingress.id, now);
+            }
+            match table.get(&dst) {
+                Some(&(port, seen)) if now.duration_since(seen) <= self.aging_time => Some(port),
+                Some(_) => {
+                    table.remove(&dst);
+                    None
+                }
+                None => None,
+            }
+        };
+
+        let mut gone = Vec::new();
+        {
+            let ports = self.ports.read().unwrap();
+            let targets = ports.iter().filter(|p| match egress {
+                Some(port) => p.id == port,
+                None => p.id != ingress.id,
+            });
+            for port in targets {
+                // frames for the port they came from are filtered
+                if port.id != ingress.id && !port.send(frame) {
+                    gone.push(port.id);
+                }
+            }
+        }
+        for port in gone {
+            self.disconnect(port);
+        }
+    }
+
+    fn learn(
+        &self,
+        table: &mut HashMap<MacAddress, (PortId, Instant)>,
+        mac: MacAddress,
+        port: PortId,
+        now: Instant,
+    ) {
+        if table.len() >= self.max_addresses && !table.contains_key(&mac) {
+            let aging_time = self.aging_time;
+            table.retain(|_, &mut (_, seen)| now.duration_since(seen) <= aging_time);
+            if table.len() >= self.max_addresses {
+                return;
+            }
+        }
+        table.insert(mac, (port, now));
+    }
+
+    fn disconnect(&self, id: PortId) -> bool {
+        let port = {
+            let mut ports = self.ports.write().unwrap();
+            match ports.iter().position(|p| p.id == id) {
+                Some(i) => ports.remove(i),
+                None => return false,
+            }
+        };
+        port.close();
+        self.table.lock().unwrap().retain(|_, &mut (p, _)| p != id);
+        true
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        for port in self.ports.get_mut().unwrap().drain(..) {
+            port.close();
+        }
+    }
+}
+
+impl Port {
+    /// returns false once the peer is gone
+    fn send(&self, frame: &[u8]) -> bool {
+        let n = unsafe {
+            libc::send(
+                self.socket.as_raw_fd(),
+                frame.as_ptr() as *const libc::c_void,
+                frame.len(),
+                SEND_FLAGS,
+            )
+        };
+        if n >= 0 {
+            self.tx_frames.fetch_add(1, Ordering::Relaxed);
+            self.tx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+            return true;
+        }
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+        let err = io::Error::last_os_error();
+        !matches!(
+            err.raw_os_error(),
+            Some(libc::ECONNREFUSED) | Some(libc::ENOTCONN) | Some(libc::EPIPE)
+        )
+    }
+
+    /// wakes the reader, which then exits
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        let _ = self.socket.shutdown(Shutdown::Both);
+    }
+}
+
+fn read_port(switch: Weak<Shared>, port: Arc<Port>, max_frame: usize) {
+    // one spare byte tells oversized frames apart from full-sized ones
+    let mut frame = vec![0u8; max_frame + 1];
+    loop {
+        let n = match port.socket.recv(&mut frame) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(_) => break,
+        };
+        // frames are never empty, reading nothing means the socket is shut down
+        if n == 0 || port.closed.load(Ordering::Relaxed) {
+            break;
+        }
+        match switch.upgrade() {
+            Some(shared) => shared.forward(&port, &frame[..n]),
+            None => break,
+        }
+    }
+    if let Some(shared) = switch.upgrade() {
+        shared.disconnect(port.id);
+    }
+}
+
+fn set_buffer_sizes(socket: &UnixDatagram) -> io::Result<()> {
+    setsockopt(socket, libc::SO_SNDBUF, SOCKET_SEND_BUFFER)?;
+    setsockopt(socket, libc::SO_RCVBUF, SOCKET_RECEIVE_BUFFER)
+}
+
+fn setsockopt(socket: &UnixDatagram, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
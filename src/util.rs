@@ -0,0 +1,94 @@
+//! helpers shared by the modules of the crate
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// unique hidden name next to `path`, `.{file}.{pid}.{counter}.tmp`, for a
+/// file or directory that is renamed into place once complete
+pub(crate) fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(
+        ".{}.{}.tmp",
+        process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    path.with_file_name(name)
+}
+
+/// replaces `path` with the file `write` creates at the temporary path it
+/// is given, so that readers never see a partial file
+///
+/// The temporary file is synced and renamed over `path`, then the directory
+/// is synced so that the rename itself survives a crash. The temporary file
+/// is removed when anything fails.
+pub(crate) fn replace_with<T, E, F>(path: &Path, write: F) -> Result<T, E>
+where
+    E: From<io::Error>,
+    F: FnOnce(&Path) -> Result<T, E>,
+{
+    persist(path, write, |tmp| fs::rename(tmp, path))
+}
+
+/// like `replace_with`, but never replaces an existing `path`
+///
+/// The file is hard linked into place, so this fails with
+/// `io::ErrorKind::AlreadyExists` when `path` appeared in the meantime.
+pub(crate) fn create_with<T, E, F>(path: &Path, write: F) -> Result<T, E>
+where
+    E: From<io::Error>,
+    F: FnOnce(&Path) -> Result<T, E>,
+{
+    persist(path, write, |tmp| {
+        fs::hard_link(tmp, path)?;
+        // the file is in place, a leftover name is only cosmetic
+        let _ = fs::remove_file(tmp);
+        Ok(())
+    })
+}
+
+/// replaces `path` with `contents`, see `replace_with`
+pub(crate) fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    replace_with(path, |tmp| {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(tmp)?
+            .write_all(contents)
+    })
+}
+
+fn persist<T, E, F, M>(path: &Path, write: F, place: M) -> Result<T, E>
+where
+    E: From<io::Error>,
+    F: FnOnce(&Path) -> Result<T, E>,
+    M: FnOnce(&Path) -> io::Result<()>,
+{
+    let tmp = tmp_path(path);
+    let result = write(&tmp).and_then(|value| {
+        File::open(&tmp)?.sync_all()?;
+        place(&tmp)?;
+        Ok(value)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+        return result;
+    }
+    sync_parent(path)?;
+    result
+}
+
+/// syncs the directory of `path` so that renames and links in it persist
+pub(crate) fn sync_parent(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
@@ -1,8 +1,15 @@
 //! network device module
 
-use crate::base::{Id, NSString};
+use crate::base::{Id, NSFileHandle, NSString, NIL};
+pub use crate::net::mac::{MacAddress, ParseMacAddressError};
+
+use std::convert::TryFrom;
+use std::io;
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixDatagram;
 
 use objc::rc::StrongPtr;
+use objc::runtime::{BOOL, NO};
 use objc::{class, msg_send, sel, sel_impl};
 
 /// common behaviors for network device attachment
@@ -28,6 +35,83 @@ impl VZNetworkDeviceAttachment for VZNATNetworkDeviceAttachment {
     }
 }
 
+/// configure of network device attachment over a datagram socket
+///
+/// Every datagram carries one Ethernet frame. Pairs well with
+/// `EthernetSwitch` to build private networks between VMs.
+/// # Examples
+/// ```rust,no_run
+/// # use virtualization_rs::net::switch::EthernetSwitchBuilder;
+/// # use virtualization_rs::virtualization::network_device::*;
+/// # use std::convert::TryFrom;
+/// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+/// let switch = EthernetSwitchBuilder::new().build();
+/// let (_port, socket) = switch.connect()?;
+/// let attachment = VZFileHandleNetworkDeviceAttachment::from_socket(socket);
+/// let mut network_device = VZVirtioNetworkDeviceConfiguration::new(attachment);
+/// network_device.set_mac_address(VZMACAddress::try_from(MacAddress::for_vm("web", 0))?);
+/// # Ok(())
+/// # }
+/// ```
+pub struct VZFileHandleNetworkDeviceAttachment(StrongPtr);
+
+impl VZFileHandleNetworkDeviceAttachment {
+    pub fn new(file_handle: NSFileHandle) -> VZFileHandleNetworkDeviceAttachment {
+        unsafe {
+            let obj: Id = msg_send![class!(VZFileHandleNetworkDeviceAttachment), alloc];
+            let p = StrongPtr::new(msg_send![obj, initWithFileHandle:*file_handle.0]);
+            VZFileHandleNetworkDeviceAttachment(p)
+        }
+    }
+
+    /// takes ownership of a connected datagram socket
+    pub fn from_socket(socket: UnixDatagram) -> VZFileHandleNetworkDeviceAttachment {
+        let file_handle = NSFileHandle::init_with_file_descriptor(socket.into_raw_fd(), true);
+        VZFileHandleNetworkDeviceAttachment::new(file_handle)
+    }
+
+    /// 1500 before macOS 13, where it cannot be changed
+    pub fn maximum_transmission_unit(&self) -> usize {
+        if !Self::supports_maximum_transmission_unit() {
+            return 1500;
+        }
+        let mtu: isize = unsafe { msg_send![*self.0, maximumTransmissionUnit] };
+        mtu as usize
+    }
+
+    /// macOS 13 and later, 1500 by default
+    ///
+    /// Fails with `io::ErrorKind::Unsupported` on older systems.
+    pub fn set_maximum_transmission_unit(&mut self, mtu: usize) -> io::Result<()> {
+        if !Self::supports_maximum_transmission_unit() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "the maximum transmission unit needs macOS 13 or later",
+            ));
+        }
+        unsafe {
+            let _: () = msg_send![*self.0, setMaximumTransmissionUnit: mtu as isize];
+        }
+        Ok(())
+    }
+
+    fn supports_maximum_transmission_unit() -> bool {
+        let supported: BOOL = unsafe {
+            msg_send![
+                class!(VZFileHandleNetworkDeviceAttachment),
+                instancesRespondToSelector: sel!(setMaximumTransmissionUnit:)
+            ]
+        };
+        supported != NO
+    }
+}
+
+impl VZNetworkDeviceAttachment for VZFileHandleNetworkDeviceAttachment {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
 /// common behaviors for bridge network interface
 pub trait VZBridgedNetworkInterface {
     fn id(&self) -> Id;
@@ -80,11 +164,45 @@ impl VZMACAddress {
         VZMACAddress(p)
     }
 
-    pub fn init_with_string(s: &str) -> VZMACAddress {
+    /// parses `s`, `None` if the framework rejects it
+    pub fn init_with_string(s: &str) -> Option<VZMACAddress> {
         let string = NSString::new(s);
-        let p =
-            unsafe { StrongPtr::new(msg_send![class!(VZMACAddress), initWithString:*string.0]) };
-        VZMACAddress(p)
+        unsafe {
+            let obj: Id = msg_send![class!(VZMACAddress), alloc];
+            let obj: Id = msg_send![obj, initWithString:*string.0];
+            if obj == NIL {
+                None
+            } else {
+                Some(VZMACAddress(StrongPtr::new(obj)))
+            }
+        }
+    }
+
+    pub fn string(&self) -> NSString {
+        let p = unsafe { StrongPtr::retain(msg_send![*self.0, string]) };
+        NSString(p)
+    }
+
+    pub fn mac_address(&self) -> MacAddress {
+        self.string()
+            .as_str()
+            .parse()
+            .expect("VZMACAddress formats a valid address")
+    }
+}
+
+impl TryFrom<MacAddress> for VZMACAddress {
+    type Error = ParseMacAddressError;
+
+    fn try_from(mac: MacAddress) -> Result<VZMACAddress, ParseMacAddressError> {
+        let text = mac.to_string();
+        VZMACAddress::init_with_string(&text).ok_or(ParseMacAddressError(text))
+    }
+}
+
+impl From<&VZMACAddress> for MacAddress {
+    fn from(mac: &VZMACAddress) -> MacAddress {
+        mac.mac_address()
     }
 }
 
@@ -116,6 +234,11 @@ impl VZVirtioNetworkDeviceConfiguration {
             let _: Id = msg_send![*self.0, setMACAddress:*mac.0];
         }
     }
+
+    pub fn mac_address(&self) -> VZMACAddress {
+        let p = unsafe { StrongPtr::retain(msg_send![*self.0, MACAddress]) };
+        VZMACAddress(p)
+    }
 }
 
 impl VZNetworkDeviceConfiguration for VZVirtioNetworkDeviceConfiguration {
@@ -1,33 +1,174 @@
 //! storage device module
 
 use crate::base::{Id, NSError, NSURL};
+use crate::definition::{validate_disk_image_modes, DiskModeError, StorageDeviceDefinition};
+use crate::disk::inspect::{inspect_disk_image, DiskReport};
+use crate::disk::lock::{DiskImageLock, DiskImageLockError};
+use crate::disk::scratch::ScratchDisk;
+use crate::disk::store::Digest;
+use crate::disk::verify::{DigestCache, VerifyError};
 
-use objc::runtime::BOOL;
+use objc::declare::ClassDecl;
+use objc::runtime::{Class, Object, Sel, BOOL};
 use objc::{class, msg_send, sel, sel_impl};
 use objc::{rc::StrongPtr, runtime::NO, runtime::YES};
 
+use std::error;
+use std::fmt;
+use std::os::raw::c_void;
+use std::sync::{Arc, Once};
+
+pub use crate::definition::{
+    VZDiskImageCachingMode, VZDiskImageSynchronizationMode, VZDiskSynchronizationMode,
+};
+
+extern "C" {
+    fn objc_setAssociatedObject(object: Id, key: *const c_void, value: Id, policy: usize);
+}
+
+const OBJC_ASSOCIATION_RETAIN_NONATOMIC: usize = 1;
+
+/// address used as the key of the resources associated with an attachment
+static RESOURCES_KEY: u8 = 0;
+
 /// common configure of storage device attachment
 pub trait VZStorageDeviceAttachment {
     fn id(&self) -> Id;
 }
 
+/// error of `VZDiskImageStorageDeviceAttachmentBuilder::build`
+pub enum DiskImageAttachmentError {
+    /// the image is attached elsewhere or could not be opened for locking
+    Lock(DiskImageLockError),
+    /// the image does not match its expected digest or could not be read
+    Verification(VerifyError),
+    /// the caching and synchronization modes are not available
+    Modes(DiskModeError),
+    /// Virtualization.framework rejected the image
+    Attachment(NSError),
+}
+
+impl fmt::Debug for DiskImageAttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskImageAttachmentError::Lock(err) => f.debug_tuple("Lock").field(err).finish(),
+            DiskImageAttachmentError::Verification(err) => {
+                f.debug_tuple("Verification").field(err).finish()
+            }
+            DiskImageAttachmentError::Modes(err) => f.debug_tuple("Modes").field(err).finish(),
+            DiskImageAttachmentError::Attachment(err) => f
+                .debug_tuple("Attachment")
+                .field(&err.localized_description().as_str())
+                .finish(),
+        }
+    }
+}
+
+impl fmt::Display for DiskImageAttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskImageAttachmentError::Lock(err) => write!(f, "{}", err),
+            DiskImageAttachmentError::Verification(err) => write!(f, "{}", err),
+            DiskImageAttachmentError::Modes(err) => write!(f, "{}", err),
+            DiskImageAttachmentError::Attachment(err) => {
+                write!(f, "{}", err.localized_description().as_str())
+            }
+        }
+    }
+}
+
+impl error::Error for DiskImageAttachmentError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            DiskImageAttachmentError::Lock(err) => Some(err),
+            DiskImageAttachmentError::Verification(err) => Some(err),
+            DiskImageAttachmentError::Modes(err) => Some(err),
+            DiskImageAttachmentError::Attachment(_) => None,
+        }
+    }
+}
+
+impl From<DiskImageLockError> for DiskImageAttachmentError {
+    fn from(err: DiskImageLockError) -> Self {
+        DiskImageAttachmentError::Lock(err)
+    }
+}
+
+impl From<VerifyError> for DiskImageAttachmentError {
+    fn from(err: VerifyError) -> Self {
+        DiskImageAttachmentError::Verification(err)
+    }
+}
+
+impl From<DiskModeError> for DiskImageAttachmentError {
+    fn from(err: DiskModeError) -> Self {
+        DiskImageAttachmentError::Modes(err)
+    }
+}
+
 /// builder for VZDiskImageStorageDeviceAttachment
+///
+/// `build` locks the image, exclusively when it is writable and shared when
+/// it is read-only, so two virtual machines cannot write the same image.
+/// The lock lasts as long as the attachment object, which is kept alive by
+/// the configurations and virtual machines using it.
+///
+/// A `ScratchDisk` given to `scratch_disk` is always attached writable. A
+/// clone kept by the caller deletes the image with
+/// `ScratchDisk::remove_if_stopped` once the virtual machine is stopped or
+/// has failed; the clone owned by the attachment object deletes it at the
+/// latest when the attachment is deallocated.
+///
+/// When a digest is given the image is hashed under the lock and `build`
+/// fails with `DiskImageAttachmentError::Verification` on a mismatch.
+/// Digests are remembered in a `DigestCache`, the process-wide one unless
+/// another is given, so an unchanged image is only hashed once.
+///
+/// The caching and synchronization modes need macOS 12. `build` checks
+/// them with `validate_disk_image_modes` and fails with
+/// `DiskImageAttachmentError::Modes` when they are not the defaults on an
+/// older system.
 /// # Examples
 /// ```rust
 /// let block_attachment = match VZDiskImageStorageDeviceAttachmentBuilder::new()
 ///     .path(canonicalize(&disk).unwrap().into_os_string().into_string().unwrap())
+///     .digest(expected_digest)
 ///     .build()
 /// {
 ///     Ok(x) => x,
 ///     Err(err) => {
-///         err.dump();
+///         eprintln!("{}", err);
 ///         return;
 ///     }
 /// };
+///
+/// let swap = ScratchDiskBuilder::new(2 << 30)
+///     .format(ScratchFormat::Swap)
+///     .build()
+///     .unwrap();
+/// let swap_attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+///     .scratch_disk(swap.clone())
+///     .build()
+///     .unwrap();
+/// let swap_device = VZVirtioBlockDeviceConfiguration::new(swap_attachment);
+/// // whenever the state of the virtual machine changes
+/// swap.remove_if_stopped(&vm).unwrap();
+///
+/// let database_attachment = VZDiskImageStorageDeviceAttachmentBuilder::new()
+///     .path("/var/vm/db.img")
+///     .read_only(false)
+///     .caching_mode(VZDiskImageCachingMode::VZDiskImageCachingModeUncached)
+///     .synchronization_mode(VZDiskImageSynchronizationMode::VZDiskImageSynchronizationModeFull)
+///     .build()
+///     .unwrap();
 /// ```
 pub struct VZDiskImageStorageDeviceAttachmentBuilder<Path, ReadOnly> {
     path: Path,
     read_only: ReadOnly,
+    digest: Option<Digest>,
+    digest_cache: Option<Arc<DigestCache>>,
+    caching_mode: VZDiskImageCachingMode,
+    synchronization_mode: VZDiskImageSynchronizationMode,
 }
 
 impl VZDiskImageStorageDeviceAttachmentBuilder<(), bool> {
@@ -35,6 +176,24 @@ impl VZDiskImageStorageDeviceAttachmentBuilder<(), bool> {
         VZDiskImageStorageDeviceAttachmentBuilder {
             path: (),
             read_only: true,
+            digest: None,
+            digest_cache: None,
+            caching_mode: VZDiskImageCachingMode::default(),
+            synchronization_mode: VZDiskImageSynchronizationMode::default(),
+        }
+    }
+
+    /// builder for a disk of a platform independent definition
+    pub fn from_definition(
+        disk: &StorageDeviceDefinition,
+    ) -> VZDiskImageStorageDeviceAttachmentBuilder<String, bool> {
+        VZDiskImageStorageDeviceAttachmentBuilder {
+            path: disk.path.to_string_lossy().into_owned(),
+            read_only: disk.read_only,
+            digest: disk.digest,
+            digest_cache: None,
+            caching_mode: disk.caching_mode,
+            synchronization_mode: disk.synchronization_mode,
         }
     }
 }
@@ -47,6 +206,26 @@ impl<Path, ReadOnly> VZDiskImageStorageDeviceAttachmentBuilder<Path, ReadOnly> {
         VZDiskImageStorageDeviceAttachmentBuilder {
             path: path.into(),
             read_only: self.read_only,
+            digest: self.digest,
+            digest_cache: self.digest_cache,
+            caching_mode: self.caching_mode,
+            synchronization_mode: self.synchronization_mode,
+        }
+    }
+
+    /// attaches a scratch disk, which is deleted once the virtual machine
+    /// stops, see `ScratchDisk::remove_if_stopped`
+    pub fn scratch_disk(
+        self,
+        disk: ScratchDisk,
+    ) -> VZDiskImageStorageDeviceAttachmentBuilder<ScratchDisk, ReadOnly> {
+        VZDiskImageStorageDeviceAttachmentBuilder {
+            path: disk,
+            read_only: self.read_only,
+            digest: self.digest,
+            digest_cache: self.digest_cache,
+            caching_mode: self.caching_mode,
+            synchronization_mode: self.synchronization_mode,
         }
     }
 
@@ -57,33 +236,189 @@ impl<Path, ReadOnly> VZDiskImageStorageDeviceAttachmentBuilder<Path, ReadOnly> {
         VZDiskImageStorageDeviceAttachmentBuilder {
             path: self.path,
             read_only: read_only,
+            digest: self.digest,
+            digest_cache: self.digest_cache,
+            caching_mode: self.caching_mode,
+            synchronization_mode: self.synchronization_mode,
+        }
+    }
+
+    /// expected digest of the image, checked by `build`
+    pub fn digest(mut self, digest: Digest) -> Self {
+        self.digest = Some(digest);
+        self
+    }
+
+    /// cache of image digests used instead of `DigestCache::global`
+    pub fn digest_cache(mut self, cache: Arc<DigestCache>) -> Self {
+        self.digest_cache = Some(cache);
+        self
+    }
+
+    pub fn caching_mode(mut self, caching_mode: VZDiskImageCachingMode) -> Self {
+        self.caching_mode = caching_mode;
+        self
+    }
+
+    pub fn synchronization_mode(
+        mut self,
+        synchronization_mode: VZDiskImageSynchronizationMode,
+    ) -> Self {
+        self.synchronization_mode = synchronization_mode;
+        self
+    }
+
+    fn verify(&self, path: &std::path::Path) -> Result<(), VerifyError> {
+        match (&self.digest, &self.digest_cache) {
+            (None, _) => Ok(()),
+            (Some(digest), Some(cache)) => cache.verify(path, digest),
+            (Some(digest), None) => DigestCache::global().verify(path, digest),
         }
     }
 }
 
+impl<ReadOnly> VZDiskImageStorageDeviceAttachmentBuilder<String, ReadOnly> {
+    /// reports the partition table and file systems of the image
+    pub fn inspect(&self) -> std::io::Result<DiskReport> {
+        inspect_disk_image(&self.path)
+    }
+}
+
 impl VZDiskImageStorageDeviceAttachmentBuilder<String, bool> {
-    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, NSError> {
+    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, DiskImageAttachmentError> {
+        let lock = if self.read_only {
+            DiskImageLock::shared(&self.path)?
+        } else {
+            DiskImageLock::exclusive(&self.path)?
+        };
+        self.verify(std::path::Path::new(&self.path))?;
         let read_only = if self.read_only { YES } else { NO };
-        unsafe { VZDiskImageStorageDeviceAttachment::new(self.path.as_str(), read_only) }
+        let attachment = unsafe {
+            VZDiskImageStorageDeviceAttachment::new(
+                self.path.as_str(),
+                read_only,
+                self.caching_mode,
+                self.synchronization_mode,
+            )
+        }?;
+        let resources = AttachmentResources {
+            _lock: lock,
+            _scratch: None,
+        };
+        unsafe { attach_resources(*attachment.0, resources) };
+        Ok(attachment)
     }
 }
 
+impl<ReadOnly> VZDiskImageStorageDeviceAttachmentBuilder<ScratchDisk, ReadOnly> {
+    pub fn build(self) -> Result<VZDiskImageStorageDeviceAttachment, DiskImageAttachmentError> {
+        let lock = DiskImageLock::exclusive(self.path.path())?;
+        self.verify(self.path.path())?;
+        let disk = self.path;
+        let path = disk.path().to_string_lossy().into_owned();
+        let attachment = unsafe {
+            VZDiskImageStorageDeviceAttachment::new(
+                &path,
+                NO,
+                self.caching_mode,
+                self.synchronization_mode,
+            )
+        }?;
+        let resources = AttachmentResources {
+            _lock: lock,
+            _scratch: Some(disk),
+        };
+        unsafe { attach_resources(*attachment.0, resources) };
+        Ok(attachment)
+    }
+}
+
+/// what an attachment keeps alive, released in field order
+struct AttachmentResources {
+    _lock: DiskImageLock,
+    _scratch: Option<ScratchDisk>,
+}
+
+/// Objective-C class whose instances own `AttachmentResources` and drop them on dealloc
+fn resource_owner_class() -> &'static Class {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut decl = ClassDecl::new("VZRSAttachmentResourceOwner", class!(NSObject)).unwrap();
+        decl.add_ivar::<usize>("resources");
+        extern "C" fn dealloc(this: &mut Object, _: Sel) {
+            unsafe {
+                let resources = *this.get_ivar::<usize>("resources") as *mut AttachmentResources;
+                if !resources.is_null() {
+                    drop(Box::from_raw(resources));
+                }
+                let _: () = msg_send![super(this, class!(NSObject)), dealloc];
+            }
+        }
+        unsafe {
+            decl.add_method(sel!(dealloc), dealloc as extern "C" fn(&mut Object, Sel));
+        }
+        decl.register();
+    });
+    class!(VZRSAttachmentResourceOwner)
+}
+
+/// ties `resources` to the lifetime of the Objective-C object `attachment`
+unsafe fn attach_resources(attachment: Id, resources: AttachmentResources) {
+    let owner: Id = msg_send![resource_owner_class(), new];
+    (*owner).set_ivar("resources", Box::into_raw(Box::new(resources)) as usize);
+    objc_setAssociatedObject(
+        attachment,
+        &RESOURCES_KEY as *const u8 as *const c_void,
+        owner,
+        OBJC_ASSOCIATION_RETAIN_NONATOMIC,
+    );
+    let _: () = msg_send![owner, release];
+}
+
 /// configure of disk image storage device attachment
 pub struct VZDiskImageStorageDeviceAttachment(StrongPtr);
 
 impl VZDiskImageStorageDeviceAttachment {
+    /// whether the installed Virtualization.framework has caching and
+    /// synchronization modes, which need macOS 12
+    pub fn modes_supported() -> bool {
+        let supported: BOOL = unsafe {
+            msg_send![
+                class!(VZDiskImageStorageDeviceAttachment),
+                instancesRespondToSelector: sel!(initWithURL:readOnly:cachingMode:synchronizationMode:error:)
+            ]
+        };
+        supported != NO
+    }
+
     unsafe fn new(
         path: &str,
         read_only: BOOL,
-    ) -> Result<VZDiskImageStorageDeviceAttachment, NSError> {
+        caching_mode: VZDiskImageCachingMode,
+        synchronization_mode: VZDiskImageSynchronizationMode,
+    ) -> Result<VZDiskImageStorageDeviceAttachment, DiskImageAttachmentError> {
+        validate_disk_image_modes(caching_mode, synchronization_mode, Self::modes_supported())?;
+        let defaults = caching_mode == VZDiskImageCachingMode::default()
+            && synchronization_mode == VZDiskImageSynchronizationMode::default();
         let i: Id = msg_send![class!(VZDiskImageStorageDeviceAttachment), alloc];
         let path_nsurl = NSURL::file_url_with_path(path, false);
         let error = NSError::nil();
-        let p = StrongPtr::new(
-            msg_send![i, initWithURL:*path_nsurl.0 readOnly:read_only error:&(*error.0)],
-        );
+        let p = if defaults {
+            StrongPtr::new(
+                msg_send![i, initWithURL:*path_nsurl.0 readOnly:read_only error:&(*error.0)],
+            )
+        } else {
+            StrongPtr::new(msg_send![
+                i,
+                initWithURL:*path_nsurl.0
+                readOnly:read_only
+                cachingMode:caching_mode as isize
+                synchronizationMode:synchronization_mode as isize
+                error:&(*error.0)
+            ])
+        };
         if error.code() != 0 {
-            Err(error)
+            Err(DiskImageAttachmentError::Attachment(error))
         } else {
             Ok(VZDiskImageStorageDeviceAttachment(p))
         }
@@ -96,6 +431,187 @@ impl VZStorageDeviceAttachment for VZDiskImageStorageDeviceAttachment {
     }
 }
 
+/// connection timeout used when none is given to the builder, in seconds
+pub const DEFAULT_NETWORK_BLOCK_DEVICE_TIMEOUT: f64 = 30.0;
+
+/// error of `VZNetworkBlockDeviceStorageDeviceAttachmentBuilder::build`
+pub enum NetworkBlockDeviceAttachmentError {
+    /// the installed Virtualization.framework has no network block device
+    /// attachment, which needs macOS 14
+    Unavailable,
+    /// Virtualization.framework rejected the URL or could not connect
+    Attachment(NSError),
+}
+
+impl fmt::Debug for NetworkBlockDeviceAttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkBlockDeviceAttachmentError::Unavailable => write!(f, "Unavailable"),
+            NetworkBlockDeviceAttachmentError::Attachment(err) => f
+                .debug_tuple("Attachment")
+                .field(&err.localized_description().as_str())
+                .finish(),
+        }
+    }
+}
+
+impl fmt::Display for NetworkBlockDeviceAttachmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkBlockDeviceAttachmentError::Unavailable => {
+                write!(f, "network block device attachments need macOS 14 or later")
+            }
+            NetworkBlockDeviceAttachmentError::Attachment(err) => {
+                write!(f, "{}", err.localized_description().as_str())
+            }
+        }
+    }
+}
+
+impl error::Error for NetworkBlockDeviceAttachmentError {}
+
+/// class of network block device attachments, which exists from macOS 14
+fn network_block_device_class() -> Result<&'static Class, NetworkBlockDeviceAttachmentError> {
+    Class::get("VZNetworkBlockDeviceStorageDeviceAttachment")
+        .ok_or(NetworkBlockDeviceAttachmentError::Unavailable)
+}
+
+/// builder for VZNetworkBlockDeviceStorageDeviceAttachment
+/// # Examples
+/// ```rust
+/// let block_attachment = match VZNetworkBlockDeviceStorageDeviceAttachmentBuilder::new()
+///     .url(nbd::unix_url("/tmp/disk.sock", "disk"))
+///     .timeout(5.0)
+///     .build()
+/// {
+///     Ok(x) => x,
+///     Err(err) => {
+///         eprintln!("{}", err);
+///         return;
+///     }
+/// };
+/// ```
+pub struct VZNetworkBlockDeviceStorageDeviceAttachmentBuilder<URL> {
+    url: URL,
+    timeout: f64,
+    forced_read_only: bool,
+    synchronization_mode: VZDiskSynchronizationMode,
+}
+
+impl VZNetworkBlockDeviceStorageDeviceAttachmentBuilder<()> {
+    pub fn new() -> Self {
+        VZNetworkBlockDeviceStorageDeviceAttachmentBuilder {
+            url: (),
+            timeout: DEFAULT_NETWORK_BLOCK_DEVICE_TIMEOUT,
+            forced_read_only: false,
+            synchronization_mode: VZDiskSynchronizationMode::default(),
+        }
+    }
+}
+
+impl<URL> VZNetworkBlockDeviceStorageDeviceAttachmentBuilder<URL> {
+    /// `nbd://host:port/export` or `nbd+unix:///export?socket=path`
+    pub fn url<T: Into<String>>(
+        self,
+        url: T,
+    ) -> VZNetworkBlockDeviceStorageDeviceAttachmentBuilder<String> {
+        VZNetworkBlockDeviceStorageDeviceAttachmentBuilder {
+            url: url.into(),
+            timeout: self.timeout,
+            forced_read_only: self.forced_read_only,
+            synchronization_mode: self.synchronization_mode,
+        }
+    }
+
+    /// timeout of the connection to the server in seconds
+    pub fn timeout(mut self, timeout: f64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn forced_read_only(mut self, forced_read_only: bool) -> Self {
+        self.forced_read_only = forced_read_only;
+        self
+    }
+
+    /// whether flushes of the guest are sent to the server
+    pub fn synchronization_mode(mut self, synchronization_mode: VZDiskSynchronizationMode) -> Self {
+        self.synchronization_mode = synchronization_mode;
+        self
+    }
+}
+
+impl VZNetworkBlockDeviceStorageDeviceAttachmentBuilder<String> {
+    /// fails with `NetworkBlockDeviceAttachmentError::Unavailable` before
+    /// macOS 14
+    pub fn build(
+        self,
+    ) -> Result<VZNetworkBlockDeviceStorageDeviceAttachment, NetworkBlockDeviceAttachmentError>
+    {
+        let forced_read_only = if self.forced_read_only { YES } else { NO };
+        unsafe {
+            VZNetworkBlockDeviceStorageDeviceAttachment::new(
+                self.url.as_str(),
+                self.timeout,
+                forced_read_only,
+                self.synchronization_mode,
+            )
+        }
+    }
+}
+
+/// configure of network block device storage device attachment
+pub struct VZNetworkBlockDeviceStorageDeviceAttachment(StrongPtr);
+
+impl VZNetworkBlockDeviceStorageDeviceAttachment {
+    unsafe fn new(
+        url: &str,
+        timeout: f64,
+        forced_read_only: BOOL,
+        synchronization_mode: VZDiskSynchronizationMode,
+    ) -> Result<VZNetworkBlockDeviceStorageDeviceAttachment, NetworkBlockDeviceAttachmentError>
+    {
+        let class = network_block_device_class()?;
+        let i: Id = msg_send![class, alloc];
+        let url_nsurl = NSURL::url_with_string(url);
+        let error = NSError::nil();
+        let p = StrongPtr::new(msg_send![
+            i,
+            initWithURL:*url_nsurl.0
+            timeout:timeout
+            forcedReadOnly:forced_read_only
+            synchronizationMode:synchronization_mode as isize
+            error:&(*error.0)
+        ]);
+        if error.code() != 0 {
+            Err(NetworkBlockDeviceAttachmentError::Attachment(error))
+        } else {
+            Ok(VZNetworkBlockDeviceStorageDeviceAttachment(p))
+        }
+    }
+
+    /// checks that `url` is an NBD URL the framework accepts
+    pub fn validate_url(url: &str) -> Result<(), NetworkBlockDeviceAttachmentError> {
+        let class = network_block_device_class()?;
+        unsafe {
+            let url_nsurl = NSURL::url_with_string(url);
+            let error = NSError::nil();
+            let _: BOOL = msg_send![class, validateURL:*url_nsurl.0 error:&(*error.0)];
+            if error.code() != 0 {
+                Err(NetworkBlockDeviceAttachmentError::Attachment(error))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+impl VZStorageDeviceAttachment for VZNetworkBlockDeviceStorageDeviceAttachment {
+    fn id(&self) -> Id {
+        *self.0
+    }
+}
+
 /// configure of storage device
 pub trait VZStorageDeviceConfiguration {
     fn id(&self) -> Id;
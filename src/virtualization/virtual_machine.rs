@@ -2,6 +2,7 @@
 
 use crate::{
     base::{Id, NSArray, NSError},
+    definition::VirtualMachineLifecycle,
     virtualization::boot_loader::VZBootLoader,
     virtualization::entropy_device::VZEntropyDeviceConfiguration,
     virtualization::memory_device::VZMemoryBalloonDeviceConfiguration,
@@ -16,6 +17,8 @@ use objc::runtime::BOOL;
 use objc::{class, msg_send, sel, sel_impl};
 use objc::{rc::StrongPtr, runtime::YES};
 
+pub use crate::definition::VZVirtualMachineState;
+
 /// builder for VZVirtualMachineConfiguration
 /// # Examples
 /// ```rust
@@ -203,34 +206,6 @@ impl VZVirtualMachineConfiguration {
 #[derive(Clone)]
 pub struct VZVirtualMachine(StrongPtr);
 
-/// state of virtual machine
-#[derive(Debug)]
-pub enum VZVirtualMachineState {
-    /// Initial state before the virtual machine is started.
-    VZVirtualMachineStateStopped,
-
-    /// Running virtual machine.
-    VZVirtualMachineStateRunning,
-
-    /// A started virtual machine is paused. This state can only be transitioned from VZVirtualMachineStatePausing.
-    VZVirtualMachineStatePaused,
-
-    /// The virtual machine has encountered an internal error.
-    VZVirtualMachineStateError,
-
-    /// The virtual machine is configuring the hardware and starting.
-    VZVirtualMachineStateStarting,
-
-    /// The virtual machine is being paused. This is the intermediate state between VZVirtualMachineStateRunning and VZVirtualMachineStatePaused.
-    VZVirtualMachineStatePausing,
-
-    /// The virtual machine is being resumed. This is the intermediate state between VZVirtualMachineStatePaused and VZVirtualMachineStateRunning. */
-    VZVirtualMachineStateResuming,
-
-    /// Other
-    Other,
-}
-
 impl VZVirtualMachine {
     pub fn new(conf: VZVirtualMachineConfiguration, queue: Id) -> VZVirtualMachine {
         unsafe {
@@ -277,3 +252,9 @@ impl VZVirtualMachine {
         }
     }
 }
+
+impl VirtualMachineLifecycle for VZVirtualMachine {
+    fn state(&self) -> VZVirtualMachineState {
+        unsafe { VZVirtualMachine::state(self) }
+    }
+}
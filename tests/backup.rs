@@ -0,0 +1,414 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use virtualization_rs::definition::*;
+use virtualization_rs::disk::backup::*;
+use virtualization_rs::disk::lock::DiskImageLock;
+
+mod common;
+
+use common::FakeVm;
+
+const KIB: u64 = 1 << 10;
+const MIB: u64 = 1 << 20;
+
+/// small chunks keep the tests fast
+const SMALL: Chunking = Chunking::ContentDefined {
+    min: 4 << 10,
+    avg: 16 << 10,
+    max: 64 << 10,
+};
+
+/// bytes that do not repeat, so chunks only match where the data does
+fn noise(len: usize, seed: u64) -> Vec<u8> {
+    let mut state = seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+/// 8 MiB disk with two data ranges, zeros inside the first one and holes
+/// everywhere else
+fn disk(path: &Path, seed: u64) {
+    let file = File::create(path).unwrap();
+    file.set_len(8 * MIB).unwrap();
+    file.write_all_at(&noise(MIB as usize, seed), MIB).unwrap();
+    file.write_all_at(&[0; 256 << 10], MIB + 256 * KIB).unwrap();
+    file.write_all_at(&noise(300_000, seed + 1), 6 * MIB)
+        .unwrap();
+}
+
+fn definition(disks: &[(PathBuf, bool)]) -> VirtualMachineDefinition {
+    let mut definition = VirtualMachineDefinition::new("web");
+    for (path, read_only) in disks {
+        definition
+            .storage_devices
+            .push(StorageDeviceDefinition::new(path, *read_only));
+    }
+    definition
+}
+
+fn repository(dir: &Path, chunking: Chunking) -> BackupRepository {
+    BackupRepository::open(dir.join("repo"))
+        .unwrap()
+        .chunking(chunking)
+}
+
+fn digests(snapshot: &Snapshot) -> HashSet<String> {
+    snapshot
+        .disks
+        .iter()
+        .flat_map(|d| d.chunks.iter())
+        .map(|c| c.digest.to_hex())
+        .collect()
+}
+
+#[test]
+fn backups_restore_to_identical_sparse_images() {
+    let dir = common::test_dir("backup", "round-trip");
+    let root = dir.join("root.img");
+    let seed = dir.join("seed.img");
+    disk(&root, 1);
+    fs::write(&seed, noise(10_000, 9)).unwrap();
+    let definition = definition(&[(root.clone(), false), (seed.clone(), true)]);
+    let repository = repository(&dir, SMALL);
+
+    let report = repository.backup(&definition, &FakeVm::stopped()).unwrap();
+    let snapshot = &report.snapshot;
+    assert!(snapshot.id.starts_with("web-"), "{}", snapshot.id);
+    assert_eq!(snapshot.chunking, SMALL);
+    assert_eq!(snapshot.disks.len(), 2);
+    assert_eq!(snapshot.disks[0].size, 8 * MIB);
+    assert!(snapshot.disks[1].read_only);
+    // chunks of zeros inside the data range are not stored
+    let data = MIB - 256 * KIB + 300_000 + 10_000;
+    let stored = snapshot.disks.iter().map(|d| d.data_size()).sum::<u64>();
+    assert!(stored >= data && stored < data + 256 * KIB, "{}", stored);
+    assert!(report.new_chunks > 0);
+    assert_eq!(report.unchanged_disks, 0);
+    assert_eq!(
+        repository.snapshots().unwrap(),
+        vec![repository.snapshot(&snapshot.id).unwrap()]
+    );
+
+    let restored = repository
+        .restore(&snapshot.id, dir.join("restored"))
+        .unwrap();
+    assert_eq!(
+        restored,
+        vec![
+            dir.join("restored").join("0-root.img"),
+            dir.join("restored").join("1-seed.img")
+        ]
+    );
+    assert_eq!(fs::read(&restored[0]).unwrap(), fs::read(&root).unwrap());
+    assert_eq!(fs::read(&restored[1]).unwrap(), fs::read(&seed).unwrap());
+    // only the data is allocated
+    assert!(fs::metadata(&restored[0]).unwrap().blocks() * 512 < 2 * MIB);
+
+    let single = dir.join("single.img");
+    repository.restore_disk(&snapshot.id, 1, &single).unwrap();
+    assert_eq!(fs::read(&single).unwrap(), fs::read(&seed).unwrap());
+    assert!(matches!(
+        repository.restore_disk(&snapshot.id, 1, &single),
+        Err(BackupError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        repository.restore_disk(&snapshot.id, 2, dir.join("missing.img")),
+        Err(BackupError::NotFound(_))
+    ));
+}
+
+#[test]
+fn unchanged_disks_are_not_read_again() {
+    let dir = common::test_dir("backup", "unchanged");
+    let root = dir.join("root.img");
+    disk(&root, 2);
+    let definition = definition(&[(root.clone(), false)]);
+    let repository = repository(&dir, SMALL);
+    let first = repository.backup(&definition, &FakeVm::stopped()).unwrap();
+
+    let second = repository.backup(&definition, &FakeVm::stopped()).unwrap();
+    assert_ne!(first.snapshot.id, second.snapshot.id);
+    assert_eq!(second.unchanged_disks, 1);
+    assert_eq!((second.scanned, second.new_chunks), (0, 0));
+    assert_eq!(first.snapshot.disks, second.snapshot.disks);
+
+    // a small change only stores the chunks around it
+    let file = OpenOptions::new().write(true).open(&root).unwrap();
+    file.write_all_at(b"changed", MIB + 100_000).unwrap();
+    drop(file);
+    let third = repository.backup(&definition, &FakeVm::stopped()).unwrap();
+    assert_eq!(third.unchanged_disks, 0);
+    assert!(third.scanned > MIB);
+    assert!((1..=2).contains(&third.new_chunks), "{:?}", third);
+    let restored = dir.join("third.img");
+    repository
+        .restore_disk(&third.snapshot.id, 0, &restored)
+        .unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), fs::read(&root).unwrap());
+}
+
+#[test]
+fn content_defined_chunks_survive_shifted_data() {
+    let dir = common::test_dir("backup", "shift");
+    let data = noise(2 * MIB as usize, 3);
+    let original = dir.join("original.img");
+    fs::write(&original, &data).unwrap();
+    // the same data moved by a few bytes
+    let shifted = dir.join("shifted.img");
+    let mut moved = b"12345".to_vec();
+    moved.extend_from_slice(&data);
+    fs::write(&shifted, &moved).unwrap();
+
+    for (chunking, name) in [(SMALL, "cdc"), (Chunking::Fixed(16 << 10), "fixed")] {
+        let repository = BackupRepository::open(dir.join(name))
+            .unwrap()
+            .chunking(chunking);
+        let first = repository
+            .backup(
+                &definition(&[(original.clone(), false)]),
+                &FakeVm::stopped(),
+            )
+            .unwrap();
+        let second = BackupRepository::open(dir.join(name))
+            .unwrap()
+            .chunking(chunking)
+            .backup(
+                &{
+                    let mut definition = definition(&[(shifted.clone(), false)]);
+                    definition.name = "moved".to_string();
+                    definition
+                },
+                &FakeVm::stopped(),
+            )
+            .unwrap();
+        let shared = digests(&first.snapshot)
+            .intersection(&digests(&second.snapshot))
+            .count();
+        let total = first.snapshot.disks[0].chunks.len();
+        if chunking == SMALL {
+            // only the chunk holding the inserted bytes differs
+            assert!(shared + 2 >= total, "{} of {} shared", shared, total);
+            assert!(second.new_bytes < first.new_bytes / 10);
+        } else {
+            assert_eq!(shared, 0);
+        }
+    }
+}
+
+#[test]
+fn content_defined_chunks_stay_within_their_bounds() {
+    let dir = common::test_dir("backup", "bounds");
+    let path = dir.join("disk.img");
+    // noise cuts by the hash, a run of ones never matches and cuts at max
+    let mut data = noise(MIB as usize, 4);
+    data.extend(std::iter::repeat_n(1u8, 300_000));
+    fs::write(&path, &data).unwrap();
+    let report = repository(&dir, SMALL)
+        .backup(&definition(&[(path, false)]), &FakeVm::stopped())
+        .unwrap();
+    let chunks = &report.snapshot.disks[0].chunks;
+    let mut offset = 0;
+    for (i, chunk) in chunks.iter().enumerate() {
+        assert_eq!(chunk.offset, offset);
+        assert!(chunk.len <= 64 * KIB);
+        if i + 1 < chunks.len() {
+            assert!(chunk.len >= 4 * KIB, "{:?}", chunk);
+        }
+        offset += chunk.len;
+    }
+    assert_eq!(offset, data.len() as u64);
+    let noise_chunks = chunks.iter().filter(|c| c.offset < MIB).count() as u64;
+    // 16 KiB on average, loosely
+    assert!((MIB / (48 * KIB)..MIB / (6 * KIB)).contains(&noise_chunks));
+    assert!(chunks
+        .iter()
+        .filter(|c| c.offset > MIB + 64 * KIB && c.offset + c.len < MIB + 300_000)
+        .all(|c| c.len == 64 * KIB));
+}
+
+#[test]
+fn invalid_chunkings_are_refused() {
+    let dir = common::test_dir("backup", "chunking");
+    let path = dir.join("disk.img");
+    fs::write(&path, noise(2_000, 5)).unwrap();
+    let definition = definition(&[(path.clone(), false)]);
+    let invalid = [
+        Chunking::Fixed(0),
+        Chunking::ContentDefined {
+            min: 1,
+            avg: 1,
+            max: 2,
+        },
+        Chunking::ContentDefined {
+            min: 0,
+            avg: 4,
+            max: 8,
+        },
+        Chunking::ContentDefined {
+            min: 1,
+            avg: 3,
+            max: 8,
+        },
+        Chunking::ContentDefined {
+            min: 8,
+            avg: 4,
+            max: 16,
+        },
+    ];
+    for chunking in invalid.iter() {
+        match repository(&dir, *chunking).backup(&definition, &FakeVm::stopped()) {
+            Err(BackupError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::InvalidInput),
+            other => panic!("{:?} gave {:?}", chunking, other),
+        }
+    }
+    // the smallest chunking that is allowed
+    let tiny = Chunking::ContentDefined {
+        min: 1,
+        avg: 2,
+        max: 4,
+    };
+    let report = repository(&dir, tiny)
+        .backup(&definition, &FakeVm::stopped())
+        .unwrap();
+    let restored = dir.join("tiny.img");
+    repository(&dir, tiny)
+        .restore_disk(&report.snapshot.id, 0, &restored)
+        .unwrap();
+    assert_eq!(fs::read(restored).unwrap(), fs::read(path).unwrap());
+}
+
+#[test]
+fn running_and_attached_machines_are_refused() {
+    let dir = common::test_dir("backup", "refused");
+    let path = dir.join("disk.img");
+    disk(&path, 6);
+    let definition = definition(&[(path.clone(), false)]);
+    let repository = repository(&dir, SMALL);
+
+    let running = FakeVm(VZVirtualMachineState::VZVirtualMachineStateRunning);
+    assert!(matches!(
+        repository.backup(&definition, &running),
+        Err(BackupError::NotQuiesced(_))
+    ));
+    let attached = DiskImageLock::exclusive(&path).unwrap();
+    assert!(matches!(
+        repository.backup(&definition, &FakeVm::stopped()),
+        Err(BackupError::Lock(_))
+    ));
+    // a paused machine holds the lock itself
+    let paused = FakeVm(VZVirtualMachineState::VZVirtualMachineStatePaused);
+    repository.backup(&definition, &paused).unwrap();
+    drop(attached);
+
+    let mut renamed = definition.clone();
+    renamed.name = "web server".to_string();
+    assert!(matches!(
+        repository.backup(&renamed, &FakeVm::stopped()),
+        Err(BackupError::InvalidName(_))
+    ));
+    assert!(matches!(
+        repository.snapshot("../web"),
+        Err(BackupError::NotFound(_))
+    ));
+}
+
+#[test]
+fn gc_frees_the_chunks_of_removed_snapshots() {
+    let dir = common::test_dir("backup", "gc");
+    let path = dir.join("disk.img");
+    disk(&path, 7);
+    let definition = definition(&[(path.clone(), false)]);
+    let repository = repository(&dir, SMALL);
+    let first = repository.backup(&definition, &FakeVm::stopped()).unwrap();
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&noise(100_000, 99), 6 * MIB).unwrap();
+    drop(file);
+    let second = repository.backup(&definition, &FakeVm::stopped()).unwrap();
+
+    assert_eq!(repository.gc().unwrap(), BackupGcReport::default());
+    repository.remove_snapshot(&first.snapshot.id).unwrap();
+    assert!(matches!(
+        repository.remove_snapshot(&first.snapshot.id),
+        Err(BackupError::NotFound(_))
+    ));
+    let only_first = digests(&first.snapshot)
+        .difference(&digests(&second.snapshot))
+        .count() as u64;
+    // a chunk a crashed backup left half written is freed but not counted
+    let hex = second.snapshot.disks[0].chunks[0].digest.to_hex();
+    let leftover = repository
+        .dir()
+        .join("chunks")
+        .join(&hex[..2])
+        .join(format!(".{}.1.0.tmp", hex));
+    fs::write(&leftover, b"partial").unwrap();
+    let report = repository.gc().unwrap();
+    assert_eq!(report.removed_chunks, only_first);
+    assert!(report.removed_chunks > 0 && report.freed > 0);
+    assert!(!leftover.exists());
+
+    // what is left still restores
+    let restored = dir.join("restored.img");
+    repository
+        .restore_disk(&second.snapshot.id, 0, &restored)
+        .unwrap();
+    assert_eq!(fs::read(&restored).unwrap(), fs::read(&path).unwrap());
+}
+
+#[test]
+fn corrupt_chunks_fail_the_restore() {
+    let dir = common::test_dir("backup", "corrupt");
+    let path = dir.join("disk.img");
+    disk(&path, 10);
+    let repository = repository(&dir, SMALL);
+    let report = repository
+        .backup(&definition(&[(path, false)]), &FakeVm::stopped())
+        .unwrap();
+    let digest = report.snapshot.disks[0].chunks[3].digest;
+    let hex = digest.to_hex();
+    let chunk = repository.dir().join("chunks").join(&hex[..2]).join(&hex);
+    let compressed = fs::read(&chunk).unwrap();
+    fs::write(&chunk, zstd::encode_all(&b"something else"[..], 3).unwrap()).unwrap();
+
+    let restored = dir.join("restored.img");
+    match repository.restore_disk(&report.snapshot.id, 0, &restored) {
+        Err(BackupError::CorruptChunk(bad)) => assert_eq!(bad, digest),
+        other => panic!("{:?}", other),
+    }
+    // no partial image is left behind
+    assert!(!restored.exists());
+    let hidden = fs::read_dir(&dir)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with('.')
+        })
+        .count();
+    assert_eq!(hidden, 0);
+
+    fs::write(&chunk, compressed).unwrap();
+    repository
+        .restore_disk(&report.snapshot.id, 0, &restored)
+        .unwrap();
+
+    let manifest = repository.dir().join("snapshots").join(&report.snapshot.id);
+    fs::write(&manifest, "snapshot 1\nbogus line\n").unwrap();
+    assert!(matches!(
+        repository.snapshot(&report.snapshot.id),
+        Err(BackupError::InvalidSnapshot(_))
+    ));
+}
@@ -0,0 +1,201 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+use std::process::Command;
+
+use virtualization_rs::definition::*;
+use virtualization_rs::disk::bundle::{BundleError, BundleExporter, BundleImporter};
+use virtualization_rs::disk::store::Digest;
+
+mod common;
+
+use common::FakeVm;
+
+const DISK_SIZE: u64 = 64 << 20;
+
+fn definition(dir: &Path) -> VirtualMachineDefinition {
+    fs::write(dir.join("vmlinuz"), vec![0x4b; 10_000]).unwrap();
+    fs::write(dir.join("initrd"), vec![0x49; 20_000]).unwrap();
+    let disk = File::create(dir.join("root.img")).unwrap();
+    disk.set_len(DISK_SIZE).unwrap();
+    disk.write_all_at(b"boot sector", 0).unwrap();
+    disk.write_all_at(&[0x5a; 1 << 20], 32 << 20).unwrap();
+    disk.write_all_at(b"end", DISK_SIZE - 3).unwrap();
+    fs::write(dir.join("seed.iso"), vec![0x53; 4096]).unwrap();
+
+    let mut definition = VirtualMachineDefinition::new("web");
+    definition.cpu_count = 2;
+    definition.memory_size = 1 << 30;
+    definition.kernel = dir.join("vmlinuz");
+    definition.initial_ramdisk = dir.join("initrd");
+    definition.command_line = "console=hvc0 root=/dev/vda".to_string();
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(dir.join("root.img"), false));
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(dir.join("seed.iso"), true));
+    definition
+}
+
+fn export(dir: &Path, definition: &VirtualMachineDefinition) -> std::path::PathBuf {
+    fs::write(dir.join("machine-identifier"), b"identifier").unwrap();
+    fs::write(dir.join("console.log"), b"login: ").unwrap();
+    let archive = dir.join("web.vmbundle.tar.zst");
+    let mut entries = Vec::new();
+    let manifest = BundleExporter::new(definition)
+        .machine_identifier(dir.join("machine-identifier"))
+        .log(dir.join("console.log"))
+        .progress(|p| {
+            assert!(p.done <= p.total);
+            if entries.last() != Some(&p.entry.to_string()) {
+                entries.push(p.entry.to_string());
+            }
+        })
+        .export(&FakeVm::stopped(), &archive)
+        .unwrap();
+    // the definition, the kernel, the initial ramdisk, two disks, the machine
+    // identifier and the log
+    assert_eq!(manifest.len(), 7);
+    assert!(entries.len() >= 4, "{:?}", entries);
+    // the holes of the disk are not stored
+    assert!(fs::metadata(&archive).unwrap().len() < 1 << 20);
+    archive
+}
+
+#[test]
+fn bundles_round_trip() {
+    let dir = common::test_dir("bundle", "round-trip");
+    let definition = definition(&dir);
+    let archive = export(&dir, &definition);
+
+    let bundle = BundleImporter::new(&archive)
+        .import(dir.join("imported"))
+        .unwrap();
+    let imported = fs::canonicalize(dir.join("imported")).unwrap();
+    let ours = &bundle.definition;
+    assert_eq!(ours.name, "web");
+    assert_eq!((ours.cpu_count, ours.memory_size), (2, 1 << 30));
+    assert_eq!(ours.command_line, definition.command_line);
+    assert!(ours.kernel.starts_with(&imported));
+    assert_eq!(
+        fs::read(&ours.kernel).unwrap(),
+        fs::read(&definition.kernel).unwrap()
+    );
+    assert_eq!(
+        fs::read(&ours.initial_ramdisk).unwrap(),
+        fs::read(&definition.initial_ramdisk).unwrap()
+    );
+    for (ours, theirs) in ours.storage_devices.iter().zip(&definition.storage_devices) {
+        assert!(ours.path.starts_with(&imported));
+        assert_eq!(ours.read_only, theirs.read_only);
+        assert_eq!(
+            Digest::of_file(&ours.path).unwrap(),
+            Digest::of_file(&theirs.path).unwrap()
+        );
+    }
+    let disk = fs::metadata(&ours.storage_devices[0].path).unwrap();
+    assert_eq!(disk.len(), DISK_SIZE);
+    assert!(disk.blocks() * 512 < 8 << 20);
+
+    let identifier = bundle.machine_identifier.as_ref().unwrap();
+    assert_eq!(fs::read(identifier).unwrap(), b"identifier");
+    assert_eq!(bundle.logs.len(), 1);
+    assert_eq!(fs::read(&bundle.logs[0]).unwrap(), b"login: ");
+    // the definition is parsed rather than extracted
+    for entry in bundle.manifest.iter().filter(|e| e.name != "definition") {
+        let path = imported.join(&entry.name);
+        assert_eq!(fs::metadata(&path).unwrap().len(), entry.size);
+        assert_eq!(Digest::of_file(&path).unwrap(), entry.digest);
+    }
+    assert_eq!(
+        BundleImporter::new(&archive).verify().unwrap(),
+        bundle.manifest
+    );
+
+    assert!(matches!(
+        BundleImporter::new(&archive).import(dir.join("imported")),
+        Err(BundleError::AlreadyExists(_))
+    ));
+}
+
+#[test]
+fn gnu_tar_extracts_bundles() {
+    let dir = common::test_dir("bundle", "tar");
+    let definition = definition(&dir);
+    let archive = export(&dir, &definition);
+    let out = dir.join("tar");
+    fs::create_dir(&out).unwrap();
+    let status = match Command::new("tar")
+        .arg("--zstd")
+        .arg("-xf")
+        .arg(&archive)
+        .arg("-C")
+        .arg(&out)
+        .status()
+    {
+        Ok(status) => status,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+        Err(err) => panic!("{}", err),
+    };
+    if !status.success() {
+        eprintln!("tar cannot extract zstd archives here, skipping");
+        return;
+    }
+    let manifest = BundleImporter::new(&archive).verify().unwrap();
+    for entry in manifest {
+        let path = out.join(&entry.name);
+        assert_eq!(
+            Digest::of_file(&path).unwrap(),
+            entry.digest,
+            "{}",
+            entry.name
+        );
+    }
+}
+
+#[test]
+fn damaged_archives_are_refused() {
+    let dir = common::test_dir("bundle", "damaged");
+    let definition = definition(&dir);
+    let archive = export(&dir, &definition);
+    let data = fs::read(&archive).unwrap();
+
+    let truncated = dir.join("truncated.tar.zst");
+    fs::write(&truncated, &data[..data.len() / 2]).unwrap();
+    assert!(BundleImporter::new(&truncated).verify().is_err());
+    assert!(BundleImporter::new(&truncated)
+        .import(dir.join("truncated"))
+        .is_err());
+    // nothing is left behind by the failed import
+    assert!(!dir.join("truncated").exists());
+    let leftovers: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().starts_with('.'))
+        .collect();
+    assert!(leftovers.is_empty());
+
+    let garbage = dir.join("garbage.tar.zst");
+    fs::write(&garbage, b"not a bundle at all").unwrap();
+    assert!(BundleImporter::new(&garbage).verify().is_err());
+}
+
+#[test]
+fn exports_need_a_stopped_machine_and_a_clean_definition() {
+    let dir = common::test_dir("bundle", "refused");
+    let mut definition = definition(&dir);
+    let archive = dir.join("web.tar.zst");
+    let running = FakeVm(VZVirtualMachineState::VZVirtualMachineStateRunning);
+    assert!(matches!(
+        BundleExporter::new(&definition).export(&running, &archive),
+        Err(BundleError::NotStopped(_))
+    ));
+    definition.command_line = "console=hvc0\nrm -rf /".to_string();
+    assert!(matches!(
+        BundleExporter::new(&definition).export(&FakeVm::stopped(), &archive),
+        Err(BundleError::InvalidDefinition(_))
+    ));
+    assert!(!archive.exists());
+}
@@ -0,0 +1,62 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::{FileExt, MetadataExt, PermissionsExt};
+
+use virtualization_rs::disk::clone::{clone_disk_image, sparse_copy};
+
+mod common;
+
+const MIB: u64 = 1 << 20;
+
+#[test]
+fn clones_match_the_source() {
+    let dir = common::test_dir("clone", "content");
+    let src = dir.join("golden.img");
+    let file = File::create(&src).unwrap();
+    file.set_len(8 * MIB).unwrap();
+    file.write_all_at(b"boot", 0).unwrap();
+    file.write_all_at(b"tail", 8 * MIB - 4).unwrap();
+    fs::set_permissions(&src, fs::Permissions::from_mode(0o640)).unwrap();
+
+    let image = clone_disk_image(&src, dir.join("vm1.img")).unwrap();
+    assert!(image.path.is_absolute());
+    assert_eq!(fs::read(&image.path).unwrap(), fs::read(&src).unwrap());
+    let metadata = fs::metadata(&image.path).unwrap();
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o640);
+}
+
+#[test]
+fn sparse_copies_keep_holes() {
+    let dir = common::test_dir("clone", "sparse");
+    let src = dir.join("golden.img");
+    let file = File::create(&src).unwrap();
+    file.set_len(64 * MIB).unwrap();
+    file.write_all_at(&[1; 4096], 32 * MIB).unwrap();
+
+    let dst = dir.join("copy.img");
+    sparse_copy(&src, &dst).unwrap();
+    let metadata = fs::metadata(&dst).unwrap();
+    assert_eq!(metadata.len(), 64 * MIB);
+    assert!(metadata.blocks() * 512 < 8 * MIB);
+    assert_eq!(fs::read(&dst).unwrap(), fs::read(&src).unwrap());
+}
+
+#[test]
+fn existing_destinations_are_left_alone() {
+    let dir = common::test_dir("clone", "existing");
+    let src = dir.join("golden.img");
+    fs::write(&src, vec![1u8; 4096]).unwrap();
+    let dst = dir.join("vm1.img");
+    fs::write(&dst, b"keep me").unwrap();
+
+    let err = clone_disk_image(&src, &dst).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    let err = sparse_copy(&src, &dst).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    assert_eq!(fs::read(&dst).unwrap(), b"keep me");
+
+    // a missing source must not remove an unrelated destination either
+    let err = clone_disk_image(dir.join("missing.img"), &dst).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    assert_eq!(fs::read(&dst).unwrap(), b"keep me");
+}
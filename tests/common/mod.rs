@@ -0,0 +1,63 @@
+//! helpers shared by the integration tests
+#![allow(dead_code)]
+
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use virtualization_rs::definition::{VZVirtualMachineState, VirtualMachineLifecycle};
+
+/// fresh, empty directory for the test `name` of the suite `prefix`
+///
+/// The directory and everything in it are removed when the returned guard
+/// is dropped at the end of the test.
+pub fn test_dir(prefix: &str, name: &str) -> TestDir {
+    let dir = std::env::temp_dir().join(format!("vz-{}-{}-{}", prefix, name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    TestDir(dir)
+}
+
+/// directory of a test, see `test_dir`
+pub struct TestDir(PathBuf);
+
+impl Deref for TestDir {
+    type Target = PathBuf;
+
+    fn deref(&self) -> &PathBuf {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl From<&TestDir> for PathBuf {
+    fn from(dir: &TestDir) -> PathBuf {
+        dir.0.clone()
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// stand-in for a `VZVirtualMachine` that stays in one state
+pub struct FakeVm(pub VZVirtualMachineState);
+
+impl FakeVm {
+    pub fn stopped() -> FakeVm {
+        FakeVm(VZVirtualMachineState::VZVirtualMachineStateStopped)
+    }
+}
+
+impl VirtualMachineLifecycle for FakeVm {
+    fn state(&self) -> VZVirtualMachineState {
+        self.0
+    }
+}
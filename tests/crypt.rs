@@ -0,0 +1,279 @@
+use std::fs;
+use std::io;
+use std::thread;
+
+use virtualization_rs::nbd::backend::BlockBackend;
+use virtualization_rs::nbd::crypt::{Argon2Params, CryptKey, EncryptedBackend};
+
+mod common;
+
+const HEADER_LEN: usize = 4096;
+const SECTOR: usize = 4096;
+const SIZE: u64 = 64 * 1024 + 1000;
+
+/// keeps the tests fast; real images use `Argon2Params::default()`
+const CHEAP: Argon2Params = Argon2Params {
+    memory_kib: 256,
+    iterations: 1,
+    parallelism: 1,
+};
+
+fn passphrase(passphrase: &str) -> CryptKey {
+    CryptKey::passphrase(passphrase).argon2_params(CHEAP)
+}
+
+fn pattern(len: usize, seed: usize) -> Vec<u8> {
+    (0..len).map(|i| ((i + seed) % 251) as u8).collect()
+}
+
+fn read_all<B: BlockBackend>(backend: &B) -> Vec<u8> {
+    let mut buf = vec![0u8; backend.size() as usize];
+    backend.read_at(&mut buf, 0).unwrap();
+    buf
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[test]
+fn sectors_are_aes_xts_encrypted() {
+    let dir = common::test_dir("crypt", "xts");
+    let path = dir.join("disk.img");
+    let mut key = [0u8; 64];
+    key.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+    let disk = EncryptedBackend::create(&path, SIZE, &CryptKey::raw(key)).unwrap();
+    disk.write_at(&pattern(SECTOR, 0), 5 * SECTOR as u64)
+        .unwrap();
+    disk.flush().unwrap();
+
+    // AES-256-XTS of sector 5, computed with another implementation
+    let raw = fs::read(&path).unwrap();
+    let at = HEADER_LEN + 5 * SECTOR;
+    assert_eq!(
+        hex(&raw[at..at + 32]),
+        "f87ca2f29b117c1b024a6ec8e8c5994e76f7d16b43eed21e6936126969e00dab"
+    );
+}
+
+#[test]
+fn guest_sees_plaintext_and_host_stores_ciphertext() {
+    let dir = common::test_dir("crypt", "roundtrip");
+    let path = dir.join("disk.img");
+    let key_file = dir.join("key");
+    fs::write(&key_file, pattern(64, 7)).unwrap();
+    let key = CryptKey::key_file(&key_file).unwrap();
+    let disk = EncryptedBackend::create(&path, SIZE, &key).unwrap();
+    assert_eq!(disk.size(), SIZE);
+
+    let mut expected = vec![0u8; SIZE as usize];
+    assert_eq!(read_all(&disk), expected);
+    // unaligned writes within a sector, across sectors and at the end
+    let writes = [(100, 37), (4000, 9000), (SIZE as usize - 700, 700)];
+    for (i, &(offset, len)) in writes.iter().enumerate() {
+        let data = pattern(len, i * 13);
+        disk.write_at(&data, offset as u64).unwrap();
+        expected[offset..offset + len].copy_from_slice(&data);
+    }
+    assert_eq!(read_all(&disk), expected);
+    assert!(disk.write_at(b"x", SIZE).is_err());
+    disk.flush().unwrap();
+    drop(disk);
+
+    let raw = fs::read(&path).unwrap();
+    assert!(!contains(&raw, &pattern(64, 13)));
+    let disk = EncryptedBackend::open(&path, &key, true).unwrap();
+    assert_eq!(read_all(&disk), expected);
+    assert!(disk.write_at(b"x", 0).is_err());
+}
+
+#[test]
+fn wrong_keys_are_refused() {
+    let dir = common::test_dir("crypt", "wrongkey");
+    let path = dir.join("disk.img");
+    EncryptedBackend::create(&path, SIZE, &passphrase("right")).unwrap();
+
+    let err = EncryptedBackend::open(&path, &passphrase("wrong"), false)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    let err = EncryptedBackend::open(&path, &CryptKey::raw([0; 64]), false)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    // the cost stored in the image is used, not the one of the key
+    let key = CryptKey::passphrase("right");
+    assert!(EncryptedBackend::open(&path, &key, false).is_ok());
+
+    fs::write(dir.join("short"), b"too short").unwrap();
+    assert!(CryptKey::key_file(dir.join("short")).is_err());
+    let plain = dir.join("plain.img");
+    fs::write(&plain, vec![0u8; 8192]).unwrap();
+    let err = EncryptedBackend::open(&plain, &passphrase("right"), false)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+}
+
+#[test]
+fn zeroed_sectors_read_as_zeros() {
+    let dir = common::test_dir("crypt", "zeroes");
+    let path = dir.join("disk.img");
+    let key = passphrase("zeroes");
+    let disk = EncryptedBackend::create(&path, SIZE, &key).unwrap();
+    let mut expected = pattern(SIZE as usize, 3);
+    disk.write_at(&expected, 0).unwrap();
+
+    disk.write_zeroes(1000, 3 * SECTOR as u64, true).unwrap();
+    expected[1000..1000 + 3 * SECTOR]
+        .iter_mut()
+        .for_each(|b| *b = 0);
+    disk.write_zeroes(8 * SECTOR as u64, 2 * SECTOR as u64, false)
+        .unwrap();
+    expected[8 * SECTOR..10 * SECTOR]
+        .iter_mut()
+        .for_each(|b| *b = 0);
+    disk.write_at(&vec![0u8; SECTOR], 12 * SECTOR as u64)
+        .unwrap();
+    expected[12 * SECTOR..13 * SECTOR]
+        .iter_mut()
+        .for_each(|b| *b = 0);
+    assert_eq!(read_all(&disk), expected);
+
+    // whole zero sectors are stored as zeros rather than as ciphertext
+    let raw = fs::read(&path).unwrap();
+    for sector in [1, 2, 8, 9, 12] {
+        let at = HEADER_LEN + sector * SECTOR;
+        assert!(raw[at..at + SECTOR].iter().all(|&b| b == 0));
+    }
+}
+
+#[test]
+fn rekey_reencrypts_under_the_new_key() {
+    let dir = common::test_dir("crypt", "rekey");
+    let path = dir.join("disk.img");
+    let old = passphrase("old");
+    let disk = EncryptedBackend::create(&path, SIZE, &old).unwrap();
+    let expected = pattern(SIZE as usize, 11);
+    disk.write_at(&expected, 0).unwrap();
+    disk.write_zeroes(0, 2 * SECTOR as u64, true).unwrap();
+    let mut expected = expected;
+    expected[..2 * SECTOR].iter_mut().for_each(|b| *b = 0);
+    disk.flush().unwrap();
+    drop(disk);
+    let before = fs::read(&path).unwrap();
+
+    let key_file = dir.join("key");
+    fs::write(&key_file, pattern(48, 5)).unwrap();
+    let new = CryptKey::key_file(&key_file).unwrap();
+    EncryptedBackend::rekey(&path, &old, &new).unwrap();
+    // the rotated image replaced the old one, no temporary file is left
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["disk.img", "key"]);
+
+    let after = fs::read(&path).unwrap();
+    assert_eq!(before.len(), after.len());
+    let at = HEADER_LEN + 2 * SECTOR;
+    assert_ne!(before[at..at + SECTOR], after[at..at + SECTOR]);
+    let err = EncryptedBackend::open(&path, &old, true).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    let disk = EncryptedBackend::open(&path, &new, false).unwrap();
+    assert_eq!(read_all(&disk), expected);
+
+    // a failed rotation leaves the image as it was
+    drop(disk);
+    assert!(EncryptedBackend::rekey(&path, &old, &passphrase("other")).is_err());
+    let disk = EncryptedBackend::open(&path, &new, true).unwrap();
+    assert_eq!(read_all(&disk), expected);
+}
+
+#[test]
+fn images_are_locked_while_open() {
+    let dir = common::test_dir("crypt", "lock");
+    let path = dir.join("disk.img");
+    let key = passphrase("lock");
+    let busy = |result: io::Result<EncryptedBackend>| {
+        assert_eq!(result.err().unwrap().kind(), io::ErrorKind::ResourceBusy)
+    };
+
+    let writer = EncryptedBackend::create(&path, SIZE, &key).unwrap();
+    busy(EncryptedBackend::open(&path, &key, false));
+    busy(EncryptedBackend::open(&path, &key, true));
+    let err = EncryptedBackend::rekey(&path, &key, &passphrase("new")).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+    drop(writer);
+
+    let reader = EncryptedBackend::open(&path, &key, true).unwrap();
+    let other = EncryptedBackend::open(&path, &key, true).unwrap();
+    busy(EncryptedBackend::open(&path, &key, false));
+    let err = EncryptedBackend::rekey(&path, &key, &passphrase("new")).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+    drop((reader, other));
+
+    EncryptedBackend::rekey(&path, &key, &passphrase("new")).unwrap();
+    assert!(EncryptedBackend::open(&path, &passphrase("new"), false).is_ok());
+}
+
+#[test]
+fn concurrent_reads_and_writes_stay_consistent() {
+    let dir = common::test_dir("crypt", "torn");
+    let disk =
+        EncryptedBackend::create(dir.join("disk.img"), SIZE, &CryptKey::raw([7; 64])).unwrap();
+    // each write covers parts of two sectors, which are read, modified and
+    // written back
+    let offset = SECTOR as u64 / 2;
+    disk.write_at(&[1; SECTOR], offset).unwrap();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            for i in 0..500 {
+                disk.write_at(&[(i % 2 + 1) as u8; SECTOR], offset).unwrap();
+            }
+        });
+        let mut buf = [0u8; SECTOR];
+        for _ in 0..500 {
+            disk.read_at(&mut buf, offset).unwrap();
+            assert!(buf.iter().all(|&b| b == buf[0]), "torn read");
+            assert!(buf[0] == 1 || buf[0] == 2);
+        }
+    });
+}
+
+#[test]
+fn excessive_argon2_costs_are_refused() {
+    let dir = common::test_dir("crypt", "argon2");
+    let path = dir.join("disk.img");
+    let greedy = CryptKey::passphrase("greedy").argon2_params(Argon2Params {
+        memory_kib: u32::MAX,
+        iterations: 1,
+        parallelism: 1,
+    });
+    let err = EncryptedBackend::create(&path, SIZE, &greedy)
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    assert!(!path.exists());
+
+    // a header forged to ask for a huge cost is refused before deriving
+    let key = passphrase("forged");
+    drop(EncryptedBackend::create(&path, SIZE, &key).unwrap());
+    let original = fs::read(&path).unwrap();
+    for (at, value) in [(28, u32::MAX), (32, 1_000_000), (36, 1 << 20)] {
+        let mut raw = original.clone();
+        raw[at..at + 4].copy_from_slice(&value.to_le_bytes());
+        let crc = crc32fast::hash(&raw[..104]);
+        raw[104..108].copy_from_slice(&crc.to_le_bytes());
+        fs::write(&path, &raw).unwrap();
+        let err = EncryptedBackend::open(&path, &key, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", err);
+    }
+    fs::write(&path, &original).unwrap();
+    assert!(EncryptedBackend::open(&path, &key, true).is_ok());
+}
@@ -0,0 +1,461 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use virtualization_rs::net::dhcp::*;
+use virtualization_rs::net::mac::MacAddress;
+use virtualization_rs::net::packet::{ArpPacket, EthernetFrame, UdpDatagram, ETHERTYPE_ARP};
+
+mod common;
+
+const DISCOVER: u8 = 1;
+const OFFER: u8 = 2;
+const REQUEST: u8 = 3;
+const DECLINE: u8 = 4;
+const ACK: u8 = 5;
+const NAK: u8 = 6;
+const RELEASE: u8 = 7;
+
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 1);
+const ZERO: Ipv4Addr = Ipv4Addr::UNSPECIFIED;
+const BROADCAST: Ipv4Addr = Ipv4Addr::BROADCAST;
+
+fn builder() -> DhcpServerBuilder {
+    DhcpServerBuilder::new(SERVER, 24)
+        .range(Ipv4Addr::new(10, 0, 2, 100), Ipv4Addr::new(10, 0, 2, 199))
+        .router(SERVER)
+        .dns_server(Ipv4Addr::new(10, 0, 2, 3))
+        .domain_name("vm.internal")
+}
+
+struct Client {
+    mac: MacAddress,
+    xid: u32,
+    broadcast: bool,
+}
+
+impl Client {
+    fn new(last: u8) -> Client {
+        Client {
+            mac: MacAddress::new([0x02, 0, 0, 0, 0, last]),
+            xid: 0x1234_0000 + last as u32,
+            broadcast: true,
+        }
+    }
+
+    fn frame(&self, message_type: u8, ciaddr: Ipv4Addr, options: &[(u8, Vec<u8>)]) -> Vec<u8> {
+        let mut message = vec![0u8; 240];
+        message[0] = 1;
+        message[1] = 1;
+        message[2] = 6;
+        message[4..8].copy_from_slice(&self.xid.to_be_bytes());
+        if self.broadcast {
+            message[10] = 0x80;
+        }
+        message[12..16].copy_from_slice(&ciaddr.octets());
+        message[28..34].copy_from_slice(&self.mac.octets());
+        message[236..240].copy_from_slice(&[99, 130, 83, 99]);
+        message.extend_from_slice(&[53, 1, message_type]);
+        for (code, data) in options {
+            message.push(*code);
+            message.push(data.len() as u8);
+            message.extend_from_slice(data);
+        }
+        message.push(255);
+        let (src, dst, dst_mac) = if ciaddr.is_unspecified() {
+            (ZERO, BROADCAST, MacAddress::BROADCAST)
+        } else {
+            (ciaddr, SERVER, MacAddress::for_vm("dhcp", 0))
+        };
+        UdpDatagram {
+            src: SocketAddrV4::new(src, 68),
+            dst: SocketAddrV4::new(dst, 67),
+            payload: &message,
+        }
+        .encode_frame(self.mac, dst_mac)
+    }
+
+    fn send(
+        &self,
+        server: &DhcpServer,
+        message_type: u8,
+        ciaddr: Ipv4Addr,
+        options: &[(u8, Vec<u8>)],
+    ) -> Option<Reply> {
+        let frame = self.frame(message_type, ciaddr, options);
+        let reply = server.handle_frame(&frame).unwrap()?;
+        let reply = Reply::parse(&reply);
+        assert_eq!(reply.xid, self.xid);
+        assert_eq!(reply.chaddr, self.mac);
+        Some(reply)
+    }
+
+    fn discover(&self, server: &DhcpServer) -> Option<Reply> {
+        self.send(server, DISCOVER, ZERO, &[])
+    }
+
+    fn request(&self, server: &DhcpServer, address: Ipv4Addr) -> Option<Reply> {
+        self.send(
+            server,
+            REQUEST,
+            ZERO,
+            &[
+                (54, SERVER.octets().to_vec()),
+                (50, address.octets().to_vec()),
+            ],
+        )
+    }
+
+    fn lease(&self, server: &DhcpServer) -> Ipv4Addr {
+        let offer = self.discover(server).unwrap();
+        assert_eq!(offer.message_type, OFFER);
+        let ack = self.request(server, offer.yiaddr).unwrap();
+        assert_eq!(ack.message_type, ACK);
+        ack.yiaddr
+    }
+}
+
+struct Reply {
+    dst_mac: MacAddress,
+    dst: SocketAddrV4,
+    xid: u32,
+    ciaddr: Ipv4Addr,
+    yiaddr: Ipv4Addr,
+    chaddr: MacAddress,
+    message_type: u8,
+    options: HashMap<u8, Vec<u8>>,
+}
+
+impl Reply {
+    fn parse(frame: &[u8]) -> Reply {
+        let (ethernet, udp) = UdpDatagram::parse_frame(frame).expect("UDP frame");
+        assert_eq!(ethernet.src, MacAddress::for_vm("dhcp", 0));
+        assert_eq!(udp.src, SocketAddrV4::new(SERVER, 67));
+        let message = udp.payload;
+        assert!(message.len() >= 300);
+        assert_eq!(message[0], 2);
+        assert_eq!(&message[236..240], &[99, 130, 83, 99]);
+        let mut options = HashMap::new();
+        let mut i = 240;
+        while message[i] != 255 {
+            let len = message[i + 1] as usize;
+            options.insert(message[i], message[i + 2..i + 2 + len].to_vec());
+            i += 2 + len;
+        }
+        let ip =
+            |o: usize| Ipv4Addr::new(message[o], message[o + 1], message[o + 2], message[o + 3]);
+        let mut chaddr = [0u8; 6];
+        chaddr.copy_from_slice(&message[28..34]);
+        Reply {
+            dst_mac: ethernet.dst,
+            dst: udp.dst,
+            xid: u32::from_be_bytes([message[4], message[5], message[6], message[7]]),
+            ciaddr: ip(12),
+            yiaddr: ip(16),
+            chaddr: MacAddress::new(chaddr),
+            message_type: options[&53][0],
+            options,
+        }
+    }
+}
+
+#[test]
+fn discover_offer_request_ack() {
+    let server = builder()
+        .lease_time(Duration::from_secs(600))
+        .build()
+        .unwrap();
+    let client = Client::new(1);
+
+    let offer = client.discover(&server).unwrap();
+    assert_eq!(offer.message_type, OFFER);
+    assert_eq!(offer.yiaddr, Ipv4Addr::new(10, 0, 2, 100));
+    assert_eq!(offer.dst, SocketAddrV4::new(BROADCAST, 68));
+    assert_eq!(offer.dst_mac, MacAddress::BROADCAST);
+    assert_eq!(offer.options[&54], SERVER.octets());
+    assert_eq!(offer.options[&1], [255, 255, 255, 0]);
+    assert_eq!(offer.options[&3], SERVER.octets());
+    assert_eq!(offer.options[&6], [10, 0, 2, 3]);
+    assert_eq!(offer.options[&15], b"vm.internal");
+    assert_eq!(offer.options[&51], 600u32.to_be_bytes());
+    assert_eq!(offer.options[&58], 300u32.to_be_bytes());
+    assert_eq!(offer.options[&59], 525u32.to_be_bytes());
+    assert!(server.leases().is_empty(), "offers are not leases");
+
+    // a second client is not offered the address held for the first
+    assert_eq!(
+        Client::new(2).discover(&server).unwrap().yiaddr,
+        Ipv4Addr::new(10, 0, 2, 101)
+    );
+
+    let ack = client
+        .send(
+            &server,
+            REQUEST,
+            ZERO,
+            &[
+                (54, SERVER.octets().to_vec()),
+                (50, offer.yiaddr.octets().to_vec()),
+                (12, b"Web-1.example".to_vec()),
+            ],
+        )
+        .unwrap();
+    assert_eq!(ack.message_type, ACK);
+    assert_eq!(ack.yiaddr, offer.yiaddr);
+    let leases = server.leases();
+    assert_eq!(leases.len(), 1);
+    assert_eq!(leases[0].mac, client.mac);
+    assert_eq!(leases[0].address, offer.yiaddr);
+    assert_eq!(leases[0].hostname.as_deref(), Some("web-1"));
+    assert!(leases[0].is_active());
+}
+
+#[test]
+fn replies_are_unicast_when_the_client_can_receive_them() {
+    let server = builder().build().unwrap();
+    let mut client = Client::new(1);
+    client.broadcast = false;
+    let offer = client.discover(&server).unwrap();
+    assert_eq!(offer.dst, SocketAddrV4::new(offer.yiaddr, 68));
+    assert_eq!(offer.dst_mac, client.mac);
+
+    let address = client.lease(&server);
+    // renewing: the client owns its address and asks the server directly
+    client.broadcast = true;
+    let ack = client.send(&server, REQUEST, address, &[]).unwrap();
+    assert_eq!(ack.message_type, ACK);
+    assert_eq!(ack.ciaddr, address);
+    assert_eq!(ack.dst, SocketAddrV4::new(address, 68));
+    assert_eq!(ack.dst_mac, client.mac);
+}
+
+#[test]
+fn reservations_follow_the_mac_address() {
+    let db = MacAddress::for_vm("db", 0);
+    let reserved = Ipv4Addr::new(10, 0, 2, 10);
+    let server = builder()
+        .reservation(Reservation::new(db, reserved).hostname("DB.vm.internal"))
+        .build()
+        .unwrap();
+    let client = Client {
+        mac: db,
+        xid: 7,
+        broadcast: true,
+    };
+    let offer = client.discover(&server).unwrap();
+    assert_eq!(offer.yiaddr, reserved);
+    // the reserved name is sent as the guest would have to store it
+    assert_eq!(offer.options[&12], b"db");
+    assert_eq!(client.lease(&server), reserved);
+    assert_eq!(server.leases()[0].hostname.as_deref(), Some("db"));
+
+    // nobody else gets it, and the reserved client gets nothing else
+    let other = Client::new(1);
+    assert_eq!(other.request(&server, reserved).unwrap().message_type, NAK);
+    let nak = client
+        .request(&server, Ipv4Addr::new(10, 0, 2, 100))
+        .unwrap();
+    assert_eq!(nak.message_type, NAK);
+    assert_eq!(nak.dst, SocketAddrV4::new(BROADCAST, 68));
+    assert_eq!(nak.yiaddr, ZERO);
+}
+
+#[test]
+fn requests_for_other_servers_and_bad_addresses() {
+    let server = builder().build().unwrap();
+    let client = Client::new(1);
+    let offer = client.discover(&server).unwrap();
+    let other_server = client.send(
+        &server,
+        REQUEST,
+        ZERO,
+        &[
+            (54, vec![10, 0, 2, 2]),
+            (50, offer.yiaddr.octets().to_vec()),
+        ],
+    );
+    assert!(other_server.is_none());
+    // the declined offer is free again
+    assert_eq!(
+        Client::new(2).discover(&server).unwrap().yiaddr,
+        offer.yiaddr
+    );
+
+    for &address in &[
+        Ipv4Addr::new(192, 168, 1, 5),
+        Ipv4Addr::new(10, 0, 2, 50),
+        SERVER,
+    ] {
+        // INIT-REBOOT with an address the client cannot have
+        let reply = client
+            .send(&server, REQUEST, ZERO, &[(50, address.octets().to_vec())])
+            .unwrap();
+        assert_eq!(reply.message_type, NAK, "{}", address);
+    }
+}
+
+#[test]
+fn release_and_decline() {
+    let server = builder()
+        .range(Ipv4Addr::new(10, 0, 2, 100), Ipv4Addr::new(10, 0, 2, 101))
+        .build()
+        .unwrap();
+    let (a, b, c) = (Client::new(1), Client::new(2), Client::new(3));
+    let first = a.lease(&server);
+    let second = b.lease(&server);
+    assert!(c.discover(&server).is_none(), "pool exhausted");
+
+    assert!(a
+        .send(&server, RELEASE, first, &[(54, SERVER.octets().to_vec())])
+        .is_none());
+    assert_eq!(server.leases().len(), 1);
+    // the released client gets its address back before anyone else takes it
+    assert_eq!(a.discover(&server).unwrap().yiaddr, first);
+
+    assert!(b
+        .send(
+            &server,
+            DECLINE,
+            ZERO,
+            &[
+                (54, SERVER.octets().to_vec()),
+                (50, second.octets().to_vec())
+            ]
+        )
+        .is_none());
+    assert_eq!(server.leases().len(), 0);
+    assert!(
+        b.discover(&server).is_none(),
+        "declined addresses are held back"
+    );
+}
+
+#[test]
+fn leases_survive_restarts() {
+    let dir = common::test_dir("dhcp", "persist");
+    let path = dir.join("leases");
+    let client = Client::new(1);
+    let address = {
+        let server = builder().lease_file(&path).build().unwrap();
+        client.send(
+            &server,
+            REQUEST,
+            ZERO,
+            &[(50, vec![10, 0, 2, 150]), (12, b"web".to_vec())],
+        );
+        server.leases()[0].address
+    };
+    assert_eq!(address, Ipv4Addr::new(10, 0, 2, 150));
+    assert!(fs::read_to_string(&path).unwrap().starts_with("leases 1\n"));
+
+    let server = builder().lease_file(&path).build().unwrap();
+    let leases = server.leases();
+    assert_eq!(leases.len(), 1);
+    assert_eq!(
+        (
+            leases[0].mac,
+            leases[0].address,
+            leases[0].hostname.as_deref()
+        ),
+        (client.mac, address, Some("web"))
+    );
+    assert_eq!(
+        Client::new(2)
+            .request(&server, address)
+            .unwrap()
+            .message_type,
+        NAK
+    );
+    let renew = client.send(&server, REQUEST, address, &[]).unwrap();
+    assert_eq!((renew.message_type, renew.yiaddr), (ACK, address));
+
+    fs::write(&path, "leases 1\n02:00:00:00:00:01 10.0.2.150\n").unwrap();
+    match builder().lease_file(&path).build() {
+        Err(DhcpError::InvalidLeases(2)) => {}
+        other => panic!("{:?}", other.map(|_| ())),
+    }
+}
+
+#[test]
+fn arp_and_garbage() {
+    let server = builder().build().unwrap();
+    let client = Client::new(1);
+    let request = ArpPacket {
+        operation: 1,
+        sender_mac: client.mac,
+        sender_ip: Ipv4Addr::new(10, 0, 2, 100),
+        target_mac: MacAddress::new([0; 6]),
+        target_ip: SERVER,
+    }
+    .encode();
+    let frame = EthernetFrame {
+        dst: MacAddress::BROADCAST,
+        src: client.mac,
+        ethertype: ETHERTYPE_ARP,
+        payload: &request,
+    }
+    .encode();
+    let reply = server.handle_frame(&frame).unwrap().unwrap();
+    let ethernet = EthernetFrame::parse(&reply).unwrap();
+    assert_eq!(ethernet.dst, client.mac);
+    let arp = ArpPacket::parse(ethernet.payload).unwrap();
+    assert_eq!(arp.operation, 2);
+    assert_eq!(
+        (arp.sender_mac, arp.sender_ip),
+        (server.mac_address(), SERVER)
+    );
+
+    let mut corrupt = client.frame(DISCOVER, ZERO, &[]);
+    let last = corrupt.len() - 1;
+    corrupt[last] ^= 0xff;
+    assert!(
+        server.handle_frame(&corrupt).unwrap().is_none(),
+        "bad UDP checksum"
+    );
+    assert!(server.handle_frame(&corrupt[..100]).unwrap().is_none());
+    assert!(server.handle_frame(&[]).unwrap().is_none());
+}
+
+#[test]
+fn invalid_configurations() {
+    let outside = Ipv4Addr::new(10, 0, 3, 1);
+    let errors = vec![
+        DhcpServerBuilder::new(SERVER, 31).build(),
+        builder()
+            .range(Ipv4Addr::new(10, 0, 2, 9), Ipv4Addr::new(10, 0, 2, 8))
+            .build(),
+        builder()
+            .range(Ipv4Addr::new(10, 0, 2, 9), Ipv4Addr::new(10, 0, 2, 255))
+            .build(),
+        builder()
+            .reservation(Reservation::new(MacAddress::for_vm("x", 0), outside))
+            .build(),
+        builder().domain_name("a".repeat(256)).build(),
+        (0..64)
+            .fold(builder(), |b, i| b.dns_server(Ipv4Addr::new(10, 0, 2, i)))
+            .build(),
+        builder()
+            .reservation(
+                Reservation::new(MacAddress::for_vm("x", 0), Ipv4Addr::new(10, 0, 2, 10))
+                    .hostname("-x"),
+            )
+            .build(),
+    ];
+    let errors: Vec<_> = errors
+        .into_iter()
+        .map(|r| r.err().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        errors,
+        vec![
+            "invalid prefix /31",
+            "address range is empty or outside the subnet",
+            "address range is empty or outside the subnet",
+            "10.0.3.1 is outside the subnet",
+            "domain name does not fit in a DHCP option",
+            "DNS server list does not fit in a DHCP option",
+            "invalid host name \"-x\"",
+        ]
+    );
+}
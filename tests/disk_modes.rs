@@ -0,0 +1,184 @@
+use std::fs;
+
+use virtualization_rs::definition::*;
+use virtualization_rs::disk::bundle::{BundleExporter, BundleImporter};
+
+mod common;
+
+use VZDiskImageCachingMode::*;
+use VZDiskImageSynchronizationMode::*;
+
+const CACHING_MODES: [VZDiskImageCachingMode; 3] = [
+    VZDiskImageCachingModeAutomatic,
+    VZDiskImageCachingModeUncached,
+    VZDiskImageCachingModeCached,
+];
+const SYNCHRONIZATION_MODES: [VZDiskImageSynchronizationMode; 3] = [
+    VZDiskImageSynchronizationModeFull,
+    VZDiskImageSynchronizationModeFsync,
+    VZDiskImageSynchronizationModeNone,
+];
+
+#[test]
+fn defaults_match_the_framework() {
+    let disk = StorageDeviceDefinition::new("disk.img", false);
+    assert_eq!(disk.caching_mode, VZDiskImageCachingModeAutomatic);
+    assert_eq!(
+        disk.synchronization_mode,
+        VZDiskImageSynchronizationModeFull
+    );
+    assert_eq!(VZDiskImageCachingModeCached as isize, 2);
+    assert_eq!(VZDiskImageSynchronizationModeNone as isize, 3);
+    assert_eq!(
+        VZDiskSynchronizationMode::default() as isize,
+        VZDiskSynchronizationMode::VZDiskSynchronizationModeFull as isize
+    );
+}
+
+#[test]
+fn every_combination_is_kept() {
+    let dir = common::test_dir("modes", "combinations");
+    let db = dir.join("db.img");
+    let base = dir.join("base.img");
+    fs::write(&db, vec![1u8; 4096]).unwrap();
+    fs::write(&base, vec![2u8; 4096]).unwrap();
+    let mut expected = Vec::new();
+    let mut definition = VirtualMachineDefinition::new("modes");
+    for &read_only in &[false, true] {
+        for &caching in &CACHING_MODES {
+            for &synchronization in &SYNCHRONIZATION_MODES {
+                // read-only disks and uncached ones take any synchronization
+                let path = if read_only { &base } else { &db };
+                definition.storage_devices.push(
+                    StorageDeviceDefinition::new(path, read_only)
+                        .caching_mode(caching)
+                        .synchronization_mode(synchronization),
+                );
+                expected.push((read_only, caching, synchronization));
+            }
+        }
+    }
+
+    let archive = dir.join("modes.tar.zst");
+    BundleExporter::new(&definition)
+        .export(&common::FakeVm::stopped(), &archive)
+        .unwrap();
+    let bundle = BundleImporter::new(&archive)
+        .import(dir.join("imported"))
+        .unwrap();
+    let modes: Vec<_> = bundle
+        .definition
+        .storage_devices
+        .iter()
+        .map(|d| (d.read_only, d.caching_mode, d.synchronization_mode))
+        .collect();
+    assert_eq!(modes, expected);
+}
+
+#[test]
+fn names_round_trip() {
+    for &mode in &CACHING_MODES {
+        assert_eq!(VZDiskImageCachingMode::from_name(mode.name()), Some(mode));
+    }
+    for &mode in &SYNCHRONIZATION_MODES {
+        assert_eq!(
+            VZDiskImageSynchronizationMode::from_name(mode.name()),
+            Some(mode)
+        );
+    }
+    assert_eq!(VZDiskImageCachingMode::from_name("writeback"), None);
+}
+
+#[test]
+fn bundles_keep_the_modes() {
+    let dir = common::test_dir("modes", "bundle");
+    let db = dir.join("db.img");
+    let base = dir.join("base.img");
+    fs::write(&db, vec![1u8; 8192]).unwrap();
+    fs::write(&base, vec![2u8; 4096]).unwrap();
+    let mut definition = VirtualMachineDefinition::new("db");
+    definition.storage_devices.push(
+        StorageDeviceDefinition::new(&db, false)
+            .caching_mode(VZDiskImageCachingModeUncached)
+            .synchronization_mode(VZDiskImageSynchronizationModeFsync),
+    );
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(&base, true).caching_mode(VZDiskImageCachingModeCached));
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(&base, true));
+
+    let archive = dir.join("db.tar.zst");
+    BundleExporter::new(&definition)
+        .export(&common::FakeVm::stopped(), &archive)
+        .unwrap();
+    let bundle = BundleImporter::new(&archive)
+        .import(dir.join("imported"))
+        .unwrap();
+
+    let modes: Vec<_> = bundle
+        .definition
+        .storage_devices
+        .iter()
+        .map(|d| (d.read_only, d.caching_mode, d.synchronization_mode))
+        .collect();
+    assert_eq!(
+        modes,
+        vec![
+            (
+                false,
+                VZDiskImageCachingModeUncached,
+                VZDiskImageSynchronizationModeFsync
+            ),
+            (
+                true,
+                VZDiskImageCachingModeCached,
+                VZDiskImageSynchronizationModeFull
+            ),
+            (
+                true,
+                VZDiskImageCachingModeAutomatic,
+                VZDiskImageSynchronizationModeFull
+            ),
+        ]
+    );
+    assert_eq!(
+        fs::read(&bundle.definition.storage_devices[0].path).unwrap(),
+        vec![1u8; 8192]
+    );
+}
+
+#[test]
+fn other_modes_need_support_of_the_framework() {
+    for &caching in &CACHING_MODES {
+        for &synchronization in &SYNCHRONIZATION_MODES {
+            assert_eq!(
+                validate_disk_image_modes(caching, synchronization, true),
+                Ok(())
+            );
+            let expected = if caching == VZDiskImageCachingModeAutomatic
+                && synchronization == VZDiskImageSynchronizationModeFull
+            {
+                Ok(())
+            } else {
+                Err(DiskModeError::Unavailable)
+            };
+            assert_eq!(
+                validate_disk_image_modes(caching, synchronization, false),
+                expected
+            );
+
+            // definitions are checked against the framework of this host
+            let disk = StorageDeviceDefinition::new("disk.img", false)
+                .caching_mode(caching)
+                .synchronization_mode(synchronization);
+            assert_eq!(
+                disk.validate(),
+                validate_disk_image_modes(caching, synchronization, disk_image_modes_supported())
+            );
+        }
+    }
+    #[cfg(not(target_os = "macos"))]
+    assert!(disk_image_modes_supported());
+}
@@ -0,0 +1,440 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use virtualization_rs::net::dhcp::DhcpServerBuilder;
+use virtualization_rs::net::dns::{DnsError, DnsServer, DnsServerBuilder};
+use virtualization_rs::net::mac::MacAddress;
+use virtualization_rs::net::packet::UdpDatagram;
+use virtualization_rs::net::switch::EthernetSwitchBuilder;
+
+const A: u16 = 1;
+const SOA: u16 = 6;
+const PTR: u16 = 12;
+const AAAA: u16 = 28;
+const MX: u16 = 15;
+
+const NOERROR: u16 = 0;
+const SERVFAIL: u16 = 2;
+const NXDOMAIN: u16 = 3;
+const REFUSED: u16 = 5;
+
+const DNS: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 3);
+const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 2, 100);
+const CLIENT_MAC: MacAddress = MacAddress::new([0x02, 0, 0, 0, 0, 0x64]);
+
+fn encode_name(name: &str) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    for label in name.split('.') {
+        encoded.push(label.len() as u8);
+        encoded.extend_from_slice(label.as_bytes());
+    }
+    encoded.push(0);
+    encoded
+}
+
+fn query(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    let mut query = Vec::new();
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    query.extend_from_slice(&encode_name(name));
+    query.extend_from_slice(&qtype.to_be_bytes());
+    query.extend_from_slice(&[0, 1]);
+    query
+}
+
+fn query_frame(id: u16, name: &str, qtype: u16) -> Vec<u8> {
+    UdpDatagram {
+        src: SocketAddrV4::new(CLIENT, 40000 + id),
+        dst: SocketAddrV4::new(DNS, 53),
+        payload: &query(id, name, qtype),
+    }
+    .encode_frame(CLIENT_MAC, MacAddress::for_vm("dns", 0))
+}
+
+#[derive(Debug)]
+struct Reply {
+    id: u16,
+    flags: u16,
+    rcode: u16,
+    question: Vec<u8>,
+    /// type, TTL and data of every answer record
+    answers: Vec<(u16, u32, Vec<u8>)>,
+}
+
+fn skip_name(message: &[u8], mut offset: usize) -> usize {
+    loop {
+        let len = message[offset] as usize;
+        if len == 0 {
+            return offset + 1;
+        }
+        if len & 0xc0 == 0xc0 {
+            return offset + 2;
+        }
+        offset += 1 + len;
+    }
+}
+
+fn parse_reply(message: &[u8]) -> Reply {
+    let be16 = |o: usize| u16::from_be_bytes([message[o], message[o + 1]]);
+    let flags = be16(2);
+    assert!(flags & 0x8000 != 0, "QR");
+    let mut offset = 12;
+    let mut question = Vec::new();
+    if be16(4) == 1 {
+        let end = skip_name(message, 12) + 4;
+        question = message[12..end].to_vec();
+        offset = end;
+    }
+    let mut answers = Vec::new();
+    for _ in 0..be16(6) {
+        offset = skip_name(message, offset);
+        let rtype = be16(offset);
+        let ttl = u32::from_be_bytes([
+            message[offset + 4],
+            message[offset + 5],
+            message[offset + 6],
+            message[offset + 7],
+        ]);
+        let len = be16(offset + 8) as usize;
+        answers.push((rtype, ttl, message[offset + 10..offset + 10 + len].to_vec()));
+        offset += 10 + len;
+    }
+    Reply {
+        id: be16(0),
+        flags,
+        rcode: flags & 0xf,
+        question,
+        answers,
+    }
+}
+
+fn ask(server: &DnsServer, id: u16, name: &str, qtype: u16) -> Reply {
+    let reply = server.handle_frame(&query_frame(id, name, qtype)).unwrap();
+    let (ethernet, udp) = UdpDatagram::parse_frame(&reply).unwrap();
+    assert_eq!(ethernet.dst, CLIENT_MAC);
+    assert_eq!(udp.src, SocketAddrV4::new(DNS, 53));
+    assert_eq!(udp.dst, SocketAddrV4::new(CLIENT, 40000 + id));
+    let reply = parse_reply(udp.payload);
+    assert_eq!(reply.id, id);
+    reply
+}
+
+/// answers like a recursive resolver for a handful of names
+struct Upstream {
+    addr: SocketAddr,
+    queries: Arc<AtomicUsize>,
+}
+
+impl Upstream {
+    fn start() -> Upstream {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        thread::spawn(move || {
+            let mut buf = [0u8; 512];
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let query = &buf[..n];
+                let end = skip_name(query, 12) + 4;
+                let name = {
+                    let mut labels = Vec::new();
+                    let mut o = 12;
+                    while query[o] != 0 {
+                        let len = query[o] as usize;
+                        labels
+                            .push(String::from_utf8_lossy(&query[o + 1..o + 1 + len]).to_string());
+                        o += 1 + len;
+                    }
+                    labels.join(".").to_ascii_lowercase()
+                };
+                let mut reply = query[..end].to_vec();
+                reply[2] = 0x81;
+                reply[3] = 0x80;
+                match name.as_str() {
+                    "example.com" | "zero.example.com" | "intranet" => {
+                        let ttl: u32 = if name == "zero.example.com" { 0 } else { 300 };
+                        reply[7] = 1;
+                        reply.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1]);
+                        reply.extend_from_slice(&ttl.to_be_bytes());
+                        reply.extend_from_slice(&[0, 4, 93, 184, 216, 34]);
+                    }
+                    "missing.example.com" => {
+                        reply[3] = 0x83;
+                        reply[9] = 1;
+                        let mut rdata = encode_name("ns.example.com");
+                        rdata.extend_from_slice(&encode_name("admin.example.com"));
+                        for field in &[1u32, 7200, 3600, 1209600, 60] {
+                            rdata.extend_from_slice(&field.to_be_bytes());
+                        }
+                        reply.extend_from_slice(&encode_name("example.com"));
+                        reply.extend_from_slice(&[0, SOA as u8, 0, 1]);
+                        reply.extend_from_slice(&3600u32.to_be_bytes());
+                        reply.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                        reply.extend_from_slice(&rdata);
+                    }
+                    "spoofed.example.com" => {
+                        let mut stray = reply.clone();
+                        stray[0] ^= 0xff;
+                        socket.send_to(&stray, from).unwrap();
+                        continue;
+                    }
+                    // never answered
+                    _ => continue,
+                }
+                socket.send_to(&reply, from).unwrap();
+            }
+        });
+        Upstream { addr, queries }
+    }
+
+    fn queries(&self) -> usize {
+        self.queries.load(Ordering::SeqCst)
+    }
+}
+
+#[test]
+fn static_hosts() {
+    let v6: Ipv6Addr = "fd00::10".parse().unwrap();
+    let server = DnsServerBuilder::new(DNS)
+        .domain("VM.Internal.")
+        .host("db", Ipv4Addr::new(10, 0, 2, 10))
+        .host("db", v6)
+        .host("cache", Ipv4Addr::new(10, 0, 2, 11))
+        .host("gateway.example.org", Ipv4Addr::new(10, 0, 2, 1))
+        .ttl(Duration::from_secs(5))
+        .build()
+        .unwrap();
+
+    let reply = ask(&server, 1, "DB.vm.internal", A);
+    assert_eq!(reply.rcode, NOERROR);
+    assert!(reply.flags & 0x0400 != 0, "authoritative");
+    assert_eq!(reply.question, query(1, "DB.vm.internal", A)[12..].to_vec());
+    assert_eq!(reply.answers, vec![(A, 5, vec![10, 0, 2, 10])]);
+    assert_eq!(
+        ask(&server, 2, "db.vm.internal", AAAA).answers,
+        vec![(AAAA, 5, v6.octets().to_vec())]
+    );
+    assert_eq!(ask(&server, 3, "db", A).answers.len(), 1, "single label");
+    assert_eq!(
+        ask(&server, 4, "gateway.example.org", A).answers,
+        vec![(A, 5, vec![10, 0, 2, 1])]
+    );
+
+    // names that exist without records of the type, and names that do not
+    let nodata = ask(&server, 5, "cache.vm.internal", AAAA);
+    assert_eq!((nodata.rcode, nodata.answers.len()), (NOERROR, 0));
+    let apex = ask(&server, 6, "vm.internal", A);
+    assert_eq!((apex.rcode, apex.answers.len()), (NOERROR, 0));
+    assert_eq!(ask(&server, 7, "db", MX).answers.len(), 0);
+    assert_eq!(ask(&server, 8, "web.vm.internal", A).rcode, NXDOMAIN);
+    assert_eq!(ask(&server, 9, "a.db.vm.internal", A).rcode, NXDOMAIN);
+    // single-label names nobody has are not ours to deny
+    assert_eq!(ask(&server, 10, "nope", A).rcode, REFUSED);
+
+    let ptr = ask(&server, 11, "10.2.0.10.in-addr.arpa", PTR);
+    assert_eq!(ptr.answers, vec![(PTR, 5, encode_name("db.vm.internal"))]);
+    let nibbles: Vec<String> = v6
+        .octets()
+        .iter()
+        .rev()
+        .flat_map(|o| vec![format!("{:x}", o & 0xf), format!("{:x}", o >> 4)])
+        .collect();
+    let ptr = ask(&server, 12, &format!("{}.ip6.arpa", nibbles.join(".")), PTR);
+    assert_eq!(ptr.answers, vec![(PTR, 5, encode_name("db.vm.internal"))]);
+    // no upstream to ask about anything else
+    assert_eq!(ask(&server, 13, "example.com", A).rcode, REFUSED);
+    assert_eq!(
+        ask(&server, 14, "99.2.0.10.in-addr.arpa", PTR).rcode,
+        REFUSED
+    );
+}
+
+#[test]
+fn names_from_dhcp_leases() {
+    let dhcp = DhcpServerBuilder::new(Ipv4Addr::new(10, 0, 2, 1), 24)
+        .build()
+        .unwrap();
+    let server = DnsServerBuilder::new(DNS).leases(&dhcp).build().unwrap();
+    assert_eq!(ask(&server, 1, "web.vm.internal", A).rcode, NXDOMAIN);
+
+    // a guest asking for 10.0.2.100 and calling itself "web"
+    let mut message = vec![0u8; 240];
+    message[0..3].copy_from_slice(&[1, 1, 6]);
+    message[28..34].copy_from_slice(&CLIENT_MAC.octets());
+    message[236..240].copy_from_slice(&[99, 130, 83, 99]);
+    message.extend_from_slice(&[53, 1, 3, 50, 4, 10, 0, 2, 100, 12, 3]);
+    message.extend_from_slice(b"web");
+    message.push(255);
+    let request = UdpDatagram {
+        src: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 68),
+        dst: SocketAddrV4::new(Ipv4Addr::BROADCAST, 67),
+        payload: &message,
+    }
+    .encode_frame(CLIENT_MAC, MacAddress::BROADCAST);
+    assert!(dhcp.handle_frame(&request).unwrap().is_some());
+
+    assert_eq!(
+        ask(&server, 2, "web.vm.internal", A).answers,
+        vec![(A, 30, vec![10, 0, 2, 100])]
+    );
+    assert_eq!(
+        ask(&server, 3, "100.2.0.10.in-addr.arpa", PTR).answers,
+        vec![(PTR, 30, encode_name("web.vm.internal"))]
+    );
+}
+
+#[test]
+fn forwards_and_caches() {
+    let upstream = Upstream::start();
+    let server = DnsServerBuilder::new(DNS)
+        .upstream(upstream.addr)
+        .timeout(Duration::from_millis(300))
+        .build()
+        .unwrap();
+
+    let reply = ask(&server, 1, "example.com", A);
+    assert_eq!(reply.rcode, NOERROR);
+    assert!(reply.flags & 0x0080 != 0, "recursion available");
+    assert_eq!(reply.answers, vec![(A, 300, vec![93, 184, 216, 34])]);
+    assert_eq!(upstream.queries(), 1);
+
+    // answered from the cache, with the client's ID and spelling
+    let reply = ask(&server, 2, "EXAMPLE.com", A);
+    assert_eq!(reply.question, query(2, "EXAMPLE.com", A)[12..].to_vec());
+    assert_eq!(reply.answers[0].2, vec![93, 184, 216, 34]);
+    assert!(reply.answers[0].1 <= 300);
+    assert_eq!(upstream.queries(), 1);
+    // other types are separate entries
+    ask(&server, 3, "example.com", AAAA);
+    assert_eq!(upstream.queries(), 2);
+
+    // negative answers are cached too, zero TTLs are not
+    assert_eq!(ask(&server, 4, "missing.example.com", A).rcode, NXDOMAIN);
+    assert_eq!(ask(&server, 5, "missing.example.com", A).rcode, NXDOMAIN);
+    assert_eq!(upstream.queries(), 3);
+    ask(&server, 6, "zero.example.com", A);
+    ask(&server, 7, "zero.example.com", A);
+    assert_eq!(upstream.queries(), 5);
+
+    server.flush_cache();
+    ask(&server, 8, "example.com", A);
+    assert_eq!(upstream.queries(), 6);
+
+    // single-label names of no VM go upstream as well
+    assert_eq!(
+        ask(&server, 9, "intranet", A).answers,
+        vec![(A, 300, vec![93, 184, 216, 34])]
+    );
+    assert_eq!(upstream.queries(), 7);
+}
+
+#[test]
+fn invalid_names() {
+    let long = "a".repeat(64);
+    let errors: Vec<_> = vec![
+        DnsServerBuilder::new(DNS).domain("vm..internal").build(),
+        DnsServerBuilder::new(DNS).domain(long.as_str()).build(),
+        DnsServerBuilder::new(DNS)
+            .host(long.as_str(), Ipv4Addr::new(10, 0, 2, 10))
+            .build(),
+        // the domain fits, the host name under it does not
+        DnsServerBuilder::new(DNS)
+            .domain(format!(
+                "{}.{}",
+                ["a".repeat(63), "a".repeat(63), "a".repeat(63)].join("."),
+                "a".repeat(61)
+            ))
+            .host("db", Ipv4Addr::new(10, 0, 2, 10))
+            .build(),
+    ]
+    .into_iter()
+    .map(|r| r.err().unwrap())
+    .collect();
+    assert_eq!(
+        errors,
+        vec![
+            DnsError::InvalidName("vm..internal".to_string()),
+            DnsError::InvalidName(long.clone()),
+            DnsError::InvalidName(long.clone()),
+            DnsError::InvalidName("db".to_string()),
+        ]
+    );
+    // 63-byte labels and 253 bytes in all are fine
+    let label = "b".repeat(63);
+    let domain = [label.as_str(); 3].join(".");
+    let server = DnsServerBuilder::new(DNS)
+        .domain(domain.as_str())
+        .host("c".repeat(61), Ipv4Addr::new(10, 0, 2, 10))
+        .build()
+        .unwrap();
+    let name = format!("{}.{}", "c".repeat(61), domain);
+    assert_eq!(name.len(), 253);
+    assert_eq!(
+        ask(&server, 1, &name, A).answers,
+        vec![(A, 30, vec![10, 0, 2, 10])]
+    );
+}
+
+#[test]
+fn upstream_failures() {
+    let upstream = Upstream::start();
+    let server = DnsServerBuilder::new(DNS)
+        .upstream(upstream.addr)
+        .timeout(Duration::from_millis(200))
+        .build()
+        .unwrap();
+    let reply = ask(&server, 1, "silent.example.com", A);
+    assert_eq!((reply.rcode, reply.answers.len()), (SERVFAIL, 0));
+    // a reply with the wrong ID is ignored, never cached
+    assert_eq!(ask(&server, 2, "spoofed.example.com", A).rcode, SERVFAIL);
+    assert_eq!(ask(&server, 3, "spoofed.example.com", A).rcode, SERVFAIL);
+    assert_eq!(upstream.queries(), 3);
+}
+
+#[test]
+fn serves_a_switch_port() {
+    let upstream = Upstream::start();
+    let switch = EthernetSwitchBuilder::new().build();
+    let server = DnsServerBuilder::new(DNS)
+        .host("db", Ipv4Addr::new(10, 0, 2, 10))
+        .upstream(upstream.addr)
+        .build()
+        .unwrap();
+    let (_, socket) = switch.connect().unwrap();
+    thread::spawn(move || server.serve(socket));
+    let (_, guest) = switch.connect().unwrap();
+    guest
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let mut frame = [0u8; 2048];
+    let mut answers = Vec::new();
+    // the forwarded query is answered after the local one
+    guest.send(&query_frame(1, "example.com", A)).unwrap();
+    guest.send(&query_frame(2, "db.vm.internal", A)).unwrap();
+    for _ in 0..2 {
+        let n = guest.recv(&mut frame).unwrap();
+        let (_, udp) = UdpDatagram::parse_frame(&frame[..n]).unwrap();
+        let reply = parse_reply(udp.payload);
+        let address = IpAddr::from([
+            reply.answers[0].2[0],
+            reply.answers[0].2[1],
+            reply.answers[0].2[2],
+            reply.answers[0].2[3],
+        ]);
+        answers.push((reply.id, address));
+    }
+    answers.sort();
+    assert_eq!(
+        answers,
+        vec![
+            (1, IpAddr::from([93, 184, 216, 34])),
+            (2, IpAddr::from([10, 0, 2, 10]))
+        ]
+    );
+}
@@ -0,0 +1,335 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::{symlink, FileExt, PermissionsExt};
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, UNIX_EPOCH};
+
+use virtualization_rs::disk::gpt::{Gpt, Guid};
+use virtualization_rs::disk::inspect::{inspect_disk_image, FilesystemKind};
+use virtualization_rs::fs::ext4::volume::{Ext4Volume, Ext4VolumeError, Partition};
+use virtualization_rs::fs::ext4::{Entry, Ext4ImageBuilder};
+
+mod common;
+
+/// runs `e2fsck -fn` on the image; skipped when e2fsck is not installed
+fn e2fsck(path: &Path) {
+    match Command::new("e2fsck").arg("-fn").arg(path).output() {
+        Ok(output) => assert!(
+            output.status.success(),
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            eprintln!("e2fsck not found, skipping the file system check")
+        }
+        Err(err) => panic!("{}", err),
+    }
+}
+
+/// output of a `debugfs` request; `None` when debugfs is not installed
+fn debugfs(path: &Path, request: &str) -> Option<String> {
+    match Command::new("debugfs")
+        .arg("-R")
+        .arg(request)
+        .arg(path)
+        .output()
+    {
+        Ok(output) => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+#[test]
+fn host_directories_are_copied() {
+    let dir = common::test_dir("ext4", "host");
+    let source = dir.join("rootfs");
+    fs::create_dir_all(source.join("etc/ssh")).unwrap();
+    fs::write(source.join("etc/hostname"), b"vm1\n").unwrap();
+    fs::write(source.join("etc/ssh/sshd_config"), vec![b'#'; 100_000]).unwrap();
+    fs::set_permissions(
+        source.join("etc/ssh/sshd_config"),
+        fs::Permissions::from_mode(0o600),
+    )
+    .unwrap();
+    fs::hard_link(source.join("etc/hostname"), source.join("etc/hostname.bak")).unwrap();
+    symlink("hostname", source.join("etc/name")).unwrap();
+    symlink(
+        "/".to_string() + &"long/".repeat(30),
+        source.join("etc/long"),
+    )
+    .unwrap();
+    fs::create_dir(source.join("empty")).unwrap();
+    let big: Vec<u8> = (0..5_000_000u32).map(|i| (i % 253) as u8).collect();
+    fs::write(source.join("big"), &big).unwrap();
+
+    let path = dir.join("rootfs.img");
+    Ext4ImageBuilder::new()
+        .label("rootfs")
+        .uuid([0x11; 16])
+        .host_directory("/", &source)
+        .host_owner(0, 0)
+        .entry("/dev/null", Entry::char_device(1, 3).mode(0o666))
+        .entry("/run/fifo", Entry::fifo())
+        .entry(
+            "/home/user/.profile",
+            Entry::file(b"export A=1\n".to_vec())
+                .owner(1000, 1000)
+                .mode(0o640),
+        )
+        .write_to_file(&path)
+        .unwrap();
+    e2fsck(&path);
+
+    let filesystem = inspect_disk_image(&path).unwrap().filesystem.unwrap();
+    assert_eq!(filesystem.kind, FilesystemKind::Ext4);
+    assert_eq!(filesystem.label.as_deref(), Some("rootfs"));
+    assert_eq!(
+        filesystem.uuid.as_deref(),
+        Some("11111111-1111-1111-1111-111111111111")
+    );
+
+    let volume = Ext4Volume::open(&path, Partition::Whole).unwrap();
+    assert_eq!(volume.label(), "rootfs");
+    assert_eq!(volume.read_file("/etc/hostname").unwrap(), b"vm1\n");
+    assert_eq!(volume.read_file("/etc/hostname.bak").unwrap(), b"vm1\n");
+    assert_eq!(volume.read_file("/big").unwrap(), big);
+    assert_eq!(volume.read_link("/etc/name").unwrap(), b"hostname");
+    assert_eq!(volume.read_link("/etc/long").unwrap().len(), 151);
+    assert!(volume.read_dir("/empty").unwrap().is_empty());
+    let config = volume.metadata("/etc/ssh/sshd_config").unwrap();
+    assert_eq!((config.mode, config.uid, config.gid), (0o100600, 0, 0));
+    assert_eq!(config.size, 100_000);
+    let profile = volume.metadata("/home/user/.profile").unwrap();
+    assert_eq!(
+        (profile.mode, profile.uid, profile.gid),
+        (0o100640, 1000, 1000)
+    );
+    assert_eq!(volume.metadata("/dev/null").unwrap().mode, 0o020666);
+
+    if let Some(stat) = debugfs(&path, "stat /etc/hostname") {
+        assert!(stat.contains("Links: 2"), "{}", stat);
+    }
+    if let Some(stat) = debugfs(&path, "stat /dev/null") {
+        assert!(
+            stat.contains("Device major/minor number: 01:03"),
+            "{}",
+            stat
+        );
+    }
+}
+
+#[test]
+fn sizes_and_journals_are_optional() {
+    let dir = common::test_dir("ext4", "options");
+    let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let mut builder = Ext4ImageBuilder::new().modified(modified).journal(false);
+    for i in 0..500 {
+        builder = builder.file(&format!("/many/file-{:04}", i), vec![i as u8; i]);
+    }
+    let path = dir.join("small.img");
+    builder.write_to_file(&path).unwrap();
+    e2fsck(&path);
+    let volume = Ext4Volume::open(&path, Partition::Whole).unwrap();
+    assert_eq!(volume.read_dir("/many").unwrap().len(), 500);
+    assert_eq!(
+        volume.read_file("/many/file-0321").unwrap(),
+        vec![65u8; 321]
+    );
+    if let Some(features) = debugfs(&path, "features") {
+        assert!(!features.contains("has_journal"), "{}", features);
+    }
+
+    let path = dir.join("sized.img");
+    Ext4ImageBuilder::new()
+        .size(64 << 20)
+        .file("/a", b"a".to_vec())
+        .write_to_file(&path)
+        .unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 64 << 20);
+    e2fsck(&path);
+    if let Some(features) = debugfs(&path, "features") {
+        assert!(features.contains("has_journal"), "{}", features);
+    }
+}
+
+#[test]
+fn invalid_input_is_refused() {
+    let dir = common::test_dir("ext4", "invalid");
+    let path = dir.join("bad.img");
+    let too_long = Ext4ImageBuilder::new().label("a label that is too long");
+    assert!(too_long.write_to_file(&path).is_err());
+    let too_small = Ext4ImageBuilder::new()
+        .size(1 << 20)
+        .file("/big", vec![1; 4 << 20]);
+    assert!(too_small.write_to_file(&path).is_err());
+    let file_as_dir = Ext4ImageBuilder::new()
+        .file("/a", Vec::new())
+        .file("/a/b", Vec::new());
+    assert!(file_as_dir.write_to_file(&path).is_err());
+}
+
+/// runs mkfs.ext4 on a new image; `None` when it is not installed
+fn mkfs(path: &Path, size: u64) -> Option<()> {
+    fs::File::create(path).unwrap().set_len(size).unwrap();
+    match Command::new("mkfs.ext4")
+        .args(["-q", "-F", "-L", "data"])
+        .arg(path)
+        .output()
+    {
+        Ok(output) => {
+            assert!(output.status.success(), "{:?}", output);
+            Some(())
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => panic!("{}", err),
+    }
+}
+
+fn edit(volume: &mut Ext4Volume) {
+    volume.write_file("/etc/hostname", b"vm1\n").unwrap();
+    volume.create_dir_all("/root/.ssh").unwrap();
+    volume.set_permissions("/root/.ssh", 0o700).unwrap();
+    volume
+        .write_file("/root/.ssh/authorized_keys", b"ssh-ed25519 AAAA test\n")
+        .unwrap();
+    volume
+        .set_owner("/root/.ssh/authorized_keys", 1000, 100)
+        .unwrap();
+    volume.symlink("../etc/hostname", "/root/name").unwrap();
+    let big: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+    volume.write_file("/var/big", &big).unwrap();
+    // overwriting replaces the contents and frees the old blocks
+    volume.write_file("/var/big", b"small").unwrap();
+    volume.create_dir_all("/tmp/a/b/c").unwrap();
+    volume.write_file("/tmp/a/b/c/file", b"x").unwrap();
+    volume.remove_dir_all("/tmp/a").unwrap();
+}
+
+fn check_edits(volume: &Ext4Volume) {
+    assert_eq!(volume.read_file("/etc/hostname").unwrap(), b"vm1\n");
+    assert_eq!(volume.metadata("/root/.ssh").unwrap().mode, 0o040700);
+    let keys = volume.metadata("/root/.ssh/authorized_keys").unwrap();
+    assert_eq!((keys.uid, keys.gid), (1000, 100));
+    assert_eq!(volume.read_link("/root/name").unwrap(), b"../etc/hostname");
+    assert_eq!(volume.read_file("/var/big").unwrap(), b"small");
+    assert!(!volume.exists("/tmp/a").unwrap());
+}
+
+#[test]
+fn edits_keep_mkfs_images_clean() {
+    let dir = common::test_dir("ext4", "mkfs");
+    let path = dir.join("data.img");
+    if mkfs(&path, 64 << 20).is_none() {
+        eprintln!("mkfs.ext4 not found, skipping");
+        return;
+    }
+    let mut volume = Ext4Volume::open(&path, Partition::Label("data".to_string())).unwrap();
+    volume.create_dir("/etc").unwrap();
+    volume.create_dir("/var").unwrap();
+    edit(&mut volume);
+    drop(volume);
+    e2fsck(&path);
+    check_edits(&Ext4Volume::open(&path, Partition::Whole).unwrap());
+}
+
+#[test]
+fn edits_keep_gpt_partitions_clean() {
+    let dir = common::test_dir("ext4", "gpt");
+    let fs_path = dir.join("root.img");
+    Ext4ImageBuilder::new()
+        .label("root")
+        .size(32 << 20)
+        .directory("/etc")
+        .directory("/var")
+        .file("/etc/machine-id", b"0123\n".to_vec())
+        .write_to_file(&fs_path)
+        .unwrap();
+
+    // place the file system in the second partition of a GPT disk
+    let path = dir.join("disk.img");
+    let disk = fs::File::create(&path).unwrap();
+    let total_blocks = (48 << 20) / 512;
+    disk.set_len(total_blocks * 512).unwrap();
+    let mut gpt = Gpt::new(512, total_blocks, Guid([1; 16]));
+    for (index, (first, last, name)) in [(2048, 4095, "boot"), (4096, 69631, "root")]
+        .iter()
+        .enumerate()
+    {
+        let partition = &mut gpt.partitions[index];
+        partition.type_guid = Guid::LINUX_FILESYSTEM;
+        partition.unique_guid = Guid([index as u8 + 2; 16]);
+        partition.first_lba = *first;
+        partition.last_lba = *last;
+        partition.set_name(name);
+    }
+    gpt.write(&disk).unwrap();
+    disk.write_all_at(&fs::read(&fs_path).unwrap(), 4096 * 512)
+        .unwrap();
+    drop(disk);
+
+    assert!(matches!(
+        Ext4Volume::open(&path, Partition::Index(0)),
+        Err(Ext4VolumeError::NotExt4)
+    ));
+    assert!(matches!(
+        Ext4Volume::open(&path, Partition::Name("home".to_string())),
+        Err(Ext4VolumeError::PartitionNotFound)
+    ));
+    let mut volume = Ext4Volume::open(&path, Partition::Name("root".to_string())).unwrap();
+    edit(&mut volume);
+    volume.remove_file("/etc/machine-id").unwrap();
+    drop(volume);
+
+    let volume = Ext4Volume::open(&path, Partition::Label("root".to_string())).unwrap();
+    check_edits(&volume);
+    assert!(!volume.exists("/etc/machine-id").unwrap());
+
+    // check the partition with e2fsck through a copy of its blocks
+    let mut partition = vec![0u8; 32 << 20];
+    fs::File::open(&path)
+        .unwrap()
+        .read_exact_at(&mut partition, 4096 * 512)
+        .unwrap();
+    fs::write(&fs_path, &partition).unwrap();
+    e2fsck(&fs_path);
+}
+
+#[test]
+fn invalid_edits_are_refused() {
+    let dir = common::test_dir("ext4", "errors");
+    let path = dir.join("root.img");
+    Ext4ImageBuilder::new()
+        .file("/etc/hostname", b"vm\n".to_vec())
+        .write_to_file(&path)
+        .unwrap();
+    let mut volume = Ext4Volume::open(&path, Partition::Whole).unwrap();
+    assert!(matches!(
+        volume.read_file("/missing"),
+        Err(Ext4VolumeError::NotFound(_))
+    ));
+    assert!(matches!(
+        volume.create_dir("/etc"),
+        Err(Ext4VolumeError::AlreadyExists(_))
+    ));
+    assert!(matches!(
+        volume.read_file("/etc"),
+        Err(Ext4VolumeError::IsADirectory(_))
+    ));
+    assert!(matches!(
+        volume.write_file("/etc/hostname/x", b""),
+        Err(Ext4VolumeError::NotADirectory(_))
+    ));
+    assert!(matches!(
+        volume.remove_dir("/etc"),
+        Err(Ext4VolumeError::DirectoryNotEmpty(_))
+    ));
+    assert!(matches!(
+        volume.write_file(&format!("/{}", "x".repeat(256)), b""),
+        Err(Ext4VolumeError::InvalidPath(_))
+    ));
+    drop(volume);
+    e2fsck(&path);
+}
@@ -0,0 +1,313 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+
+use virtualization_rs::disk::gpt::Guid;
+use virtualization_rs::disk::inspect::{
+    inspect_disk_image, FilesystemKind, PartitionEntry, PartitionTable,
+};
+use virtualization_rs::fs::fat::{FatImageBuilder, FatType};
+
+mod common;
+
+/// minimal reader for the volume at the start of `image`
+struct Volume<'a> {
+    image: &'a [u8],
+    sector_size: usize,
+    cluster_size: usize,
+    fat_start: usize,
+    root_start: usize,
+    root_entries: usize,
+    data_start: usize,
+    root_cluster: u32,
+    fat_type: FatType,
+}
+
+fn le16(raw: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes(raw[offset..offset + 2].try_into().unwrap()) as usize
+}
+
+fn le32(raw: &[u8], offset: usize) -> usize {
+    u32::from_le_bytes(raw[offset..offset + 4].try_into().unwrap()) as usize
+}
+
+impl<'a> Volume<'a> {
+    fn new(image: &'a [u8]) -> Volume<'a> {
+        assert_eq!(&image[510..512], &[0x55, 0xaa]);
+        let sector_size = le16(image, 11);
+        let reserved = le16(image, 14);
+        let fats = image[16] as usize;
+        let root_entries = le16(image, 17);
+        let total = match le16(image, 19) {
+            0 => le32(image, 32),
+            total => total,
+        };
+        let fat_sectors = match le16(image, 22) {
+            0 => le32(image, 36),
+            sectors => sectors,
+        };
+        let root_start = (reserved + fats * fat_sectors) * sector_size;
+        let data_start = root_start + root_entries * 32;
+        let cluster_size = image[13] as usize * sector_size;
+        let clusters = (total * sector_size - data_start) / cluster_size;
+        let fat_type = if clusters <= 4084 {
+            FatType::Fat12
+        } else if clusters <= 65524 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        Volume {
+            image,
+            sector_size,
+            cluster_size,
+            fat_start: reserved * sector_size,
+            root_start,
+            root_entries,
+            data_start,
+            root_cluster: le32(image, 44) as u32,
+            fat_type,
+        }
+    }
+
+    fn next(&self, cluster: u32) -> Option<u32> {
+        let fat = &self.image[self.fat_start..];
+        let (next, end) = match self.fat_type {
+            FatType::Fat12 => {
+                let offset = cluster as usize * 3 / 2;
+                let raw = le16(fat, offset) as u32;
+                let next = if cluster.is_multiple_of(2) {
+                    raw & 0xfff
+                } else {
+                    raw >> 4
+                };
+                (next, 0xff8)
+            }
+            FatType::Fat16 => (le16(fat, cluster as usize * 2) as u32, 0xfff8),
+            FatType::Fat32 => (
+                le32(fat, cluster as usize * 4) as u32 & 0x0fff_ffff,
+                0x0fff_fff8,
+            ),
+        };
+        assert_ne!(next, 0, "chain of cluster {} is broken", cluster);
+        if next >= end {
+            None
+        } else {
+            Some(next)
+        }
+    }
+
+    fn chain(&self, first: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut cluster = Some(first);
+        while let Some(c) = cluster {
+            let start = self.data_start + (c as usize - 2) * self.cluster_size;
+            data.extend_from_slice(&self.image[start..start + self.cluster_size]);
+            cluster = self.next(c);
+        }
+        data
+    }
+
+    fn files(&self) -> BTreeMap<String, Vec<u8>> {
+        let root = if self.fat_type == FatType::Fat32 {
+            self.chain(self.root_cluster)
+        } else {
+            self.image[self.root_start..self.root_start + self.root_entries * 32].to_vec()
+        };
+        let mut files = BTreeMap::new();
+        self.walk(&root, "", &mut files);
+        files
+    }
+
+    fn walk(&self, dir: &[u8], prefix: &str, files: &mut BTreeMap<String, Vec<u8>>) {
+        let mut long_name: Vec<u16> = Vec::new();
+        for entry in dir.chunks(32) {
+            match entry[0] {
+                0 => break,
+                0xe5 => continue,
+                _ => {}
+            }
+            let attributes = entry[11];
+            if attributes == 0x0f {
+                let mut units: Vec<u16> = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30]
+                    .iter()
+                    .map(|&o| le16(entry, o) as u16)
+                    .take_while(|&u| u != 0 && u != 0xffff)
+                    .collect();
+                units.extend_from_slice(&long_name);
+                long_name = units;
+                continue;
+            }
+            if attributes & 0x08 != 0 || entry[0] == b'.' {
+                long_name.clear();
+                continue;
+            }
+            let name = if long_name.is_empty() {
+                let base = String::from_utf8_lossy(&entry[0..8]).trim_end().to_string();
+                let ext = String::from_utf8_lossy(&entry[8..11])
+                    .trim_end()
+                    .to_string();
+                if ext.is_empty() {
+                    base
+                } else {
+                    format!("{}.{}", base, ext)
+                }
+            } else {
+                String::from_utf16(&long_name).unwrap()
+            };
+            long_name.clear();
+            let path = format!("{}/{}", prefix, name);
+            let first = ((le16(entry, 20) << 16) | le16(entry, 26)) as u32;
+            let size = le32(entry, 28);
+            if attributes & 0x10 != 0 {
+                self.walk(&self.chain(first), &path, files);
+            } else if first == 0 {
+                files.insert(path, Vec::new());
+            } else {
+                let mut data = self.chain(first);
+                data.truncate(size);
+                files.insert(path, data);
+            }
+        }
+    }
+}
+
+fn fsck(path: &Path) {
+    match Command::new("fsck.vfat").arg("-n").arg(path).output() {
+        Ok(output) => assert!(
+            output.status.success(),
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            eprintln!("fsck.vfat not found, skipping the file system check")
+        }
+        Err(err) => panic!("{}", err),
+    }
+}
+
+fn sample() -> BTreeMap<String, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    files.insert("/EFI/BOOT/BOOTAA64.EFI".to_string(), vec![0x4d; 70_000]);
+    files.insert("/a long file name.conf".to_string(), b"long".to_vec());
+    files.insert("/Mixed.Case".to_string(), b"mixed".to_vec());
+    files.insert("/dir/empty".to_string(), Vec::new());
+    files.insert("/ünïcödé.txt".to_string(), b"utf-16".to_vec());
+    files
+}
+
+#[test]
+fn every_fat_type_round_trips() {
+    let dir = common::test_dir("fat", "types");
+    for (fat_type, size, version) in [
+        (FatType::Fat12, 4 << 20, "FAT12"),
+        (FatType::Fat16, 32 << 20, "FAT16"),
+        (FatType::Fat32, 64 << 20, "FAT32"),
+    ]
+    .iter()
+    {
+        let path = dir.join(format!("{}.img", version));
+        let mut builder = FatImageBuilder::new()
+            .fat_type(*fat_type)
+            .size(*size)
+            .volume_label("data")
+            .volume_id(0x1234_abcd)
+            .directory("empty-dir");
+        for (name, data) in sample() {
+            builder = builder.file(&name, data);
+        }
+        builder.write_to_file(&path).unwrap();
+
+        let image = fs::read(&path).unwrap();
+        assert_eq!(image.len() as u64, *size);
+        let volume = Volume::new(&image);
+        assert_eq!(volume.fat_type, *fat_type);
+        assert_eq!(volume.sector_size, 512);
+        assert_eq!(volume.files(), sample());
+        fsck(&path);
+
+        let report = inspect_disk_image(&path).unwrap();
+        assert_eq!(report.table, PartitionTable::None);
+        let filesystem = report.filesystem.unwrap();
+        assert_eq!(filesystem.kind, FilesystemKind::Vfat);
+        assert_eq!(filesystem.version.as_deref(), Some(*version));
+        assert_eq!(filesystem.label.as_deref(), Some("DATA"));
+        assert_eq!(filesystem.uuid.as_deref(), Some("1234-ABCD"));
+    }
+}
+
+#[test]
+fn gpt_partitions_hold_the_volume() {
+    let dir = common::test_dir("fat", "gpt");
+    let source = dir.join("source");
+    fs::create_dir_all(source.join("BOOT")).unwrap();
+    fs::write(source.join("BOOT/grub.cfg"), b"set timeout=0\n").unwrap();
+    let path = dir.join("esp.img");
+    FatImageBuilder::new()
+        .fat_type(FatType::Fat32)
+        .size(40 << 20)
+        .volume_label("ESP")
+        .host_directory("EFI", &source)
+        .gpt_partition(Guid::EFI_SYSTEM_PARTITION, "EFI System Partition")
+        .write_to_file(&path)
+        .unwrap();
+
+    let report = inspect_disk_image(&path).unwrap();
+    match report.table {
+        PartitionTable::Gpt { block_size, .. } => assert_eq!(block_size, 512),
+        other => panic!("unexpected table {:?}", other),
+    }
+    assert_eq!(report.partitions.len(), 1);
+    let partition = &report.partitions[0];
+    assert_eq!(partition.start, 1 << 20);
+    match &partition.entry {
+        PartitionEntry::Gpt {
+            type_guid, name, ..
+        } => {
+            assert_eq!(*type_guid, Guid::EFI_SYSTEM_PARTITION);
+            assert_eq!(name, "EFI System Partition");
+        }
+        other => panic!("unexpected entry {:?}", other),
+    }
+    let filesystem = partition.filesystem.as_ref().unwrap();
+    assert_eq!(filesystem.kind, FilesystemKind::Vfat);
+    assert_eq!(filesystem.label.as_deref(), Some("ESP"));
+
+    let image = fs::read(&path).unwrap();
+    let volume = &image[partition.start as usize..(partition.start + partition.size) as usize];
+    let files = Volume::new(volume).files();
+    assert_eq!(files["/EFI/BOOT/grub.cfg"], b"set timeout=0\n");
+}
+
+#[test]
+fn sizes_fit_the_contents_by_default() {
+    let dir = common::test_dir("fat", "auto");
+    let path = dir.join("auto.img");
+    FatImageBuilder::new()
+        .file("big", vec![1u8; 3 << 20])
+        .write_to_file(&path)
+        .unwrap();
+    let image = fs::read(&path).unwrap();
+    assert!(image.len() < 8 << 20);
+    assert_eq!(Volume::new(&image).files()["/big"], vec![1u8; 3 << 20]);
+    fsck(&path);
+}
+
+#[test]
+fn invalid_input_is_refused() {
+    let dir = common::test_dir("fat", "invalid");
+    for builder in [
+        FatImageBuilder::new().volume_label("much too long"),
+        FatImageBuilder::new().file("what?", Vec::new()),
+        FatImageBuilder::new()
+            .file("a", Vec::new())
+            .file("a/b", Vec::new()),
+    ] {
+        let err = builder.write_to_file(dir.join("bad.img")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
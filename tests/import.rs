@@ -0,0 +1,269 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use virtualization_rs::disk::import::{Compression, ImageImporter, ImportError};
+
+mod common;
+
+/// image of `len` bytes: stretches of noise between long runs of zeros
+fn image(len: usize) -> Vec<u8> {
+    let mut data = vec![0u8; len];
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    for (i, byte) in data.iter_mut().enumerate() {
+        // 64 KiB of noise in every 256 KiB
+        if i % (256 << 10) < (64 << 10) {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            *byte = state as u8;
+        }
+    }
+    data
+}
+
+/// output of `tool args` fed with `input`; `None` when the tool is not
+/// installed
+fn compress(tool: &str, args: &[&str], input: &[u8]) -> Option<Vec<u8>> {
+    let mut child = match Command::new(tool)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            eprintln!("{} not found, skipping", tool);
+            return None;
+        }
+        Err(err) => panic!("{}", err),
+    };
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    let feeder = std::thread::spawn(move || stdin.write_all(&input));
+    let output = child.wait_with_output().unwrap();
+    feeder.join().unwrap().unwrap();
+    assert!(output.status.success(), "{} failed", tool);
+    Some(output.stdout)
+}
+
+/// compresses `path` in place with `tool args`, as a file so that zstd
+/// records the size of its frames
+fn compress_file(tool: &str, args: &[&str], path: &Path) -> Option<Vec<u8>> {
+    let status = match Command::new(tool).args(args).arg(path).status() {
+        Ok(status) => status,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            eprintln!("{} not found, skipping", tool);
+            return None;
+        }
+        Err(err) => panic!("{}", err),
+    };
+    assert!(status.success(), "{} failed", tool);
+    let mut out = path.as_os_str().to_owned();
+    out.push(".zst");
+    let data = fs::read(&out).unwrap();
+    fs::remove_file(&out).unwrap();
+    Some(data)
+}
+
+/// imports `compressed` and checks the image against `raw`
+fn check_import(dir: &Path, compressed: &[u8], raw: &[u8], expected: Compression, threads: usize) {
+    let src = dir.join(format!("image.{}.{}", expected, threads));
+    fs::write(&src, compressed).unwrap();
+    let dst = dir.join(format!("image.{}.{}.img", expected, threads));
+    let mut last = None;
+    let report = ImageImporter::new(&src)
+        .threads(threads)
+        .progress(|p| last = Some(*p))
+        .import(&dst)
+        .unwrap();
+    assert_eq!(report.compression, expected);
+    assert_eq!(report.path, dst);
+
+    let size = (raw.len() as u64).div_ceil(512) * 512;
+    assert_eq!(report.size, size);
+    let image = fs::read(&dst).unwrap();
+    assert_eq!(image.len() as u64, size);
+    assert!(image[..raw.len()] == *raw);
+    assert!(image[raw.len()..].iter().all(|&b| b == 0));
+
+    let last = last.expect("progress is reported at the end");
+    // skippable zstd frames are not read when frames are decoded in parallel
+    assert!(last.read > 0 && last.read <= compressed.len() as u64);
+    assert_eq!(last.compressed_size, compressed.len() as u64);
+    assert_eq!(last.written, raw.len() as u64);
+    assert_no_leftovers(dir);
+}
+
+/// checks that no temporary file is left in `dir`
+fn assert_no_leftovers(dir: &Path) {
+    let hidden: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .filter(|name| name.to_string_lossy().starts_with('.'))
+        .collect();
+    assert!(hidden.is_empty(), "{:?}", hidden);
+}
+
+#[test]
+fn gzip_images_are_imported() {
+    let dir = common::test_dir("import", "gzip");
+    let raw = image(1 << 20);
+    let compressed = match compress("gzip", &["-c"], &raw) {
+        Some(data) => data,
+        None => return,
+    };
+    check_import(&dir, &compressed, &raw, Compression::Gzip, 1);
+
+    // concatenated members are one image
+    let (head, tail) = raw.split_at(300_000);
+    let mut members = compress("gzip", &["-c"], head).unwrap();
+    members.extend(compress("gzip", &["-c"], tail).unwrap());
+    check_import(&dir, &members, &raw, Compression::Gzip, 2);
+}
+
+#[test]
+fn xz_images_are_imported() {
+    let dir = common::test_dir("import", "xz");
+    let raw = image(1 << 20);
+    // several blocks, decoded on several threads
+    let compressed = match compress("xz", &["-c", "-T2", "--block-size=200000"], &raw) {
+        Some(data) => data,
+        None => return,
+    };
+    check_import(&dir, &compressed, &raw, Compression::Xz, 1);
+    check_import(&dir, &compressed, &raw, Compression::Xz, 4);
+
+    // concatenated streams with padding in between
+    let (head, tail) = raw.split_at(400_000);
+    let mut streams = compress("xz", &["-c"], head).unwrap();
+    streams.extend([0u8; 8]);
+    streams.extend(compress("xz", &["-c"], tail).unwrap());
+    check_import(&dir, &streams, &raw, Compression::Xz, 2);
+}
+
+#[test]
+fn zstd_images_are_imported() {
+    let dir = common::test_dir("import", "zstd");
+    // a size that is not a multiple of 512 bytes is rounded up
+    let raw = image((1 << 20) + 100);
+    // a stream read from a pipe does not record its size
+    let compressed = match compress("zstd", &["-c", "-q"], &raw) {
+        Some(data) => data,
+        None => return,
+    };
+    check_import(&dir, &compressed, &raw, Compression::Zstd, 1);
+    check_import(&dir, &compressed, &raw, Compression::Zstd, 4);
+}
+
+#[test]
+fn multi_frame_zstd_images_are_imported() {
+    let dir = common::test_dir("import", "frames");
+    let raw = image(3 << 20);
+    // frames compressed from files record their size and are decoded in
+    // parallel, like those of pzstd
+    let mut frames = Vec::new();
+    for (i, part) in raw.chunks(700_000).enumerate() {
+        let path = dir.join(format!("part{}", i));
+        fs::write(&path, part).unwrap();
+        match compress_file("zstd", &["-q", "--content-size"], &path) {
+            Some(frame) => frames.extend(frame),
+            None => return,
+        }
+    }
+    // skippable frames are ignored
+    frames.extend(0x184d_2a50u32.to_le_bytes());
+    frames.extend(4u32.to_le_bytes());
+    frames.extend(b"skip");
+    check_import(&dir, &frames, &raw, Compression::Zstd, 4);
+    check_import(&dir, &frames, &raw, Compression::Zstd, 1);
+}
+
+#[test]
+fn zero_blocks_become_holes() {
+    let dir = common::test_dir("import", "sparse");
+    let raw = image(4 << 20);
+    let compressed = match compress("zstd", &["-c", "-q"], &raw) {
+        Some(data) => data,
+        None => return,
+    };
+    let src = dir.join("image.zst");
+    fs::write(&src, &compressed).unwrap();
+    let dst = dir.join("image.img");
+    let report = ImageImporter::new(&src).import(&dst).unwrap();
+    assert_eq!(report.size, raw.len() as u64);
+    // a quarter of every 256 KiB is noise, and the noise is block aligned
+    assert_eq!(report.allocated, raw.len() as u64 / 4);
+    let metadata = fs::metadata(&dst).unwrap();
+    assert_eq!(metadata.len(), raw.len() as u64);
+    assert!(
+        metadata.blocks() * 512 < raw.len() as u64 / 2,
+        "{} blocks allocated",
+        metadata.blocks()
+    );
+}
+
+#[test]
+fn corrupt_and_unknown_sources_leave_nothing_behind() {
+    let dir = common::test_dir("import", "corrupt");
+    let raw = image(1 << 20);
+    let compressed = match compress("gzip", &["-c"], &raw) {
+        Some(data) => data,
+        None => return,
+    };
+    let src = dir.join("truncated.gz");
+    fs::write(&src, &compressed[..compressed.len() / 2]).unwrap();
+    match ImageImporter::new(&src).import(dir.join("truncated.img")) {
+        Err(ImportError::Corrupt(_)) => {}
+        other => panic!("{:?}", other),
+    }
+
+    let src = dir.join("raw.img");
+    fs::write(&src, &raw).unwrap();
+    match ImageImporter::new(&src).import(dir.join("copy.img")) {
+        Err(ImportError::UnknownFormat(path)) => assert_eq!(path, src),
+        other => panic!("{:?}", other),
+    }
+
+    let src = dir.join("image.gz");
+    fs::write(&src, &compressed).unwrap();
+    match ImageImporter::new(&src).import(&src) {
+        Err(ImportError::AlreadyExists(path)) => assert_eq!(path, src),
+        other => panic!("{:?}", other),
+    }
+
+    let mut names: Vec<_> = fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    names.sort();
+    assert_eq!(names, ["image.gz", "raw.img", "truncated.gz"]);
+}
+
+#[test]
+fn files_created_during_the_import_are_kept() {
+    let dir = common::test_dir("import", "race");
+    let raw = image(1 << 20);
+    let compressed = match compress("gzip", &["-c"], &raw) {
+        Some(data) => data,
+        None => return,
+    };
+    let src = dir.join("image.gz");
+    fs::write(&src, &compressed).unwrap();
+    let dst = dir.join("image.img");
+    let result = ImageImporter::new(&src)
+        .progress(|_| {
+            if !dst.exists() {
+                fs::write(&dst, b"other").unwrap();
+            }
+        })
+        .import(&dst);
+    match result {
+        Err(ImportError::AlreadyExists(path)) => assert_eq!(path, dst),
+        other => panic!("{:?}", other),
+    }
+    assert_eq!(fs::read(&dst).unwrap(), b"other");
+    assert_no_leftovers(&dir);
+}
@@ -0,0 +1,330 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use virtualization_rs::definition::*;
+use virtualization_rs::disk::gpt::{Gpt, Guid};
+use virtualization_rs::disk::inspect::*;
+use virtualization_rs::disk::scratch::{ScratchDiskBuilder, ScratchFormat};
+use virtualization_rs::fs::ext4::Ext4ImageBuilder;
+use virtualization_rs::fs::fat::FatImageBuilder;
+use virtualization_rs::fs::iso9660::IsoImageBuilder;
+
+mod common;
+
+const MIB: u64 = 1 << 20;
+const SECTORS_PER_MIB: u64 = MIB / 512;
+
+fn ext4(dir: &Path, size: u64, label: &str) -> Vec<u8> {
+    let path = dir.join(format!("{}.ext4", label));
+    Ext4ImageBuilder::new()
+        .size(size)
+        .label(label)
+        .uuid([0x11; 16])
+        .write_to_file(&path)
+        .unwrap();
+    fs::read(path).unwrap()
+}
+
+fn fat(dir: &Path, size: u64, label: &str) -> Vec<u8> {
+    let path = dir.join(format!("{}.fat", label));
+    FatImageBuilder::new()
+        .size(size)
+        .volume_label(label)
+        .volume_id(0x1234_abcd)
+        .file("hello.txt", b"hello".to_vec())
+        .write_to_file(&path)
+        .unwrap();
+    fs::read(path).unwrap()
+}
+
+/// first page of a swap area, which is all that identifies it
+fn swap(dir: &Path, label: &str) -> Vec<u8> {
+    let disk = ScratchDiskBuilder::new(MIB)
+        .format(ScratchFormat::Swap)
+        .label(label)
+        .dir(dir)
+        .build()
+        .unwrap();
+    fs::read(disk.path()).unwrap()[..4096].to_vec()
+}
+
+fn blank(path: &Path, size: u64) -> File {
+    let file = File::create(path).unwrap();
+    file.set_len(size).unwrap();
+    file
+}
+
+/// partition table entry of an MBR or EBR
+fn mbr_entry(sector: &mut [u8], index: usize, type_id: u8, lba: u64, sectors: u64) {
+    let entry = &mut sector[446 + index * 16..462 + index * 16];
+    entry[4] = type_id;
+    entry[8..12].copy_from_slice(&(lba as u32).to_le_bytes());
+    entry[12..16].copy_from_slice(&(sectors as u32).to_le_bytes());
+}
+
+fn boot_sector() -> [u8; 512] {
+    let mut sector = [0u8; 512];
+    sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+    sector
+}
+
+fn numbers(report: &DiskReport) -> Vec<usize> {
+    report.partitions.iter().map(|p| p.number).collect()
+}
+
+#[test]
+fn gpt_partitions_and_their_file_systems_are_reported() {
+    let dir = common::test_dir("inspect", "gpt");
+    let path = dir.join("disk.img");
+    let size = 64 * MIB;
+    let file = blank(&path, size);
+    let mut gpt = Gpt::new(512, size / 512, Guid([7; 16]));
+    let swap_type: Guid = "0657FD6D-A4AB-43C4-84E5-0933C84B4F4F".parse().unwrap();
+    // the second slot stays empty, so the numbers are 1, 3 and 4
+    let layout = [
+        (0, Guid::EFI_SYSTEM_PARTITION, 1, 16, "EFI", 0),
+        (2, Guid::LINUX_FILESYSTEM, 17, 24, "root", 1 << 2),
+        (3, swap_type, 41, 8, "swap", 1 << 63),
+    ];
+    for &(slot, type_guid, start_mib, size_mib, name, attributes) in layout.iter() {
+        let partition = &mut gpt.partitions[slot];
+        partition.type_guid = type_guid;
+        partition.unique_guid = Guid([slot as u8 + 1; 16]);
+        partition.first_lba = start_mib * SECTORS_PER_MIB;
+        partition.last_lba = (start_mib + size_mib) * SECTORS_PER_MIB - 1;
+        partition.attributes = attributes;
+        partition.set_name(name);
+    }
+    gpt.write(&file).unwrap();
+    gpt.create_protective_mbr(&file, size / 512).unwrap();
+    file.write_all_at(&fat(&dir, 16 * MIB, "EFI"), MIB).unwrap();
+    file.write_all_at(&ext4(&dir, 24 * MIB, "rootfs"), 17 * MIB)
+        .unwrap();
+    file.write_all_at(&swap(&dir, "swap0"), 41 * MIB).unwrap();
+
+    let report = inspect_disk_image(&path).unwrap();
+    assert_eq!(report.size, size);
+    assert_eq!(
+        report.table,
+        PartitionTable::Gpt {
+            disk_guid: Guid([7; 16]),
+            block_size: 512
+        }
+    );
+    assert_eq!(report.filesystem, None);
+    assert_eq!(numbers(&report), vec![1, 3, 4]);
+
+    let efi = &report.partitions[0];
+    assert_eq!((efi.start, efi.size), (MIB, 16 * MIB));
+    assert_eq!(efi.entry.type_name(), Some("EFI System"));
+    let fs = efi.filesystem.as_ref().unwrap();
+    assert_eq!(fs.kind, FilesystemKind::Vfat);
+    assert_eq!(fs.label.as_deref(), Some("EFI"));
+    assert_eq!(fs.uuid.as_deref(), Some("1234-ABCD"));
+
+    let root = &report.partitions[1];
+    assert_eq!((root.start, root.size), (17 * MIB, 24 * MIB));
+    assert_eq!(root.entry.type_name(), Some("Linux filesystem"));
+    assert_eq!(root.entry.flags(), vec!["legacy-bios-bootable"]);
+    match &root.entry {
+        PartitionEntry::Gpt {
+            unique_guid, name, ..
+        } => {
+            assert_eq!(*unique_guid, Guid([3; 16]));
+            assert_eq!(name, "root");
+        }
+        entry => panic!("{:?}", entry),
+    }
+    let fs = root.filesystem.as_ref().unwrap();
+    assert_eq!(fs.kind, FilesystemKind::Ext4);
+    assert_eq!(fs.label.as_deref(), Some("rootfs"));
+    assert_eq!(
+        fs.uuid.as_deref(),
+        Some("11111111-1111-1111-1111-111111111111")
+    );
+
+    let swap = &report.partitions[2];
+    assert_eq!(swap.entry.type_name(), Some("Linux swap"));
+    assert_eq!(swap.entry.flags(), vec!["no-automount"]);
+    let fs = swap.filesystem.as_ref().unwrap();
+    assert_eq!(fs.kind, FilesystemKind::Swap);
+    assert_eq!(fs.label.as_deref(), Some("swap0"));
+
+    let text = report.to_string();
+    assert!(text.contains("gpt, disk guid"), "{}", text);
+    assert!(text.contains("ext4 (1.0) label=\"rootfs\""), "{}", text);
+}
+
+/// MBR disk with a primary ext4 partition and an extended partition whose
+/// second EBR has no partition of its own, only the link to the third
+fn mbr_image(dir: &Path) -> PathBuf {
+    let path = dir.join("mbr.img");
+    let file = blank(&path, 48 * MIB);
+    let mut mbr = boot_sector();
+    mbr[440..444].copy_from_slice(&0xdead_beefu32.to_le_bytes());
+    mbr_entry(&mut mbr, 0, 0x83, SECTORS_PER_MIB, 16 * SECTORS_PER_MIB);
+    mbr[446] = 0x80;
+    let extended = 17 * SECTORS_PER_MIB;
+    mbr_entry(&mut mbr, 1, 0x05, extended, 31 * SECTORS_PER_MIB);
+    file.write_all_at(&mbr, 0).unwrap();
+    file.write_all_at(&ext4(dir, 16 * MIB, "primary"), MIB)
+        .unwrap();
+
+    // links are relative to the extended partition, partitions to their EBR
+    let mut ebr = boot_sector();
+    mbr_entry(&mut ebr, 0, 0x82, SECTORS_PER_MIB, SECTORS_PER_MIB);
+    mbr_entry(&mut ebr, 1, 0x05, 2 * SECTORS_PER_MIB, 2 * SECTORS_PER_MIB);
+    file.write_all_at(&ebr, extended * 512).unwrap();
+    file.write_all_at(&swap(dir, "logical"), (extended + SECTORS_PER_MIB) * 512)
+        .unwrap();
+
+    let mut empty = boot_sector();
+    mbr_entry(
+        &mut empty,
+        1,
+        0x05,
+        4 * SECTORS_PER_MIB,
+        20 * SECTORS_PER_MIB,
+    );
+    file.write_all_at(&empty, (extended + 2 * SECTORS_PER_MIB) * 512)
+        .unwrap();
+
+    let mut last = boot_sector();
+    mbr_entry(&mut last, 0, 0x0c, SECTORS_PER_MIB, 16 * SECTORS_PER_MIB);
+    let last_lba = extended + 4 * SECTORS_PER_MIB;
+    file.write_all_at(&last, last_lba * 512).unwrap();
+    file.write_all_at(
+        &fat(dir, 16 * MIB, "DATA"),
+        (last_lba + SECTORS_PER_MIB) * 512,
+    )
+    .unwrap();
+    path
+}
+
+#[test]
+fn logical_partitions_are_numbered_as_linux_does() {
+    let dir = common::test_dir("inspect", "mbr");
+    let path = mbr_image(&dir);
+    let report = inspect_disk_image(&path).unwrap();
+    assert_eq!(
+        report.table,
+        PartitionTable::Mbr {
+            disk_signature: 0xdead_beef
+        }
+    );
+    // the EBR without a partition does not take a number
+    assert_eq!(numbers(&report), vec![1, 2, 5, 6]);
+
+    let primary = &report.partitions[0];
+    assert_eq!(primary.entry.flags(), vec!["boot"]);
+    assert_eq!(
+        primary.filesystem.as_ref().map(|fs| fs.kind),
+        Some(FilesystemKind::Ext4)
+    );
+    let extended = &report.partitions[1];
+    assert_eq!(extended.entry.type_name(), Some("Extended"));
+    assert_eq!(extended.filesystem, None);
+
+    let swap = &report.partitions[2];
+    assert_eq!(swap.start, 18 * MIB);
+    assert_eq!(
+        swap.entry,
+        PartitionEntry::Mbr {
+            type_id: 0x82,
+            bootable: false,
+            logical: true
+        }
+    );
+    assert_eq!(
+        swap.filesystem.as_ref().and_then(|fs| fs.label.as_deref()),
+        Some("logical")
+    );
+    let data = &report.partitions[3];
+    assert_eq!((data.start, data.size), (22 * MIB, 16 * MIB));
+    let fs = data.filesystem.as_ref().unwrap();
+    assert_eq!(fs.kind, FilesystemKind::Vfat);
+    assert_eq!(fs.label.as_deref(), Some("DATA"));
+
+    // libblkid, which follows the kernel, agrees
+    let output = match Command::new("partx")
+        .args(["--show", "--noheadings", "--output", "NR,START,SECTORS"])
+        .arg(&path)
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return,
+        Err(err) => panic!("{}", err),
+    };
+    assert!(output.status.success());
+    let listed: Vec<Vec<u64>> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(|line| {
+            line.split_whitespace()
+                .map(|field| field.parse().unwrap())
+                .collect()
+        })
+        .collect();
+    let ours: Vec<Vec<u64>> = report
+        .partitions
+        .iter()
+        .map(|p| vec![p.number as u64, p.start / 512, p.size / 512])
+        .collect();
+    // partx lists the extended partition with a size of one or two sectors
+    let without_extended = |rows: &[Vec<u64>]| -> Vec<Vec<u64>> {
+        rows.iter().filter(|row| row[0] != 2).cloned().collect()
+    };
+    assert_eq!(without_extended(&listed), without_extended(&ours));
+}
+
+#[test]
+fn unpartitioned_images_report_their_file_system() {
+    let dir = common::test_dir("inspect", "whole");
+
+    let ext4_path = dir.join("ext4.img");
+    fs::write(&ext4_path, ext4(&dir, 16 * MIB, "whole")).unwrap();
+    let fat_path = dir.join("fat.img");
+    fs::write(&fat_path, fat(&dir, 16 * MIB, "WHOLE")).unwrap();
+    let iso_path = dir.join("seed.iso");
+    IsoImageBuilder::new()
+        .volume_id("cidata")
+        .file("meta-data", b"instance-id: test\n".to_vec())
+        .write_to_file(&iso_path)
+        .unwrap();
+    let swap_path = dir.join("swap.img");
+    fs::write(&swap_path, swap(&dir, "whole")).unwrap();
+    let empty_path = dir.join("empty.img");
+    blank(&empty_path, MIB);
+
+    let expected = [
+        (&ext4_path, Some(FilesystemKind::Ext4), Some("whole")),
+        (&fat_path, Some(FilesystemKind::Vfat), Some("WHOLE")),
+        (&iso_path, Some(FilesystemKind::Iso9660), Some("cidata")),
+        (&swap_path, Some(FilesystemKind::Swap), Some("whole")),
+        (&empty_path, None, None),
+    ];
+    let mut definition = VirtualMachineDefinition::new("inspect");
+    for (path, kind, label) in expected.iter() {
+        let report = inspect_disk_image(path).unwrap();
+        // a FAT boot sector carries the MBR signature but is no table
+        assert_eq!(report.table, PartitionTable::None, "{}", path.display());
+        assert!(report.partitions.is_empty());
+        assert_eq!(report.filesystem.as_ref().map(|fs| fs.kind), *kind);
+        assert_eq!(
+            report
+                .filesystem
+                .as_ref()
+                .and_then(|fs| fs.label.as_deref()),
+            *label
+        );
+        definition
+            .storage_devices
+            .push(StorageDeviceDefinition::new(path.as_path(), true));
+    }
+
+    let reports = inspect_definition(&definition).unwrap();
+    assert_eq!(reports.len(), expected.len());
+    assert_eq!(reports[2].path, iso_path);
+}
@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs;
+use std::process::Command;
+use std::time::{Duration, UNIX_EPOCH};
+
+use virtualization_rs::cloud_init::NoCloudSeedBuilder;
+use virtualization_rs::fs::iso9660::IsoImageBuilder;
+
+mod common;
+
+const SECTOR: usize = 2048;
+
+fn le32(raw: &[u8]) -> usize {
+    u32::from_le_bytes(raw[0..4].try_into().unwrap()) as usize
+}
+
+/// files of the image by path, read through the Rock Ridge or Joliet tree
+fn files(image: &[u8], joliet: bool) -> BTreeMap<String, Vec<u8>> {
+    let descriptor = &image[(if joliet { 17 } else { 16 }) * SECTOR..][..SECTOR];
+    assert_eq!(descriptor[0], if joliet { 2 } else { 1 });
+    assert_eq!(&descriptor[1..6], b"CD001");
+    if joliet {
+        assert_eq!(&descriptor[88..91], b"%/E");
+    }
+    let root = &descriptor[156..190];
+    let mut files = BTreeMap::new();
+    walk(
+        image,
+        le32(&root[2..]),
+        le32(&root[10..]),
+        "",
+        joliet,
+        &mut files,
+    );
+    files
+}
+
+fn walk(
+    image: &[u8],
+    extent: usize,
+    size: usize,
+    prefix: &str,
+    joliet: bool,
+    files: &mut BTreeMap<String, Vec<u8>>,
+) {
+    let dir = &image[extent * SECTOR..extent * SECTOR + size];
+    let mut pos = 0;
+    while pos < dir.len() {
+        let len = dir[pos] as usize;
+        if len == 0 {
+            // records do not cross sectors
+            pos = (pos / SECTOR + 1) * SECTOR;
+            continue;
+        }
+        let record = &dir[pos..pos + len];
+        pos += len;
+        let id_len = record[32] as usize;
+        let id = &record[33..33 + id_len];
+        if id == [0] || id == [1] {
+            continue;
+        }
+        let name = if joliet {
+            let units: Vec<u16> = id
+                .chunks(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16(&units)
+                .unwrap()
+                .trim_end_matches(";1")
+                .to_string()
+        } else {
+            rock_ridge_name(&record[33 + id_len + (1 - id_len % 2)..]).unwrap()
+        };
+        let path = format!("{}/{}", prefix, name);
+        let (child, child_size) = (le32(&record[2..]), le32(&record[10..]));
+        if record[25] & 2 != 0 {
+            walk(image, child, child_size, &path, joliet, files);
+        } else {
+            files.insert(
+                path,
+                image[child * SECTOR..child * SECTOR + child_size].to_vec(),
+            );
+        }
+    }
+}
+
+fn rock_ridge_name(mut susp: &[u8]) -> Option<String> {
+    while susp.len() >= 4 {
+        let len = susp[2] as usize;
+        if &susp[0..2] == b"NM" {
+            return Some(String::from_utf8(susp[5..len].to_vec()).unwrap());
+        }
+        susp = &susp[len..];
+    }
+    None
+}
+
+#[test]
+fn seeds_hold_the_nocloud_files() {
+    let dir = common::test_dir("iso9660", "seed");
+    let path = dir.join("seed.iso");
+    NoCloudSeedBuilder::new()
+        .meta_data("instance-id: vm1\n")
+        .user_data("#cloud-config\n")
+        .network_config("version: 2\n")
+        .write_to_file(&path)
+        .unwrap();
+    let image = fs::read(&path).unwrap();
+    assert_eq!(image.len() % SECTOR, 0);
+    assert_eq!(&image[16 * SECTOR + 40..16 * SECTOR + 46], b"CIDATA");
+
+    for joliet in [false, true].iter() {
+        let files = files(&image, *joliet);
+        assert_eq!(files.len(), 3);
+        assert_eq!(files["/meta-data"], b"instance-id: vm1\n");
+        assert_eq!(files["/user-data"], b"#cloud-config\n");
+        assert_eq!(files["/network-config"], b"version: 2\n");
+    }
+
+    if let Ok(output) = Command::new("blkid")
+        .args(["-p", "-o", "export"])
+        .arg(&path)
+        .output()
+    {
+        let output = String::from_utf8_lossy(&output.stdout);
+        assert!(output.contains("TYPE=iso9660"), "{}", output);
+        assert!(output.contains("LABEL=cidata"), "{}", output);
+    }
+}
+
+#[test]
+fn long_names_and_nested_directories_survive() {
+    let long = "a-rather-long-file-name-that-is-not-8.3-at-all.configuration";
+    let mut expected = BTreeMap::new();
+    expected.insert(format!("/etc/{}", long), b"long".to_vec());
+    expected.insert("/etc/deep/er/file.txt".to_string(), vec![7u8; 5000]);
+    expected.insert("/Mixed Case.TXT".to_string(), Vec::new());
+    expected.insert("/ünïcode".to_string(), b"utf-8".to_vec());
+    let mut builder = IsoImageBuilder::new().volume_id("test").directory("/empty");
+    for (path, data) in expected.iter() {
+        builder = builder.file(path, data.clone());
+    }
+    let mut image = Vec::new();
+    builder.write(&mut image).unwrap();
+
+    assert_eq!(files(&image, false), expected);
+    assert_eq!(files(&image, true), expected);
+}
+
+#[test]
+fn output_is_reproducible() {
+    let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+    let build = || {
+        let mut image = Vec::new();
+        IsoImageBuilder::new()
+            .modified(modified)
+            .file("b", b"2".to_vec())
+            .file("a", b"1".to_vec())
+            .write(&mut image)
+            .unwrap();
+        image
+    };
+    assert_eq!(build(), build());
+}
+
+#[test]
+fn invalid_paths_are_refused() {
+    let too_long = "x".repeat(65);
+    for path in [too_long.as_str(), "a/../b", "nul\0"].iter() {
+        let mut image = Vec::new();
+        let err = IsoImageBuilder::new()
+            .file(path, Vec::new())
+            .write(&mut image)
+            .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{}", path);
+    }
+    // a file cannot also be a directory
+    let mut image = Vec::new();
+    assert!(IsoImageBuilder::new()
+        .file("a", Vec::new())
+        .file("a/b", Vec::new())
+        .write(&mut image)
+        .is_err());
+}
@@ -0,0 +1,111 @@
+use std::fs;
+use std::io;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use virtualization_rs::disk::lock::{DiskImageLock, DiskImageLockError};
+
+mod common;
+
+fn assert_locked(result: Result<DiskImageLock, DiskImageLockError>, pid: u32) {
+    match result {
+        Err(DiskImageLockError::Locked { pid: holder, .. }) => assert_eq!(holder, Some(pid)),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn readers_share_and_writers_exclude() {
+    let dir = common::test_dir("lock", "modes");
+    let path = dir.join("disk.img");
+    fs::write(&path, b"disk").unwrap();
+    let me = std::process::id();
+
+    let first = DiskImageLock::shared(&path).unwrap();
+    let second = DiskImageLock::shared(&path).unwrap();
+    assert!(!first.is_exclusive());
+    assert_eq!(first.path(), path.as_path());
+    assert_locked(DiskImageLock::exclusive(&path), me);
+    drop(first);
+    assert_locked(DiskImageLock::exclusive(&path), me);
+    drop(second);
+
+    let writer = DiskImageLock::exclusive(&path).unwrap();
+    assert!(writer.is_exclusive());
+    assert_locked(DiskImageLock::shared(&path), me);
+    assert_locked(DiskImageLock::exclusive(&path), me);
+    // a hard link is the same image
+    let link = dir.join("link.img");
+    fs::hard_link(&path, &link).unwrap();
+    assert_locked(DiskImageLock::shared(&link), me);
+    drop(writer);
+    DiskImageLock::exclusive(&link).unwrap();
+}
+
+#[test]
+fn exclusive_locks_open_the_image_for_writing() {
+    let dir = common::test_dir("lock", "file");
+    let path = dir.join("disk.img");
+    fs::write(&path, b"disk").unwrap();
+
+    let reader = DiskImageLock::shared(&path).unwrap();
+    let mut file = reader.file();
+    assert!(io::Write::write(&mut file, b"x").is_err());
+    drop(reader);
+    let writer = DiskImageLock::exclusive(&path).unwrap();
+    let mut file = writer.file();
+    io::Write::write_all(&mut file, b"D").unwrap();
+    drop(writer);
+    assert_eq!(fs::read(&path).unwrap(), b"Disk");
+
+    match DiskImageLock::shared(dir.join("missing.img")) {
+        Err(DiskImageLockError::Io(err)) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn locks_of_other_processes_name_the_holder() {
+    let dir = common::test_dir("lock", "process");
+    let path = dir.join("disk.img");
+    fs::write(&path, b"disk").unwrap();
+    let ready = dir.join("ready");
+    let script = "import fcntl, sys, time\n\
+                  f = open(sys.argv[1])\n\
+                  fcntl.flock(f, fcntl.LOCK_EX)\n\
+                  open(sys.argv[2], 'w').close()\n\
+                  time.sleep(30)\n";
+    let mut child = match Command::new("python3")
+        .arg("-c")
+        .arg(script)
+        .arg(&path)
+        .arg(&ready)
+        .stdin(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            eprintln!("python3 not found, skipping");
+            return;
+        }
+        Err(err) => panic!("{}", err),
+    };
+    let start = Instant::now();
+    while !ready.exists() {
+        assert!(start.elapsed() < Duration::from_secs(10));
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    let result = DiskImageLock::shared(&path);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    match result {
+        Err(DiskImageLockError::Locked { pid, .. }) => {
+            if cfg!(target_os = "linux") {
+                assert_eq!(pid, Some(child.id()));
+            }
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+}
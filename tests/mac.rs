@@ -0,0 +1,85 @@
+use std::collections::HashSet;
+
+use virtualization_rs::net::mac::{MacAddress, MacNotation};
+
+const NOTATIONS: [MacNotation; 4] = [
+    MacNotation::Colon,
+    MacNotation::Hyphen,
+    MacNotation::Dotted,
+    MacNotation::Bare,
+];
+
+#[test]
+fn every_notation_round_trips() {
+    let mac = MacAddress::new([0x0a, 0x1b, 0x2c, 0x3d, 0x4e, 0x5f]);
+    for &notation in &NOTATIONS {
+        let text = mac.format(notation);
+        assert_eq!(text.parse::<MacAddress>(), Ok(mac), "{}", text);
+        assert_eq!(text.to_uppercase().parse::<MacAddress>(), Ok(mac));
+    }
+    assert_eq!(mac.to_string(), "0a:1b:2c:3d:4e:5f");
+    assert_eq!(
+        "a:1b:2c:3d:4e:5f".parse::<MacAddress>(),
+        Ok(mac),
+        "colon notation without leading zeros"
+    );
+}
+
+#[test]
+fn malformed_addresses_are_rejected() {
+    for text in &[
+        "",
+        "0a:1b:2c:3d:4e",
+        "0a:1b:2c:3d:4e:5f:60",
+        "0a:1b:2c:3d:4e:5g",
+        "0a-1b-2c-3d-4e-5",
+        "0a1b.2c3d.4e5",
+        "0a:1b-2c:3d:4e:5f",
+        "0a1b2c3d4e5f0",
+        "+a:1b:2c:3d:4e:5f",
+    ] {
+        let err = text.parse::<MacAddress>().unwrap_err();
+        assert!(err.to_string().contains(text));
+    }
+}
+
+#[test]
+fn address_bits() {
+    assert!(MacAddress::BROADCAST.is_broadcast());
+    assert!(MacAddress::BROADCAST.is_multicast());
+    let multicast: MacAddress = "01:00:5e:00:00:fb".parse().unwrap();
+    assert!(multicast.is_multicast() && !multicast.is_broadcast());
+    let vendor: MacAddress = "00:1c:42:00:00:01".parse().unwrap();
+    assert!(vendor.is_unicast() && vendor.is_universally_administered());
+    assert_eq!(vendor.oui(), [0x00, 0x1c, 0x42]);
+}
+
+#[test]
+fn derived_addresses_are_stable_and_distinct() {
+    assert_eq!(
+        MacAddress::for_vm("web", 0).to_string(),
+        MacAddress::for_vm("web", 0).to_string()
+    );
+    let mut seen = HashSet::new();
+    for vm in 0..64 {
+        for nic in 0..4 {
+            let mac = MacAddress::for_vm(&format!("vm{}", vm), nic);
+            assert!(mac.is_unicast() && mac.is_locally_administered());
+            assert!(seen.insert(mac), "{} collides", mac);
+        }
+    }
+    assert_ne!(MacAddress::for_vm("a", 10), MacAddress::for_vm("a1", 0));
+}
+
+#[test]
+fn derived_addresses_do_not_change_between_releases() {
+    // DHCP reservations and guest configurations hold these addresses
+    for &(name, nic, expected) in &[
+        ("web", 0, "66:97:0d:d3:08:d7"),
+        ("web", 1, "b6:b4:97:5f:48:f0"),
+        ("db", 0, "16:9b:2c:26:73:60"),
+        ("", 0, "da:08:63:5a:e2:67"),
+    ] {
+        assert_eq!(MacAddress::for_vm(name, nic).to_string(), expected);
+    }
+}
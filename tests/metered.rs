@@ -0,0 +1,161 @@
+use std::fs;
+use std::time::{Duration, Instant};
+
+use virtualization_rs::nbd::backend::{BlockBackend, FileBackend};
+use virtualization_rs::nbd::metered::{IoLimits, LatencyHistogram, MeteredBackend};
+
+mod common;
+
+const SIZE: usize = 64 * 1024;
+
+/// backend on a zeroed disk of `SIZE` bytes
+fn test_disk(name: &str) -> FileBackend {
+    let dir = common::test_dir("metered", name);
+    let path = dir.join("disk.img");
+    fs::write(&path, vec![0u8; SIZE]).unwrap();
+    FileBackend::open(&path, false).unwrap()
+}
+
+#[test]
+fn operations_are_counted_per_kind() {
+    let disk = MeteredBackend::new(test_disk("counters"));
+    let meter = disk.meter();
+
+    let mut buf = vec![0u8; 4096];
+    disk.write_at(&buf, 0).unwrap();
+    disk.write_at(&buf[..100], 8192).unwrap();
+    disk.read_at(&mut buf, 4096).unwrap();
+    assert!(disk.read_at(&mut buf, SIZE as u64).is_err());
+    disk.flush().unwrap();
+    disk.trim(0, 8192).unwrap();
+    disk.write_zeroes(8192, 4096, false).unwrap();
+
+    let stats = meter.stats();
+    assert_eq!((stats.write.ops, stats.write.bytes), (2, 4196));
+    assert_eq!((stats.read.ops, stats.read.bytes), (2, 8192));
+    assert_eq!((stats.read.errors, stats.write.errors), (1, 0));
+    assert_eq!(stats.flush.ops, 1);
+    assert_eq!((stats.discard.ops, stats.discard.bytes), (2, 12288));
+    assert_eq!(stats.read.latency.count(), 2);
+    assert!(stats.write.mean_latency().is_some());
+    assert_eq!(stats.throttled_ops, 0);
+
+    meter.reset();
+    let stats = meter.stats();
+    assert_eq!(stats.read.ops + stats.write.ops, 0);
+    assert_eq!(stats.read.latency.quantile(0.5), None);
+}
+
+#[test]
+fn latency_quantiles_use_bucket_bounds() {
+    let disk = MeteredBackend::new(test_disk("quantiles"));
+    let mut buf = vec![0u8; 512];
+    for _ in 0..10 {
+        disk.read_at(&mut buf, 0).unwrap();
+    }
+    let latency = disk.meter().stats().read.latency;
+    let p99 = latency.quantile(0.99).unwrap();
+    assert!(latency.quantile(0.0).unwrap() <= p99);
+    assert!((0..28).any(|i| LatencyHistogram::upper_bound(i) == p99));
+}
+
+#[test]
+fn limits_delay_operations_beyond_the_burst() {
+    let disk = MeteredBackend::new(test_disk("iops")).limits(IoLimits {
+        iops: Some(40),
+        bandwidth: None,
+    });
+    let meter = disk.meter();
+    let mut buf = vec![0u8; 512];
+
+    // a second worth of operations passes at once, the next ten take 1/4s
+    let start = Instant::now();
+    for _ in 0..50 {
+        disk.read_at(&mut buf, 0).unwrap();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+    let stats = meter.stats();
+    assert!(stats.throttled_ops >= 9, "{:?}", stats);
+    assert!(stats.throttled_time >= Duration::from_millis(200));
+
+    // lifting the limits at runtime takes effect immediately
+    meter.set_limits(IoLimits::unlimited());
+    assert_eq!(meter.limits(), IoLimits::unlimited());
+    for _ in 0..200 {
+        disk.read_at(&mut buf, 0).unwrap();
+    }
+    let after = meter.stats();
+    assert_eq!(after.throttled_ops, stats.throttled_ops);
+    assert_eq!(after.throttled_time, stats.throttled_time);
+    assert_eq!(after.read.ops, 250);
+}
+
+#[test]
+fn a_zero_rate_is_unlimited() {
+    let disk = MeteredBackend::new(test_disk("zero")).limits(IoLimits {
+        iops: Some(0),
+        bandwidth: Some(0),
+    });
+    assert_eq!(disk.meter().limits(), IoLimits::unlimited());
+    let mut buf = vec![0u8; 512];
+    for _ in 0..100 {
+        disk.read_at(&mut buf, 0).unwrap();
+    }
+    assert_eq!(disk.meter().stats().throttled_ops, 0);
+
+    disk.meter().set_limits(IoLimits {
+        iops: Some(0),
+        bandwidth: None,
+    });
+    assert_eq!(disk.meter().limits(), IoLimits::unlimited());
+}
+
+#[test]
+fn lowering_a_limit_keeps_the_spent_tokens() {
+    let disk = MeteredBackend::new(test_disk("lower")).limits(IoLimits {
+        iops: None,
+        bandwidth: Some(SIZE as u64),
+    });
+    let mut buf = vec![0u8; SIZE];
+    // the whole burst is spent, a smaller bucket does not start full
+    disk.read_at(&mut buf, 0).unwrap();
+    disk.meter().set_limits(IoLimits {
+        iops: None,
+        bandwidth: Some(SIZE as u64 / 2),
+    });
+    disk.read_at(&mut buf[..4096], 0).unwrap();
+    let stats = disk.meter().stats();
+    assert_eq!(stats.throttled_ops, 1);
+    assert!(stats.throttled_time >= Duration::from_millis(50));
+
+    // raising it keeps them too
+    disk.meter().set_limits(IoLimits {
+        iops: None,
+        bandwidth: Some(SIZE as u64 * 4),
+    });
+    disk.read_at(&mut buf, 0).unwrap();
+    assert_eq!(disk.meter().stats().throttled_ops, 2);
+}
+
+#[test]
+fn bandwidth_limits_count_bytes() {
+    let disk = MeteredBackend::new(test_disk("bandwidth")).limits(IoLimits {
+        iops: None,
+        bandwidth: Some(128 * 1024),
+    });
+    let buf = vec![0u8; SIZE];
+
+    // 64 KiB twice fits the burst, another 64 KiB needs half a second
+    let start = Instant::now();
+    for _ in 0..3 {
+        disk.write_at(&buf, 0).unwrap();
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(400), "{:?}", elapsed);
+    let stats = disk.meter().stats();
+    assert_eq!(stats.write.bytes, 3 * SIZE as u64);
+    assert_eq!(stats.throttled_ops, 1);
+    assert!(stats.throttled_time >= Duration::from_millis(400));
+    assert!(stats.throttled_time <= Duration::from_millis(500));
+}
@@ -0,0 +1,263 @@
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+
+use virtualization_rs::nbd::backend::FileBackend;
+use virtualization_rs::nbd::server::{NbdExport, NbdServer, NbdServerBuilder};
+use virtualization_rs::nbd::{tcp_url, unix_url};
+
+mod common;
+
+const SIZE: usize = 64 * 1024;
+const IHAVEOPT: u64 = 0x4948_4156_454f_5054;
+const OPT_EXPORT_NAME: u32 = 1;
+const OPT_LIST: u32 = 3;
+const OPT_GO: u32 = 7;
+const OPT_STRUCTURED_REPLY: u32 = 8;
+const REP_ACK: u32 = 1;
+const REP_SERVER: u32 = 2;
+const REP_INFO: u32 = 3;
+const REP_ERR_UNKNOWN: u32 = (1 << 31) | 6;
+const CMD_READ: u16 = 0;
+const CMD_WRITE: u16 = 1;
+const CMD_DISC: u16 = 2;
+const CMD_FLUSH: u16 = 3;
+const CMD_WRITE_ZEROES: u16 = 6;
+const REPLY_TYPE_OFFSET_DATA: u16 = 1;
+const REPLY_TYPE_OFFSET_HOLE: u16 = 2;
+const REPLY_TYPE_ERROR: u16 = (1 << 15) | 1;
+
+struct Client {
+    stream: UnixStream,
+    handle: u64,
+}
+
+impl Client {
+    fn connect(server: &NbdServer) -> (Client, thread::JoinHandle<std::io::Result<()>>) {
+        let (client, stream) = UnixStream::pair().unwrap();
+        let server = server.clone();
+        let thread = thread::spawn(move || server.handle_connection(stream));
+        let mut client = Client {
+            stream: client,
+            handle: 0,
+        };
+        assert_eq!(client.u64(), 0x4e42_444d_4147_4943);
+        assert_eq!(client.u64(), IHAVEOPT);
+        assert_eq!(client.u16() & 3, 3);
+        // fixed newstyle, no zeroes
+        client.stream.write_all(&3u32.to_be_bytes()).unwrap();
+        (client, thread)
+    }
+
+    fn bytes(&mut self, len: usize) -> Vec<u8> {
+        let mut buf = vec![0u8; len];
+        self.stream.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_be_bytes(self.bytes(2).try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_be_bytes(self.bytes(4).try_into().unwrap())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_be_bytes(self.bytes(8).try_into().unwrap())
+    }
+
+    fn option(&mut self, option: u32, data: &[u8]) {
+        let mut buf = IHAVEOPT.to_be_bytes().to_vec();
+        buf.extend_from_slice(&option.to_be_bytes());
+        buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf).unwrap();
+    }
+
+    /// reads an option reply, returning its type and data
+    fn option_reply(&mut self, option: u32) -> (u32, Vec<u8>) {
+        assert_eq!(self.u64(), 0x0003_e889_0455_65a9);
+        assert_eq!(self.u32(), option);
+        let reply = self.u32();
+        let len = self.u32() as usize;
+        (reply, self.bytes(len))
+    }
+
+    fn go(&mut self, name: &str) -> Result<(u64, u16), u32> {
+        let mut data = (name.len() as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(name.as_bytes());
+        data.extend_from_slice(&0u16.to_be_bytes());
+        self.option(OPT_GO, &data);
+        let mut export = None;
+        loop {
+            match self.option_reply(OPT_GO) {
+                (REP_INFO, info) => {
+                    if info[0..2] == [0, 0] {
+                        export = Some((
+                            u64::from_be_bytes(info[2..10].try_into().unwrap()),
+                            u16::from_be_bytes(info[10..12].try_into().unwrap()),
+                        ));
+                    }
+                }
+                (REP_ACK, _) => return Ok(export.unwrap()),
+                (error, _) => return Err(error),
+            }
+        }
+    }
+
+    fn request(&mut self, command: u16, offset: u64, length: u32, data: &[u8]) -> u64 {
+        self.handle += 1;
+        let mut buf = 0x2560_9513u32.to_be_bytes().to_vec();
+        buf.extend_from_slice(&0u16.to_be_bytes());
+        buf.extend_from_slice(&command.to_be_bytes());
+        buf.extend_from_slice(&self.handle.to_be_bytes());
+        buf.extend_from_slice(&offset.to_be_bytes());
+        buf.extend_from_slice(&length.to_be_bytes());
+        buf.extend_from_slice(data);
+        self.stream.write_all(&buf).unwrap();
+        self.handle
+    }
+
+    /// reads a simple reply, returning its error and `len` bytes of data
+    fn simple_reply(&mut self, handle: u64, len: usize) -> (u32, Vec<u8>) {
+        assert_eq!(self.u32(), 0x6744_6698);
+        let error = self.u32();
+        assert_eq!(self.u64(), handle);
+        let data = if error == 0 {
+            self.bytes(len)
+        } else {
+            Vec::new()
+        };
+        (error, data)
+    }
+
+    /// reads a single chunk structured reply, returning its type and payload
+    fn chunk(&mut self, handle: u64) -> (u16, Vec<u8>) {
+        assert_eq!(self.u32(), 0x668e_33ef);
+        assert_eq!(self.u16(), 1);
+        let reply_type = self.u16();
+        assert_eq!(self.u64(), handle);
+        let len = self.u32() as usize;
+        (reply_type, self.bytes(len))
+    }
+}
+
+fn server(name: &str) -> NbdServer {
+    let dir = common::test_dir("nbd", name);
+    let path = dir.join("disk.img");
+    let mut data = vec![0u8; SIZE];
+    data[..4096].iter_mut().for_each(|b| *b = 0xaa);
+    fs::write(&path, &data).unwrap();
+    NbdServerBuilder::new()
+        .export(NbdExport::new(
+            "disk",
+            FileBackend::open(&path, false).unwrap(),
+        ))
+        .export(
+            NbdExport::new("ro", FileBackend::open(&path, true).unwrap())
+                .read_only(true)
+                .description("read-only view"),
+        )
+        .build()
+}
+
+#[test]
+fn structured_replies_report_data_and_holes() {
+    let server = server("structured");
+    let (mut client, thread) = Client::connect(&server);
+
+    client.option(OPT_LIST, &[]);
+    let mut names = Vec::new();
+    loop {
+        match client.option_reply(OPT_LIST) {
+            (REP_SERVER, data) => names.push(String::from_utf8(data[4..].to_vec()).unwrap()),
+            (REP_ACK, _) => break,
+            other => panic!("unexpected reply {:?}", other),
+        }
+    }
+    assert_eq!(names, vec!["disk", "ro"]);
+
+    client.option(OPT_STRUCTURED_REPLY, &[]);
+    assert_eq!(client.option_reply(OPT_STRUCTURED_REPLY).0, REP_ACK);
+    assert_eq!(client.go("nope"), Err(REP_ERR_UNKNOWN));
+    let (size, flags) = client.go("disk").unwrap();
+    assert_eq!(size, SIZE as u64);
+    assert_eq!(flags & 2, 0);
+
+    let handle = client.request(CMD_READ, 0, 4096, &[]);
+    let (reply_type, payload) = client.chunk(handle);
+    assert_eq!(reply_type, REPLY_TYPE_OFFSET_DATA);
+    assert_eq!(&payload[..8], &0u64.to_be_bytes());
+    assert!(payload[8..].iter().all(|&b| b == 0xaa));
+
+    let handle = client.request(CMD_READ, 8192, 4096, &[]);
+    let (reply_type, payload) = client.chunk(handle);
+    assert_eq!(reply_type, REPLY_TYPE_OFFSET_HOLE);
+    assert_eq!(&payload[..8], &8192u64.to_be_bytes());
+    assert_eq!(&payload[8..], &4096u32.to_be_bytes());
+
+    let handle = client.request(CMD_READ, SIZE as u64 - 512, 1024, &[]);
+    let (reply_type, payload) = client.chunk(handle);
+    assert_eq!(reply_type, REPLY_TYPE_ERROR);
+    assert_eq!(&payload[..4], &22u32.to_be_bytes());
+
+    client.request(CMD_DISC, 0, 0, &[]);
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn simple_replies_read_back_writes() {
+    let server = server("simple");
+    let (mut client, thread) = Client::connect(&server);
+    client.option(OPT_EXPORT_NAME, b"disk");
+    assert_eq!(client.u64(), SIZE as u64);
+    client.u16();
+
+    let handle = client.request(CMD_WRITE, 100, 5, b"hello");
+    assert_eq!(client.simple_reply(handle, 0).0, 0);
+    let handle = client.request(CMD_WRITE_ZEROES, 0, 50, &[]);
+    assert_eq!(client.simple_reply(handle, 0).0, 0);
+    let handle = client.request(CMD_FLUSH, 0, 0, &[]);
+    assert_eq!(client.simple_reply(handle, 0).0, 0);
+
+    let handle = client.request(CMD_READ, 40, 70, &[]);
+    let (error, data) = client.simple_reply(handle, 70);
+    assert_eq!(error, 0);
+    assert_eq!(&data[..10], &[0; 10]);
+    assert!(data[10..60].iter().all(|&b| b == 0xaa));
+    assert_eq!(&data[60..65], b"hello");
+
+    drop(client);
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn read_only_exports_refuse_writes() {
+    let server = server("read-only");
+    let (mut client, thread) = Client::connect(&server);
+    let (_, flags) = client.go("ro").unwrap();
+    assert_ne!(flags & 2, 0);
+
+    let handle = client.request(CMD_WRITE, 0, 4, b"nope");
+    assert_eq!(client.simple_reply(handle, 0).0, 1);
+    let handle = client.request(CMD_READ, 0, 4, &[]);
+    assert_eq!(client.simple_reply(handle, 4), (0, vec![0xaa; 4]));
+
+    client.request(CMD_DISC, 0, 0, &[]);
+    thread.join().unwrap().unwrap();
+}
+
+#[test]
+fn urls_are_percent_encoded() {
+    assert_eq!(
+        unix_url("/tmp/my vm/nbd.sock", "disk 1"),
+        "nbd+unix:///disk%201?socket=/tmp/my%20vm/nbd.sock"
+    );
+    assert_eq!(
+        tcp_url("127.0.0.1:10809".parse().unwrap(), "a/b"),
+        "nbd://127.0.0.1:10809/a%2Fb"
+    );
+}
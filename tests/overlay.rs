@@ -0,0 +1,189 @@
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use virtualization_rs::nbd::backend::{BlockBackend, FileBackend};
+use virtualization_rs::nbd::overlay::OverlayBackend;
+
+mod common;
+
+const CLUSTER: u32 = 4096;
+const SIZE: usize = 64 * 1024 + 1000;
+
+fn create_base(dir: &Path) -> (PathBuf, Vec<u8>) {
+    let base: Vec<u8> = (0..SIZE).map(|i| (i % 251) as u8).collect();
+    let path = dir.join("base.img");
+    fs::write(&path, &base).unwrap();
+    (path, base)
+}
+
+fn read_all<B: BlockBackend>(backend: &B) -> Vec<u8> {
+    let mut buf = vec![0u8; backend.size() as usize];
+    backend.read_at(&mut buf, 0).unwrap();
+    buf
+}
+
+#[test]
+fn reads_fall_through_to_the_base() {
+    let dir = common::test_dir("overlay", "fallthrough");
+    let (base_path, mut expected) = create_base(&dir);
+    let base = FileBackend::open(&base_path, true).unwrap();
+    let overlay = OverlayBackend::create(dir.join("vm.delta"), base, CLUSTER).unwrap();
+
+    overlay.write_at(b"hello", 4094).unwrap();
+    expected[4094..4099].copy_from_slice(b"hello");
+    overlay.write_zeroes(8192, 8192, true).unwrap();
+    expected[8192..16384].iter_mut().for_each(|b| *b = 0);
+    overlay.write_at(b"tail", SIZE as u64 - 4).unwrap();
+    expected[SIZE - 4..].copy_from_slice(b"tail");
+
+    assert_eq!(read_all(&overlay), expected);
+    assert_eq!(overlay.allocated_clusters(), 5);
+    assert!(overlay.write_at(b"x", SIZE as u64).is_err());
+    // the base is never written
+    assert_eq!(fs::read(&base_path).unwrap(), create_base(&dir).1);
+}
+
+#[test]
+fn flushed_writes_survive_a_crash() {
+    let dir = common::test_dir("overlay", "flushed");
+    let (base_path, mut expected) = create_base(&dir);
+    let delta = dir.join("vm.delta");
+    let base = FileBackend::open(&base_path, true).unwrap();
+    let overlay = OverlayBackend::create(&delta, base, CLUSTER).unwrap();
+    overlay.write_at(b"durable", 100).unwrap();
+    overlay.flush().unwrap();
+    expected[100..107].copy_from_slice(b"durable");
+    // crash: no flush from drop
+    mem::forget(overlay);
+
+    let base = FileBackend::open(&base_path, true).unwrap();
+    let overlay = OverlayBackend::open(&delta, base, false).unwrap();
+    assert_eq!(read_all(&overlay), expected);
+}
+
+#[test]
+fn unflushed_copy_up_is_invisible_after_a_crash() {
+    let dir = common::test_dir("overlay", "unflushed");
+    let (base_path, expected) = create_base(&dir);
+    let delta = dir.join("vm.delta");
+    let base = FileBackend::open(&base_path, true).unwrap();
+    let overlay = OverlayBackend::create(&delta, base, CLUSTER).unwrap();
+    // the copied-up cluster reaches the delta, but the bitmap is not persisted
+    overlay.write_at(b"lost", 5000).unwrap();
+    mem::forget(overlay);
+
+    let base = FileBackend::open(&base_path, true).unwrap();
+    let overlay = OverlayBackend::open(&delta, base, false).unwrap();
+    assert_eq!(overlay.allocated_clusters(), 0);
+    assert_eq!(read_all(&overlay), expected);
+}
+
+#[test]
+fn torn_bitmap_update_keeps_the_previous_bitmap() {
+    let dir = common::test_dir("overlay", "torn");
+    let (base_path, mut expected) = create_base(&dir);
+    let delta = dir.join("vm.delta");
+    let base = FileBackend::open(&base_path, true).unwrap();
+    let overlay = OverlayBackend::create(&delta, base, CLUSTER).unwrap();
+    overlay.write_at(b"first", 0).unwrap();
+    overlay.flush().unwrap();
+    expected[0..5].copy_from_slice(b"first");
+    mem::forget(overlay);
+
+    // crash while the next bitmap was being written
+    let bitmap = OverlayBackend::bitmap_path(&delta);
+    let name = bitmap.file_name().unwrap().to_string_lossy();
+    let tmp = bitmap.with_file_name(format!(".{}.{}.0.tmp", name, std::process::id()));
+    let partial = fs::read(&bitmap).unwrap();
+    fs::write(&tmp, &partial[..partial.len() / 2]).unwrap();
+
+    let base = FileBackend::open(&base_path, true).unwrap();
+    let overlay = OverlayBackend::open(&delta, base, false).unwrap();
+    assert_eq!(read_all(&overlay), expected);
+
+    // a damaged bitmap is refused instead of silently exposing the base
+    drop(overlay);
+    fs::write(&bitmap, &partial[..partial.len() - 1]).unwrap();
+    let base = FileBackend::open(&base_path, true).unwrap();
+    assert!(OverlayBackend::open(&delta, base, false).is_err());
+}
+
+#[test]
+fn interrupted_commit_can_be_resumed() {
+    let dir = common::test_dir("overlay", "commit");
+    let (base_path, mut expected) = create_base(&dir);
+    let delta = dir.join("vm.delta");
+    let base = FileBackend::open(&base_path, false).unwrap();
+    let overlay = OverlayBackend::create(&delta, base, CLUSTER).unwrap();
+    overlay.write_at(&[0xaa; 3000], 1000).unwrap();
+    overlay.write_at(&[0xbb; 10], 40000).unwrap();
+    overlay.flush().unwrap();
+    expected[1000..4000].iter_mut().for_each(|b| *b = 0xaa);
+    expected[40000..40010].iter_mut().for_each(|b| *b = 0xbb);
+    mem::forget(overlay);
+
+    // crash after the first cluster was committed into the base
+    let mut base_data = fs::read(&base_path).unwrap();
+    base_data[0..4096].copy_from_slice(&expected[0..4096]);
+    fs::write(&base_path, &base_data).unwrap();
+
+    let base = FileBackend::open(&base_path, false).unwrap();
+    let overlay = OverlayBackend::open(&delta, base, false).unwrap();
+    assert_eq!(read_all(&overlay), expected);
+
+    overlay.commit().unwrap();
+    assert_eq!(overlay.allocated_clusters(), 0);
+    assert_eq!(read_all(&overlay), expected);
+    drop(overlay);
+    assert_eq!(fs::read(&base_path).unwrap(), expected);
+}
+
+#[test]
+fn chain_of_read_only_bases() {
+    let dir = common::test_dir("overlay", "chain");
+    let (base_path, mut expected) = create_base(&dir);
+    let lower = dir.join("lower.delta");
+    let upper = dir.join("upper.delta");
+
+    let base = FileBackend::open(&base_path, true).unwrap();
+    let overlay = OverlayBackend::create(&lower, base, CLUSTER).unwrap();
+    overlay.write_at(b"lower", 0).unwrap();
+    drop(overlay);
+    expected[0..5].copy_from_slice(b"lower");
+
+    let base = FileBackend::open(&base_path, true).unwrap();
+    let lower_layer = OverlayBackend::open(&lower, base, true).unwrap();
+    assert!(lower_layer.write_at(b"x", 0).is_err());
+    let overlay = OverlayBackend::create(&upper, lower_layer, CLUSTER).unwrap();
+    overlay.write_at(b"upper", 10).unwrap();
+    expected[10..15].copy_from_slice(b"upper");
+    assert_eq!(read_all(&overlay), expected);
+    // the lower layer is opened read-only, so nothing can be committed into it
+    assert!(overlay.commit().is_err());
+}
+
+#[test]
+fn bad_cluster_sizes_are_refused() {
+    let dir = common::test_dir("overlay", "cluster-size");
+    let (base_path, _) = create_base(&dir);
+    let delta = dir.join("vm.delta");
+    let base = FileBackend::open(&base_path, true).unwrap();
+    assert!(OverlayBackend::create(&delta, base, 256).is_err());
+    let base = FileBackend::open(&base_path, true).unwrap();
+    drop(OverlayBackend::create(&delta, base, CLUSTER).unwrap());
+
+    // a bitmap with a valid checksum but a nonsensical cluster size
+    let bitmap = OverlayBackend::bitmap_path(&delta);
+    let mut raw = fs::read(&bitmap).unwrap();
+    for cluster_size in [0u32, 1, 3, 256].iter() {
+        raw[8..12].copy_from_slice(&cluster_size.to_le_bytes());
+        let len = raw.len() - 4;
+        let crc = crc32fast::hash(&raw[..len]);
+        raw[len..].copy_from_slice(&crc.to_le_bytes());
+        fs::write(&bitmap, &raw).unwrap();
+        let base = FileBackend::open(&base_path, true).unwrap();
+        let err = OverlayBackend::open(&delta, base, false).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
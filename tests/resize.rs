@@ -0,0 +1,162 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+use virtualization_rs::disk::gpt::{Gpt, Guid};
+use virtualization_rs::disk::resize::{resize_raw_disk_image, ResizeError};
+
+mod common;
+
+const MIB: u64 = 1 << 20;
+
+fn gpt_image(dir: &Path, size: u64, partition_end: u64) -> PathBuf {
+    let path = dir.join("disk.img");
+    let file = File::create(&path).unwrap();
+    file.set_len(size).unwrap();
+    let mut gpt = Gpt::new(512, size / 512, Guid([7; 16]));
+    let partition = &mut gpt.partitions[0];
+    partition.type_guid = Guid::LINUX_FILESYSTEM;
+    partition.unique_guid = Guid([9; 16]);
+    partition.first_lba = 2048;
+    partition.last_lba = partition_end;
+    partition.set_name("root");
+    gpt.write(&file).unwrap();
+    gpt.create_protective_mbr(&file, size / 512).unwrap();
+    file.write_all_at(b"payload", 2048 * 512).unwrap();
+    path
+}
+
+fn read_gpt(path: &Path) -> Gpt {
+    Gpt::read(&File::open(path).unwrap()).unwrap().unwrap()
+}
+
+#[test]
+fn growing_moves_the_backup_table_to_the_new_end() {
+    let dir = common::test_dir("resize", "grow");
+    let path = gpt_image(&dir, 4 * MIB, 4095);
+    resize_raw_disk_image(&path, 16 * MIB, false).unwrap();
+
+    assert_eq!(fs::metadata(&path).unwrap().len(), 16 * MIB);
+    let gpt = read_gpt(&path);
+    let total_blocks = 16 * MIB / 512;
+    assert_eq!(gpt.header.backup_lba, total_blocks - 1);
+    assert_eq!(
+        gpt.header.last_usable_lba,
+        total_blocks - 2 - gpt.entry_blocks()
+    );
+    assert_eq!(gpt.partitions[0].last_lba, 4095);
+    assert_eq!(gpt.partitions[0].name(), "root");
+
+    // the old backup header is wiped and the new one is readable on its own
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&path)
+        .unwrap();
+    let mut old_backup = [0u8; 8];
+    file.read_exact_at(&mut old_backup, (4 * MIB / 512 - 1) * 512)
+        .unwrap();
+    assert_eq!(old_backup, [0; 8]);
+    file.write_all_at(&[0; 512], 512).unwrap();
+    assert_eq!(read_gpt(&path).header.backup_lba, total_blocks - 1);
+
+    let mut payload = [0u8; 7];
+    file.read_exact_at(&mut payload, 2048 * 512).unwrap();
+    assert_eq!(&payload, b"payload");
+}
+
+#[test]
+fn growing_can_extend_the_last_partition() {
+    let dir = common::test_dir("resize", "grow-partition");
+    let path = gpt_image(&dir, 4 * MIB, 4095);
+    resize_raw_disk_image(&path, 8 * MIB, true).unwrap();
+
+    let gpt = read_gpt(&path);
+    assert_eq!(gpt.partitions[0].first_lba, 2048);
+    assert_eq!(gpt.partitions[0].last_lba, gpt.header.last_usable_lba);
+}
+
+#[test]
+fn shrinking_keeps_partitions_intact() {
+    let dir = common::test_dir("resize", "shrink");
+    let path = gpt_image(&dir, 8 * MIB, 4095);
+    resize_raw_disk_image(&path, 4 * MIB, false).unwrap();
+    let gpt = read_gpt(&path);
+    assert_eq!(gpt.header.backup_lba, 4 * MIB / 512 - 1);
+    assert_eq!(gpt.partitions[0].last_lba, 4095);
+
+    match resize_raw_disk_image(&path, 2 * MIB, false) {
+        Err(ResizeError::PartitionOutOfRange {
+            index: 0,
+            last_lba: 4095,
+            ..
+        }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(fs::metadata(&path).unwrap().len(), 4 * MIB);
+}
+
+#[test]
+fn sizes_must_fit_the_table() {
+    let dir = common::test_dir("resize", "invalid");
+    let path = gpt_image(&dir, 4 * MIB, 4095);
+    match resize_raw_disk_image(&path, 8 * MIB + 100, false) {
+        Err(ResizeError::Unaligned {
+            block_size: 512, ..
+        }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    match resize_raw_disk_image(&path, 16 * 512, false) {
+        Err(ResizeError::TooSmall { .. }) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn plain_images_only_shrink_over_zeros() {
+    let dir = common::test_dir("resize", "plain");
+    let path = dir.join("disk.img");
+    let file = File::create(&path).unwrap();
+    file.set_len(4 * MIB).unwrap();
+    file.write_all_at(b"data", 3 * MIB + 10).unwrap();
+
+    resize_raw_disk_image(&path, 8 * MIB, false).unwrap();
+    assert_eq!(fs::metadata(&path).unwrap().len(), 8 * MIB);
+    resize_raw_disk_image(&path, 4 * MIB, false).unwrap();
+    match resize_raw_disk_image(&path, 2 * MIB, false) {
+        Err(ResizeError::DataBeyondEnd { offset }) => assert_eq!(offset, 3 * MIB + 10),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(fs::metadata(&path).unwrap().len(), 4 * MIB);
+}
+
+/// points the primary header at entries at `lba` and fixes up its checksum
+fn move_primary_entries(path: &Path, lba: u64) {
+    let file = OpenOptions::new()
+        .write(true)
+        .read(true)
+        .open(path)
+        .unwrap();
+    let mut header = [0u8; 92];
+    file.read_exact_at(&mut header, 512).unwrap();
+    header[72..80].copy_from_slice(&lba.to_le_bytes());
+    header[16..20].copy_from_slice(&[0; 4]);
+    let crc = crc32fast::hash(&header);
+    header[16..20].copy_from_slice(&crc.to_le_bytes());
+    file.write_all_at(&header, 512).unwrap();
+}
+
+#[test]
+fn entries_outside_the_image_fall_back_to_the_backup() {
+    let dir = common::test_dir("resize", "entries");
+    let path = gpt_image(&dir, 4 * MIB, 4095);
+    // an offset that overflows and one past the end of the image
+    for &lba in [u64::MAX / 256, 4 * MIB / 512].iter() {
+        move_primary_entries(&path, lba);
+        let gpt = read_gpt(&path);
+        assert_eq!(gpt.header.current_lba, 1);
+        assert_eq!(gpt.header.partition_entry_lba, 2);
+        assert_eq!(gpt.partitions[0].name(), "root");
+        assert_eq!(gpt.partitions[0].last_lba, 4095);
+    }
+}
@@ -0,0 +1,176 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::process::Command;
+
+use virtualization_rs::definition::VZVirtualMachineState;
+use virtualization_rs::disk::inspect::{inspect_disk_image, FilesystemKind};
+use virtualization_rs::disk::scratch::{ScratchDiskBuilder, ScratchFormat};
+
+mod common;
+
+use common::FakeVm;
+
+const MIB: u64 = 1 << 20;
+
+#[test]
+fn raw_scratch_disks_are_sparse_and_deleted_on_drop() {
+    let dir = common::test_dir("scratch", "raw");
+    let disk = ScratchDiskBuilder::new(64 * MIB + 100)
+        .dir(&dir)
+        .build()
+        .unwrap();
+    assert_eq!(disk.size(), 64 * MIB);
+    assert_eq!(disk.format(), ScratchFormat::Raw);
+    assert!(disk.path().is_absolute());
+    assert_eq!(
+        disk.path().parent(),
+        Some(&*fs::canonicalize(&dir).unwrap())
+    );
+
+    let metadata = fs::metadata(disk.path()).unwrap();
+    assert_eq!(metadata.len(), 64 * MIB);
+    assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+    assert!(metadata.blocks() * 512 < MIB);
+    assert!(inspect_disk_image(disk.path())
+        .unwrap()
+        .filesystem
+        .is_none());
+
+    let path = disk.path().to_path_buf();
+    drop(disk);
+    assert!(!path.exists());
+}
+
+#[test]
+fn scratch_disks_are_deleted_once_the_machine_stops() {
+    let dir = common::test_dir("scratch", "stopped");
+    let disk = ScratchDiskBuilder::new(MIB).dir(&dir).build().unwrap();
+    // the clone an attachment would own
+    let attached = disk.clone();
+
+    let mut vm = FakeVm(VZVirtualMachineState::VZVirtualMachineStateRunning);
+    assert!(!disk.remove_if_stopped(&vm).unwrap());
+    assert!(disk.path().exists());
+    vm.0 = VZVirtualMachineState::VZVirtualMachineStateStopped;
+    assert!(disk.remove_if_stopped(&vm).unwrap());
+    assert!(!disk.path().exists());
+    assert!(disk.remove_if_stopped(&vm).unwrap());
+    drop(attached);
+
+    let disk = ScratchDiskBuilder::new(MIB).dir(&dir).build().unwrap();
+    let failed = FakeVm(VZVirtualMachineState::VZVirtualMachineStateError);
+    assert!(disk.remove_if_stopped(&failed).unwrap());
+    assert!(!disk.path().exists());
+}
+
+#[test]
+fn relative_directories_give_absolute_paths() {
+    let dir = common::test_dir("scratch", "relative");
+    let relative = dir.strip_prefix("/").unwrap();
+    let up = "../".repeat(std::env::current_dir().unwrap().components().count());
+    let disk = ScratchDiskBuilder::new(MIB)
+        .dir(format!("{}{}", up, relative.display()))
+        .build()
+        .unwrap();
+    assert!(disk.path().is_absolute());
+    assert!(disk.path().exists());
+}
+
+#[test]
+fn swap_scratch_disks_are_recognised() {
+    let dir = common::test_dir("scratch", "swap");
+    let disk = ScratchDiskBuilder::new(32 * MIB)
+        .format(ScratchFormat::Swap)
+        .label("scratch-swap")
+        .dir(&dir)
+        .build()
+        .unwrap();
+    let filesystem = inspect_disk_image(disk.path()).unwrap().filesystem.unwrap();
+    assert_eq!(filesystem.kind, FilesystemKind::Swap);
+    assert_eq!(filesystem.version.as_deref(), Some("1"));
+    assert_eq!(filesystem.label.as_deref(), Some("scratch-swap"));
+    let uuid = filesystem.uuid.unwrap();
+
+    match Command::new("blkid")
+        .arg("-p")
+        .arg("-o")
+        .arg("export")
+        .arg(disk.path())
+        .output()
+    {
+        Ok(output) => {
+            let text = String::from_utf8_lossy(&output.stdout);
+            assert!(text.contains("TYPE=swap"), "{}", text);
+            assert!(text.contains("LABEL=scratch-swap"), "{}", text);
+            assert!(text.contains(&format!("UUID={}", uuid)), "{}", text);
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => panic!("{}", err),
+    }
+}
+
+#[test]
+fn ext4_scratch_disks_are_recognised_and_clean() {
+    let dir = common::test_dir("scratch", "ext4");
+    let disk = ScratchDiskBuilder::new(64 * MIB)
+        .format(ScratchFormat::Ext4)
+        .label("scratch")
+        .dir(&dir)
+        .build()
+        .unwrap();
+    let filesystem = inspect_disk_image(disk.path()).unwrap().filesystem.unwrap();
+    assert_eq!(filesystem.kind, FilesystemKind::Ext4);
+    assert_eq!(filesystem.label.as_deref(), Some("scratch"));
+
+    match Command::new("e2fsck").arg("-fn").arg(disk.path()).output() {
+        Ok(output) => assert!(
+            output.status.success(),
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => panic!("{}", err),
+    }
+}
+
+#[test]
+fn failed_builds_leave_nothing_behind() {
+    let dir = common::test_dir("scratch", "failed");
+    assert!(ScratchDiskBuilder::new(100).dir(&dir).build().is_err());
+    // a swap area needs 10 pages
+    assert!(ScratchDiskBuilder::new(9 * 4096)
+        .format(ScratchFormat::Swap)
+        .dir(&dir)
+        .build()
+        .is_err());
+    assert!(ScratchDiskBuilder::new(MIB)
+        .format(ScratchFormat::Swap)
+        .label("a label longer than 16 bytes")
+        .dir(&dir)
+        .build()
+        .is_err());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+    assert!(ScratchDiskBuilder::new(MIB)
+        .dir(dir.join("missing"))
+        .build()
+        .is_err());
+}
+
+#[test]
+fn images_of_dead_processes_are_removed() {
+    let dir = common::test_dir("scratch", "stale");
+    // pids above the kernel's pid_max never run
+    let stale = dir.join("vzrs-scratch-2147483646-0.img");
+    fs::write(&stale, b"").unwrap();
+    let mine = dir.join(format!("vzrs-scratch-{}-999.img", std::process::id()));
+    fs::write(&mine, b"").unwrap();
+    let other = dir.join("unrelated.img");
+    fs::write(&other, b"").unwrap();
+
+    let _disk = ScratchDiskBuilder::new(MIB).dir(&dir).build().unwrap();
+    assert!(!stale.exists());
+    assert!(mine.exists());
+    assert!(other.exists());
+}
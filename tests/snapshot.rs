@@ -0,0 +1,128 @@
+use std::fs;
+use std::path::Path;
+
+use virtualization_rs::definition::*;
+use virtualization_rs::disk::snapshot::{SnapshotError, SnapshotManager};
+
+mod common;
+
+use common::FakeVm;
+
+fn definition(dir: &Path) -> VirtualMachineDefinition {
+    let mut definition = VirtualMachineDefinition::new("web");
+    for (name, read_only) in [("root.img", false), ("data.img", false), ("iso.img", true)].iter() {
+        let path = dir.join(name);
+        fs::write(&path, format!("{} v1", name)).unwrap();
+        definition
+            .storage_devices
+            .push(StorageDeviceDefinition::new(path, *read_only));
+    }
+    definition
+}
+
+#[test]
+fn restore_brings_back_the_writable_disks() {
+    let dir = common::test_dir("snapshot", "restore");
+    let definition = definition(&dir);
+    let snapshots = SnapshotManager::new(&definition, dir.join("snapshots"));
+
+    let snapshot = snapshots.create("first", &FakeVm::stopped()).unwrap();
+    assert_eq!(
+        snapshot.disks,
+        vec![dir.join("root.img"), dir.join("data.img")]
+    );
+    assert_eq!(snapshots.list().unwrap(), vec![snapshot.clone()]);
+    assert_eq!(snapshots.get("first").unwrap(), snapshot);
+
+    fs::write(dir.join("root.img"), "root.img v2").unwrap();
+    fs::write(dir.join("data.img"), "data.img v2").unwrap();
+    fs::write(dir.join("iso.img"), "iso.img v2").unwrap();
+    snapshots.restore("first", &FakeVm::stopped()).unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("root.img")).unwrap(),
+        "root.img v1"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("data.img")).unwrap(),
+        "data.img v1"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("iso.img")).unwrap(),
+        "iso.img v2"
+    );
+    assert!(!dir.join("root.img.restore").exists());
+
+    snapshots.delete("first").unwrap();
+    assert!(snapshots.list().unwrap().is_empty());
+    match snapshots.restore("first", &FakeVm::stopped()) {
+        Err(SnapshotError::NotFound(name)) => assert_eq!(name, "first"),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn running_machines_and_bad_names_are_refused() {
+    let dir = common::test_dir("snapshot", "refused");
+    let definition = definition(&dir);
+    let snapshots = SnapshotManager::new(&definition, dir.join("snapshots"));
+
+    let running = FakeVm(VZVirtualMachineState::VZVirtualMachineStateRunning);
+    match snapshots.create("first", &running) {
+        Err(SnapshotError::NotStopped(VZVirtualMachineState::VZVirtualMachineStateRunning)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    for name in ["", ".hidden", "a/b"].iter() {
+        match snapshots.create(name, &FakeVm::stopped()) {
+            Err(SnapshotError::InvalidName(_)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+    snapshots.create("first", &FakeVm::stopped()).unwrap();
+    match snapshots.create("first", &FakeVm::stopped()) {
+        Err(SnapshotError::AlreadyExists(_)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    let mut smaller = definition.clone();
+    smaller.storage_devices.remove(1);
+    match SnapshotManager::new(&smaller, dir.join("snapshots")).restore("first", &FakeVm::stopped())
+    {
+        Err(SnapshotError::DiskMismatch(disk)) => assert_eq!(disk, dir.join("data.img")),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+#[test]
+fn interrupted_restores_are_completed() {
+    let dir = common::test_dir("snapshot", "recover");
+    let definition = definition(&dir);
+    let snapshots = SnapshotManager::new(&definition, dir.join("snapshots"));
+    snapshots.create("first", &FakeVm::stopped()).unwrap();
+
+    // state after a crash that renamed the first staged disk but not the second
+    fs::write(dir.join("root.img"), "root.img v1").unwrap();
+    fs::write(dir.join("data.img"), "data.img v2").unwrap();
+    fs::write(dir.join("data.img.restore"), "data.img v1").unwrap();
+    fs::write(
+        dir.join("snapshots/.restore-journal"),
+        format!(
+            "disk {}\ndisk {}\n",
+            dir.join("root.img").display(),
+            dir.join("data.img").display()
+        ),
+    )
+    .unwrap();
+
+    snapshots.recover().unwrap();
+    assert_eq!(
+        fs::read_to_string(dir.join("root.img")).unwrap(),
+        "root.img v1"
+    );
+    assert_eq!(
+        fs::read_to_string(dir.join("data.img")).unwrap(),
+        "data.img v1"
+    );
+    assert!(!dir.join("data.img.restore").exists());
+    assert!(!dir.join("snapshots/.restore-journal").exists());
+    snapshots.recover().unwrap();
+}
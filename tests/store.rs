@@ -0,0 +1,200 @@
+use std::fs::{self, File};
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::Path;
+
+use sha2::{Digest as _, Sha256};
+use virtualization_rs::definition::*;
+use virtualization_rs::disk::store::{Digest, ImageStore, StoreError};
+
+mod common;
+
+fn image(path: &Path, fill: u8) -> Vec<u8> {
+    let file = File::create(path).unwrap();
+    file.set_len(4 << 20).unwrap();
+    file.write_all_at(&[fill; 4096], 1 << 20).unwrap();
+    fs::read(path).unwrap()
+}
+
+#[test]
+fn digests_name_the_contents() {
+    let dir = common::test_dir("store", "digest");
+    let data = image(&dir.join("a.img"), 1);
+    let digest = Digest::of_file(dir.join("a.img")).unwrap();
+    let expected: [u8; 32] = Sha256::digest(&data).into();
+    assert_eq!(digest, Digest::from(expected));
+
+    let text = digest.to_string();
+    assert!(text.starts_with("sha256:"));
+    assert_eq!(text.len(), 7 + 64);
+    assert_eq!(text.parse::<Digest>().unwrap(), digest);
+    for bad in [
+        "",
+        "sha256:",
+        "md5:00",
+        &text[..70],
+        &text.replace("sha256", "SHA256"),
+    ]
+    .iter()
+    {
+        assert!(matches!(
+            bad.parse::<Digest>(),
+            Err(StoreError::InvalidDigest(_))
+        ));
+    }
+}
+
+#[test]
+fn imports_are_deduplicated_and_tagged() {
+    let dir = common::test_dir("store", "import");
+    let store = ImageStore::open(dir.join("store")).unwrap();
+    image(&dir.join("a.img"), 1);
+    image(&dir.join("b.img"), 2);
+    fs::copy(dir.join("a.img"), dir.join("a-copy.img")).unwrap();
+
+    let a = store.import(dir.join("a.img")).unwrap();
+    let b = store.import(dir.join("b.img")).unwrap();
+    assert_eq!(store.import(dir.join("a-copy.img")).unwrap(), a);
+    assert_eq!(store.images().unwrap().len(), 2);
+
+    assert_eq!(store.tag("base:1", &a.to_string()).unwrap(), a);
+    assert_eq!(store.tag("base:latest", "base:1").unwrap(), a);
+    store.tag("other", &b.to_string()).unwrap();
+    assert_eq!(store.resolve("base:latest").unwrap(), a);
+    assert_eq!(
+        store.tags().unwrap(),
+        vec![
+            ("base:1".to_string(), a),
+            ("base:latest".to_string(), a),
+            ("other".to_string(), b)
+        ]
+    );
+    let images = store.images().unwrap();
+    let tagged = images.iter().find(|i| i.digest == a).unwrap();
+    assert_eq!(tagged.tags, vec!["base:1", "base:latest"]);
+    assert_eq!(tagged.size, 4 << 20);
+    // no temporary files are left behind by the atomic tag writes
+    let entries: Vec<_> = fs::read_dir(dir.join("store/tags")).unwrap().collect();
+    assert_eq!(entries.len(), 3);
+
+    let path = store.path("base:1").unwrap();
+    assert_eq!(
+        fs::read(&path).unwrap(),
+        fs::read(dir.join("a.img")).unwrap()
+    );
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o444
+    );
+
+    store.untag("other").unwrap();
+    assert!(matches!(store.untag("other"), Err(StoreError::NotFound(_))));
+    assert!(matches!(
+        store.resolve("other"),
+        Err(StoreError::NotFound(_))
+    ));
+    for name in ["", ".hidden", "a/b"].iter() {
+        assert!(matches!(
+            store.tag(name, "base:1"),
+            Err(StoreError::InvalidName(_))
+        ));
+    }
+}
+
+#[test]
+fn tags_cannot_look_like_digests() {
+    let dir = common::test_dir("store", "digest-tag");
+    let store = ImageStore::open(dir.join("store")).unwrap();
+    image(&dir.join("a.img"), 1);
+    let digest = store.import(dir.join("a.img")).unwrap();
+
+    // such a tag would be shadowed by the digest it names
+    let name = digest.to_string();
+    assert!(matches!(
+        store.tag(&name, &name),
+        Err(StoreError::InvalidName(_))
+    ));
+    assert!(matches!(
+        store.tag("sha256:latest", &name),
+        Err(StoreError::InvalidName(_))
+    ));
+    assert!(matches!(
+        store.untag("sha256:latest"),
+        Err(StoreError::InvalidName(_))
+    ));
+    assert!(store.tags().unwrap().is_empty());
+    assert_eq!(store.resolve(&name).unwrap(), digest);
+}
+
+#[test]
+fn gc_keeps_tagged_and_checked_out_images() {
+    let dir = common::test_dir("store", "gc");
+    let store = ImageStore::open(dir.join("store")).unwrap();
+    image(&dir.join("a.img"), 1);
+    image(&dir.join("b.img"), 2);
+    image(&dir.join("c.img"), 3);
+    let a = store.import(dir.join("a.img")).unwrap();
+    let b = store.import(dir.join("b.img")).unwrap();
+    let c = store.import(dir.join("c.img")).unwrap();
+    store.tag("a", &a.to_string()).unwrap();
+
+    let disk = store
+        .checkout("web", &b.to_string(), dir.join("web.img"))
+        .unwrap();
+    assert_eq!(
+        fs::read(&disk.path).unwrap(),
+        fs::read(dir.join("b.img")).unwrap()
+    );
+    assert_eq!(
+        fs::metadata(&disk.path).unwrap().permissions().mode() & 0o777,
+        0o644
+    );
+    assert!(matches!(
+        store.checkout("web", "a", dir.join("web.img")),
+        Err(StoreError::AlreadyExists(_))
+    ));
+    let references = store.references().unwrap();
+    assert_eq!(references.len(), 1);
+    assert_eq!(
+        (references[0].vm.as_str(), references[0].digest),
+        ("web", b)
+    );
+
+    // a leftover import of a process that no longer exists
+    fs::write(dir.join("store/tmp/999999999-0"), vec![0u8; 8192]).unwrap();
+
+    let report = store.gc().unwrap();
+    assert_eq!(report.removed, vec![c]);
+    assert!(report.stale_references.is_empty());
+    assert!(report.freed > 0);
+    assert!(!dir.join("store/tmp/999999999-0").exists());
+    let digests: Vec<Digest> = store.images().unwrap().iter().map(|i| i.digest).collect();
+    assert_eq!(digests.len(), 2);
+    assert!(digests.contains(&a) && digests.contains(&b));
+
+    // the disk is dropped from the definition, so its image is no longer used
+    let mut definition = VirtualMachineDefinition::new("web");
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(store.path("a").unwrap(), true));
+    store.track(&definition).unwrap();
+    let references = store.references().unwrap();
+    assert_eq!(references.len(), 1);
+    assert_eq!(references[0].digest, a);
+    store.untag("a").unwrap();
+    assert_eq!(store.gc().unwrap().removed, vec![b]);
+
+    // references to deleted disks are dropped
+    assert!(matches!(
+        store.checkout("db", "sha256:", dir.join("db.img")),
+        Err(StoreError::InvalidDigest(_))
+    ));
+    let disk = store
+        .checkout("db", &a.to_string(), dir.join("db.img"))
+        .unwrap();
+    fs::remove_file(&disk.path).unwrap();
+    store.untrack("web").unwrap();
+    let report = store.gc().unwrap();
+    assert_eq!(report.stale_references.len(), 1);
+    assert_eq!(report.removed, vec![a]);
+    assert!(store.images().unwrap().is_empty());
+}
@@ -0,0 +1,185 @@
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::thread;
+use std::time::Duration;
+
+use virtualization_rs::net::mac::MacAddress;
+use virtualization_rs::net::switch::{EthernetSwitch, EthernetSwitchBuilder, PortId};
+
+const A: MacAddress = MacAddress::new([0x02, 0, 0, 0, 0, 0x0a]);
+const B: MacAddress = MacAddress::new([0x02, 0, 0, 0, 0, 0x0b]);
+const C: MacAddress = MacAddress::new([0x02, 0, 0, 0, 0, 0x0c]);
+const UNKNOWN: MacAddress = MacAddress::new([0x02, 0, 0, 0, 0, 0xff]);
+
+fn frame(dst: MacAddress, src: MacAddress, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&dst.octets());
+    frame.extend_from_slice(&src.octets());
+    frame.extend_from_slice(&[0x88, 0xb5]);
+    frame.extend_from_slice(payload);
+    frame
+}
+
+struct Nic {
+    port: PortId,
+    socket: UnixDatagram,
+}
+
+impl Nic {
+    fn connect(switch: &EthernetSwitch) -> Nic {
+        let (port, socket) = switch.connect().unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        Nic { port, socket }
+    }
+
+    fn send(&self, frame: &[u8]) {
+        self.socket.send(frame).unwrap();
+    }
+
+    fn recv(&self) -> Option<Vec<u8>> {
+        let mut buf = vec![0u8; 2048];
+        match self.socket.recv(&mut buf) {
+            Ok(n) => Some(buf[..n].to_vec()),
+            Err(ref e)
+                if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut =>
+            {
+                None
+            }
+            Err(e) => panic!("{}", e),
+        }
+    }
+}
+
+#[test]
+fn unknown_destinations_are_flooded_until_learned() {
+    let switch = EthernetSwitchBuilder::new().build();
+    let (a, b, c) = (
+        Nic::connect(&switch),
+        Nic::connect(&switch),
+        Nic::connect(&switch),
+    );
+
+    let hello = frame(UNKNOWN, A, b"hello");
+    a.send(&hello);
+    assert_eq!(b.recv(), Some(hello.clone()));
+    assert_eq!(c.recv(), Some(hello));
+    assert_eq!(a.recv(), None, "no echo to the ingress port");
+
+    let reply = frame(A, B, b"reply");
+    b.send(&reply);
+    assert_eq!(a.recv(), Some(reply));
+    assert_eq!(c.recv(), None);
+
+    let direct = frame(B, A, b"direct");
+    a.send(&direct);
+    assert_eq!(b.recv(), Some(direct));
+    assert_eq!(c.recv(), None);
+
+    let table = switch.mac_table();
+    let learned: Vec<_> = table.iter().map(|e| (e.mac, e.port)).collect();
+    assert_eq!(learned, vec![(A, a.port), (B, b.port)]);
+    let stats = switch.port_stats(a.port).unwrap();
+    assert_eq!((stats.rx_frames, stats.tx_frames), (2, 1));
+}
+
+#[test]
+fn broadcast_and_multicast_are_flooded_and_never_learned() {
+    let switch = EthernetSwitchBuilder::new().build();
+    let (a, b, c) = (
+        Nic::connect(&switch),
+        Nic::connect(&switch),
+        Nic::connect(&switch),
+    );
+    let multicast: MacAddress = "01:00:5e:00:00:fb".parse().unwrap();
+
+    let broadcast = frame(MacAddress::BROADCAST, A, b"arp");
+    a.send(&broadcast);
+    assert_eq!(b.recv(), Some(broadcast.clone()));
+    assert_eq!(c.recv(), Some(broadcast));
+
+    // a multicast source address is bogus and must not capture unicast traffic
+    b.send(&frame(A, multicast, b"spoof"));
+    assert!(a.recv().is_some());
+    let mdns = frame(multicast, C, b"mdns");
+    a.send(&mdns);
+    assert_eq!(b.recv(), Some(mdns.clone()));
+    assert_eq!(c.recv(), Some(mdns));
+    assert!(switch.mac_table().iter().all(|e| e.mac != multicast));
+}
+
+#[test]
+fn stations_move_and_age_out() {
+    let switch = EthernetSwitchBuilder::new()
+        .aging_time(Duration::from_millis(300))
+        .build();
+    let (a, b, c) = (
+        Nic::connect(&switch),
+        Nic::connect(&switch),
+        Nic::connect(&switch),
+    );
+
+    a.send(&frame(UNKNOWN, A, b"1"));
+    b.recv().unwrap();
+    c.recv().unwrap();
+    // A moved behind port c
+    c.send(&frame(UNKNOWN, A, b"2"));
+    a.recv().unwrap();
+    b.recv().unwrap();
+    b.send(&frame(A, B, b"3"));
+    assert!(c.recv().is_some());
+    assert_eq!(a.recv(), None);
+
+    thread::sleep(Duration::from_millis(400));
+    assert!(switch.mac_table().is_empty());
+    b.send(&frame(A, B, b"4"));
+    assert!(a.recv().is_some());
+    assert!(c.recv().is_some());
+}
+
+#[test]
+fn malformed_frames_are_dropped() {
+    let switch = EthernetSwitchBuilder::new().mtu(100).build();
+    let (a, b) = (Nic::connect(&switch), Nic::connect(&switch));
+
+    a.send(&[0xff; 13]);
+    a.send(&frame(MacAddress::BROADCAST, A, &[0; 105]));
+    let largest = frame(MacAddress::BROADCAST, A, &[0; 104]);
+    a.send(&largest);
+    assert_eq!(b.recv(), Some(largest));
+    assert_eq!(b.recv(), None);
+    let stats = switch.port_stats(a.port).unwrap();
+    assert_eq!((stats.rx_frames, stats.errors), (3, 2));
+}
+
+#[test]
+fn ports_are_removed() {
+    let switch = EthernetSwitchBuilder::new().build();
+    let (a, b, c) = (
+        Nic::connect(&switch),
+        Nic::connect(&switch),
+        Nic::connect(&switch),
+    );
+    b.send(&frame(UNKNOWN, B, b"learn"));
+    a.recv().unwrap();
+    c.recv().unwrap();
+
+    assert!(switch.disconnect(b.port));
+    assert!(!switch.disconnect(b.port));
+    assert_eq!(switch.ports(), vec![a.port, c.port]);
+    assert!(switch.mac_table().is_empty());
+
+    // a NIC that goes away is noticed on the next delivery
+    let c_port = c.port;
+    drop(c);
+    a.send(&frame(MacAddress::BROADCAST, A, b"anyone?"));
+    for _ in 0..50 {
+        if switch.ports() == vec![a.port] {
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert_eq!(switch.ports(), vec![a.port]);
+    assert!(switch.port_stats(c_port).is_none());
+}
@@ -0,0 +1,155 @@
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::Path;
+
+use virtualization_rs::definition::*;
+use virtualization_rs::disk::lock::DiskImageLock;
+use virtualization_rs::disk::store::Digest;
+use virtualization_rs::disk::usage::*;
+
+mod common;
+
+use common::FakeVm;
+
+const MIB: u64 = 1 << 20;
+
+/// 16 MiB image with 4 MiB of data, of which the middle 2 MiB are written
+/// zeros, and a hole everywhere else
+fn zeroed_image(path: &Path) {
+    let file = File::create(path).unwrap();
+    file.set_len(16 * MIB).unwrap();
+    file.write_all_at(&vec![0xa5; MIB as usize], 4 * MIB)
+        .unwrap();
+    file.write_all_at(&vec![0; 2 * MIB as usize], 5 * MIB)
+        .unwrap();
+    file.write_all_at(&vec![0x5a; MIB as usize], 7 * MIB)
+        .unwrap();
+    file.sync_all().unwrap();
+}
+
+fn blksize(path: &Path) -> u64 {
+    fs::metadata(path).unwrap().blksize()
+}
+
+#[test]
+fn usage_counts_data_and_allocation() {
+    let dir = common::test_dir("usage", "usage");
+    let path = dir.join("disk.img");
+    zeroed_image(&path);
+    let usage = disk_usage(&path).unwrap();
+    assert_eq!(usage.apparent, 16 * MIB);
+    // SEEK_DATA may report a little more than was written, never less
+    assert!(
+        usage.data >= 4 * MIB && usage.data < 16 * MIB,
+        "{:?}",
+        usage
+    );
+    assert!(
+        usage.allocated >= 4 * MIB && usage.allocated < 16 * MIB,
+        "{:?}",
+        usage
+    );
+    assert!(usage.ratio() > 0.0 && usage.ratio() < 1.0);
+
+    let empty = dir.join("empty.img");
+    File::create(&empty).unwrap();
+    assert_eq!(disk_usage(&empty).unwrap(), DiskUsage::default());
+    assert_eq!(DiskUsage::default().ratio(), 0.0);
+
+    let mut definition = VirtualMachineDefinition::new("usage");
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(&path, false));
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(&empty, true));
+    let usages = definition_usage(&definition).unwrap();
+    assert_eq!(usages, vec![(path, usage), (empty, DiskUsage::default())]);
+}
+
+#[test]
+fn zero_blocks_become_holes() {
+    let dir = common::test_dir("usage", "reclaim");
+    let path = dir.join("disk.img");
+    zeroed_image(&path);
+    let digest = Digest::of_file(&path).unwrap();
+
+    let report = reclaim_disk_image(&path).unwrap();
+    assert_eq!(report.path, path);
+    assert_eq!(report.punched, 2 * MIB);
+    assert_eq!(report.before.apparent, report.after.apparent);
+    assert!(
+        report.after.data <= report.before.data - 2 * MIB,
+        "{:?}",
+        report
+    );
+    assert!(report.saved() >= 2 * MIB, "{:?}", report);
+    assert_eq!(Digest::of_file(&path).unwrap(), digest);
+
+    // nothing is left to punch the second time
+    let again = reclaim_disk_image(&path).unwrap();
+    assert_eq!(again.punched, 0);
+    assert_eq!(again.saved(), 0);
+}
+
+#[test]
+fn partial_blocks_at_the_end_are_kept() {
+    let dir = common::test_dir("usage", "tail");
+    let path = dir.join("disk.img");
+    let block = {
+        File::create(&path).unwrap();
+        blksize(&path)
+    };
+    let file = OpenOptions::new().write(true).open(&path).unwrap();
+    file.write_all_at(&vec![0; (3 * block + 100) as usize], 0)
+        .unwrap();
+    drop(file);
+
+    let report = reclaim_disk_image(&path).unwrap();
+    assert_eq!(report.punched, 3 * block);
+    assert_eq!(fs::metadata(&path).unwrap().len(), 3 * block + 100);
+    assert!(fs::read(&path).unwrap().iter().all(|&b| b == 0));
+}
+
+#[test]
+fn attached_images_are_not_reclaimed() {
+    let dir = common::test_dir("usage", "locked");
+    let path = dir.join("disk.img");
+    zeroed_image(&path);
+    let lock = DiskImageLock::shared(&path).unwrap();
+    assert!(matches!(
+        reclaim_disk_image(&path),
+        Err(ReclaimError::Lock(_))
+    ));
+    drop(lock);
+    assert_eq!(reclaim_disk_image(&path).unwrap().punched, 2 * MIB);
+}
+
+#[test]
+fn reclaim_disks_skips_read_only_disks_of_stopped_machines() {
+    let dir = common::test_dir("usage", "definition");
+    let writable = dir.join("root.img");
+    let read_only = dir.join("seed.img");
+    zeroed_image(&writable);
+    zeroed_image(&read_only);
+    let mut definition = VirtualMachineDefinition::new("usage");
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(&writable, false));
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(&read_only, true));
+
+    let running = FakeVm(VZVirtualMachineState::VZVirtualMachineStateRunning);
+    assert!(matches!(
+        reclaim_disks(&definition, &running),
+        Err(ReclaimError::NotStopped(_))
+    ));
+
+    let reports = reclaim_disks(&definition, &FakeVm::stopped()).unwrap();
+    assert_eq!(reports.len(), 1);
+    assert_eq!(reports[0].path, writable);
+    assert_eq!(reports[0].punched, 2 * MIB);
+    let untouched = reclaim_disk_image(&read_only).unwrap();
+    assert_eq!(untouched.punched, 2 * MIB);
+}
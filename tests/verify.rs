@@ -0,0 +1,222 @@
+use std::fs::{self, File};
+use std::os::unix::fs::{FileExt, MetadataExt};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use virtualization_rs::definition::*;
+use virtualization_rs::disk::store::Digest;
+use virtualization_rs::disk::verify::*;
+
+mod common;
+
+const EMPTY: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+fn digest(hex: &str) -> Digest {
+    format!("sha256:{}", hex).parse().unwrap()
+}
+
+#[test]
+fn gnu_and_bsd_manifests_are_parsed() {
+    let text = format!(
+        "# images\n\
+         {empty}  empty.img\n\
+         {abc} *binary name.img\r\n\
+         \n\
+         SHA256 (bsd (1).img) = {abc_upper}\n",
+        empty = EMPTY,
+        abc = ABC,
+        abc_upper = ABC.to_ascii_uppercase()
+    );
+    let manifest = ChecksumManifest::parse(&text, "/images").unwrap();
+    assert_eq!(
+        manifest.entries(),
+        &[
+            ("empty.img".to_string(), digest(EMPTY)),
+            ("binary name.img".to_string(), digest(ABC)),
+            ("bsd (1).img".to_string(), digest(ABC)),
+        ]
+    );
+    assert_eq!(manifest.digest_of("/images/empty.img"), Some(digest(EMPTY)));
+    assert_eq!(manifest.digest_of("/images/bsd (1).img"), Some(digest(ABC)));
+    assert_eq!(manifest.digest_of("/elsewhere/empty.img"), None);
+
+    for line in [
+        "not a checksum line",
+        "e3b0  empty.img",
+        "SHA256 (empty.img) e3b0",
+        &format!("{} empty.img", EMPTY),
+        &format!("{}zz  empty.img", &EMPTY[..62]),
+    ] {
+        match ChecksumManifest::parse(line, "/images") {
+            Err(VerifyError::InvalidManifest(bad)) => assert_eq!(bad, line),
+            other => panic!("{:?} parsed as {:?}", line, other),
+        }
+    }
+}
+
+#[test]
+fn manifests_are_applied_to_definitions() {
+    let dir = common::test_dir("verify", "apply");
+    fs::write(dir.join("abc.img"), b"abc").unwrap();
+    fs::write(dir.join("SHA256SUMS"), format!("{}  abc.img\n", ABC)).unwrap();
+    let manifest = ChecksumManifest::open(dir.join("SHA256SUMS")).unwrap();
+
+    let mut definition = VirtualMachineDefinition::new("verify");
+    // a path naming the same file differently still matches
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(
+            dir.join(".").join("abc.img"),
+            true,
+        ));
+    manifest.apply(&mut definition).unwrap();
+    assert_eq!(definition.storage_devices[0].digest, Some(digest(ABC)));
+    DigestCache::new().verify_definition(&definition).unwrap();
+
+    definition
+        .storage_devices
+        .push(StorageDeviceDefinition::new(dir.join("other.img"), true));
+    match manifest.apply(&mut definition) {
+        Err(VerifyError::NotListed(path)) => assert_eq!(path, dir.join("other.img")),
+        other => panic!("{:?}", other),
+    }
+}
+
+#[test]
+fn mismatches_name_both_digests() {
+    let dir = common::test_dir("verify", "mismatch");
+    let path = dir.join("abc.img");
+    fs::write(&path, b"abc").unwrap();
+    let cache = DigestCache::new();
+    cache.verify(&path, &digest(ABC)).unwrap();
+    match cache.verify(&path, &digest(EMPTY)) {
+        Err(VerifyError::Mismatch {
+            path: bad,
+            expected,
+            actual,
+        }) => {
+            assert_eq!(bad, path);
+            assert_eq!(expected, digest(EMPTY));
+            assert_eq!(actual, digest(ABC));
+        }
+        other => panic!("{:?}", other),
+    }
+
+    let empty = dir.join("empty.img");
+    File::create(&empty).unwrap();
+    let images: Vec<(PathBuf, Digest)> = vec![
+        (path.clone(), digest(ABC)),
+        (empty.clone(), digest(ABC)),
+        (path, digest(EMPTY)),
+    ];
+    match cache.verify_all(&images) {
+        Err(VerifyError::Mismatch { path, .. }) => assert_eq!(path, empty),
+        other => panic!("{:?}", other),
+    }
+}
+
+/// rewrites the contents of `path` without changing its size or times
+fn overwrite_in_place(path: &std::path::Path, contents: &[u8]) {
+    let mtime = fs::metadata(path).unwrap().modified().unwrap();
+    let file = fs::OpenOptions::new().write(true).open(path).unwrap();
+    file.write_all_at(contents, 0).unwrap();
+    file.set_modified(mtime).unwrap();
+}
+
+#[test]
+fn cached_digests_follow_size_mtime_and_inode() {
+    let dir = common::test_dir("verify", "cache");
+    let path = dir.join("disk.img");
+    fs::write(&path, b"abc").unwrap();
+    let cache = DigestCache::new();
+    assert_eq!(cache.digest(&path).unwrap(), digest(ABC));
+
+    // an unchanged key is answered from the cache
+    overwrite_in_place(&path, b"xyz");
+    assert_eq!(cache.digest(&path).unwrap(), digest(ABC));
+
+    // a new modification time is hashed again
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_modified(SystemTime::now() - Duration::from_secs(3600))
+        .unwrap();
+    assert_eq!(
+        cache.digest(&path).unwrap(),
+        Digest::of_file(&path).unwrap()
+    );
+
+    // so is a new size with the same modification time
+    fs::write(&path, b"abc").unwrap();
+    assert_eq!(cache.digest(&path).unwrap(), digest(ABC));
+    let mtime = fs::metadata(&path).unwrap().modified().unwrap();
+    let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+    file.set_len(0).unwrap();
+    file.set_modified(mtime).unwrap();
+    assert_eq!(cache.digest(&path).unwrap(), digest(EMPTY));
+
+    // and a file replaced by another inode with the same size and time
+    fs::write(&path, b"abc").unwrap();
+    assert_eq!(cache.digest(&path).unwrap(), digest(ABC));
+    let metadata = fs::metadata(&path).unwrap();
+    let replacement = dir.join("replacement.img");
+    fs::write(&replacement, b"xyz").unwrap();
+    File::options()
+        .write(true)
+        .open(&replacement)
+        .unwrap()
+        .set_modified(metadata.modified().unwrap())
+        .unwrap();
+    fs::rename(&replacement, &path).unwrap();
+    assert_ne!(fs::metadata(&path).unwrap().ino(), metadata.ino());
+    assert_eq!(
+        cache.digest(&path).unwrap(),
+        Digest::of_file(&path).unwrap()
+    );
+    assert_ne!(cache.digest(&path).unwrap(), digest(ABC));
+}
+
+#[test]
+fn saved_caches_are_keyed_on_the_device_too() {
+    let dir = common::test_dir("verify", "saved");
+    let path = dir.join("disk.img");
+    fs::write(&path, b"abc").unwrap();
+    let cache_path = dir.join("digests");
+
+    let cache = DigestCache::open(&cache_path).unwrap();
+    cache.digest(&path).unwrap();
+    cache.save().unwrap();
+    // no temporary file is left next to the cache
+    let hidden = fs::read_dir(&dir)
+        .unwrap()
+        .filter(|entry| {
+            entry
+                .as_ref()
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with('.')
+        })
+        .count();
+    assert_eq!(hidden, 0);
+    let saved = fs::read_to_string(&cache_path).unwrap();
+    assert_eq!(saved.lines().count(), 1);
+
+    // a reloaded cache answers without hashing, as a forged digest shows
+    let forged = saved.replace(&digest(ABC).to_string(), &digest(EMPTY).to_string());
+    fs::write(&cache_path, &forged).unwrap();
+    let cache = DigestCache::open(&cache_path).unwrap();
+    assert_eq!(cache.digest(&path).unwrap(), digest(EMPTY));
+
+    // the same inode on another device is hashed
+    let dev = fs::metadata(&path).unwrap().dev();
+    let (_, rest) = forged.split_once(' ').unwrap();
+    fs::write(&cache_path, format!("{} {}", dev + 1, rest)).unwrap();
+    let cache = DigestCache::open(&cache_path).unwrap();
+    assert_eq!(cache.digest(&path).unwrap(), digest(ABC));
+
+    // garbage lines are ignored
+    fs::write(&cache_path, "garbage\n1 2 3\n").unwrap();
+    let cache = DigestCache::open(&cache_path).unwrap();
+    assert_eq!(cache.digest(&path).unwrap(), digest(ABC));
+    assert!(DigestCache::open(dir.join("missing")).is_ok());
+}